@@ -0,0 +1,12 @@
+#![no_main]
+
+use iroh::collection::Collection;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(collection) = Collection::from_bytes(data) {
+        let bytes = collection.to_bytes().expect("serialize");
+        let back = Collection::from_bytes(&bytes).expect("roundtrip");
+        assert_eq!(back, collection);
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use iroh::dial::Ticket;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(ticket) = Ticket::from_bytes(data) {
+        let back = Ticket::from_bytes(&ticket.to_bytes()).expect("roundtrip");
+        assert_eq!(back, ticket);
+    }
+    if let Ok(s) = std::str::from_utf8(data) {
+        s.parse::<Ticket>().ok();
+    }
+});
@@ -1,5 +1,5 @@
 //! Traits for in-memory or persistent maps of blob with bao encoded outboards.
-use std::{io, path::PathBuf};
+use std::{io, path::PathBuf, time::SystemTime};
 
 use crate::{
     util::{
@@ -14,7 +14,7 @@ use futures::future::BoxFuture;
 use iroh_io::AsyncSliceReader;
 use range_collections::RangeSet2;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 
 pub use bao_tree;
 pub use range_collections;
@@ -30,6 +30,10 @@ pub trait MapEntry<D: Map>: Clone + Send + Sync + 'static {
     fn hash(&self) -> blake3::Hash;
     /// The size of the entry.
     fn size(&self) -> u64;
+    /// Whether the entry is complete.
+    ///
+    /// Partial entries only contain the ranges given by [`MapEntry::available_ranges`].
+    fn is_complete(&self) -> bool;
     /// Compute the available ranges.
     ///
     /// Depending on the implementation, this may be an expensive operation.
@@ -66,6 +70,11 @@ pub trait Map: Clone + Send + Sync + 'static {
     /// This function should not block to perform io. The knowledge about
     /// existing entries must be present in memory.
     fn get(&self, hash: &Hash) -> Option<Self::Entry>;
+
+    /// Drop data that is only cached in memory, e.g. because the process is short of memory.
+    ///
+    /// The default does nothing, for stores without caches.
+    fn shrink_caches(&self) {}
 }
 
 /// A partial entry
@@ -115,6 +124,8 @@ pub trait ReadableStore: Map {
     fn blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static>;
     /// list all roots (collections or other explicitly added things) in the database
     ///
+    /// The roots are the complete entries that are pinned recursively by an unexpired pin.
+    ///
     /// This function should not block to perform io. The knowledge about
     /// existing roots must be present in memory.
     fn roots(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static>;
@@ -124,6 +135,18 @@ pub trait ReadableStore: Map {
     /// list partial blobs in the database
     fn partial_blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static>;
 
+    /// list all pins by name, including expired pins
+    ///
+    /// This function should not block to perform io. The pins must be present
+    /// in memory.
+    fn pins(&self) -> Box<dyn Iterator<Item = (String, Pin)> + Send + Sync + 'static>;
+
+    /// Subscribe to the entries that are added to or removed from the store, and to
+    /// changes of the pins.
+    ///
+    /// A receiver that lags behind misses events, and has to list the entries again.
+    fn subscribe(&self) -> broadcast::Receiver<StoreEvent>;
+
     /// This trait method extracts a file to a local path.
     ///
     /// `hash` is the hash of the file
@@ -152,6 +175,10 @@ pub trait Store: ReadableStore + PartialMap {
     ///
     /// Returns the hash of the imported file. The reason to have this method is that some database
     /// implementations might be able to import a file without copying it.
+    ///
+    /// The data can only be served once the import is complete: the hash, and the hashes
+    /// needed to verify any range of the data, depend on all of the data, so there is
+    /// nothing a getter could request or verify while the file is still being hashed.
     fn import(
         &self,
         data: PathBuf,
@@ -163,6 +190,142 @@ pub trait Store: ReadableStore + PartialMap {
     ///
     /// It is a special case of `import` that does not use the file system.
     fn import_bytes(&self, bytes: Bytes) -> BoxFuture<'_, io::Result<Hash>>;
+
+    /// Add a pin with the given name, replacing an existing pin with the same name.
+    ///
+    /// The pinned hash does not have to be in the store yet.
+    fn set_pin(&self, name: String, pin: Pin) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Remove the pin with the given name, returning it if it existed.
+    fn remove_pin(&self, name: String) -> BoxFuture<'_, io::Result<Option<Pin>>>;
+
+    /// Remove a complete entry whose data does not match its hash, so it is no longer served.
+    ///
+    /// Stores should keep the data around for inspection where possible. Removing an
+    /// entry that does not exist is not an error.
+    fn quarantine(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Delete a complete entry, so it is no longer served.
+    ///
+    /// Stores with a trash keep the data for a while, so the entry can be brought back
+    /// with [`Store::restore`]. Deleting an entry that does not exist is not an error.
+    ///
+    /// Fails with [`io::ErrorKind::PermissionDenied`] if an unexpired pin refers to the
+    /// entry, see [`Pin::protects`].
+    fn delete(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Restore an entry from the trash, returning whether it was found there.
+    fn restore(&self, hash: Hash) -> BoxFuture<'_, io::Result<bool>>;
+
+    /// Export the verified ranges of a partial entry to `target`, so the download can be
+    /// resumed elsewhere with [`Store::import_partial`].
+    ///
+    /// Stores that support this write a sparse file of the full size of the blob, and a
+    /// sidecar next to it that records which ranges have been verified.
+    fn export_partial(&self, hash: Hash, target: PathBuf) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Import a partial entry written by [`Store::export_partial`], returning its hash.
+    ///
+    /// The data must be verified before it is added to the store.
+    fn import_partial(&self, path: PathBuf) -> BoxFuture<'_, io::Result<Hash>>;
+}
+
+/// A pin protects a blob from garbage collection.
+///
+/// Pins are identified by name, so multiple pins can protect the same blob, e.g. for
+/// different applications. A blob is protected as long as at least one unexpired pin
+/// refers to it.
+///
+/// The stores do not collect garbage yet, so for now a pin only makes deleting the blob
+/// fail, see [`Store::delete`]. Unpinned blobs are kept until they are deleted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pin {
+    /// The pinned hash.
+    pub hash: Hash,
+    /// Whether all children of the pinned collection are protected as well.
+    pub recursive: bool,
+    /// When the pin expires, `None` if it never does.
+    pub expires: Option<SystemTime>,
+}
+
+impl Pin {
+    /// Whether the pin has expired at `now`, and no longer protects the blob.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+
+    /// Whether the pin refers to `hash` and has not expired at `now`.
+    ///
+    /// This does not consider the children of recursive pins, since the store does not
+    /// know how to parse collections.
+    pub fn protects(&self, hash: &Hash, now: SystemTime) -> bool {
+        self.hash == *hash && !self.is_expired(now)
+    }
+
+    /// The error for deleting `hash` while the pin `name` protects it.
+    pub fn protected_error(hash: &Hash, name: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{hash} is protected by the pin {name}"),
+        )
+    }
+}
+
+/// An entry that data can be appended to, e.g. a log that is shipped to peers.
+///
+/// Sealing the entry produces the hash of all data appended so far, and makes the
+/// data available in the store as a complete entry under that hash. Data can still be
+/// appended after sealing, and each seal produces a new hash. Implementations hash
+/// complete subtrees as data is appended, so sealing does not re-hash the prefix.
+pub trait AppendableEntry: Send + Sync + 'static {
+    /// The number of bytes appended so far.
+    fn size(&self) -> u64;
+
+    /// Append data to the end of the entry.
+    fn append(&mut self, data: &[u8]) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Make the data appended so far available as a complete entry.
+    ///
+    /// Returns the hash of the data.
+    fn seal(&mut self) -> BoxFuture<'_, io::Result<Hash>>;
+}
+
+/// A store that supports entries that grow over time.
+pub trait AppendableStore: Store {
+    /// The appendable entry type
+    type AppendableEntry: AppendableEntry;
+
+    /// Create a new, empty appendable entry.
+    fn create_appendable(&self) -> io::Result<Self::AppendableEntry>;
+}
+
+/// Error returned by persistent stores that refuse to accept new data because
+/// free disk space is below the configured low watermark.
+///
+/// This is a transient condition. The operation can be retried once disk space
+/// has been freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("insufficient disk space: {free} bytes free, low watermark is {low_watermark} bytes")]
+pub struct InsufficientSpace {
+    /// The free disk space in bytes when the write was refused
+    pub free: u64,
+    /// The configured low watermark in bytes
+    pub low_watermark: u64,
+}
+
+impl InsufficientSpace {
+    /// Check if an io error was caused by insufficient disk space.
+    ///
+    /// Operations that fail with this error are retryable.
+    pub fn from_io_error(e: &io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl From<InsufficientSpace> for io::Error {
+    fn from(e: InsufficientSpace) -> Self {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
 }
 
 /// Progress messages for an import operation
@@ -196,6 +359,10 @@ pub enum ImportProgress {
     ///
     /// This comes after `Size` and zero or more `OutboardProgress` messages
     OutboardDone { id: u64, hash: Hash },
+    /// The data was already in the store, so the import did not take up any new space
+    ///
+    /// This comes after `OutboardDone`, and is omitted for data that is new to the store
+    Deduplicated { id: u64, size: u64 },
 }
 
 /// The import mode describes how files will be imported.
@@ -268,6 +435,17 @@ pub enum ExportProgress {
     Done { id: u64 },
 }
 
+/// A change to the entries of a store, see [`ReadableStore::subscribe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// A complete or partial entry was added.
+    Added(Hash),
+    /// A complete entry was removed.
+    Removed(Hash),
+    /// A pin was set or removed, see [`ReadableStore::pins`] for the current pins.
+    PinsChanged,
+}
+
 /// Progress updates for the provide operation
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ValidateProgress {
@@ -24,7 +24,10 @@ use quinn::RecvStream;
 use range_collections::RangeSet2;
 use tracing::{debug, error};
 
-use crate::protocol::{write_lp, AnyGetRequest, RangeSpecSeq};
+use crate::protocol::{
+    read_lp, write_lp, AnyGetRequest, ErrorCode, KeyedGetRequest, ListRequest, ListResponse,
+    ProbeRequest, ProbeResponse, RangeSpecSeq, Request,
+};
 use crate::util::io::{TrackingReader, TrackingWriter};
 use crate::IROH_BLOCK_SIZE;
 
@@ -53,7 +56,7 @@ impl Stats {
 pub mod fsm {
     use std::result;
 
-    use crate::protocol::{read_lp, GetRequest, NonEmptyRequestRangeSpecIter};
+    use crate::protocol::{GetRequest, NonEmptyRequestRangeSpecIter};
 
     use super::*;
 
@@ -79,6 +82,22 @@ pub mod fsm {
         AtInitial::new(connection, request)
     }
 
+    /// The entry point of the get response machine for a request by name
+    ///
+    /// The provider does not reveal the root hash a name stands for, so the requester has
+    /// to know the `root` to verify the data against.
+    pub fn start_named(
+        connection: quinn::Connection,
+        request: KeyedGetRequest,
+        root: Hash,
+    ) -> AtInitial {
+        AtInitial {
+            connection,
+            request: request.into(),
+            root: Some(root),
+        }
+    }
+
     /// Owned iterator for the ranges in a request
     ///
     /// We need an owned iterator for a fsm style API, otherwise we would have
@@ -113,6 +132,7 @@ pub mod fsm {
     pub struct AtInitial {
         connection: quinn::Connection,
         request: AnyGetRequest,
+        root: Option<Hash>,
     }
 
     impl AtInitial {
@@ -124,6 +144,7 @@ pub mod fsm {
             Self {
                 connection,
                 request,
+                root: None,
             }
         }
 
@@ -138,6 +159,7 @@ pub mod fsm {
                 reader,
                 writer,
                 request: self.request,
+                root: self.root,
             })
         }
     }
@@ -149,6 +171,8 @@ pub mod fsm {
         reader: TrackingReader<quinn::RecvStream>,
         writer: TrackingWriter<quinn::SendStream>,
         request: AnyGetRequest,
+        // the root of a request by name, which the response does not contain
+        root: Option<Hash>,
     }
 
     /// Possible next states after the handshake has been sent
@@ -175,6 +199,7 @@ pub mod fsm {
                 mut reader,
                 mut writer,
                 request,
+                root,
             } = self;
             // 1. Send Request
             {
@@ -194,8 +219,14 @@ pub mod fsm {
                     // we already have a get request, just return it
                     get_request
                 }
+                AnyGetRequest::KeyedGet(keyed) => {
+                    // the provider does not send the root hash, the requester brings it
+                    let root = root.context("a request by name needs the root hash")?;
+                    GetRequest::new(root, keyed.ranges)
+                }
                 AnyGetRequest::CustomGet(_) => {
-                    // we sent a custom request, so we need the actual GetRequest from the response
+                    // we sent a custom request, so we need the actual GetRequest from the
+                    // response
                     let mut buffer = BytesMut::new();
                     let response = read_lp(&mut reader, &mut buffer)
                         .await?
@@ -204,6 +235,9 @@ pub mod fsm {
                         "unable to deserialize response to custom get request as get request",
                     )?
                 }
+                AnyGetRequest::Probe(_) | AnyGetRequest::List(_) => {
+                    return Err(anyhow::anyhow!("request has no get response").into());
+                }
             };
             let hash = request.hash;
             let ranges_iter = RangesIter::new(request.ranges);
@@ -212,6 +246,7 @@ pub mod fsm {
                 start,
                 bytes_written,
                 ranges_iter,
+                root: hash,
             });
             Ok(match misc.ranges_iter.next() {
                 Some((offset, ranges)) => {
@@ -271,6 +306,11 @@ pub mod fsm {
             &self.ranges
         }
 
+        /// The root hash of the request, as resolved by the provider for custom requests
+        pub fn root(&self) -> Hash {
+            self.misc.root
+        }
+
         /// Go into the next state, reading the header
         ///
         /// This requires passing in the hash of the child for validation
@@ -303,6 +343,11 @@ pub mod fsm {
             &self.ranges
         }
 
+        /// The root hash of the request, as resolved by the provider for custom requests
+        pub fn root(&self) -> Hash {
+            self.hash
+        }
+
         /// Go into the next state, reading the header
         ///
         /// For the collection we already know the hash, since it was part of the request
@@ -370,23 +415,8 @@ pub mod fsm {
         pub async fn concatenate_into_vec(
             self,
         ) -> result::Result<(AtEndBlob, Vec<u8>), DecodeError> {
-            let (mut curr, size) = self.next().await?;
-            let mut res = Vec::with_capacity(size as usize);
-            let done = loop {
-                match curr.next().await {
-                    BlobContentNext::More((next, data)) => {
-                        if let BaoContentItem::Leaf(leaf) = data? {
-                            res.extend_from_slice(&leaf.data);
-                        }
-                        curr = next;
-                    }
-                    BlobContentNext::Done(done) => {
-                        // we are done with the root blob
-                        break done;
-                    }
-                }
-            };
-            Ok((done, res))
+            let (curr, _size) = self.next().await?;
+            curr.concatenate_into_vec().await
         }
 
         /// Write the entire blob to a slice writer.
@@ -468,6 +498,33 @@ pub mod fsm {
             self.stream.hash()
         }
 
+        /// Concatenate the rest of the response into a vec
+        ///
+        /// Unlike [`AtBlobHeader::concatenate_into_vec`], this allows checking the size
+        /// before reading the data. The vec grows with the data that is actually received,
+        /// not with the size announced by the provider.
+        pub async fn concatenate_into_vec(
+            self,
+        ) -> result::Result<(AtEndBlob, Vec<u8>), DecodeError> {
+            let mut curr = self;
+            let mut res = Vec::new();
+            let done = loop {
+                match curr.next().await {
+                    BlobContentNext::More((next, data)) => {
+                        if let BaoContentItem::Leaf(leaf) = data? {
+                            res.extend_from_slice(&leaf.data);
+                        }
+                        curr = next;
+                    }
+                    BlobContentNext::Done(done) => {
+                        // we are done with the blob
+                        break done;
+                    }
+                }
+            };
+            Ok((done, res))
+        }
+
         /// Write the entire blob to a slice writer and to an optional outboard.
         ///
         /// The outboard is only written to if the blob is larger than a single
@@ -599,21 +656,63 @@ pub mod fsm {
         bytes_written: u64,
         /// iterator over the ranges of the collection and the children
         ranges_iter: RangesIter,
+        /// the root hash of the request
+        root: Hash,
     }
 }
 
+/// Asks the provider what it has of a blob, without transferring any data.
+///
+/// Opens a new stream on `connection`, so it can be used before or next to get requests on
+/// the same connection.
+pub async fn probe(
+    connection: &quinn::Connection,
+    request: ProbeRequest,
+) -> Result<ProbeResponse, GetResponseError> {
+    request_response(connection, Request::Probe(request)).await
+}
+
+/// Asks the provider for the roots it offers.
+///
+/// Fails with [`ErrorCode::Unauthorized`] if the provider does not allow listing.
+pub async fn list(
+    connection: &quinn::Connection,
+    request: ListRequest,
+) -> Result<ListResponse, GetResponseError> {
+    request_response(connection, Request::List(request)).await
+}
+
+/// Sends `request` on a new stream and reads a single length prefixed response.
+async fn request_response<T: serde::de::DeserializeOwned>(
+    connection: &quinn::Connection,
+    request: Request,
+) -> Result<T, GetResponseError> {
+    let (mut writer, mut reader) = connection.open_bi().await?;
+    let request_bytes = postcard::to_stdvec(&request)?;
+    write_lp(&mut writer, &request_bytes).await?;
+    writer.finish().await?;
+    let mut buffer = BytesMut::new();
+    let response = read_lp(&mut reader, &mut buffer)
+        .await?
+        .context("unexpected EOF when reading response")?;
+    Ok(postcard::from_bytes(&response)?)
+}
+
 /// Error when processing a response
 #[derive(thiserror::Error, Debug)]
 pub enum GetResponseError {
+    /// The provider closed the connection or reset the stream with a known error code
+    #[error("remote: {0}")]
+    Remote(ErrorCode),
     /// Error when opening a stream
     #[error("connection: {0}")]
-    Connection(#[from] quinn::ConnectionError),
+    Connection(quinn::ConnectionError),
     /// Error when writing the handshake or request to the stream
     #[error("write: {0}")]
-    Write(#[from] quinn::WriteError),
+    Write(quinn::WriteError),
     /// Error when reading from the stream
     #[error("read: {0}")]
-    Read(#[from] quinn::ReadError),
+    Read(quinn::ReadError),
     /// Error when decoding, e.g. hash mismatch
     #[error("decode: {0}")]
     Decode(bao_tree::io::DecodeError),
@@ -622,6 +721,33 @@ pub enum GetResponseError {
     Generic(anyhow::Error),
 }
 
+impl From<quinn::ConnectionError> for GetResponseError {
+    fn from(cause: quinn::ConnectionError) -> Self {
+        match ErrorCode::from_connection_error(&cause) {
+            Some(code) => Self::Remote(code),
+            None => Self::Connection(cause),
+        }
+    }
+}
+
+impl From<quinn::WriteError> for GetResponseError {
+    fn from(cause: quinn::WriteError) -> Self {
+        match cause {
+            quinn::WriteError::ConnectionLost(cause) => cause.into(),
+            cause => Self::Write(cause),
+        }
+    }
+}
+
+impl From<quinn::ReadError> for GetResponseError {
+    fn from(cause: quinn::ReadError) -> Self {
+        match ErrorCode::from_read_error(&cause) {
+            Some(code) => Self::Remote(code),
+            None => Self::Read(cause),
+        }
+    }
+}
+
 impl From<postcard::Error> for GetResponseError {
     fn from(cause: postcard::Error) -> Self {
         Self::Generic(cause.into())
@@ -635,13 +761,13 @@ impl From<bao_tree::io::DecodeError> for GetResponseError {
                 // try to downcast to specific quinn errors
                 if let Some(source) = cause.source() {
                     if let Some(error) = source.downcast_ref::<quinn::ConnectionError>() {
-                        return Self::Connection(error.clone());
+                        return error.clone().into();
                     }
                     if let Some(error) = source.downcast_ref::<quinn::ReadError>() {
-                        return Self::Read(error.clone());
+                        return error.clone().into();
                     }
                     if let Some(error) = source.downcast_ref::<quinn::WriteError>() {
-                        return Self::Write(error.clone());
+                        return error.clone().into();
                     }
                 }
                 Self::Generic(cause.into())
@@ -0,0 +1,214 @@
+//! Keyed names for private content.
+//!
+//! Content is addressed by its blake3 hash, so anyone who has or guesses some content can
+//! compute its hash, ask providers whether they have it, and recognize the same content in
+//! different deployments. A provider can instead name its content with a
+//! [`NamespaceKey`]: the name of a blob is the keyed blake3 hash of its root hash, which
+//! differs between keys and can not be computed without the key.
+//!
+//! Names are a [`HashDomain`] of their own. A requester asks for a name with a
+//! [`KeyedGetRequest`](crate::protocol::KeyedGetRequest), and the provider answers with
+//! the data only, never with the root hash the name stands for. The data is still
+//! encoded with the bao tree of its plain root hash, so the requester has to know the
+//! root to verify it, e.g. from a ticket. A provider with a namespace does not answer
+//! requests or probes for plain hashes, and lists its content by name, so its content can
+//! neither be found by guessing nor correlated with other deployments.
+//!
+//! The children of a collection are blobs of the store, so they have names too. The
+//! collection itself lists its children by root hash, which is needed to verify them, so
+//! whoever may fetch a collection learns the root hashes of its children.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock, Weak};
+
+use bao_tree::blake3;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::baomap::{ReadableStore, StoreEvent};
+use crate::Hash;
+
+/// Context for deriving namespace keys, see [`NamespaceKey::derive`].
+const DERIVE_KEY_CONTEXT: &str = "iroh-bytes 2023-08-01 keyed namespace";
+
+/// The domain of the hash in a request or ticket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HashDomain {
+    /// The blake3 hash of the content.
+    #[default]
+    Plain,
+    /// The hash is the root hash of the content, requested by its name in the keyed
+    /// namespace of the provider.
+    Keyed(Hash),
+}
+
+impl HashDomain {
+    /// The name to request the content by, if any.
+    pub fn name(&self) -> Option<Hash> {
+        match self {
+            Self::Plain => None,
+            Self::Keyed(name) => Some(*name),
+        }
+    }
+}
+
+/// The key of a keyed namespace.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceKey([u8; 32]);
+
+impl fmt::Debug for NamespaceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // names can be guessed with the key, so it is not logged
+        f.write_str("NamespaceKey(..)")
+    }
+}
+
+impl NamespaceKey {
+    /// A new random key.
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    /// Derives a key from a secret, e.g. to share one namespace between several stores.
+    pub fn derive(secret: &[u8]) -> Self {
+        Self(blake3::derive_key(DERIVE_KEY_CONTEXT, secret))
+    }
+
+    /// The key from its bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// The bytes of the key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The name of the content with root hash `hash` in this namespace.
+    pub fn name(&self, hash: &Hash) -> Hash {
+        blake3::keyed_hash(&self.0, hash.as_bytes()).into()
+    }
+}
+
+/// Resolves the names of a keyed namespace to root hashes.
+pub trait NameResolver: Send + Sync + fmt::Debug + 'static {
+    /// The root hash named `name`, `None` if there is no such content.
+    fn resolve(&self, name: Hash) -> BoxFuture<'static, anyhow::Result<Option<Hash>>>;
+}
+
+/// Resolves the names of the blobs in a store.
+///
+/// The names of all blobs are computed when the resolver is created. After that a
+/// background task keeps the index up to date with the [`StoreEvent`]s of the store, so
+/// resolving a name is a map lookup, whether the name exists or not.
+///
+/// The task stops when the last clone of the resolver is dropped.
+#[derive(Clone)]
+pub struct StoreNames {
+    key: NamespaceKey,
+    names: Arc<RwLock<HashMap<Hash, Hash>>>,
+}
+
+impl fmt::Debug for StoreNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreNames")
+            .field("names", &self.names.read().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl StoreNames {
+    /// Resolves the names of the blobs in `db` in the namespace of `key`.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn new<D: ReadableStore>(key: NamespaceKey, db: D) -> Self {
+        // subscribe before listing, so no blob added in between is missed
+        let events = db.subscribe();
+        let names = Arc::new(RwLock::new(all_names(&key, &db)));
+        tokio::spawn(update_names(
+            key.clone(),
+            db,
+            events,
+            Arc::downgrade(&names),
+        ));
+        Self { key, names }
+    }
+
+    /// The key of the namespace.
+    pub fn key(&self) -> &NamespaceKey {
+        &self.key
+    }
+
+    fn lookup(&self, name: &Hash) -> Option<Hash> {
+        self.names.read().unwrap().get(name).copied()
+    }
+}
+
+/// The names of all blobs in `db`.
+fn all_names(key: &NamespaceKey, db: &impl ReadableStore) -> HashMap<Hash, Hash> {
+    db.blobs()
+        .chain(db.partial_blobs())
+        .map(|hash| (key.name(&hash), hash))
+        .collect()
+}
+
+/// Apply the changes to the store to `names`, until the index is dropped.
+async fn update_names<D: ReadableStore>(
+    key: NamespaceKey,
+    db: D,
+    mut events: broadcast::Receiver<StoreEvent>,
+    names: Weak<RwLock<HashMap<Hash, Hash>>>,
+) {
+    loop {
+        let event = events.recv().await;
+        let Some(names) = names.upgrade() else {
+            break;
+        };
+        match event {
+            Ok(StoreEvent::Added(hash)) => {
+                let name = key.name(&hash);
+                names.write().unwrap().insert(name, hash);
+            }
+            Ok(StoreEvent::Removed(hash)) => {
+                let name = key.name(&hash);
+                names.write().unwrap().remove(&name);
+            }
+            Ok(StoreEvent::PinsChanged) => {}
+            Err(RecvError::Lagged(_)) => {
+                // skip the queued events, they are older than the listing
+                tracing::debug!("name index lagged behind the store, rebuilding");
+                events = events.resubscribe();
+                // lookups keep using the old index until the new one is complete
+                let all = all_names(&key, &db);
+                *names.write().unwrap() = all;
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+impl NameResolver for StoreNames {
+    fn resolve(&self, name: Hash) -> BoxFuture<'static, anyhow::Result<Option<Hash>>> {
+        let hash = self.lookup(&name);
+        futures::future::ready(Ok(hash)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let hash = Hash::new(b"private");
+        let key = NamespaceKey::derive(b"secret");
+        assert_eq!(key, NamespaceKey::derive(b"secret"));
+        assert_eq!(key.name(&hash), key.name(&hash));
+        assert_ne!(key.name(&hash), hash);
+        assert_ne!(key.name(&hash), NamespaceKey::derive(b"other").name(&hash));
+        assert_ne!(key.name(&hash), NamespaceKey::generate().name(&hash));
+        assert_eq!(format!("{key:?}"), "NamespaceKey(..)");
+    }
+}
@@ -6,6 +6,7 @@
 pub mod baomap;
 pub mod collection;
 pub mod get;
+pub mod keyed;
 pub mod protocol;
 pub mod provider;
 pub mod util;
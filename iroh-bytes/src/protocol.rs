@@ -82,6 +82,12 @@ pub enum Request {
     Get(GetRequest),
     /// A get request that allows the receiver to create a collection
     CustomGet(CustomGetRequest),
+    /// A request for what the provider has of a blob, without transferring data
+    Probe(ProbeRequest),
+    /// A request for the roots the provider offers, if it allows listing them
+    List(ListRequest),
+    /// A get request for a name in the keyed namespace of the provider
+    KeyedGet(KeyedGetRequest),
 }
 
 impl Request {
@@ -90,6 +96,9 @@ impl Request {
         match self {
             Request::Get(get) => get.token(),
             Request::CustomGet(get) => get.token.as_ref(),
+            Request::Probe(probe) => probe.token.as_ref(),
+            Request::List(list) => list.token.as_ref(),
+            Request::KeyedGet(get) => get.token.as_ref(),
         }
     }
 
@@ -98,6 +107,9 @@ impl Request {
         match &mut self {
             Request::Get(get) => get.token = value,
             Request::CustomGet(get) => get.token = value,
+            Request::Probe(probe) => probe.token = value,
+            Request::List(list) => list.token = value,
+            Request::KeyedGet(get) => get.token = value,
         }
         self
     }
@@ -114,6 +126,109 @@ pub struct CustomGetRequest {
     pub data: Bytes,
 }
 
+/// A request for whether the provider has a blob, its size and the ranges it can serve.
+///
+/// The provider answers with a single length prefixed [`ProbeResponse`].
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct ProbeRequest {
+    /// The hash of the blob
+    pub hash: Hash,
+    /// The optional request token
+    pub token: Option<RequestToken>,
+}
+
+impl ProbeRequest {
+    /// Probe for the blob `hash`
+    pub fn new(hash: Hash) -> Self {
+        Self { hash, token: None }
+    }
+}
+
+/// The response to a [`ProbeRequest`]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct ProbeResponse {
+    /// The size of the blob, `None` if the provider does not have it
+    ///
+    /// For partial blobs this is the size the provider expects, which is not verified yet.
+    pub size: Option<u64>,
+    /// True if the provider has the complete blob
+    pub complete: bool,
+    /// The chunk ranges the provider can serve
+    pub ranges: RangeSpec,
+}
+
+impl ProbeResponse {
+    /// The response of a provider that does not have the blob
+    pub fn not_found() -> Self {
+        Self {
+            size: None,
+            complete: false,
+            ranges: RangeSpec::EMPTY,
+        }
+    }
+}
+
+/// A request for the roots a provider offers, with their names and sizes.
+///
+/// Providers refuse it unless they opted in to listing their content. The provider answers
+/// with a single length prefixed [`ListResponse`].
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct ListRequest {
+    /// The optional request token
+    pub token: Option<RequestToken>,
+}
+
+/// The response to a [`ListRequest`]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct ListResponse {
+    /// The roots the provider offers
+    pub entries: Vec<ListEntry>,
+}
+
+/// A root offered by a provider
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct ListEntry {
+    /// The hash of the blob or collection
+    pub hash: Hash,
+    /// The name the provider gave it, if any
+    pub name: Option<String>,
+    /// The size of the blob, or the total size of the blobs of a collection
+    pub size: u64,
+    /// True if this is a collection
+    pub collection: bool,
+}
+
+/// A get request for a name in the keyed namespace of the provider, see [`crate::keyed`].
+///
+/// The provider answers with the data, like for a [`GetRequest`] for the root hash the
+/// name stands for. It does not send the root hash, so the requester has to know it to
+/// verify the data, see [`crate::get::fsm::start_named`].
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct KeyedGetRequest {
+    /// The name of the blob or collection
+    pub name: Hash,
+    /// The range of data to request, like for a [`GetRequest`]
+    pub ranges: RangeSpecSeq,
+    /// The optional request token
+    pub token: Option<RequestToken>,
+}
+
+impl KeyedGetRequest {
+    /// Request `ranges` of the content named `name`
+    pub fn new(name: Hash, ranges: RangeSpecSeq) -> Self {
+        Self {
+            name,
+            ranges,
+            token: None,
+        }
+    }
+
+    /// Set the request token
+    pub fn with_token(self, token: Option<RequestToken>) -> Self {
+        Self { token, ..self }
+    }
+}
+
 /// Currently all requests are get requests. But that won't always be the case.
 ///
 /// Hence this type alias that will at some point be replaced by a proper enum.
@@ -290,3 +405,124 @@ impl TryFrom<VarInt> for Closed {
         }
     }
 }
+
+/// Application error codes of the provider, sent when closing a connection or resetting a
+/// response stream.
+///
+/// This is the registry of all error codes used in QUIC `CONNECTION_CLOSE` and
+/// `RESET_STREAM` frames by the provider, so that the getter can tell why a request failed
+/// and decide whether retrying makes sense. The values do not overlap, the same code means
+/// the same thing in both frames. `0` is not an error, and `2` is used by [`Closed`] to
+/// stop request streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, thiserror::Error)]
+#[repr(u32)]
+pub enum ErrorCode {
+    /// The provider is shutting down, same as [`Closed::ProviderTerminating`].
+    #[error("provider shutting down")]
+    ShuttingDown = 1,
+    /// The request was not authorized, e.g. because of a missing or wrong request token.
+    #[error("unauthorized")]
+    Unauthorized = 3,
+    /// The provider does not have the requested hash.
+    #[error("not found")]
+    NotFound = 4,
+    /// The provider refused the request or connection because of its limits, or because
+    /// it is low on disk space. The request can be retried later.
+    #[error("rate limited")]
+    RateLimited = 5,
+    /// The provider failed to answer the request because of an error on its side.
+    #[error("internal error")]
+    Internal = 6,
+    /// The request took longer than the provider allows.
+    #[error("request timed out")]
+    RequestTimeout = 7,
+    /// The requester did not read the response for longer than the provider allows.
+    #[error("requester stalled")]
+    Stalled = 8,
+    /// The request token has expired, e.g. the token of a ticket with an expiry.
+    #[error("token expired")]
+    TokenExpired = 9,
+}
+
+impl ErrorCode {
+    /// The close reason as bytes. This is a valid utf8 string describing the reason.
+    pub fn reason(&self) -> &'static [u8] {
+        match self {
+            ErrorCode::ShuttingDown => b"provider shutting down",
+            ErrorCode::Unauthorized => b"unauthorized",
+            ErrorCode::NotFound => b"not found",
+            ErrorCode::RateLimited => b"rate limited",
+            ErrorCode::Internal => b"internal error",
+            ErrorCode::RequestTimeout => b"request timed out",
+            ErrorCode::Stalled => b"requester stalled",
+            ErrorCode::TokenExpired => b"token expired",
+        }
+    }
+
+    /// The error code of a connection closed by the provider, if it is a known one.
+    pub fn from_connection_error(error: &quinn::ConnectionError) -> Option<Self> {
+        match error {
+            quinn::ConnectionError::ApplicationClosed(close) => close.error_code.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// The error code of a stream reset or a connection closed by the provider, if it is
+    /// a known one.
+    pub fn from_read_error(error: &quinn::ReadError) -> Option<Self> {
+        match error {
+            quinn::ReadError::Reset(code) => (*code).try_into().ok(),
+            quinn::ReadError::ConnectionLost(error) => Self::from_connection_error(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorCode> for VarInt {
+    fn from(code: ErrorCode) -> Self {
+        VarInt::from_u32(code as u32)
+    }
+}
+
+impl TryFrom<VarInt> for ErrorCode {
+    type Error = UnknownErrorCode;
+
+    fn try_from(value: VarInt) -> std::result::Result<Self, Self::Error> {
+        match value.into_inner() {
+            1 => Ok(Self::ShuttingDown),
+            3 => Ok(Self::Unauthorized),
+            4 => Ok(Self::NotFound),
+            5 => Ok(Self::RateLimited),
+            6 => Ok(Self::Internal),
+            7 => Ok(Self::RequestTimeout),
+            8 => Ok(Self::Stalled),
+            9 => Ok(Self::TokenExpired),
+            val => Err(UnknownErrorCode(val)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_code_roundtrip() {
+        for code in [
+            ErrorCode::ShuttingDown,
+            ErrorCode::Unauthorized,
+            ErrorCode::NotFound,
+            ErrorCode::RateLimited,
+            ErrorCode::Internal,
+            ErrorCode::RequestTimeout,
+            ErrorCode::Stalled,
+            ErrorCode::TokenExpired,
+        ] {
+            assert_eq!(ErrorCode::try_from(VarInt::from(code)).unwrap(), code);
+        }
+        let closed: VarInt = Closed::ProviderTerminating.into();
+        assert_eq!(closed, ErrorCode::ShuttingDown.into());
+        assert!(ErrorCode::try_from(VarInt::from(Closed::RequestReceived)).is_err());
+        assert!(ErrorCode::try_from(VarInt::from_u32(0)).is_err());
+    }
+}
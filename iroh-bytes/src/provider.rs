@@ -1,25 +1,41 @@
 //! The server side API
 use std::fmt::Debug;
-use std::sync::Arc;
-use std::time::Duration;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
 
 use anyhow::{ensure, Context, Result};
 use bao_tree::io::fsm::{encode_ranges_validated, Outboard};
+use bao_tree::ChunkNum;
 use bytes::{Bytes, BytesMut};
-use futures::future::BoxFuture;
+use futures::future::{self, poll_fn, BoxFuture, Either};
+use futures::{Future, FutureExt};
+use range_collections::RangeSet2;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWrite;
-use tracing::{debug, debug_span, warn};
+use tracing::{debug, debug_span, error, warn};
 use tracing_futures::Instrument;
 
 use crate::baomap::*;
 use crate::collection::CollectionParser;
+use crate::keyed::NameResolver;
 use crate::protocol::{
-    read_lp, write_lp, CustomGetRequest, GetRequest, RangeSpec, Request, RequestToken,
+    read_lp, write_lp, CustomGetRequest, ErrorCode, GetRequest, KeyedGetRequest, ListEntry,
+    ListRequest, ListResponse, ProbeRequest, ProbeResponse, RangeSpec, Request, RequestToken,
 };
-use crate::util::RpcError;
+use crate::util::{panic_message, RequestId, RpcError};
 use crate::Hash;
 
+mod scheduler;
+
+use self::scheduler::Slot;
+pub use self::scheduler::{
+    EqualPriority, FairScheduler, PriorityHandler, DEFAULT_QUANTUM, DEFAULT_TIME_SLICE,
+};
+
 /// Events emitted by the provider informing about the current status.
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -38,7 +54,7 @@ pub enum Event {
         /// An unique connection id.
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
-        request_id: u64,
+        request_id: RequestId,
         /// Token requester gve for this request, if any
         token: Option<RequestToken>,
         /// The hash for which the client wants to receive data.
@@ -49,7 +65,7 @@ pub enum Event {
         /// An unique connection id.
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
-        request_id: u64,
+        request_id: RequestId,
         /// Token requester gve for this request, if any
         token: Option<RequestToken>,
         /// The size of the custom get request.
@@ -60,7 +76,7 @@ pub enum Event {
         /// An unique connection id.
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
-        request_id: u64,
+        request_id: RequestId,
         /// The number of blobs in the collection.
         num_blobs: Option<u64>,
         /// The total blob size of the data.
@@ -71,14 +87,14 @@ pub enum Event {
         /// An unique connection id.
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
-        request_id: u64,
+        request_id: RequestId,
     },
     /// A blob in a collection was transferred.
     TransferBlobCompleted {
         /// An unique connection id.
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
-        request_id: u64,
+        request_id: RequestId,
         /// The hash of the blob
         hash: Hash,
         /// The index of the blob in the collection.
@@ -91,7 +107,19 @@ pub enum Event {
         /// The quic connection id.
         connection_id: u64,
         /// An identifier uniquely identifying this request.
-        request_id: u64,
+        request_id: RequestId,
+    },
+    /// The handler of a request panicked.
+    ///
+    /// The stream of the request was reset with [`ErrorCode::Internal`], other requests
+    /// and connections are served as usual.
+    RequestPanicked {
+        /// The quic connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this request.
+        request_id: RequestId,
+        /// The message of the panic.
+        message: String,
     },
 }
 
@@ -121,6 +149,15 @@ pub enum ProvideProgress {
         /// The hash of the entry.
         hash: Hash,
     },
+    /// Item `id` was already in the store, so adding it took up no new space.
+    ///
+    /// This comes after `Done`, and is omitted for new data.
+    Deduplicated {
+        /// The unique id of the entry.
+        id: u64,
+        /// The size of the entry in bytes.
+        size: u64,
+    },
     /// We are done with the whole operation.
     AllDone {
         /// The hash of the created collection.
@@ -135,6 +172,11 @@ pub enum ProvideProgress {
 /// Progress updates for the provide operation.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ShareProgress {
+    /// The download started, this is always the first message in the stream.
+    Started {
+        /// The id of the request, it is part of all log messages of the download.
+        request_id: RequestId,
+    },
     /// A new connection was established.
     Connected,
     /// An item was found with hash `hash`, from now on referred to via `id`.
@@ -197,6 +239,17 @@ pub enum ShareProgress {
         /// The offset of the progress, in bytes.
         offset: u64,
     },
+    /// An attempt failed, and the download will be retried after `delay`.
+    ///
+    /// Data that was already received is kept, so the next attempt resumes.
+    Retry {
+        /// The attempt that failed, starting at 1.
+        attempt: u32,
+        /// The error that caused the attempt to fail.
+        cause: String,
+        /// The time until the next attempt.
+        delay: Duration,
+    },
     /// We got an error and need to abort.
     Abort(RpcError),
     /// We are done with the whole operation.
@@ -205,7 +258,8 @@ pub enum ShareProgress {
 
 /// hook into the request handling to process authorization by examining
 /// the request and any given token. Any error returned will abort the request,
-/// and the error will be sent to the requester.
+/// and the response stream is reset with [`ErrorCode::Unauthorized`], or with the
+/// [`ErrorCode`] the error contains, e.g. [`ErrorCode::RateLimited`].
 pub trait RequestAuthorizationHandler: Send + Sync + Debug + 'static {
     /// Handle the authorization request, given an opaque data blob from the requester.
     fn authorize(
@@ -226,6 +280,30 @@ pub trait CustomGetHandler: Send + Sync + Debug + 'static {
     ) -> BoxFuture<'static, anyhow::Result<GetRequest>>;
 }
 
+/// A handler for list requests, which lists the roots a provider offers.
+pub trait ListHandler: Send + Sync + Debug + 'static {
+    /// The roots to list for a requester with `token`, which is already authorized.
+    fn list(
+        &self,
+        token: Option<RequestToken>,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<ListEntry>>>;
+}
+
+/// A [`ListHandler`] that refuses all list requests.
+///
+/// Listing exposes everything a provider has, so it is disabled unless a provider opts in.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoListHandler;
+
+impl ListHandler for NoListHandler {
+    fn list(
+        &self,
+        _token: Option<RequestToken>,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<ListEntry>>> {
+        async move { Err(ErrorCode::Unauthorized).context("listing is disabled") }.boxed()
+    }
+}
+
 /// Read the request from the getter.
 ///
 /// Will fail if there is an error while reading, if the reader
@@ -255,6 +333,9 @@ pub async fn read_request(mut reader: quinn::RecvStream, buffer: &mut BytesMut)
 /// If a blob from the collection cannot be found in the database, the transfer will gracefully
 /// close the writer, and return with `Ok(SentStatus::NotFound)`.
 ///
+/// For partial entries only the available ranges are sent, so the requester receives less
+/// data than it asked for.
+///
 /// If the transfer does _not_ end in error, the buffer will be empty and the writer is gracefully closed.
 pub async fn transfer_collection<D: Map, E: EventSender, C: CollectionParser>(
     request: GetRequest,
@@ -263,15 +344,17 @@ pub async fn transfer_collection<D: Map, E: EventSender, C: CollectionParser>(
     // Response writer, containing the quinn stream.
     writer: &mut ResponseWriter<E>,
     // the collection to transfer
-    mut outboard: D::Outboard,
-    mut data: D::DataReader,
+    entry: D::Entry,
     collection_parser: C,
 ) -> Result<SentStatus> {
     let hash = request.hash;
+    let mut outboard = entry.outboard().await?;
+    let mut data = entry.data_reader().await?;
 
     // if the request is just for the root, we don't need to deserialize the collection
     let just_root = matches!(request.ranges.single(), Some((0, _)));
     let mut c = if !just_root {
+        ensure!(entry.is_complete(), "collection {hash} is incomplete");
         // use the collection parser to parse the collection
         let (c, stats) = collection_parser.parse(0, &mut data).await?;
         writer
@@ -292,14 +375,9 @@ pub async fn transfer_collection<D: Map, E: EventSender, C: CollectionParser>(
     for (offset, ranges) in request.ranges.iter_non_empty() {
         if offset == 0 {
             debug!("writing ranges '{:?}' of collection {}", ranges, hash);
+            let ranges = available_chunk_ranges::<D>(&entry, ranges).await?;
             // send the root
-            encode_ranges_validated(
-                &mut data,
-                &mut outboard,
-                &ranges.to_chunk_ranges(),
-                &mut writer.inner,
-            )
-            .await?;
+            encode_ranges_validated(&mut data, &mut outboard, &ranges, &mut writer.inner).await?;
             debug!(
                 "finished writing ranges '{:?}' of collection {}",
                 ranges, hash
@@ -313,7 +391,9 @@ pub async fn transfer_collection<D: Map, E: EventSender, C: CollectionParser>(
             }
             if let Some(hash) = c.next().await? {
                 tokio::task::yield_now().await;
-                let (status, size) = send_blob(db, hash, ranges, &mut writer.inner).await?;
+                let serve_partial = writer.serve_partial;
+                let (status, size) =
+                    send_blob(db, hash, ranges, serve_partial, &mut writer.inner).await?;
                 if SentStatus::NotFound == status {
                     writer.inner.finish().await?;
                     return Ok(status);
@@ -348,55 +428,129 @@ pub trait EventSender: Clone + Sync + Send + 'static {
     fn send(&self, event: Event) -> BoxFuture<()>;
 }
 
+/// The default for [`WriteTimeouts::stall`].
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits on how long the provider keeps writing a response.
+///
+/// Requests that exceed a limit are aborted, and the response stream is reset with
+/// [`ErrorCode::RequestTimeout`] or [`ErrorCode::Stalled`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteTimeouts {
+    /// Maximum duration of a single request, from the start of the transfer.
+    ///
+    /// `None` means requests can take arbitrarily long, as long as they make progress.
+    pub request: Option<Duration>,
+    /// Maximum duration a write may be blocked because the requester does not read
+    /// or acknowledge data.
+    ///
+    /// `None` disables stall detection.
+    pub stall: Option<Duration>,
+}
+
+impl Default for WriteTimeouts {
+    fn default() -> Self {
+        Self {
+            request: None,
+            stall: Some(DEFAULT_STALL_TIMEOUT),
+        }
+    }
+}
+
 /// Handle a single connection.
+///
+/// If `serve_partial` is set, requests are also answered from partial entries, with the
+/// ranges that have been verified so far.
+///
+/// Every request is handled in its own task. If a handler panics, the stream of the
+/// request is reset and [`Event::RequestPanicked`] is emitted, while the connection keeps
+/// serving other requests.
+///
+/// If a `scheduler` is given, the responses take turns writing with the responses of all
+/// other connections that share the scheduler.
+///
+/// If `names` is given, the content is only served by its names in the keyed namespace,
+/// and requests for plain hashes are answered as if the content did not exist. See
+/// [`crate::keyed`].
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_connection<D: Map, E: EventSender, C: CollectionParser>(
-    connecting: quinn::Connecting,
+    connecting: impl Future<Output = Result<quinn::Connection, quinn::ConnectionError>>,
     db: D,
     events: E,
     collection_parser: C,
     custom_get_handler: Arc<dyn CustomGetHandler>,
+    list_handler: Arc<dyn ListHandler>,
+    names: Option<Arc<dyn NameResolver>>,
     authorization_handler: Arc<dyn RequestAuthorizationHandler>,
+    timeouts: WriteTimeouts,
+    scheduler: Option<FairScheduler>,
+    serve_partial: bool,
     rt: crate::util::runtime::Handle,
 ) {
-    let remote_addr = connecting.remote_address();
     let connection = match connecting.await {
         Ok(conn) => conn,
         Err(err) => {
-            warn!(%remote_addr, "Error connecting: {err:#}");
+            warn!("Error connecting: {err:#}");
             return;
         }
     };
+    let remote_addr = connection.remote_address();
     let connection_id = connection.stable_id() as u64;
     let span = debug_span!("connection", connection_id, %remote_addr);
     async move {
         while let Ok((writer, reader)) = connection.accept_bi().await {
-            // The stream ID index is used to identify this request.  Requests only arrive in
-            // bi-directional RecvStreams initiated by the client, so this uniquely identifies them.
-            let request_id = reader.id().index();
-            let span = debug_span!("stream", stream_id = %request_id);
+            // Requests only arrive in bi-directional RecvStreams initiated by the client,
+            // every stream is a new request.
+            let request_id = RequestId::generate();
+            let span = debug_span!("stream", stream_id = %reader.id().index(), %request_id);
             let writer = ResponseWriter {
                 connection_id,
+                request_id,
                 events: events.clone(),
-                inner: writer,
+                inner: SharedSendStream::new(writer),
+                timeouts,
+                serve_partial,
             };
             events.send(Event::ClientConnected { connection_id }).await;
             let db = db.clone();
             let custom_get_handler = custom_get_handler.clone();
+            let list_handler = list_handler.clone();
+            let names = names.clone();
             let authorization_handler = authorization_handler.clone();
             let collection_parser = collection_parser.clone();
+            let events = events.clone();
+            let scheduler = scheduler.clone();
             rt.local_pool().spawn_pinned(|| {
                 async move {
-                    if let Err(err) = handle_stream(
+                    let stream = writer.inner.clone();
+                    let res = AssertUnwindSafe(handle_stream(
                         db,
                         reader,
                         writer,
                         custom_get_handler,
+                        list_handler,
+                        names,
                         authorization_handler,
                         collection_parser,
-                    )
-                    .await
-                    {
-                        warn!("error: {err:#?}",);
+                        scheduler,
+                    ))
+                    .catch_unwind()
+                    .await;
+                    match res {
+                        Ok(Ok(())) => {}
+                        Ok(Err(err)) => warn!("error: {err:#?}",),
+                        Err(payload) => {
+                            let message = panic_message(&*payload);
+                            error!("request handler panicked: {message}");
+                            stream.reset(ErrorCode::Internal);
+                            events
+                                .send(Event::RequestPanicked {
+                                    connection_id,
+                                    request_id,
+                                    message,
+                                })
+                                .await;
+                        }
                     }
                 }
                 .instrument(span)
@@ -407,13 +561,17 @@ pub async fn handle_connection<D: Map, E: EventSender, C: CollectionParser>(
     .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_stream<D: Map, E: EventSender, C: CollectionParser>(
     db: D,
     reader: quinn::RecvStream,
     writer: ResponseWriter<E>,
     custom_get_handler: Arc<dyn CustomGetHandler>,
+    list_handler: Arc<dyn ListHandler>,
+    names: Option<Arc<dyn NameResolver>>,
     authorization_handler: Arc<dyn RequestAuthorizationHandler>,
     collection_parser: C,
+    scheduler: Option<FairScheduler>,
 ) -> Result<()> {
     let mut in_buffer = BytesMut::with_capacity(1024);
 
@@ -433,16 +591,60 @@ async fn handle_stream<D: Map, E: EventSender, C: CollectionParser>(
         .authorize(request.token().cloned(), &request)
         .await
     {
+        writer
+            .inner
+            .reset(error_code(&e).unwrap_or(ErrorCode::Unauthorized));
         writer.notify_transfer_aborted().await;
         return Err(e);
     }
+    if let Some(scheduler) = scheduler {
+        writer.inner.schedule(scheduler.register(&request));
+    }
 
-    match request {
+    let stream = writer.inner.clone();
+    let res = match request {
+        Request::Get(_) | Request::Probe(_) if names.is_some() => {
+            // content in a keyed namespace can only be found by its name
+            debug!("refusing request for a plain hash");
+            writer.notify_transfer_aborted().await;
+            writer.inner.reset(ErrorCode::NotFound);
+            Ok(())
+        }
         Request::Get(request) => handle_get(db, request, collection_parser, writer).await,
         Request::CustomGet(request) => {
             handle_custom_get(db, request, writer, custom_get_handler, collection_parser).await
         }
+        Request::Probe(request) => handle_probe(db, request, writer).await,
+        Request::List(request) => handle_list(request, writer, list_handler).await,
+        Request::KeyedGet(request) => {
+            handle_keyed_get(db, request, writer, names, collection_parser).await
+        }
+    };
+    if let Err(e) = &res {
+        // a no-op if the stream was already reset with a more specific code
+        stream.reset(error_code(e).unwrap_or(ErrorCode::Internal));
     }
+    res
+}
+
+/// The [`ErrorCode`] in the chain of causes of an error, if any.
+///
+/// Handlers can return an [`ErrorCode`], e.g. [`ErrorCode::RateLimited`], to choose
+/// the code the response stream is reset with. A store that refused a write with
+/// [`InsufficientSpace`] maps to [`ErrorCode::RateLimited`], so that the requester
+/// retries later instead of giving up.
+fn error_code(error: &anyhow::Error) -> Option<ErrorCode> {
+    error.chain().find_map(|cause| {
+        if let Some(code) = cause.downcast_ref::<ErrorCode>() {
+            return Some(*code);
+        }
+        let insufficient_space = cause.is::<InsufficientSpace>()
+            || cause
+                .downcast_ref::<io::Error>()
+                .and_then(InsufficientSpace::from_io_error)
+                .is_some();
+        insufficient_space.then_some(ErrorCode::RateLimited)
+    })
 }
 async fn handle_custom_get<E: EventSender, D: Map, C: CollectionParser>(
     db: D,
@@ -471,6 +673,76 @@ async fn handle_custom_get<E: EventSender, D: Map, C: CollectionParser>(
     handle_get(db, request, collection_parser, writer).await
 }
 
+/// Resolve the name of a keyed get request, and handle it like a normal get request.
+///
+/// Unlike for a custom get request, the resolved root hash is not sent to the requester.
+async fn handle_keyed_get<E: EventSender, D: Map, C: CollectionParser>(
+    db: D,
+    request: KeyedGetRequest,
+    writer: ResponseWriter<E>,
+    names: Option<Arc<dyn NameResolver>>,
+    collection_parser: C,
+) -> Result<()> {
+    debug!(name = %request.name, "received keyed get request");
+    let hash = match names {
+        Some(names) => names.resolve(request.name).await?,
+        None => None,
+    };
+    let Some(hash) = hash else {
+        debug!("unknown name {}", request.name);
+        writer.notify_transfer_aborted().await;
+        writer.inner.reset(ErrorCode::NotFound);
+        return Ok(());
+    };
+    let request = GetRequest::new(hash, request.ranges).with_token(request.token);
+    handle_get(db, request, collection_parser, writer).await
+}
+
+/// Answer a probe request with what the store has of the blob.
+///
+/// Partial entries are only reported if `serve_partial` is set, like for get requests.
+async fn handle_probe<D: Map, E: EventSender>(
+    db: D,
+    request: ProbeRequest,
+    writer: ResponseWriter<E>,
+) -> Result<()> {
+    debug!(hash = %request.hash, "received probe");
+    let response = match db
+        .get(&request.hash)
+        .filter(|entry| writer.serve_partial || entry.is_complete())
+    {
+        Some(entry) => {
+            let ranges = available_chunk_ranges::<D>(&entry, &RangeSpec::all()).await?;
+            ProbeResponse {
+                size: Some(entry.size()),
+                complete: entry.is_complete(),
+                ranges: RangeSpec::new(ranges),
+            }
+        }
+        None => ProbeResponse::not_found(),
+    };
+    let data = postcard::to_stdvec(&response)?;
+    let mut stream = writer.inner;
+    write_lp(&mut stream, &data).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Answer a list request with the entries of the list handler.
+async fn handle_list<E: EventSender>(
+    request: ListRequest,
+    writer: ResponseWriter<E>,
+    list_handler: Arc<dyn ListHandler>,
+) -> Result<()> {
+    debug!("received list request");
+    let entries = list_handler.list(request.token).await?;
+    let data = postcard::to_stdvec(&ListResponse { entries })?;
+    let mut stream = writer.inner;
+    write_lp(&mut stream, &data).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
 /// Handle a single standard get request.
 pub async fn handle_get<D: Map, E: EventSender, C: CollectionParser>(
     db: D,
@@ -491,24 +763,42 @@ pub async fn handle_get<D: Map, E: EventSender, C: CollectionParser>(
         .await;
 
     // 4. Attempt to find hash
-    match db.get(&hash) {
+    let serve_partial = writer.serve_partial;
+    match db
+        .get(&hash)
+        .filter(|entry| serve_partial || entry.is_complete())
+    {
         // Collection or blob request
         Some(entry) => {
-            // 5. Transfer data!
-            match transfer_collection(
-                request,
-                &db,
-                &mut writer,
-                entry.outboard().await?,
-                entry.data_reader().await?,
-                collection_parser,
-            )
-            .await
-            {
+            // 5. Transfer data, until the requester stops the stream or a timeout expires
+            let stream = writer.inner.clone();
+            let timeouts = writer.timeouts;
+            let res = {
+                let transfer =
+                    transfer_collection(request, &db, &mut writer, entry, collection_parser);
+                let stopped = stream.stopped();
+                let watchdog = stream.watchdog(timeouts);
+                futures::pin_mut!(transfer, stopped, watchdog);
+                // the transfer is dropped when the select completes, including pending
+                // reads from the store
+                match future::select(transfer, future::select(stopped, watchdog)).await {
+                    Either::Left((res, _)) => res,
+                    Either::Right((Either::Left((code, _)), _)) => {
+                        debug!("request cancelled by requester: {:?}", code);
+                        Ok(SentStatus::Cancelled)
+                    }
+                    Either::Right((Either::Right((code, _)), _)) => {
+                        warn!("aborting request: {}", code);
+                        stream.reset(code);
+                        Err(code.into())
+                    }
+                }
+            };
+            match res {
                 Ok(SentStatus::Sent) => {
                     writer.notify_transfer_completed().await;
                 }
-                Ok(SentStatus::NotFound) => {
+                Ok(SentStatus::NotFound) | Ok(SentStatus::Cancelled) => {
                     writer.notify_transfer_aborted().await;
                 }
                 Err(e) => {
@@ -522,19 +812,145 @@ pub async fn handle_get<D: Map, E: EventSender, C: CollectionParser>(
         None => {
             debug!("not found {}", hash);
             writer.notify_transfer_aborted().await;
-            writer.inner.finish().await?;
+            writer.inner.reset(ErrorCode::NotFound);
         }
     };
 
     Ok(())
 }
 
+/// A [`quinn::SendStream`] that is shared between the request handler writing to it
+/// and the handler waiting for the requester to stop the stream.
+///
+/// The lock is only held for the duration of a poll. If the response is scheduled, every
+/// write waits for its turn in the [`FairScheduler`].
+#[derive(Debug, Clone)]
+struct SharedSendStream(Arc<Mutex<SendStreamState>>);
+
+#[derive(Debug)]
+struct SendStreamState {
+    stream: quinn::SendStream,
+    // when the current write started to be blocked, if it is blocked
+    blocked_since: Option<Instant>,
+    slot: Option<Slot>,
+}
+
+impl SharedSendStream {
+    fn new(stream: quinn::SendStream) -> Self {
+        Self(Arc::new(Mutex::new(SendStreamState {
+            stream,
+            blocked_since: None,
+            slot: None,
+        })))
+    }
+
+    fn schedule(&self, slot: Slot) {
+        self.0.lock().unwrap().slot = Some(slot);
+    }
+
+    fn reset(&self, code: ErrorCode) {
+        // the lock is poisoned if a handler panicked while holding it, the stream is still
+        // usable for a reset
+        let mut state = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        // the stream might already be finished or reset
+        state.stream.reset(code.into()).ok();
+    }
+
+    async fn finish(&self) -> Result<(), quinn::WriteError> {
+        poll_fn(|cx| self.0.lock().unwrap().stream.poll_finish(cx)).await
+    }
+
+    /// Completes when the requester stops the stream or the connection is lost.
+    async fn stopped(&self) -> Result<quinn::VarInt, quinn::StoppedError> {
+        poll_fn(|cx| self.0.lock().unwrap().stream.poll_stopped(cx)).await
+    }
+
+    /// Completes when one of the timeouts expires.
+    ///
+    /// A stall is detected at the latest twice the stall timeout after a write got blocked.
+    async fn watchdog(&self, timeouts: WriteTimeouts) -> ErrorCode {
+        let start = Instant::now();
+        loop {
+            let now = Instant::now();
+            let mut next = None;
+            if let Some(timeout) = timeouts.request {
+                let deadline = start + timeout;
+                if now >= deadline {
+                    return ErrorCode::RequestTimeout;
+                }
+                next = Some(deadline);
+            }
+            if let Some(timeout) = timeouts.stall {
+                let blocked_since = self.0.lock().unwrap().blocked_since;
+                let deadline = blocked_since.unwrap_or(now) + timeout;
+                if now >= deadline {
+                    return ErrorCode::Stalled;
+                }
+                next = Some(next.map_or(deadline, |next: Instant| next.min(deadline)));
+            }
+            match next {
+                Some(next) => tokio::time::sleep_until(next.into()).await,
+                None => future::pending().await,
+            }
+        }
+    }
+}
+
+impl SendStreamState {
+    fn track_blocked<T>(&mut self, res: Poll<T>) -> Poll<T> {
+        if res.is_pending() {
+            self.blocked_since.get_or_insert_with(Instant::now);
+        } else {
+            self.blocked_since = None;
+        }
+        res
+    }
+}
+
+impl AsyncWrite for SharedSendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut guard = self.0.lock().unwrap();
+        let state = &mut *guard;
+        // waiting for a turn is not a stall of the requester
+        if let Some(slot) = &mut state.slot {
+            futures::ready!(slot.poll_acquire(cx));
+        }
+        let res = Pin::new(&mut state.stream).poll_write(cx, buf);
+        if let Some(slot) = &state.slot {
+            match &res {
+                Poll::Ready(Ok(n)) => slot.record(*n),
+                // the requester does not accept data, let the others write meanwhile
+                Poll::Pending => slot.release(),
+                Poll::Ready(Err(_)) => {}
+            }
+        }
+        state.track_blocked(res)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0.lock().unwrap().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.0.lock().unwrap();
+        let res = Pin::new(&mut state.stream).poll_shutdown(cx);
+        state.track_blocked(res)
+    }
+}
+
 /// A helper struct that combines a quinn::SendStream with auxiliary information
 #[derive(Debug)]
 pub struct ResponseWriter<E> {
-    inner: quinn::SendStream,
+    inner: SharedSendStream,
     events: E,
     connection_id: u64,
+    request_id: RequestId,
+    timeouts: WriteTimeouts,
+    serve_partial: bool,
 }
 
 impl<E: EventSender> ResponseWriter<E> {
@@ -542,8 +958,8 @@ impl<E: EventSender> ResponseWriter<E> {
         self.connection_id
     }
 
-    fn request_id(&self) -> u64 {
-        self.inner.id().index()
+    fn request_id(&self) -> RequestId {
+        self.request_id
     }
 
     async fn notify_transfer_completed(&self) {
@@ -572,24 +988,31 @@ pub enum SentStatus {
     Sent,
     /// The requested data was not found
     NotFound,
+    /// The requester stopped the stream before all data was sent
+    Cancelled,
 }
 
-/// Send a
+/// Send the requested ranges of a blob.
+///
+/// Partial entries are only sent if `serve_partial` is set, and then only their
+/// available ranges. Otherwise they are treated as not found.
 pub async fn send_blob<D: Map, W: AsyncWrite + Unpin + Send + 'static>(
     db: &D,
     name: Hash,
     ranges: &RangeSpec,
+    serve_partial: bool,
     writer: &mut W,
 ) -> Result<(SentStatus, u64)> {
     match db.get(&name) {
-        Some(entry) => {
+        Some(entry) if serve_partial || entry.is_complete() => {
+            let ranges = available_chunk_ranges::<D>(&entry, ranges).await?;
             let outboard = entry.outboard().await?;
             let size = outboard.tree().size().0;
             let mut file_reader = entry.data_reader().await?;
             let res = bao_tree::io::fsm::encode_ranges_validated(
                 &mut file_reader,
                 outboard,
-                &ranges.to_chunk_ranges(),
+                &ranges,
                 writer,
             )
             .await;
@@ -604,3 +1027,42 @@ pub async fn send_blob<D: Map, W: AsyncWrite + Unpin + Send + 'static>(
         }
     }
 }
+
+/// The requested ranges of an entry, limited to the ranges that are available.
+async fn available_chunk_ranges<D: Map>(
+    entry: &D::Entry,
+    ranges: &RangeSpec,
+) -> io::Result<RangeSet2<ChunkNum>> {
+    let ranges = ranges.to_chunk_ranges();
+    if entry.is_complete() {
+        return Ok(ranges);
+    }
+    let available = entry.available_ranges().await?;
+    debug!(
+        "serving partial entry {}, available {:?}",
+        Hash::from(entry.hash()),
+        available
+    );
+    Ok(ranges.intersection(&available))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_codes() {
+        let err = anyhow::Error::from(ErrorCode::NotFound).context("lookup");
+        assert_eq!(error_code(&err), Some(ErrorCode::NotFound));
+        let space = InsufficientSpace {
+            free: 1,
+            low_watermark: 2,
+        };
+        let err = anyhow::Error::from(space);
+        assert_eq!(error_code(&err), Some(ErrorCode::RateLimited));
+        let err = anyhow::Error::from(io::Error::from(space)).context("import");
+        assert_eq!(error_code(&err), Some(ErrorCode::RateLimited));
+        let err = anyhow::anyhow!("something else");
+        assert_eq!(error_code(&err), None);
+    }
+}
@@ -0,0 +1,331 @@
+//! Fair scheduling of the responses of a provider.
+//!
+//! Responses to different requesters share the uplink of the provider, and a single large
+//! transfer can keep it busy for a long time while small fetches wait behind it. The
+//! [`FairScheduler`] hands out turns to write: responses that want to write queue up, and
+//! the response at the front may write up to a quantum of bytes, multiplied by its
+//! priority, before the turn passes to the next one. Small responses therefore complete
+//! after a few turns, no matter how many bytes the large transfers still have to send.
+//!
+//! A response that does not use its turn, because it is reading from the store or its
+//! requester does not accept data, must not hold up the others. A turn therefore ends
+//! when the stream of the response is blocked, and expires after a time slice.
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use tokio::time::{Instant, Sleep};
+
+use crate::protocol::Request;
+
+/// Default number of bytes a response with priority 1 may write per turn.
+pub const DEFAULT_QUANTUM: usize = 64 * 1024;
+
+/// Default time after which an unused turn passes to the next response.
+pub const DEFAULT_TIME_SLICE: Duration = Duration::from_millis(10);
+
+/// Assigns priorities to responses.
+pub trait PriorityHandler: Send + Sync + Debug + 'static {
+    /// The priority of the response to `request`.
+    ///
+    /// A response with priority 2 writes twice as many bytes per turn as one with priority
+    /// 1. A priority of 0 is treated as 1.
+    fn priority(&self, request: &Request) -> u32;
+}
+
+/// Gives all responses the same priority.
+#[derive(Debug)]
+pub struct EqualPriority;
+
+impl PriorityHandler for EqualPriority {
+    fn priority(&self, _request: &Request) -> u32 {
+        1
+    }
+}
+
+/// Round-robin scheduler for the chunk writes of concurrent responses.
+///
+/// Cloning the scheduler gives another handle to the same queue of responses.
+#[derive(Debug, Clone)]
+pub struct FairScheduler {
+    state: Arc<Mutex<State>>,
+    priorities: Arc<dyn PriorityHandler>,
+    quantum: usize,
+    time_slice: Duration,
+}
+
+impl Default for FairScheduler {
+    fn default() -> Self {
+        Self {
+            state: Default::default(),
+            priorities: Arc::new(EqualPriority),
+            quantum: DEFAULT_QUANTUM,
+            time_slice: DEFAULT_TIME_SLICE,
+        }
+    }
+}
+
+impl FairScheduler {
+    /// Sets the number of bytes a response with priority 1 may write per turn.
+    ///
+    /// Defaults to [`DEFAULT_QUANTUM`].
+    pub fn quantum(mut self, quantum: usize) -> Self {
+        self.quantum = quantum.max(1);
+        self
+    }
+
+    /// Sets the time after which an unused turn passes to the next response.
+    ///
+    /// Defaults to [`DEFAULT_TIME_SLICE`].
+    pub fn time_slice(mut self, time_slice: Duration) -> Self {
+        self.time_slice = time_slice;
+        self
+    }
+
+    /// Sets the handler that assigns priorities to responses.
+    ///
+    /// By default all responses have the same priority.
+    pub fn priority_handler(mut self, priorities: Arc<dyn PriorityHandler>) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    /// Number of responses that are currently scheduled.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().slots.len()
+    }
+
+    /// Whether no responses are currently scheduled.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Schedules the response to `request`, until the returned slot is dropped.
+    pub(crate) fn register(&self, request: &Request) -> Slot {
+        let priority = self.priorities.priority(request).max(1);
+        let budget = self.quantum.saturating_mul(priority as usize);
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.slots.insert(
+            id,
+            SlotState {
+                budget,
+                waker: None,
+            },
+        );
+        Slot {
+            id,
+            scheduler: self.clone(),
+            timer: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_id: u64,
+    slots: HashMap<u64, SlotState>,
+    /// Slots waiting for a turn, in order.
+    queue: VecDeque<u64>,
+    turn: Option<Turn>,
+}
+
+#[derive(Debug)]
+struct SlotState {
+    /// Bytes the slot may write per turn.
+    budget: usize,
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Turn {
+    id: u64,
+    /// Bytes left to write in this turn.
+    remaining: usize,
+    until: Instant,
+}
+
+impl State {
+    /// Ends the current turn and gives the next one to the front of the queue.
+    ///
+    /// Returns the waker of the slot that got the turn.
+    fn next_turn(&mut self, now: Instant, time_slice: Duration) -> Option<Waker> {
+        self.turn = None;
+        let id = self.queue.pop_front()?;
+        let slot = self
+            .slots
+            .get_mut(&id)
+            .expect("queued slots are registered");
+        self.turn = Some(Turn {
+            id,
+            remaining: slot.budget,
+            until: now + time_slice,
+        });
+        slot.waker.take()
+    }
+}
+
+/// The place of one response in the [`FairScheduler`].
+#[derive(Debug)]
+pub(crate) struct Slot {
+    id: u64,
+    scheduler: FairScheduler,
+    /// Wakes the slot when the current turn expires.
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Slot {
+    /// Completes once it is the turn of this slot to write.
+    pub(crate) fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let now = Instant::now();
+        let time_slice = self.scheduler.time_slice;
+        let mut state = self.scheduler.state.lock().unwrap();
+        let expired = match &state.turn {
+            Some(turn) if turn.id == self.id && now < turn.until => return Poll::Ready(()),
+            // either our turn expired and we line up again behind the others, or the holder
+            // did not use its turn
+            Some(turn) => turn.id == self.id || now >= turn.until,
+            None => false,
+        };
+        let mut wake = None;
+        if expired {
+            wake = state.next_turn(now, time_slice);
+        }
+        if state.turn.is_none() {
+            if state.queue.is_empty() || state.queue.front() == Some(&self.id) {
+                state.queue.retain(|id| *id != self.id);
+                state.queue.push_front(self.id);
+                state.next_turn(now, time_slice);
+                return Poll::Ready(());
+            }
+            wake = state.next_turn(now, time_slice);
+        }
+        let until = state.turn.as_ref().expect("someone has the turn").until;
+        if !state.queue.contains(&self.id) {
+            state.queue.push_back(self.id);
+        }
+        let slot = state.slots.get_mut(&self.id).expect("slot is registered");
+        slot.waker = Some(cx.waker().clone());
+        drop(state);
+        if let Some(waker) = wake {
+            waker.wake();
+        }
+        let timer = self
+            .timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(until)));
+        timer.as_mut().reset(until);
+        // only registers the waker, an expired turn is handled on the next poll
+        let _ = timer.as_mut().poll(cx);
+        Poll::Pending
+    }
+
+    /// Records that `n` bytes were written in the turn of this slot.
+    pub(crate) fn record(&self, n: usize) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        let Some(turn) = state.turn.as_mut().filter(|turn| turn.id == self.id) else {
+            return;
+        };
+        turn.remaining = turn.remaining.saturating_sub(n);
+        if turn.remaining == 0 {
+            let wake = state.next_turn(Instant::now(), self.scheduler.time_slice);
+            drop(state);
+            if let Some(waker) = wake {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Passes the turn on, if this slot has it.
+    pub(crate) fn release(&self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        if state.turn.as_ref().map(|turn| turn.id) != Some(self.id) {
+            return;
+        }
+        let wake = state.next_turn(Instant::now(), self.scheduler.time_slice);
+        drop(state);
+        if let Some(waker) = wake {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.release();
+        let mut state = self.scheduler.state.lock().unwrap();
+        state.slots.remove(&self.id);
+        state.queue.retain(|id| *id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker;
+
+    use super::*;
+    use crate::protocol::GetRequest;
+    use crate::Hash;
+
+    #[derive(Debug)]
+    struct ByHash(Hash);
+
+    impl PriorityHandler for ByHash {
+        fn priority(&self, request: &Request) -> u32 {
+            match request {
+                Request::Get(get) if get.hash == self.0 => 2,
+                _ => 1,
+            }
+        }
+    }
+
+    fn request(hash: Hash) -> Request {
+        Request::Get(GetRequest::single(hash))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn round_robin() {
+        let big = Hash::new(b"big");
+        let small = Hash::new(b"small");
+        let scheduler = FairScheduler::default()
+            .quantum(10)
+            .priority_handler(Arc::new(ByHash(big)));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut a = scheduler.register(&request(big));
+        let mut b = scheduler.register(&request(small));
+        assert_eq!(scheduler.len(), 2);
+
+        // a has priority 2, so it writes 20 bytes per turn
+        assert!(a.poll_acquire(&mut cx).is_ready());
+        assert!(b.poll_acquire(&mut cx).is_pending());
+        a.record(15);
+        assert!(a.poll_acquire(&mut cx).is_ready());
+        a.record(5);
+        assert!(a.poll_acquire(&mut cx).is_pending());
+        assert!(b.poll_acquire(&mut cx).is_ready());
+        b.record(10);
+        assert!(b.poll_acquire(&mut cx).is_pending());
+        assert!(a.poll_acquire(&mut cx).is_ready());
+
+        // a blocked stream passes the turn on
+        a.release();
+        assert!(b.poll_acquire(&mut cx).is_ready());
+
+        // an unused turn expires
+        tokio::time::advance(DEFAULT_TIME_SLICE).await;
+        assert!(a.poll_acquire(&mut cx).is_ready());
+
+        // a dropped slot passes the turn on
+        assert!(b.poll_acquire(&mut cx).is_pending());
+        drop(a);
+        assert!(b.poll_acquire(&mut cx).is_ready());
+        drop(b);
+        assert!(scheduler.is_empty());
+    }
+}
@@ -8,6 +8,7 @@ use thiserror::Error;
 pub mod io;
 pub mod progress;
 pub mod runtime;
+pub mod verify;
 
 /// Hash type used throught.
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
@@ -57,6 +58,80 @@ impl Hash {
     pub fn to_hex(&self) -> String {
         self.0.to_hex().to_string()
     }
+
+    /// Parse a hash from 64 hex digits, in upper or lower case.
+    pub fn from_hex(s: &str) -> anyhow::Result<Self> {
+        let mut res = [0u8; 32];
+        anyhow::ensure!(
+            s.len() == 64,
+            "invalid hex hash length, expected 64, got {}",
+            s.len()
+        );
+        data_encoding::HEXLOWER_PERMISSIVE
+            .decode_mut(s.as_bytes(), &mut res)
+            .map_err(|_e| anyhow::anyhow!("invalid hex"))?;
+        Ok(Self::from(res))
+    }
+
+    /// Convert the hash bytes to a lower case base32 string without padding.
+    ///
+    /// Unlike [`Hash::to_cid`], this is just the 32 bytes of the hash, 52 characters.
+    pub fn to_base32(&self) -> String {
+        let mut res = data_encoding::BASE32_NOPAD.encode(self.as_bytes());
+        res.make_ascii_lowercase();
+        res
+    }
+
+    /// Parse a hash from its base32 representation, see [`Hash::to_base32`].
+    ///
+    /// Upper and lower case are accepted.
+    pub fn from_base32(s: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            s.len() == 52,
+            "invalid base32 hash length, expected 52, got {}",
+            s.len()
+        );
+        let s = s.to_ascii_uppercase();
+        let mut res = [0u8; 32];
+        data_encoding::BASE32_NOPAD
+            .decode_mut(s.as_bytes(), &mut res)
+            .map_err(|_e| anyhow::anyhow!("invalid base32"))?;
+        Ok(Self::from(res))
+    }
+
+    /// Convert the hash to a CID v1 with the raw codec, in lower case base32 multibase.
+    ///
+    /// This is the same as the [`Display`](fmt::Display) implementation.
+    pub fn to_cid(&self) -> String {
+        self.to_string()
+    }
+
+    /// Parse a hash from a CID v1 with the raw codec and a blake3 hash, in any multibase
+    /// encoding.
+    pub fn from_cid(s: &str) -> anyhow::Result<Self> {
+        let sb = s.as_bytes();
+        if sb.len() == 59 && sb[0] == b'b' {
+            // this is a base32 encoded cid, we can decode it directly
+            let mut t = [0u8; 58];
+            t.copy_from_slice(&sb[1..]);
+            // hack since data_encoding doesn't have BASE32LOWER_NOPAD as a const
+            std::str::from_utf8_mut(t.as_mut())
+                .unwrap()
+                .make_ascii_uppercase();
+            // decode the bytes
+            let mut res = [0u8; 36];
+            data_encoding::BASE32_NOPAD
+                .decode_mut(&t, &mut res)
+                .map_err(|_e| anyhow::anyhow!("invalid base32"))?;
+            // convert to cid, this will check the prefix
+            Self::from_cid_bytes(&res)
+        } else {
+            // if we want to support all the weird multibase prefixes, we have no choice
+            // but to use the multibase crate
+            let (_base, bytes) = multibase::decode(s)?;
+            Self::from_cid_bytes(bytes.as_ref())
+        }
+    }
 }
 
 impl AsRef<[u8]> for Hash {
@@ -110,31 +185,19 @@ impl fmt::Display for Hash {
     }
 }
 
+/// Parses any of the well-known hash encodings.
+///
+/// 64 characters are parsed as hex, see [`Hash::from_hex`], 52 characters as the base32
+/// of the hash bytes, see [`Hash::from_base32`], and everything else as a multibase CID,
+/// see [`Hash::from_cid`]. None of these lengths can be confused with each other.
 impl FromStr for Hash {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let sb = s.as_bytes();
-        if sb.len() == 59 && sb[0] == b'b' {
-            // this is a base32 encoded cid, we can decode it directly
-            let mut t = [0u8; 58];
-            t.copy_from_slice(&sb[1..]);
-            // hack since data_encoding doesn't have BASE32LOWER_NOPAD as a const
-            std::str::from_utf8_mut(t.as_mut())
-                .unwrap()
-                .make_ascii_uppercase();
-            // decode the bytes
-            let mut res = [0u8; 36];
-            data_encoding::BASE32_NOPAD
-                .decode_mut(&t, &mut res)
-                .map_err(|_e| anyhow::anyhow!("invalid base32"))?;
-            // convert to cid, this will check the prefix
-            Self::from_cid_bytes(&res)
-        } else {
-            // if we want to support all the weird multibase prefixes, we have no choice
-            // but to use the multibase crate
-            let (_base, bytes) = multibase::decode(s)?;
-            Self::from_cid_bytes(bytes.as_ref())
+        match s.len() {
+            64 => Self::from_hex(s),
+            52 => Self::from_base32(s),
+            _ => Self::from_cid(s),
         }
     }
 }
@@ -186,6 +249,34 @@ const CID_PREFIX: [u8; 4] = [
     0x20, // hash size, 32 bytes
 ];
 
+/// An identifier of a request, to correlate the logs and events of the request.
+///
+/// Request ids are random, so ids generated by different processes don't collide. They
+/// are displayed as 16 hex digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    /// Generate a new random request id.
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for RequestId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(Self)
+    }
+}
+
 /// A serializable error type for use in RPC responses.
 #[derive(Serialize, Deserialize, Debug, Error)]
 pub struct RpcError(serde_error::Error);
@@ -222,6 +313,17 @@ impl NonSend {
     }
 }
 
+/// The message of a panic, from the payload caught by `catch_unwind` or a `JoinError`.
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -234,4 +336,54 @@ mod tests {
         let encoded = hash.to_string();
         assert_eq!(encoded.parse::<Hash>().unwrap(), hash);
     }
+
+    #[test]
+    fn test_hash_encodings() {
+        let hash = Hash::new(b"hello world");
+
+        let hex = hash.to_hex();
+        assert_eq!(Hash::from_hex(&hex).unwrap(), hash);
+        assert_eq!(Hash::from_hex(&hex.to_ascii_uppercase()).unwrap(), hash);
+        assert_eq!(hex.parse::<Hash>().unwrap(), hash);
+
+        let base32 = hash.to_base32();
+        assert_eq!(base32.len(), 52);
+        assert_eq!(Hash::from_base32(&base32).unwrap(), hash);
+        assert_eq!(
+            Hash::from_base32(&base32.to_ascii_uppercase()).unwrap(),
+            hash
+        );
+        assert_eq!(base32.parse::<Hash>().unwrap(), hash);
+
+        let cid = hash.to_cid();
+        assert_eq!(cid, hash.to_string());
+        assert_eq!(Hash::from_cid(&cid).unwrap(), hash);
+        // the same cid in base16 and base58btc multibase
+        let base16 = multibase::encode(multibase::Base::Base16Lower, hash.as_cid_bytes());
+        assert_eq!(base16.parse::<Hash>().unwrap(), hash);
+        let base58 = multibase::encode(multibase::Base::Base58Btc, hash.as_cid_bytes());
+        assert_eq!(base58.parse::<Hash>().unwrap(), hash);
+
+        assert!(Hash::from_hex(&base32).is_err());
+        assert!(Hash::from_base32(&hex).is_err());
+        assert!(Hash::from_cid(&hex).is_err());
+        assert!("not a hash".parse::<Hash>().is_err());
+    }
+
+    #[test]
+    fn test_request_id() {
+        let id = RequestId::generate();
+        let encoded = id.to_string();
+        assert_eq!(encoded.len(), 16);
+        assert_eq!(encoded.parse::<RequestId>().unwrap(), id);
+        assert_eq!(RequestId(1).to_string(), "0000000000000001");
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let payload = std::panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "static");
+        let payload = std::panic::catch_unwind(|| panic!("formatted {}", 42)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "formatted 42");
+    }
 }
@@ -1,5 +1,10 @@
 //! The runtime module provides the iroh runtime, consisting of a general purpose
 //! tokio runtime and a set of single threaded runtimes.
+//!
+//! Everything that takes a runtime accepts a plain [`tokio::runtime::Handle`] as well, in
+//! which case a thread per core is started for the single threaded runtimes. The main
+//! runtime can be a multi threaded or a current thread runtime, since the single threaded
+//! runtimes always run on their own threads.
 use std::sync::Arc;
 
 /// A handle to the iroh runtime
@@ -28,6 +33,19 @@ impl Handle {
         ))
     }
 
+    /// Create a new iroh runtime using `rt` as the main runtime, and a thread per core
+    /// executor.
+    pub fn from_tokio(rt: tokio::runtime::Handle) -> Self {
+        let size = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(rt, tokio_util::task::LocalPoolHandle::new(size))
+    }
+
+    /// Create a new iroh runtime using the current tokio runtime as the main runtime, and
+    /// a thread per core executor.
+    pub fn try_current() -> std::result::Result<Self, tokio::runtime::TryCurrentError> {
+        Ok(Self::from_tokio(tokio::runtime::Handle::try_current()?))
+    }
+
     /// Get a handle to the main tokio runtime
     pub fn main(&self) -> &tokio::runtime::Handle {
         &self.inner.rt
@@ -39,8 +57,40 @@ impl Handle {
     }
 }
 
+impl From<tokio::runtime::Handle> for Handle {
+    fn from(rt: tokio::runtime::Handle) -> Self {
+        Self::from_tokio(rt)
+    }
+}
+
+impl From<&tokio::runtime::Handle> for Handle {
+    fn from(rt: &tokio::runtime::Handle) -> Self {
+        Self::from_tokio(rt.clone())
+    }
+}
+
+impl From<&Handle> for Handle {
+    fn from(rt: &Handle) -> Self {
+        rt.clone()
+    }
+}
+
 #[derive(Debug)]
 struct HandleInner {
     rt: tokio::runtime::Handle,
     tpc: tokio_util::task::LocalPoolHandle,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn current_thread_runtime() {
+        let rt = Handle::try_current().unwrap();
+        let res = rt.local_pool().spawn_pinned(|| async { 42 }).await.unwrap();
+        assert_eq!(res, 42);
+        let res = rt.main().spawn(async { 43 }).await.unwrap();
+        assert_eq!(res, 43);
+    }
+}
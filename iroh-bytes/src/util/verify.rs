@@ -0,0 +1,167 @@
+//! Verification of data obtained outside of iroh.
+//!
+//! Data does not have to be transferred by iroh to be verified against an iroh hash.
+//! The bao encoding of a blob, as sent by a provider, can be downloaded over HTTP or
+//! copied from a disk, and verified chunk by chunk while it is read.
+use bao_tree::io::fsm::{
+    BaoContentItem, ResponseDecoderReading, ResponseDecoderReadingNext, ResponseDecoderStart,
+};
+use bao_tree::io::DecodeError;
+use bao_tree::ChunkNum;
+use bytes::Bytes;
+use futures::Stream;
+use range_collections::RangeSet2;
+use tokio::io::AsyncRead;
+
+use crate::{Hash, IROH_BLOCK_SIZE};
+
+/// A chunk of a blob that was verified against the hash of the blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedChunk {
+    /// Offset of the chunk in the blob.
+    pub offset: u64,
+    /// The verified data.
+    pub data: Bytes,
+}
+
+enum State<R> {
+    Start(ResponseDecoderStart<R>),
+    Reading(ResponseDecoderReading<R>),
+    Done,
+}
+
+/// Verify the bao encoding of an entire blob against its hash.
+///
+/// The reader must yield the encoding a provider sends for a request of the entire
+/// blob: the size of the blob, followed by the hashes and data of the blob in pre-order.
+///
+/// Chunks are only yielded once they are verified. The stream ends after the first
+/// error, so a stream that ends without an error yielded all of the blob.
+pub fn verify_stream<R>(
+    hash: Hash,
+    reader: R,
+) -> impl Stream<Item = Result<VerifiedChunk, DecodeError>>
+where
+    R: AsyncRead + Unpin,
+{
+    verify_ranges_stream(hash, RangeSet2::all(), reader)
+}
+
+/// Verify the bao encoding of some ranges of a blob against the hash of the blob.
+///
+/// Like [`verify_stream`], for an encoding that contains only the given ranges.
+pub fn verify_ranges_stream<R>(
+    hash: Hash,
+    ranges: RangeSet2<ChunkNum>,
+    reader: R,
+) -> impl Stream<Item = Result<VerifiedChunk, DecodeError>>
+where
+    R: AsyncRead + Unpin,
+{
+    let start = ResponseDecoderStart::new(hash.into(), ranges, IROH_BLOCK_SIZE, reader);
+    futures::stream::unfold(State::Start(start), |state| async move {
+        let mut decoder = match state {
+            State::Start(start) => match start.next().await {
+                Ok((decoder, _size)) => decoder,
+                Err(cause) => return Some((Err(cause.into()), State::Done)),
+            },
+            State::Reading(decoder) => decoder,
+            State::Done => return None,
+        };
+        loop {
+            match decoder.next().await {
+                ResponseDecoderReadingNext::More((next, Ok(BaoContentItem::Leaf(leaf)))) => {
+                    let chunk = VerifiedChunk {
+                        offset: leaf.offset.0,
+                        data: leaf.data,
+                    };
+                    return Some((Ok(chunk), State::Reading(next)));
+                }
+                // parents are verified by the decoder, there is nothing to yield for them
+                ResponseDecoderReadingNext::More((next, Ok(BaoContentItem::Parent(_)))) => {
+                    decoder = next;
+                }
+                ResponseDecoderReadingNext::More((_, Err(cause))) => {
+                    return Some((Err(cause), State::Done));
+                }
+                ResponseDecoderReadingNext::Done(_) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bao_tree::io::outboard::PreOrderOutboard;
+    use bao_tree::{BaoTree, ByteNum};
+    use futures::{StreamExt, TryStreamExt};
+
+    use super::*;
+
+    async fn encode(data: &[u8], ranges: &RangeSet2<ChunkNum>) -> (Hash, Vec<u8>) {
+        let (outboard, hash) = bao_tree::io::outboard(data, IROH_BLOCK_SIZE);
+        let outboard = PreOrderOutboard {
+            root: hash,
+            tree: BaoTree::new(ByteNum(data.len() as u64), IROH_BLOCK_SIZE),
+            data: Bytes::from(outboard),
+        };
+        let mut encoded = Vec::new();
+        bao_tree::io::fsm::encode_ranges_validated(
+            Bytes::copy_from_slice(data),
+            outboard,
+            ranges,
+            &mut encoded,
+        )
+        .await
+        .unwrap();
+        (hash.into(), encoded)
+    }
+
+    #[tokio::test]
+    async fn verify_valid_and_corrupted() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (hash, encoded) = encode(&data, &RangeSet2::all()).await;
+
+        let chunks = verify_stream(hash, &encoded[..])
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let mut verified = Vec::new();
+        for chunk in chunks {
+            assert_eq!(chunk.offset, verified.len() as u64);
+            verified.extend_from_slice(&chunk.data);
+        }
+        assert_eq!(verified, data);
+
+        // flip a bit in the last byte of data, everything before it still verifies
+        let mut corrupted = encoded.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let results = verify_stream(hash, &corrupted[..])
+            .collect::<Vec<_>>()
+            .await;
+        let (last, init) = results.split_last().unwrap();
+        assert!(init.iter().all(|res| res.is_ok()));
+        assert!(last.is_err());
+
+        // the wrong hash does not verify anything
+        let results = verify_stream(Hash::new(b"other"), &encoded[..])
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_ranges() {
+        let data = vec![7u8; 100_000];
+        let ranges = RangeSet2::from(ChunkNum(16)..ChunkNum(32));
+        let (hash, encoded) = encode(&data, &ranges).await;
+        let chunks = verify_ranges_stream(hash, ranges, &encoded[..])
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].offset, 16 * 1024);
+        assert_eq!(chunks[0].data.len(), 16 * 1024);
+    }
+}
@@ -232,7 +232,10 @@ async fn endpoint_loop(endpoint: MagicEndpoint, gossip: Gossip) {
         });
     }
 }
-async fn handle_connection(conn: quinn::Connecting, gossip: Gossip) -> anyhow::Result<()> {
+async fn handle_connection(
+    conn: iroh_net::magic_endpoint::Connecting,
+    gossip: Gossip,
+) -> anyhow::Result<()> {
     let (peer_id, alpn, conn) = accept_conn(conn).await?;
     match alpn.as_bytes() {
         GOSSIP_ALPN => gossip
@@ -153,8 +153,9 @@ impl Core {
         self.metrics_map.get::<T>()
     }
 
+    /// Encodes all registered metrics in the OpenMetrics text format.
     #[cfg(feature = "metrics")]
-    pub(crate) fn encode(&self) -> Result<String, std::fmt::Error> {
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
         let mut buf = String::new();
         encode(&mut buf, &self.registry)?;
         Ok(buf)
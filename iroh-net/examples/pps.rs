@@ -0,0 +1,228 @@
+//! Measures the packets per second two magic endpoints on this host can exchange.
+//!
+//! The client sends small unreliable datagrams as fast as it can, the server counts what it
+//! receives. The measurement is repeated for a number of rounds, and the median, minimum and
+//! maximum rate of all rounds is reported, together with the average number of packets the
+//! magicsock moved per batch. Run it against two revisions to compare the magicsock IO path:
+//!
+//!     cargo run --release --example pps -- --duration 5 --rounds 5
+//!
+//! The last line of the output is a tab separated summary meant for diffing between runs.
+//!
+//! Only unix platforms batch syscalls with `recvmmsg`/`sendmmsg` (and segmentation offload
+//! where the kernel supports it). On Windows `quinn-udp` sends and receives one datagram per
+//! syscall, so batching there only saves wakeups inside the magicsock; a batched Windows
+//! socket path is out of scope for this benchmark.
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use clap::Parser;
+use iroh_net::{magic_endpoint::accept_conn, MagicEndpoint};
+
+const BENCH_ALPN: &[u8] = b"n0/iroh/examples/pps/0";
+
+#[derive(Debug, Parser)]
+struct Cli {
+    /// How long to send for in each round, in seconds.
+    #[clap(long, default_value = "5")]
+    duration: u64,
+    /// Number of measured rounds.
+    #[clap(long, default_value = "5")]
+    rounds: usize,
+    /// Size of each datagram in bytes.
+    #[clap(long, default_value = "64")]
+    size: usize,
+    /// Number of concurrent senders.
+    #[clap(long, default_value = "4")]
+    senders: usize,
+    /// Disable UDP segmentation offload.
+    #[clap(long)]
+    no_segmentation_offload: bool,
+}
+
+/// The rates measured in one round.
+#[derive(Debug, Clone, Copy)]
+struct Round {
+    sent_pps: f64,
+    received_pps: f64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Cli::parse();
+    anyhow::ensure!(args.rounds > 0, "at least one round is needed");
+
+    #[cfg(feature = "metrics")]
+    iroh_metrics::core::Core::init(|reg, metrics| {
+        use iroh_metrics::core::Metric;
+        metrics.insert(iroh_net::metrics::MagicsockMetrics::new(reg));
+    });
+
+    let bind = || {
+        MagicEndpoint::builder()
+            .alpns(vec![BENCH_ALPN.to_vec()])
+            .derp_map(None)
+            .segmentation_offload(!args.no_segmentation_offload)
+            .bind(0)
+    };
+    let server = bind().await?;
+    let client = bind().await?;
+    let server_peer_id = server.peer_id();
+    let server_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), server.local_addr()?.0.port());
+
+    let received = Arc::new(AtomicU64::new(0));
+    let server_task = {
+        let received = received.clone();
+        tokio::spawn(async move {
+            let conn = server.accept().await.expect("server closed");
+            let (_peer_id, _alpn, conn) = accept_conn(conn).await?;
+            while conn.read_datagram().await.is_ok() {
+                received.fetch_add(1, Ordering::Relaxed);
+            }
+            anyhow::Ok(())
+        })
+    };
+
+    let conn = client
+        .connect(server_peer_id, BENCH_ALPN, None, &[server_addr])
+        .await?;
+    let max_size = conn
+        .max_datagram_size()
+        .ok_or_else(|| anyhow::anyhow!("datagrams not supported"))?;
+    let payload = Bytes::from(vec![0u8; args.size.min(max_size)]);
+
+    let mut rounds = Vec::with_capacity(args.rounds);
+    for i in 0..args.rounds {
+        let round = run_round(&conn, &payload, &received, &args).await?;
+        println!(
+            "round {}: sent {:.0} pps, received {:.0} pps",
+            i + 1,
+            round.sent_pps,
+            round.received_pps
+        );
+        rounds.push(round);
+    }
+
+    let sent = summary(rounds.iter().map(|r| r.sent_pps).collect());
+    let received = summary(rounds.iter().map(|r| r.received_pps).collect());
+    println!(
+        "{} byte datagrams, {} senders, segmentation offload {}",
+        payload.len(),
+        args.senders,
+        if args.no_segmentation_offload {
+            "off"
+        } else {
+            "on"
+        }
+    );
+    println!(
+        "sent:     median {:.0} pps (min {:.0}, max {:.0})",
+        sent.0, sent.1, sent.2
+    );
+    println!(
+        "received: median {:.0} pps (min {:.0}, max {:.0})",
+        received.0, received.1, received.2
+    );
+    let (recv_batch, send_batch) = batch_sizes();
+    if let (Some(recv_batch), Some(send_batch)) = (recv_batch, send_batch) {
+        println!(
+            "batches:  {:.1} packets per receive, {:.1} transmit groups per send",
+            recv_batch, send_batch
+        );
+    }
+    println!(
+        "summary\t{}\t{}\t{:.0}\t{:.0}",
+        payload.len(),
+        args.senders,
+        sent.0,
+        received.0
+    );
+
+    conn.close(0u8.into(), b"done");
+    client.close(0u8.into(), b"done").await?;
+    server_task.abort();
+    Ok(())
+}
+
+/// Send datagrams for one round and measure the rates.
+async fn run_round(
+    conn: &quinn::Connection,
+    payload: &Bytes,
+    received: &AtomicU64,
+    args: &Cli,
+) -> anyhow::Result<Round> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let sent = Arc::new(AtomicU64::new(0));
+    let senders: Vec<_> = (0..args.senders)
+        .map(|_| {
+            let conn = conn.clone();
+            let payload = payload.clone();
+            let stop = stop.clone();
+            let sent = sent.clone();
+            tokio::spawn(async move {
+                while !stop.load(Ordering::Relaxed) {
+                    if conn.send_datagram(payload.clone()).is_ok() {
+                        sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
+
+    let received_before = received.load(Ordering::Relaxed);
+    let start = Instant::now();
+    tokio::time::sleep(Duration::from_secs(args.duration)).await;
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = start.elapsed().as_secs_f64();
+    for sender in senders {
+        sender.await?;
+    }
+    let received = received.load(Ordering::Relaxed) - received_before;
+    Ok(Round {
+        sent_pps: sent.load(Ordering::Relaxed) as f64 / elapsed,
+        received_pps: received as f64 / elapsed,
+    })
+}
+
+/// The median, minimum and maximum of the values.
+fn summary(mut values: Vec<f64>) -> (f64, f64, f64) {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    let median = if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    };
+    (median, values[0], values[values.len() - 1])
+}
+
+/// The average number of packets per receive batch and of transmit groups per send batch,
+/// over both endpoints.
+#[cfg(feature = "metrics")]
+fn batch_sizes() -> (Option<f64>, Option<f64>) {
+    let Some(core) = iroh_metrics::core::Core::get() else {
+        return (None, None);
+    };
+    let Some(metrics) = core.get_collector::<iroh_net::metrics::MagicsockMetrics>() else {
+        return (None, None);
+    };
+    let ratio = |n: u64, d: u64| (d > 0).then(|| n as f64 / d as f64);
+    (
+        ratio(metrics.recv_datagrams.get(), metrics.recv_batches.get()),
+        ratio(metrics.send_batch_groups.get(), metrics.send_batches.get()),
+    )
+}
+
+#[cfg(not(feature = "metrics"))]
+fn batch_sizes() -> (Option<f64>, Option<f64>) {
+    (None, None)
+}
@@ -7,7 +7,10 @@ use std::{
     net::{IpAddr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
 };
 
@@ -180,8 +183,11 @@ struct Config {
     ///
     /// Defaults to `true`.
     enable_stun: bool,
-    /// Whether to run a DERP server. The only reason to set this false is if you're decommissioning a
-    /// server but want to keep its bootstrap DNS functionality still running.
+    /// Whether to run a DERP server. Set this to false to run a STUN-only server, e.g. to scale
+    /// STUN capacity independently of relay capacity.
+    ///
+    /// In STUN-only mode no TLS certificates are acquired, and the listener at `addr` only serves
+    /// plain HTTP health and metrics endpoints.
     ///
     /// Defaults to `true`
     enable_derp: bool,
@@ -191,8 +197,15 @@ struct Config {
     limits: Option<Limits>,
     /// Mesh network configuration
     mesh: Option<MeshConfig>,
+    /// Path to a file listing the nodes allowed to use this DERP server, one hex encoded
+    /// node public key per line. Lines starting with `#` are ignored.
+    ///
+    /// If not set, any node may use the server.
+    allowed_clients_file: Option<PathBuf>,
     #[cfg(feature = "metrics")]
-    /// Metrics serve address. If not set, metrics are not served.
+    /// Metrics serve address. If not set, metrics are not served on a separate address.
+    ///
+    /// Metrics are always available at `/debug/varz` on the main listener.
     metrics_addr: Option<SocketAddr>,
 }
 
@@ -252,6 +265,7 @@ impl Default for Config {
             tls: None,
             limits: None,
             mesh: None,
+            allowed_clients_file: None,
             #[cfg(feature = "metrics")]
             metrics_addr: None,
         }
@@ -315,13 +329,14 @@ pub fn init_metrics_collection(
 
     let rt = tokio::runtime::Handle::current();
 
+    // always collect metrics, they are served at `/debug/varz`
+    iroh_metrics::core::Core::init(|reg, metrics| {
+        metrics.insert(iroh_net::metrics::DerpMetrics::new(reg));
+        metrics.insert(StunMetrics::new(reg));
+    });
+
     // doesn't start the server if the address is None
     if let Some(metrics_addr) = metrics_addr {
-        iroh_metrics::core::Core::init(|reg, metrics| {
-            metrics.insert(iroh_net::metrics::DerpMetrics::new(reg));
-            metrics.insert(StunMetrics::new(reg));
-        });
-
         return Some(rt.spawn(async move {
             if let Err(e) = iroh_metrics::metrics::start_metrics_server(metrics_addr).await {
                 eprintln!("Failed to start metrics server: {e}");
@@ -365,6 +380,9 @@ async fn run(
     cfg: Config,
     addr_sender: Option<tokio::sync::oneshot::Sender<SocketAddr>>,
 ) -> Result<()> {
+    if !cfg.enable_derp && !cfg.enable_stun {
+        bail!("Both `enable_derp` and `enable_stun` are disabled, there is nothing to serve.");
+    }
     let (addr, tls_config) = if dev_mode {
         let port = if cfg.addr.port() != 443 {
             cfg.addr.port()
@@ -375,6 +393,9 @@ async fn run(
         let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
         info!(%addr, "Running in dev mode.");
         (addr, None)
+    } else if !cfg.enable_derp {
+        info!("Running in STUN-only mode.");
+        (cfg.addr, None)
     } else {
         (cfg.addr, cfg.tls)
     };
@@ -385,13 +406,13 @@ async fn run(
                 bail!("The main listening address {addr:?} and the `captive_portal_port` have the same port number.");
             }
         }
-    } else if addr.port() == 443 {
+    } else if cfg.enable_derp && addr.port() == 443 {
         // no tls config, but the port is 443
         warn!("The address port is 443, which is typically the expected tls port, but you have not supplied any tls configuration.\nIf you meant to run the derper with tls enabled, adjust the config file to include tls configuration.");
     }
 
     // set up derp configuration details
-    let (secret_key, mesh_key, mesh_derpers) = match cfg.enable_derp {
+    let (secret_key, mesh_key, mesh_derpers, client_acl) = match cfg.enable_derp {
         true => {
             let (mesh_key, mesh_derpers) = if let Some(mesh_config) = cfg.mesh {
                 let raw = tokio::fs::read_to_string(mesh_config.mesh_psk_file)
@@ -408,16 +429,21 @@ async fn run(
             } else {
                 (None, None)
             };
-            (Some(cfg.private_key), mesh_key, mesh_derpers)
+            let client_acl = if let Some(path) = cfg.allowed_clients_file {
+                let acl = derp::ClientAcl::load(path).await?;
+                info!("DERP client allowlist configured");
+                Some(acl)
+            } else {
+                None
+            };
+            (Some(cfg.private_key), mesh_key, mesh_derpers, client_acl)
         }
-        false => (None, None, None),
+        false => (None, None, None, None),
     };
 
     // run stun
     let stun_task = if cfg.enable_stun {
-        Some(tokio::task::spawn(async move {
-            serve_stun(addr.ip(), cfg.stun_port).await
-        }))
+        Some(serve_stun(addr.ip(), cfg.stun_port).await?)
     } else {
         None
     };
@@ -447,27 +473,39 @@ async fn run(
         (None, Vec::new(), 0)
     };
 
-    let mut builder = DerpServerBuilder::new(addr)
+    let ready = Arc::new(AtomicBool::new(false));
+    let ready_check = ready.clone();
+    // if tls is enabled, captive portal detection is also served from a non-tls
+    // connection which we check for below
+    let builder = DerpServerBuilder::new(addr)
+        .derp(cfg.enable_derp)
         .secret_key(secret_key)
         .mesh_key(mesh_key)
         .headers(headers)
         .tls_config(tls_config.clone())
         .derp_override(Box::new(derp_disabled_handler))
         .mesh_derpers(mesh_derpers)
+        .client_acl(client_acl)
         .request_handler(Method::GET, "/", Box::new(root_handler))
         .request_handler(Method::GET, "/index.html", Box::new(root_handler))
         .request_handler(Method::GET, "/derp/probe", Box::new(probe_handler))
-        .request_handler(Method::GET, "/robots.txt", Box::new(robots_handler));
-    // if tls is enabled, we need to serve this endpoint from a non-tls connection
-    // which we check for below
-    if tls_config.is_none() {
-        builder = builder.request_handler(
+        .request_handler(Method::GET, "/robots.txt", Box::new(robots_handler))
+        .request_handler(
             Method::GET,
             "/generate_204",
             Box::new(serve_no_content_handler),
-        );
-    }
+        )
+        .request_handler(Method::GET, "/health", Box::new(health_handler))
+        .request_handler(
+            Method::GET,
+            "/ready",
+            Box::new(move |r: Request<Body>, response: ResponseBuilder| {
+                ready_handler(&ready_check, r, response)
+            }),
+        )
+        .request_handler(Method::GET, "/debug/varz", Box::new(varz_handler));
     let derp_server = builder.spawn().await?;
+    ready.store(true, Ordering::SeqCst);
 
     // captive portal detections must be served over HTTP
     let captive_portal_task = if tls_config.is_some() {
@@ -485,6 +523,8 @@ async fn run(
     }
 
     tokio::signal::ctrl_c().await?;
+    // stop receiving traffic from load balancers while shutting down
+    ready.store(false, Ordering::SeqCst);
     // Shutdown all tasks
     if let Some(task) = stun_task {
         task.abort();
@@ -503,6 +543,9 @@ const NO_CONTENT_RESPONSE_HEADER: &str = "X-Tailscale-Response";
 const NOTFOUND: &[u8] = b"Not Found";
 const DERP_DISABLED: &[u8] = b"derp server disabled";
 const ROBOTS_TXT: &[u8] = b"User-agent: *\nDisallow: /\n";
+const HEALTH_OK: &[u8] = b"ok\n";
+const NOT_READY: &[u8] = b"not ready\n";
+const METRICS_DISABLED: &[u8] = b"metrics disabled";
 const INDEX: &[u8] = br#"<html><body>
 <h1>DERP</h1>
 <p>
@@ -626,6 +669,51 @@ fn probe_handler(_r: Request<Body>, response: ResponseBuilder) -> HyperResult<Re
     Ok(response)
 }
 
+/// Liveness check, succeeds as long as the server answers HTTP requests.
+fn health_handler(_r: Request<Body>, response: ResponseBuilder) -> HyperResult<Response<Body>> {
+    Ok(response
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(HEALTH_OK.into())
+        .unwrap())
+}
+
+/// Readiness check, succeeds once the server is set up and until it starts shutting down.
+fn ready_handler(
+    ready: &AtomicBool,
+    _r: Request<Body>,
+    response: ResponseBuilder,
+) -> HyperResult<Response<Body>> {
+    let (status, body) = if ready.load(Ordering::SeqCst) {
+        (StatusCode::OK, HEALTH_OK)
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, NOT_READY)
+    };
+    Ok(response
+        .status(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(body.into())
+        .unwrap())
+}
+
+/// Serves the metrics in the OpenMetrics text format.
+fn varz_handler(_r: Request<Body>, response: ResponseBuilder) -> HyperResult<Response<Body>> {
+    #[cfg(feature = "metrics")]
+    if let Some(core) = iroh_metrics::core::Core::get() {
+        if let Ok(metrics) = core.encode() {
+            return Ok(response
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(metrics.into())
+                .unwrap());
+        }
+    }
+    Ok(response
+        .status(StatusCode::NOT_FOUND)
+        .body(METRICS_DISABLED.into())
+        .unwrap())
+}
+
 fn robots_handler(_r: Request<Body>, response: ResponseBuilder) -> HyperResult<Response<Body>> {
     Ok(response
         .status(StatusCode::OK)
@@ -669,19 +757,16 @@ fn is_challenge_char(c: char) -> bool {
         || c == '_'
 }
 
-async fn serve_stun(host: IpAddr, port: u16) {
-    match UdpSocket::bind((host, port)).await {
-        Ok(sock) => {
-            let addr = sock.local_addr().expect("socket just bound");
-            info!(%addr, "running STUN server");
-            server_stun_listener(sock)
-                .instrument(debug_span!("stun_server", %addr))
-                .await;
-        }
-        Err(err) => {
-            error!("failed to open STUN listener: {:#?}", err);
-        }
-    }
+async fn serve_stun(host: IpAddr, port: u16) -> Result<tokio::task::JoinHandle<()>> {
+    let sock = UdpSocket::bind((host, port))
+        .await
+        .context("failed to open STUN listener")?;
+    let addr = sock.local_addr()?;
+    info!(%addr, "running STUN server");
+    let task = tokio::task::spawn(
+        server_stun_listener(sock).instrument(debug_span!("stun_server", %addr)),
+    );
+    Ok(task)
 }
 
 async fn server_stun_listener(sock: UdpSocket) {
@@ -913,6 +998,22 @@ mod tests {
             .is_empty());
     }
 
+    #[tokio::test]
+    async fn test_ready_handler() {
+        let ready = AtomicBool::new(false);
+        let res = ready_handler(&ready, Request::default(), Response::builder()).unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        ready.store(true, Ordering::SeqCst);
+        let res = ready_handler(&ready, Request::default(), Response::builder()).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], HEALTH_OK);
+
+        let res = health_handler(Request::default(), Response::builder()).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
     #[test]
     fn test_escape_hostname() {
         assert_eq!(
@@ -1080,4 +1181,40 @@ mod tests {
         derper_task.abort();
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_derper_stun_only() -> Result<()> {
+        let cfg = Config {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+            stun_port: 0,
+            enable_derp: false,
+            ..Default::default()
+        };
+        let (addr_send, addr_recv) = tokio::sync::oneshot::channel();
+        let derper_task = tokio::spawn(async move { run(false, cfg, Some(addr_send)).await });
+        let derper_addr = addr_recv.await?;
+
+        let client = hyper::Client::new();
+        let res = client
+            .get(format!("http://{derper_addr}/health").parse()?)
+            .await?;
+        assert_eq!(StatusCode::OK, res.status());
+        let res = client
+            .get(format!("http://{derper_addr}/derp").parse()?)
+            .await?;
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        derper_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_derper_nothing_to_serve() {
+        let cfg = Config {
+            enable_derp: false,
+            enable_stun: false,
+            ..Default::default()
+        };
+        assert!(run(false, cfg, None).await.is_err());
+    }
 }
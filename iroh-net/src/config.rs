@@ -6,6 +6,8 @@ use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
 };
 
+use serde::{Deserialize, Serialize};
+
 use super::{key, portmapper};
 
 /// Fake WireGuard endpoint IP address that means to
@@ -109,6 +111,72 @@ impl NetInfo {
             && self.preferred_derp == other.preferred_derp
             && self.link_type == other.link_type
     }
+
+    /// The type of NAT the host is behind, as far as the netcheck can tell.
+    pub fn nat_type(&self) -> NatType {
+        match (self.working_udp, self.mapping_varies_by_dest_ip) {
+            (None, _) => NatType::Unknown,
+            (Some(false), _) => NatType::NoUdp,
+            (Some(true), _) if self.have_port_map => NatType::PortMapped,
+            (Some(true), Some(false)) => NatType::EndpointIndependent,
+            (Some(true), Some(true)) => NatType::EndpointDependent,
+            (Some(true), None) => NatType::Unknown,
+        }
+    }
+}
+
+/// The type of NAT a host is behind, which determines how likely hole punching works.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum NatType {
+    /// Not known, the netcheck has not completed or the peer did not tell.
+    #[default]
+    Unknown,
+    /// UDP does not work, only relayed connections are possible.
+    NoUdp,
+    /// A port mapping (UPnP, PMP or PCP) is open, the host is directly reachable.
+    PortMapped,
+    /// The NAT mapping is the same for all destinations, hole punching usually works.
+    EndpointIndependent,
+    /// The NAT mapping varies by destination, hole punching rarely works.
+    EndpointDependent,
+}
+
+impl NatType {
+    /// The byte this type is encoded as in disco pings.
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            NatType::Unknown => 0,
+            NatType::NoUdp => 1,
+            NatType::PortMapped => 2,
+            NatType::EndpointIndependent => 3,
+            NatType::EndpointDependent => 4,
+        }
+    }
+
+    /// Decodes a byte from a disco ping, unknown values are [`NatType::Unknown`].
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            1 => NatType::NoUdp,
+            2 => NatType::PortMapped,
+            3 => NatType::EndpointIndependent,
+            4 => NatType::EndpointDependent,
+            _ => NatType::Unknown,
+        }
+    }
+}
+
+impl Display for NatType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatType::Unknown => write!(f, "unknown"),
+            NatType::NoUdp => write!(f, "no-udp"),
+            NatType::PortMapped => write!(f, "port-mapped"),
+            NatType::EndpointIndependent => write!(f, "easy"),
+            NatType::EndpointDependent => write!(f, "hard"),
+        }
+    }
 }
 
 /// The type of link.
@@ -9,6 +9,7 @@
 //! Based on tailscale/derp/derp.go
 
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
+mod acl;
 pub(crate) mod client;
 pub(crate) mod client_conn;
 pub(crate) mod clients;
@@ -17,7 +18,9 @@ mod map;
 mod metrics;
 pub(crate) mod server;
 pub(crate) mod types;
+mod usage;
 
+pub use self::acl::ClientAcl;
 pub use self::client::{Client as DerpClient, ReceivedMessage};
 pub use self::http::Client as HttpClient;
 pub use self::map::{DerpMap, DerpNode, DerpRegion, UseIpv4, UseIpv6};
@@ -26,6 +29,8 @@ pub use self::server::{
     ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer, PacketForwarderHandler, Server,
 };
 pub use self::types::{MeshKey, PacketForwarder};
+pub use self::usage::PeerUsage;
+pub(crate) use self::usage::{UsageReader, UsageRecorder};
 
 use std::time::Duration;
 
@@ -43,7 +48,7 @@ use types::ClientInfo;
 /// including its on-wire framing overhead)
 pub const MAX_PACKET_SIZE: usize = 64 * 1024;
 
-const MAX_FRAME_SIZE: usize = 1024 * 1024;
+pub(crate) const MAX_FRAME_SIZE: usize = 1024 * 1024;
 
 /// The DERP magic number, sent in the FrameType::ServerKey frame
 /// upon initial connection
@@ -85,9 +90,9 @@ const NOT_PREFERRED: u8 = 0u8;
 /// The one byte frame type at the beginning of the frame
 /// header. The second field is a big-endian u32 describing the
 /// length of the remaining frame (not including the initial 5 bytes)
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-enum FrameType {
+pub(crate) enum FrameType {
     /// 8B magic + 32B public key + (0+ bytes future use)
     ServerKey = 1,
     /// 32b pub key + 24B nonce + chachabox(bytes)
@@ -146,6 +151,11 @@ enum FrameType {
     Restarting = 15,
     /// 32B src pub key + 32B dst pub key + packet bytes
     ForwardPacket = 16,
+    /// Sent from server to client instead of [`FrameType::ServerInfo`] when the client is
+    /// not allowed to use the server. The server closes the connection afterwards.
+    ///
+    /// The entire frame body is the text of the reason.
+    Rejected = 17,
     Unknown = 255,
 }
 
@@ -174,6 +184,7 @@ impl From<u8> for FrameType {
             14 => FrameType::Health,
             15 => FrameType::Restarting,
             16 => FrameType::ForwardPacket,
+            17 => FrameType::Rejected,
             _ => FrameType::Unknown,
         }
     }
@@ -198,7 +209,7 @@ async fn read_frame_header(mut reader: impl AsyncRead + Unpin) -> Result<(FrameT
 /// an error after reading the frame header.
 ///
 /// Also errors if we receive EOF before the end of the expected length of the frame.
-async fn read_frame(
+pub(crate) async fn read_frame(
     mut reader: impl AsyncRead + Unpin,
     max_size: usize,
     buf: &mut BytesMut,
@@ -324,6 +335,7 @@ async fn recv_client_key<R: AsyncRead + Unpin>(
         frame_type == FrameType::ClientInfo,
         "expected FrameType::ClientInfo frame got {frame_type}"
     );
+    ensure!(buf.len() >= PUBLIC_KEY_LENGTH, "short ClientInfo frame");
     let key = PublicKey::try_from(&buf[..PUBLIC_KEY_LENGTH]).context("public key")?;
     let msg = &buf[PUBLIC_KEY_LENGTH..];
     let msg = secret_key.open_from(&key, msg).context("shared secret")?;
@@ -333,6 +345,8 @@ async fn recv_client_key<R: AsyncRead + Unpin>(
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     #[tokio::test]
@@ -380,4 +394,49 @@ mod tests {
         assert_eq!(client_info, got_client_info);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_recv_short_client_key() -> Result<()> {
+        let (mut reader, mut writer) = tokio::io::duplex(1024);
+        write_frame(&mut writer, FrameType::ClientInfo, &[&[1u8; 8]]).await?;
+        writer.flush().await?;
+        assert!(recv_client_key(SecretKey::generate(), &mut reader)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn frame_roundtrip(
+            frame_type in any::<u8>(),
+            content in proptest::collection::vec(any::<u8>(), 0..2048),
+        ) {
+            let frame_type = FrameType::from(frame_type);
+            let mut frame = Vec::new();
+            let mut got = BytesMut::new();
+            let (got_type, got_len) = futures::executor::block_on(async {
+                write_frame(&mut frame, frame_type, &[&content]).await?;
+                read_frame(frame.as_slice(), MAX_FRAME_SIZE, &mut got).await
+            })
+            .unwrap();
+            // one byte of frame type, four bytes of length
+            prop_assert_eq!(frame.len(), 5 + content.len());
+            prop_assert_eq!(got_type, frame_type);
+            prop_assert_eq!(got_len, content.len());
+            prop_assert_eq!(&got[..], &content[..]);
+        }
+
+        #[test]
+        fn frame_from_arbitrary_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..2048)) {
+            // must not panic, and never read beyond the announced frame length
+            let mut reader = bytes.as_slice();
+            let mut buf = BytesMut::new();
+            while let Ok((_, len)) =
+                futures::executor::block_on(read_frame(&mut reader, MAX_FRAME_SIZE, &mut buf))
+            {
+                prop_assert_eq!(buf.len(), len);
+            }
+        }
+    }
 }
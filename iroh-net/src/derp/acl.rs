@@ -0,0 +1,106 @@
+//! Access control for the clients of a DERP server.
+//!
+//! By default a DERP server relays traffic for anyone that connects to it. A
+//! [`ClientAcl`] restricts the server to a known set of nodes, so that a relay can be
+//! deployed privately while still being reachable from the internet.
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use crate::key::node::{PublicKey, PUBLIC_KEY_LENGTH};
+
+/// Decides which clients may register with a DERP server and relay traffic through it.
+///
+/// Clients that are not allowed receive a `Rejected` frame instead of the server info
+/// and are disconnected. Servers that mesh with this server using the mesh key are
+/// always allowed.
+#[derive(Clone)]
+pub enum ClientAcl {
+    /// Only the listed node keys are allowed.
+    Allowlist(Arc<HashSet<PublicKey>>),
+    /// A callback that decides for every client key.
+    Callback(Arc<dyn Fn(&PublicKey) -> bool + Send + Sync + 'static>),
+}
+
+impl ClientAcl {
+    /// Create an ACL that only allows the given keys.
+    pub fn allowlist(keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        Self::Allowlist(Arc::new(keys.into_iter().collect()))
+    }
+
+    /// Create an ACL that asks `f` whether a client is allowed.
+    pub fn callback(f: impl Fn(&PublicKey) -> bool + Send + Sync + 'static) -> Self {
+        Self::Callback(Arc::new(f))
+    }
+
+    /// Load an allowlist from a file.
+    ///
+    /// The file contains one hex encoded node key per line. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("unable to read ACL file {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("invalid ACL file {}", path.display()))
+    }
+
+    /// Parse an allowlist in the format of [`ClientAcl::load`].
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut keys = HashSet::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut bytes = [0u8; PUBLIC_KEY_LENGTH];
+            hex::decode_to_slice(line, &mut bytes)
+                .with_context(|| format!("invalid node key on line {}", i + 1))?;
+            keys.insert(PublicKey::from(bytes));
+        }
+        Ok(Self::Allowlist(Arc::new(keys)))
+    }
+
+    /// Whether the client with key `key` is allowed.
+    pub fn allows(&self, key: &PublicKey) -> bool {
+        match self {
+            Self::Allowlist(keys) => keys.contains(key),
+            Self::Callback(f) => f(key),
+        }
+    }
+}
+
+impl fmt::Debug for ClientAcl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Allowlist(keys) => write!(f, "ClientAcl::Allowlist({} keys)", keys.len()),
+            Self::Callback(_) => write!(f, "ClientAcl::Callback"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::node::SecretKey;
+
+    #[test]
+    fn parse_allowlist() {
+        let a = SecretKey::generate().public_key();
+        let b = SecretKey::generate().public_key();
+        let content = format!(
+            "# allowed nodes\n{}\n\n  {}  \n",
+            hex::encode(a.as_bytes()),
+            hex::encode(b.as_bytes())
+        );
+        let acl = ClientAcl::parse(&content).unwrap();
+        assert!(acl.allows(&a));
+        assert!(acl.allows(&b));
+        assert!(!acl.allows(&SecretKey::generate().public_key()));
+
+        assert!(ClientAcl::parse("not a key").is_err());
+    }
+}
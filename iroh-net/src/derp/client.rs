@@ -476,7 +476,16 @@ where
         let mut buf = BytesMut::new();
         let (frame_type, _) =
             crate::derp::read_frame(&mut self.reader, MAX_FRAME_SIZE, &mut buf).await?;
-        assert_eq!(FrameType::ServerInfo, frame_type);
+        match frame_type {
+            FrameType::ServerInfo => {}
+            FrameType::Rejected => {
+                bail!(
+                    "rejected by server: {}",
+                    String::from_utf8_lossy(&buf).trim()
+                );
+            }
+            _ => bail!("expected FrameType::ServerInfo frame got {frame_type}"),
+        }
         let msg = self.secret_key.open_from(&server_key, &buf)?;
         let info: ServerInfo = postcard::from_bytes(&msg)?;
         if info.version != PROTOCOL_VERSION {
@@ -642,7 +642,7 @@ where
     }
 }
 
-fn parse_forward_packet(data: &[u8]) -> Result<(PublicKey, PublicKey, &[u8])> {
+pub(crate) fn parse_forward_packet(data: &[u8]) -> Result<(PublicKey, PublicKey, &[u8])> {
     ensure!(
         data.len() >= PUBLIC_KEY_LENGTH * 2,
         "short FORWARD_PACKET frame"
@@ -660,7 +660,7 @@ fn parse_forward_packet(data: &[u8]) -> Result<(PublicKey, PublicKey, &[u8])> {
     Ok((srckey, dstkey, data))
 }
 
-fn parse_send_packet(data: &[u8]) -> Result<(PublicKey, &[u8])> {
+pub(crate) fn parse_send_packet(data: &[u8]) -> Result<(PublicKey, &[u8])> {
     ensure!(data.len() >= PUBLIC_KEY_LENGTH, "short SEND_PACKET frame");
     let packet_len = data.len() - PUBLIC_KEY_LENGTH;
     ensure!(
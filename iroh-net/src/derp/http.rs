@@ -134,6 +134,21 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_derp_disabled() -> Result<()> {
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .derp(false)
+            .spawn()
+            .await?;
+
+        let res = reqwest::get(format!("http://{}/derp", server.addr())).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+        server.shutdown().await;
+        Ok(())
+    }
+
     fn create_test_client(
         key: SecretKey,
         region: DerpRegion,
@@ -25,7 +25,7 @@ use crate::derp::{
     metrics::Metrics, server::PacketForwarderHandler, DerpNode, DerpRegion, MeshKey,
     PacketForwarder, ReceivedMessage, UseIpv4, UseIpv6,
 };
-use crate::dns::DNS_RESOLVER;
+use crate::dns::{DnsError, DnsResolver};
 use crate::key;
 
 const DIAL_NODE_TIMEOUT: Duration = Duration::from_millis(1500);
@@ -94,8 +94,8 @@ pub enum ClientError {
     #[error("invalid url: {0}")]
     InvalidUrl(String),
     /// There was an error with DNS resolution
-    #[error("dns: {0:?}")]
-    Dns(Option<trust_dns_resolver::error::ResolveError>),
+    #[error("dns: {0}")]
+    Dns(#[from] DnsError),
 }
 
 /// An HTTP DERP client.
@@ -136,6 +136,7 @@ struct InnerClient {
     is_prober: bool,
     server_public_key: Option<key::node::PublicKey>,
     url: Option<Url>,
+    dns_resolver: DnsResolver,
 }
 
 /// Build a Client.
@@ -166,6 +167,8 @@ pub struct ClientBuilder {
     /// will fail on `build`.
     get_region:
         Option<Box<dyn Fn() -> BoxFuture<'static, Option<DerpRegion>> + Send + Sync + 'static>>,
+    /// Resolver for the hostnames of the servers.
+    dns_resolver: DnsResolver,
 }
 
 impl std::fmt::Debug for ClientBuilder {
@@ -233,6 +236,14 @@ impl ClientBuilder {
         self
     }
 
+    /// Sets the resolver for the hostnames of the servers.
+    ///
+    /// Defaults to the resolver with the system configuration.
+    pub fn dns_resolver(mut self, dns_resolver: DnsResolver) -> Self {
+        self.dns_resolver = dns_resolver;
+        self
+    }
+
     /// Build this [`Client`] with a [`MeshKey`], and allow it to mesh
     pub fn mesh_key(mut self, mesh_key: Option<MeshKey>) -> Self {
         self.mesh_key = mesh_key;
@@ -259,6 +270,7 @@ impl ClientBuilder {
                 is_prober: self.is_prober,
                 server_public_key: self.server_public_key,
                 url: self.url,
+                dns_resolver: self.dns_resolver,
             }),
         })
     }
@@ -577,13 +589,10 @@ impl Client {
         let dst_ip = match host {
             url::Host::Domain(hostname) => {
                 // Need to do a DNS lookup
-                let addr = DNS_RESOLVER
-                    .lookup_ip(hostname)
-                    .await
-                    .map_err(|e| ClientError::Dns(Some(e)))?
-                    .iter()
-                    .next();
-                addr.ok_or_else(|| ClientError::Dns(None))?
+                self.inner
+                    .dns_resolver
+                    .lookup_ip_filtered(hostname, |_| true)
+                    .await?
             }
             url::Host::Ipv4(ip) => IpAddr::V4(ip),
             url::Host::Ipv6(ip) => IpAddr::V6(ip),
@@ -712,16 +721,13 @@ impl Client {
                 match host {
                     url::Host::Domain(domain) => {
                         // Need to do a DNS lookup
-                        let addr = DNS_RESOLVER
-                            .lookup_ip(domain)
-                            .await
-                            .map_err(|e| ClientError::Dns(Some(e)))?
-                            .iter()
-                            .find(|addr| match dst_primary {
+                        self.inner
+                            .dns_resolver
+                            .lookup_ip_filtered(domain, |addr| match dst_primary {
                                 UseIp::Ipv4(_) => addr.is_ipv4(),
                                 UseIp::Ipv6(_) => addr.is_ipv6(),
-                            });
-                        addr.ok_or_else(|| ClientError::Dns(None))?
+                            })
+                            .await?
                     }
                     url::Host::Ipv4(ip) => IpAddr::V4(ip),
                     url::Host::Ipv6(ip) => IpAddr::V6(ip),
@@ -28,13 +28,14 @@ use tracing::{debug, error, info, info_span, warn, Instrument};
 use super::HTTP_UPGRADE_PROTOCOL;
 use crate::{
     derp::{
+        acl::ClientAcl,
         http::client::Client as HttpClient,
         http::mesh_clients::{MeshAddrs, MeshClients},
         server::ClientConnHandler,
         server::MaybeTlsStream,
         types::MeshKey,
         types::PacketForwarder,
-        MaybeTlsStreamServer,
+        MaybeTlsStreamServer, PeerUsage,
     },
     key::node::SecretKey,
 };
@@ -103,6 +104,16 @@ impl Server {
         self.addr
     }
 
+    /// Traffic relayed per client key, empty if this server does not run a derp server.
+    ///
+    /// See [`crate::derp::Server::relay_usage`].
+    pub fn relay_usage(&self) -> Vec<PeerUsage> {
+        self.server
+            .as_ref()
+            .map(|server| server.relay_usage())
+            .unwrap_or_default()
+    }
+
     /// Mesh this server to a new list of derp servers.
     pub async fn re_mesh(
         &mut self,
@@ -165,6 +176,10 @@ pub struct ServerBuilder {
     /// Having a `mesh_depers` but no `mesh_key` when attempting to `spawn` a
     /// [`Server`] results in an error.
     mesh_derpers: Option<MeshAddrs>,
+    /// Optional [`ClientAcl`] restricting which clients may use the derp server.
+    ///
+    /// When `None`, any client may connect.
+    client_acl: Option<ClientAcl>,
     /// Optional tls configuration/TlsAcceptor combination.
     ///
     /// When `None`, the server will serve HTTP, otherwise it will serve HTTPS.
@@ -175,6 +190,8 @@ pub struct ServerBuilder {
     handlers: Handlers,
     /// Defaults to `GET` request at "/derp".
     derp_endpoint: &'static str,
+    /// Whether to run the derp relay. Defaults to `true`.
+    derp: bool,
     /// Use a custom derp response handler. Typically used when you want to disable any derp connections.
     #[debug("{}", derp_override.as_ref().map_or("None", |_| "Some(Box<Fn(ResponseBuilder) -> Result<Response<Body>> + Send + Sync + 'static>)"))]
     derp_override: Option<HyperFn>,
@@ -195,9 +212,11 @@ impl ServerBuilder {
             addr,
             mesh_key: None,
             mesh_derpers: None,
+            client_acl: None,
             tls_config: None,
             handlers: Default::default(),
             derp_endpoint: "/derp",
+            derp: true,
             derp_override: None,
             headers: Vec::new(),
             not_found_fn: None,
@@ -225,6 +244,12 @@ impl ServerBuilder {
         self
     }
 
+    /// Only allow the clients accepted by the [`ClientAcl`] to use the derp server.
+    pub fn client_acl(mut self, acl: Option<ClientAcl>) -> Self {
+        self.client_acl = acl;
+        self
+    }
+
     /// Serve derp content using TLS.
     pub fn tls_config(mut self, config: Option<TlsConfig>) -> Self {
         self.tls_config = config;
@@ -255,6 +280,17 @@ impl ServerBuilder {
         self
     }
 
+    /// Whether to run the derp relay, `true` by default.
+    ///
+    /// When disabled the [`SecretKey`], mesh and [`ClientAcl`] settings are ignored, and the
+    /// derp endpoint is answered by the [`ServerBuilder::derp_override`] handler, or with a
+    /// "404" if there is none. The server then only serves the custom request handlers, e.g.
+    /// the health checks of a STUN-only deployment.
+    pub fn derp(mut self, enabled: bool) -> Self {
+        self.derp = enabled;
+        self
+    }
+
     /// Change the derp endpoint from "/derp" to `endpoint`.
     pub fn derp_endpoint(mut self, endpoint: &'static str) -> Self {
         self.derp_endpoint = endpoint;
@@ -269,9 +305,11 @@ impl ServerBuilder {
 
     /// Build and spawn an HTTP(S) derp Server
     pub async fn spawn(self) -> Result<Server> {
-        ensure!(self.secret_key.is_some() || self.derp_override.is_some(), "Must provide a `SecretKey` for the derp server OR pass in an override function for the 'derp' endpoint");
-        let (derp_handler, derp_server, mesh_clients) = if let Some(secret_key) = self.secret_key {
-            let server = crate::derp::server::Server::new(secret_key.clone(), self.mesh_key);
+        ensure!(!self.derp || self.secret_key.is_some() || self.derp_override.is_some(), "Must provide a `SecretKey` for the derp server OR pass in an override function for the 'derp' endpoint");
+        let secret_key = self.secret_key.filter(|_| self.derp);
+        let (derp_handler, derp_server, mesh_clients) = if let Some(secret_key) = secret_key {
+            let mut server = crate::derp::server::Server::new(secret_key.clone(), self.mesh_key);
+            server.set_client_acl(self.client_acl);
             let header_map: HeaderMap = HeaderMap::from_iter(
                 self.headers
                     .iter()
@@ -300,26 +338,14 @@ impl ServerBuilder {
                 mesh_clients,
             )
         } else {
-            (
-                DerpHandler::Override(self.derp_override.unwrap()),
-                None,
-                None,
-            )
-        };
-        let h = self.headers.clone();
-        let not_found_fn = match self.not_found_fn {
-            Some(f) => f,
-            None => Box::new(move |_req: Request<Body>, mut res: ResponseBuilder| {
-                for (k, v) in h.iter() {
-                    res = res.header(*k, *v);
-                }
-                let r = res
-                    .status(StatusCode::NOT_FOUND)
-                    .body(b"Not Found"[..].into())
-                    .unwrap();
-                HyperResult::Ok(r)
-            }),
+            let derp_override = self
+                .derp_override
+                .unwrap_or_else(|| not_found_handler(self.headers.clone()));
+            (DerpHandler::Override(derp_override), None, None)
         };
+        let not_found_fn = self
+            .not_found_fn
+            .unwrap_or_else(|| not_found_handler(self.headers.clone()));
 
         let service = DerpService::new(
             self.handlers,
@@ -537,6 +563,20 @@ type HyperFn = Box<
 >;
 type Headers = Vec<(&'static str, &'static str)>;
 
+/// The default "404" response, with the given `headers`.
+fn not_found_handler(headers: Headers) -> HyperFn {
+    Box::new(move |_req: Request<Body>, mut res: ResponseBuilder| {
+        for (k, v) in headers.iter() {
+            res = res.header(*k, *v);
+        }
+        let r = res
+            .status(StatusCode::NOT_FOUND)
+            .body(b"Not Found"[..].into())
+            .unwrap();
+        HyperResult::Ok(r)
+    })
+}
+
 #[derive(derive_more::Debug)]
 struct Inner {
     pub derp_handler: DerpHandler,
@@ -60,6 +60,8 @@ pub struct Metrics {
     pub accepts: Counter,
     /// Number of connections we have removed because of an error
     pub disconnects: Counter,
+    /// Number of connections rejected because the client is not allowed by the ACL
+    pub rejected_clients: Counter,
     // TODO: enable when we can have multiple connections for one peer id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
@@ -115,6 +117,9 @@ impl Default for Metrics {
 
             accepts: Counter::new("Number of times this server has accepted a connection."),
             disconnects: Counter::new("Number of clients that have then disconnected."),
+            rejected_clients: Counter::new(
+                "Number of clients rejected because they are not allowed to use this server.",
+            ),
             // TODO: enable when we can have multiple connections for one peer id
             // pub duplicate_client_keys: Counter::new("Number of dupliate client keys."),
             // pub duplicate_client_conns: Counter::new("Number of duplicate client connections."),
@@ -21,10 +21,11 @@ use crate::key::node::{PublicKey, SecretKey};
 
 use super::client_conn::ClientConnBuilder;
 use super::{
+    acl::ClientAcl,
     clients::Clients,
     metrics::Metrics,
     types::{PacketForwarder, PeerConnState, ServerMessage},
-    MeshKey,
+    MeshKey, PeerUsage, UsageReader, UsageRecorder,
 };
 use super::{
     recv_client_key, types::ServerInfo, write_frame, write_frame_timeout, FrameType, MAGIC,
@@ -64,6 +65,8 @@ where
     mesh_key: Option<MeshKey>,
     /// The DER encoded x509 cert to send after `LetsEncrypt` cert+intermediate.
     meta_cert: Vec<u8>,
+    /// Optionally restricts which clients may use the server.
+    client_acl: Option<ClientAcl>,
     /// Channel on which to communicate to the [`ServerActor`]
     server_channel: mpsc::Sender<ServerMessage<P>>,
     /// When true, the server has been shutdown.
@@ -75,6 +78,8 @@ where
     loop_handler: JoinHandle<Result<()>>,
     /// Done token, forces a hard shutdown. To gracefully shutdown, use [`Server::close`]
     cancel: CancellationToken,
+    /// Traffic relayed per client key.
+    usage: UsageReader,
     // TODO: stats collection
     // Counters:
     // 	packetsSent, bytesSent       expvar.Int
@@ -119,6 +124,7 @@ where
     pub fn new(key: SecretKey, mesh_key: Option<MeshKey>) -> Self {
         let (server_channel_s, server_channel_r) = mpsc::channel(SERVER_CHANNEL_SIZE);
         let server_actor = ServerActor::new(key.public_key(), server_channel_r);
+        let usage = server_actor.usage.reader();
         let cancel_token = CancellationToken::new();
         let done = cancel_token.clone();
         let server_task = tokio::spawn(
@@ -130,12 +136,14 @@ where
             secret_key: key,
             mesh_key,
             meta_cert,
+            client_acl: None,
             server_channel: server_channel_s,
             closed: false,
             // TODO: come up with good default
             server_info: ServerInfo::no_rate_limit(),
             loop_handler: server_task,
             cancel: cancel_token,
+            usage,
         }
     }
 
@@ -149,6 +157,14 @@ where
         self.mesh_key
     }
 
+    /// Restricts which clients may register with the server and relay traffic.
+    ///
+    /// Only affects [`ClientConnHandler`]s created after this call. Servers in the same
+    /// mesh are always allowed.
+    pub fn set_client_acl(&mut self, acl: Option<ClientAcl>) {
+        self.client_acl = acl;
+    }
+
     /// Returns the server's private key.
     pub fn private_key(&self) -> SecretKey {
         self.secret_key.clone()
@@ -159,6 +175,16 @@ where
         self.secret_key.public_key()
     }
 
+    /// Traffic relayed per connected client since it connected, the clients with the most
+    /// relayed bytes first.
+    ///
+    /// Bytes sent are the packets delivered to the client, bytes received the packets the
+    /// client sent through this server. Clients connected to other servers of the mesh are
+    /// not tracked here.
+    pub fn relay_usage(&self) -> Vec<PeerUsage> {
+        self.usage.usage()
+    }
+
     /// Closes the server and waits for the connections to disconnect.
     pub async fn close(mut self) {
         if !self.closed {
@@ -198,6 +224,7 @@ where
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
             server_info: self.server_info.clone(),
+            client_acl: self.client_acl.clone(),
             default_headers: Arc::new(default_headers),
         }
     }
@@ -277,6 +304,7 @@ where
     secret_key: SecretKey,
     write_timeout: Option<Duration>,
     server_info: ServerInfo,
+    client_acl: Option<ClientAcl>,
     pub(super) default_headers: Arc<HeaderMap>,
 }
 
@@ -291,6 +319,7 @@ where
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
             server_info: self.server_info.clone(),
+            client_acl: self.client_acl.clone(),
             default_headers: Arc::clone(&self.default_headers),
         }
     }
@@ -304,7 +333,8 @@ where
     ///
     /// Will error if it takes too long (10 sec) to write or read to the connection, if there is
     /// some read or write error to the connection,  if the server is meant to verify clients,
-    /// and is unable to verify this one, or if there is some issue communicating with the server.
+    /// and is unable to verify this one, if the client is not allowed by the [`ClientAcl`],
+    /// or if there is some issue communicating with the server.
     ///
     /// The provided [`AsyncRead`] and [`AsyncWrite`] must be already connected to the connection.
     pub async fn accept(&self, mut io: MaybeTlsStream) -> Result<()> {
@@ -316,6 +346,14 @@ where
         let (client_key, client_info) = recv_client_key(self.secret_key.clone(), &mut io)
             .await
             .context("unable to receive client information")?;
+        let can_mesh = self.can_mesh(client_info.mesh_key);
+        if !can_mesh && !self.is_allowed(&client_key) {
+            inc!(Metrics, rejected_clients);
+            self.send_rejected(&mut io, "client not allowed")
+                .await
+                .context("unable to send rejection to client")?;
+            anyhow::bail!("client {client_key} is not allowed");
+        }
         trace!("accept: send server info");
         self.send_server_info(&mut io, &client_key)
             .await
@@ -325,7 +363,7 @@ where
             key: client_key,
             conn_num: new_conn_num(),
             io,
-            can_mesh: self.can_mesh(client_info.mesh_key),
+            can_mesh,
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
             server_channel: self.server_channel.clone(),
@@ -373,6 +411,28 @@ where
         Ok(())
     }
 
+    async fn send_rejected<T>(&self, mut writer: &mut T, reason: &str) -> Result<()>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        write_frame_timeout(
+            &mut writer,
+            FrameType::Rejected,
+            &[reason.as_bytes()],
+            Some(Duration::from_secs(10)),
+        )
+        .await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Whether the client is allowed by the [`ClientAcl`], if any.
+    fn is_allowed(&self, client_key: &PublicKey) -> bool {
+        self.client_acl
+            .as_ref()
+            .map_or(true, |acl| acl.allows(client_key))
+    }
+
     /// Determines if the server and client can mesh, and, if so, are apart of the same mesh.
     fn can_mesh(&self, client_mesh_key: Option<MeshKey>) -> bool {
         if let (Some(a), Some(b)) = (self.mesh_key, client_mesh_key) {
@@ -395,6 +455,8 @@ where
     client_mesh: HashMap<PublicKey, Option<P>>,
     /// Mesh clients that need to be appraised on the state of the network
     watchers: HashSet<PublicKey>,
+    /// Traffic relayed per client key.
+    usage: UsageRecorder,
     name: String,
 }
 
@@ -410,6 +472,7 @@ where
             clients: Clients::new(),
             client_mesh: HashMap::default(),
             watchers: HashSet::default(),
+            usage: UsageRecorder::default(),
             name,
         }
     }
@@ -459,10 +522,15 @@ where
                         ServerMessage::SendPacket((key, packet)) => {
                            tracing::trace!("send disco packet from: {:?} to: {:?} ({}b)", packet.src, key, packet.bytes.len());
                             let src = packet.src.clone();
+                            let len = packet.bytes.len();
+                            if self.clients.contains_key(&src) {
+                                self.usage.record_recv(&src, len);
+                            }
                             if self.clients.contains_key(&key) {
                                 // if this client is in our local network, just try to send the
                                 // packet
                                 if self.clients.send_packet(&key, packet).is_ok() {
+                                    self.usage.record_sent(&key, len);
                                     self.clients.record_send(&src, key);
                                 }
                             } else if let Some(Some(fwd)) = self.client_mesh.get_mut(&key) {
@@ -477,11 +545,15 @@ where
                        ServerMessage::SendDiscoPacket((key, packet)) => {
                            tracing::trace!("send disco packet from: {:?} to: {:?} ({}b)", packet.src, key, packet.bytes.len());
                             let src = packet.src.clone();
+                            let len = packet.bytes.len();
+                            if self.clients.contains_key(&src) {
+                                self.usage.record_recv(&src, len);
+                            }
                             if self.clients.contains_key(&key) {
                                 // if this client is in our local network, just try to send the
                                 // packet
                                 if self.clients.send_disco_packet(&key, packet).is_ok() {
-
+                                    self.usage.record_sent(&key, len);
                                     self.clients.record_send(&src, key);
                                 }
                             } else if let Some(Some(fwd)) = self.client_mesh.get_mut(&key) {
@@ -518,6 +590,13 @@ where
                                // remove the client from the map of clients, & notify any peers that it
                                // has sent messages that it has left the network
                                self.clients.unregister(&key);
+                               if let Some(usage) = self.usage.remove(&key) {
+                                   tracing::debug!(
+                                       "client {:?} disconnected after relaying {} bytes",
+                                       key,
+                                       usage.total_bytes()
+                                   );
+                               }
                                // remove from mesh
                                self.client_mesh.remove(&key);
                                // broadcast to watchers that this peer has left the network
@@ -890,6 +969,7 @@ mod tests {
             secret_key: SecretKey::generate(),
             write_timeout: None,
             server_info: ServerInfo::no_rate_limit(),
+            client_acl: None,
             server_channel: server_channel_s,
             default_headers: Default::default(),
         };
@@ -1053,6 +1133,34 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_server_client_acl() -> Result<()> {
+        let key_a = SecretKey::generate();
+        let key_b = SecretKey::generate();
+        let mut server: Server<MockPacketForwarder> = Server::new(SecretKey::generate(), None);
+        server.set_client_acl(Some(ClientAcl::allowlist([key_a.public_key()])));
+
+        // an allowed client connects
+        let (rw_a, client_a_builder) = make_test_client(key_a);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+        let _client_a = client_a_builder.build(None).await?;
+        handler_task.await??;
+
+        // any other client is rejected
+        let (rw_b, client_b_builder) = make_test_client(key_b);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+        let err = client_b_builder.build(None).await.unwrap_err();
+        assert!(err.to_string().contains("rejected"), "{err:?}");
+        assert!(handler_task.await?.is_err());
+
+        server.close().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_server_replace_client() -> Result<()> {
         tracing_subscriber::registry()
@@ -0,0 +1,161 @@
+//! Relay bandwidth accounting per peer.
+//!
+//! Both ends of a DERP connection keep track of how many bytes were relayed for which
+//! peer: clients count the packets they send to and receive from each remote peer over
+//! DERP, and the server counts the packets each connected client sends and has
+//! delivered. Peers with a lot of relayed traffic are the ones for which direct
+//! connectivity is worth improving.
+//!
+//! Peers are only tracked while they are known: the server stops tracking a client when
+//! it disconnects, and clients stop tracking peers that are removed from the network map.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::key::node::PublicKey;
+
+/// Traffic relayed for a single peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerUsage {
+    /// The key of the peer.
+    pub peer: PublicKey,
+    /// Bytes relayed to the peer.
+    pub bytes_sent: u64,
+    /// Bytes relayed from the peer.
+    pub bytes_recv: u64,
+    /// Packets relayed to the peer.
+    pub packets_sent: u64,
+    /// Packets relayed from the peer.
+    pub packets_recv: u64,
+}
+
+impl PeerUsage {
+    /// Bytes relayed in both directions.
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_recv
+    }
+}
+
+/// The counters of a single peer, shared between the recorder and the readers.
+#[derive(Debug, Default)]
+struct Counters {
+    bytes_sent: AtomicU64,
+    bytes_recv: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_recv: AtomicU64,
+}
+
+impl Counters {
+    fn usage(&self, peer: &PublicKey) -> PeerUsage {
+        PeerUsage {
+            peer: peer.clone(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_recv: self.bytes_recv.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_recv: self.packets_recv.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Records relayed traffic per peer.
+///
+/// The recorder is owned by the actor that relays the packets. It keeps the counters of
+/// the peers it knows, so counting a packet takes no lock. The shared map of the
+/// [`UsageReader`]s is only locked when a peer is added or removed.
+#[derive(Debug, Default)]
+pub(crate) struct UsageRecorder {
+    counters: HashMap<PublicKey, Arc<Counters>>,
+    reader: UsageReader,
+}
+
+impl UsageRecorder {
+    /// A reader for the usage of the peers of this recorder.
+    pub(crate) fn reader(&self) -> UsageReader {
+        self.reader.clone()
+    }
+
+    /// Records a packet of `len` bytes relayed to `peer`.
+    pub(crate) fn record_sent(&mut self, peer: &PublicKey, len: usize) {
+        let counters = self.counters(peer);
+        counters.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        counters.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a packet of `len` bytes relayed from `peer`.
+    pub(crate) fn record_recv(&mut self, peer: &PublicKey, len: usize) {
+        let counters = self.counters(peer);
+        counters.bytes_recv.fetch_add(len as u64, Ordering::Relaxed);
+        counters.packets_recv.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stops tracking `peer`, returning its final usage if anything was recorded.
+    pub(crate) fn remove(&mut self, peer: &PublicKey) -> Option<PeerUsage> {
+        let counters = self.counters.remove(peer)?;
+        self.reader.0.lock().unwrap().remove(peer);
+        Some(counters.usage(peer))
+    }
+
+    fn counters(&mut self, peer: &PublicKey) -> &Counters {
+        self.counters.entry(peer.clone()).or_insert_with(|| {
+            let counters = Arc::new(Counters::default());
+            let mut shared = self.reader.0.lock().unwrap();
+            shared.insert(peer.clone(), counters.clone());
+            counters
+        })
+    }
+}
+
+/// Reads the usage recorded by a [`UsageRecorder`], cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UsageReader(Arc<Mutex<HashMap<PublicKey, Arc<Counters>>>>);
+
+impl UsageReader {
+    /// The usage of all peers, the peers with the most relayed bytes first.
+    pub(crate) fn usage(&self) -> Vec<PeerUsage> {
+        let mut usage: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, counters)| counters.usage(peer))
+            .collect();
+        usage.sort_by(|a, b| b.total_bytes().cmp(&a.total_bytes()));
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::node::SecretKey;
+
+    #[test]
+    fn usage_by_peer() {
+        let a = SecretKey::generate().public_key();
+        let b = SecretKey::generate().public_key();
+        let mut recorder = UsageRecorder::default();
+        let reader = recorder.reader();
+        recorder.record_sent(&a, 100);
+        recorder.record_recv(&a, 50);
+        recorder.record_sent(&b, 1000);
+        recorder.record_sent(&b, 24);
+
+        let usage = reader.usage();
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[0].peer, b);
+        assert_eq!(usage[0].bytes_sent, 1024);
+        assert_eq!(usage[0].packets_sent, 2);
+        assert_eq!(usage[0].packets_recv, 0);
+        assert_eq!(usage[1].peer, a);
+        assert_eq!(usage[1].total_bytes(), 150);
+        assert_eq!(usage[1].packets_recv, 1);
+
+        // removed peers are no longer tracked
+        let removed = recorder.remove(&a).unwrap();
+        assert_eq!(removed.total_bytes(), 150);
+        assert!(recorder.remove(&a).is_none());
+        assert_eq!(reader.usage().len(), 1);
+    }
+}
@@ -25,6 +25,7 @@ use std::{
 
 use anyhow::{anyhow, ensure, Result};
 
+use crate::config::NatType;
 use crate::net::ip::to_canonical;
 
 use super::{key, stun};
@@ -48,6 +49,11 @@ const HEADER_LEN: usize = 2;
 
 const PING_LEN: usize = TX_LEN + key::node::PUBLIC_KEY_LENGTH;
 const PONG_LEN: usize = TX_LEN + EP_LENGTH;
+/// The maximum number of endpoints in a [`CallMeMaybe`] message.
+///
+/// Call-me-maybe messages arrive from untrusted peers. A node has a handful of endpoints,
+/// anything beyond this limit is not a message of a well-behaved peer.
+pub(crate) const MAX_CALL_ME_MAYBE_ENDPOINTS: usize = 128;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
@@ -119,6 +125,11 @@ pub struct Ping {
     /// It shouldn't be trusted by itself, but can be combined with
     /// netmap data to reduce the discokey:nodekey relation from 1:N to 1:1.
     pub node_key: key::node::PublicKey,
+
+    /// The NAT type of the sender, used to correlate hole punching outcomes.
+    ///
+    /// Sent as an optional trailing byte, older peers ignore it.
+    pub nat_type: NatType,
 }
 
 /// A response a Ping.
@@ -156,8 +167,15 @@ impl Ping {
             .unwrap();
         let node_key = key::node::PublicKey::from(raw_key);
         let tx_id = stun::TransactionId::from(tx_id);
-
-        Ok(Ping { tx_id, node_key })
+        let nat_type = p
+            .get(PING_LEN)
+            .map_or(NatType::Unknown, |b| NatType::from_u8(*b));
+
+        Ok(Ping {
+            tx_id,
+            node_key,
+            nat_type,
+        })
     }
 
     fn as_bytes(&self) -> Vec<u8> {
@@ -167,6 +185,9 @@ impl Ping {
         out[..HEADER_LEN].copy_from_slice(&header);
         out[HEADER_LEN..HEADER_LEN + TX_LEN].copy_from_slice(&self.tx_id);
         out[HEADER_LEN + TX_LEN..].copy_from_slice(self.node_key.as_ref());
+        if self.nat_type != NatType::Unknown {
+            out.push(self.nat_type.to_u8());
+        }
 
         out
     }
@@ -227,6 +248,10 @@ impl CallMeMaybe {
         ensure!(p.len() % EP_LENGTH == 0, "invalid entries");
 
         let num_entries = p.len() / EP_LENGTH;
+        ensure!(
+            num_entries <= MAX_CALL_ME_MAYBE_ENDPOINTS,
+            "too many endpoints: {num_entries}"
+        );
         let mut m = CallMeMaybe {
             my_number: Vec::with_capacity(num_entries),
         };
@@ -313,6 +338,8 @@ const fn msg_header(t: MessageType, ver: u8) -> [u8; HEADER_LEN] {
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -328,9 +355,19 @@ mod tests {
                 m: Message::Ping(Ping {
                     tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
                     node_key: key::node::PublicKey::from([0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 30, 31]),
+                    nat_type: NatType::Unknown,
                 }),
                 want: "01 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 00 01 02 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 1e 1f",
             },
+            Test {
+                name: "ping_with_nat_type",
+                m: Message::Ping(Ping {
+                    tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
+                    node_key: key::node::PublicKey::from([0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 30, 31]),
+                    nat_type: NatType::EndpointDependent,
+                }),
+                want: "01 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 00 01 02 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 1e 1f 04",
+            },
             Test {
                 name: "pong",
                 m: Message::Pong(Pong{
@@ -385,6 +422,7 @@ mod tests {
         let msg = Message::Ping(Ping {
             tx_id: stun::TransactionId::default(),
             node_key: sender_node_key,
+            nat_type: NatType::Unknown,
         });
 
         let sender_disco_key = key::node::SecretKey::generate();
@@ -408,4 +446,55 @@ mod tests {
         let msg_back = Message::from_bytes(&open_seal).unwrap();
         assert_eq!(msg_back, msg);
     }
+
+    #[test]
+    fn test_call_me_maybe_limit() {
+        let my_number = vec!["1.2.3.4:5".parse().unwrap(); MAX_CALL_ME_MAYBE_ENDPOINTS + 1];
+        let bytes = Message::CallMeMaybe(CallMeMaybe { my_number }).as_bytes();
+        assert!(Message::from_bytes(&bytes).is_err());
+    }
+
+    fn socket_addr() -> impl Strategy<Value = SocketAddr> {
+        // only canonical addresses survive a roundtrip
+        (any::<[u8; 16]>(), any::<u16>())
+            .prop_map(|(ip, port)| SocketAddr::new(to_canonical(IpAddr::from(ip)), port))
+    }
+
+    fn message() -> impl Strategy<Value = Message> {
+        prop_oneof![
+            (any::<[u8; TX_LEN]>(), any::<[u8; KEY_LEN]>(), 0..=4u8).prop_map(
+                |(tx_id, key, nat_type)| {
+                    Message::Ping(Ping {
+                        tx_id: tx_id.into(),
+                        node_key: key::node::PublicKey::from(key),
+                        nat_type: NatType::from_u8(nat_type),
+                    })
+                }
+            ),
+            (any::<[u8; TX_LEN]>(), socket_addr()).prop_map(|(tx_id, src)| {
+                Message::Pong(Pong {
+                    tx_id: tx_id.into(),
+                    src,
+                })
+            }),
+            proptest::collection::vec(socket_addr(), 0..=MAX_CALL_ME_MAYBE_ENDPOINTS)
+                .prop_map(|my_number| Message::CallMeMaybe(CallMeMaybe { my_number })),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn message_roundtrip(msg in message()) {
+            let back = Message::from_bytes(&msg.as_bytes()).unwrap();
+            prop_assert_eq!(msg, back);
+        }
+
+        #[test]
+        fn message_from_arbitrary_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+            // must not panic, and whatever parses must serialize to something equivalent
+            if let Ok(msg) = Message::from_bytes(&bytes) {
+                prop_assert_eq!(Message::from_bytes(&msg.as_bytes()).unwrap(), msg);
+            }
+        }
+    }
 }
@@ -1,9 +1,226 @@
+//! DNS resolution for DERP and STUN hostnames.
+//!
+//! By default names are resolved with the nameservers of the system configuration. Nodes
+//! behind broken or captive DNS can instead use a fixed set of nameservers, or resolve
+//! over HTTPS. Every endpoint uses its own [`DnsResolver`], see
+//! [`MagicEndpointBuilder::dns_resolver`](crate::magic_endpoint::MagicEndpointBuilder::dns_resolver).
+//! Endpoints without one share a resolver with the system configuration.
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveError;
 use trust_dns_resolver::TokioAsyncResolver;
 
-pub static DNS_RESOLVER: Lazy<TokioAsyncResolver> = Lazy::new(|| {
-    TokioAsyncResolver::tokio_from_system_conf().expect("unable to create DNS resolver")
-});
+/// The resolver of endpoints that were not given one.
+static SYSTEM_RESOLVER: Lazy<DnsResolver> =
+    Lazy::new(|| DnsResolver::new(DnsConfig::System).expect("unable to create DNS resolver"));
+
+/// How hostnames are resolved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum DnsConfig {
+    /// Use the nameservers of the system configuration.
+    ///
+    /// Falls back to public nameservers if the system configuration can not be read.
+    #[default]
+    System,
+    /// Use these nameservers over UDP and TCP.
+    Nameservers {
+        /// The addresses of the nameservers, usually on port 53.
+        addrs: Vec<SocketAddr>,
+    },
+    /// Use DNS-over-HTTPS.
+    Https {
+        /// The addresses of the servers, usually on port 443.
+        addrs: Vec<SocketAddr>,
+        /// The name the TLS certificates of the servers are issued for.
+        tls_name: String,
+    },
+}
+
+impl DnsConfig {
+    /// DNS-over-HTTPS with the Cloudflare resolvers.
+    pub fn cloudflare_https() -> Self {
+        DnsConfig::Https {
+            addrs: vec![
+                "1.1.1.1:443".parse().unwrap(),
+                "1.0.0.1:443".parse().unwrap(),
+                "[2606:4700:4700::1111]:443".parse().unwrap(),
+                "[2606:4700:4700::1001]:443".parse().unwrap(),
+            ],
+            tls_name: "cloudflare-dns.com".to_string(),
+        }
+    }
+
+    /// Checks that nameservers are configured where they are needed.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            DnsConfig::System => {}
+            DnsConfig::Nameservers { addrs } => {
+                if addrs.is_empty() {
+                    bail!("no nameservers configured");
+                }
+            }
+            DnsConfig::Https { addrs, tls_name } => {
+                if addrs.is_empty() {
+                    bail!("no DNS-over-HTTPS servers configured");
+                }
+                if tls_name.is_empty() {
+                    bail!("the TLS name of the DNS-over-HTTPS servers is empty");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn resolver_config(&self) -> (ResolverConfig, ResolverOpts) {
+        match self {
+            DnsConfig::System => match trust_dns_resolver::system_conf::read_system_conf() {
+                Ok(conf) => conf,
+                Err(err) => {
+                    warn!("unable to read the system DNS configuration, using public nameservers: {err}");
+                    (ResolverConfig::default(), ResolverOpts::default())
+                }
+            },
+            DnsConfig::Nameservers { addrs } => {
+                let mut group = NameServerConfigGroup::new();
+                for addr in addrs {
+                    group.merge(NameServerConfigGroup::from_ips_clear(
+                        &[addr.ip()],
+                        addr.port(),
+                        true,
+                    ));
+                }
+                let config = ResolverConfig::from_parts(None, Vec::new(), group);
+                (config, ResolverOpts::default())
+            }
+            DnsConfig::Https { addrs, tls_name } => {
+                let mut group = NameServerConfigGroup::new();
+                for addr in addrs {
+                    group.merge(NameServerConfigGroup::from_ips_https(
+                        &[addr.ip()],
+                        addr.port(),
+                        tls_name.clone(),
+                        true,
+                    ));
+                }
+                let config = ResolverConfig::from_parts(None, Vec::new(), group);
+                (config, ResolverOpts::default())
+            }
+        }
+    }
+}
+
+impl fmt::Display for DnsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsConfig::System => write!(f, "system resolver"),
+            DnsConfig::Nameservers { addrs } => write!(f, "nameservers {addrs:?}"),
+            DnsConfig::Https { tls_name, .. } => write!(f, "DNS-over-HTTPS via {tls_name}"),
+        }
+    }
+}
+
+/// Resolves hostnames as configured by a [`DnsConfig`].
+///
+/// Clones share the same resolver and its cache.
+#[derive(Clone)]
+pub struct DnsResolver {
+    config: Arc<DnsConfig>,
+    resolver: Arc<TokioAsyncResolver>,
+}
+
+impl fmt::Debug for DnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DnsResolver").field(&self.config).finish()
+    }
+}
+
+/// The resolver with the system configuration, shared by everything that uses the default.
+impl Default for DnsResolver {
+    fn default() -> Self {
+        SYSTEM_RESOLVER.clone()
+    }
+}
+
+impl DnsResolver {
+    /// Creates a resolver for `config`.
+    ///
+    /// Fails if the config is invalid.
+    pub fn new(config: DnsConfig) -> Result<Self> {
+        config.validate()?;
+        let (resolver_config, opts) = config.resolver_config();
+        let resolver = TokioAsyncResolver::tokio(resolver_config, opts)?;
+        Ok(Self {
+            config: Arc::new(config),
+            resolver: Arc::new(resolver),
+        })
+    }
+
+    /// The config hostnames are resolved with.
+    pub fn config(&self) -> &DnsConfig {
+        &self.config
+    }
+
+    /// Resolves `host` to its addresses.
+    pub(crate) async fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        let addrs = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|source| DnsError::Lookup {
+                host: host.to_string(),
+                resolver: self.config.to_string(),
+                source,
+            })?;
+        Ok(addrs.iter().collect())
+    }
+
+    /// Resolves `host` to its first address that matches `filter`.
+    pub(crate) async fn lookup_ip_filtered(
+        &self,
+        host: &str,
+        filter: impl Fn(&IpAddr) -> bool,
+    ) -> Result<IpAddr, DnsError> {
+        self.lookup_ip(host)
+            .await?
+            .into_iter()
+            .find(filter)
+            .ok_or_else(|| DnsError::NoAddresses {
+                host: host.to_string(),
+                resolver: self.config.to_string(),
+            })
+    }
+}
+
+/// Resolving a hostname failed.
+#[derive(Debug, thiserror::Error)]
+pub enum DnsError {
+    /// The lookup failed.
+    #[error("failed to resolve {host} ({resolver}): {source}")]
+    Lookup {
+        /// The hostname.
+        host: String,
+        /// The resolver that was used.
+        resolver: String,
+        /// The error of the lookup.
+        source: ResolveError,
+    },
+    /// The hostname has no addresses of the requested family.
+    #[error("{host} has no usable addresses ({resolver})")]
+    NoAddresses {
+        /// The hostname.
+        host: String,
+        /// The resolver that was used.
+        resolver: String,
+    },
+}
 
 #[cfg(test)]
 mod tests {
@@ -13,9 +230,25 @@ mod tests {
 
     #[tokio::test]
     async fn test_dns_lookup() {
-        let res = DNS_RESOLVER.lookup_ip(NA_DERP_HOSTNAME).await.unwrap();
-        let res: Vec<_> = res.iter().collect();
+        let res = DnsResolver::default()
+            .lookup_ip(NA_DERP_HOSTNAME)
+            .await
+            .unwrap();
         assert!(!res.is_empty());
         dbg!(res);
     }
+
+    #[test]
+    fn test_dns_config() {
+        assert!(DnsConfig::default().validate().is_ok());
+        assert!(DnsConfig::cloudflare_https().validate().is_ok());
+        assert!(DnsConfig::Nameservers { addrs: Vec::new() }
+            .validate()
+            .is_err());
+        assert!(DnsResolver::new(DnsConfig::Nameservers { addrs: Vec::new() }).is_err());
+        assert_eq!(
+            DnsConfig::cloudflare_https().to_string(),
+            "DNS-over-HTTPS via cloudflare-dns.com"
+        );
+    }
 }
@@ -0,0 +1,41 @@
+//! Entry points for fuzzing the parsers of untrusted input.
+//!
+//! The parsers themselves are private, the functions here feed them arbitrary bytes and
+//! check the invariants that must hold for any input. They are used by the targets in the
+//! `fuzz` directory of the repository and panic on a violated invariant.
+
+use bytes::BytesMut;
+
+use crate::derp::{self, FrameType, MAX_FRAME_SIZE};
+use crate::disco;
+
+/// Parses a disco message, and checks that whatever parses survives a roundtrip.
+pub fn disco_message(data: &[u8]) {
+    if let Ok(msg) = disco::Message::from_bytes(data) {
+        let back = disco::Message::from_bytes(&msg.as_bytes()).expect("roundtrip");
+        assert_eq!(back, msg);
+    }
+}
+
+/// Reads DERP frames from a stream and parses the packets, as client and as server.
+pub fn derp_frames(data: &[u8]) {
+    let mut reader = data;
+    let mut buf = BytesMut::new();
+    while let Ok((frame_type, len)) =
+        futures::executor::block_on(derp::read_frame(&mut reader, MAX_FRAME_SIZE, &mut buf))
+    {
+        assert_eq!(buf.len(), len);
+        match frame_type {
+            FrameType::RecvPacket => {
+                derp::client::parse_recv_frame(buf.clone()).ok();
+            }
+            FrameType::SendPacket => {
+                derp::client_conn::parse_send_packet(&buf).ok();
+            }
+            FrameType::ForwardPacket => {
+                derp::client_conn::parse_forward_packet(&buf).ok();
+            }
+            _ => {}
+        }
+    }
+}
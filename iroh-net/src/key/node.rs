@@ -88,6 +88,26 @@ impl TryFrom<&[u8]> for PublicKey {
     }
 }
 
+impl Serialize for PublicKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        serdect::array::serialize_hex_upper_or_bin(self.0.as_bytes(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let mut bytes = [0u8; PUBLIC_KEY_LENGTH];
+        serdect::array::deserialize_hex_or_bin(&mut bytes, deserializer)?;
+        Ok(PublicKey::from(bytes))
+    }
+}
+
 impl PublicKey {
     /// Borrow the public key as bytes.
     pub fn as_bytes(&self) -> &[u8; PUBLIC_KEY_LENGTH] {
@@ -277,4 +297,12 @@ mod tests {
             .unwrap();
         assert_eq!(&msg[..], &decrypted_message);
     }
+
+    #[test]
+    fn test_public_key_postcard_roundtrip() {
+        let key = SecretKey::generate().public_key();
+        let bytes = postcard::to_stdvec(&key).unwrap();
+        let key2: PublicKey = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(key, key2);
+    }
 }
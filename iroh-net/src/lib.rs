@@ -11,7 +11,9 @@ pub mod config;
 pub mod defaults;
 pub mod derp;
 mod disco;
-mod dns;
+pub mod dns;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 pub mod key;
 pub mod magic_endpoint;
 pub mod magicsock;
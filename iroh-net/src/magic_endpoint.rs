@@ -1,24 +1,43 @@
 //! An endpoint that leverages a [quinn::Endpoint] backed by a [magicsock::MagicSock].
 
 use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    future::Future,
     net::SocketAddr,
+    pin::Pin,
     sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
     time::Duration,
 };
 
 use anyhow::{anyhow, Context};
-use quinn_proto::VarInt;
+use quinn_proto::{Side, VarInt};
 use tracing::{debug, trace};
 
+use self::limits::{IpSlot, Limiter};
+use self::qlog::QlogConfig;
 use crate::{
     config,
-    derp::DerpMap,
+    derp::{DerpMap, PeerUsage},
+    dns::DnsResolver,
     key,
-    magicsock::{self, Callbacks, MagicSock},
+    magicsock::{self, keepalive::KeepaliveConfig, Callbacks, MagicSock, PacketCapture},
     netmap::NetworkMap,
-    tls::{self, Keypair, PeerId},
+    tls::{self, AlpnOverride, Keypair, PeerId, RotatingTicketer},
 };
 
+mod limits;
+pub mod qlog;
+
+pub use self::limits::ConnectionLimits;
+
+/// Application error code used to close connections refused by the [`ConnectionLimits`].
+///
+/// This is the rate limited code of the error code registry of iroh-bytes, so that getters
+/// can tell a refused connection from a lost one.
+const CONNECTION_REFUSED: VarInt = VarInt::from_u32(5);
+
 /// Builder for [MagicEndpoint]
 #[derive(Debug, Default)]
 pub struct MagicEndpointBuilder {
@@ -27,7 +46,20 @@ pub struct MagicEndpointBuilder {
     alpn_protocols: Vec<Vec<u8>>,
     transport_config: Option<quinn::TransportConfig>,
     concurrent_connections: Option<u32>,
+    connection_limits: ConnectionLimits,
+    allowed_peers: Option<HashSet<PeerId>>,
+    session_ticketer: Option<Arc<RotatingTicketer>>,
+    alpn_overrides: HashMap<Vec<u8>, AlpnOverride>,
     keylog: bool,
+    packet_capture: Option<PacketCapture>,
+    qlog: Option<QlogConfig>,
+    segmentation_offload: Option<bool>,
+    keepalive: Option<KeepaliveConfig>,
+    dns_resolver: Option<DnsResolver>,
+    /// `None` keeps quinn's default.
+    mtu_discovery: Option<Option<quinn::MtuDiscoveryConfig>>,
+    #[cfg(any(test, feature = "test-utils"))]
+    virtual_host: Option<magicsock::sim::VirtualHost>,
     callbacks: Callbacks,
 }
 
@@ -88,6 +120,115 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Limits on incoming connections, to protect public endpoints from connection floods.
+    ///
+    /// The total number of connections and the handshake rate are enforced before any
+    /// TLS work is done. The per IP limit is enforced in [`MagicEndpoint::accept`], before
+    /// the connection is returned.
+    pub fn connection_limits(mut self, connection_limits: ConnectionLimits) -> Self {
+        self.connection_limits = connection_limits;
+        self
+    }
+
+    /// Only accept incoming connections from these peers.
+    ///
+    /// Connections from any other peer fail the TLS handshake, before the connection is
+    /// returned from [`MagicEndpoint::accept`]. Outgoing connections are unaffected.
+    ///
+    /// By default connections from all peers are accepted.
+    pub fn allowed_peers(mut self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.allowed_peers = Some(peers.into_iter().collect());
+        self
+    }
+
+    /// Enable TLS session tickets for incoming connections, encrypted by `ticketer`.
+    ///
+    /// Session tickets allow peers to resume a session with a shorter handshake. The
+    /// [`RotatingTicketer`] rotates its key on a schedule, and can be used to force a
+    /// rotation. By default no session tickets are issued.
+    pub fn session_ticketer(mut self, ticketer: Arc<RotatingTicketer>) -> Self {
+        self.session_ticketer = Some(ticketer);
+        self
+    }
+
+    /// Customize the TLS configuration for incoming connections negotiating `alpn`.
+    ///
+    /// This allows e.g. to only accept a few pinned peers on an admin protocol, while
+    /// accepting everyone on the others. `alpn` must be one of the protocols set with
+    /// [`Self::alpns`].
+    pub fn alpn_override(mut self, alpn: Vec<u8>, alpn_override: AlpnOverride) -> Self {
+        self.alpn_overrides.insert(alpn, alpn_override);
+        self
+    }
+
+    /// Record metadata of all packets sent and received by the endpoint into `capture`.
+    ///
+    /// This is meant for debugging connectivity problems, see [`PacketCapture`]. Keep a
+    /// clone of the capture to read the packets.
+    pub fn packet_capture(mut self, capture: PacketCapture) -> Self {
+        self.packet_capture = Some(capture);
+        self
+    }
+
+    /// Write qlog traces of the connections selected by `config`.
+    ///
+    /// Connections opened with [`MagicEndpoint::connect`] are traced automatically,
+    /// accepted connections once they are passed to [`MagicEndpoint::qlog_accepted`].
+    pub fn qlog(mut self, config: QlogConfig) -> Self {
+        self.qlog = Some(config);
+        self
+    }
+
+    /// Configure path MTU discovery for all connections, or disable it with `None`.
+    ///
+    /// By default quinn searches for an MTU up to 1452 bytes of UDP payload. On networks
+    /// with jumbo frames, raise the [`quinn::MtuDiscoveryConfig::upper_bound`] to use larger
+    /// packets. The largest payload received from a peer is reported in
+    /// [`magicsock::EndpointInfo::largest_udp_payload`].
+    pub fn mtu_discovery(mut self, config: Option<quinn::MtuDiscoveryConfig>) -> Self {
+        self.mtu_discovery = Some(config);
+        self
+    }
+
+    /// Whether to send batches of UDP packets using segmentation offload, if the OS
+    /// supports it.
+    ///
+    /// Enabled by default. Receive offload is always used when available.
+    pub fn segmentation_offload(mut self, enabled: bool) -> Self {
+        self.segmentation_offload = Some(enabled);
+        self
+    }
+
+    /// Sets the keepalives sent on quiet DERP connections and direct paths.
+    ///
+    /// Keepalives stop NATs and firewalls from dropping the bindings of long-lived sessions
+    /// that rarely send data. Enabled by default, see [`KeepaliveConfig`].
+    pub fn keepalive(mut self, config: KeepaliveConfig) -> Self {
+        self.keepalive = Some(config);
+        self
+    }
+
+    /// Sets the resolver used to look up the hostnames of DERP and STUN servers.
+    ///
+    /// Endpoints built with the same [`DnsResolver`] share its cache. If unset, the system
+    /// configuration is used.
+    pub fn dns_resolver(mut self, resolver: DnsResolver) -> Self {
+        self.dns_resolver = Some(resolver);
+        self
+    }
+
+    /// Bind on a host of a [`VirtualNetwork`] instead of the OS sockets.
+    ///
+    /// This is meant for tests and only available with the `test-utils` feature, see
+    /// [`magicsock::sim`] for what the virtual network simulates.
+    ///
+    /// [`VirtualNetwork`]: magicsock::sim::VirtualNetwork
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn virtual_host(mut self, host: magicsock::sim::VirtualHost) -> Self {
+        self.virtual_host = Some(host);
+        self
+    }
+
     /// Optionally set a callback function to be called when endpoints change.
     #[allow(clippy::type_complexity)]
     pub fn on_endpoints(
@@ -121,35 +262,83 @@ impl MagicEndpointBuilder {
     /// NOTE: This will be improved soon to add support for binding on specific addresses.
     pub async fn bind(self, bind_port: u16) -> anyhow::Result<MagicEndpoint> {
         let keypair = self.keypair.unwrap_or_else(Keypair::generate);
+        let limiter = self
+            .connection_limits
+            .needs_limiter()
+            .then(|| Limiter::new(self.connection_limits.clone()));
+        let mut transport_config = self.transport_config.unwrap_or_default();
+        if let Some(mtu_discovery) = &self.mtu_discovery {
+            transport_config.mtu_discovery_config(mtu_discovery.clone());
+        }
         let mut server_config = make_server_config(
             &keypair,
+            self.allowed_peers,
+            self.session_ticketer,
             self.alpn_protocols,
-            self.transport_config,
+            self.alpn_overrides,
+            Some(transport_config),
             self.keylog,
+            limiter.as_ref(),
         )?;
-        if let Some(c) = self.concurrent_connections {
+        let concurrent_connections = match (
+            self.concurrent_connections,
+            self.connection_limits.max_connections,
+        ) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if let Some(c) = concurrent_connections {
             server_config.concurrent_connections(c);
         }
+        let msock_opts = magicsock::Options {
+            port: bind_port,
+            derp_map: Some(self.derp_map.unwrap_or_default()),
+            private_key: keypair.secret().clone().into(),
+            callbacks: self.callbacks,
+            packet_capture: self.packet_capture,
+            segmentation_offload: self.segmentation_offload.unwrap_or(true),
+            keepalive: self.keepalive.unwrap_or_default(),
+            dns_resolver: self.dns_resolver.unwrap_or_default(),
+            #[cfg(any(test, feature = "test-utils"))]
+            virtual_host: self.virtual_host,
+        };
         MagicEndpoint::bind(
             keypair,
-            bind_port,
+            msock_opts,
             Some(server_config),
-            self.derp_map,
-            Some(self.callbacks),
             self.keylog,
+            limiter,
+            self.mtu_discovery,
+            self.qlog,
         )
         .await
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn make_server_config(
     keypair: &Keypair,
+    allowed_peers: Option<HashSet<PeerId>>,
+    session_ticketer: Option<Arc<RotatingTicketer>>,
     alpn_protocols: Vec<Vec<u8>>,
+    alpn_overrides: HashMap<Vec<u8>, AlpnOverride>,
     transport_config: Option<quinn::TransportConfig>,
     keylog: bool,
+    limiter: Option<&Limiter>,
 ) -> anyhow::Result<quinn::ServerConfig> {
-    let tls_server_config = tls::make_server_config(keypair, alpn_protocols, keylog)?;
-    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
+    let mut builder = tls::ServerConfigBuilder::new(keypair.clone())
+        .alpns(alpn_protocols)
+        .allowed_peers(allowed_peers)
+        .session_ticketer(session_ticketer)
+        .keylog(keylog);
+    for (alpn, alpn_override) in alpn_overrides {
+        builder = builder.alpn_override(alpn, alpn_override);
+    }
+    let mut crypto = builder.build()?;
+    if let Some(limiter) = limiter {
+        crypto = limiter.wrap(crypto);
+    }
+    let mut server_config = quinn::ServerConfig::with_crypto(crypto);
     server_config.transport_config(Arc::new(transport_config.unwrap_or_default()));
     Ok(server_config)
 }
@@ -161,7 +350,16 @@ pub struct MagicEndpoint {
     msock: MagicSock,
     endpoint: quinn::Endpoint,
     netmap: Arc<Mutex<NetworkMap>>,
+    /// Peer ids of the peers added with [`MagicEndpoint::add_known_addrs`], by node key.
+    peer_ids: Arc<Mutex<HashMap<key::node::PublicKey, PeerId>>>,
     keylog: bool,
+    limiter: Option<Limiter>,
+    /// An incoming connection whose remote IP is being looked up in [`MagicEndpoint::accept`].
+    ///
+    /// It is kept here so that it is not lost if the accept future is dropped meanwhile.
+    pending: Arc<tokio::sync::Mutex<Option<quinn::Connecting>>>,
+    mtu_discovery: Option<Option<quinn::MtuDiscoveryConfig>>,
+    qlog: Option<Arc<QlogConfig>>,
 }
 
 impl MagicEndpoint {
@@ -176,19 +374,14 @@ impl MagicEndpoint {
     /// [Self::builder]. See the methods on the builder for documentation of the parameters.
     async fn bind(
         keypair: Keypair,
-        bind_port: u16,
+        msock_opts: magicsock::Options,
         server_config: Option<quinn::ServerConfig>,
-        derp_map: Option<DerpMap>,
-        callbacks: Option<Callbacks>,
         keylog: bool,
+        limiter: Option<Limiter>,
+        mtu_discovery: Option<Option<quinn::MtuDiscoveryConfig>>,
+        qlog: Option<QlogConfig>,
     ) -> anyhow::Result<Self> {
-        let msock = magicsock::MagicSock::new(magicsock::Options {
-            port: bind_port,
-            derp_map: Some(derp_map.unwrap_or_default()),
-            private_key: keypair.secret().clone().into(),
-            callbacks: callbacks.unwrap_or_default(),
-        })
-        .await?;
+        let msock = magicsock::MagicSock::new(msock_opts).await?;
         trace!("created magicsock");
 
         let endpoint = quinn::Endpoint::new_with_abstract_socket(
@@ -204,13 +397,49 @@ impl MagicEndpoint {
             msock,
             endpoint,
             netmap: Arc::new(Mutex::new(NetworkMap { peers: vec![] })),
+            peer_ids: Default::default(),
             keylog,
+            limiter,
+            pending: Default::default(),
+            mtu_discovery,
+            qlog: qlog.map(Arc::new),
         })
     }
 
     /// Accept an incoming connection on the socket.
-    pub fn accept(&self) -> quinn::Accept<'_> {
-        self.endpoint.accept()
+    ///
+    /// Connections exceeding the per IP limit of the [`ConnectionLimits`] are refused
+    /// here, and never returned.
+    pub async fn accept(&self) -> Option<Connecting> {
+        loop {
+            let Some(limiter) = self.limiter.as_ref().filter(|l| l.limits_ips()) else {
+                let inner = self.endpoint.accept().await?;
+                return Some(Connecting { inner, slot: None });
+            };
+            let mut pending = self.pending.lock().await;
+            if pending.is_none() {
+                *pending = Some(self.endpoint.accept().await?);
+            }
+            let remote = pending.as_ref().expect("just set").remote_address();
+            let ip = self.msock.remote_ip(remote).await;
+            let inner = pending.take().expect("held by the lock");
+            drop(pending);
+            let Some(ip) = ip else {
+                // relayed over DERP, there is no IP to limit
+                return Some(Connecting { inner, slot: None });
+            };
+            if let Some(slot) = limiter.claim(ip) {
+                return Some(Connecting {
+                    inner,
+                    slot: Some(slot),
+                });
+            }
+            debug!("refusing connection from {}: too many connections", ip);
+            // incoming connections can always be converted, which lets us close them
+            if let Ok((conn, _)) = inner.into_0rtt() {
+                conn.close(CONNECTION_REFUSED, b"too many connections");
+            }
+        }
     }
 
     /// Get the peer id of this endpoint.
@@ -247,6 +476,88 @@ impl MagicEndpoint {
         self.msock.my_derp().await
     }
 
+    /// Get information about the connection to a peer, including the path in use.
+    ///
+    /// Returns `None` if the peer is not known.
+    pub async fn connection_info(
+        &self,
+        peer_id: PeerId,
+    ) -> anyhow::Result<Option<magicsock::EndpointInfo>> {
+        let node_key: key::node::PublicKey = peer_id.into();
+        let infos = self.msock.tracked_endpoints().await?;
+        Ok(infos.into_iter().find(|info| info.public_key == node_key))
+    }
+
+    /// Get information about the connections to all known peers.
+    pub async fn connection_infos(&self) -> anyhow::Result<Vec<magicsock::EndpointInfo>> {
+        self.msock.tracked_endpoints().await
+    }
+
+    /// Get the [`PeerId`] for the node key of a peer.
+    ///
+    /// The node key can not be converted back into a peer id, so this only knows the peers
+    /// that were dialed or added with [`MagicEndpoint::add_known_addrs`].
+    pub fn known_peer_id(&self, node_key: &key::node::PublicKey) -> Option<PeerId> {
+        self.peer_ids.lock().unwrap().get(node_key).copied()
+    }
+
+    /// Ping a peer over disco, returning the path and latency of the first pong.
+    pub async fn ping(&self, peer_id: PeerId) -> anyhow::Result<config::PingResult> {
+        let res = self.msock.ping(peer_id.into()).await?;
+        if let Some(err) = &res.err {
+            anyhow::bail!("failed to ping {peer_id}: {err}");
+        }
+        Ok(res)
+    }
+
+    /// Forget the addresses and the connection state of a peer.
+    ///
+    /// Existing connections to the peer are not closed, but lose their path until the
+    /// peer contacts us again.
+    pub async fn forget_peer(&self, peer_id: PeerId) -> anyhow::Result<()> {
+        let node_key: key::node::PublicKey = peer_id.into();
+        self.peer_ids.lock().unwrap().remove(&node_key);
+        let netmap = {
+            let mut netmap = self.netmap.lock().unwrap();
+            netmap.peers.retain(|peer| peer.key != node_key);
+            netmap.clone()
+        };
+        self.msock.set_network_map(netmap).await?;
+        Ok(())
+    }
+
+    /// Get the network conditions found by the last netcheck, if one completed.
+    pub async fn net_info(&self) -> anyhow::Result<Option<config::NetInfo>> {
+        self.msock.net_info().await
+    }
+
+    /// Get the sources of disco messages that failed authentication.
+    ///
+    /// See [`MagicSock::disco_auth_failures`].
+    pub async fn disco_auth_failures(&self) -> anyhow::Result<Vec<magicsock::DiscoAuthFailure>> {
+        self.msock.disco_auth_failures().await
+    }
+
+    /// Get the outcomes of upgrading connections to direct paths.
+    ///
+    /// See [`MagicSock::hole_punch_stats`].
+    pub fn hole_punch_stats(&self) -> magicsock::HolePunchStats {
+        self.msock.hole_punch_stats()
+    }
+
+    /// Get the traffic relayed over DERP per peer.
+    ///
+    /// See [`MagicSock::relay_usage`].
+    pub fn relay_usage(&self) -> Vec<PeerUsage> {
+        self.msock.relay_usage()
+    }
+
+    /// The packet capture of this endpoint, if enabled with
+    /// [`MagicEndpointBuilder::packet_capture`].
+    pub fn packet_capture(&self) -> Option<&PacketCapture> {
+        self.msock.packet_capture()
+    }
+
     /// Connect to a remote endpoint.
     ///
     /// The PeerId and the ALPN protocol are required. If you happen to know dialable addresses of
@@ -285,6 +596,9 @@ impl MagicEndpoint {
             let mut client_config = quinn::ClientConfig::new(Arc::new(tls_client_config));
             let mut transport_config = quinn::TransportConfig::default();
             transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
+            if let Some(mtu_discovery) = &self.mtu_discovery {
+                transport_config.mtu_discovery_config(mtu_discovery.clone());
+            }
             client_config.transport_config(Arc::new(transport_config));
             client_config
         };
@@ -299,7 +613,29 @@ impl MagicEndpoint {
             .endpoint
             .connect_with(client_config, addr, "localhost")?;
 
-        connect.await.context("failed connecting to provider")
+        let conn = connect.await.context("failed connecting to provider")?;
+        if let Some(qlog) = &self.qlog {
+            qlog.trace(&conn, Side::Client, peer_id, alpn);
+        }
+        Ok(conn)
+    }
+
+    /// Write a qlog trace of an accepted connection, if it is selected by the
+    /// [`QlogConfig`] of this endpoint.
+    ///
+    /// Does nothing if qlog traces are not enabled with [`MagicEndpointBuilder::qlog`].
+    pub async fn qlog_accepted(&self, conn: &quinn::Connection) -> anyhow::Result<()> {
+        let Some(qlog) = &self.qlog else {
+            return Ok(());
+        };
+        let peer_id = get_peer_id(conn).await?;
+        let alpn = conn
+            .handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|data| data.protocol)
+            .context("no ALPN protocol available")?;
+        qlog.trace(conn, Side::Server, peer_id, &alpn);
+        Ok(())
     }
 
     /// Inform the magic socket about addresses of the peer.
@@ -334,6 +670,10 @@ impl MagicEndpoint {
         }
 
         let node_key: key::node::PublicKey = peer_id.into();
+        self.peer_ids
+            .lock()
+            .unwrap()
+            .insert(node_key.clone(), peer_id);
         let netmap = {
             let mut netmap = self.netmap.lock().unwrap();
             let node = netmap.peers.iter_mut().find(|peer| peer.key == node_key);
@@ -390,9 +730,54 @@ impl MagicEndpoint {
     }
 }
 
+/// An incoming connection that is still handshaking, returned by [`MagicEndpoint::accept`].
+///
+/// Await it to get the connection. It holds the slot of the remote IP in the
+/// [`ConnectionLimits`], which is passed on to the connection and given back once the
+/// connection is closed, or when this is dropped before the handshake completes.
+#[derive(Debug)]
+pub struct Connecting {
+    inner: quinn::Connecting,
+    slot: Option<IpSlot>,
+}
+
+impl Connecting {
+    /// The parameters of the handshake, available before it completes.
+    ///
+    /// See [`quinn::Connecting::handshake_data`].
+    pub async fn handshake_data(&mut self) -> Result<Box<dyn Any>, quinn::ConnectionError> {
+        self.inner.handshake_data().await
+    }
+
+    /// The mapped address of the remote, see [`quinn::Connecting::remote_address`].
+    pub fn remote_address(&self) -> SocketAddr {
+        self.inner.remote_address()
+    }
+}
+
+impl Future for Connecting {
+    type Output = Result<quinn::Connection, quinn::ConnectionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let conn = match Pin::new(&mut self.inner).poll(cx) {
+            Poll::Ready(Ok(conn)) => conn,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        };
+        if let Some(slot) = self.slot.take() {
+            let conn = conn.clone();
+            tokio::spawn(async move {
+                conn.closed().await;
+                drop(slot);
+            });
+        }
+        Poll::Ready(Ok(conn))
+    }
+}
+
 /// Accept an incoming connection and extract the client-provided [`PeerId`] and ALPN protocol.
 pub async fn accept_conn(
-    mut conn: quinn::Connecting,
+    mut conn: Connecting,
 ) -> anyhow::Result<(PeerId, String, quinn::Connection)> {
     let alpn = get_alpn(&mut conn).await?;
     let conn = conn.await?;
@@ -401,7 +786,7 @@ pub async fn accept_conn(
 }
 
 /// Extract the ALPN protocol from the peer's TLS certificate.
-pub async fn get_alpn(connecting: &mut quinn::Connecting) -> anyhow::Result<String> {
+pub async fn get_alpn(connecting: &mut Connecting) -> anyhow::Result<String> {
     let data = connecting.handshake_data().await?;
     match data.downcast::<quinn::crypto::rustls::HandshakeData>() {
         Ok(data) => match data.protocol {
@@ -437,6 +822,8 @@ pub async fn get_peer_id(connection: &quinn::Connection) -> anyhow::Result<PeerI
 // https://github.com/n0-computer/iroh/issues/1183
 #[cfg(test)]
 mod tests {
+    use std::time::Instant;
+
     use tracing::{info, info_span, Instrument};
 
     use crate::test_utils::{run_derp_and_stun, setup_logging};
@@ -445,6 +832,139 @@ mod tests {
 
     const TEST_ALPN: &[u8] = b"n0/iroh/test";
 
+    #[tokio::test(start_paused = true)]
+    async fn magic_endpoint_virtual_network() -> anyhow::Result<()> {
+        use crate::magicsock::sim::{Nat, VirtualNetwork};
+
+        let _guard = setup_logging();
+        let network = VirtualNetwork::new(0);
+        let server = MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .virtual_host(network.add_host(Nat::None))
+            .bind(0)
+            .await?;
+        let client = MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .virtual_host(network.add_host(Nat::Cone))
+            .bind(0)
+            .await?;
+        let (server_addr, _) = server.local_addr()?;
+        assert_eq!(server_addr.ip(), std::net::IpAddr::from([198, 18, 0, 1]));
+        let server_peer_id = server.peer_id();
+
+        let server_task = tokio::spawn(async move {
+            let conn = server.accept().await.context("no connection")?;
+            let (_peer_id, _alpn, conn) = accept_conn(conn).await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let data = recv.read_to_end(100).await?;
+            send.write_all(&data).await?;
+            send.finish().await?;
+            anyhow::Ok(())
+        });
+
+        let conn = client
+            .connect(server_peer_id, TEST_ALPN, None, &[server_addr])
+            .await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        assert_eq!(recv.read_to_end(100).await?, b"hello");
+        server_task.await??;
+        Ok(())
+    }
+
+    /// Connects two hosts of a virtual network that only know each other's DERP region,
+    /// and returns the path the client ends up using after `settle`.
+    async fn virtual_network_path(
+        server_nat: crate::magicsock::sim::Nat,
+        client_nat: crate::magicsock::sim::Nat,
+        settle: Duration,
+    ) -> anyhow::Result<(magicsock::ConnectionType, u16)> {
+        use crate::magicsock::sim::VirtualNetwork;
+
+        // DERP runs over TCP on localhost, so these tests use the real clock
+        let (derp_map, region_id, _guard) = run_derp_and_stun([127, 0, 0, 1].into()).await?;
+        let network = VirtualNetwork::new(0);
+        let server = MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .derp_map(Some(derp_map.clone()))
+            .virtual_host(network.add_host(server_nat))
+            .bind(0)
+            .await?;
+        let client = MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .derp_map(Some(derp_map))
+            .virtual_host(network.add_host(client_nat))
+            .bind(0)
+            .await?;
+        let server_peer_id = server.peer_id();
+
+        let server_task = tokio::spawn(async move {
+            let conn = server.accept().await.context("no connection")?;
+            let (_peer_id, _alpn, conn) = accept_conn(conn).await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let data = recv.read_to_end(100).await?;
+            send.write_all(&data).await?;
+            send.finish().await?;
+            // keep the connection open while the client looks at its path
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        let conn = client
+            .connect(server_peer_id, TEST_ALPN, Some(region_id), &[])
+            .await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        assert_eq!(recv.read_to_end(100).await?, b"hello");
+
+        let deadline = Instant::now() + settle;
+        let path = loop {
+            let info = client
+                .connection_info(server_peer_id)
+                .await?
+                .context("server unknown")?;
+            if matches!(info.conn_type, magicsock::ConnectionType::Direct(_))
+                || Instant::now() >= deadline
+            {
+                break info.conn_type;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+        conn.close(0u8.into(), b"done");
+        server_task.await??;
+        Ok((path, region_id))
+    }
+
+    #[tokio::test]
+    async fn virtual_network_hole_punching() -> anyhow::Result<()> {
+        use crate::magicsock::sim::Nat;
+
+        let _guard = setup_logging();
+        // the first packets go through DERP, until the holes are punched
+        let (path, _region_id) =
+            virtual_network_path(Nat::Cone, Nat::Cone, Duration::from_secs(10)).await?;
+        assert!(
+            matches!(path, magicsock::ConnectionType::Direct(_)),
+            "no direct path: {path:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn virtual_network_derp_fallback() -> anyhow::Result<()> {
+        use crate::magicsock::sim::Nat;
+
+        let _guard = setup_logging();
+        // the public address of a symmetric NAT is of no use to peers, so all traffic
+        // stays on DERP
+        let (path, region_id) =
+            virtual_network_path(Nat::Symmetric, Nat::Symmetric, Duration::from_secs(3)).await?;
+        assert_eq!(path, magicsock::ConnectionType::Relay(region_id));
+        Ok(())
+    }
+
     #[ignore]
     #[tokio::test]
     async fn magic_endpoint_connect_close() {
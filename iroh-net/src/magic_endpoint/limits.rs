@@ -0,0 +1,287 @@
+//! Limits on incoming connections.
+//!
+//! The handshake rate is enforced by wrapping the crypto config of the quinn endpoint:
+//! a session that exceeds the rate refuses the client hello, so the connection is
+//! closed with `CONNECTION_REFUSED` before any TLS work is done. The total number of
+//! connections is enforced by quinn itself, in the same place.
+//!
+//! The per IP limit is enforced in [`MagicEndpoint::accept`](super::MagicEndpoint::accept),
+//! before the handshake of the connection is driven any further. Quinn only knows the
+//! mapped address of a peer, so the IP address the peer sends from is looked up in the
+//! magic socket. Keying on the IP rather than on the peer id means a remote can not get
+//! around the limit by generating fresh peer ids or by using more ports. The slot of the
+//! IP is held by the [`Connecting`](super::Connecting), and then by the connection
+//! until it is closed.
+//!
+//! Peers that are only reachable over DERP have no IP address, and are not subject to
+//! the per IP limit. The DERP servers limit their clients instead.
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use quinn_proto::crypto::{
+    self, ExportKeyingMaterialError, HeaderKey, KeyPair, Keys, PacketKey, UnsupportedVersion,
+};
+use quinn_proto::transport_parameters::TransportParameters;
+use quinn_proto::{ConnectionId, Side, TransportError, TransportErrorCode};
+use tracing::debug;
+
+/// Limits on the incoming connections of a [`MagicEndpoint`](super::MagicEndpoint).
+///
+/// All limits are disabled by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Maximum number of open connections, both incoming and outgoing.
+    ///
+    /// Incoming connections beyond this are refused.
+    pub max_connections: Option<u32>,
+    /// Maximum number of incoming handshakes started per second.
+    pub max_handshakes_per_sec: Option<u32>,
+    /// Maximum number of concurrent incoming connections from a single remote IP address.
+    pub max_connections_per_ip: Option<u32>,
+}
+
+impl ConnectionLimits {
+    /// Whether any of the limits needs the [`Limiter`].
+    pub(super) fn needs_limiter(&self) -> bool {
+        self.max_handshakes_per_sec.is_some() || self.max_connections_per_ip.is_some()
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Number of open incoming connections per remote IP address.
+    per_ip: HashMap<IpAddr, u32>,
+    /// Start of the current rate limiting window, and the handshakes started in it.
+    window: Option<(Instant, u32)>,
+}
+
+/// Enforces the handshake rate and per IP [`ConnectionLimits`].
+#[derive(Debug, Clone)]
+pub(super) struct Limiter {
+    limits: ConnectionLimits,
+    state: Arc<Mutex<State>>,
+}
+
+impl Limiter {
+    pub(super) fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            state: Default::default(),
+        }
+    }
+
+    /// Wrap a crypto config so that new sessions are registered with this limiter.
+    pub(super) fn wrap(
+        &self,
+        inner: Arc<dyn crypto::ServerConfig>,
+    ) -> Arc<dyn crypto::ServerConfig> {
+        Arc::new(LimitedServerConfig {
+            inner,
+            limiter: self.clone(),
+        })
+    }
+
+    /// Start a new session, returning whether it is admitted.
+    fn start(&self) -> bool {
+        let Some(max) = self.limits.max_handshakes_per_sec else {
+            return true;
+        };
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match &mut state.window {
+            Some((start, count)) if now.duration_since(*start) < Duration::from_secs(1) => {
+                if *count >= max {
+                    return false;
+                }
+                *count += 1;
+            }
+            window => *window = Some((now, 1)),
+        }
+        true
+    }
+
+    /// Whether connections need a slot of their remote IP.
+    pub(super) fn limits_ips(&self) -> bool {
+        self.limits.max_connections_per_ip.is_some()
+    }
+
+    /// Take a connection slot of `ip`, which is given back when the slot is dropped.
+    ///
+    /// Returns `None` if the IP already has as many connections as allowed.
+    pub(super) fn claim(&self, ip: IpAddr) -> Option<IpSlot> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.per_ip.entry(ip).or_default();
+        if let Some(max) = self.limits.max_connections_per_ip {
+            if *count >= max {
+                return None;
+            }
+        }
+        *count += 1;
+        Some(IpSlot {
+            ip,
+            limiter: self.clone(),
+        })
+    }
+
+    /// Give back a connection slot taken with [`Limiter::claim`].
+    fn release(&self, ip: IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// A connection slot of a remote IP address, see [`Limiter::claim`].
+#[derive(Debug)]
+pub(super) struct IpSlot {
+    ip: IpAddr,
+    limiter: Limiter,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+struct LimitedServerConfig {
+    inner: Arc<dyn crypto::ServerConfig>,
+    limiter: Limiter,
+}
+
+impl crypto::ServerConfig for LimitedServerConfig {
+    fn initial_keys(
+        &self,
+        version: u32,
+        dst_cid: &ConnectionId,
+        side: Side,
+    ) -> Result<Keys, UnsupportedVersion> {
+        self.inner.initial_keys(version, dst_cid, side)
+    }
+
+    fn retry_tag(&self, version: u32, orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; 16] {
+        self.inner.retry_tag(version, orig_dst_cid, packet)
+    }
+
+    fn start_session(
+        self: Arc<Self>,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Box<dyn crypto::Session> {
+        Box::new(LimitedSession {
+            inner: self.inner.clone().start_session(version, params),
+            admitted: self.limiter.start(),
+        })
+    }
+}
+
+struct LimitedSession {
+    inner: Box<dyn crypto::Session>,
+    admitted: bool,
+}
+
+impl crypto::Session for LimitedSession {
+    fn initial_keys(&self, dst_cid: &ConnectionId, side: Side) -> Keys {
+        self.inner.initial_keys(dst_cid, side)
+    }
+
+    fn handshake_data(&self) -> Option<Box<dyn Any>> {
+        self.inner.handshake_data()
+    }
+
+    fn peer_identity(&self) -> Option<Box<dyn Any>> {
+        self.inner.peer_identity()
+    }
+
+    fn early_crypto(&self) -> Option<(Box<dyn HeaderKey>, Box<dyn PacketKey>)> {
+        self.inner.early_crypto()
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        self.inner.early_data_accepted()
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.inner.is_handshaking()
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
+        if !self.admitted {
+            debug!("refusing handshake: too many handshakes");
+            return Err(TransportError {
+                code: TransportErrorCode::CONNECTION_REFUSED,
+                frame: None,
+                reason: "too many handshakes".to_string(),
+            });
+        }
+        self.inner.read_handshake(buf)
+    }
+
+    fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
+        self.inner.transport_parameters()
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Keys> {
+        self.inner.write_handshake(buf)
+    }
+
+    fn next_1rtt_keys(&mut self) -> Option<KeyPair<Box<dyn PacketKey>>> {
+        self.inner.next_1rtt_keys()
+    }
+
+    fn is_valid_retry(&self, orig_dst_cid: &ConnectionId, header: &[u8], payload: &[u8]) -> bool {
+        self.inner.is_valid_retry(orig_dst_cid, header, payload)
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        self.inner.export_keying_material(output, label, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn handshake_rate() {
+        let limiter = Limiter::new(ConnectionLimits {
+            max_handshakes_per_sec: Some(2),
+            ..Default::default()
+        });
+        assert!(limiter.start());
+        assert!(limiter.start());
+        assert!(!limiter.start());
+    }
+
+    #[test]
+    fn per_ip() {
+        let limiter = Limiter::new(ConnectionLimits {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+        let a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let slot = limiter.claim(a).unwrap();
+        assert!(limiter.claim(a).is_none());
+        let _b = limiter.claim(b).unwrap();
+        // closing the first connection frees the slot
+        drop(slot);
+        let _a = limiter.claim(a).unwrap();
+        assert!(limiter.claim(a).is_none());
+    }
+}
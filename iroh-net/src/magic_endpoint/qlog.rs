@@ -0,0 +1,285 @@
+//! qlog traces of selected connections.
+//!
+//! quinn does not log the events of its recovery and congestion controllers, so the traces
+//! are built by sampling the [`quinn::ConnectionStats`] of a connection: every change of
+//! the RTT or congestion window is a `recovery:metrics_updated` event, every new
+//! congestion event a `recovery:congestion_state_updated` event, and lost packets and
+//! sent bytes are reported in `iroh:path_stats` events. The traces are written in the
+//! JSON-SEQ format of qlog 0.3, which standard QUIC visualization tools such as qvis can
+//! read.
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use quinn_proto::Side;
+use serde_json::{json, Value};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tracing::{debug, warn};
+
+use crate::tls::PeerId;
+
+/// Default interval between two samples of the connection stats.
+pub const DEFAULT_QLOG_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Which connections to write qlog traces for, and where.
+///
+/// Without any peers or ALPNs, all connections are traced.
+#[derive(Debug, Clone)]
+pub struct QlogConfig {
+    dir: PathBuf,
+    peers: HashSet<PeerId>,
+    alpns: HashSet<Vec<u8>>,
+    interval: Duration,
+}
+
+impl QlogConfig {
+    /// Writes one trace per connection into `dir`, which is created if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            peers: HashSet::new(),
+            alpns: HashSet::new(),
+            interval: DEFAULT_QLOG_INTERVAL,
+        }
+    }
+
+    /// Traces connections to and from `peer`.
+    pub fn peer(mut self, peer: PeerId) -> Self {
+        self.peers.insert(peer);
+        self
+    }
+
+    /// Traces connections that negotiated `alpn`.
+    pub fn alpn(mut self, alpn: impl Into<Vec<u8>>) -> Self {
+        self.alpns.insert(alpn.into());
+        self
+    }
+
+    /// Sets the interval between two samples of the connection stats.
+    ///
+    /// Defaults to [`DEFAULT_QLOG_INTERVAL`].
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The directory the traces are written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn matches(&self, peer: &PeerId, alpn: &[u8]) -> bool {
+        let peer_matches = self.peers.is_empty() || self.peers.contains(peer);
+        let alpn_matches = self.alpns.is_empty() || self.alpns.contains(alpn);
+        peer_matches && alpn_matches
+    }
+
+    /// Starts tracing `conn` in the background, if it matches this config.
+    pub(super) fn trace(&self, conn: &quinn::Connection, side: Side, peer: PeerId, alpn: &[u8]) {
+        if !self.matches(&peer, alpn) {
+            return;
+        }
+        let name = format!(
+            "{peer}-{}-{}-{}.sqlog",
+            sanitize(alpn),
+            side_name(side),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+        );
+        let path = self.dir.join(name);
+        let conn = conn.clone();
+        let dir = self.dir.clone();
+        let interval = self.interval;
+        tokio::spawn(async move {
+            if let Err(err) = write_trace(&dir, &path, conn, side, interval).await {
+                warn!("failed to write qlog trace to {}: {err:#}", path.display());
+            }
+        });
+    }
+}
+
+fn side_name(side: Side) -> &'static str {
+    match side {
+        Side::Client => "client",
+        Side::Server => "server",
+    }
+}
+
+/// Keeps the printable ASCII characters of an ALPN that are safe in a file name.
+fn sanitize(alpn: &[u8]) -> String {
+    alpn.iter()
+        .map(|&b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'_' => b as char,
+            _ => '-',
+        })
+        .collect()
+}
+
+async fn write_trace(
+    dir: &Path,
+    path: &Path,
+    conn: quinn::Connection,
+    side: Side,
+    interval: Duration,
+) -> Result<()> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+    let file = tokio::fs::File::create(path).await?;
+    let mut out = BufWriter::new(file);
+    debug!("writing qlog trace to {}", path.display());
+
+    let reference_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let start = Instant::now();
+    write_record(
+        &mut out,
+        json!({
+            "qlog_version": "0.3",
+            "qlog_format": "JSON-SEQ",
+            "title": "iroh",
+            "trace": {
+                "vantage_point": { "name": "iroh", "type": side_name(side) },
+                "common_fields": {
+                    "protocol_type": ["QUIC"],
+                    "time_format": "relative",
+                    "reference_time": millis(reference_time),
+                },
+            },
+        }),
+    )
+    .await?;
+    let remote = conn.remote_address();
+    write_event(
+        &mut out,
+        start,
+        "connectivity:connection_started",
+        json!({ "dst_ip": remote.ip().to_string(), "dst_port": remote.port() }),
+    )
+    .await?;
+
+    let mut last: Option<quinn::ConnectionStats> = None;
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let reason = loop {
+        tokio::select! {
+            err = conn.closed() => break err,
+            _ = ticker.tick() => {}
+        }
+        let stats = conn.stats();
+        for (name, data) in sample_events(last.as_ref(), &stats) {
+            write_event(&mut out, start, name, data).await?;
+        }
+        last = Some(stats);
+    };
+    let stats = conn.stats();
+    for (name, data) in sample_events(last.as_ref(), &stats) {
+        write_event(&mut out, start, name, data).await?;
+    }
+    write_event(
+        &mut out,
+        start,
+        "connectivity:connection_closed",
+        json!({ "reason": reason.to_string() }),
+    )
+    .await?;
+    out.flush().await?;
+    Ok(())
+}
+
+/// The events for the changes from `last` to `stats`.
+fn sample_events(
+    last: Option<&quinn::ConnectionStats>,
+    stats: &quinn::ConnectionStats,
+) -> Vec<(&'static str, Value)> {
+    let mut events = Vec::new();
+    let path = &stats.path;
+    let last_path = last.map(|last| &last.path);
+    if last_path.map(|l| (l.rtt, l.cwnd)) != Some((path.rtt, path.cwnd)) {
+        events.push((
+            "recovery:metrics_updated",
+            json!({
+                "smoothed_rtt": millis(path.rtt),
+                "congestion_window": path.cwnd,
+            }),
+        ));
+    }
+    if path.congestion_events > last_path.map_or(0, |l| l.congestion_events) {
+        events.push((
+            "recovery:congestion_state_updated",
+            json!({ "new": "recovery" }),
+        ));
+    }
+    let changed = match last {
+        Some(last) => {
+            last.udp_tx.bytes != stats.udp_tx.bytes
+                || last.udp_rx.bytes != stats.udp_rx.bytes
+                || last.path.lost_packets != path.lost_packets
+        }
+        None => true,
+    };
+    if changed {
+        events.push((
+            "iroh:path_stats",
+            json!({
+                "sent_packets": path.sent_packets,
+                "lost_packets": path.lost_packets,
+                "lost_bytes": path.lost_bytes,
+                "congestion_events": path.congestion_events,
+                "udp_tx_bytes": stats.udp_tx.bytes,
+                "udp_rx_bytes": stats.udp_rx.bytes,
+            }),
+        ));
+    }
+    events
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+async fn write_event(
+    out: &mut BufWriter<tokio::fs::File>,
+    start: Instant,
+    name: &str,
+    data: Value,
+) -> Result<()> {
+    let event = json!({ "time": millis(start.elapsed()), "name": name, "data": data });
+    write_record(out, event).await
+}
+
+/// Writes a JSON-SEQ record: a record separator, the JSON text and a line feed.
+async fn write_record(out: &mut BufWriter<tokio::fs::File>, record: Value) -> Result<()> {
+    let mut buf = vec![0x1e];
+    serde_json::to_writer(&mut buf, &record)?;
+    buf.push(b'\n');
+    out.write_all(&buf).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qlog_filter() {
+        let a = PeerId::from(crate::tls::Keypair::generate().public());
+        let b = PeerId::from(crate::tls::Keypair::generate().public());
+
+        let all = QlogConfig::new("/tmp");
+        assert!(all.matches(&a, b"n0/iroh-bytes/2"));
+
+        let config = QlogConfig::new("/tmp")
+            .peer(a)
+            .alpn(&b"n0/iroh-bytes/2"[..]);
+        assert!(config.matches(&a, b"n0/iroh-bytes/2"));
+        assert!(!config.matches(&b, b"n0/iroh-bytes/2"));
+        assert!(!config.matches(&a, b"other"));
+
+        assert_eq!(sanitize(b"n0/iroh-bytes/2"), "n0-iroh-bytes-2");
+    }
+}
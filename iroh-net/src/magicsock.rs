@@ -32,8 +32,10 @@ use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument
 
 use crate::{
     config::{self, DERP_MAGIC_IP},
-    derp::{DerpMap, DerpRegion},
-    disco, key,
+    derp::{DerpMap, DerpRegion, PeerUsage, UsageReader, UsageRecorder},
+    disco,
+    dns::DnsResolver,
+    key,
     net::ip::LocalAddresses,
     netcheck, netmap, portmapper, stun,
     util::AbortingJoinHandle,
@@ -41,20 +43,36 @@ use crate::{
 
 use self::{
     derp_actor::{DerpActor, DerpActorMessage, DerpReadResult},
+    disco_auth::DiscoAuthTracker,
     endpoint::{Options as EndpointOptions, PeerMap},
+    hole_punch::HolePunchRecorder,
+    keepalive::KeepaliveConfig,
     metrics::Metrics as MagicsockMetrics,
     rebinding_conn::RebindingUdpConn,
     udp_actor::{IpPacket, NetworkReadResult, NetworkSource, UdpActor, UdpActorMessage},
 };
 
+mod capture;
 mod derp_actor;
+mod disco_auth;
 mod endpoint;
+mod hole_punch;
+pub mod keepalive;
 mod metrics;
 mod rebinding_conn;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod sim;
 mod timer;
 mod udp_actor;
 
-pub use self::endpoint::EndpointInfo;
+pub use self::capture::{
+    CapturedPacket, Direction, DiscoKind, PacketCapture, PacketPath, DEFAULT_CAPTURE_CAPACITY,
+};
+pub use self::disco_auth::{DiscoAuthFailure, DISCO_AUTH_FAILURE_THRESHOLD};
+pub use self::endpoint::{ConnectionType, EndpointInfo};
+pub use self::hole_punch::{
+    HolePunchStats, NatPairOutcomes, OutcomeCounts, PathOutcome, HOLE_PUNCH_TIMEOUT,
+};
 pub use self::metrics::Metrics;
 pub use self::timer::Timer;
 
@@ -64,6 +82,17 @@ const ENDPOINTS_FRESH_ENOUGH_DURATION: Duration = Duration::from_secs(27);
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
+/// How long [`MagicSock::ping`] waits for a pong.
+pub const CLI_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of queued transmit groups the actor sends in one go.
+const NETWORK_BATCH_SIZE: usize = 32;
+
+/// The default upper bound of path MTU discovery, as UDP payload size.
+///
+/// Larger datagrams are only sent on paths for which a larger MTU was discovered.
+const DEFAULT_MAX_UDP_PAYLOAD: usize = 1452;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(self) enum CurrentPortFate {
     Keep,
@@ -126,6 +155,25 @@ pub struct Options {
 
     /// Callbacks to emit on various socket events
     pub callbacks: Callbacks,
+
+    /// Record metadata of all sent and received packets into this capture.
+    pub packet_capture: Option<PacketCapture>,
+
+    /// Send batches of UDP packets with generic segmentation offload, if the OS supports it.
+    ///
+    /// If disabled, batches are split into individual packets before sending. Packets sent
+    /// through DERP are always split.
+    pub segmentation_offload: bool,
+
+    /// Keepalives sent on quiet DERP connections and direct paths.
+    pub keepalive: KeepaliveConfig,
+
+    /// Resolver used for DERP and STUN hostnames.
+    pub dns_resolver: DnsResolver,
+
+    /// Bind on a host of a [`sim::VirtualNetwork`] instead of the OS sockets.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub virtual_host: Option<sim::VirtualHost>,
 }
 
 /// Contains options for `MagicSock::listen`.
@@ -152,6 +200,12 @@ impl Default for Options {
             private_key: key::node::SecretKey::generate(),
             derp_map: None,
             callbacks: Default::default(),
+            packet_capture: None,
+            segmentation_offload: true,
+            keepalive: KeepaliveConfig::default(),
+            dns_resolver: DnsResolver::default(),
+            #[cfg(any(test, feature = "test-utils"))]
+            virtual_host: None,
         }
     }
 }
@@ -216,6 +270,19 @@ pub(self) struct Inner {
     pub(self) derp_map: Option<DerpMap>,
     /// Nearest DERP region ID; 0 means none/unknown.
     my_derp: AtomicU16,
+
+    /// Records packet metadata, if enabled.
+    packet_capture: Option<PacketCapture>,
+    /// Whether to send UDP packet batches with segmentation offload.
+    segmentation_offload: bool,
+    /// Keepalives sent on quiet DERP connections and direct paths.
+    pub(self) keepalive: KeepaliveConfig,
+    /// Resolver used for DERP hostnames.
+    pub(self) dns_resolver: DnsResolver,
+    /// Outcomes of upgrading connections to direct paths.
+    hole_punch: HolePunchRecorder,
+    /// Traffic relayed over DERP per peer.
+    relay_usage: UsageReader,
 }
 
 impl Inner {
@@ -255,6 +322,27 @@ impl Inner {
     pub(self) fn is_closed(&self) -> bool {
         self.closed.load(Ordering::SeqCst)
     }
+
+    /// Records a packet in the packet capture, if enabled.
+    pub(self) fn capture(
+        &self,
+        direction: Direction,
+        path: SendAddr,
+        peer: Option<&key::node::PublicKey>,
+        size: usize,
+        disco: Option<DiscoKind>,
+    ) {
+        if let Some(capture) = &self.packet_capture {
+            capture.record(CapturedPacket {
+                time: std::time::SystemTime::now(),
+                direction,
+                path: path.into(),
+                peer: peer.cloned(),
+                size,
+                disco,
+            });
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -311,15 +399,29 @@ impl MagicSock {
                     on_derp_active,
                     on_net_info,
                 },
+            packet_capture,
+            segmentation_offload,
+            keepalive,
+            dns_resolver,
+            #[cfg(any(test, feature = "test-utils"))]
+            virtual_host,
         } = opts;
 
         let (network_recv_ch_sender, network_recv_ch_receiver) = flume::bounded(128);
 
+        #[cfg(any(test, feature = "test-utils"))]
+        let (pconn4, pconn6) = match &virtual_host {
+            Some(host) => (RebindingUdpConn::bind_virtual(host, port)?, None),
+            None => bind(port).await?,
+        };
+        #[cfg(not(any(test, feature = "test-utils")))]
         let (pconn4, pconn6) = bind(port).await?;
         let port = pconn4.port();
 
         // NOTE: we can end up with a zero port if `std::net::UdpSocket::socket_addr` fails
         match port.try_into() {
+            // there is no router to map a port on in a virtual network
+            Ok(_) if pconn4.as_socket().is_none() => {}
             Ok(non_zero_port) => {
                 port_mapper.update_local_port(non_zero_port);
             }
@@ -328,10 +430,13 @@ impl MagicSock {
         let ipv4_addr = pconn4.local_addr()?;
         let ipv6_addr = pconn6.as_ref().and_then(|c| c.local_addr().ok());
 
-        let net_checker = netcheck::Client::new(Some(port_mapper.clone())).await?;
+        let net_checker =
+            netcheck::Client::with_dns_resolver(Some(port_mapper.clone()), dns_resolver.clone())
+                .await?;
         let (actor_sender, actor_receiver) = mpsc::channel(128);
         let (network_sender, network_receiver) = mpsc::channel(128);
 
+        let relay_usage = UsageRecorder::default();
         let inner = Arc::new(Inner {
             name,
             on_endpoints,
@@ -351,6 +456,12 @@ impl MagicSock {
             ipv6_reported: Arc::new(AtomicBool::new(false)),
             derp_map,
             my_derp: AtomicU16::new(0),
+            packet_capture,
+            segmentation_offload,
+            keepalive,
+            dns_resolver,
+            hole_punch: HolePunchRecorder::default(),
+            relay_usage: relay_usage.reader(),
         });
 
         let udp_state = quinn_udp::UdpState::default();
@@ -400,7 +511,9 @@ impl MagicSock {
                     periodic_re_stun_timer: new_re_stun_timer(false),
                     net_info_last: None,
                     disco_info: HashMap::new(),
+                    disco_auth: Default::default(),
                     peer_map: Default::default(),
+                    relay_usage,
                     port_mapper,
                     pconn4,
                     pconn6,
@@ -439,6 +552,30 @@ impl MagicSock {
         Ok(res)
     }
 
+    /// Retrieve the sources of disco messages that failed authentication.
+    ///
+    /// Sources exceeding [`DISCO_AUTH_FAILURE_THRESHOLD`] are temporarily ignored.
+    pub async fn disco_auth_failures(&self) -> Result<Vec<DiscoAuthFailure>> {
+        let (s, r) = sync::oneshot::channel();
+        self.inner
+            .actor_sender
+            .send(ActorMessage::DiscoAuthFailures(s))
+            .await?;
+        let res = r.await?;
+        Ok(res)
+    }
+
+    /// Retrieve the network conditions found by the last netcheck, if any.
+    pub async fn net_info(&self) -> Result<Option<config::NetInfo>> {
+        let (s, r) = sync::oneshot::channel();
+        self.inner
+            .actor_sender
+            .send(ActorMessage::NetInfo(s))
+            .await?;
+        let res = r.await?;
+        Ok(res)
+    }
+
     /// Query for the local endpoints discovered during the last endpoint discovery.
     pub async fn local_endpoints(&self) -> Result<Vec<config::Endpoint>> {
         let (s, r) = sync::oneshot::channel();
@@ -450,6 +587,22 @@ impl MagicSock {
         Ok(res)
     }
 
+    /// Outcomes of upgrading connections to direct paths, by the NAT types of both sides.
+    pub fn hole_punch_stats(&self) -> HolePunchStats {
+        self.inner.hole_punch.stats()
+    }
+
+    /// Traffic relayed over DERP per peer since the socket was started, the peers with the
+    /// most relayed bytes first.
+    pub fn relay_usage(&self) -> Vec<PeerUsage> {
+        self.inner.relay_usage.usage()
+    }
+
+    /// The packet capture of this socket, if enabled with [`Options::packet_capture`].
+    pub fn packet_capture(&self) -> Option<&PacketCapture> {
+        self.inner.packet_capture.as_ref()
+    }
+
     /// Get the cached version of the Ipv4 and Ipv6 addrs of the current connection.
     pub fn local_addr(&self) -> Result<(SocketAddr, Option<SocketAddr>)> {
         Ok(*self.inner.local_addrs.read().unwrap())
@@ -483,40 +636,35 @@ impl MagicSock {
         None
     }
 
-    // TODO
-    // /// Handles a "ping" CLI query.
-    // #[instrument(skip_all, fields(self.name = %self.name))]
-    // pub async fn ping<F>(&self, peer: config::Node, mut res: config::PingResult, cb: F)
-    // where
-    //     F: Fn(config::PingResult) -> BoxFuture<'static, ()> + Send + Sync + 'static,
-    // {
-    //     res.node_ip = peer.addresses.get(0).copied();
-    //     res.node_name = match peer.name.as_ref().and_then(|n| n.split('.').next()) {
-    //         Some(name) => {
-    //             // prefer DNS name
-    //             Some(name.to_string())
-    //         }
-    //         None => {
-    //             // else hostname
-    //             Some(peer.hostinfo.hostname.clone())
-    //         }
-    //     };
-    //     let ep = self
-    //         .peer_map
-    //         .read()
-    //         .await
-    //         .endpoint_for_node_key(&peer.key)
-    //         .cloned();
-    //     match ep {
-    //         Some(ep) => {
-    //             ep.cli_ping(res, cb).await;
-    //         }
-    //         None => {
-    //             res.err = Some("unknown peer".to_string());
-    //             cb(res);
-    //         }
-    //     }
-    // }
+    /// Returns the IP address the peer behind the QUIC address `addr` sends to us from.
+    ///
+    /// `addr` is the remote address of a quinn connection on this socket. Peers that are
+    /// only reachable over DERP have no IP address.
+    pub async fn remote_ip(&self, addr: SocketAddr) -> Option<IpAddr> {
+        let (s, r) = sync::oneshot::channel();
+        self.inner
+            .actor_sender
+            .send(ActorMessage::RemoteIp(QuicMappedAddr(addr), s))
+            .await
+            .ok()?;
+        r.await.ok().flatten()
+    }
+
+    /// Pings a peer over disco, on its DERP region and best known UDP address.
+    ///
+    /// Resolves with the first pong received, or an error if no pong arrives within
+    /// [`CLI_PING_TIMEOUT`].
+    pub async fn ping(&self, node_key: key::node::PublicKey) -> Result<config::PingResult> {
+        let (s, r) = sync::oneshot::channel();
+        self.inner
+            .actor_sender
+            .send(ActorMessage::Ping(node_key, s))
+            .await?;
+        let res = time::timeout(CLI_PING_TIMEOUT, r)
+            .await
+            .context("timeout waiting for pong")??;
+        Ok(res)
+    }
 
     /// Sets the connection's preferred local port.
     #[instrument(skip_all, fields(self.name = %self.inner.name))]
@@ -814,10 +962,17 @@ impl Drop for WgGuard {
 pub(self) enum ActorMessage {
     TrackedEndpoints(sync::oneshot::Sender<Vec<EndpointInfo>>),
     LocalEndpoints(sync::oneshot::Sender<Vec<config::Endpoint>>),
+    DiscoAuthFailures(sync::oneshot::Sender<Vec<DiscoAuthFailure>>),
+    NetInfo(sync::oneshot::Sender<Option<config::NetInfo>>),
+    Ping(
+        key::node::PublicKey,
+        sync::oneshot::Sender<config::PingResult>,
+    ),
     GetMappingAddr(
         key::node::PublicKey,
         sync::oneshot::Sender<Option<QuicMappedAddr>>,
     ),
+    RemoteIp(QuicMappedAddr, sync::oneshot::Sender<Option<IpAddr>>),
     SetPreferredPort(u16, sync::oneshot::Sender<()>),
     RebindAll(sync::oneshot::Sender<()>),
     Shutdown,
@@ -835,6 +990,7 @@ pub(self) enum ActorMessage {
     SetNetworkMap(netmap::NetworkMap, sync::oneshot::Sender<()>),
     ReceiveDerp(DerpReadResult),
     EndpointPingExpired(usize, stun::TransactionId),
+    EndpointStaggeredPing(usize, SendAddr),
 }
 
 struct Actor {
@@ -845,7 +1001,7 @@ struct Actor {
     derp_actor_sender: mpsc::Sender<DerpActorMessage>,
     udp_actor_sender: mpsc::Sender<UdpActorMessage>,
     network_receiver: mpsc::Receiver<Vec<quinn_udp::Transmit>>,
-    ip_receiver: mpsc::Receiver<IpPacket>,
+    ip_receiver: mpsc::Receiver<Vec<IpPacket>>,
     /// Channel to send received derp messages on, for processing.
     derp_recv_sender: flume::Sender<NetworkReadResult>,
     /// Indicates the update endpoint state.
@@ -866,8 +1022,12 @@ struct Actor {
     net_info_last: Option<config::NetInfo>,
     /// The state for an active DiscoKey.
     disco_info: HashMap<key::node::PublicKey, DiscoInfo>,
+    /// Failed disco authentications, per source.
+    disco_auth: DiscoAuthTracker,
     /// Tracks the networkmap node entity for each peer discovery key.
     peer_map: PeerMap,
+    /// Traffic relayed over DERP per peer in the peer map.
+    relay_usage: UsageRecorder,
 
     // The underlying UDP sockets used to send/rcv packets.
     pconn4: RebindingUdpConn,
@@ -900,7 +1060,24 @@ impl Actor {
             tokio::select! {
                 Some(transmits) = self.network_receiver.recv() => {
                     trace!("tick: network send");
-                    self.send_network(transmits).await;
+                    // Drain whatever else quinn queued meanwhile, so that transmits to the
+                    // same destination go out in a single sendmmsg call.
+                    let mut groups = vec![transmits];
+                    while groups.len() < NETWORK_BATCH_SIZE {
+                        match self.network_receiver.try_recv() {
+                            Ok(transmits) => groups.push(transmits),
+                            Err(_) => break,
+                        }
+                    }
+                    inc!(MagicsockMetrics, send_batches);
+                    inc_by!(MagicsockMetrics, send_batch_groups, groups.len() as _);
+                    for transmits in coalesce_transmits(groups) {
+                        self.send_network(transmits).await;
+                    }
+                    // There is room in the channel again.
+                    if let Some(waker) = self.inner.network_send_wakers.lock().unwrap().take() {
+                        waker.wake();
+                    }
                 }
                 Some(msg) = self.msg_receiver.recv() => {
                     trace!(?msg, "tick: msg");
@@ -908,26 +1085,28 @@ impl Actor {
                         return Ok(());
                     }
                 }
-                Some(msg) = self.ip_receiver.recv() => {
-                    trace!("tick: ip_receiver");
-                    match msg {
-                        IpPacket::Disco { source, sealed_box, src } => {
-                            self.handle_disco_message(source, &sealed_box, src, None).await;
-                        }
-                        IpPacket::Forward(mut forward) => {
-                            if let NetworkReadResult::Ok { meta, bytes, .. } = &mut forward {
-                                if !self.receive_ip(bytes, meta) {
-                                    continue;
-                                }
+                Some(msgs) = self.ip_receiver.recv() => {
+                    trace!("tick: ip_receiver {} msgs", msgs.len());
+                    let mut forwarded = false;
+                    for msg in msgs {
+                        match msg {
+                            IpPacket::Disco { source, sealed_box, src } => {
+                                self.handle_disco_message(source, &sealed_box, src, None).await;
                             }
-
-                            let _ = self.derp_recv_sender.send_async(forward).await;
-                            let mut wakers = self.inner.network_recv_wakers.lock().unwrap();
-                            while let Some(waker) = wakers.take() {
-                                waker.wake();
+                            IpPacket::Forward(mut forward) => {
+                                if let NetworkReadResult::Ok { meta, bytes, .. } = &mut forward {
+                                    if !self.receive_ip(bytes, meta) {
+                                        continue;
+                                    }
+                                }
+                                let _ = self.forward_to_quinn(forward).await;
+                                forwarded = true;
                             }
                         }
                     }
+                    if forwarded {
+                        self.wake_network_recv();
+                    }
                 }
                 tick = self.periodic_re_stun_timer.tick() => {
                     trace!("tick: re_stun {:?}", tick);
@@ -943,7 +1122,7 @@ impl Actor {
                     trace!("tick: endpoint heartbeat {} endpoints", self.peer_map.node_count());
                     // TODO: this might trigger too many packets at once, pace this
                     for (_, ep) in self.peer_map.endpoints_mut() {
-                        ep.stayin_alive().await;
+                        ep.stayin_alive(&self.inner.keepalive).await;
                     }
                 }
                 _ = endpoints_update_receiver.changed() => {
@@ -973,6 +1152,32 @@ impl Actor {
                 let eps: Vec<_> = self.last_endpoints.clone();
                 let _ = s.send(eps);
             }
+            ActorMessage::DiscoAuthFailures(s) => {
+                let _ = s.send(self.disco_auth.failures(Instant::now()));
+            }
+            ActorMessage::NetInfo(s) => {
+                let _ = s.send(self.net_info_last.clone());
+            }
+            ActorMessage::Ping(node_key, s) => {
+                let mut res = config::PingResult::default();
+                match self.peer_map.endpoint_for_node_key_mut(&node_key) {
+                    Some(ep) => {
+                        // The callback is shared, but only called once per ping.
+                        let s = std::sync::Mutex::new(Some(s));
+                        ep.cli_ping(res, move |res| {
+                            if let Some(s) = s.lock().unwrap().take() {
+                                let _ = s.send(res);
+                            }
+                            Box::pin(async {})
+                        })
+                        .await;
+                    }
+                    None => {
+                        res.err = Some("unknown peer".to_string());
+                        let _ = s.send(res);
+                    }
+                }
+            }
             ActorMessage::GetMappingAddr(node_key, s) => {
                 let res = self
                     .peer_map
@@ -980,6 +1185,10 @@ impl Actor {
                     .map(|ep| ep.quic_mapped_addr);
                 let _ = s.send(res);
             }
+            ActorMessage::RemoteIp(addr, s) => {
+                let res = self.peer_map.remote_ip_for_quic_mapped_addr(&addr);
+                let _ = s.send(res);
+            }
             ActorMessage::Shutdown => {
                 debug!("shutting down");
                 for (_, ep) in self.peer_map.endpoints_mut() {
@@ -1035,15 +1244,14 @@ impl Actor {
             }
             ActorMessage::ReceiveDerp(read_result) => {
                 let passthroughs = self.process_derp_read_result(read_result).await;
+                let forwarded = !passthroughs.is_empty();
                 for passthrough in passthroughs {
-                    self.derp_recv_sender
-                        .send_async(passthrough)
+                    self.forward_to_quinn(passthrough)
                         .await
                         .expect("missing recv sender");
-                    let mut wakers = self.inner.network_recv_wakers.lock().unwrap();
-                    if let Some(waker) = wakers.take() {
-                        waker.wake();
-                    }
+                }
+                if forwarded {
+                    self.wake_network_recv();
                 }
             }
             ActorMessage::EndpointPingExpired(id, txid) => {
@@ -1051,11 +1259,41 @@ impl Actor {
                     ep.ping_timeout(txid);
                 }
             }
+            ActorMessage::EndpointStaggeredPing(id, addr) => {
+                if let Some(ep) = self.peer_map.by_id_mut(&id) {
+                    ep.staggered_ping(addr).await;
+                }
+            }
         }
 
         false
     }
 
+    /// Queues a received packet for quinn.
+    ///
+    /// Quinn is only woken up early if the queue is full, callers must call
+    /// [`Actor::wake_network_recv`] once done with a batch of packets.
+    async fn forward_to_quinn(
+        &self,
+        packet: NetworkReadResult,
+    ) -> Result<(), flume::SendError<NetworkReadResult>> {
+        match self.derp_recv_sender.try_send(packet) {
+            Ok(()) => Ok(()),
+            Err(flume::TrySendError::Full(packet)) => {
+                self.wake_network_recv();
+                self.derp_recv_sender.send_async(packet).await
+            }
+            Err(flume::TrySendError::Disconnected(packet)) => Err(flume::SendError(packet)),
+        }
+    }
+
+    /// Wakes up quinn to read the queued packets.
+    fn wake_network_recv(&self) {
+        if let Some(waker) = self.inner.network_recv_wakers.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
     /// This modifies the [`quinn_udp::RecvMeta`] for the packet to set the addresses
     /// to those that the QUIC layer should see.  E.g. the remote address will be set to the
     /// [`QuicMappedAddr`] instead of the actual remote.
@@ -1065,7 +1303,7 @@ impl Actor {
         debug!("received data {} from {}", meta.len, meta.addr);
         match self
             .peer_map
-            .endpoint_for_ip_port(&SendAddr::Udp(meta.addr))
+            .endpoint_for_ip_port_mut(&SendAddr::Udp(meta.addr))
         {
             None => {
                 warn!(peer=?meta.addr, "no peer_map state found for peer, skipping");
@@ -1073,6 +1311,17 @@ impl Actor {
             }
             Some(ep) => {
                 debug!("peer_map state found for {}", meta.addr);
+                ep.note_recv_udp(bytes.len());
+                if bytes.len() > DEFAULT_MAX_UDP_PAYLOAD {
+                    inc!(MagicsockMetrics, recv_large_datagrams);
+                }
+                self.inner.capture(
+                    Direction::Recv,
+                    SendAddr::Udp(meta.addr),
+                    Some(ep.public_key()),
+                    bytes.len(),
+                    None,
+                );
                 meta.addr = ep.quic_mapped_addr.0;
             }
         }
@@ -1115,6 +1364,8 @@ impl Actor {
             warn!("received empty derp packet");
             return Vec::new();
         }
+        inc_by!(MagicsockMetrics, recv_derp_bytes, dm.buf.len() as _);
+        self.relay_usage.record_recv(&dm.src, dm.buf.len());
         let region_id = dm.region_id;
         let ipp = SendAddr::Derp(region_id);
 
@@ -1127,6 +1378,7 @@ impl Actor {
                     msock_public_key: self.inner.public_key.clone(),
                     public_key: dm.src.clone(),
                     derp_addr: Some(region_id),
+                    hole_punch: self.inner.hole_punch.clone(),
                 });
                 self.peer_map.set_endpoint_for_ip_port(&ipp, id);
                 let ep = self.peer_map.by_id_mut(&id).expect("inserted");
@@ -1154,6 +1406,8 @@ impl Actor {
                         continue;
                     }
 
+                    self.inner
+                        .capture(Direction::Recv, ipp, Some(&dm.src), part.len(), None);
                     let meta = quinn_udp::RecvMeta {
                         len: part.len(),
                         stride: part.len(),
@@ -1193,6 +1447,11 @@ impl Actor {
         if transmits.is_empty() {
             return;
         }
+        let transmits = if self.inner.segmentation_offload {
+            transmits
+        } else {
+            split_segments(transmits)
+        };
         let current_destination = &transmits[0].destination;
         debug_assert!(
             transmits
@@ -1214,25 +1473,35 @@ impl Actor {
                     public_key
                 );
 
-                match ep.get_send_addrs().await {
+                let send_addrs = ep.get_send_addrs().await;
+                if let Ok((udp_addr, derp_addr)) = &send_addrs {
+                    let paths = udp_addr
+                        .map(SendAddr::Udp)
+                        .into_iter()
+                        .chain(derp_addr.map(SendAddr::Derp));
+                    for path in paths {
+                        for t in &transmits {
+                            self.inner.capture(
+                                Direction::Send,
+                                path,
+                                Some(&public_key),
+                                t.contents.len(),
+                                None,
+                            );
+                        }
+                    }
+                }
+                match send_addrs {
                     Ok((Some(udp_addr), Some(derp_addr))) => {
                         let res = self.send_raw(udp_addr, transmits.clone()).await;
-                        self.send_derp(
-                            derp_addr,
-                            public_key,
-                            transmits.into_iter().map(|t| t.contents).collect(),
-                        );
+                        self.send_derp(derp_addr, public_key, derp_contents(transmits));
 
                         if let Err(err) = res {
                             warn!("failed to send UDP: {:?}", err);
                         }
                     }
                     Ok((None, Some(derp_addr))) => {
-                        self.send_derp(
-                            derp_addr,
-                            public_key.clone(),
-                            transmits.into_iter().map(|t| t.contents).collect(),
-                        );
+                        self.send_derp(derp_addr, public_key.clone(), derp_contents(transmits));
                     }
                     Ok((Some(udp_addr), None)) => {
                         if let Err(err) = self.send_raw(udp_addr, transmits).await {
@@ -1260,6 +1529,9 @@ impl Actor {
 
     #[instrument(level = "debug", skip_all)]
     fn send_derp(&mut self, region_id: u16, peer: key::node::PublicKey, contents: Vec<Bytes>) {
+        let len = contents.iter().map(|c| c.len()).sum::<usize>();
+        inc_by!(MagicsockMetrics, send_derp_bytes, len as _);
+        self.relay_usage.record_sent(&peer, len);
         self.send_derp_actor(DerpActorMessage::Send {
             region_id,
             contents,
@@ -1524,6 +1796,7 @@ impl Actor {
 
     #[instrument(level = "debug", skip_all)]
     fn call_net_info_callback_locked(&mut self, ni: config::NetInfo) {
+        self.inner.hole_punch.set_local_nat_type(ni.nat_type());
         self.net_info_last = Some(ni.clone());
         if let Some(ref on_net_info) = self.inner.on_net_info {
             debug!("net_info update: {:?}", ni);
@@ -1533,22 +1806,34 @@ impl Actor {
 
     #[instrument(level = "debug", skip_all)]
     async fn update_net_info(&mut self) -> Result<Arc<netcheck::Report>> {
-        let derp_map = self.inner.derp_map.as_ref();
-        if derp_map.is_none() {
-            debug!("skipping netcheck, no Derp Map");
-            return Ok(Default::default());
-        }
+        let report = if let Some(global_v4) = self.pconn4.virtual_stun() {
+            // there is nothing to probe on a virtual network, it knows our public address
+            debug!("skipping netcheck, virtual network");
+            Arc::new(netcheck::Report {
+                udp: true,
+                ipv4: true,
+                ipv4_can_send: true,
+                global_v4: Some(global_v4),
+                ..Default::default()
+            })
+        } else {
+            let derp_map = self.inner.derp_map.as_ref();
+            if derp_map.is_none() {
+                debug!("skipping netcheck, no Derp Map");
+                return Ok(Default::default());
+            }
 
-        let derp_map = derp_map.cloned().unwrap();
-        let net_checker = &mut self.net_checker;
-        let pconn4 = Some(self.pconn4.as_socket());
-        let pconn6 = self.pconn6.as_ref().map(|p| p.as_socket());
+            let derp_map = derp_map.cloned().unwrap();
+            let net_checker = &mut self.net_checker;
+            let pconn4 = self.pconn4.as_socket();
+            let pconn6 = self.pconn6.as_ref().and_then(|p| p.as_socket());
 
-        debug!("requesting netcheck report");
-        let report = time::timeout(Duration::from_secs(10), async move {
-            net_checker.get_report(derp_map, pconn4, pconn6).await
-        })
-        .await??;
+            debug!("requesting netcheck report");
+            time::timeout(Duration::from_secs(10), async move {
+                net_checker.get_report(derp_map, pconn4, pconn6).await
+            })
+            .await??
+        };
         self.inner
             .ipv6_reported
             .store(report.ipv6, Ordering::Relaxed);
@@ -1872,6 +2157,7 @@ impl Actor {
         }
 
         let pkt = disco::encode_message(&self.inner.public_key, seal);
+        let pkt_len = pkt.len();
         let sent = self.send_addr(dst, Some(&dst_key), pkt.into()).await;
         match sent {
             Ok(0) => {
@@ -1881,6 +2167,13 @@ impl Actor {
             }
             Ok(_n) => {
                 debug!("disco: sent message to {}", dst);
+                self.inner.capture(
+                    Direction::Send,
+                    dst,
+                    Some(&dst_key),
+                    pkt_len,
+                    Some(DiscoKind::from(&msg)),
+                );
                 if is_derp {
                     inc!(MagicsockMetrics, sent_disco_derp);
                 } else {
@@ -1971,6 +2264,24 @@ impl Actor {
         }
 
         let sender = key::node::PublicKey::from(source);
+        if let Some(derp_node_src) = &derp_node_src {
+            // The DERP server authenticated the node that relayed the message, a message
+            // claiming to be from another node is spoofed. Dropping these means failures
+            // over DERP are only ever recorded against the node that actually sent them.
+            if derp_node_src != &sender {
+                debug!(
+                    "disco: dropping message claiming {:?} relayed by {:?} - {}",
+                    sender, derp_node_src, src
+                );
+                inc!(MagicsockMetrics, recv_disco_bad_peer);
+                return true;
+            }
+        }
+        if self.disco_auth.is_ignored(&sender, src, Instant::now()) {
+            trace!("disco: ignoring message from {:?} - {}", sender, src);
+            inc!(MagicsockMetrics, recv_disco_ignored);
+            return true;
+        }
         let mut unknown_sender = false;
         if self.peer_map.endpoint_for_node_key(&sender).is_none()
             && self.peer_map.endpoint_for_ip_port_mut(&src).is_none()
@@ -1984,6 +2295,7 @@ impl Actor {
         // We're now reasonably sure we're expecting communication from
         // this peer, do the heavy crypto lifting to see what they want.
 
+        let pkt_len = disco::MAGIC_LEN + disco::KEY_LEN + sealed_box.len();
         let di = get_disco_info(&mut self.disco_info, &self.inner.private_key, &sender);
         let payload = di.shared_key.open(sealed_box);
         if payload.is_err() {
@@ -1999,6 +2311,19 @@ impl Actor {
                 self.inner.public_key, sender, payload,
             );
             inc!(MagicsockMetrics, recv_disco_bad_key);
+            if self.disco_auth.record_failure(&sender, src, Instant::now()) {
+                warn!(
+                    "disco: too many authentication failures from {:?} - {}, ignoring",
+                    sender, src
+                );
+            }
+            self.inner.capture(
+                Direction::Recv,
+                src,
+                Some(&sender),
+                pkt_len,
+                Some(DiscoKind::Invalid),
+            );
             return true;
         }
         let payload = payload.unwrap();
@@ -2013,10 +2338,24 @@ impl Actor {
             // be too spammy for old clients.
 
             inc!(MagicsockMetrics, recv_disco_bad_parse);
+            self.inner.capture(
+                Direction::Recv,
+                src,
+                Some(&sender),
+                pkt_len,
+                Some(DiscoKind::Invalid),
+            );
             return true;
         }
 
         let dm = dm.unwrap();
+        self.inner.capture(
+            Direction::Recv,
+            src,
+            Some(&sender),
+            pkt_len,
+            Some(DiscoKind::from(&dm)),
+        );
         let is_derp = src.is_derp();
         if is_derp {
             inc!(MagicsockMetrics, recv_disco_derp);
@@ -2036,6 +2375,7 @@ impl Actor {
                         msock_public_key: self.inner.public_key.clone(),
                         public_key: sender.clone(),
                         derp_addr: src.derp_region(),
+                        hole_punch: self.inner.hole_punch.clone(),
                     });
                 }
                 self.handle_ping(ping, &sender, src, derp_node_src).await;
@@ -2121,6 +2461,7 @@ impl Actor {
             Some(dst_key) => {
                 // From Derp
                 if let Some(ep) = self.peer_map.endpoint_for_node_key_mut(&dst_key) {
+                    ep.note_remote_nat_type(dm.nat_type);
                     if ep.add_candidate_endpoint(src, dm.tx_id) {
                         debug!("disco: ping got duplicate endpoint {} - {}", src, dm.tx_id);
                         return;
@@ -2132,6 +2473,7 @@ impl Actor {
             }
             None => {
                 if let Some(ep) = self.peer_map.endpoint_for_node_key_mut(&di.node_key) {
+                    ep.note_remote_nat_type(dm.nat_type);
                     if ep.add_candidate_endpoint(src, dm.tx_id) {
                         debug!("disco: ping got duplicate endpoint {} - {}", src, dm.tx_id);
                         return;
@@ -2207,6 +2549,7 @@ impl Actor {
                     msock_public_key: self.inner.public_key.clone(),
                     public_key: n.key.clone(),
                     derp_addr: n.derp,
+                    hole_punch: self.inner.hole_punch.clone(),
                 });
             }
 
@@ -2243,6 +2586,9 @@ impl Actor {
             }
 
             for id in to_delete {
+                if let Some(ep) = self.peer_map.by_id(&id) {
+                    self.relay_usage.remove(ep.public_key());
+                }
                 self.peer_map.delete_endpoint(id);
             }
         }
@@ -2276,8 +2622,26 @@ impl Actor {
                 t.destination = addr;
             }
         }
-        let sum =
-            futures::future::poll_fn(|cx| conn.poll_send(&self.udp_state, cx, &transmits)).await?;
+        let gso_batches = transmits
+            .iter()
+            .filter(|t| t.segment_size.is_some())
+            .count();
+        if gso_batches > 0 {
+            inc_by!(MagicsockMetrics, send_gso_batches, gso_batches as _);
+        }
+        // quinn-udp sends at most `BATCH_SIZE` transmits per call, so keep going until all
+        // of them are out.
+        let mut sum = 0;
+        while sum < transmits.len() {
+            let remaining = &transmits[sum..];
+            let sent =
+                futures::future::poll_fn(|cx| conn.poll_send(&self.udp_state, cx, remaining))
+                    .await?;
+            if sent == 0 {
+                break;
+            }
+            sum += sent;
+        }
         let total_bytes: u64 = transmits
             .iter()
             .take(sum)
@@ -2461,6 +2825,58 @@ impl Iterator for TransmitIter<'_> {
     }
 }
 
+/// Merges groups of transmits to the same destination, keeping their order.
+///
+/// Each group must only contain transmits to a single destination.
+fn coalesce_transmits(groups: Vec<Vec<quinn_udp::Transmit>>) -> Vec<Vec<quinn_udp::Transmit>> {
+    let mut res: Vec<Vec<quinn_udp::Transmit>> = Vec::with_capacity(groups.len());
+    for group in groups {
+        let Some(destination) = group.first().map(|t| t.destination) else {
+            continue;
+        };
+        match res.iter_mut().find(|g| g[0].destination == destination) {
+            Some(existing) => existing.extend(group),
+            None => res.push(group),
+        }
+    }
+    res
+}
+
+/// Splits transmits batched for segmentation offload into one transmit per packet.
+fn split_segments(transmits: Vec<quinn_udp::Transmit>) -> Vec<quinn_udp::Transmit> {
+    if transmits.iter().all(|t| t.segment_size.is_none()) {
+        return transmits;
+    }
+    let mut res = Vec::with_capacity(transmits.len());
+    for t in transmits {
+        let Some(segment_size) = t.segment_size else {
+            res.push(t);
+            continue;
+        };
+        let mut contents = t.contents;
+        while !contents.is_empty() {
+            let segment = contents.split_to(segment_size.min(contents.len()));
+            inc!(MagicsockMetrics, send_segments_split);
+            res.push(quinn_udp::Transmit {
+                destination: t.destination,
+                ecn: t.ecn,
+                contents: segment,
+                segment_size: None,
+                src_ip: t.src_ip,
+            });
+        }
+    }
+    res
+}
+
+/// The packets to send over DERP, which does not support segmentation offload.
+fn derp_contents(transmits: Vec<quinn_udp::Transmit>) -> Vec<Bytes> {
+    split_segments(transmits)
+        .into_iter()
+        .map(|t| t.contents)
+        .collect()
+}
+
 /// Splits a packet into its component items.
 #[derive(Debug)]
 pub struct PacketSplitIter {
@@ -2606,6 +3022,46 @@ pub(crate) mod tests {
         assert_eq!(groups[0].len(), 2);
     }
 
+    #[test]
+    fn test_split_segments() {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1);
+        let transmits = vec![
+            quinn_udp::Transmit {
+                destination: addr,
+                ecn: None,
+                contents: Bytes::from(vec![1u8; 25]),
+                segment_size: Some(10),
+                src_ip: None,
+            },
+            make_transmit(addr),
+        ];
+        let lens: Vec<_> = split_segments(transmits)
+            .iter()
+            .map(|t| {
+                assert!(t.segment_size.is_none());
+                t.contents.len()
+            })
+            .collect();
+        assert_eq!(lens, vec![10, 10, 5, 6]);
+    }
+
+    #[test]
+    fn test_coalesce_transmits() {
+        let a = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1);
+        let b = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 2);
+        let groups = vec![
+            vec![make_transmit(a)],
+            vec![make_transmit(b), make_transmit(b)],
+            vec![],
+            vec![make_transmit(a)],
+        ];
+        let dests: Vec<Vec<_>> = coalesce_transmits(groups)
+            .iter()
+            .map(|g| g.iter().map(|t| t.destination).collect())
+            .collect();
+        assert_eq!(dests, vec![vec![a, a], vec![b, b]]);
+    }
+
     async fn pick_port() -> u16 {
         let conn = net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
         conn.local_addr().unwrap().port()
@@ -3060,7 +3516,7 @@ pub(crate) mod tests {
             let conn = std::net::UdpSocket::bind(addr)?;
 
             let tls_server_config =
-                tls::make_server_config(&key.clone().into(), vec![ALPN.to_vec()], false)?;
+                tls::make_server_config(&key.clone().into(), None, vec![ALPN.to_vec()], false)?;
             let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
             let mut transport_config = quinn::TransportConfig::default();
             transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
@@ -3204,7 +3660,7 @@ pub(crate) mod tests {
             let conn = RebindingUdpConn::bind(addr.port(), addr.ip().into()).await?;
 
             let tls_server_config =
-                tls::make_server_config(&key.clone().into(), vec![ALPN.to_vec()], false)?;
+                tls::make_server_config(&key.clone().into(), None, vec![ALPN.to_vec()], false)?;
             let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
             let mut transport_config = quinn::TransportConfig::default();
             transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
@@ -0,0 +1,298 @@
+//! Packet capture for debugging connectivity.
+//!
+//! When enabled, the [`MagicSock`](super::MagicSock) records metadata about every packet it
+//! sends or receives: when, on which path, to or from which peer, how large, and for disco
+//! packets the message type. Payloads are never recorded, so a capture can be shared to
+//! debug hole punching failures without leaking any data.
+//!
+//! Packets are kept in a bounded ring buffer, and can be exported as a pcapng file. Every
+//! packet in the pcapng file has its original length but no captured data, with the
+//! metadata in the packet comment.
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{disco, key};
+
+use super::SendAddr;
+
+/// Default number of packets kept by a [`PacketCapture`].
+pub const DEFAULT_CAPTURE_CAPACITY: usize = 4096;
+
+/// pcapng link type for the packets, `LINKTYPE_USER0`.
+const PCAPNG_LINKTYPE: u16 = 147;
+
+/// Whether a packet was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The packet was sent by this node.
+    Send,
+    /// The packet was received by this node.
+    Recv,
+}
+
+/// The path a packet took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketPath {
+    /// Directly over UDP, to or from this address.
+    Udp(SocketAddr),
+    /// Relayed through the DERP server of this region.
+    Derp(u16),
+}
+
+impl From<SendAddr> for PacketPath {
+    fn from(addr: SendAddr) -> Self {
+        match addr {
+            SendAddr::Udp(addr) => Self::Udp(addr),
+            SendAddr::Derp(region) => Self::Derp(region),
+        }
+    }
+}
+
+/// The type of a disco packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoKind {
+    /// A ping.
+    Ping,
+    /// A pong, in reply to a ping.
+    Pong,
+    /// A call me maybe, asking the peer to start hole punching.
+    CallMeMaybe,
+    /// A disco packet that could not be decrypted or parsed.
+    Invalid,
+}
+
+impl From<&disco::Message> for DiscoKind {
+    fn from(msg: &disco::Message) -> Self {
+        match msg {
+            disco::Message::Ping(_) => Self::Ping,
+            disco::Message::Pong(_) => Self::Pong,
+            disco::Message::CallMeMaybe(_) => Self::CallMeMaybe,
+        }
+    }
+}
+
+/// Metadata of a single captured packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedPacket {
+    /// When the packet was sent or received.
+    pub time: SystemTime,
+    /// Whether the packet was sent or received.
+    pub direction: Direction,
+    /// The path the packet took.
+    pub path: PacketPath,
+    /// The peer the packet was sent to or received from, if known.
+    pub peer: Option<key::node::PublicKey>,
+    /// Size of the packet in bytes.
+    pub size: usize,
+    /// The disco message type, `None` for data packets.
+    pub disco: Option<DiscoKind>,
+}
+
+impl fmt::Display for CapturedPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let direction = match self.direction {
+            Direction::Send => "send",
+            Direction::Recv => "recv",
+        };
+        write!(f, "{time:.6} {direction} ")?;
+        match self.path {
+            PacketPath::Udp(addr) => write!(f, "udp {addr}")?,
+            PacketPath::Derp(region) => write!(f, "derp region {region}")?,
+        }
+        if let Some(peer) = &self.peer {
+            write!(f, " peer {}", peer.short_hex())?;
+        }
+        write!(f, " {}b", self.size)?;
+        match self.disco {
+            Some(disco) => write!(f, " disco {disco:?}"),
+            None => write!(f, " data"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Ring {
+    capacity: usize,
+    packets: VecDeque<CapturedPacket>,
+    dropped: u64,
+}
+
+/// Records metadata of the packets of a [`MagicSock`](super::MagicSock).
+///
+/// Cloning a capture returns a handle to the same buffer, so a clone can be kept to read
+/// the packets while the socket records them. Once the buffer is full, the oldest packets
+/// are dropped.
+#[derive(Debug, Clone)]
+pub struct PacketCapture(Arc<Mutex<Ring>>);
+
+impl Default for PacketCapture {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPTURE_CAPACITY)
+    }
+}
+
+impl PacketCapture {
+    /// Create a new capture keeping at most `capacity` packets.
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(Ring {
+            capacity,
+            packets: VecDeque::with_capacity(capacity.min(DEFAULT_CAPTURE_CAPACITY)),
+            dropped: 0,
+        })))
+    }
+
+    pub(super) fn record(&self, packet: CapturedPacket) {
+        let mut ring = self.0.lock().unwrap();
+        if ring.capacity == 0 {
+            ring.dropped += 1;
+            return;
+        }
+        if ring.packets.len() >= ring.capacity {
+            ring.packets.pop_front();
+            ring.dropped += 1;
+        }
+        ring.packets.push_back(packet);
+    }
+
+    /// The captured packets, oldest first.
+    pub fn packets(&self) -> Vec<CapturedPacket> {
+        self.0.lock().unwrap().packets.iter().cloned().collect()
+    }
+
+    /// Number of packets dropped because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.0.lock().unwrap().dropped
+    }
+
+    /// Remove all captured packets.
+    pub fn clear(&self) {
+        let mut ring = self.0.lock().unwrap();
+        ring.packets.clear();
+        ring.dropped = 0;
+    }
+
+    /// Write the captured packets to `w` in the pcapng format.
+    pub fn write_pcapng(&self, mut w: impl Write) -> io::Result<()> {
+        // section header block
+        write_block(&mut w, 0x0A0D_0D0A, |body| {
+            body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&(-1i64).to_le_bytes());
+        })?;
+        // interface description block, timestamps default to microseconds
+        write_block(&mut w, 0x0000_0001, |body| {
+            body.extend_from_slice(&PCAPNG_LINKTYPE.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+        })?;
+        for packet in self.packets() {
+            let micros = packet
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64;
+            // enhanced packet block
+            write_block(&mut w, 0x0000_0006, |body| {
+                body.extend_from_slice(&0u32.to_le_bytes());
+                body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+                body.extend_from_slice(&(micros as u32).to_le_bytes());
+                body.extend_from_slice(&0u32.to_le_bytes());
+                body.extend_from_slice(&(packet.size as u32).to_le_bytes());
+                // opt_comment
+                let comment = packet.to_string();
+                body.extend_from_slice(&1u16.to_le_bytes());
+                body.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+                body.extend_from_slice(comment.as_bytes());
+                pad(body);
+                // opt_endofopt
+                body.extend_from_slice(&[0; 4]);
+            })?;
+        }
+        w.flush()
+    }
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+fn write_block(w: &mut impl Write, typ: u32, f: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+    let mut body = Vec::new();
+    f(&mut body);
+    pad(&mut body);
+    let len = (body.len() + 12) as u32;
+    w.write_all(&typ.to_le_bytes())?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&body)?;
+    w.write_all(&len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(size: usize) -> CapturedPacket {
+        CapturedPacket {
+            time: SystemTime::now(),
+            direction: Direction::Send,
+            path: PacketPath::Derp(1),
+            peer: Some(key::node::SecretKey::generate().public_key()),
+            size,
+            disco: Some(DiscoKind::Ping),
+        }
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let capture = PacketCapture::new(2);
+        capture.record(packet(1));
+        capture.record(packet(2));
+        capture.record(packet(3));
+        let sizes: Vec<_> = capture.packets().iter().map(|p| p.size).collect();
+        assert_eq!(sizes, vec![2, 3]);
+        assert_eq!(capture.dropped(), 1);
+        capture.clear();
+        assert!(capture.packets().is_empty());
+    }
+
+    #[test]
+    fn pcapng() {
+        let capture = PacketCapture::default();
+        capture.record(packet(1200));
+        let mut out = Vec::new();
+        capture.write_pcapng(&mut out).unwrap();
+        assert_eq!(out.len() % 4, 0);
+
+        // walk the blocks, checking that the lengths are consistent
+        let mut blocks = Vec::new();
+        let mut rest = &out[..];
+        while !rest.is_empty() {
+            let typ = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            let trailer = u32::from_le_bytes(rest[len - 4..len].try_into().unwrap()) as usize;
+            assert_eq!(len, trailer);
+            blocks.push((typ, rest[8..len - 4].to_vec()));
+            rest = &rest[len..];
+        }
+        let types: Vec<_> = blocks.iter().map(|(typ, _)| *typ).collect();
+        assert_eq!(types, vec![0x0A0D_0D0A, 1, 6]);
+        let epb = &blocks[2].1;
+        let original_len = u32::from_le_bytes(epb[16..20].try_into().unwrap());
+        assert_eq!(original_len, 1200);
+        let comment = String::from_utf8_lossy(&epb[24..]);
+        assert!(comment.contains("derp region 1"));
+        assert!(comment.contains("disco Ping"));
+    }
+}
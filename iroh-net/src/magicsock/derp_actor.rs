@@ -26,6 +26,10 @@ const DERP_INACTIVE_CLEANUP_TIME: Duration = Duration::from_secs(60);
 /// How often `clean_stale_derp` runs when there are potentially-stale DERP connections to close.
 const DERP_CLEAN_STALE_INTERVAL: Duration = Duration::from_secs(15);
 
+/// How long a keepalive ping may take, including connecting, before the connection is
+/// closed for reconnect.
+const KEEPALIVE_PING_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub(super) enum DerpActorMessage {
     Send {
         region_id: u16,
@@ -55,6 +59,8 @@ struct ActiveDerp {
     last_write: Instant,
     create_time: Instant,
     reader: ReaderState,
+    /// The keepalive ping in flight, if any.
+    keepalive: Option<tokio::task::JoinHandle<()>>,
 }
 
 /// A route entry for a public key, saying that a certain peer should be available at DERP
@@ -94,6 +100,11 @@ impl DerpActor {
             time::Instant::now() + DERP_CLEAN_STALE_INTERVAL,
             DERP_CLEAN_STALE_INTERVAL,
         );
+        let keepalive = self.conn.keepalive.clone();
+        let keepalive_interval = keepalive.derp_interval();
+        let keepalive_timer =
+            time::sleep(keepalive_interval.map_or(Duration::MAX, |i| keepalive.jittered(i)));
+        tokio::pin!(keepalive_timer);
 
         loop {
             tokio::select! {
@@ -147,6 +158,14 @@ impl DerpActor {
                     trace!("tick: cleanup");
                     self.clean_stale_derp().await;
                 }
+                _ = &mut keepalive_timer, if keepalive_interval.is_some() => {
+                    trace!("tick: keepalive");
+                    let interval = keepalive_interval.expect("checked above");
+                    self.send_keepalives(interval);
+                    keepalive_timer
+                        .as_mut()
+                        .reset(time::Instant::now() + keepalive.jittered(interval));
+                }
                 else => {
                     trace!("shutting down derp recv loop");
                     break;
@@ -282,6 +301,7 @@ impl DerpActor {
             })
             .can_ack_pings(true)
             .is_preferred(my_derp == region_id)
+            .dns_resolver(self.conn.dns_resolver.clone())
             .get_region(move || {
                 let conn = conn1.clone();
                 Box::pin(async move {
@@ -302,6 +322,7 @@ impl DerpActor {
             last_write: Instant::now(),
             create_time: Instant::now(),
             reader: ReaderState::new(region_id, cancel, dc.clone()),
+            keepalive: None,
         };
 
         // Insert, to make sure we do not attempt to double connect.
@@ -380,6 +401,37 @@ impl DerpActor {
         }
     }
 
+    /// Pings the DERP servers of connections that were not written to for `interval`.
+    ///
+    /// The pings run in the background, a failed ping closes the connection for reconnect.
+    /// A connection whose previous ping is still in flight is skipped.
+    fn send_keepalives(&mut self, interval: Duration) {
+        let now = Instant::now();
+        for (region_id, ad) in &mut self.active_derp {
+            if now.duration_since(ad.last_write) < interval {
+                continue;
+            }
+            if matches!(&ad.keepalive, Some(ping) if !ping.is_finished()) {
+                continue;
+            }
+            let region_id = *region_id;
+            let client = ad.c.clone();
+            let msg_sender = self.msg_sender.clone();
+            ad.keepalive = Some(tokio::spawn(async move {
+                let err = match time::timeout(KEEPALIVE_PING_TIMEOUT, client.ping()).await {
+                    Ok(Ok(())) => return,
+                    Ok(Err(err)) => format!("{err:?}"),
+                    Err(_elapsed) => "timed out".to_string(),
+                };
+                debug!("derp.{region_id}: keepalive failed: {err}");
+                msg_sender
+                    .send(ActorMessage::CloseOrReconnect(region_id, "keepalive-fail"))
+                    .await
+                    .ok();
+            }));
+        }
+    }
+
     async fn clean_stale_derp(&mut self) {
         debug!("cleanup {} derps", self.active_derp.len());
         let now = Instant::now();
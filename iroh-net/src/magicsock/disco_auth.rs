@@ -0,0 +1,191 @@
+//! Tracking of disco messages that fail authentication.
+//!
+//! Disco messages are sealed with a key shared between the two peers. A message that can
+//! not be opened was either meant for a previous key of ours, or was spoofed. Failures are
+//! tracked per source, the sender key together with the path the message arrived on.
+//!
+//! Over UDP the sender key is only claimed, and keying on the address as well means a
+//! spoofer can only get its own address ignored, not the real sender's. Every node of a
+//! DERP region shares the path of the region, so messages relayed over DERP are only
+//! accepted if the claimed sender is the node the DERP server authenticated, and the
+//! sender key of these is authentic.
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::key;
+
+use super::{PacketPath, SendAddr};
+
+/// Number of failures within a minute after which a source is ignored for five minutes.
+pub const DISCO_AUTH_FAILURE_THRESHOLD: u32 = 20;
+
+/// Window in which failures are counted towards the threshold.
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a source is ignored for after exceeding the threshold.
+const IGNORE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Maximum number of tracked sources, the one with the oldest failure is evicted first.
+const MAX_SOURCES: usize = 1024;
+
+/// Failed disco authentications from a single source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoAuthFailure {
+    /// The sender key of the messages.
+    ///
+    /// This is claimed by the messages received over UDP, and authenticated by the DERP
+    /// server for messages relayed over DERP.
+    pub sender: key::node::PublicKey,
+    /// The path the messages arrived on.
+    pub path: PacketPath,
+    /// Total number of failed authentications.
+    pub failures: u64,
+    /// When the last authentication failed.
+    pub last_failure: SystemTime,
+    /// Whether disco messages from this source are currently ignored.
+    pub ignored: bool,
+}
+
+#[derive(Debug)]
+struct Source {
+    failures: u64,
+    window_start: Instant,
+    window_failures: u32,
+    last_failure: SystemTime,
+    last_failure_at: Instant,
+    ignored_until: Option<Instant>,
+}
+
+/// Tracks failed disco authentications and ignores sources exceeding the threshold.
+#[derive(Debug, Default)]
+pub(super) struct DiscoAuthTracker {
+    sources: HashMap<(key::node::PublicKey, SendAddr), Source>,
+}
+
+impl DiscoAuthTracker {
+    /// Whether messages from `sender` over `src` should be dropped without processing.
+    pub(super) fn is_ignored(
+        &mut self,
+        sender: &key::node::PublicKey,
+        src: SendAddr,
+        now: Instant,
+    ) -> bool {
+        let Some(source) = self.sources.get_mut(&(sender.clone(), src)) else {
+            return false;
+        };
+        match source.ignored_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                source.ignored_until = None;
+                source.window_start = now;
+                source.window_failures = 0;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Record a failed authentication, returns `true` if the source is now ignored.
+    pub(super) fn record_failure(
+        &mut self,
+        sender: &key::node::PublicKey,
+        src: SendAddr,
+        now: Instant,
+    ) -> bool {
+        let id = (sender.clone(), src);
+        if !self.sources.contains_key(&id) && self.sources.len() >= MAX_SOURCES {
+            let oldest = self
+                .sources
+                .iter()
+                .min_by_key(|(_, source)| source.last_failure_at)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.sources.remove(&oldest);
+            }
+        }
+        let source = self.sources.entry(id).or_insert_with(|| Source {
+            failures: 0,
+            window_start: now,
+            window_failures: 0,
+            last_failure: SystemTime::now(),
+            last_failure_at: now,
+            ignored_until: None,
+        });
+        if now.saturating_duration_since(source.window_start) >= FAILURE_WINDOW {
+            source.window_start = now;
+            source.window_failures = 0;
+        }
+        source.failures += 1;
+        source.window_failures += 1;
+        source.last_failure = SystemTime::now();
+        source.last_failure_at = now;
+        if source.ignored_until.is_none() && source.window_failures >= DISCO_AUTH_FAILURE_THRESHOLD
+        {
+            source.ignored_until = Some(now + IGNORE_DURATION);
+            return true;
+        }
+        false
+    }
+
+    /// All tracked sources, the most recent failure first.
+    pub(super) fn failures(&self, now: Instant) -> Vec<DiscoAuthFailure> {
+        let mut res = self
+            .sources
+            .iter()
+            .map(|((sender, src), source)| DiscoAuthFailure {
+                sender: sender.clone(),
+                path: (*src).into(),
+                failures: source.failures,
+                last_failure: source.last_failure,
+                ignored: source.ignored_until.map_or(false, |until| now < until),
+            })
+            .collect::<Vec<_>>();
+        res.sort_by(|a, b| b.last_failure.cmp(&a.last_failure));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_after_threshold() {
+        let mut tracker = DiscoAuthTracker::default();
+        let sender = key::node::SecretKey::generate().public_key();
+        let spoofed = SendAddr::Udp("127.0.0.1:1".parse().unwrap());
+        let real = SendAddr::Udp("127.0.0.1:2".parse().unwrap());
+        let now = Instant::now();
+
+        for _ in 0..DISCO_AUTH_FAILURE_THRESHOLD - 1 {
+            assert!(!tracker.record_failure(&sender, spoofed, now));
+        }
+        assert!(!tracker.is_ignored(&sender, spoofed, now));
+        assert!(tracker.record_failure(&sender, spoofed, now));
+        assert!(tracker.is_ignored(&sender, spoofed, now));
+        // the same key on another path is unaffected
+        assert!(!tracker.is_ignored(&sender, real, now));
+
+        let failures = tracker.failures(now);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].failures, DISCO_AUTH_FAILURE_THRESHOLD as u64);
+        assert!(failures[0].ignored);
+
+        // the source is forgiven after a while
+        let later = now + IGNORE_DURATION;
+        assert!(!tracker.is_ignored(&sender, spoofed, later));
+        assert!(!tracker.record_failure(&sender, spoofed, later));
+    }
+
+    #[test]
+    fn failures_outside_window() {
+        let mut tracker = DiscoAuthTracker::default();
+        let sender = key::node::SecretKey::generate().public_key();
+        let src = SendAddr::Derp(1);
+        let mut now = Instant::now();
+        for _ in 0..DISCO_AUTH_FAILURE_THRESHOLD * 2 {
+            assert!(!tracker.record_failure(&sender, src, now));
+            now += FAILURE_WINDOW / (DISCO_AUTH_FAILURE_THRESHOLD / 2);
+        }
+    }
+}
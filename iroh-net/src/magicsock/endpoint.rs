@@ -8,14 +8,17 @@ use std::{
 
 use futures::future::BoxFuture;
 use iroh_metrics::inc;
-use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
 use tokio::{sync::mpsc, time::Instant};
 use tracing::{debug, info, trace, warn};
 
 use crate::{config, disco, key, magicsock::Timer, net::ip::is_unicast_link_local, stun};
 
 use super::{
-    metrics::Metrics as MagicsockMetrics, ActorMessage, DiscoInfo, QuicMappedAddr, SendAddr,
+    hole_punch::{HolePunchRecorder, PathOutcome, HOLE_PUNCH_TIMEOUT},
+    keepalive::KeepaliveConfig,
+    metrics::Metrics as MagicsockMetrics,
+    ActorMessage, DiscoInfo, QuicMappedAddr, SendAddr,
 };
 
 /// How long we wait for a pong reply before assuming it's never coming.
@@ -41,6 +44,12 @@ const UPGRADE_INTERVAL: Duration = Duration::from_secs(60);
 /// How long we trust a UDP address as the exclusive path (without using DERP) without having heard a Pong reply.
 const TRUST_UDP_ADDR_DURATION: Duration = Duration::from_millis(6500);
 
+/// Delay between the discovery pings to the candidate addresses of an endpoint.
+///
+/// Like happy eyeballs (RFC 8305), the candidates are raced with staggered starts, and the
+/// first to reply wins. This is the minimum connection attempt delay of the RFC.
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(100);
+
 /// A conneciton endpoint that picks the best available path to communicate with a peer,
 /// based on network conditions and what the peer supports.
 #[derive(Debug)]
@@ -75,8 +84,25 @@ pub(super) struct Endpoint {
 
     sent_ping: HashMap<stun::TransactionId, SentPing>,
 
+    /// Timers for the discovery pings not yet sent, dropped once an address replies.
+    staggered_pings: Vec<Timer>,
+
     /// Last time this endpoint was used.
     last_active: Instant,
+    /// When to send the next keepalive on the best address, while the session is quiet.
+    next_keepalive: Option<Instant>,
+
+    /// Largest datagram received directly over UDP.
+    largest_udp_payload: Option<usize>,
+
+    /// Records the outcome of upgrading to a direct path.
+    hole_punch: HolePunchRecorder,
+    /// The NAT type the peer sent in its pings.
+    remote_nat_type: config::NatType,
+    /// When we first pinged a direct address of the peer, in the current attempt.
+    hole_punch_started: Option<Instant>,
+    /// Whether the outcome of the current attempt has been recorded.
+    path_outcome_recorded: bool,
 }
 
 #[derive(derive_more::Debug)]
@@ -92,6 +118,7 @@ pub(super) struct Options {
     pub(super) msock_public_key: key::node::PublicKey,
     pub(super) public_key: key::node::PublicKey,
     pub(super) derp_addr: Option<u16>,
+    pub(super) hole_punch: HolePunchRecorder,
 }
 
 impl Endpoint {
@@ -115,11 +142,18 @@ impl Endpoint {
             best_addr_at: None,
             trust_best_addr_until: None,
             sent_ping: HashMap::new(),
+            staggered_pings: Vec::new(),
             endpoint_state: HashMap::new(),
             is_call_me_maybe_ep: HashMap::new(),
             pending_cli_pings: Vec::new(),
             expired: false,
             last_active: Instant::now(),
+            next_keepalive: None,
+            largest_udp_payload: None,
+            hole_punch: options.hole_punch,
+            remote_nat_type: config::NatType::Unknown,
+            hole_punch_started: None,
+            path_outcome_recorded: false,
         }
     }
 
@@ -127,6 +161,14 @@ impl Endpoint {
         &self.public_key
     }
 
+    /// Records the size of a datagram received directly over UDP.
+    pub(super) fn note_recv_udp(&mut self, size: usize) {
+        let largest = self
+            .largest_udp_payload
+            .map_or(size, |largest| largest.max(size));
+        self.largest_udp_payload = Some(largest);
+    }
+
     /// Returns info about this endpoint
     pub fn info(&self) -> EndpointInfo {
         let addrs = self
@@ -138,12 +180,22 @@ impl Endpoint {
             })
             .collect();
 
+        let has_direct_connection = self.is_best_addr_valid(Instant::now());
+        let conn_type = match (&self.best_addr, self.derp_addr) {
+            (Some(best_addr), _) if has_direct_connection => ConnectionType::Direct(best_addr.addr),
+            (Some(best_addr), Some(region)) => ConnectionType::Mixed(best_addr.addr, region),
+            (Some(best_addr), None) => ConnectionType::Direct(best_addr.addr),
+            (None, Some(region)) => ConnectionType::Relay(region),
+            (None, None) => ConnectionType::None,
+        };
         EndpointInfo {
             public_key: self.public_key.clone(),
             derp_addr: self.derp_addr,
             addrs,
-            has_direct_connection: self.is_best_addr_valid(Instant::now()),
+            has_direct_connection,
             latency: self.best_addr.as_ref().and_then(|a| a.latency),
+            conn_type,
+            largest_udp_payload: self.largest_udp_payload,
         }
     }
 
@@ -222,13 +274,10 @@ impl Endpoint {
             return (Some(pong.from.as_socket_addr()), false);
         }
 
-        // Randomly select an address to use until we retrieve latency information.
-        let udp_addr = self
-            .endpoint_state
-            .keys()
-            .filter_map(|k| k.as_udp())
-            .choose_stable(&mut rand::thread_rng())
-            .copied();
+        // Use the address that is pinged first until we retrieve latency information.
+        let udp_addr = happy_eyeballs_order(self.endpoint_state.iter())
+            .into_iter()
+            .find_map(|addr| addr.as_udp().copied());
 
         (udp_addr, udp_addr.is_some())
     }
@@ -270,14 +319,13 @@ impl Endpoint {
 
     /// Starts a ping for the "ping" command.
     /// `res` is value to call cb with, already partially filled.
-    #[allow(unused)]
     pub async fn cli_ping<F>(&mut self, mut res: config::PingResult, cb: F)
     where
         F: Fn(config::PingResult) -> BoxFuture<'static, ()> + Send + Sync + 'static,
     {
         if self.expired {
             res.err = Some("endpoint expired".to_string());
-            cb(res);
+            tokio::task::spawn(cb(res));
             return;
         }
 
@@ -316,6 +364,10 @@ impl Endpoint {
                 self.id,
                 self.public_key
             );
+            for PendingCliPing { mut res, cb } in self.pending_cli_pings.drain(..) {
+                res.err = Some("no UDP or DERP addresses known".to_string());
+                tokio::task::spawn(cb(res));
+            }
         }
     }
 
@@ -343,6 +395,29 @@ impl Endpoint {
                     self.trust_best_addr_until = None;
                 }
             }
+            if self.best_addr.is_none() {
+                if let Some(started) = self.hole_punch_started {
+                    if started.elapsed() >= HOLE_PUNCH_TIMEOUT {
+                        self.record_path_outcome(PathOutcome::RelayOnly);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records the outcome of the current attempt to find a direct path, only the first
+    /// outcome of an attempt counts.
+    fn record_path_outcome(&mut self, outcome: PathOutcome) {
+        if !self.path_outcome_recorded {
+            self.path_outcome_recorded = true;
+            self.hole_punch.record(self.remote_nat_type, outcome);
+        }
+    }
+
+    /// Notes the NAT type the peer sent in a ping.
+    pub(super) fn note_remote_nat_type(&mut self, nat_type: config::NatType) {
+        if nat_type != config::NatType::Unknown {
+            self.remote_nat_type = nat_type;
         }
     }
 
@@ -377,6 +452,7 @@ impl Endpoint {
                     msg: disco::Message::Ping(disco::Ping {
                         tx_id,
                         node_key: self.conn_public_key.clone(),
+                        nat_type: self.hole_punch.local_nat_type(),
                     }),
                 })
                 .await
@@ -403,6 +479,9 @@ impl Endpoint {
                 );
                 return;
             }
+            if !ep.is_derp() && self.hole_punch_started.is_none() {
+                self.hole_punch_started = Some(now);
+            }
         }
 
         let txid = stun::TransactionId::default();
@@ -456,33 +535,42 @@ impl Endpoint {
             true
         });
 
-        let pings: Vec<_> = self
-            .endpoint_state
-            .iter()
-            .filter_map(|(ep, st)| {
-                if st.last_ping.is_some()
-                    && now - *st.last_ping.as_ref().unwrap() < DISCO_PING_INTERVAL
-                {
-                    debug!(
-                        "disco: [{:?}] skipping ping, too new {:?} {:?}",
-                        ep, now, st.last_ping
-                    );
-                    return None;
-                }
-                Some(*ep)
-            })
-            .collect();
+        let pings: Vec<_> = happy_eyeballs_order(self.endpoint_state.iter().filter(|(ep, st)| {
+            if st.last_ping.is_some() && now - *st.last_ping.as_ref().unwrap() < DISCO_PING_INTERVAL
+            {
+                debug!(
+                    "disco: [{:?}] skipping ping, too new {:?} {:?}",
+                    ep, now, st.last_ping
+                );
+                return false;
+            }
+            true
+        }));
         debug!("sending pings to {:?}", pings);
 
         let sent_any = !pings.is_empty();
         let have_endpoints = !self.endpoint_state.is_empty();
 
+        // Race the candidates with staggered starts, the remaining pings are cancelled
+        // once one of them replies.
+        self.staggered_pings.clear();
         for (i, ep) in pings.into_iter().enumerate() {
-            if i == 0 && send_call_me_maybe {
-                debug!("disco: send, starting discovery for {:?}", self.public_key);
+            if i == 0 {
+                if send_call_me_maybe {
+                    debug!("disco: send, starting discovery for {:?}", self.public_key);
+                }
+                self.start_ping(ep, now, DiscoPingPurpose::Discovery).await;
+                continue;
             }
-
-            self.start_ping(ep, now, DiscoPingPurpose::Discovery).await;
+            let id = self.id;
+            let sender = self.conn_sender.clone();
+            let timer = Timer::after(HAPPY_EYEBALLS_DELAY * i as u32, async move {
+                sender
+                    .send(ActorMessage::EndpointStaggeredPing(id, ep))
+                    .await
+                    .ok();
+            });
+            self.staggered_pings.push(timer);
         }
 
         let derp_addr = self.derp_addr;
@@ -513,6 +601,19 @@ impl Endpoint {
         }
     }
 
+    /// Sends a discovery ping that was delayed by [`Endpoint::send_pings`].
+    pub(super) async fn staggered_ping(&mut self, ep: SendAddr) {
+        let now = Instant::now();
+        if self.is_best_addr_valid(now) {
+            debug!(
+                "disco: skipping staggered ping to {}, have a direct path",
+                ep
+            );
+            return;
+        }
+        self.start_ping(ep, now, DiscoPingPurpose::Discovery).await;
+    }
+
     pub fn update_from_node(&mut self, n: &config::Node) {
         if self.best_addr.is_none() {
             // we do not have a direct connection, so changing the derp information may
@@ -583,6 +684,9 @@ impl Endpoint {
             }
         }
         self.last_full_ping = None;
+        self.staggered_pings.clear();
+        self.hole_punch_started = None;
+        self.path_outcome_recorded = false;
         self.best_addr = None;
         self.best_addr_at = None;
         self.trust_best_addr_until = None;
@@ -776,6 +880,13 @@ impl Endpoint {
                     if is_better {
                         info!("disco: node {:?} now using {:?}", self.public_key, sp.to);
                         if self.best_addr.is_none() {
+                            // the first candidate to reply wins the race
+                            self.staggered_pings.clear();
+                            self.record_path_outcome(if to.is_ipv6() {
+                                PathOutcome::DirectIpv6
+                            } else {
+                                PathOutcome::DirectIpv4
+                            });
                             // we now have direct connection!
                             inc!(MagicsockMetrics, num_direct_conns_added);
                             if self.derp_addr.is_some() {
@@ -883,13 +994,19 @@ impl Endpoint {
 
     /// Send a heartbeat to the peer to keep the connection alive, or trigger a full ping
     /// if necessary.
-    pub(super) async fn stayin_alive(&mut self) {
+    ///
+    /// Once the session is inactive, the best address is only pinged as configured in
+    /// `keepalive`, to keep the NAT bindings of the path open.
+    pub(super) async fn stayin_alive(&mut self, keepalive: &KeepaliveConfig) {
         trace!("stayin_alive");
         let now = Instant::now();
-        if now.duration_since(self.last_active) > SESSION_ACTIVE_TIMEOUT {
+        let idle = now.duration_since(self.last_active);
+        if idle > SESSION_ACTIVE_TIMEOUT {
             debug!("skipping stayin alive: session is inactive");
+            self.send_keepalive(keepalive, idle, now).await;
             return;
         }
+        self.next_keepalive = None;
 
         // If we do not have an optimal addr, send pings to all known places.
         if self.want_full_ping(&now) {
@@ -918,6 +1035,29 @@ impl Endpoint {
         }
     }
 
+    /// Pings the best address of a quiet session, if a keepalive is due.
+    async fn send_keepalive(&mut self, keepalive: &KeepaliveConfig, idle: Duration, now: Instant) {
+        let Some(interval) = keepalive.direct_interval() else {
+            return;
+        };
+        let Some(udp_addr) = self.best_addr.as_ref().map(|a| a.addr) else {
+            return;
+        };
+        if idle > keepalive.max_idle() {
+            return;
+        }
+        let next = *self
+            .next_keepalive
+            .get_or_insert_with(|| now + keepalive.jittered(interval));
+        if now < next {
+            return;
+        }
+        debug!("keepalive ping for {} after {:?} idle", udp_addr, idle);
+        self.next_keepalive = Some(now + keepalive.jittered(interval));
+        self.start_ping(SendAddr::Udp(udp_addr), now, DiscoPingPurpose::StayinAlive)
+            .await;
+    }
+
     pub(crate) async fn get_send_addrs(&mut self) -> io::Result<(Option<SocketAddr>, Option<u16>)> {
         if self.expired {
             return Err(io::Error::new(io::ErrorKind::Other, "endpoint expired"));
@@ -1030,6 +1170,22 @@ impl PeerMap {
             .and_then(|id| self.by_id.get_mut(id))
     }
 
+    /// The IP address the peer behind `addr` sends to us from, if it is reachable over UDP.
+    ///
+    /// This is the IP of the best address of the peer, or otherwise one of the addresses
+    /// it pinged us from. Peers that are only reachable over DERP have none.
+    pub(super) fn remote_ip_for_quic_mapped_addr(&self, addr: &QuicMappedAddr) -> Option<IpAddr> {
+        let id = self.by_quic_mapped_addr.get(addr)?;
+        let ep = self.by_id.get(id)?;
+        if let Some(best_addr) = &ep.best_addr {
+            return Some(best_addr.addr.ip());
+        }
+        self.by_ip_port.iter().find_map(|(ipp, ep_id)| match ipp {
+            SendAddr::Udp(addr) if ep_id == id => Some(addr.ip()),
+            _ => None,
+        })
+    }
+
     pub(super) fn endpoints(&self) -> impl Iterator<Item = (&usize, &Endpoint)> {
         self.by_id.iter()
     }
@@ -1130,9 +1286,58 @@ pub struct EndpointInfo {
     pub has_direct_connection: bool,
     /// Current latency information, for a direct connection if available.
     pub latency: Option<Duration>,
+    /// The path currently used to send to this node.
+    pub conn_type: ConnectionType,
+    /// Largest datagram received directly over UDP from this node.
+    ///
+    /// With path MTU discovery this shows the MTU discovered by the node for the path
+    /// towards us.
+    pub largest_udp_payload: Option<usize>,
 }
 
-#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, Hash)]
+/// The path used to send to a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectionType {
+    /// Directly over UDP, to this address.
+    Direct(SocketAddr),
+    /// Through the DERP server of this region.
+    Relay(u16),
+    /// Both directly and through DERP, while the direct address is not yet confirmed.
+    Mixed(SocketAddr, u16),
+    /// No path is known.
+    None,
+}
+
+/// Orders candidate addresses in the order in which they are tried.
+///
+/// Following happy eyeballs (RFC 8305), IPv6 and IPv4 addresses are interleaved starting
+/// with IPv6, each family in network map order. DERP addresses come last.
+fn happy_eyeballs_order<'a>(
+    candidates: impl Iterator<Item = (&'a SendAddr, &'a EndpointState)>,
+) -> Vec<SendAddr> {
+    let mut candidates: Vec<_> = candidates.collect();
+    candidates.sort_by_key(|(addr, st)| (st.index, addr.as_socket_addr()));
+    let (mut v6, mut v4, mut derp) = (Vec::new(), Vec::new(), Vec::new());
+    for (addr, _) in candidates {
+        match addr {
+            SendAddr::Udp(a) if a.is_ipv6() => v6.push(*addr),
+            SendAddr::Udp(_) => v4.push(*addr),
+            SendAddr::Derp(_) => derp.push(*addr),
+        }
+    }
+    let mut res = Vec::with_capacity(v6.len() + v4.len() + derp.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => res.extend(a.into_iter().chain(b)),
+        }
+    }
+    res.extend(derp);
+    res
+}
+
+#[derive(Default, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 enum Index {
     #[default]
     Deleted,
@@ -1233,3 +1438,32 @@ impl AddrLatency {
         self.latency < other.latency
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_happy_eyeballs_order() {
+        let state = |i| EndpointState {
+            index: Index::Some(i),
+            ..Default::default()
+        };
+        let v4_a = SendAddr::Udp("1.1.1.1:1".parse().unwrap());
+        let v4_b = SendAddr::Udp("2.2.2.2:2".parse().unwrap());
+        let v4_c = SendAddr::Udp("3.3.3.3:3".parse().unwrap());
+        let v6_a = SendAddr::Udp("[::1]:1".parse().unwrap());
+        let derp = SendAddr::Derp(1);
+        let candidates = HashMap::from([
+            (v4_b, state(1)),
+            (derp, state(5)),
+            (v4_a, state(0)),
+            (v6_a, state(3)),
+            (v4_c, state(2)),
+        ]);
+        assert_eq!(
+            happy_eyeballs_order(candidates.iter()),
+            vec![v6_a, v4_a, v4_b, v4_c, derp]
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Outcomes of upgrading connections from DERP to direct paths.
+//!
+//! Every peer starts out relayed over DERP, and is upgraded to a direct path once a disco
+//! ping to one of its addresses gets a pong. An attempt that has not found a direct path
+//! within [`HOLE_PUNCH_TIMEOUT`] counts as relay only. Outcomes are recorded together with
+//! the NAT type of this node from the last netcheck, and the NAT type the peer sent in its
+//! disco pings, so operators can see how well hole punching works for which networks.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use iroh_metrics::inc;
+use serde::{Deserialize, Serialize};
+
+use crate::config::NatType;
+
+use super::metrics::Metrics as MagicsockMetrics;
+
+/// How long an attempt may take to find a direct path before it counts as relay only.
+pub const HOLE_PUNCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The result of trying to upgrade the connection to a peer to a direct path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathOutcome {
+    /// A direct path over IPv4 was found.
+    DirectIpv4,
+    /// A direct path over IPv6 was found.
+    DirectIpv6,
+    /// No direct path was found, the connection stays relayed.
+    RelayOnly,
+}
+
+/// Number of attempts per outcome.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutcomeCounts {
+    /// Attempts that found a direct IPv4 path.
+    pub direct_ipv4: u64,
+    /// Attempts that found a direct IPv6 path.
+    pub direct_ipv6: u64,
+    /// Attempts that found no direct path.
+    pub relay_only: u64,
+}
+
+impl OutcomeCounts {
+    /// The total number of attempts.
+    pub fn total(&self) -> u64 {
+        self.direct_ipv4 + self.direct_ipv6 + self.relay_only
+    }
+
+    /// The share of attempts that found a direct path, `None` if there were none.
+    pub fn direct_ratio(&self) -> Option<f64> {
+        match self.total() {
+            0 => None,
+            total => Some((self.direct_ipv4 + self.direct_ipv6) as f64 / total as f64),
+        }
+    }
+
+    fn add(&mut self, outcome: PathOutcome) {
+        match outcome {
+            PathOutcome::DirectIpv4 => self.direct_ipv4 += 1,
+            PathOutcome::DirectIpv6 => self.direct_ipv6 += 1,
+            PathOutcome::RelayOnly => self.relay_only += 1,
+        }
+    }
+}
+
+/// Outcomes for one combination of NAT types.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NatPairOutcomes {
+    /// The NAT type of this node at the time of the attempts.
+    pub local: NatType,
+    /// The NAT type the peer reported.
+    pub remote: NatType,
+    /// The outcomes of the attempts.
+    pub counts: OutcomeCounts,
+}
+
+/// Path upgrade outcomes since the magic socket was started.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HolePunchStats {
+    /// The current NAT type of this node.
+    pub local: NatType,
+    /// All outcomes, regardless of NAT types.
+    pub total: OutcomeCounts,
+    /// Outcomes by the NAT types of both sides.
+    pub by_nat_type: Vec<NatPairOutcomes>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    local: NatType,
+    outcomes: BTreeMap<(NatType, NatType), OutcomeCounts>,
+}
+
+/// Records path upgrade outcomes, shared by the magic socket and its endpoints.
+#[derive(Debug, Clone, Default)]
+pub(super) struct HolePunchRecorder(Arc<Mutex<Inner>>);
+
+impl HolePunchRecorder {
+    /// The NAT type of this node, sent to peers in disco pings.
+    pub(super) fn local_nat_type(&self) -> NatType {
+        self.0.lock().unwrap().local
+    }
+
+    /// Updates the NAT type of this node after a netcheck.
+    pub(super) fn set_local_nat_type(&self, nat_type: NatType) {
+        self.0.lock().unwrap().local = nat_type;
+    }
+
+    /// Records the outcome of an attempt with a peer behind `remote`.
+    pub(super) fn record(&self, remote: NatType, outcome: PathOutcome) {
+        match outcome {
+            PathOutcome::DirectIpv4 => {
+                inc!(MagicsockMetrics, path_upgrade_direct_ipv4);
+            }
+            PathOutcome::DirectIpv6 => {
+                inc!(MagicsockMetrics, path_upgrade_direct_ipv6);
+            }
+            PathOutcome::RelayOnly => {
+                inc!(MagicsockMetrics, path_upgrade_relay_only);
+            }
+        }
+        let mut inner = self.0.lock().unwrap();
+        let local = inner.local;
+        inner
+            .outcomes
+            .entry((local, remote))
+            .or_default()
+            .add(outcome);
+    }
+
+    /// A snapshot of the outcomes so far.
+    pub(super) fn stats(&self) -> HolePunchStats {
+        let inner = self.0.lock().unwrap();
+        let mut total = OutcomeCounts::default();
+        let by_nat_type = inner
+            .outcomes
+            .iter()
+            .map(|(&(local, remote), &counts)| {
+                total.direct_ipv4 += counts.direct_ipv4;
+                total.direct_ipv6 += counts.direct_ipv6;
+                total.relay_only += counts.relay_only;
+                NatPairOutcomes {
+                    local,
+                    remote,
+                    counts,
+                }
+            })
+            .collect();
+        HolePunchStats {
+            local: inner.local,
+            total,
+            by_nat_type,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_by_nat_type() {
+        let recorder = HolePunchRecorder::default();
+        recorder.record(NatType::Unknown, PathOutcome::RelayOnly);
+        recorder.set_local_nat_type(NatType::EndpointIndependent);
+        recorder.record(NatType::EndpointDependent, PathOutcome::RelayOnly);
+        recorder.record(NatType::EndpointDependent, PathOutcome::DirectIpv4);
+        recorder.record(NatType::EndpointIndependent, PathOutcome::DirectIpv6);
+
+        let stats = recorder.stats();
+        assert_eq!(stats.local, NatType::EndpointIndependent);
+        assert_eq!(stats.total.total(), 4);
+        assert_eq!(stats.total.direct_ratio(), Some(0.5));
+        assert_eq!(stats.by_nat_type.len(), 3);
+        let hard = stats
+            .by_nat_type
+            .iter()
+            .find(|o| o.remote == NatType::EndpointDependent)
+            .unwrap();
+        assert_eq!(hard.local, NatType::EndpointIndependent);
+        assert_eq!(hard.counts.relay_only, 1);
+        assert_eq!(hard.counts.direct_ipv4, 1);
+        assert_eq!(stats.by_nat_type[0].local, NatType::Unknown);
+    }
+}
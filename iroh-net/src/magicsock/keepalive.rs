@@ -0,0 +1,149 @@
+//! Keepalives for quiet sessions.
+//!
+//! NATs and stateful firewalls drop the bindings of flows that were quiet for a while,
+//! often after 30 seconds for UDP. A long-lived session that rarely sends anything, such
+//! as a document that is kept in sync, would then lose its direct path and its DERP
+//! connection without noticing. To keep the bindings alive, the magicsock pings the DERP
+//! servers it is connected to, and the direct paths of peers that were recently active,
+//! whenever they were quiet for the configured interval.
+//!
+//! Every interval is randomized by the jitter, so that many nodes started at the same
+//! time do not send their keepalives in synchronized bursts.
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Default interval of the keepalives on DERP connections.
+pub const DEFAULT_DERP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+
+/// Default interval of the keepalives on direct paths.
+pub const DEFAULT_DIRECT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+
+/// Default time after the last use of a direct path during which it is kept alive.
+pub const DEFAULT_DIRECT_KEEPALIVE_MAX_IDLE: Duration = Duration::from_secs(10 * 60);
+
+/// Default fraction by which the keepalive intervals are randomized.
+pub const DEFAULT_KEEPALIVE_JITTER: f64 = 0.2;
+
+/// Largest fraction by which the keepalive intervals are randomized.
+///
+/// Keeps a jittered interval at least half of the configured one, a jitter close to one
+/// would allow intervals close to zero.
+pub const MAX_KEEPALIVE_JITTER: f64 = 0.5;
+
+/// Keepalives sent on quiet DERP connections and direct paths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepaliveConfig {
+    derp: Option<Duration>,
+    direct: Option<Duration>,
+    direct_max_idle: Duration,
+    jitter: f64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            derp: Some(DEFAULT_DERP_KEEPALIVE_INTERVAL),
+            direct: Some(DEFAULT_DIRECT_KEEPALIVE_INTERVAL),
+            direct_max_idle: DEFAULT_DIRECT_KEEPALIVE_MAX_IDLE,
+            jitter: DEFAULT_KEEPALIVE_JITTER,
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    /// No keepalives at all.
+    pub fn disabled() -> Self {
+        Self {
+            derp: None,
+            direct: None,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the interval of the keepalives on DERP connections, `None` disables them.
+    ///
+    /// Defaults to [`DEFAULT_DERP_KEEPALIVE_INTERVAL`].
+    pub fn derp(mut self, interval: Option<Duration>) -> Self {
+        self.derp = interval;
+        self
+    }
+
+    /// Sets the interval of the keepalives on direct paths, `None` disables them.
+    ///
+    /// Defaults to [`DEFAULT_DIRECT_KEEPALIVE_INTERVAL`]. The interval is checked on every
+    /// heartbeat of the magicsock, so intervals below five seconds have no effect.
+    pub fn direct(mut self, interval: Option<Duration>) -> Self {
+        self.direct = interval;
+        self
+    }
+
+    /// Sets how long a direct path is kept alive after it was last used.
+    ///
+    /// Defaults to [`DEFAULT_DIRECT_KEEPALIVE_MAX_IDLE`].
+    pub fn direct_max_idle(mut self, max_idle: Duration) -> Self {
+        self.direct_max_idle = max_idle;
+        self
+    }
+
+    /// Sets the fraction by which the intervals are randomized, clamped to
+    /// `0.0..=`[`MAX_KEEPALIVE_JITTER`].
+    ///
+    /// Defaults to [`DEFAULT_KEEPALIVE_JITTER`].
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, MAX_KEEPALIVE_JITTER);
+        self
+    }
+
+    /// The interval of the keepalives on DERP connections, without jitter.
+    pub(super) fn derp_interval(&self) -> Option<Duration> {
+        self.derp
+    }
+
+    /// The interval of the keepalives on direct paths, without jitter.
+    pub(super) fn direct_interval(&self) -> Option<Duration> {
+        self.direct
+    }
+
+    pub(super) fn max_idle(&self) -> Duration {
+        self.direct_max_idle
+    }
+
+    /// Randomizes `interval` by the configured jitter.
+    pub(super) fn jittered(&self, interval: Duration) -> Duration {
+        if self.jitter == 0.0 {
+            return interval;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+        interval.mul_f64(factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalive_jitter() {
+        let interval = Duration::from_secs(10);
+        let config = KeepaliveConfig::default().jitter(0.5);
+        for _ in 0..100 {
+            let delay = config.jittered(interval);
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15));
+        }
+        let config = KeepaliveConfig::default().jitter(0.0);
+        assert_eq!(config.jittered(interval), interval);
+        assert_eq!(
+            KeepaliveConfig::default().jitter(3.0).jitter,
+            MAX_KEEPALIVE_JITTER
+        );
+        let config = KeepaliveConfig::default().jitter(1.0);
+        for _ in 0..100 {
+            assert!(config.jittered(interval) >= Duration::from_secs(5));
+        }
+
+        let disabled = KeepaliveConfig::disabled();
+        assert_eq!(disabled.derp_interval(), None);
+        assert_eq!(disabled.direct_interval(), None);
+    }
+}
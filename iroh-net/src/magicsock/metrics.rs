@@ -25,6 +25,10 @@ pub struct Metrics {
     pub send_ipv6_error: Counter,
     pub send_derp: Counter,
     pub send_derp_error: Counter,
+    /// Number of bytes sent to peers over DERP.
+    pub send_derp_bytes: Counter,
+    /// Number of bytes received from peers over DERP.
+    pub recv_derp_bytes: Counter,
 
     // Data packets (non-disco)
     pub send_data: Counter,
@@ -34,6 +38,18 @@ pub struct Metrics {
     pub recv_data_ipv6: Counter,
     /// Number of QUIC datagrams received.
     pub recv_datagrams: Counter,
+    /// UDP datagrams received that are larger than the default MTU discovery bound.
+    pub recv_large_datagrams: Counter,
+    /// Batches of UDP packets sent with segmentation offload.
+    pub send_gso_batches: Counter,
+    /// Packets split out of segmentation offload batches before sending.
+    pub send_segments_split: Counter,
+    /// Batches of packets handed from the UDP sockets to the magicsock actor.
+    pub recv_batches: Counter,
+    /// Batches of QUIC transmits sent by the magicsock actor.
+    pub send_batches: Counter,
+    /// Groups of QUIC transmits drained into send batches.
+    pub send_batch_groups: Counter,
 
     // Disco packets
     pub send_disco_udp: Counter,
@@ -46,6 +62,8 @@ pub struct Metrics {
     pub recv_disco_bad_peer: Counter,
     pub recv_disco_bad_key: Counter,
     pub recv_disco_bad_parse: Counter,
+    /// Disco messages dropped because their source failed authentication too often.
+    pub recv_disco_ignored: Counter,
 
     pub recv_disco_udp: Counter,
     pub recv_disco_derp: Counter,
@@ -69,6 +87,12 @@ pub struct Metrics {
     pub num_relay_conns_added: Counter,
     /// The number of connections to peers we have removed over relay.
     pub num_relay_conns_removed: Counter,
+    /// Path upgrades that found a direct IPv4 path.
+    pub path_upgrade_direct_ipv4: Counter,
+    /// Path upgrades that found a direct IPv6 path.
+    pub path_upgrade_direct_ipv6: Counter,
+    /// Path upgrades that found no direct path in time.
+    pub path_upgrade_relay_only: Counter,
 }
 
 impl Default for Metrics {
@@ -92,6 +116,8 @@ impl Default for Metrics {
             send_ipv6_error: Counter::new("send_ipv6_error"),
             send_derp: Counter::new("send_derp"),
             send_derp_error: Counter::new("send_derp_error"),
+            send_derp_bytes: Counter::new("send_derp_bytes"),
+            recv_derp_bytes: Counter::new("recv_derp_bytes"),
 
             // Data packets (non-disco)
             send_data: Counter::new("send_data"),
@@ -100,6 +126,12 @@ impl Default for Metrics {
             recv_data_ipv4: Counter::new("recv_data_ipv4"),
             recv_data_ipv6: Counter::new("recv_data_ipv6"),
             recv_datagrams: Counter::new("recv_datagrams"),
+            recv_large_datagrams: Counter::new("recv_large_datagrams"),
+            send_gso_batches: Counter::new("send_gso_batches"),
+            send_segments_split: Counter::new("send_segments_split"),
+            recv_batches: Counter::new("recv_batches"),
+            send_batches: Counter::new("send_batches"),
+            send_batch_groups: Counter::new("send_batch_groups"),
 
             // Disco packets
             send_disco_udp: Counter::new("disco_send_udp"),
@@ -112,6 +144,7 @@ impl Default for Metrics {
             recv_disco_bad_peer: Counter::new("disco_recv_bad_peer"),
             recv_disco_bad_key: Counter::new("disco_recv_bad_key"),
             recv_disco_bad_parse: Counter::new("disco_recv_bad_parse"),
+            recv_disco_ignored: Counter::new("disco_recv_ignored"),
 
             recv_disco_udp: Counter::new("disco_recv_udp"),
             recv_disco_derp: Counter::new("disco_recv_derp"),
@@ -134,6 +167,9 @@ impl Default for Metrics {
             num_relay_conns_removed: Counter::new(
                 "number of relay connections to a peer we no longer rely on",
             ),
+            path_upgrade_direct_ipv4: Counter::new("path upgrades to a direct IPv4 path"),
+            path_upgrade_direct_ipv6: Counter::new("path upgrades to a direct IPv6 path"),
+            path_upgrade_relay_only: Counter::new("path upgrades that stayed relay only"),
         }
     }
 }
@@ -12,6 +12,8 @@ use quinn::AsyncUdpSocket;
 use tokio::io::Interest;
 use tracing::{debug, trace, warn};
 
+#[cfg(any(test, feature = "test-utils"))]
+use super::sim::{VirtualHost, VirtualSocket};
 use super::{CurrentPortFate, Network};
 
 /// UDP socket read/write buffer size (7MB). The value of 7MB is chosen as it
@@ -19,15 +21,43 @@ use super::{CurrentPortFate, Network};
 const SOCKET_BUFFER_SIZE: usize = 7 << 20;
 
 /// A UDP socket that can be re-bound. Unix has no notion of re-binding a socket, so we swap it out for a new one.
+///
+/// On a [`VirtualNetwork`](super::sim::VirtualNetwork) the socket is virtual and is never
+/// re-bound.
 #[derive(Clone, Debug)]
 pub struct RebindingUdpConn {
-    io: Arc<tokio::net::UdpSocket>,
-    state: Arc<quinn_udp::UdpSocketState>,
+    inner: Inner,
+}
+
+#[derive(Clone, Debug)]
+enum Inner {
+    Os {
+        io: Arc<tokio::net::UdpSocket>,
+        state: Arc<quinn_udp::UdpSocketState>,
+    },
+    #[cfg(any(test, feature = "test-utils"))]
+    Virtual(VirtualSocket),
 }
 
 impl RebindingUdpConn {
-    pub(super) fn as_socket(&self) -> Arc<tokio::net::UdpSocket> {
-        self.io.clone()
+    /// The OS socket, `None` for virtual sockets.
+    pub(super) fn as_socket(&self) -> Option<Arc<tokio::net::UdpSocket>> {
+        match &self.inner {
+            Inner::Os { io, .. } => Some(io.clone()),
+            #[cfg(any(test, feature = "test-utils"))]
+            Inner::Virtual(_) => None,
+        }
+    }
+
+    /// The public address of a virtual socket, as a STUN server would report it.
+    ///
+    /// `None` for OS sockets, whose public address is found by netcheck.
+    pub(super) fn virtual_stun(&self) -> Option<SocketAddr> {
+        match &self.inner {
+            Inner::Os { .. } => None,
+            #[cfg(any(test, feature = "test-utils"))]
+            Inner::Virtual(socket) => socket.stun(),
+        }
     }
 
     pub(super) async fn rebind(
@@ -43,14 +73,21 @@ impl RebindingUdpConn {
             cur_port_fate
         );
 
+        let Inner::Os { io, state } = &mut self.inner else {
+            // virtual networks do not change underneath the socket
+            return Ok(());
+        };
+
         // Do not bother rebinding if we are keeping the port.
-        if self.port() == port && cur_port_fate == CurrentPortFate::Keep {
+        if io.local_addr().map(|a| a.port()).ok() == Some(port)
+            && cur_port_fate == CurrentPortFate::Keep
+        {
             return Ok(());
         }
 
-        let sock = bind(Some(&self.io), port, network, cur_port_fate).await?;
-        self.io = Arc::new(tokio::net::UdpSocket::from_std(sock)?);
-        self.state = Default::default();
+        let sock = bind(Some(&**io), port, network, cur_port_fate).await?;
+        *io = Arc::new(tokio::net::UdpSocket::from_std(sock)?);
+        *state = Default::default();
 
         Ok(())
     }
@@ -58,8 +95,22 @@ impl RebindingUdpConn {
     pub(super) async fn bind(port: u16, network: Network) -> anyhow::Result<Self> {
         let sock = bind(None, port, network, CurrentPortFate::Keep).await?;
         Ok(Self {
-            io: Arc::new(tokio::net::UdpSocket::from_std(sock)?),
-            state: Default::default(),
+            inner: Inner::Os {
+                io: Arc::new(tokio::net::UdpSocket::from_std(sock)?),
+                state: Default::default(),
+            },
+        })
+    }
+
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(super) fn bind_virtual(host: &VirtualHost, port: u16) -> anyhow::Result<Self> {
+        let socket = host.bind(port)?;
+        debug!(
+            "bind_socket: successfully bound virtual {}",
+            socket.local_addr()
+        );
+        Ok(Self {
+            inner: Inner::Virtual(socket),
         })
     }
 
@@ -80,8 +131,11 @@ impl AsyncUdpSocket for RebindingUdpConn {
         cx: &mut Context,
         transmits: &[quinn_udp::Transmit],
     ) -> Poll<io::Result<usize>> {
-        let inner = &self.state;
-        let io = &self.io;
+        let (io, inner) = match &self.inner {
+            Inner::Os { io, state } => (io, state),
+            #[cfg(any(test, feature = "test-utils"))]
+            Inner::Virtual(socket) => return socket.poll_send(transmits),
+        };
         loop {
             ready!(io.poll_send_ready(cx))?;
             if let Ok(res) = io.try_io(Interest::WRITABLE, || {
@@ -107,10 +161,15 @@ impl AsyncUdpSocket for RebindingUdpConn {
         bufs: &mut [io::IoSliceMut<'_>],
         meta: &mut [quinn_udp::RecvMeta],
     ) -> Poll<io::Result<usize>> {
+        let (io, state) = match &self.inner {
+            Inner::Os { io, state } => (io, state),
+            #[cfg(any(test, feature = "test-utils"))]
+            Inner::Virtual(socket) => return socket.poll_recv(cx, bufs, meta),
+        };
         loop {
-            ready!(self.io.poll_recv_ready(cx))?;
-            if let Ok(res) = self.io.try_io(Interest::READABLE, || {
-                self.state.recv(Arc::as_ref(&self.io).into(), bufs, meta)
+            ready!(io.poll_recv_ready(cx))?;
+            if let Ok(res) = io.try_io(Interest::READABLE, || {
+                state.recv(Arc::as_ref(io).into(), bufs, meta)
             }) {
                 for meta in meta.iter().take(res) {
                     trace!(
@@ -127,7 +186,11 @@ impl AsyncUdpSocket for RebindingUdpConn {
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.io.local_addr()
+        match &self.inner {
+            Inner::Os { io, .. } => io.local_addr(),
+            #[cfg(any(test, feature = "test-utils"))]
+            Inner::Virtual(socket) => Ok(socket.local_addr()),
+        }
     }
 }
 
@@ -236,7 +299,7 @@ mod tests {
     fn wrap_socket(conn: impl AsyncUdpSocket) -> Result<(quinn::Endpoint, key::node::SecretKey)> {
         let key = key::node::SecretKey::generate();
         let tls_server_config =
-            tls::make_server_config(&key.clone().into(), vec![ALPN.to_vec()], false)?;
+            tls::make_server_config(&key.clone().into(), None, vec![ALPN.to_vec()], false)?;
         let server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
         let mut quic_ep = quinn::Endpoint::new_with_abstract_socket(
             quinn::EndpointConfig::default(),
@@ -0,0 +1,542 @@
+//! An in-memory network to run [`MagicSock`]s on, for deterministic tests.
+//!
+//! A [`VirtualNetwork`] connects virtual hosts, each of which can sit behind a [`Nat`].
+//! Datagrams between hosts are translated and filtered like a real NAT would, and are
+//! delayed and dropped according to the [`LinkConditions`] of the hosts. Loss is drawn
+//! from a seeded random number generator and delays use the tokio clock, so a test with
+//! paused time sees the same network behaviour on every run.
+//!
+//! The network only carries UDP. DERP connections still use TCP, so tests of the DERP
+//! fallback need a DERP server on localhost. There is no STUN server either: a host
+//! learns its public address from the network directly, with the mapping a STUN server
+//! at [`STUN_IP`] would have observed.
+//!
+//! The module is only available in tests and with the `test-utils` feature.
+//!
+//! [`MagicSock`]: super::MagicSock
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::time::{Instant, Sleep};
+
+/// The address of the notional STUN server hosts learn their public address from.
+pub const STUN_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+/// The port of the notional STUN server.
+pub const STUN_PORT: u16 = 3478;
+
+/// First port handed out for ports bound as 0 and for NAT mappings.
+const FIRST_EPHEMERAL_PORT: u16 = 20000;
+
+/// Network address translation in front of a virtual host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nat {
+    /// The host has a public address, anyone can reach it.
+    None,
+    /// Endpoint independent mapping, with address and port dependent filtering.
+    ///
+    /// A socket keeps its public port for all destinations, but only datagrams from
+    /// addresses it sent to are let in. Holes can be punched through this NAT.
+    Cone,
+    /// A new mapping for every destination, with address and port dependent filtering.
+    ///
+    /// The public address learned via STUN is useless to peers, so two hosts behind such
+    /// NATs can only talk through DERP.
+    Symmetric,
+}
+
+/// Latency and loss of the access link of a host.
+///
+/// A datagram between two hosts crosses the links of both.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// One way delay.
+    pub latency: Duration,
+    /// Probability to drop a datagram, between 0 and 1.
+    pub loss: f64,
+}
+
+/// An in-memory network of virtual hosts.
+#[derive(Debug, Clone)]
+pub struct VirtualNetwork(Arc<Mutex<State>>);
+
+/// A host on a [`VirtualNetwork`], to bind a magic socket on.
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    network: VirtualNetwork,
+    ip: IpAddr,
+}
+
+#[derive(Debug)]
+struct State {
+    rng: StdRng,
+    /// Conditions of hosts which have none of their own.
+    conditions: LinkConditions,
+    /// Hosts by their own address, which is private for hosts behind a NAT.
+    hosts: HashMap<IpAddr, Host>,
+    /// Hosts behind a NAT by the public address of the NAT.
+    nat_ips: HashMap<IpAddr, IpAddr>,
+    sockets: HashMap<SocketAddr, Weak<SocketInner>>,
+    /// Breaks ties between datagrams delivered at the same time.
+    seq: u64,
+}
+
+#[derive(Debug)]
+struct Host {
+    nat: Nat,
+    public_ip: IpAddr,
+    conditions: Option<LinkConditions>,
+    next_port: u16,
+    /// Public port of every mapping, by private source and, for symmetric NATs,
+    /// destination.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// Mappings by their public port.
+    ports: HashMap<u16, Mapping>,
+}
+
+#[derive(Debug)]
+struct Mapping {
+    private: SocketAddr,
+    /// Addresses the mapping sent to, which are the only ones let in.
+    allowed: HashSet<SocketAddr>,
+}
+
+impl Host {
+    fn next_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = self.next_port.checked_add(1).expect("out of ports");
+        port
+    }
+}
+
+impl State {
+    /// The source address of a datagram from `from` to `to`, after translation.
+    fn outbound(&mut self, from: SocketAddr, to: SocketAddr) -> Option<SocketAddr> {
+        let host = self.hosts.get_mut(&from.ip())?;
+        let key = match host.nat {
+            Nat::None => return Some(from),
+            Nat::Cone => (from, None),
+            Nat::Symmetric => (from, Some(to)),
+        };
+        let port = match host.mappings.get(&key) {
+            Some(port) => *port,
+            None => {
+                let port = host.next_port();
+                host.mappings.insert(key, port);
+                let mapping = Mapping {
+                    private: from,
+                    allowed: HashSet::new(),
+                };
+                host.ports.insert(port, mapping);
+                port
+            }
+        };
+        let mapping = host.ports.get_mut(&port).expect("mapping of a known port");
+        mapping.allowed.insert(to);
+        Some(SocketAddr::new(host.public_ip, port))
+    }
+
+    /// The socket a datagram from the public address `src` to `to` is delivered to.
+    fn inbound(&self, src: SocketAddr, to: SocketAddr) -> Option<SocketAddr> {
+        if let Some(private_ip) = self.nat_ips.get(&to.ip()) {
+            let mapping = self.hosts.get(private_ip)?.ports.get(&to.port())?;
+            return mapping.allowed.contains(&src).then_some(mapping.private);
+        }
+        // the private addresses of hosts behind a NAT are not routed
+        let host = self.hosts.get(&to.ip())?;
+        (host.nat == Nat::None).then_some(to)
+    }
+
+    fn in_use(&self, addr: SocketAddr) -> bool {
+        self.sockets
+            .get(&addr)
+            .map_or(false, |socket| socket.strong_count() > 0)
+    }
+
+    fn conditions(&self, ip: IpAddr) -> LinkConditions {
+        self.hosts
+            .get(&ip)
+            .and_then(|host| host.conditions)
+            .unwrap_or(self.conditions)
+    }
+
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, data: Bytes) {
+        let route = if from.ip() == to.ip() {
+            Some((from, to, Duration::ZERO))
+        } else {
+            self.outbound(from, to).and_then(|src| {
+                let dest = self.inbound(src, to)?;
+                let (a, b) = (self.conditions(from.ip()), self.conditions(dest.ip()));
+                let delivered = (1.0 - a.loss.clamp(0.0, 1.0)) * (1.0 - b.loss.clamp(0.0, 1.0));
+                self.rng
+                    .gen_bool(delivered)
+                    .then_some((src, dest, a.latency + b.latency))
+            })
+        };
+        let Some((src, dest, latency)) = route else {
+            tracing::trace!("[SIM] dropped {from} -> {to} ({}b)", data.len());
+            return;
+        };
+        let Some(socket) = self.sockets.get(&dest).and_then(Weak::upgrade) else {
+            tracing::trace!("[SIM] nobody listens on {dest} ({}b)", data.len());
+            return;
+        };
+        self.seq += 1;
+        let queued = Queued {
+            deliver_at: Instant::now() + latency,
+            seq: self.seq,
+            src,
+            data,
+        };
+        let mut inbox = socket.inbox.lock().unwrap();
+        inbox.queue.push(queued);
+        if let Some(waker) = inbox.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl VirtualNetwork {
+    /// Create a network whose random decisions, i.e. which datagrams are lost, are
+    /// derived from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(State {
+            rng: StdRng::seed_from_u64(seed),
+            conditions: Default::default(),
+            hosts: HashMap::new(),
+            nat_ips: HashMap::new(),
+            sockets: HashMap::new(),
+            seq: 0,
+        })))
+    }
+
+    /// Set the link conditions of all hosts which have none of their own.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.0.lock().unwrap().conditions = conditions;
+    }
+
+    /// Add a host, behind its own NAT unless `nat` is [`Nat::None`].
+    ///
+    /// Public addresses are taken from 198.18.0.0/15, private addresses from 10.0.0.0/8.
+    pub fn add_host(&self, nat: Nat) -> VirtualHost {
+        let mut state = self.0.lock().unwrap();
+        let n = u8::try_from(state.hosts.len() + 1)
+            .ok()
+            .filter(|n| *n < 255)
+            .expect("too many hosts");
+        let (ip, public_ip) = match nat {
+            Nat::None => {
+                let ip = IpAddr::V4(Ipv4Addr::new(198, 18, 0, n));
+                (ip, ip)
+            }
+            Nat::Cone | Nat::Symmetric => {
+                let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, n));
+                let public_ip = IpAddr::V4(Ipv4Addr::new(198, 19, 0, n));
+                state.nat_ips.insert(public_ip, ip);
+                (ip, public_ip)
+            }
+        };
+        let host = Host {
+            nat,
+            public_ip,
+            conditions: None,
+            next_port: FIRST_EPHEMERAL_PORT,
+            mappings: HashMap::new(),
+            ports: HashMap::new(),
+        };
+        state.hosts.insert(ip, host);
+        VirtualHost {
+            network: self.clone(),
+            ip,
+        }
+    }
+}
+
+impl VirtualHost {
+    /// The address of the host, private if it is behind a NAT.
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// The address of the host as seen from the internet.
+    pub fn public_ip(&self) -> IpAddr {
+        let state = self.network.0.lock().unwrap();
+        state.hosts[&self.ip].public_ip
+    }
+
+    /// Set the conditions of the link of this host, overriding those of the network.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        let mut state = self.network.0.lock().unwrap();
+        if let Some(host) = state.hosts.get_mut(&self.ip) {
+            host.conditions = Some(conditions);
+        }
+    }
+
+    /// Bind a socket on `port` of this host, or on a free port if `port` is 0.
+    pub(super) fn bind(&self, port: u16) -> io::Result<VirtualSocket> {
+        let mut state = self.network.0.lock().unwrap();
+        let port = match port {
+            0 => loop {
+                let host = state.hosts.get_mut(&self.ip).expect("host of the network");
+                let port = host.next_port();
+                if !state.in_use(SocketAddr::new(self.ip, port)) {
+                    break port;
+                }
+            },
+            port => port,
+        };
+        let addr = SocketAddr::new(self.ip, port);
+        if state.in_use(addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{addr} is in use"),
+            ));
+        }
+        let inner = Arc::new(SocketInner {
+            network: self.network.clone(),
+            addr,
+            inbox: Default::default(),
+        });
+        state.sockets.insert(addr, Arc::downgrade(&inner));
+        Ok(VirtualSocket(inner))
+    }
+}
+
+/// A datagram waiting to be received.
+#[derive(Debug)]
+struct Queued {
+    deliver_at: Instant,
+    seq: u64,
+    src: SocketAddr,
+    data: Bytes,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, the heap pops the earliest datagram first
+        (other.deliver_at, other.seq).cmp(&(self.deliver_at, self.seq))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inbox {
+    queue: BinaryHeap<Queued>,
+    waker: Option<Waker>,
+    /// Fires when the next datagram in the queue is due.
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+#[derive(Debug)]
+struct SocketInner {
+    network: VirtualNetwork,
+    addr: SocketAddr,
+    inbox: Mutex<Inbox>,
+}
+
+impl Drop for SocketInner {
+    fn drop(&mut self) {
+        // the network may be locked by this thread if it held the last reference, the
+        // entry is then left behind and no longer counts as in use
+        if let Ok(mut state) = self.network.0.try_lock() {
+            if !state.in_use(self.addr) {
+                state.sockets.remove(&self.addr);
+            }
+        }
+    }
+}
+
+/// A UDP socket on a [`VirtualHost`].
+#[derive(Debug, Clone)]
+pub(super) struct VirtualSocket(Arc<SocketInner>);
+
+impl VirtualSocket {
+    pub(super) fn local_addr(&self) -> SocketAddr {
+        self.0.addr
+    }
+
+    /// The public address of this socket, as a STUN server would report it.
+    pub(super) fn stun(&self) -> Option<SocketAddr> {
+        let mut state = self.0.network.0.lock().unwrap();
+        state.outbound(self.0.addr, (STUN_IP, STUN_PORT).into())
+    }
+
+    pub(super) fn poll_send(&self, transmits: &[quinn_udp::Transmit]) -> Poll<io::Result<usize>> {
+        let mut state = self.0.network.0.lock().unwrap();
+        for transmit in transmits {
+            let size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for segment in transmit.contents.chunks(size.max(1)) {
+                let data = Bytes::copy_from_slice(segment);
+                state.send(self.0.addr, transmit.destination, data);
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    pub(super) fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut inbox = self.0.inbox.lock().unwrap();
+        let now = Instant::now();
+        let mut count = 0;
+        while count < bufs.len().min(meta.len()) {
+            match inbox.queue.peek() {
+                Some(queued) if queued.deliver_at <= now => {}
+                _ => break,
+            }
+            let queued = inbox.queue.pop().expect("peeked");
+            let len = queued.data.len().min(bufs[count].len());
+            bufs[count][..len].copy_from_slice(&queued.data[..len]);
+            meta[count] = quinn_udp::RecvMeta {
+                len,
+                stride: len,
+                addr: queued.src,
+                dst_ip: Some(self.0.addr.ip()),
+                ecn: None,
+            };
+            count += 1;
+        }
+        if count > 0 {
+            return Poll::Ready(Ok(count));
+        }
+        inbox.waker = Some(cx.waker().clone());
+        if let Some(deliver_at) = inbox.queue.peek().map(|queued| queued.deliver_at) {
+            let timer = inbox
+                .timer
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deliver_at)));
+            timer.as_mut().reset(deliver_at);
+            if timer.as_mut().poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use super::*;
+
+    async fn send(socket: &VirtualSocket, to: SocketAddr, data: &'static [u8]) {
+        let transmit = quinn_udp::Transmit {
+            destination: to,
+            ecn: None,
+            contents: Bytes::from_static(data),
+            segment_size: None,
+            src_ip: None,
+        };
+        poll_fn(|_| socket.poll_send(std::slice::from_ref(&transmit)))
+            .await
+            .unwrap();
+    }
+
+    /// Receive a datagram, or `None` if there is none within a second.
+    async fn recv(socket: &VirtualSocket) -> Option<(SocketAddr, Vec<u8>)> {
+        let mut buf = [0u8; 64];
+        let mut meta = [quinn_udp::RecvMeta {
+            len: 0,
+            stride: 0,
+            addr: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            dst_ip: None,
+            ecn: None,
+        }];
+        let recv = poll_fn(|cx| {
+            let mut bufs = [io::IoSliceMut::new(&mut buf)];
+            socket.poll_recv(cx, &mut bufs, &mut meta)
+        });
+        tokio::time::timeout(Duration::from_secs(1), recv)
+            .await
+            .ok()?
+            .unwrap();
+        Some((meta[0].addr, buf[..meta[0].len].to_vec()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cone_nat_lets_in_replies_only() {
+        let network = VirtualNetwork::new(0);
+        let server = network.add_host(Nat::None).bind(1000).unwrap();
+        let client = network.add_host(Nat::Cone).bind(0).unwrap();
+
+        // the STUN mapping is the one used for every destination
+        let public = client.stun().unwrap();
+        assert_eq!(public.ip(), IpAddr::V4(Ipv4Addr::new(198, 19, 0, 2)));
+        send(&client, server.local_addr(), b"hello").await;
+        let (src, data) = recv(&server).await.unwrap();
+        assert_eq!((src, data.as_slice()), (public, &b"hello"[..]));
+
+        send(&server, src, b"reply").await;
+        assert_eq!(recv(&client).await.unwrap().1, b"reply");
+
+        // a host the client never sent to is filtered
+        let other = network.add_host(Nat::None).bind(1000).unwrap();
+        send(&other, public, b"unsolicited").await;
+        assert!(recv(&client).await.is_none());
+        // the private address is not routed
+        send(&server, client.local_addr(), b"private").await;
+        assert!(recv(&client).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn symmetric_nat_maps_per_destination() {
+        let network = VirtualNetwork::new(0);
+        let server = network.add_host(Nat::None).bind(1000).unwrap();
+        let client = network.add_host(Nat::Symmetric).bind(0).unwrap();
+
+        let public = client.stun().unwrap();
+        send(&client, server.local_addr(), b"hello").await;
+        let (src, _) = recv(&server).await.unwrap();
+        assert_eq!(src.ip(), public.ip());
+        assert_ne!(src.port(), public.port());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_and_loss() {
+        let network = VirtualNetwork::new(0);
+        let a = network.add_host(Nat::None);
+        let b = network.add_host(Nat::None);
+        a.set_conditions(LinkConditions {
+            latency: Duration::from_millis(30),
+            loss: 0.0,
+        });
+        let a = a.bind(1000).unwrap();
+        let b = b.bind(1000).unwrap();
+
+        let start = Instant::now();
+        send(&a, b.local_addr(), b"hello").await;
+        recv(&b).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        network.set_conditions(LinkConditions {
+            latency: Duration::ZERO,
+            loss: 1.0,
+        });
+        send(&a, b.local_addr(), b"lost").await;
+        assert!(recv(&b).await.is_none());
+    }
+}
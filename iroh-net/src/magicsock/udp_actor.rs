@@ -9,6 +9,7 @@ use std::{
 
 use bytes::{Bytes, BytesMut};
 use futures::{Stream, StreamExt};
+use iroh_metrics::inc;
 use quinn::AsyncUdpSocket;
 use tokio::sync::mpsc;
 use tracing::{debug, trace, warn};
@@ -16,6 +17,7 @@ use tracing::{debug, trace, warn};
 use crate::{disco, netcheck, stun};
 
 use super::{
+    metrics::Metrics as MagicsockMetrics,
     rebinding_conn::RebindingUdpConn,
     {Inner, Network, SendAddr},
 };
@@ -82,7 +84,7 @@ impl UdpActor {
         mut self,
         mut msg_receiver: mpsc::Receiver<UdpActorMessage>,
         net_checker: netcheck::Client,
-        ip_sender: mpsc::Sender<IpPacket>,
+        ip_sender: mpsc::Sender<Vec<IpPacket>>,
     ) {
         loop {
             trace!("tick");
@@ -99,69 +101,58 @@ impl UdpActor {
                     }
                 }
                 msg = self.next() => {
-                    match msg {
-                        None => break,
-                        Some(ip_msgs) => {
-                            trace!("tick: ip_msgs");
-                            match ip_msgs {
-                                Ok((packet, network, meta)) => {
-                                    // Classify packets
-
-                                    // Stun?
-                                    if stun::is(&packet) {
-                                        trace!("tick: stun packet");
-                                        net_checker.receive_stun_packet(packet, meta.addr);
-                                    } else if let Some((source, sealed_box)) = disco::source_and_box(&packet) {
-                                        // Disco?
-                                        trace!("tick: disco packet: {:?}", meta);
-                                        if ip_sender
-                                            .send(
-                                                IpPacket::Disco {
-                                                source,
-                                                sealed_box: packet.slice_ref(sealed_box),
-                                                src: SendAddr::Udp(meta.addr),
-                                            })
-                                            .await
-                                            .is_err()
-                                        {
-                                            warn!("ip_sender gone");
-                                            break;
-                                        };
-                                    } else {
-                                        // Forward
-                                        trace!("tick: udp forward packet");
-                                        let forward = match network {
-                                            Network::Ipv4 => NetworkReadResult::Ok {
-                                                source: NetworkSource::Ipv4,
-                                                bytes: packet,
-                                                meta,
-                                            },
-                                            Network::Ipv6 => NetworkReadResult::Ok {
-                                                source: NetworkSource::Ipv6,
-                                                bytes: packet,
-                                                meta,
-                                            },
-                                        };
-
-                                        if ip_sender.send(IpPacket::Forward(forward)).await.is_err() {
-                                            warn!("ip_sender gone");
-                                            break;
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    if ip_sender
-                                        .send(IpPacket::Forward(NetworkReadResult::Error(err)))
-                                        .await
-                                        .is_err()
-                                    {
-                                        warn!("ip_sender gone");
-                                        break;
-                                    }
+                    let Some(first) = msg else {
+                        break;
+                    };
+                    trace!("tick: ip_msgs");
+                    // The rest of the batch read from the socket is already buffered, classify
+                    // all of it and hand it to the actor at once.
+                    let mut batch = Vec::with_capacity(1 + self.out_buffer.len());
+                    let rest = std::mem::take(&mut self.out_buffer).into_iter().map(Ok);
+                    for msg in std::iter::once(first).chain(rest) {
+                        match msg {
+                            Ok((packet, network, meta)) => {
+                                // Classify packets
+
+                                // Stun?
+                                if stun::is(&packet) {
+                                    trace!("tick: stun packet");
+                                    net_checker.receive_stun_packet(packet, meta.addr);
+                                } else if let Some((source, sealed_box)) = disco::source_and_box(&packet) {
+                                    // Disco?
+                                    trace!("tick: disco packet: {:?}", meta);
+                                    batch.push(IpPacket::Disco {
+                                        source,
+                                        sealed_box: packet.slice_ref(sealed_box),
+                                        src: SendAddr::Udp(meta.addr),
+                                    });
+                                } else {
+                                    // Forward
+                                    trace!("tick: udp forward packet");
+                                    let source = match network {
+                                        Network::Ipv4 => NetworkSource::Ipv4,
+                                        Network::Ipv6 => NetworkSource::Ipv6,
+                                    };
+                                    batch.push(IpPacket::Forward(NetworkReadResult::Ok {
+                                        source,
+                                        bytes: packet,
+                                        meta,
+                                    }));
                                 }
                             }
+                            Err(err) => {
+                                batch.push(IpPacket::Forward(NetworkReadResult::Error(err)));
+                            }
                         }
                     }
+                    if batch.is_empty() {
+                        continue;
+                    }
+                    inc!(MagicsockMetrics, recv_batches);
+                    if ip_sender.send(batch).await.is_err() {
+                        warn!("ip_sender gone");
+                        break;
+                    }
                 }
             }
         }
@@ -20,6 +20,7 @@ use crate::net::ip::to_canonical;
 use crate::util::CancelOnDrop;
 
 use super::derp::DerpMap;
+use super::dns::DnsResolver;
 use super::portmapper;
 use super::stun;
 
@@ -195,7 +196,15 @@ impl Client {
     /// This starts a connected actor in the background.  Once the client is dropped it will
     /// stop running.
     pub async fn new(port_mapper: Option<portmapper::Client>) -> Result<Self> {
-        let mut actor = Actor::new(port_mapper)?;
+        Self::with_dns_resolver(port_mapper, DnsResolver::default()).await
+    }
+
+    /// Creates a new netcheck client looking up DERP hostnames with *dns_resolver*.
+    pub async fn with_dns_resolver(
+        port_mapper: Option<portmapper::Client>,
+        dns_resolver: DnsResolver,
+    ) -> Result<Self> {
+        let mut actor = Actor::new(port_mapper, dns_resolver)?;
         let addr = actor.addr();
         let task =
             tokio::spawn(async move { actor.run().await }.instrument(info_span!("netcheck.actor")));
@@ -383,6 +392,8 @@ struct Actor {
     /// The port mapper is responsible for talking to routers via UPnP and the like to try
     /// and open ports.
     port_mapper: Option<portmapper::Client>,
+    /// Resolver for the hostnames of DERP nodes.
+    dns_resolver: DnsResolver,
 
     // Actor state.
     /// Information about the currently in-flight STUN requests.
@@ -398,7 +409,7 @@ impl Actor {
     ///
     /// This does not start the actor, see [`Actor::run`] for this.  You should not
     /// normally create this directly but rather create a [`Client`].
-    fn new(port_mapper: Option<portmapper::Client>) -> Result<Self> {
+    fn new(port_mapper: Option<portmapper::Client>, dns_resolver: DnsResolver) -> Result<Self> {
         // TODO: consider an instrumented flume channel so we have metrics.
         let (sender, receiver) = mpsc::channel(32);
         Ok(Self {
@@ -407,6 +418,7 @@ impl Actor {
             reports: Default::default(),
             skip_external_network: false,
             port_mapper,
+            dns_resolver,
             in_flight_stun_requests: Default::default(),
             current_report_run: None,
         })
@@ -526,6 +538,7 @@ impl Actor {
             derp_map,
             stun_sock_v4,
             stun_sock_v6,
+            self.dns_resolver.clone(),
         );
 
         self.current_report_run = Some(ReportRun {
@@ -1149,7 +1162,7 @@ mod tests {
         ];
         for mut tt in tests {
             println!("test: {}", tt.name);
-            let mut actor = Actor::new(None).unwrap();
+            let mut actor = Actor::new(None, DnsResolver::default()).unwrap();
             for s in &mut tt.steps {
                 // trigger the timer
                 time::advance(Duration::from_secs(s.after)).await;
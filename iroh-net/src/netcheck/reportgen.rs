@@ -37,7 +37,7 @@ use tracing::{
 use super::NetcheckMetrics;
 use crate::defaults::DEFAULT_DERP_STUN_PORT;
 use crate::derp::{DerpMap, DerpNode, DerpRegion, UseIpv4, UseIpv6};
-use crate::dns::DNS_RESOLVER;
+use crate::dns::DnsResolver;
 use crate::net::interfaces;
 use crate::net::ip;
 use crate::netcheck::{self, Report};
@@ -93,6 +93,7 @@ impl Client {
         derp_map: DerpMap,
         stun_sock4: Option<Arc<UdpSocket>>,
         stun_sock6: Option<Arc<UdpSocket>>,
+        dns_resolver: DnsResolver,
     ) -> Self {
         let (msg_tx, msg_rx) = mpsc::channel(32);
         let addr = Addr {
@@ -110,6 +111,7 @@ impl Client {
             derp_map,
             stun_sock4,
             stun_sock6,
+            dns_resolver,
             report: Report::default(),
             hairpin_actor: hairpin::Client::new(netcheck, addr),
             outstanding_tasks: OutstandingTasks::default(),
@@ -178,6 +180,8 @@ struct Actor {
     stun_sock4: Option<Arc<UdpSocket>>,
     /// Socket so send IPv6 STUN requests from.
     stun_sock6: Option<Arc<UdpSocket>>,
+    /// Resolver for the hostnames of DERP nodes.
+    dns_resolver: DnsResolver,
 
     // Internal state.
     /// Whether we're doing an incremental report.
@@ -600,6 +604,7 @@ impl Actor {
                 let probe = probe.clone();
                 let netcheck = self.netcheck.clone();
                 let pinger = pinger.clone();
+                let dns_resolver = self.dns_resolver.clone();
 
                 set.push(Box::pin(async move {
                     run_probe(
@@ -610,6 +615,7 @@ impl Actor {
                         probe,
                         netcheck,
                         pinger,
+                        dns_resolver,
                     )
                     .await
                 }));
@@ -720,6 +726,7 @@ async fn run_probe(
     probe: Probe,
     netcheck: netcheck::Addr,
     pinger: Option<Pinger>,
+    dns_resolver: DnsResolver,
 ) -> Result<ProbeReport, ProbeError> {
     if !probe.delay().is_zero() {
         trace!("delaying probe");
@@ -752,7 +759,7 @@ async fn run_probe(
         ));
     }
 
-    let derp_addr = get_derp_addr(&derp_node, probe.proto())
+    let derp_addr = get_derp_addr(&dns_resolver, &derp_node, probe.proto())
         .await
         .context("no derp node addr")
         .map_err(|e| ProbeError::AbortSet(e, probe.clone()))?;
@@ -946,7 +953,11 @@ async fn check_captive_portal(dm: &DerpMap, preferred_derp: Option<u16>) -> Resu
 /// Returns the IP address to use to communicate to this derp node.
 ///
 /// *proto* specifies the protocol we want to use to talk to the node.
-async fn get_derp_addr(n: &DerpNode, proto: ProbeProto) -> Result<SocketAddr> {
+async fn get_derp_addr(
+    dns_resolver: &DnsResolver,
+    n: &DerpNode,
+    proto: ProbeProto,
+) -> Result<SocketAddr> {
     let mut port = n.stun_port;
     if port == 0 {
         port = DEFAULT_DERP_STUN_PORT;
@@ -987,19 +998,18 @@ async fn get_derp_addr(n: &DerpNode, proto: ProbeProto) -> Result<SocketAddr> {
             async move {
                 debug!(?proto, %hostname, "Performing DNS lookup for derp addr");
 
-                if let Ok(addrs) = DNS_RESOLVER.lookup_ip(hostname).await {
-                    for addr in addrs {
-                        let addr = ip::to_canonical(addr);
-                        if addr.is_ipv4() && proto == ProbeProto::StunIpv4 {
-                            return Ok(SocketAddr::new(addr, port));
-                        }
-                        if addr.is_ipv6() && proto == ProbeProto::StunIpv6 {
-                            return Ok(SocketAddr::new(addr, port));
-                        }
-                        if proto == ProbeProto::Https {
-                            // For now just return the first one
-                            return Ok(SocketAddr::new(addr, port));
-                        }
+                let addrs = dns_resolver.lookup_ip(hostname).await?;
+                for addr in addrs {
+                    let addr = ip::to_canonical(addr);
+                    if addr.is_ipv4() && proto == ProbeProto::StunIpv4 {
+                        return Ok(SocketAddr::new(addr, port));
+                    }
+                    if addr.is_ipv6() && proto == ProbeProto::StunIpv6 {
+                        return Ok(SocketAddr::new(addr, port));
+                    }
+                    if proto == ProbeProto::Https {
+                        // For now just return the first one
+                        return Ok(SocketAddr::new(addr, port));
                     }
                 }
                 Err(anyhow!("no suitable addr found for derp config"))
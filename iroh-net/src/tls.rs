@@ -3,15 +3,19 @@
 //! See <https://github.com/libp2p/specs/blob/master/tls/tls.md>.
 //! Based on rust-libp2p/transports/tls
 
+mod alpn;
 pub mod certificate;
+mod ticketer;
 mod verifier;
 
 use std::{
+    collections::{HashMap, HashSet},
     fmt::{Debug, Display},
     str::FromStr,
     sync::Arc,
 };
 
+pub use self::ticketer::{RotatingTicketer, DEFAULT_ROTATION_INTERVAL};
 pub use ed25519_dalek::{Signature, SigningKey as SecretKey, VerifyingKey as PublicKey};
 use serde::{Deserialize, Serialize};
 use ssh_key::LineEnding;
@@ -68,7 +72,8 @@ impl Keypair {
         }
     }
 
-    fn sign(&self, msg: &[u8]) -> Signature {
+    /// Sign a message with the secret key of this keypair.
+    pub fn sign(&self, msg: &[u8]) -> Signature {
         use ed25519_dalek::Signer;
 
         self.secret.sign(msg)
@@ -202,11 +207,14 @@ pub fn make_client_config(
 
 /// Create a TLS server configuration.
 ///
+/// If *allowed_peers* is set, only clients with one of these peer ids can connect.
+///
 /// If *keylog* is `true` this will enable logging of the pre-master key to the file in the
 /// `SSLKEYLOGFILE` environment variable.  This can be used to inspect the traffic for
 /// debugging purposes.
 pub fn make_server_config(
     keypair: &Keypair,
+    allowed_peers: Option<Arc<HashSet<PeerId>>>,
     alpn_protocols: Vec<Vec<u8>>,
     keylog: bool,
 ) -> Result<rustls::ServerConfig, certificate::GenError> {
@@ -217,7 +225,9 @@ pub fn make_server_config(
         .with_safe_default_kx_groups()
         .with_protocol_versions(verifier::PROTOCOL_VERSIONS)
         .expect("Cipher suites and kx groups are configured; qed")
-        .with_client_cert_verifier(Arc::new(verifier::Libp2pCertificateVerifier::new()))
+        .with_client_cert_verifier(Arc::new(
+            verifier::Libp2pCertificateVerifier::with_allowed_peers(allowed_peers),
+        ))
         .with_single_cert(vec![certificate], private_key)
         .expect("Server cert key DER is valid; qed");
     crypto.alpn_protocols = alpn_protocols;
@@ -227,6 +237,136 @@ pub fn make_server_config(
     Ok(crypto)
 }
 
+/// Customizations of the server configuration for a single ALPN.
+///
+/// See [`ServerConfigBuilder::alpn_override`].
+#[derive(Clone, Default)]
+pub struct AlpnOverride {
+    /// Only accept clients with these peer ids on this ALPN.
+    ///
+    /// If `None`, the allowed peers of the [`ServerConfigBuilder`] apply.
+    pub allowed_peers: Option<HashSet<PeerId>>,
+    /// Further changes to the rustls config used for this ALPN.
+    #[allow(clippy::type_complexity)]
+    pub configure: Option<Arc<dyn Fn(&mut rustls::ServerConfig) + Send + Sync + 'static>>,
+}
+
+impl Debug for AlpnOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlpnOverride")
+            .field("allowed_peers", &self.allowed_peers)
+            .field("configure", &self.configure.as_ref().map(|_| "Fn"))
+            .finish()
+    }
+}
+
+/// Builder for the crypto configuration of a QUIC server.
+///
+/// All configurations share the certificate of the keypair and the libp2p certificate
+/// verification. Connections for an ALPN with an [`AlpnOverride`] use their own rustls
+/// config, selected when the client hello is received.
+#[derive(Debug)]
+pub struct ServerConfigBuilder {
+    keypair: Keypair,
+    alpn_protocols: Vec<Vec<u8>>,
+    allowed_peers: Option<HashSet<PeerId>>,
+    ticketer: Option<Arc<RotatingTicketer>>,
+    keylog: bool,
+    overrides: HashMap<Vec<u8>, AlpnOverride>,
+}
+
+impl ServerConfigBuilder {
+    /// Create a new builder for a server authenticating with `keypair`.
+    pub fn new(keypair: Keypair) -> Self {
+        Self {
+            keypair,
+            alpn_protocols: Vec::new(),
+            allowed_peers: None,
+            ticketer: None,
+            keylog: false,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Set the ALPN protocols the server accepts, in order of preference.
+    pub fn alpns(mut self, alpn_protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = alpn_protocols;
+        self
+    }
+
+    /// Only accept clients with one of these peer ids.
+    pub fn allowed_peers(mut self, allowed_peers: Option<HashSet<PeerId>>) -> Self {
+        self.allowed_peers = allowed_peers;
+        self
+    }
+
+    /// Issue session tickets encrypted by `ticketer`.
+    pub fn session_ticketer(mut self, ticketer: Option<Arc<RotatingTicketer>>) -> Self {
+        self.ticketer = ticketer;
+        self
+    }
+
+    /// Log the pre-master keys, see [`make_server_config`].
+    pub fn keylog(mut self, keylog: bool) -> Self {
+        self.keylog = keylog;
+        self
+    }
+
+    /// Customize the configuration for connections negotiating `alpn`.
+    ///
+    /// `alpn` must be one of the protocols set with [`Self::alpns`].
+    pub fn alpn_override(mut self, alpn: Vec<u8>, alpn_override: AlpnOverride) -> Self {
+        self.overrides.insert(alpn, alpn_override);
+        self
+    }
+
+    /// Build the crypto configuration.
+    pub fn build(
+        self,
+    ) -> Result<Arc<dyn quinn_proto::crypto::ServerConfig>, certificate::GenError> {
+        // tickets are scoped to the ALPN they were issued for, a session resumed from a
+        // ticket skips the client certificate verification of the config it resumes on
+        let make = |allowed_peers: Option<HashSet<PeerId>>, alpn: Option<&Vec<u8>>| {
+            let mut config = make_server_config(
+                &self.keypair,
+                allowed_peers.map(Arc::new),
+                self.alpn_protocols.clone(),
+                self.keylog,
+            )?;
+            if let Some(ticketer) = &self.ticketer {
+                config.ticketer = match alpn {
+                    Some(alpn) => Arc::new(ticketer::ScopedTicketer::new(
+                        ticketer.clone(),
+                        alpn.clone(),
+                    )),
+                    None => ticketer.clone(),
+                };
+            }
+            Ok::<_, certificate::GenError>(config)
+        };
+        let default = make(self.allowed_peers.clone(), None)?;
+        if self.overrides.is_empty() {
+            return Ok(Arc::new(default));
+        }
+        let mut overrides = HashMap::new();
+        for (alpn, alpn_override) in &self.overrides {
+            let allowed_peers = alpn_override
+                .allowed_peers
+                .clone()
+                .or_else(|| self.allowed_peers.clone());
+            let mut config = make(allowed_peers, Some(alpn))?;
+            if let Some(configure) = &alpn_override.configure {
+                configure(&mut config);
+            }
+            overrides.insert(alpn.clone(), Arc::new(config));
+        }
+        Ok(Arc::new(alpn::AlpnServerConfig {
+            default: Arc::new(default),
+            overrides,
+        }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -238,4 +378,92 @@ mod tests {
         let de = Keypair::try_from_openssh(&ser).unwrap();
         assert_eq!(kp.to_bytes(), de.to_bytes());
     }
+
+    /// Run a TLS handshake between the two configs in memory.
+    fn handshake(
+        client: rustls::ClientConfig,
+        server: rustls::ServerConfig,
+    ) -> Result<(), rustls::Error> {
+        let server_name = "localhost".try_into().unwrap();
+        let mut client = rustls::ClientConnection::new(Arc::new(client), server_name)?;
+        let mut server = rustls::ServerConnection::new(Arc::new(server))?;
+        let mut buf = Vec::new();
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+            buf.clear();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets()?;
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets()?;
+        }
+        panic!("handshake did not complete");
+    }
+
+    #[test]
+    fn test_allowed_peers() {
+        let server_kp = Keypair::generate();
+        let allowed_kp = Keypair::generate();
+        let other_kp = Keypair::generate();
+        let alpn = vec![b"test".to_vec()];
+        let allowed_peers = Arc::new(HashSet::from([PeerId::from(allowed_kp.public())]));
+        let server_id = Some(PeerId::from(server_kp.public()));
+
+        let server =
+            make_server_config(&server_kp, Some(allowed_peers), alpn.clone(), false).unwrap();
+        let client = make_client_config(&allowed_kp, server_id, alpn.clone(), false).unwrap();
+        handshake(client, server.clone()).unwrap();
+
+        let client = make_client_config(&other_kp, server_id, alpn, false).unwrap();
+        assert!(handshake(client, server).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_alpn_override() {
+        let server_kp = Keypair::generate();
+        let admin_kp = Keypair::generate();
+        let other_kp = Keypair::generate();
+        let crypto = ServerConfigBuilder::new(server_kp.clone())
+            .alpns(vec![b"admin".to_vec(), b"data".to_vec()])
+            .alpn_override(
+                b"admin".to_vec(),
+                AlpnOverride {
+                    allowed_peers: Some(HashSet::from([PeerId::from(admin_kp.public())])),
+                    configure: None,
+                },
+            )
+            .build()
+            .unwrap();
+        let server = quinn::Endpoint::server(
+            quinn::ServerConfig::with_crypto(crypto),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let server_id = Some(PeerId::from(server_kp.public()));
+
+        // returns whether the server accepted the connection
+        let try_connect = |keypair: &Keypair, alpn: &[u8]| {
+            let config = make_client_config(keypair, server_id, vec![alpn.to_vec()], false);
+            let client_config = quinn::ClientConfig::new(Arc::new(config.unwrap()));
+            let client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            let connecting = client
+                .connect_with(client_config, server_addr, "localhost")
+                .unwrap();
+            let server = server.clone();
+            async move {
+                let _client = client;
+                let accept = async { server.accept().await.unwrap().await };
+                let (_conn, accepted) = tokio::join!(connecting, accept);
+                accepted.is_ok()
+            }
+        };
+        assert!(try_connect(&admin_kp, b"admin").await);
+        assert!(try_connect(&other_kp, b"data").await);
+        assert!(!try_connect(&other_kp, b"admin").await);
+    }
 }
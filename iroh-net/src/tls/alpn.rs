@@ -0,0 +1,338 @@
+//! Per-ALPN server configurations.
+//!
+//! A QUIC endpoint has a single crypto config, but the rustls config that should be
+//! used for a connection can depend on the application protocol, e.g. to only accept
+//! a few pinned peers on an admin protocol. The ALPN is only known once the client
+//! hello is received, so the sessions created here buffer the handshake data until the
+//! client hello is complete, and then start the actual session with the config for the
+//! negotiated ALPN.
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use quinn_proto::crypto::{
+    self, ExportKeyingMaterialError, HeaderKey, KeyPair, Keys, PacketKey, UnsupportedVersion,
+};
+use quinn_proto::transport_parameters::TransportParameters;
+use quinn_proto::{ConnectionId, Side, TransportError, TransportErrorCode};
+
+/// TLS handshake message type of the client hello.
+const CLIENT_HELLO: u8 = 1;
+/// TLS extension type of the ALPN extension.
+const ALPN_EXTENSION: u16 = 16;
+/// Maximum size of the client hello that is buffered before a session is started.
+///
+/// Client hellos are usually well below 2 KiB, this leaves room for large key shares.
+const MAX_CLIENT_HELLO_LEN: usize = 8 * 1024;
+
+/// A crypto config that picks the rustls config by the negotiated ALPN.
+pub(super) struct AlpnServerConfig {
+    pub(super) default: Arc<rustls::ServerConfig>,
+    pub(super) overrides: HashMap<Vec<u8>, Arc<rustls::ServerConfig>>,
+}
+
+impl AlpnServerConfig {
+    /// The config for the protocol the server will negotiate with a client offering
+    /// `offered`.
+    fn select(&self, offered: &[Vec<u8>]) -> Arc<rustls::ServerConfig> {
+        // rustls picks the first of the server's protocols that the client offers
+        self.default
+            .alpn_protocols
+            .iter()
+            .find(|alpn| offered.contains(alpn))
+            .and_then(|alpn| self.overrides.get(alpn))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl crypto::ServerConfig for AlpnServerConfig {
+    fn initial_keys(
+        &self,
+        version: u32,
+        dst_cid: &ConnectionId,
+        side: Side,
+    ) -> Result<Keys, UnsupportedVersion> {
+        crypto::ServerConfig::initial_keys(&*self.default, version, dst_cid, side)
+    }
+
+    fn retry_tag(&self, version: u32, orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; 16] {
+        crypto::ServerConfig::retry_tag(&*self.default, version, orig_dst_cid, packet)
+    }
+
+    fn start_session(
+        self: Arc<Self>,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Box<dyn crypto::Session> {
+        Box::new(AlpnSession::Pending {
+            config: self,
+            version,
+            params: *params,
+            buf: Vec::new(),
+        })
+    }
+}
+
+enum AlpnSession {
+    /// Waiting for the complete client hello.
+    Pending {
+        config: Arc<AlpnServerConfig>,
+        version: u32,
+        params: TransportParameters,
+        buf: Vec<u8>,
+    },
+    Started(Box<dyn crypto::Session>),
+}
+
+impl AlpnSession {
+    fn started(&self) -> Option<&dyn crypto::Session> {
+        match self {
+            AlpnSession::Started(inner) => Some(&**inner),
+            AlpnSession::Pending { .. } => None,
+        }
+    }
+
+    fn started_mut(&mut self) -> Option<&mut Box<dyn crypto::Session>> {
+        match self {
+            AlpnSession::Started(inner) => Some(inner),
+            AlpnSession::Pending { .. } => None,
+        }
+    }
+}
+
+impl crypto::Session for AlpnSession {
+    fn initial_keys(&self, dst_cid: &ConnectionId, side: Side) -> Keys {
+        match self {
+            AlpnSession::Started(inner) => inner.initial_keys(dst_cid, side),
+            AlpnSession::Pending {
+                config, version, ..
+            } => crypto::ServerConfig::initial_keys(&**config, *version, dst_cid, side)
+                .expect("version checked when the session was started"),
+        }
+    }
+
+    fn handshake_data(&self) -> Option<Box<dyn Any>> {
+        self.started()?.handshake_data()
+    }
+
+    fn peer_identity(&self) -> Option<Box<dyn Any>> {
+        self.started()?.peer_identity()
+    }
+
+    fn early_crypto(&self) -> Option<(Box<dyn HeaderKey>, Box<dyn PacketKey>)> {
+        self.started()?.early_crypto()
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        self.started()?.early_data_accepted()
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.started().map_or(true, |inner| inner.is_handshaking())
+    }
+
+    fn read_handshake(&mut self, data: &[u8]) -> Result<bool, TransportError> {
+        let (inner, res) = match self {
+            AlpnSession::Started(inner) => return inner.read_handshake(data),
+            AlpnSession::Pending {
+                config,
+                version,
+                params,
+                buf,
+            } => {
+                if buf.len() + data.len() > MAX_CLIENT_HELLO_LEN {
+                    return Err(TransportError {
+                        code: TransportErrorCode::CRYPTO_BUFFER_EXCEEDED,
+                        frame: None,
+                        reason: "client hello too large".to_string(),
+                    });
+                }
+                buf.extend_from_slice(data);
+                let offered = match parse_client_hello_alpns(buf) {
+                    ClientHello::Incomplete => return Ok(false),
+                    ClientHello::Alpns(offered) => offered,
+                    // let rustls produce a proper error
+                    ClientHello::Invalid => Vec::new(),
+                };
+                let selected = config.select(&offered);
+                let mut inner = crypto::ServerConfig::start_session(selected, *version, params);
+                let res = inner.read_handshake(buf);
+                (inner, res)
+            }
+        };
+        *self = AlpnSession::Started(inner);
+        res
+    }
+
+    fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
+        match self.started() {
+            Some(inner) => inner.transport_parameters(),
+            None => Ok(None),
+        }
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Keys> {
+        self.started_mut()?.write_handshake(buf)
+    }
+
+    fn next_1rtt_keys(&mut self) -> Option<KeyPair<Box<dyn PacketKey>>> {
+        self.started_mut()?.next_1rtt_keys()
+    }
+
+    fn is_valid_retry(&self, orig_dst_cid: &ConnectionId, header: &[u8], payload: &[u8]) -> bool {
+        self.started().map_or(false, |inner| {
+            inner.is_valid_retry(orig_dst_cid, header, payload)
+        })
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        match self.started() {
+            Some(inner) => inner.export_keying_material(output, label, context),
+            None => Err(ExportKeyingMaterialError),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ClientHello {
+    /// More data is needed.
+    Incomplete,
+    /// The data is not a client hello.
+    Invalid,
+    /// The protocols offered by the client, in its order of preference.
+    Alpns(Vec<Vec<u8>>),
+}
+
+/// Extract the offered ALPNs from a TLS client hello handshake message.
+fn parse_client_hello_alpns(data: &[u8]) -> ClientHello {
+    if data.len() < 4 {
+        return ClientHello::Incomplete;
+    }
+    if data[0] != CLIENT_HELLO {
+        return ClientHello::Invalid;
+    }
+    let len = u32::from_be_bytes([0, data[1], data[2], data[3]]) as usize;
+    let Some(body) = data[4..].get(..len) else {
+        return ClientHello::Incomplete;
+    };
+    match parse_client_hello_body(body) {
+        Some(alpns) => ClientHello::Alpns(alpns),
+        None => ClientHello::Invalid,
+    }
+}
+
+fn parse_client_hello_body(body: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut reader = Reader(body);
+    // legacy version and random
+    reader.take(2 + 32)?;
+    // legacy session id
+    let n = reader.u8()? as usize;
+    reader.take(n)?;
+    // cipher suites
+    let n = reader.u16()? as usize;
+    reader.take(n)?;
+    // legacy compression methods
+    let n = reader.u8()? as usize;
+    reader.take(n)?;
+    let n = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(n)?);
+    while !extensions.0.is_empty() {
+        let typ = extensions.u16()?;
+        let n = extensions.u16()? as usize;
+        let data = extensions.take(n)?;
+        if typ == ALPN_EXTENSION {
+            let mut ext = Reader(data);
+            let n = ext.u16()? as usize;
+            let mut list = Reader(ext.take(n)?);
+            let mut alpns = Vec::new();
+            while !list.0.is_empty() {
+                let n = list.u8()? as usize;
+                alpns.push(list.take(n)?.to_vec());
+            }
+            return Some(alpns);
+        }
+    }
+    Some(Vec::new())
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(alpns: &[&[u8]]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for alpn in alpns {
+            list.push(alpn.len() as u8);
+            list.extend_from_slice(alpn);
+        }
+        let mut ext = Vec::new();
+        ext.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        ext.extend_from_slice(&list);
+        let mut extensions = Vec::new();
+        // an unrelated extension first
+        extensions.extend_from_slice(&[0, 43, 0, 3, 2, 3, 4]);
+        extensions.extend_from_slice(&ALPN_EXTENSION.to_be_bytes());
+        extensions.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&ext);
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[7u8; 32]);
+        body.extend_from_slice(&[0]);
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut msg = vec![CLIENT_HELLO];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        msg.extend_from_slice(&body);
+        msg
+    }
+
+    #[test]
+    fn parse_alpns() {
+        let hello = client_hello(&[b"admin", b"data"]);
+        assert_eq!(
+            parse_client_hello_alpns(&hello),
+            ClientHello::Alpns(vec![b"admin".to_vec(), b"data".to_vec()])
+        );
+        for i in 0..hello.len() {
+            assert_eq!(
+                parse_client_hello_alpns(&hello[..i]),
+                ClientHello::Incomplete
+            );
+        }
+        assert_eq!(
+            parse_client_hello_alpns(&[2, 0, 0, 0]),
+            ClientHello::Invalid
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! Session ticket encryption with key rotation.
+//!
+//! Session tickets let clients resume a TLS session without a full handshake. The
+//! tickets are encrypted with a key only the server knows, so anyone who obtains that
+//! key can decrypt every ticket issued with it. [`RotatingTicketer`] bounds this window
+//! by regularly replacing the key. The previous key is kept for one more interval, so
+//! tickets issued shortly before a rotation can still be used.
+//!
+//! Servers with several rustls configs share one ticketer through [`ScopedTicketer`]s, so
+//! a ticket issued under one config can not resume a session under another one.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use rustls::server::ProducesTickets;
+
+/// Default interval after which the ticket key is rotated.
+pub const DEFAULT_ROTATION_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Length of the key id prefixed to every ticket.
+const KEY_ID_LEN: usize = 4;
+
+struct TicketKey {
+    id: [u8; KEY_ID_LEN],
+    key: LessSafeKey,
+}
+
+impl TicketKey {
+    fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let secret: [u8; 32] = rng.gen();
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &secret).expect("valid key length");
+        Self {
+            id: rng.gen(),
+            key: LessSafeKey::new(key),
+        }
+    }
+
+    fn encrypt(&self, plain: &[u8], scope: &[u8]) -> Option<Vec<u8>> {
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let mut ticket =
+            Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + plain.len() + CHACHA20_POLY1305.tag_len());
+        ticket.extend_from_slice(&self.id);
+        ticket.extend_from_slice(&nonce);
+        let mut sealed = plain.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(scope),
+                &mut sealed,
+            )
+            .ok()?;
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, nonce: &[u8], sealed: &[u8], scope: &[u8]) -> Option<Vec<u8>> {
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut buf = sealed.to_vec();
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from(scope), &mut buf)
+            .ok()?;
+        let len = plain.len();
+        buf.truncate(len);
+        Some(buf)
+    }
+}
+
+struct Keys {
+    current: TicketKey,
+    previous: Option<TicketKey>,
+    rotated_at: Instant,
+}
+
+/// A [`ProducesTickets`] implementation that rotates its encryption key on a schedule.
+///
+/// Use it as the `ticketer` of a [`rustls::ServerConfig`] to enable session tickets.
+/// Keys are rotated lazily, when a ticket is encrypted or decrypted after the rotation
+/// interval has passed. Tickets stay valid for at most two rotation intervals.
+pub struct RotatingTicketer {
+    interval: Duration,
+    keys: Mutex<Keys>,
+}
+
+impl std::fmt::Debug for RotatingTicketer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RotatingTicketer")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for RotatingTicketer {
+    fn default() -> Self {
+        Self::new(DEFAULT_ROTATION_INTERVAL)
+    }
+}
+
+impl RotatingTicketer {
+    /// Create a new ticketer that rotates its key every `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            keys: Mutex::new(Keys {
+                current: TicketKey::generate(),
+                previous: None,
+                rotated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Rotate the key now.
+    ///
+    /// Tickets issued with the current key remain valid until the next rotation.
+    pub fn rotate(&self) {
+        let mut keys = self.keys.lock().unwrap();
+        Self::rotate_keys(&mut keys, Instant::now());
+    }
+
+    /// Replace all keys, invalidating every ticket issued so far.
+    ///
+    /// Use this if a key might have been compromised.
+    pub fn revoke(&self) {
+        let mut keys = self.keys.lock().unwrap();
+        keys.current = TicketKey::generate();
+        keys.previous = None;
+        keys.rotated_at = Instant::now();
+    }
+
+    fn rotate_keys(keys: &mut Keys, now: Instant) {
+        let previous = std::mem::replace(&mut keys.current, TicketKey::generate());
+        keys.previous = Some(previous);
+        keys.rotated_at = now;
+    }
+
+    fn keys_at(&self, now: Instant) -> std::sync::MutexGuard<'_, Keys> {
+        let mut keys = self.keys.lock().unwrap();
+        let elapsed = now.saturating_duration_since(keys.rotated_at);
+        if elapsed >= self.interval * 2 {
+            // idle for longer than the overlap, the previous key has expired as well
+            keys.current = TicketKey::generate();
+            keys.previous = None;
+            keys.rotated_at = now;
+        } else if elapsed >= self.interval {
+            Self::rotate_keys(&mut keys, now);
+        }
+        keys
+    }
+
+    fn decrypt_at(&self, cipher: &[u8], now: Instant) -> Option<Vec<u8>> {
+        self.decrypt_scoped(cipher, &[], now)
+    }
+
+    fn encrypt_scoped(&self, plain: &[u8], scope: &[u8]) -> Option<Vec<u8>> {
+        self.keys_at(Instant::now()).current.encrypt(plain, scope)
+    }
+
+    fn decrypt_scoped(&self, cipher: &[u8], scope: &[u8], now: Instant) -> Option<Vec<u8>> {
+        if cipher.len() < KEY_ID_LEN + NONCE_LEN {
+            return None;
+        }
+        let (id, rest) = cipher.split_at(KEY_ID_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let keys = self.keys_at(now);
+        if keys.current.id == id {
+            return keys.current.decrypt(nonce, sealed, scope);
+        }
+        match &keys.previous {
+            Some(previous) if previous.id == id => previous.decrypt(nonce, sealed, scope),
+            _ => None,
+        }
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.interval.as_secs().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.encrypt_scoped(plain, &[])
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.decrypt_at(cipher, Instant::now())
+    }
+}
+
+/// A [`RotatingTicketer`] that only accepts the tickets it issued itself.
+///
+/// The scope is authenticated with every ticket, so tickets of other scopes of the same
+/// ticketer fail to decrypt. Rotating or revoking the keys of the ticketer affects all
+/// scopes.
+#[derive(Debug)]
+pub(super) struct ScopedTicketer {
+    inner: Arc<RotatingTicketer>,
+    scope: Vec<u8>,
+}
+
+impl ScopedTicketer {
+    pub(super) fn new(inner: Arc<RotatingTicketer>, scope: Vec<u8>) -> Self {
+        Self { inner, scope }
+    }
+}
+
+impl ProducesTickets for ScopedTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.inner.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.inner.encrypt_scoped(plain, &self.scope)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.inner
+            .decrypt_scoped(cipher, &self.scope, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let ticketer = RotatingTicketer::default();
+        let ticket = ticketer.encrypt(b"hello world").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"hello world");
+
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ticketer.decrypt(&tampered).is_none());
+        assert!(ticketer.decrypt(&ticket[..10]).is_none());
+    }
+
+    #[test]
+    fn rotation() {
+        let interval = Duration::from_secs(60);
+        let ticketer = RotatingTicketer::new(interval);
+        let ticket = ticketer.encrypt(b"resume").unwrap();
+
+        // forced rotation keeps the previous key
+        ticketer.rotate();
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"resume");
+        ticketer.rotate();
+        assert!(ticketer.decrypt(&ticket).is_none());
+
+        // scheduled rotation
+        ticketer.revoke();
+        let start = ticketer.keys.lock().unwrap().rotated_at;
+        let ticket = ticketer.encrypt(b"resume").unwrap();
+        let later = start + interval + Duration::from_secs(1);
+        assert_eq!(ticketer.decrypt_at(&ticket, later).unwrap(), b"resume");
+        let much_later = later + interval;
+        assert!(ticketer.decrypt_at(&ticket, much_later).is_none());
+
+        // revocation drops all keys
+        let ticket = ticketer.encrypt(b"resume").unwrap();
+        ticketer.revoke();
+        assert!(ticketer.decrypt(&ticket).is_none());
+    }
+
+    #[test]
+    fn scopes() {
+        let ticketer = Arc::new(RotatingTicketer::default());
+        let admin = ScopedTicketer::new(ticketer.clone(), b"admin".to_vec());
+        let other = ScopedTicketer::new(ticketer.clone(), b"other".to_vec());
+        let ticket = admin.encrypt(b"resume").unwrap();
+        assert_eq!(admin.decrypt(&ticket).unwrap(), b"resume");
+        assert!(other.decrypt(&ticket).is_none());
+        assert!(ticketer.decrypt(&ticket).is_none());
+
+        let ticket = ticketer.encrypt(b"resume").unwrap();
+        assert!(admin.decrypt(&ticket).is_none());
+
+        // the keys are shared
+        let ticket = admin.encrypt(b"resume").unwrap();
+        ticketer.revoke();
+        assert!(admin.decrypt(&ticket).is_none());
+    }
+}
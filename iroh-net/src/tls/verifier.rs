@@ -5,6 +5,7 @@
 //!
 //! Based on rust-libp2p/transports/tls/src/verifier.rs originally licensed under MIT by Parity
 //! Technologies (UK) Ltd.
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use super::{certificate, PeerId};
@@ -42,6 +43,8 @@ pub static CIPHERSUITES: &[SupportedCipherSuite] = &[
 pub struct Libp2pCertificateVerifier {
     /// The peer ID we intend to connect to
     remote_peer_id: Option<PeerId>,
+    /// The peer IDs of the clients we accept. If `None`, all clients are accepted.
+    allowed_peers: Option<Arc<HashSet<PeerId>>>,
 }
 
 /// libp2p requires the following of X.509 server certificate chains:
@@ -51,13 +54,17 @@ pub struct Libp2pCertificateVerifier {
 /// - The certificate must have a valid libp2p extension that includes a
 ///   signature of its public key.
 impl Libp2pCertificateVerifier {
-    pub fn new() -> Self {
+    pub fn with_remote_peer_id(remote_peer_id: Option<PeerId>) -> Self {
         Self {
-            remote_peer_id: None,
+            remote_peer_id,
+            allowed_peers: None,
         }
     }
-    pub fn with_remote_peer_id(remote_peer_id: Option<PeerId>) -> Self {
-        Self { remote_peer_id }
+    pub fn with_allowed_peers(allowed_peers: Option<Arc<HashSet<PeerId>>>) -> Self {
+        Self {
+            remote_peer_id: None,
+            allowed_peers,
+        }
     }
 
     /// Return the list of SignatureSchemes that this verifier will handle,
@@ -148,7 +155,17 @@ impl ClientCertVerifier for Libp2pCertificateVerifier {
         intermediates: &[Certificate],
         _now: std::time::SystemTime,
     ) -> Result<ClientCertVerified, rustls::Error> {
-        verify_presented_certs(end_entity, intermediates)?;
+        let peer_id = verify_presented_certs(end_entity, intermediates)?;
+
+        if let Some(ref allowed_peers) = self.allowed_peers {
+            // Only pinned peers may connect, everyone else is rejected before the
+            // connection is established.
+            if !allowed_peers.contains(&peer_id) {
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+        }
 
         Ok(ClientCertVerified::assertion())
     }
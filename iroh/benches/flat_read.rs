@@ -0,0 +1,88 @@
+//! Concurrent chunk reads from the flat store, with the default and the io_uring backend.
+//!
+//! Run with `cargo bench --bench flat_read --features io-uring` to include io_uring.
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use iroh::baomap::{flat, uring::IoBackend};
+use iroh_bytes::baomap::{Map, MapEntry, Store};
+use iroh_bytes::util::runtime;
+use iroh_io::AsyncSliceReader;
+use rand::Rng;
+
+const BLOBS: usize = 16;
+const BLOB_SIZE: usize = 1024 * 1024 * 4;
+const CHUNK_SIZE: usize = 1024 * 16;
+const READS_PER_TASK: usize = 64;
+
+fn concurrent_reads(c: &mut Criterion) {
+    let tokio = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let (db, hashes) = tokio.block_on(async {
+        let rt = runtime::Handle::from_currrent(1).unwrap();
+        let db = flat::Store::load(dir.path(), dir.path(), &rt)
+            .await
+            .unwrap();
+        let mut hashes = Vec::new();
+        for i in 0..BLOBS {
+            let data = vec![i as u8; BLOB_SIZE];
+            hashes.push(db.import_bytes(data.into()).await.unwrap());
+        }
+        (db, Arc::new(hashes))
+    });
+
+    let mut group = c.benchmark_group("flat_read");
+    for backend in [IoBackend::Std, IoBackend::Uring] {
+        if db.set_io_backend(backend) != backend {
+            eprintln!("skipping {backend}, not available");
+            continue;
+        }
+        for tasks in [1, 16, 128] {
+            group.throughput(Throughput::Bytes(
+                (tasks * READS_PER_TASK * CHUNK_SIZE) as u64,
+            ));
+            group.bench_with_input(
+                BenchmarkId::new(backend.to_string(), tasks),
+                &tasks,
+                |b, &tasks| {
+                    b.to_async(&tokio)
+                        .iter(|| read_chunks(db.clone(), hashes.clone(), tasks))
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+/// Read random chunks of random blobs from `tasks` tasks at the same time.
+async fn read_chunks(db: flat::Store, hashes: Arc<Vec<iroh_bytes::Hash>>, tasks: usize) {
+    let handles = (0..tasks)
+        .map(|_| {
+            let db = db.clone();
+            let hashes = hashes.clone();
+            tokio::spawn(async move {
+                for _ in 0..READS_PER_TASK {
+                    let (hash, offset) = {
+                        let mut rng = rand::thread_rng();
+                        let hash = hashes[rng.gen_range(0..hashes.len())];
+                        let offset = rng.gen_range(0..BLOB_SIZE / CHUNK_SIZE) * CHUNK_SIZE;
+                        (hash, offset as u64)
+                    };
+                    let entry = db.get(&hash).unwrap();
+                    let mut reader = entry.data_reader().await.unwrap();
+                    let data = reader.read_at(offset, CHUNK_SIZE).await.unwrap();
+                    assert_eq!(data.len(), CHUNK_SIZE);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+criterion_group!(benches, concurrent_reads);
+criterion_main!(benches);
@@ -0,0 +1,115 @@
+//! Import, random range reads and export for every store backend.
+//!
+//! The blob sizes default to 16 KiB, 1 MiB and 16 MiB. Set `IROH_BENCH_SIZES` to a comma
+//! separated list of sizes in bytes to change them, e.g.
+//! `IROH_BENCH_SIZES=1024,1048576 cargo bench --bench store`.
+use std::path::{Path, PathBuf};
+
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use iroh::baomap::{flat, mem};
+use iroh_bytes::baomap::{ExportMode, ImportMode, Map, MapEntry, ReadableStore, Store};
+use iroh_bytes::util::progress::IgnoreProgressSender;
+use iroh_bytes::util::runtime;
+use iroh_bytes::Hash;
+use iroh_io::AsyncSliceReader;
+use rand::{Rng, RngCore};
+use tokio::runtime::Runtime;
+
+const DEFAULT_SIZES: [usize; 3] = [16 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+const CHUNK_SIZE: usize = 16 * 1024;
+const READS: usize = 64;
+
+/// The blob sizes to benchmark, from `IROH_BENCH_SIZES` or the defaults.
+fn sizes() -> Vec<usize> {
+    match std::env::var("IROH_BENCH_SIZES") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| size.trim().parse().expect("invalid IROH_BENCH_SIZES"))
+            .collect(),
+        Err(_) => DEFAULT_SIZES.to_vec(),
+    }
+}
+
+fn stores(c: &mut Criterion) {
+    let tokio = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let rt = tokio.block_on(async { runtime::Handle::from_currrent(1).unwrap() });
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut group = c.benchmark_group("store");
+    for size in sizes() {
+        let source = dir.path().join(format!("source-{size}"));
+        let mut data = vec![0u8; size];
+        rand::thread_rng().fill_bytes(&mut data);
+        std::fs::write(&source, &data).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let db = mem::Store::new(rt.clone());
+        bench_store(&mut group, &tokio, "mem", db, &source, dir.path());
+
+        let path = dir.path().join(format!("flat-{size}"));
+        let db = tokio
+            .block_on(flat::Store::load(&path, &path, &rt))
+            .unwrap();
+        bench_store(&mut group, &tokio, "flat", db, &source, dir.path());
+    }
+    group.finish();
+}
+
+fn bench_store<S: Store>(
+    group: &mut BenchmarkGroup<WallTime>,
+    tokio: &Runtime,
+    backend: &str,
+    db: S,
+    source: &Path,
+    dir: &Path,
+) {
+    let size = std::fs::metadata(source).unwrap().len();
+    group.bench_function(BenchmarkId::new(format!("{backend}/import"), size), |b| {
+        b.to_async(tokio).iter(|| import(&db, source.to_path_buf()))
+    });
+
+    let hash = tokio.block_on(import(&db, source.to_path_buf()));
+    group.bench_function(BenchmarkId::new(format!("{backend}/read"), size), |b| {
+        b.to_async(tokio).iter(|| read_ranges(&db, hash, size))
+    });
+
+    let target = dir.join(format!("{backend}-export-{size}"));
+    group.bench_function(BenchmarkId::new(format!("{backend}/export"), size), |b| {
+        b.to_async(tokio).iter(|| export(&db, hash, target.clone()))
+    });
+}
+
+async fn import(db: &impl Store, source: PathBuf) -> Hash {
+    let (hash, _) = db
+        .import(source, ImportMode::Copy, IgnoreProgressSender::default())
+        .await
+        .unwrap();
+    hash
+}
+
+/// Read [`READS`] chunks at random offsets, reads close to the end are shorter.
+async fn read_ranges(db: &impl Map, hash: Hash, size: u64) {
+    let entry = db.get(&hash).unwrap();
+    let mut reader = entry.data_reader().await.unwrap();
+    for _ in 0..READS {
+        let offset = rand::thread_rng().gen_range(0..size);
+        let data = reader.read_at(offset, CHUNK_SIZE).await.unwrap();
+        assert!(!data.is_empty());
+    }
+}
+
+async fn export(db: &impl ReadableStore, hash: Hash, target: PathBuf) {
+    std::fs::remove_file(&target).ok();
+    db.export(hash, target, ExportMode::Copy, |_| Ok(()))
+        .await
+        .unwrap();
+}
+
+criterion_group!(benches, stores);
+criterion_main!(benches);
@@ -0,0 +1,99 @@
+//! End to end transfers of a single blob from a node to a getter over localhost.
+//!
+//! Every iteration dials the node, so the time includes the connection setup. The blob
+//! sizes default to 16 KiB, 1 MiB and 16 MiB. Set `IROH_BENCH_SIZES` to a comma separated
+//! list of sizes in bytes to change them, e.g.
+//! `IROH_BENCH_SIZES=1024,1048576 cargo bench --bench transfer`.
+use std::net::SocketAddr;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use iroh::baomap::readonly_mem;
+use iroh::node::Node;
+use iroh_bytes::get::fsm::{self, ConnectedNext, EndBlobNext};
+use iroh_bytes::protocol::GetRequest;
+use iroh_bytes::util::runtime;
+use iroh_bytes::Hash;
+use iroh_net::tls::{Keypair, PeerId};
+use rand::RngCore;
+
+const DEFAULT_SIZES: [usize; 3] = [16 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+
+/// The blob sizes to benchmark, from `IROH_BENCH_SIZES` or the defaults.
+fn sizes() -> Vec<usize> {
+    match std::env::var("IROH_BENCH_SIZES") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| size.trim().parse().expect("invalid IROH_BENCH_SIZES"))
+            .collect(),
+        Err(_) => DEFAULT_SIZES.to_vec(),
+    }
+}
+
+fn transfer(c: &mut Criterion) {
+    let tokio = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let sizes = sizes();
+    let mut db = readonly_mem::Store::default();
+    let hashes = sizes
+        .iter()
+        .map(|&size| {
+            let mut data = vec![0u8; size];
+            rand::thread_rng().fill_bytes(&mut data);
+            db.insert(data)
+        })
+        .collect::<Vec<_>>();
+    let node = tokio.block_on(async {
+        let rt = runtime::Handle::from_currrent(1).unwrap();
+        Node::builder(db)
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .runtime(&rt)
+            .spawn()
+            .await
+            .unwrap()
+    });
+    let peer_id = node.peer_id();
+    let addrs = tokio.block_on(node.local_endpoint_addresses()).unwrap();
+
+    let mut group = c.benchmark_group("transfer");
+    group.sample_size(10);
+    for (size, hash) in sizes.into_iter().zip(hashes) {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &hash, |b, &hash| {
+            b.to_async(&tokio)
+                .iter(|| get(peer_id, addrs.clone(), hash, size))
+        });
+    }
+    group.finish();
+    node.shutdown();
+}
+
+/// Dial the node and get a single blob.
+async fn get(peer_id: PeerId, addrs: Vec<SocketAddr>, hash: Hash, size: usize) {
+    let opts = iroh::dial::Options {
+        keypair: Keypair::generate(),
+        peer_id,
+        addrs,
+        derp_region: None,
+        keylog: false,
+        derp_map: None,
+        qlog: None,
+        dns_resolver: Default::default(),
+    };
+    let connection = iroh::dial::dial(opts).await.unwrap();
+    let request = GetRequest::single(hash).into();
+    let connected = fsm::start(connection, request).next().await.unwrap();
+    let ConnectedNext::StartRoot(start) = connected.next().await.unwrap() else {
+        panic!("request did not start with the root");
+    };
+    let (end, data) = start.next().concatenate_into_vec().await.unwrap();
+    assert_eq!(data.len(), size);
+    let EndBlobNext::Closing(closing) = end.next() else {
+        panic!("request has more than one blob");
+    };
+    closing.next().await.unwrap();
+}
+
+criterion_group!(benches, transfer);
+criterion_main!(benches);
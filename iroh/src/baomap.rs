@@ -1,10 +1,33 @@
 //! Various database implementations for storing blob data
+pub mod append;
+#[cfg(any(feature = "mem-db", feature = "flat-db"))]
+mod cancel;
+pub mod chunk_cache;
+pub mod coalesce;
+#[cfg(feature = "flat-db")]
+pub mod disk_space;
+#[cfg(feature = "flat-db")]
+pub mod encryption;
 #[cfg(feature = "flat-db")]
 pub mod flat;
+#[cfg(feature = "flat-db")]
+pub mod fsync;
+#[cfg(feature = "flat-db")]
+pub mod handle_cache;
 #[cfg(feature = "mem-db")]
 pub mod mem;
+pub mod outboard;
+pub mod outboard_hasher;
 
 pub mod readonly_mem;
+pub mod sparse;
+#[cfg(feature = "flat-db")]
+pub mod uring;
+pub mod validation;
+#[cfg(any(feature = "mem-db", feature = "flat-db"))]
+pub mod verified;
+#[cfg(feature = "flat-db")]
+mod wal;
 
 #[cfg(any(feature = "mem-db", feature = "flat-db"))]
 fn flatten_to_io<T>(
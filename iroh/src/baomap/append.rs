@@ -0,0 +1,177 @@
+//! Incremental hashing for appendable entries.
+//!
+//! Data is hashed one block at a time as it is appended. Complete subtrees are
+//! merged lazily, the same way the blake3 hasher does it, and the hash pairs of
+//! merged subtrees are kept as a post-order outboard. Since a subtree never changes
+//! once it is complete, sealing only has to hash the incomplete last block and the
+//! right edge of the tree, so the cost of a seal is independent of the size of the
+//! prefix.
+use std::io;
+
+use bao_tree::blake3;
+use bao_tree::blake3::guts::{parent_cv, ChunkState};
+use iroh_bytes::IROH_BLOCK_SIZE;
+
+use super::outboard::post_order_to_pre_order;
+
+/// Size of a blake3 chunk.
+const CHUNK_LEN: usize = 1024;
+
+/// Incrementally computes the hash and outboard of a growing blob.
+#[derive(Debug, Clone, Default)]
+pub struct IncrementalOutboard {
+    /// Number of complete blocks that have been hashed.
+    blocks: u64,
+    /// Chaining values of complete subtrees, largest first.
+    stack: Vec<blake3::Hash>,
+    /// Hash pairs of merged subtrees, in post-order.
+    pairs: Vec<u8>,
+}
+
+impl IncrementalOutboard {
+    /// Create a new incremental outboard for an empty blob.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of bytes of data that have been hashed.
+    pub fn hashed(&self) -> u64 {
+        self.blocks * IROH_BLOCK_SIZE.bytes() as u64
+    }
+
+    /// Hash all complete blocks of `data` that have not been hashed yet.
+    ///
+    /// `data` must be the entire blob, of which the first [`Self::hashed`] bytes must
+    /// not have changed since the last call.
+    pub fn update(&mut self, data: &[u8]) {
+        let block_size = IROH_BLOCK_SIZE.bytes();
+        let mut offset = self.hashed() as usize;
+        while offset + block_size <= data.len() {
+            let cv = hash_subtree(
+                self.start_chunk(),
+                &data[offset..offset + block_size],
+                false,
+            );
+            self.push(cv);
+            offset += block_size;
+        }
+    }
+
+    /// Compute the hash and pre-order outboard of `data`.
+    ///
+    /// `data` must be the entire blob, and all complete blocks must have been hashed
+    /// using [`Self::update`]. This does not modify the state, so more data can be
+    /// appended afterwards.
+    pub fn seal(&self, data: &[u8]) -> io::Result<(blake3::Hash, Vec<u8>)> {
+        let block_size = IROH_BLOCK_SIZE.bytes();
+        let hashed = self.hashed() as usize;
+        if hashed + block_size <= data.len() || hashed > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data does not match hashed blocks",
+            ));
+        }
+        let size = data.len() as u64;
+        if data.len() <= block_size {
+            // a single block, which is the root of the tree
+            return Ok((hash_subtree(0, data, true), size.to_le_bytes().to_vec()));
+        }
+        let mut stack = self.stack.clone();
+        let mut pairs = self.pairs.clone();
+        let tail = &data[hashed..];
+        if !tail.is_empty() {
+            stack.push(hash_subtree(self.start_chunk(), tail, false));
+        }
+        // merge the right edge of the tree, the last merge produces the root
+        let mut root = stack.pop().expect("at least two blocks");
+        while let Some(left) = stack.pop() {
+            pairs.extend_from_slice(left.as_bytes());
+            pairs.extend_from_slice(root.as_bytes());
+            root = parent_cv(&left, &root, stack.is_empty());
+        }
+        pairs.extend_from_slice(&size.to_le_bytes());
+        let outboard = post_order_to_pre_order(&pairs)?;
+        Ok((root, outboard))
+    }
+
+    fn start_chunk(&self) -> u64 {
+        self.blocks * (IROH_BLOCK_SIZE.bytes() / CHUNK_LEN) as u64
+    }
+
+    fn push(&mut self, cv: blake3::Hash) {
+        // merge complete subtrees lazily, since the last subtree might be the root
+        while self.stack.len() as u32 > self.blocks.count_ones() {
+            let right = self.stack.pop().unwrap();
+            let left = self.stack.pop().unwrap();
+            self.pairs.extend_from_slice(left.as_bytes());
+            self.pairs.extend_from_slice(right.as_bytes());
+            self.stack.push(parent_cv(&left, &right, false));
+        }
+        self.stack.push(cv);
+        self.blocks += 1;
+    }
+}
+
+/// Hash a subtree of chunks starting at chunk `start_chunk`.
+fn hash_subtree(start_chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
+    if data.len() <= CHUNK_LEN {
+        let mut state = ChunkState::new(start_chunk);
+        state.update(data);
+        state.finalize(is_root)
+    } else {
+        let chunks = ((data.len() + CHUNK_LEN - 1) / CHUNK_LEN) as u64;
+        let left_chunks = 1u64 << (63 - (chunks - 1).leading_zeros());
+        let (l, r) = data.split_at(left_chunks as usize * CHUNK_LEN);
+        let left = hash_subtree(start_chunk, l, false);
+        let right = hash_subtree(start_chunk + left_chunks, r, false);
+        parent_cv(&left, &right, is_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn incremental_matches_outboard() {
+        let block_size = IROH_BLOCK_SIZE.bytes();
+        let data = test_data(block_size * 9 + 123);
+        let mut ob = IncrementalOutboard::new();
+        // append in odd sized pieces and seal at various points
+        let mut len = 0;
+        while len < data.len() {
+            len = (len + 5000).min(data.len());
+            ob.update(&data[..len]);
+            let (hash, outboard) = ob.seal(&data[..len]).unwrap();
+            let (expected_outboard, expected_hash) =
+                bao_tree::io::outboard(&data[..len], IROH_BLOCK_SIZE);
+            assert_eq!(hash, expected_hash, "len {len}");
+            assert_eq!(outboard, expected_outboard, "len {len}");
+        }
+    }
+
+    #[test]
+    fn block_boundaries() {
+        let block_size = IROH_BLOCK_SIZE.bytes();
+        for blocks in [0, 1, 2, 3, 4, 8] {
+            let data = test_data(block_size * blocks);
+            let mut ob = IncrementalOutboard::new();
+            ob.update(&data);
+            let (hash, outboard) = ob.seal(&data).unwrap();
+            let (expected_outboard, expected_hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+            assert_eq!(hash, expected_hash, "blocks {blocks}");
+            assert_eq!(outboard, expected_outboard, "blocks {blocks}");
+        }
+    }
+
+    #[test]
+    fn seal_requires_update() {
+        let data = test_data(IROH_BLOCK_SIZE.bytes() * 2);
+        let ob = IncrementalOutboard::new();
+        assert!(ob.seal(&data).is_err());
+    }
+}
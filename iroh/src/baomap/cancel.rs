@@ -0,0 +1,152 @@
+//! Cancellation of blocking store operations.
+//!
+//! Work handed to `spawn_blocking` keeps running when the caller drops the future awaiting
+//! it. Imports and exports can take a long time, so they get a [`CancellationToken`] that
+//! is cancelled once their future is dropped, and check it between steps and whenever they
+//! report progress. A cancelled operation fails with [`io::ErrorKind::Interrupted`] and
+//! removes the temporary files it created.
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use futures::FutureExt;
+use tokio_util::sync::CancellationToken;
+
+use super::flatten_to_io;
+
+/// Size of the chunks [`copy_to_file`] checks for cancellation in between.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Runs `f` on the blocking pool of `rt`, cancelling its token if the returned future is
+/// dropped before it completes.
+pub(crate) fn spawn_blocking<T: Send + 'static>(
+    rt: &tokio::runtime::Handle,
+    f: impl FnOnce(CancellationToken) -> io::Result<T> + Send + 'static,
+) -> impl Future<Output = io::Result<T>> + Send + 'static {
+    let cancel = CancellationToken::new();
+    let guard = cancel.clone().drop_guard();
+    rt.spawn_blocking(move || f(cancel)).map(move |res| {
+        guard.disarm();
+        flatten_to_io(res)
+    })
+}
+
+/// Fails with [`io::ErrorKind::Interrupted`] if `cancel` is cancelled.
+pub(crate) fn check(cancel: &CancellationToken) -> io::Result<()> {
+    if cancel.is_cancelled() {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "operation cancelled",
+        ));
+    }
+    Ok(())
+}
+
+/// Wraps a progress callback so that it fails once `cancel` is cancelled.
+pub(crate) fn progress(
+    cancel: CancellationToken,
+    progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
+) -> impl Fn(u64) -> io::Result<()> + Send + Sync + 'static {
+    move |offset| {
+        check(&cancel)?;
+        progress(offset)
+    }
+}
+
+/// Copies `reader` to a new file at `target` in chunks, checking `cancel` and reporting
+/// the offset to `progress` before every chunk.
+///
+/// If the copy fails or is cancelled, the partially written target is removed.
+pub(crate) fn copy_to_file(
+    mut reader: impl Read,
+    target: &Path,
+    cancel: &CancellationToken,
+    progress: impl Fn(u64) -> io::Result<()>,
+) -> io::Result<u64> {
+    let mut file = std::fs::File::create(target)?;
+    let mut copy = || {
+        let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            check(cancel)?;
+            progress(offset)?;
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            file.write_all(&buf[..n])?;
+            offset += n as u64;
+        }
+        file.flush()?;
+        Ok(offset)
+    };
+    let res = copy();
+    if res.is_err() {
+        drop(file);
+        // do not leave a truncated export behind
+        std::fs::remove_file(target).ok();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drop_cancels() {
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let op = spawn_blocking(&tokio::runtime::Handle::current(), move |cancel| {
+            started_tx.send(()).ok();
+            let res = loop {
+                if let Err(e) = check(&cancel) {
+                    break e;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            };
+            done_tx.send(res.kind()).ok();
+            Ok(())
+        });
+        let op = tokio::spawn(op);
+        started_rx.await.unwrap();
+        op.abort();
+        let kind = tokio::time::timeout(Duration::from_secs(5), done_rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(kind, io::ErrorKind::Interrupted);
+
+        let res = spawn_blocking(&tokio::runtime::Handle::current(), |cancel| {
+            check(&cancel)?;
+            Ok(42)
+        });
+        assert_eq!(res.await.unwrap(), 42);
+    }
+
+    #[test]
+    fn copy_removes_cancelled_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("export");
+        let data = vec![7u8; COPY_CHUNK_SIZE * 3];
+
+        let cancel = CancellationToken::new();
+        let size = copy_to_file(&data[..], &target, &cancel, |_| Ok(())).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+
+        // cancelled after the first chunk
+        let res = copy_to_file(&data[..], &target, &cancel, |offset| {
+            if offset > 0 {
+                cancel.cancel();
+            }
+            Ok(())
+        });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert!(!target.exists());
+    }
+}
@@ -0,0 +1,176 @@
+//! An in-memory cache for hot ranges of blobs.
+//!
+//! Popular content is read from disk over and over again. The [`ChunkCache`] keeps the
+//! bytes of recently read ranges in memory, up to a configured number of bytes, and
+//! evicts the least recently used ranges when it is full.
+//!
+//! Ranges are cached exactly as they are read, keyed by hash, offset and length. Since
+//! requests are encoded in whole chunk groups, repeated requests for the same ranges
+//! read, and hit, the same keys.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use iroh_bytes::Hash;
+
+/// Reads larger than this fraction of the capacity are not cached, so a single large
+/// read can not evict everything else.
+const MAX_ENTRY_FRACTION: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RangeKey {
+    hash: Hash,
+    offset: u64,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Cached {
+    data: Bytes,
+    tick: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<RangeKey, Cached>,
+    /// Keys by the tick of their last use, least recently used first.
+    lru: BTreeMap<u64, RangeKey>,
+    tick: u64,
+    /// Total size of the cached data.
+    size: u64,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &RangeKey) {
+        if let Some(cached) = self.entries.remove(key) {
+            self.lru.remove(&cached.tick);
+            self.size -= cached.data.len() as u64;
+        }
+    }
+}
+
+/// A cache for ranges of blobs, sized in bytes.
+#[derive(Debug)]
+pub struct ChunkCache {
+    capacity: u64,
+    inner: Mutex<Inner>,
+}
+
+impl ChunkCache {
+    /// Create a new cache that holds up to `capacity` bytes of data.
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            inner: Default::default(),
+        }
+    }
+
+    /// The maximum number of bytes held by the cache.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// The number of bytes currently held by the cache.
+    pub fn size(&self) -> u64 {
+        self.inner.lock().unwrap().size
+    }
+
+    /// Get the cached bytes of a range, if they are cached.
+    pub fn get(&self, hash: Hash, offset: u64, len: usize) -> Option<Bytes> {
+        let key = RangeKey { hash, offset, len };
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let res = match inner.entries.get_mut(&key) {
+            Some(cached) => {
+                let old_tick = std::mem::replace(&mut cached.tick, tick);
+                let data = cached.data.clone();
+                inner.lru.remove(&old_tick);
+                inner.lru.insert(tick, key);
+                Some(data)
+            }
+            None => None,
+        };
+        #[cfg(feature = "metrics")]
+        if res.is_some() {
+            iroh_metrics::inc!(crate::metrics::Metrics, chunk_cache_hits);
+        } else {
+            iroh_metrics::inc!(crate::metrics::Metrics, chunk_cache_misses);
+        }
+        res
+    }
+
+    /// Cache the bytes of a range, evicting the least recently used ranges if needed.
+    pub fn insert(&self, hash: Hash, offset: u64, len: usize, data: Bytes) {
+        let size = data.len() as u64;
+        if size > self.capacity / MAX_ENTRY_FRACTION {
+            return;
+        }
+        let key = RangeKey { hash, offset, len };
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        while inner.size + size > self.capacity {
+            let Some((_, key)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.remove(&key);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.lru.insert(tick, key);
+        inner.entries.insert(key, Cached { data, tick });
+        inner.size += size;
+    }
+
+    /// Remove all cached ranges.
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap() = Inner::default();
+    }
+
+    /// Remove all cached ranges of a blob.
+    pub fn invalidate(&self, hash: Hash) {
+        let mut inner = self.inner.lock().unwrap();
+        let keys = inner
+            .entries
+            .keys()
+            .filter(|key| key.hash == hash)
+            .copied()
+            .collect::<Vec<_>>();
+        for key in keys {
+            inner.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_eviction_by_size() {
+        let cache = ChunkCache::new(16 * 1024);
+        let a = Hash::new(b"a");
+        let b = Hash::new(b"b");
+        let chunk = Bytes::from(vec![0u8; 1024]);
+        for i in 0..16 {
+            cache.insert(a, i * 1024, 1024, chunk.clone());
+        }
+        assert_eq!(cache.size(), 16 * 1024);
+        // use the first range, so the second one is the least recently used
+        assert!(cache.get(a, 0, 1024).is_some());
+        cache.insert(b, 0, 1024, chunk.clone());
+        assert_eq!(cache.size(), 16 * 1024);
+        assert!(cache.get(a, 0, 1024).is_some());
+        assert!(cache.get(a, 1024, 1024).is_none());
+        // a different length is a different range
+        assert!(cache.get(b, 0, 512).is_none());
+
+        // too large to be cached
+        cache.insert(b, 0, 2048, Bytes::from(vec![0u8; 2048]));
+        assert!(cache.get(b, 0, 2048).is_none());
+
+        cache.invalidate(a);
+        assert_eq!(cache.size(), 1024);
+        assert!(cache.get(b, 0, 1024).is_some());
+    }
+}
@@ -0,0 +1,197 @@
+//! Coalescing of identical concurrent reads.
+//!
+//! When many peers request the same popular ranges at the same time, every response
+//! reads the same bytes from the store. The [`ReadCoalescer`] lets the first of a group
+//! of identical reads go to the store, and hands its result to all the others that
+//! arrive while it is in flight.
+//!
+//! Reads are identical if they are for the same hash, offset and length. Since requests
+//! for the same ranges are encoded the same way, their reads line up.
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use futures::future::Shared;
+use futures::FutureExt;
+use iroh_bytes::Hash;
+use tokio::sync::oneshot;
+
+/// Result of a read, in a form that can be handed to several readers.
+type SharedResult = Result<Bytes, (io::ErrorKind, String)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ReadKey {
+    hash: Hash,
+    offset: u64,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    id: u64,
+    result: Shared<oneshot::Receiver<SharedResult>>,
+}
+
+/// Shares the results of in-flight reads with identical concurrent reads.
+#[derive(Debug, Default)]
+pub struct ReadCoalescer {
+    in_flight: Mutex<HashMap<ReadKey, InFlight>>,
+    next_id: AtomicU64,
+}
+
+impl ReadCoalescer {
+    /// Read `len` bytes at `offset` of the blob `hash`.
+    ///
+    /// If an identical read is in flight, waits for its result. Otherwise, performs the
+    /// read using `read`, and shares the result with identical reads that arrive in the
+    /// meantime. If the read in flight is cancelled, one of the waiting reads takes over.
+    pub async fn read<F, Fut>(
+        &self,
+        hash: Hash,
+        offset: u64,
+        len: usize,
+        read: F,
+    ) -> io::Result<Bytes>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<Bytes>>,
+    {
+        let key = ReadKey { hash, offset, len };
+        loop {
+            let role = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(&key) {
+                    Some(entry) => Role::Follow(entry.result.clone()),
+                    None => {
+                        let (tx, rx) = oneshot::channel();
+                        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                        let result = rx.shared();
+                        in_flight.insert(key, InFlight { id, result });
+                        Role::Lead(tx, id)
+                    }
+                }
+            };
+            match role {
+                Role::Follow(result) => match result.await {
+                    Ok(res) => {
+                        #[cfg(feature = "metrics")]
+                        iroh_metrics::inc!(crate::metrics::Metrics, reads_coalesced);
+                        return res.map_err(|(kind, msg)| io::Error::new(kind, msg));
+                    }
+                    // the read in flight was cancelled, try again
+                    Err(_) => continue,
+                },
+                Role::Lead(tx, id) => {
+                    let _guard = Leader {
+                        coalescer: self,
+                        key,
+                        id,
+                    };
+                    let res = read().await;
+                    let shared = match &res {
+                        Ok(bytes) => Ok(bytes.clone()),
+                        Err(e) => Err((e.kind(), e.to_string())),
+                    };
+                    tx.send(shared).ok();
+                    return res;
+                }
+            }
+        }
+    }
+
+    /// Number of distinct reads in flight.
+    #[cfg(test)]
+    fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+enum Role {
+    /// Wait for the result of the read in flight.
+    Follow(Shared<oneshot::Receiver<SharedResult>>),
+    /// Perform the read and share its result.
+    Lead(oneshot::Sender<SharedResult>, u64),
+}
+
+/// Removes the read of a leader from the in-flight reads when it is done or cancelled.
+struct Leader<'a> {
+    coalescer: &'a ReadCoalescer,
+    key: ReadKey,
+    id: u64,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.coalescer.in_flight.lock().unwrap();
+        // a new leader might have taken over if this one was slow to clean up
+        if in_flight.get(&self.key).map(|entry| entry.id) == Some(self.id) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn identical_reads_are_shared() {
+        let coalescer = Arc::new(ReadCoalescer::default());
+        let reads = Arc::new(AtomicUsize::new(0));
+        let hash = Hash::new(b"hello");
+        let tasks = (0..10)
+            .map(|i| {
+                let coalescer = coalescer.clone();
+                let reads = reads.clone();
+                // two distinct reads, five times each
+                let offset = (i % 2) * 1024;
+                tokio::spawn(async move {
+                    coalescer
+                        .read(hash, offset, 1024, || async move {
+                            reads.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            Ok(Bytes::from(vec![(offset / 1024) as u8; 1024]))
+                        })
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        for (i, task) in tasks.into_iter().enumerate() {
+            let data = task.await.unwrap().unwrap();
+            assert_eq!(data[0], (i % 2) as u8);
+        }
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        assert_eq!(coalescer.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_is_replaced() {
+        let coalescer = Arc::new(ReadCoalescer::default());
+        let hash = Hash::new(b"hello");
+        let leader = tokio::spawn({
+            let coalescer = coalescer.clone();
+            async move { coalescer.read(hash, 0, 1, futures::future::pending).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let follower = tokio::spawn({
+            let coalescer = coalescer.clone();
+            async move {
+                coalescer
+                    .read(hash, 0, 1, || async { Ok(Bytes::from_static(b"x")) })
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        leader.abort();
+        let data = follower.await.unwrap().unwrap();
+        assert_eq!(&data[..], b"x");
+        assert_eq!(coalescer.in_flight(), 0);
+    }
+}
@@ -0,0 +1,194 @@
+//! Disk space admission control for persistent stores.
+//!
+//! A store configured with [`Watermarks`] refuses new imports and the creation of
+//! new partial entries once the free space on the file system drops below the low
+//! watermark. Writes are admitted again once the free space has recovered above the
+//! high watermark, so the store does not flap around a single threshold.
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use iroh_bytes::baomap::InsufficientSpace;
+use tokio::sync::broadcast;
+
+/// Low and high watermarks for free disk space, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Below this amount of free space, new writes are refused.
+    pub low: u64,
+    /// Writes are admitted again once free space is above this amount.
+    pub high: u64,
+}
+
+impl Watermarks {
+    /// Create new watermarks.
+    ///
+    /// The high watermark is raised to the low watermark if it is below it.
+    pub fn new(low: u64, high: u64) -> Self {
+        Self {
+            low,
+            high: high.max(low),
+        }
+    }
+}
+
+/// Events emitted when free disk space crosses a watermark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskSpaceEvent {
+    /// Free space dropped below the low watermark, writes are refused.
+    Low {
+        /// Free space in bytes
+        free: u64,
+    },
+    /// Free space recovered above the high watermark, writes are admitted.
+    Recovered {
+        /// Free space in bytes
+        free: u64,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) struct DiskSpaceMonitor {
+    path: PathBuf,
+    watermarks: Watermarks,
+    low: Mutex<bool>,
+    events: broadcast::Sender<DiskSpaceEvent>,
+}
+
+impl DiskSpaceMonitor {
+    pub fn new(
+        path: PathBuf,
+        watermarks: Watermarks,
+        events: broadcast::Sender<DiskSpaceEvent>,
+    ) -> Self {
+        Self {
+            path,
+            watermarks,
+            low: Mutex::new(false),
+            events,
+        }
+    }
+
+    /// Check if a new write should be admitted.
+    ///
+    /// This is cheap enough to be called once per import or partial entry creation.
+    pub fn check(&self) -> io::Result<()> {
+        let free = free_space(&self.path)?;
+        let (low, event) = {
+            let mut low = self.low.lock().unwrap();
+            let was_low = *low;
+            *low = self.state(free, was_low);
+            let event = match (was_low, *low) {
+                (false, true) => Some(DiskSpaceEvent::Low { free }),
+                (true, false) => Some(DiskSpaceEvent::Recovered { free }),
+                _ => None,
+            };
+            (*low, event)
+        };
+        match event {
+            Some(DiskSpaceEvent::Low { .. }) => {
+                tracing::warn!(
+                    "free disk space {} below low watermark {}, refusing writes",
+                    free,
+                    self.watermarks.low
+                );
+                #[cfg(feature = "metrics")]
+                iroh_metrics::inc!(crate::metrics::Metrics, disk_space_low);
+            }
+            Some(DiskSpaceEvent::Recovered { .. }) => {
+                tracing::info!(
+                    "free disk space {} above high watermark {}, admitting writes",
+                    free,
+                    self.watermarks.high
+                );
+            }
+            None => {}
+        }
+        if let Some(event) = event {
+            // there might be no subscribers
+            self.events.send(event).ok();
+        }
+        if low {
+            #[cfg(feature = "metrics")]
+            iroh_metrics::inc!(crate::metrics::Metrics, writes_rejected_disk_space);
+            return Err(InsufficientSpace {
+                free,
+                low_watermark: self.watermarks.low,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Compute whether we are in the low state given the free space and the previous state.
+    fn state(&self, free: u64, low: bool) -> bool {
+        if low {
+            free < self.watermarks.high
+        } else {
+            free < self.watermarks.low
+        }
+    }
+}
+
+/// Free space available to unprivileged users on the file system containing `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is a valid nul terminated string and stat is a valid pointer
+    let res = unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statvfs succeeded, so stat is initialized
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free space available to unprivileged users on the file system containing `path`.
+///
+/// Not supported on this platform, so admission control is effectively disabled.
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(low: u64, high: u64) -> DiskSpaceMonitor {
+        let (tx, _) = broadcast::channel(1);
+        DiskSpaceMonitor::new(PathBuf::from("."), Watermarks::new(low, high), tx)
+    }
+
+    #[test]
+    fn hysteresis() {
+        let m = monitor(100, 200);
+        assert!(!m.state(150, false));
+        assert!(m.state(50, false));
+        assert!(m.state(150, true));
+        assert!(!m.state(250, true));
+    }
+
+    #[test]
+    fn check_admits_and_refuses() {
+        let (tx, mut rx) = broadcast::channel(4);
+        let m = DiskSpaceMonitor::new(PathBuf::from("."), Watermarks::new(0, 0), tx);
+        assert!(m.check().is_ok());
+        let m = DiskSpaceMonitor::new(
+            PathBuf::from("."),
+            Watermarks::new(u64::MAX, u64::MAX),
+            m.events.clone(),
+        );
+        let err = m.check().unwrap_err();
+        let err = InsufficientSpace::from_io_error(&err).unwrap();
+        assert_eq!(err.low_watermark, u64::MAX);
+        assert!(matches!(rx.try_recv(), Ok(DiskSpaceEvent::Low { .. })));
+    }
+}
@@ -0,0 +1,823 @@
+//! Encryption at rest for the flat store.
+//!
+//! An encrypted store encrypts the data and outboard files it owns with a random data key.
+//! The data key is stored next to the data, wrapped by a [`KeyWrapper`]: either with a
+//! key derived from a passphrase, see [`Passphrase`], or by an external key management
+//! service. Without the wrapper the store can not be read.
+//!
+//! # File format
+//!
+//! Files are split into segments of [`SEGMENT_SIZE`] bytes of plaintext, only the last
+//! segment may be shorter. Every segment is encrypted on its own with XChaCha20-Poly1305
+//! and a random nonce, and stored as the nonce followed by the ciphertext. Segments can
+//! therefore be read and rewritten independently, which is what random access reads and
+//! out of order writes of downloads need. Rewriting a segment picks a new nonce.
+//!
+//! There are no holes: a write past the end of a file fills the gap with encrypted zeros,
+//! so every segment on disk is authenticated, and zeroing a segment is detected like any
+//! other change to the ciphertext.
+//!
+//! Encryption protects the confidentiality of the data. Its integrity is protected by the
+//! outboards, like for unencrypted stores, so segments are not bound to their position.
+//!
+//! # Writes
+//!
+//! Writes to partial files go through a [`WriteBuffer`] that is shared by all handles to
+//! the file. It serializes the writes, and keeps the segment that is being written in
+//! memory until it is complete, so small sequential writes do not reencrypt a whole
+//! segment each. Readers that share the buffer see the buffered segment.
+//!
+//! # Wrapped key format
+//!
+//! [`Passphrase`] derives a key encryption key from the passphrase with
+//! PBKDF2-HMAC-SHA256. The wrapped key consists of a version byte, the little endian
+//! iteration count as a u32, a 16 byte salt and the data key sealed like a segment.
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use crypto_secretbox::aead::{Aead, KeyInit};
+use crypto_secretbox::{Key, Nonce, XChaCha20Poly1305};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
+use rand::Rng;
+
+use super::fsync::{self, SyncingFile};
+use super::handle_cache::CachedFile;
+
+/// Size of the plaintext of a segment.
+pub const SEGMENT_SIZE: usize = 16 * 1024;
+/// Size of the nonce stored in front of every segment.
+const NONCE_SIZE: usize = 24;
+/// Size of the authentication tag of every segment.
+const TAG_SIZE: usize = 16;
+/// Bytes a stored segment is larger than its plaintext.
+const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+/// Size of a full segment on disk.
+const STORED_SEGMENT_SIZE: usize = SEGMENT_SIZE + OVERHEAD;
+/// Number of zero segments that are encrypted and written at once to fill a gap.
+const ZERO_BATCH: u64 = 64;
+
+/// Version of the wrapped key format of [`Passphrase`].
+const PASSPHRASE_VERSION: u8 = 1;
+/// Size of the salt of [`Passphrase`].
+const SALT_SIZE: usize = 16;
+
+/// Wraps and unwraps the data key of an encrypted store.
+///
+/// Implement this to keep the key encryption key in a key management service. The
+/// wrapped key is stored in the store, and unwrapped every time the store is loaded.
+pub trait KeyWrapper: fmt::Debug + Send + Sync + 'static {
+    /// Wrap a newly generated data key.
+    fn wrap(&self, key: &[u8; 32]) -> io::Result<Vec<u8>>;
+
+    /// Unwrap a data key wrapped by [`KeyWrapper::wrap`].
+    ///
+    /// Must fail if `wrapped` was not wrapped with the same key encryption key.
+    fn unwrap(&self, wrapped: &[u8]) -> io::Result<[u8; 32]>;
+}
+
+/// A [`KeyWrapper`] with a key encryption key derived from a passphrase.
+#[derive(Clone)]
+pub struct Passphrase {
+    passphrase: String,
+    iterations: NonZeroU32,
+}
+
+impl Passphrase {
+    /// The default number of PBKDF2 iterations for newly wrapped keys.
+    pub const DEFAULT_ITERATIONS: u32 = 600_000;
+
+    /// Derive the key encryption key from `passphrase`.
+    pub fn new(passphrase: impl Into<String>) -> Self {
+        Self {
+            passphrase: passphrase.into(),
+            iterations: NonZeroU32::new(Self::DEFAULT_ITERATIONS).expect("not zero"),
+        }
+    }
+
+    /// Set the number of PBKDF2 iterations used to wrap new keys.
+    ///
+    /// Existing keys are unwrapped with the iterations they were wrapped with.
+    pub fn iterations(mut self, iterations: NonZeroU32) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    fn derive(&self, salt: &[u8], iterations: NonZeroU32) -> DataKey {
+        let mut key = [0u8; 32];
+        ring::pbkdf2::derive(
+            ring::pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            salt,
+            self.passphrase.as_bytes(),
+            &mut key,
+        );
+        DataKey::new(&key)
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Passphrase")
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyWrapper for Passphrase {
+    fn wrap(&self, key: &[u8; 32]) -> io::Result<Vec<u8>> {
+        let salt = rand::thread_rng().gen::<[u8; SALT_SIZE]>();
+        let mut wrapped = vec![PASSPHRASE_VERSION];
+        wrapped.extend_from_slice(&self.iterations.get().to_le_bytes());
+        wrapped.extend_from_slice(&salt);
+        wrapped.extend_from_slice(&self.derive(&salt, self.iterations).seal(key));
+        Ok(wrapped)
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> io::Result<[u8; 32]> {
+        let header = 1 + 4 + SALT_SIZE;
+        if wrapped.len() < header || wrapped[0] != PASSPHRASE_VERSION {
+            return Err(invalid_data("unsupported wrapped key"));
+        }
+        let iterations = u32::from_le_bytes(wrapped[1..5].try_into().unwrap());
+        let iterations =
+            NonZeroU32::new(iterations).ok_or_else(|| invalid_data("invalid iterations"))?;
+        let kek = self.derive(&wrapped[5..header], iterations);
+        let key = kek
+            .open(&wrapped[header..])
+            .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "wrong passphrase"))?;
+        key.try_into()
+            .map_err(|_| invalid_data("wrapped key has the wrong size"))
+    }
+}
+
+/// The key the files of an encrypted store are encrypted with.
+#[derive(Clone)]
+pub struct DataKey(XChaCha20Poly1305);
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+impl DataKey {
+    fn new(key: &[u8; 32]) -> Self {
+        Self(XChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    /// Load the data key from `path`, or create it if it does not exist yet.
+    pub(crate) fn load_or_create(path: &Path, wrapper: &dyn KeyWrapper) -> io::Result<Self> {
+        if path.exists() {
+            let wrapped = std::fs::read(path)?;
+            return Ok(Self::new(&wrapper.unwrap(&wrapped)?));
+        }
+        let key = rand::thread_rng().gen::<[u8; 32]>();
+        let wrapped = wrapper.wrap(&key)?;
+        // losing the key loses the store, so it is never visible half written
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fsync::write_file(Path::new(&tmp), &wrapped, true)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(Self::new(&key))
+    }
+
+    /// Encrypt a single segment, or anything else that fits in memory.
+    fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = rand::thread_rng().gen::<[u8; NONCE_SIZE]>();
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("encrypting to a vec can not fail");
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt something encrypted with [`DataKey::seal`].
+    fn open(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < OVERHEAD {
+            return Err(invalid_data("encrypted segment is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| invalid_data("failed to decrypt segment, wrong key or corrupted data"))
+    }
+
+    /// Encrypt a whole file.
+    pub(crate) fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::with_capacity(stored_len(data.len() as u64) as usize);
+        for segment in data.chunks(SEGMENT_SIZE) {
+            encrypted.extend_from_slice(&self.seal(segment));
+        }
+        encrypted
+    }
+
+    /// Decrypt a whole file.
+    pub(crate) fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decrypted = Vec::with_capacity(plaintext_len(data.len() as u64) as usize);
+        for segment in data.chunks(STORED_SEGMENT_SIZE) {
+            decrypted.extend_from_slice(&self.open(segment)?);
+        }
+        Ok(decrypted)
+    }
+}
+
+/// Encrypt `data` to be written to a file if there is a key.
+pub(crate) fn seal<'a>(key: Option<&DataKey>, data: &'a [u8]) -> Cow<'a, [u8]> {
+    match key {
+        Some(key) => Cow::Owned(key.encrypt(data)),
+        None => Cow::Borrowed(data),
+    }
+}
+
+/// Decrypt the contents of a file if there is a key.
+pub(crate) fn open(key: Option<&DataKey>, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match key {
+        Some(key) => key.decrypt(&data),
+        None => Ok(data),
+    }
+}
+
+/// The size of the data in a file of size `stored` if there is a key.
+pub(crate) fn file_len(key: Option<&DataKey>, stored: u64) -> u64 {
+    match key {
+        Some(_) => plaintext_len(stored),
+        None => stored,
+    }
+}
+
+/// The size of the plaintext of an encrypted file of size `stored`.
+pub(crate) fn plaintext_len(stored: u64) -> u64 {
+    let full = stored / STORED_SEGMENT_SIZE as u64;
+    let rest = stored % STORED_SEGMENT_SIZE as u64;
+    full * SEGMENT_SIZE as u64 + rest.saturating_sub(OVERHEAD as u64)
+}
+
+/// The size of an encrypted file with `len` bytes of plaintext.
+fn stored_len(len: u64) -> u64 {
+    let full = len / SEGMENT_SIZE as u64;
+    let rest = len % SEGMENT_SIZE as u64;
+    let last = if rest > 0 { rest + OVERHEAD as u64 } else { 0 };
+    full * STORED_SEGMENT_SIZE as u64 + last
+}
+
+/// A reader that encrypts everything read through it into a writer.
+///
+/// Used to hash data while encrypting it, so the hash is of exactly the data that was
+/// stored. Call [`EncryptingReader::finish`] to write the last segment.
+#[derive(Debug)]
+pub(crate) struct EncryptingReader<R, W> {
+    inner: R,
+    target: W,
+    key: DataKey,
+    segment: Vec<u8>,
+}
+
+impl<R: Read, W: Write> EncryptingReader<R, W> {
+    pub(crate) fn new(inner: R, target: W, key: DataKey) -> Self {
+        Self {
+            inner,
+            target,
+            key,
+            segment: Vec::with_capacity(SEGMENT_SIZE),
+        }
+    }
+
+    /// Write the last segment and return the writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if !self.segment.is_empty() {
+            self.target.write_all(&self.key.seal(&self.segment))?;
+        }
+        Ok(self.target)
+    }
+}
+
+impl<R: Read, W: Write> Read for EncryptingReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut rest = &buf[..n];
+        while !rest.is_empty() {
+            let take = rest.len().min(SEGMENT_SIZE - self.segment.len());
+            self.segment.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.segment.len() == SEGMENT_SIZE {
+                self.target.write_all(&self.key.seal(&self.segment))?;
+                self.segment.clear();
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// A reader that sequentially decrypts an encrypted file.
+#[derive(Debug)]
+pub(crate) struct DecryptingReader<R> {
+    inner: R,
+    key: DataKey,
+    segment: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub(crate) fn new(inner: R, key: DataKey) -> Self {
+        Self {
+            inner,
+            key,
+            segment: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.segment.len() {
+            let mut stored = Vec::with_capacity(STORED_SEGMENT_SIZE);
+            (&mut self.inner)
+                .take(STORED_SEGMENT_SIZE as u64)
+                .read_to_end(&mut stored)?;
+            if stored.is_empty() {
+                return Ok(0);
+            }
+            self.segment = self.key.open(&stored)?;
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.segment.len() - self.pos);
+        buf[..n].copy_from_slice(&self.segment[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// A segment that was written partially and is not stored yet.
+#[derive(Debug)]
+struct Pending {
+    index: u64,
+    data: Vec<u8>,
+}
+
+/// Serializes the writes to an encrypted file and buffers the segment that is being
+/// written, shared by all handles to the file.
+///
+/// The buffered segment is stored when a write moves on to another segment, and on
+/// [`AsyncSliceWriter::sync`]. Writers must sync before the file is moved or read without
+/// the buffer.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WriteBuffer(Arc<tokio::sync::Mutex<Option<Pending>>>);
+
+/// A file encrypted with a [`DataKey`], see the [module docs](self) for the format.
+///
+/// Reads and writes take plaintext offsets.
+#[derive(Debug)]
+pub struct EncryptedFile<F> {
+    inner: F,
+    key: DataKey,
+    buffer: WriteBuffer,
+}
+
+impl<F> EncryptedFile<F> {
+    /// A file that is only read, or written by this handle alone.
+    pub(crate) fn new(inner: F, key: DataKey) -> Self {
+        Self::with_buffer(inner, key, WriteBuffer::default())
+    }
+
+    /// A file whose writes are buffered in `buffer`, shared with the other handles.
+    pub(crate) fn with_buffer(inner: F, key: DataKey, buffer: WriteBuffer) -> Self {
+        Self { inner, key, buffer }
+    }
+}
+
+impl<F: AsyncSliceReader> EncryptedFile<F> {
+    /// The size of the plaintext, including the buffered segment.
+    async fn len_with(&mut self, pending: &Option<Pending>) -> io::Result<u64> {
+        let stored = plaintext_len(self.inner.len().await?);
+        Ok(match pending {
+            Some(p) => stored.max(p.index * SEGMENT_SIZE as u64 + p.data.len() as u64),
+            None => stored,
+        })
+    }
+
+    async fn plaintext_len(&mut self) -> io::Result<u64> {
+        let buffer = self.buffer.clone();
+        let pending = buffer.0.lock().await;
+        self.len_with(&pending).await
+    }
+
+    async fn read(&mut self, offset: u64, len: usize) -> io::Result<Bytes> {
+        let buffer = self.buffer.clone();
+        let pending = buffer.0.lock().await;
+        // reads past the end are clipped, read_to_end asks for usize::MAX bytes
+        let end = offset
+            .saturating_add(len as u64)
+            .min(self.len_with(&pending).await?);
+        if offset >= end {
+            return Ok(Bytes::new());
+        }
+        let segment_size = SEGMENT_SIZE as u64;
+        let first = offset / segment_size;
+        let last = (end - 1) / segment_size;
+        let stored = self
+            .inner
+            .read_at(
+                first * STORED_SEGMENT_SIZE as u64,
+                ((last - first + 1) as usize) * STORED_SEGMENT_SIZE,
+            )
+            .await?;
+        let mut stored = stored.chunks(STORED_SEGMENT_SIZE);
+        let mut data = Vec::with_capacity(((last - first + 1) as usize) * SEGMENT_SIZE);
+        for index in first..=last {
+            let segment = stored.next().unwrap_or_default();
+            match pending.as_ref() {
+                Some(p) if p.index == index => data.extend_from_slice(&p.data),
+                _ => data.extend_from_slice(&self.key.open(segment)?),
+            }
+        }
+        let start = (offset - first * segment_size) as usize;
+        let end = ((end - first * segment_size) as usize).min(data.len());
+        Ok(Bytes::from(data).slice(start.min(end)..end))
+    }
+
+    /// Read the plaintext of a stored segment, empty if it is past the end.
+    async fn read_segment(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let offset = index * STORED_SEGMENT_SIZE as u64;
+        let stored = self.inner.read_at(offset, STORED_SEGMENT_SIZE).await?;
+        if stored.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.key.open(&stored)
+    }
+}
+
+impl<F: AsyncSliceReader + AsyncSliceWriter> EncryptedFile<F> {
+    async fn write_segment(&mut self, index: u64, plaintext: &[u8]) -> io::Result<()> {
+        let offset = index * STORED_SEGMENT_SIZE as u64;
+        let sealed = self.key.seal(plaintext);
+        self.inner.write_bytes_at(offset, sealed.into()).await
+    }
+
+    /// Store the buffered segment, if any.
+    async fn flush(&mut self, pending: &mut Option<Pending>) -> io::Result<()> {
+        if let Some(p) = pending.as_ref() {
+            self.write_segment(p.index, &p.data).await?;
+            *pending = None;
+        }
+        Ok(())
+    }
+
+    /// Take the plaintext of a segment out of the buffer, or read it after storing the
+    /// buffered segment.
+    async fn take_segment(
+        &mut self,
+        pending: &mut Option<Pending>,
+        index: u64,
+    ) -> io::Result<Vec<u8>> {
+        match pending.take() {
+            Some(p) if p.index == index => Ok(p.data),
+            other => {
+                *pending = other;
+                self.flush(pending).await?;
+                self.read_segment(index).await
+            }
+        }
+    }
+
+    /// Grow the plaintext from `len` to `until` bytes with encrypted zeros.
+    ///
+    /// A partial last segment is left in the buffer.
+    async fn extend(
+        &mut self,
+        pending: &mut Option<Pending>,
+        len: u64,
+        until: u64,
+    ) -> io::Result<()> {
+        let segment_size = SEGMENT_SIZE as u64;
+        if len % segment_size != 0 {
+            // complete the current last segment, or as much of it as needed
+            let index = len / segment_size;
+            let mut segment = self.take_segment(pending, index).await?;
+            segment.resize((until - index * segment_size).min(segment_size) as usize, 0);
+            let complete = segment.len() == SEGMENT_SIZE;
+            *pending = Some(Pending {
+                index,
+                data: segment,
+            });
+            if !complete {
+                return Ok(());
+            }
+            self.flush(pending).await?;
+        }
+        let mut index = (len + segment_size - 1) / segment_size;
+        let full = until / segment_size;
+        let zeros = vec![0u8; SEGMENT_SIZE];
+        while index < full {
+            let count = (full - index).min(ZERO_BATCH);
+            let mut batch = Vec::with_capacity(count as usize * STORED_SEGMENT_SIZE);
+            for _ in 0..count {
+                batch.extend_from_slice(&self.key.seal(&zeros));
+            }
+            let offset = index * STORED_SEGMENT_SIZE as u64;
+            self.inner.write_bytes_at(offset, batch.into()).await?;
+            index += count;
+        }
+        let rest = (until % segment_size) as usize;
+        if rest != 0 {
+            self.flush(pending).await?;
+            *pending = Some(Pending {
+                index: full,
+                data: vec![0u8; rest],
+            });
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let buffer = self.buffer.clone();
+        let mut pending = buffer.0.lock().await;
+        let segment_size = SEGMENT_SIZE as u64;
+        let mut len = self.len_with(&pending).await?;
+        if offset > len {
+            self.extend(&mut pending, len, offset).await?;
+            len = offset;
+        }
+        let end = offset + data.len() as u64;
+        let mut index = offset / segment_size;
+        while index * segment_size < end {
+            let start = index * segment_size;
+            let from = offset.max(start);
+            let to = end.min(start + segment_size);
+            let chunk = &data[(from - offset) as usize..(to - offset) as usize];
+            let within = (from - start) as usize;
+            let existing = len.saturating_sub(start).min(segment_size) as usize;
+            let mut segment = match pending.take() {
+                Some(p) if p.index == index => p.data,
+                other => {
+                    *pending = other;
+                    self.flush(&mut pending).await?;
+                    if within == 0 && chunk.len() >= existing {
+                        // the segment is overwritten completely, nothing to read
+                        Vec::with_capacity(SEGMENT_SIZE)
+                    } else {
+                        self.read_segment(index).await?
+                    }
+                }
+            };
+            if segment.len() < within + chunk.len() {
+                segment.resize(within + chunk.len(), 0);
+            }
+            segment[within..within + chunk.len()].copy_from_slice(chunk);
+            if segment.len() == SEGMENT_SIZE && to == start + segment_size {
+                // a write that completes a segment stores it
+                self.write_segment(index, &segment).await?;
+            } else {
+                *pending = Some(Pending {
+                    index,
+                    data: segment,
+                });
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+
+    async fn truncate(&mut self, new_len: u64) -> io::Result<()> {
+        let buffer = self.buffer.clone();
+        let mut pending = buffer.0.lock().await;
+        let segment_size = SEGMENT_SIZE as u64;
+        let len = self.len_with(&pending).await?;
+        if new_len > len {
+            self.extend(&mut pending, len, new_len).await?;
+        }
+        self.flush(&mut pending).await?;
+        if new_len >= len {
+            return Ok(());
+        }
+        let rest = (new_len % segment_size) as usize;
+        if rest != 0 {
+            let index = new_len / segment_size;
+            let mut segment = self.read_segment(index).await?;
+            segment.resize(rest, 0);
+            self.write_segment(index, &segment).await?;
+        }
+        self.inner.set_len(stored_len(new_len)).await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        let buffer = self.buffer.clone();
+        let mut pending = buffer.0.lock().await;
+        self.flush(&mut pending).await?;
+        self.inner.sync().await
+    }
+}
+
+impl AsyncSliceReader for EncryptedFile<CachedFile> {
+    type ReadAtFuture<'a> = BoxFuture<'a, io::Result<Bytes>>;
+    fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
+        self.read(offset, len).boxed()
+    }
+
+    type LenFuture<'a> = BoxFuture<'a, io::Result<u64>>;
+    fn len(&mut self) -> Self::LenFuture<'_> {
+        self.plaintext_len().boxed()
+    }
+}
+
+impl AsyncSliceReader for EncryptedFile<SyncingFile> {
+    type ReadAtFuture<'a> = BoxFuture<'a, io::Result<Bytes>>;
+    fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
+        self.read(offset, len).boxed()
+    }
+
+    type LenFuture<'a> = BoxFuture<'a, io::Result<u64>>;
+    fn len(&mut self) -> Self::LenFuture<'_> {
+        self.plaintext_len().boxed()
+    }
+}
+
+impl AsyncSliceWriter for EncryptedFile<SyncingFile> {
+    type WriteAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Self::WriteAtFuture<'_> {
+        let data = data.to_vec();
+        async move { self.write(offset, &data).await }.boxed()
+    }
+
+    type WriteBytesAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_bytes_at(&mut self, offset: u64, data: Bytes) -> Self::WriteBytesAtFuture<'_> {
+        async move { self.write(offset, &data).await }.boxed()
+    }
+
+    type SetLenFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn set_len(&mut self, len: u64) -> Self::SetLenFuture<'_> {
+        self.truncate(len).boxed()
+    }
+
+    type SyncFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn sync(&mut self) -> Self::SyncFuture<'_> {
+        EncryptedFile::sync(self).boxed()
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> DataKey {
+        DataKey::new(&[7u8; 32])
+    }
+
+    #[test]
+    fn whole_file_roundtrip() {
+        let key = test_key();
+        for size in [0, 1, SEGMENT_SIZE - 1, SEGMENT_SIZE, 3 * SEGMENT_SIZE + 5] {
+            let data = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+            let encrypted = key.encrypt(&data);
+            assert_eq!(encrypted.len() as u64, stored_len(size as u64));
+            assert_eq!(plaintext_len(encrypted.len() as u64), size as u64);
+            assert_eq!(key.decrypt(&encrypted).unwrap(), data);
+
+            let mut reader = DecryptingReader::new(&encrypted[..], key.clone());
+            let mut decrypted = Vec::new();
+            reader.read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, data);
+
+            let mut reader = EncryptingReader::new(&data[..], Vec::new(), key.clone());
+            io::copy(&mut reader, &mut io::sink()).unwrap();
+            let encrypted = reader.finish().unwrap();
+            assert_eq!(key.decrypt(&encrypted).unwrap(), data);
+        }
+        assert!(DataKey::new(&[8u8; 32])
+            .decrypt(&key.encrypt(b"hello"))
+            .is_err());
+    }
+
+    #[test]
+    fn passphrase() {
+        let iterations = NonZeroU32::new(10).unwrap();
+        let wrapper = Passphrase::new("secret").iterations(iterations);
+        let key = [3u8; 32];
+        let wrapped = wrapper.wrap(&key).unwrap();
+        assert_eq!(wrapper.unwrap(&wrapped).unwrap(), key);
+        assert!(Passphrase::new("wrong").unwrap(&wrapped).is_err());
+    }
+
+    #[tokio::test]
+    async fn random_access() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file");
+        let file = iroh_io::File::create(move || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)
+        })
+        .await?;
+        let mut file = EncryptedFile::new(SyncingFile::new(file, false), test_key());
+        let mut expected = Vec::new();
+        // out of order writes, leaving holes, and writes across segment boundaries
+        let writes = [
+            (3 * SEGMENT_SIZE, 100),
+            (10, 20),
+            (SEGMENT_SIZE - 5, 10),
+            (3 * SEGMENT_SIZE + 50, SEGMENT_SIZE),
+        ];
+        for (i, (offset, len)) in writes.into_iter().enumerate() {
+            let data = vec![i as u8 + 1; len];
+            file.write_at(offset as u64, &data).await?;
+            if expected.len() < offset + len {
+                expected.resize(offset + len, 0);
+            }
+            expected[offset..offset + len].copy_from_slice(&data);
+        }
+        assert_eq!(file.len().await?, expected.len() as u64);
+        let all = file.read_at(0, usize::MAX).await?;
+        assert_eq!(all, expected);
+        let part = file.read_at(SEGMENT_SIZE as u64 - 10, 30).await?;
+        assert_eq!(part, expected[SEGMENT_SIZE - 10..SEGMENT_SIZE + 20]);
+
+        file.set_len(SEGMENT_SIZE as u64 + 1).await?;
+        expected.truncate(SEGMENT_SIZE + 1);
+        assert_eq!(file.read_at(0, usize::MAX).await?, expected);
+        file.set_len(3 * SEGMENT_SIZE as u64).await?;
+        expected.resize(3 * SEGMENT_SIZE, 0);
+        assert_eq!(file.read_at(0, usize::MAX).await?, expected);
+        Ok(())
+    }
+
+    async fn create(path: std::path::PathBuf) -> io::Result<SyncingFile> {
+        let file = iroh_io::File::create(move || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)
+        })
+        .await?;
+        Ok(SyncingFile::new(file, false))
+    }
+
+    #[tokio::test]
+    async fn zeroed_segment() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file");
+        let mut file = EncryptedFile::new(create(path.clone()).await?, test_key());
+        // a write past the end stores the gap encrypted
+        file.write_at(2 * SEGMENT_SIZE as u64, &[1u8; SEGMENT_SIZE])
+            .await?;
+        file.sync().await?;
+        assert_eq!(
+            file.read_at(0, SEGMENT_SIZE).await?,
+            vec![0u8; SEGMENT_SIZE]
+        );
+
+        let mut stored = std::fs::read(&path)?;
+        stored[STORED_SEGMENT_SIZE..2 * STORED_SEGMENT_SIZE].fill(0);
+        std::fs::write(&path, &stored)?;
+        let mut file = EncryptedFile::new(create(path).await?, test_key());
+        let err = file.read_at(0, usize::MAX).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn buffered_writes() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file");
+        let buffer = WriteBuffer::default();
+        let mut a =
+            EncryptedFile::with_buffer(create(path.clone()).await?, test_key(), buffer.clone());
+        let mut b = EncryptedFile::with_buffer(create(path.clone()).await?, test_key(), buffer);
+        let mut expected = Vec::new();
+        // small writes from two handles, interleaved within the same segments
+        for i in 0..(SEGMENT_SIZE + 100) / 100 {
+            let data = vec![i as u8; 100];
+            let file = if i % 2 == 0 { &mut a } else { &mut b };
+            file.write_at(expected.len() as u64, &data).await?;
+            expected.extend_from_slice(&data);
+        }
+        // the first segment is stored, the second one is still buffered
+        assert_eq!(std::fs::metadata(&path)?.len(), STORED_SEGMENT_SIZE as u64);
+        assert_eq!(a.read_at(0, usize::MAX).await?, expected);
+        assert_eq!(b.len().await?, expected.len() as u64);
+
+        b.sync().await?;
+        let mut reader = EncryptedFile::new(create(path).await?, test_key());
+        assert_eq!(reader.read_at(0, usize::MAX).await?, expected);
+        Ok(())
+    }
+}
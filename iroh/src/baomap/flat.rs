@@ -52,9 +52,15 @@
 //!
 //! The first 8 bytes of the file are the little endian encoded size of the data.
 //!
-//! In the future we might support other block sizes as well as in-order or post-order
-//! encoded trees. The file extension will then change accordingly. E.g. `obao` for
-//! pre-order outboard files with a block size of 1024*2^0=1024 bytes.
+//! Stores can be configured to write complete outboards in post-order instead, see
+//! [`Store::set_outboard_format`]. Post-order outboard files have the extension
+//! `.pobao4`, and the last 8 bytes of the file are the little endian encoded size of
+//! the data. Both kinds of outboard files are read on startup, regardless of the
+//! configured format, and converted to pre-order in memory.
+//!
+//! In the future we might support other block sizes as well as in-order encoded trees.
+//! The file extension will then change accordingly. E.g. `obao` for pre-order outboard
+//! files with a block size of 1024*2^0=1024 bytes.
 //!
 //! For files that are smaller than the block size, the outboard file would just contain
 //! the size. Storing these outboard files is not necessary, and therefore they are not
@@ -88,14 +94,58 @@
 //! It is unusual but not impossible to have multiple partial data files for the same
 //! hash. In that case the best partial data file should be chosen on startup.
 //!
+//! ### Pins file
+//!
+//! The pins are stored in the complete directory, in a file named `70696e73.meta`, the
+//! hex encoded string `pins`. It contains a postcard serialized map from pin name to
+//! [`Pin`], and is rewritten whenever a pin is added or removed.
+//!
+//! A pins file that can not be parsed does not prevent the store from loading. It is
+//! moved aside to `70696e73.meta.corrupt` for inspection, and the store starts without
+//! pins.
+//!
+//! ### Write-ahead log
+//!
+//! Imports and completions of partial entries are recorded in a small write-ahead log in
+//! the complete directory, in a file named `77616c.meta`, the hex encoded string `wal`.
+//! Operations that were interrupted by a crash are replayed on startup: temp files of
+//! interrupted imports are removed, and the files of interrupted completions are moved
+//! into place and checked against their hash, or removed if the data is incomplete.
+//!
+//! ### Key file
+//!
+//! Encrypted stores keep their wrapped data key in the complete directory, in a file named
+//! `6b6579.meta`, the hex encoded string `key`. A store with a key file can only be loaded
+//! with [`Store::load_encrypted`] and a [`KeyWrapper`] that can unwrap the key.
+//!
+//! In an encrypted store, owned data files, outboard files and partial files are encrypted
+//! in the format described in [`super::encryption`]. Paths files, the pins file and the
+//! write-ahead log are not encrypted. Files stored externally are never written by the
+//! store, so imports into an encrypted store are always copied.
+//!
+//! ### Quarantined files
+//!
+//! Complete entries that failed validation are moved to the `quarantine` subdirectory
+//! of the complete directory, keeping their file names. Data stored externally stays
+//! where it is, only its paths file is moved. Files in this directory are not loaded.
+//!
+//! ### Trash
+//!
+//! Deleted complete entries are moved to the `trash` subdirectory of the complete
+//! directory, into a directory named after the hex encoded hash and the time of deletion
+//! in seconds since the unix epoch, e.g. `<hash>-1690000000`. The files keep their names,
+//! so an entry can be restored by moving them back. Directories that are older than the
+//! retention window of the store are removed by [`Store::purge_trash`], which also runs
+//! periodically.
+//!
 //! ### Temp files
 //!
 //! When copying data into the database, we first copy the data into a temporary file to
 //! ensure that the data is not modified while we compute the outboard. These files have
 //! just a hex encoded 16 byte random uuid as name, and the extension `.temp`.
 //!
-//! We don't know the hash of the data yet. These files are fully ephemeral, and can
-//! be deleted on restart.
+//! We don't know the hash of the data yet. These files are fully ephemeral, and are
+//! deleted on restart if the import that created them was interrupted.
 //!
 //! # File lifecycle
 //!
@@ -123,10 +173,11 @@
 //! to the final partial data and partial outboard files.
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bao_tree::io::outboard::{PostOrderMemOutboard, PreOrderOutboard};
 use bao_tree::io::sync::ReadAt;
@@ -135,20 +186,38 @@ use bao_tree::{BaoTree, ByteNum};
 use bytes::Bytes;
 use futures::future::BoxFuture;
 use futures::future::Either;
-use futures::{Future, FutureExt};
+use futures::{Future, FutureExt, TryFutureExt};
 use iroh_bytes::baomap::range_collections::RangeSet2;
 use iroh_bytes::baomap::{
-    self, ExportMode, ImportMode, ImportProgress, Map, MapEntry, PartialMap, PartialMapEntry,
-    ReadableStore, ValidateProgress,
+    self, ExportMode, ImportMode, ImportProgress, Map, MapEntry, PartialMap, PartialMapEntry, Pin,
+    ReadableStore, StoreEvent, ValidateProgress,
 };
+use iroh_bytes::protocol::RangeSpec;
 use iroh_bytes::util::progress::{IdGenerator, ProgressSender};
 use iroh_bytes::{Hash, IROH_BLOCK_SIZE};
-use iroh_io::{AsyncSliceReader, AsyncSliceWriter, File};
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
 use rand::Rng;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
 use tracing::trace_span;
 
+use super::cancel;
+use super::chunk_cache::ChunkCache;
+use super::coalesce::ReadCoalescer;
+use super::disk_space::{DiskSpaceEvent, DiskSpaceMonitor, Watermarks};
+use super::encryption::{
+    self, DataKey, DecryptingReader, EncryptedFile, EncryptingReader, KeyWrapper, WriteBuffer,
+};
 use super::flatten_to_io;
+use super::fsync::{self, FsyncPolicy, SyncingFile};
+use super::handle_cache::{CachedFile, FileKind, HandleCache, HandleLimits, SWEEP_INTERVAL};
+use super::outboard::{from_pre_order, to_pre_order, OutboardFormat};
+use super::outboard_hasher::OutboardHasher;
+use super::sparse;
+use super::uring::{self, IoBackend, Ring};
+use super::validation;
+use super::verified::{TrackedWriter, VerifiedRanges};
+use super::wal::{InFlight, Intent, Wal};
 
 #[derive(Debug, Default)]
 struct State {
@@ -160,6 +229,8 @@ struct State {
     outboard: BTreeMap<Hash, Bytes>,
     // data, cached for all complete entries that are small enough
     data: BTreeMap<Hash, Bytes>,
+    // pins by name
+    pins: BTreeMap<String, Pin>,
 }
 
 #[derive(Debug, Default)]
@@ -226,11 +297,22 @@ struct PartialEntryData {
     size: u64,
     // unique id for this entry
     uuid: [u8; 16],
+    // verified ranges of the data, shared by all handles to the entry
+    verified: VerifiedRanges,
+    // writes to the data and outboard files of an encrypted store, shared by all handles
+    data_buffer: WriteBuffer,
+    outboard_buffer: WriteBuffer,
 }
 
 impl PartialEntryData {
     fn new(size: u64, uuid: [u8; 16]) -> Self {
-        Self { size, uuid }
+        Self {
+            size,
+            uuid,
+            verified: VerifiedRanges::default(),
+            data_buffer: WriteBuffer::default(),
+            outboard_buffer: WriteBuffer::default(),
+        }
     }
 }
 
@@ -243,17 +325,34 @@ impl MapEntry<Store> for PartialEntry {
         self.size
     }
 
+    fn is_complete(&self) -> bool {
+        false
+    }
+
     fn available_ranges(&self) -> BoxFuture<'_, io::Result<RangeSet2<ChunkNum>>> {
-        futures::future::ok(RangeSet2::all()).boxed()
+        async move {
+            let outboard = self.outboard().await?;
+            let data = self.data_reader().await?;
+            self.verified.get(outboard, data, self.size).await
+        }
+        .boxed()
     }
 
     fn outboard(&self) -> BoxFuture<'_, io::Result<<Store as Map>::Outboard>> {
         async move {
-            let file = File::open(self.outboard_path.clone()).await?;
+            let file = self
+                .handles
+                .clone()
+                .open(
+                    self.hash.into(),
+                    FileKind::Outboard,
+                    self.outboard_path.clone(),
+                )
+                .await?;
             Ok(PreOrderOutboard {
                 root: self.hash,
                 tree: BaoTree::new(ByteNum(self.size), IROH_BLOCK_SIZE),
-                data: MemOrFile::File(file),
+                data: MemOrFile::partial_file(file, self.key.clone(), self.outboard_buffer.clone()),
             })
         }
         .boxed()
@@ -261,8 +360,17 @@ impl MapEntry<Store> for PartialEntry {
 
     fn data_reader(&self) -> BoxFuture<'_, io::Result<<Store as Map>::DataReader>> {
         async move {
-            let file = File::open(self.data_path.clone()).await?;
-            Ok(MemOrFile::File(file))
+            let file = self
+                .handles
+                .clone()
+                .open(self.hash.into(), FileKind::Data, self.data_path.clone())
+                .await?;
+            Ok(DataReader {
+                inner: MemOrFile::partial_file(file, self.key.clone(), self.data_buffer.clone()),
+                hash: self.hash.into(),
+                coalescer: None,
+                cache: None,
+            })
         }
         .boxed()
     }
@@ -274,19 +382,19 @@ impl PartialMapEntry<Store> for PartialEntry {
         let size = self.size;
         let tree = BaoTree::new(ByteNum(size), IROH_BLOCK_SIZE);
         let path = self.outboard_path.clone();
+        let sync_writes = self.fsync_policy.sync_writes();
+        let key = self.key.clone();
+        let buffer = self.outboard_buffer.clone();
+        let verified = self.verified.clone();
+        let ring = self.ring.clone();
         async move {
-            let mut writer = iroh_io::File::create(move || {
-                std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(path)
-            })
-            .await?;
+            let file = open_partial(ring, path, sync_writes).await?;
+            let mut writer = PartialFile::new(file, key, buffer);
             writer.write_at(0, &size.to_le_bytes()).await?;
             Ok(PreOrderOutboard {
                 root: hash,
                 tree,
-                data: writer,
+                data: TrackedWriter::outboard(writer, verified),
             })
         }
         .boxed()
@@ -294,20 +402,98 @@ impl PartialMapEntry<Store> for PartialEntry {
 
     fn data_writer(&self) -> BoxFuture<'_, io::Result<<Store as PartialMap>::DataWriter>> {
         let path = self.data_path.clone();
-        iroh_io::File::create(move || {
-            std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(path.clone())
-        })
+        let sync_writes = self.fsync_policy.sync_writes();
+        let key = self.key.clone();
+        let buffer = self.data_buffer.clone();
+        let verified = self.verified.clone();
+        let ring = self.ring.clone();
+        async move {
+            let file = open_partial(ring, path, sync_writes).await?;
+            let file = PartialFile::new(file, key, buffer);
+            Ok(TrackedWriter::data(file, verified))
+        }
         .boxed()
     }
 }
 
+/// Open or create a file of a partial entry for writing, through the ring if there is one.
+async fn open_partial(
+    ring: Option<Ring>,
+    path: PathBuf,
+    sync_writes: bool,
+) -> io::Result<SyncingFile> {
+    Ok(match ring {
+        Some(ring) => SyncingFile::uring(uring::File::create(ring, path).await?, sync_writes),
+        None => {
+            let file = iroh_io::File::create(move || {
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .open(path)
+            })
+            .await?;
+            SyncingFile::new(file, sync_writes)
+        }
+    })
+}
+
+/// A writer for the data or outboard of a [`PartialEntry`].
+#[derive(Debug)]
+pub enum PartialFile {
+    /// An unencrypted file
+    Plain(SyncingFile),
+    /// A file encrypted with the data key of the store
+    Encrypted(EncryptedFile<SyncingFile>),
+}
+
+impl PartialFile {
+    fn new(file: SyncingFile, key: Option<DataKey>, buffer: WriteBuffer) -> Self {
+        match key {
+            Some(key) => Self::Encrypted(EncryptedFile::with_buffer(file, key, buffer)),
+            None => Self::Plain(file),
+        }
+    }
+}
+
+impl AsyncSliceWriter for PartialFile {
+    type WriteAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Self::WriteAtFuture<'_> {
+        match self {
+            Self::Plain(file) => file.write_at(offset, data),
+            Self::Encrypted(file) => file.write_at(offset, data),
+        }
+    }
+
+    type WriteBytesAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_bytes_at(&mut self, offset: u64, data: Bytes) -> Self::WriteBytesAtFuture<'_> {
+        match self {
+            Self::Plain(file) => file.write_bytes_at(offset, data),
+            Self::Encrypted(file) => file.write_bytes_at(offset, data),
+        }
+    }
+
+    type SetLenFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn set_len(&mut self, len: u64) -> Self::SetLenFuture<'_> {
+        match self {
+            Self::Plain(file) => file.set_len(len),
+            Self::Encrypted(file) => file.set_len(len),
+        }
+    }
+
+    type SyncFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn sync(&mut self) -> Self::SyncFuture<'_> {
+        match self {
+            Self::Plain(file) => file.sync(),
+            Self::Encrypted(file) => file.sync(),
+        }
+    }
+}
+
 impl PartialMap for Store {
-    type OutboardMut = PreOrderOutboard<File>;
+    type OutboardMut = PreOrderOutboard<TrackedWriter<PartialFile>>;
 
-    type DataWriter = iroh_io::File;
+    type DataWriter = TrackedWriter<PartialFile>;
 
     type PartialEntry = PartialEntry;
 
@@ -318,11 +504,27 @@ impl PartialMap for Store {
             size: entry.size,
             data_path: self.0.options.partial_data_path(*hash, &entry.uuid),
             outboard_path: self.0.options.partial_outboard_path(*hash, &entry.uuid),
+            fsync_policy: self.fsync_policy(),
+            handles: self.0.handles.clone(),
+            key: self.0.options.key.clone(),
+            verified: entry.verified,
+            data_buffer: entry.data_buffer,
+            outboard_buffer: entry.outboard_buffer,
+            ring: self.0.ring.read().unwrap().clone(),
         })
     }
 
     fn get_or_create_partial(&self, hash: Hash, size: u64) -> io::Result<Self::PartialEntry> {
+        let exists = self.0.state.read().unwrap().partial.contains_key(&hash);
+        if !exists {
+            // only new entries are subject to admission control, existing ones are resumed
+            self.check_disk_space()?;
+        }
+        let fsync_policy = self.fsync_policy();
         let mut state = self.0.state.write().unwrap();
+        if !state.partial.contains_key(&hash) {
+            self.notify(StoreEvent::Added(hash));
+        }
         let entry = state.partial.entry(hash).or_insert_with(|| {
             let uuid = rand::thread_rng().gen::<[u8; 16]>();
             PartialEntryData::new(size, uuid)
@@ -334,32 +536,98 @@ impl PartialMap for Store {
             size: entry.size,
             data_path,
             outboard_path,
+            fsync_policy,
+            handles: self.0.handles.clone(),
+            key: self.0.options.key.clone(),
+            verified: entry.verified.clone(),
+            data_buffer: entry.data_buffer.clone(),
+            outboard_buffer: entry.outboard_buffer.clone(),
+            ring: self.0.ring.read().unwrap().clone(),
         })
     }
 
     fn insert_complete(&self, entry: Self::PartialEntry) -> BoxFuture<'_, io::Result<()>> {
         let hash = entry.hash.into();
         let data_path = self.0.options.owned_data_path(&hash);
+        // partial files are encrypted like complete files, so they can be moved into place
+        let key = self.0.options.key.as_ref();
         async move {
             let size = entry.size;
             let temp_data_path = entry.data_path;
             let temp_outboard_path = entry.outboard_path;
+            let sync = self.fsync_policy().sync_complete();
+            if sync {
+                let data = temp_data_path.clone();
+                let outboard = temp_outboard_path.clone();
+                self.0
+                    .options
+                    .rt
+                    .spawn_blocking(move || {
+                        fsync::sync_file(&data)?;
+                        if outboard.exists() {
+                            fsync::sync_file(&outboard)?;
+                        }
+                        Ok(())
+                    })
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+            }
+            let intent = self.begin(Intent::Complete {
+                hash,
+                size,
+                data: Some(temp_data_path.clone()),
+                outboard: Some(temp_outboard_path.clone()),
+            })?;
             // for a short time we will have neither partial nor complete
             self.0.state.write().unwrap().partial.remove(&hash);
+            // close the handles of the partial files
+            self.0.handles.invalidate(hash);
             tokio::fs::rename(temp_data_path, &data_path).await?;
             let outboard = if tokio::fs::try_exists(&temp_outboard_path).await? {
-                let outboard_path = self.0.options.owned_outboard_path(&hash);
-                tokio::fs::rename(temp_outboard_path, &outboard_path).await?;
-                Some(tokio::fs::read(&outboard_path).await?.into())
+                // partial outboards are always pre-order
+                match self.outboard_format() {
+                    OutboardFormat::PreOrder => {
+                        let outboard_path = self.0.options.owned_outboard_path(&hash);
+                        tokio::fs::rename(temp_outboard_path, &outboard_path).await?;
+                        let outboard = tokio::fs::read(&outboard_path).await?;
+                        Some(encryption::open(key, outboard)?.into())
+                    }
+                    format => {
+                        let outboard = tokio::fs::read(&temp_outboard_path).await?;
+                        let outboard = encryption::open(key, outboard)?;
+                        let converted = from_pre_order(format, &outboard)?;
+                        let outboard_path = self.0.options.owned_post_order_outboard_path(&hash);
+                        tokio::fs::write(&outboard_path, encryption::seal(key, &converted)).await?;
+                        if sync {
+                            tokio::fs::File::open(&outboard_path)
+                                .await?
+                                .sync_all()
+                                .await?;
+                        }
+                        tokio::fs::remove_file(temp_outboard_path).await?;
+                        Some(outboard.into())
+                    }
+                }
             } else {
                 None
             };
+            if sync {
+                let complete_path = self.0.options.complete_path.clone();
+                self.0
+                    .options
+                    .rt
+                    .spawn_blocking(move || fsync::sync_dir(&complete_path))
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+            }
+            intent.done()?;
             let mut state = self.0.state.write().unwrap();
             let entry = state.complete.entry(hash).or_default();
             entry.union_with(CompleteEntry::new_default(size))?;
             if let Some(outboard) = outboard {
                 state.outboard.insert(hash, outboard);
             }
+            self.notify(StoreEvent::Added(hash));
             Ok(())
         }
         .boxed()
@@ -373,6 +641,8 @@ struct Options {
     move_threshold: u64,
     inline_threshold: u64,
     rt: tokio::runtime::Handle,
+    // data key, if the store is encrypted
+    key: Option<DataKey>,
 }
 
 impl Options {
@@ -395,15 +665,53 @@ impl Options {
             .join(FileName::Outboard(*hash).to_string())
     }
 
+    fn owned_post_order_outboard_path(&self, hash: &Hash) -> PathBuf {
+        self.complete_path
+            .join(FileName::PostOrderOutboard(*hash).to_string())
+    }
+
     fn paths_path(&self, hash: Hash) -> PathBuf {
         self.complete_path.join(FileName::Paths(hash).to_string())
     }
+
+    fn pins_path(&self) -> PathBuf {
+        self.complete_path
+            .join(FileName::Meta(PINS_META.to_vec()).to_string())
+    }
+
+    fn trash_path(&self) -> PathBuf {
+        self.complete_path.join(TRASH_DIR)
+    }
 }
 
 #[derive(Debug)]
 struct Inner {
     options: Options,
     state: RwLock<State>,
+    // disk space admission control, if configured
+    disk_space: RwLock<Option<DiskSpaceMonitor>>,
+    disk_space_events: broadcast::Sender<DiskSpaceEvent>,
+    // entries added to or removed from the store
+    events: broadcast::Sender<StoreEvent>,
+    // format in which complete outboards are written
+    outboard_format: RwLock<OutboardFormat>,
+    // when to sync written files
+    fsync_policy: RwLock<FsyncPolicy>,
+    // log of in-flight operations, replayed on startup
+    wal: Wal,
+    // ring used to read complete data files and write partial entries, if the io_uring
+    // backend is used
+    ring: RwLock<Option<Ring>>,
+    // open read handles, shared between readers
+    handles: Arc<HandleCache>,
+    // coalescer for identical concurrent reads, if enabled
+    coalescer: RwLock<Option<Arc<ReadCoalescer>>>,
+    // cache for hot ranges of complete entries, if enabled
+    chunk_cache: RwLock<Option<Arc<ChunkCache>>>,
+    // hasher used to compute the outboards of imports
+    outboard_hasher: RwLock<OutboardHasher>,
+    // how long deleted entries are kept in the trash
+    trash_retention: RwLock<Duration>,
 }
 
 /// Flat file database implementation.
@@ -417,6 +725,10 @@ pub struct Entry {
     /// the hash is not part of the entry itself
     hash: blake3::Hash,
     entry: EntryData,
+    /// whether the entry is complete or partial
+    is_complete: bool,
+    /// the verified ranges of a partial entry
+    verified: Option<VerifiedRanges>,
 }
 
 impl MapEntry<Store> for Entry {
@@ -431,14 +743,26 @@ impl MapEntry<Store> for Entry {
         }
     }
 
+    fn is_complete(&self) -> bool {
+        self.is_complete
+    }
+
     fn available_ranges(&self) -> BoxFuture<'_, io::Result<RangeSet2<ChunkNum>>> {
-        futures::future::ok(RangeSet2::all()).boxed()
+        let Some(verified) = &self.verified else {
+            return futures::future::ok(RangeSet2::all()).boxed();
+        };
+        async move {
+            let outboard = self.outboard().await?;
+            let data = self.data_reader().await?;
+            verified.get(outboard, data, self.size()).await
+        }
+        .boxed()
     }
 
     fn outboard(&self) -> BoxFuture<'_, io::Result<PreOrderOutboard<MemOrFile>>> {
         async move {
             let size = self.entry.size();
-            let data = self.entry.outboard_reader().await?;
+            let data = self.entry.outboard_reader(self.hash.into()).await?;
             Ok(PreOrderOutboard {
                 root: self.hash,
                 tree: BaoTree::new(ByteNum(size), IROH_BLOCK_SIZE),
@@ -448,8 +772,23 @@ impl MapEntry<Store> for Entry {
         .boxed()
     }
 
-    fn data_reader(&self) -> BoxFuture<'_, io::Result<MemOrFile>> {
-        self.entry.data_reader().boxed()
+    fn data_reader(&self) -> BoxFuture<'_, io::Result<DataReader>> {
+        let hash = self.hash.into();
+        let coalescer = self.entry.coalescer.clone();
+        let cache = self.entry.cache.clone();
+        self.entry
+            .data_reader(hash)
+            .map_ok(move |inner| {
+                // only reads from disk are worth coalescing and caching
+                let from_disk = !matches!(inner, MemOrFile::Mem(_));
+                DataReader {
+                    inner,
+                    hash,
+                    coalescer: coalescer.filter(|_| from_disk),
+                    cache: cache.filter(|_| from_disk),
+                }
+            })
+            .boxed()
     }
 }
 
@@ -465,6 +804,20 @@ struct EntryData {
     data: Either<Bytes, (PathBuf, u64)>,
     /// The bao outboard data.
     outboard: Either<Bytes, PathBuf>,
+    /// The ring to read the data file with, if any.
+    ///
+    /// Only set for complete entries, since the ring relies on the file size not changing.
+    ring: Option<Ring>,
+    /// The open handles of the store.
+    handles: Arc<HandleCache>,
+    /// The coalescer for reads of the data, if any.
+    coalescer: Option<Arc<ReadCoalescer>>,
+    /// The cache for reads of the data, if any.
+    cache: Option<Arc<ChunkCache>>,
+    /// The key the data and outboard files are encrypted with, if any.
+    ///
+    /// Not set for data stored externally, which is never encrypted.
+    key: Option<DataKey>,
 }
 
 /// A reader for either a file or a byte slice.
@@ -474,55 +827,178 @@ struct EntryData {
 pub enum MemOrFile {
     /// We got it all in memory
     Mem(Bytes),
-    /// An iroh_io::File
-    File(File),
+    /// A file handle, possibly shared with other readers
+    File(CachedFile),
+    /// A file read through an io_uring
+    Uring(uring::File),
+    /// A file encrypted with the data key of the store
+    Encrypted(EncryptedFile<CachedFile>),
+}
+
+impl MemOrFile {
+    /// A reader for a file of a complete entry, decrypting it if there is a key.
+    fn file(file: Arc<std::fs::File>, key: Option<DataKey>) -> Self {
+        let file = CachedFile::fixed(file);
+        match key {
+            Some(key) => MemOrFile::Encrypted(EncryptedFile::new(file, key)),
+            None => MemOrFile::File(file),
+        }
+    }
+
+    /// A reader for a file of a partial entry, which sees the writes buffered in `buffer`.
+    fn partial_file(file: Arc<std::fs::File>, key: Option<DataKey>, buffer: WriteBuffer) -> Self {
+        let file = CachedFile::new(file);
+        match key {
+            Some(key) => MemOrFile::Encrypted(EncryptedFile::with_buffer(file, key, buffer)),
+            None => MemOrFile::File(file),
+        }
+    }
 }
 
 impl AsyncSliceReader for MemOrFile {
     type ReadAtFuture<'a> = futures::future::Either<
         <Bytes as AsyncSliceReader>::ReadAtFuture<'a>,
-        <File as AsyncSliceReader>::ReadAtFuture<'a>,
+        futures::future::Either<
+            <CachedFile as AsyncSliceReader>::ReadAtFuture<'a>,
+            futures::future::Either<
+                <uring::File as AsyncSliceReader>::ReadAtFuture<'a>,
+                <EncryptedFile<CachedFile> as AsyncSliceReader>::ReadAtFuture<'a>,
+            >,
+        >,
     >;
 
     fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
         match self {
             MemOrFile::Mem(mem) => Either::Left(mem.read_at(offset, len)),
-            MemOrFile::File(file) => Either::Right(file.read_at(offset, len)),
+            MemOrFile::File(file) => Either::Right(Either::Left(file.read_at(offset, len))),
+            MemOrFile::Uring(file) => {
+                Either::Right(Either::Right(Either::Left(file.read_at(offset, len))))
+            }
+            MemOrFile::Encrypted(file) => {
+                Either::Right(Either::Right(Either::Right(file.read_at(offset, len))))
+            }
         }
     }
 
     type LenFuture<'a> = futures::future::Either<
         <Bytes as AsyncSliceReader>::LenFuture<'a>,
-        <File as AsyncSliceReader>::LenFuture<'a>,
+        futures::future::Either<
+            <CachedFile as AsyncSliceReader>::LenFuture<'a>,
+            futures::future::Either<
+                <uring::File as AsyncSliceReader>::LenFuture<'a>,
+                <EncryptedFile<CachedFile> as AsyncSliceReader>::LenFuture<'a>,
+            >,
+        >,
     >;
 
     fn len(&mut self) -> Self::LenFuture<'_> {
         match self {
             MemOrFile::Mem(mem) => Either::Left(mem.len()),
-            MemOrFile::File(file) => Either::Right(file.len()),
+            MemOrFile::File(file) => Either::Right(Either::Left(file.len())),
+            MemOrFile::Uring(file) => Either::Right(Either::Right(Either::Left(file.len()))),
+            MemOrFile::Encrypted(file) => Either::Right(Either::Right(Either::Right(file.len()))),
         }
     }
 }
 
+/// The data reader of a [`Store`] entry.
+///
+/// Reads of complete entries from disk are served from the [`ChunkCache`] of the store
+/// if possible, and otherwise go through its [`ReadCoalescer`], so identical concurrent
+/// reads share a single read from disk. Both are optional.
+#[derive(Debug)]
+pub struct DataReader {
+    inner: MemOrFile,
+    hash: Hash,
+    coalescer: Option<Arc<ReadCoalescer>>,
+    cache: Option<Arc<ChunkCache>>,
+}
+
+impl AsyncSliceReader for DataReader {
+    type ReadAtFuture<'a> = BoxFuture<'a, io::Result<Bytes>>;
+
+    fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
+        let Self {
+            inner,
+            hash,
+            coalescer,
+            cache,
+        } = self;
+        if coalescer.is_none() && cache.is_none() {
+            return inner.read_at(offset, len).boxed();
+        }
+        let hash = *hash;
+        let coalescer = coalescer.clone();
+        let cache = cache.clone();
+        async move {
+            if let Some(data) = cache.as_ref().and_then(|c| c.get(hash, offset, len)) {
+                return Ok(data);
+            }
+            let data = match coalescer {
+                Some(coalescer) => {
+                    let read = move || {
+                        // move the reference into the closure, so the read can borrow it
+                        let inner = inner;
+                        inner.read_at(offset, len)
+                    };
+                    coalescer.read(hash, offset, len, read).await?
+                }
+                None => inner.read_at(offset, len).await?,
+            };
+            if let Some(cache) = cache {
+                cache.insert(hash, offset, len, data.clone());
+            }
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    type LenFuture<'a> = <MemOrFile as AsyncSliceReader>::LenFuture<'a>;
+
+    fn len(&mut self) -> Self::LenFuture<'_> {
+        self.inner.len()
+    }
+}
+
 impl EntryData {
     /// Get the outboard data for this entry, as a `Bytes`.
-    pub fn outboard_reader(&self) -> impl Future<Output = io::Result<MemOrFile>> + 'static {
+    pub fn outboard_reader(
+        &self,
+        hash: Hash,
+    ) -> impl Future<Output = io::Result<MemOrFile>> + 'static {
         let outboard = self.outboard.clone();
+        let handles = self.handles.clone();
+        let key = self.key.clone();
         async move {
             Ok(match outboard {
                 Either::Left(mem) => MemOrFile::Mem(mem),
-                Either::Right(path) => MemOrFile::File(File::open(path).await?),
+                Either::Right(path) => {
+                    let file = handles.open(hash, FileKind::Outboard, path).await?;
+                    MemOrFile::file(file, key)
+                }
             })
         }
     }
 
     /// A reader for the data.
-    pub fn data_reader(&self) -> impl Future<Output = io::Result<MemOrFile>> + 'static {
+    pub fn data_reader(&self, hash: Hash) -> impl Future<Output = io::Result<MemOrFile>> + 'static {
         let data = self.data.clone();
+        let ring = self.ring.clone();
+        let handles = self.handles.clone();
+        let key = self.key.clone();
         async move {
             Ok(match data {
                 Either::Left(mem) => MemOrFile::Mem(mem),
-                Either::Right((path, _)) => MemOrFile::File(File::open(path).await?),
+                Either::Right((path, size)) => {
+                    let file = handles.open(hash, FileKind::Data, path).await?;
+                    match ring {
+                        // encrypted files are decrypted by segment, not read through the ring
+                        Some(ring) if key.is_none() => {
+                            MemOrFile::Uring(uring::File::from_std(ring, file, size))
+                        }
+                        _ => MemOrFile::file(file, key),
+                    }
+                }
             })
         }
     }
@@ -547,12 +1023,20 @@ pub struct PartialEntry {
     size: u64,
     data_path: PathBuf,
     outboard_path: PathBuf,
+    fsync_policy: FsyncPolicy,
+    handles: Arc<HandleCache>,
+    key: Option<DataKey>,
+    verified: VerifiedRanges,
+    data_buffer: WriteBuffer,
+    outboard_buffer: WriteBuffer,
+    // ring the files are written through, if the io_uring backend is used
+    ring: Option<Ring>,
 }
 
 impl Map for Store {
     type Entry = Entry;
     type Outboard = PreOrderOutboard<MemOrFile>;
-    type DataReader = MemOrFile;
+    type DataReader = DataReader;
     fn get(&self, hash: &Hash) -> Option<Self::Entry> {
         let state = self.0.state.read().unwrap();
         if let Some(entry) = state.complete.get(hash) {
@@ -578,7 +1062,14 @@ impl Map for Store {
                         Either::Right((path, entry.size))
                     },
                     outboard: Either::Left(outboard),
+                    ring: self.0.ring.read().unwrap().clone(),
+                    handles: self.0.handles.clone(),
+                    coalescer: self.0.coalescer.read().unwrap().clone(),
+                    cache: self.0.chunk_cache.read().unwrap().clone(),
+                    key: self.0.options.key.clone().filter(|_| entry.owned_data),
                 },
+                is_complete: true,
+                verified: None,
             })
         } else if let Some(entry) = state.partial.get(hash) {
             let data_path = self.0.options.partial_data_path(*hash, &entry.uuid);
@@ -594,13 +1085,26 @@ impl Map for Store {
                 entry: EntryData {
                     data: Either::Right((data_path, entry.size)),
                     outboard: Either::Right(outboard_path),
+                    ring: None,
+                    handles: self.0.handles.clone(),
+                    coalescer: None,
+                    cache: None,
+                    key: self.0.options.key.clone(),
                 },
+                is_complete: false,
+                verified: Some(entry.verified.clone()),
             })
         } else {
             tracing::trace!("got none {}", hash);
             None
         }
     }
+
+    fn shrink_caches(&self) {
+        if let Some(cache) = self.0.chunk_cache.read().unwrap().as_ref() {
+            cache.clear();
+        }
+    }
 }
 
 impl ReadableStore for Store {
@@ -611,11 +1115,20 @@ impl ReadableStore for Store {
     }
 
     fn roots(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
-        unimplemented!()
+        let now = SystemTime::now();
+        let state = self.0.state.read().unwrap();
+        let roots = state
+            .pins
+            .values()
+            .filter(|pin| pin.recursive && !pin.is_expired(now))
+            .filter(|pin| state.complete.contains_key(&pin.hash))
+            .map(|pin| pin.hash)
+            .collect::<BTreeSet<_>>();
+        Box::new(roots.into_iter())
     }
 
-    fn validate(&self, _tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>> {
-        unimplemented!()
+    fn validate(&self, tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>> {
+        validation::validate_store(self, tx).boxed()
     }
 
     fn partial_blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
@@ -624,6 +1137,16 @@ impl ReadableStore for Store {
         Box::new(res.into_iter())
     }
 
+    fn pins(&self) -> Box<dyn Iterator<Item = (String, Pin)> + Send + Sync + 'static> {
+        let lock = self.0.state.read().unwrap();
+        let res = lock.pins.clone();
+        Box::new(res.into_iter())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<StoreEvent> {
+        self.0.events.subscribe()
+    }
+
     fn export(
         &self,
         hash: Hash,
@@ -632,12 +1155,10 @@ impl ReadableStore for Store {
         progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
     ) -> BoxFuture<'_, io::Result<()>> {
         let this = self.clone();
-        self.0
-            .options
-            .rt
-            .spawn_blocking(move || this.export_sync(hash, target, mode, progress))
-            .map(flatten_to_io)
-            .boxed()
+        cancel::spawn_blocking(&self.0.options.rt, move |cancel| {
+            this.export_sync(hash, target, mode, progress, cancel)
+        })
+        .boxed()
     }
 }
 
@@ -648,24 +1169,84 @@ impl baomap::Store for Store {
         mode: ImportMode,
         progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
     ) -> BoxFuture<'_, io::Result<(Hash, u64)>> {
+        let this = self.clone();
+        cancel::spawn_blocking(&self.0.options.rt, move |cancel| {
+            this.import_sync(path, mode, progress, cancel)
+        })
+        .boxed()
+    }
+
+    fn import_bytes(&self, data: Bytes) -> BoxFuture<'_, io::Result<Hash>> {
         let this = self.clone();
         self.0
             .options
             .rt
-            .spawn_blocking(move || this.import_sync(path, mode, progress))
+            .spawn_blocking(move || this.import_bytes_sync(data))
             .map(flatten_to_io)
             .boxed()
     }
 
-    fn import_bytes(&self, data: Bytes) -> BoxFuture<'_, io::Result<Hash>> {
+    fn set_pin(&self, name: String, pin: Pin) -> BoxFuture<'_, io::Result<()>> {
         let this = self.clone();
         self.0
             .options
             .rt
-            .spawn_blocking(move || this.import_bytes_sync(data))
+            .spawn_blocking(move || {
+                this.update_pins(|pins| {
+                    pins.insert(name, pin);
+                })
+            })
             .map(flatten_to_io)
             .boxed()
     }
+
+    fn remove_pin(&self, name: String) -> BoxFuture<'_, io::Result<Option<Pin>>> {
+        let this = self.clone();
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.update_pins(|pins| pins.remove(&name)))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn quarantine(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.quarantine_sync(hash))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn delete(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let this = self.clone();
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.delete_sync(hash))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn restore(&self, hash: Hash) -> BoxFuture<'_, io::Result<bool>> {
+        let this = self.clone();
+        self.0
+            .options
+            .rt
+            .spawn_blocking(move || this.restore_sync(hash))
+            .map(flatten_to_io)
+            .boxed()
+    }
+
+    fn export_partial(&self, hash: Hash, target: PathBuf) -> BoxFuture<'_, io::Result<()>> {
+        self.export_partial_impl(hash, target).boxed()
+    }
+
+    fn import_partial(&self, path: PathBuf) -> BoxFuture<'_, io::Result<Hash>> {
+        self.import_partial_impl(path).boxed()
+    }
 }
 
 impl State {
@@ -683,11 +1264,258 @@ impl State {
 }
 
 impl Store {
+    /// Update the pins and persist them.
+    ///
+    /// The state lock is held while writing, so concurrent updates are persisted in order.
+    /// The file is replaced atomically, so a crash never leaves a truncated pin file.
+    fn update_pins<T>(&self, f: impl FnOnce(&mut BTreeMap<String, Pin>) -> T) -> io::Result<T> {
+        let mut state = self.0.state.write().unwrap();
+        let mut pins = state.pins.clone();
+        let res = f(&mut pins);
+        let data = postcard::to_stdvec(&pins)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let sync = self.fsync_policy().sync_complete();
+        fsync::replace_file(&self.0.options.pins_path(), &data, sync)?;
+        state.pins = pins;
+        self.notify(StoreEvent::PinsChanged);
+        Ok(res)
+    }
+
+    fn quarantine_sync(&self, hash: Hash) -> io::Result<()> {
+        let target = self.0.options.complete_path.join(QUARANTINE_DIR);
+        if self.remove_complete_sync(hash, &target)? {
+            tracing::warn!("quarantined {}", hash);
+        }
+        Ok(())
+    }
+
+    fn delete_sync(&self, hash: Hash) -> io::Result<()> {
+        let now = SystemTime::now();
+        if let Some(pin) = self.protecting_pin(&hash, now) {
+            return Err(Pin::protected_error(&hash, &pin));
+        }
+        let name = trash_name(hash, now);
+        let target = self.0.options.trash_path().join(name);
+        if self.remove_complete_sync(hash, &target)? {
+            tracing::info!("moved {} to the trash", hash);
+        }
+        self.purge_trash()?;
+        Ok(())
+    }
+
+    /// The name of an unexpired pin of `hash`.
+    fn protecting_pin(&self, hash: &Hash, now: SystemTime) -> Option<String> {
+        let state = self.0.state.read().unwrap();
+        let mut pins = state.pins.iter();
+        let (name, _) = pins.find(|(_, pin)| pin.protects(hash, now))?;
+        Some(name.clone())
+    }
+
+    fn restore_sync(&self, hash: Hash) -> io::Result<bool> {
+        let latest = self
+            .trash_entries()?
+            .into_iter()
+            .filter(|(h, _, _)| *h == hash)
+            .max_by_key(|(_, deleted, _)| *deleted);
+        let Some((_, _, dir)) = latest else {
+            return Ok(false);
+        };
+        let exists = self.0.state.read().unwrap().complete.contains_key(&hash);
+        if !exists {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let target = self.0.options.complete_path.join(entry.file_name());
+                std::fs::rename(entry.path(), target)?;
+            }
+            let (new, outboard) = self.read_complete_sync(hash)?;
+            let mut state = self.0.state.write().unwrap();
+            state.complete.entry(hash).or_default().union_with(new)?;
+            if let Some(outboard) = outboard {
+                state.outboard.insert(hash, outboard);
+            }
+            self.notify(StoreEvent::Added(hash));
+        }
+        std::fs::remove_dir_all(dir)?;
+        tracing::info!("restored {} from the trash", hash);
+        Ok(true)
+    }
+
+    /// Read a complete entry from the files in the complete directory.
+    fn read_complete_sync(&self, hash: Hash) -> io::Result<(CompleteEntry, Option<Bytes>)> {
+        let options = &self.0.options;
+        let key = options.key.as_ref();
+        let data_path = options.owned_data_path(&hash);
+        let paths_path = options.paths_path(hash);
+        let external: BTreeSet<PathBuf> = if paths_path.exists() {
+            postcard::from_bytes(&std::fs::read(paths_path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        } else {
+            Default::default()
+        };
+        let owned_data = data_path.exists();
+        let size = if owned_data {
+            encryption::file_len(key, std::fs::metadata(&data_path)?.len())
+        } else if let Some(path) = external.iter().next() {
+            std::fs::metadata(path)?.len()
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "neither internal nor external file exists",
+            ));
+        };
+        let outboard = if needs_outboard(size) {
+            let pre_order_path = options.owned_outboard_path(&hash);
+            let (path, format) = if pre_order_path.exists() {
+                (pre_order_path, OutboardFormat::PreOrder)
+            } else {
+                let path = options.owned_post_order_outboard_path(&hash);
+                (path, OutboardFormat::PostOrder)
+            };
+            let outboard = encryption::open(key, std::fs::read(path)?)?;
+            Some(to_pre_order(format, &outboard)?.into())
+        } else {
+            None
+        };
+        let entry = CompleteEntry {
+            size,
+            owned_data,
+            external,
+        };
+        Ok((entry, outboard))
+    }
+
+    /// Export the verified ranges of a partial entry to a sparse file at `target`, with a
+    /// sidecar file that holds the outboard, so that it can be imported to resume later.
+    async fn export_partial_impl(&self, hash: Hash, target: PathBuf) -> io::Result<()> {
+        let entry = self.get_partial(&hash).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no partial entry for {hash}"),
+            )
+        })?;
+        let ranges = entry.available_ranges().await?;
+        let mut outboard = entry.outboard().await?.data;
+        let outboard_size = outboard.len().await?;
+        let outboard = outboard.read_at(0, outboard_size as usize).await?;
+        let mut data = entry.data_reader().await?;
+        let path = target.clone();
+        let mut file = iroh_io::File::create(move || std::fs::File::create(path)).await?;
+        file.set_len(entry.size).await?;
+        sparse::copy_ranges(&mut data, &mut file, &ranges, entry.size).await?;
+        file.sync().await?;
+        let sidecar = sparse::Sidecar {
+            hash,
+            size: entry.size,
+            ranges: RangeSpec::new(&ranges),
+            outboard: outboard.to_vec(),
+        };
+        sidecar.write(&target).await
+    }
+
+    /// Import a sparse file written by [`Self::export_partial_impl`] as a partial entry,
+    /// after verifying the ranges it claims to contain.
+    async fn import_partial_impl(&self, path: PathBuf) -> io::Result<Hash> {
+        let sidecar = sparse::Sidecar::read(&path).await?;
+        let hash = sidecar.hash;
+        if self.0.state.read().unwrap().complete.contains_key(&hash) {
+            return Ok(hash);
+        }
+        if self.get_partial(&hash).is_some() {
+            // the existing entry might be further along, we don't merge them
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("a partial entry for {hash} already exists"),
+            ));
+        }
+        let path2 = path.clone();
+        let mut source = iroh_io::File::create(move || std::fs::File::open(path2)).await?;
+        if source.len().await? != sidecar.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sparse file does not match the size of the blob",
+            ));
+        }
+        sidecar.verify(&mut source).await?;
+        let entry = self.get_or_create_partial(hash, sidecar.size)?;
+        let mut data = entry.data_writer().await?;
+        let ranges = sidecar.chunk_ranges();
+        sparse::copy_ranges(&mut source, &mut data, &ranges, sidecar.size).await?;
+        data.sync().await?;
+        let mut outboard = entry.outboard_mut().await?.data;
+        outboard
+            .write_bytes_at(0, Bytes::from(sidecar.outboard))
+            .await?;
+        outboard.sync().await?;
+        tracing::info!("imported partial entry {} from {}", hash, path.display());
+        Ok(hash)
+    }
+
+    /// The entries in the trash, with the time they were deleted and their directory.
+    fn trash_entries(&self) -> io::Result<Vec<(Hash, SystemTime, PathBuf)>> {
+        let dirs = match std::fs::read_dir(self.0.options.trash_path()) {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for dir in dirs {
+            let path = dir?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+            let Some((hash, deleted)) = name.and_then(parse_trash_name) else {
+                tracing::warn!("skipping unexpected trash entry: {:?}", path);
+                continue;
+            };
+            entries.push((hash, deleted, path));
+        }
+        Ok(entries)
+    }
+
+    /// Remove a complete entry, moving the files owned by the store to `target`.
+    ///
+    /// Returns false if there is no such entry.
+    fn remove_complete_sync(&self, hash: Hash, target: &Path) -> io::Result<bool> {
+        let options = &self.0.options;
+        let mut state = self.0.state.write().unwrap();
+        let Some(entry) = state.complete.remove(&hash) else {
+            return Ok(false);
+        };
+        state.outboard.remove(&hash);
+        state.data.remove(&hash);
+        drop(state);
+        self.notify(StoreEvent::Removed(hash));
+        self.0.handles.invalidate(hash);
+        if let Some(cache) = self.0.chunk_cache.read().unwrap().as_ref() {
+            cache.invalidate(hash);
+        }
+        std::fs::create_dir_all(target)?;
+        let owned = [
+            options.owned_data_path(&hash),
+            options.owned_outboard_path(&hash),
+            options.owned_post_order_outboard_path(&hash),
+        ];
+        for path in owned {
+            if path.exists() {
+                let name = path.file_name().expect("owned paths have a file name");
+                tracing::debug!("moving {} to {}", path.display(), target.display());
+                std::fs::rename(&path, target.join(name))?;
+            }
+        }
+        // external files are not ours to move, just stop referencing them
+        if !entry.external.is_empty() {
+            let paths_path = options.paths_path(hash);
+            if paths_path.exists() {
+                std::fs::rename(&paths_path, target.join(FileName::Paths(hash).to_string()))?;
+            }
+        }
+        Ok(true)
+    }
+
     fn import_sync(
         self,
         path: PathBuf,
         mode: ImportMode,
         progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
+        cancel: CancellationToken,
     ) -> io::Result<(Hash, u64)> {
         if !path.is_absolute() {
             return Err(io::Error::new(
@@ -701,25 +1529,34 @@ impl Store {
                 "path is not a file or symlink",
             ));
         }
+        self.check_disk_space()?;
         let id = progress.new_id();
         progress.blocking_send(ImportProgress::Found {
             id,
             path: path.clone(),
         })?;
-        let (hash, new, outboard) = match mode {
+        // external files can not be encrypted, so encrypted stores always copy
+        let mode = match self.0.options.key {
+            Some(_) => ImportMode::Copy,
+            None => mode,
+        };
+        let (hash, new, outboard, intent) = match mode {
             ImportMode::TryReference => {
                 // compute outboard and hash from the data in place, since we assume that it is stable
                 let size = path.metadata()?.len();
                 progress.blocking_send(ImportProgress::Size { id, size })?;
                 let progress2 = progress.clone();
-                let (hash, outboard) = compute_outboard(&path, size, move |offset| {
+                let on_progress = cancel::progress(cancel, move |offset| {
                     Ok(progress2.try_send(ImportProgress::OutboardProgress { id, offset })?)
-                })?;
+                });
+                let hasher = self.outboard_hasher();
+                let (hash, outboard) = compute_outboard(&hasher, &path, size, on_progress)?;
                 progress.blocking_send(ImportProgress::OutboardDone { id, hash })?;
                 (
                     hash,
                     CompleteEntry::new_external(size, path.clone()),
                     outboard,
+                    None,
                 )
             }
             ImportMode::Copy => {
@@ -729,51 +1566,132 @@ impl Store {
                     .options
                     .partial_path
                     .join(format!("{}.temp", hex::encode(uuid)));
+                let import = self.begin(Intent::Import {
+                    temp: temp_data_path.clone(),
+                })?;
                 // copy the data, since it is not stable
                 progress.try_send(ImportProgress::CopyProgress { id, offset: 0 })?;
-                let size = std::fs::copy(&path, &temp_data_path)?;
-                // report the size only after the copy is done
-                progress.blocking_send(ImportProgress::Size { id, size })?;
-                // compute outboard and hash from the temp file that we own
                 let progress2 = progress.clone();
-                let (hash, outboard) = compute_outboard(&temp_data_path, size, move |offset| {
+                let on_progress = cancel::progress(cancel.clone(), move |offset| {
                     Ok(progress2.try_send(ImportProgress::OutboardProgress { id, offset })?)
-                })?;
+                });
+                let copy = || -> io::Result<_> {
+                    match self.0.options.key.clone() {
+                        Some(key) => {
+                            // hash the data while encrypting it, so the hash is of the stored data
+                            let size = path.metadata()?.len();
+                            progress.blocking_send(ImportProgress::Size { id, size })?;
+                            let source = std::fs::File::open(&path)?.take(size);
+                            let target =
+                                io::BufWriter::new(std::fs::File::create(&temp_data_path)?);
+                            let mut reader = EncryptingReader::new(source, target, key);
+                            let (hash, outboard) =
+                                compute_outboard_sequential(&mut reader, size, on_progress)?;
+                            reader.finish()?.flush()?;
+                            if self.fsync_policy().sync_complete() {
+                                fsync::sync_file(&temp_data_path)?;
+                            }
+                            Ok((size, hash, outboard))
+                        }
+                        None => {
+                            let size = std::fs::copy(&path, &temp_data_path)?;
+                            cancel::check(&cancel)?;
+                            if self.fsync_policy().sync_complete() {
+                                fsync::sync_file(&temp_data_path)?;
+                            }
+                            // report the size only after the copy is done
+                            progress.blocking_send(ImportProgress::Size { id, size })?;
+                            // compute outboard and hash from the temp file that we own
+                            let hasher = self.outboard_hasher();
+                            let (hash, outboard) =
+                                compute_outboard(&hasher, &temp_data_path, size, on_progress)?;
+                            Ok((size, hash, outboard))
+                        }
+                    }
+                };
+                let (size, hash, outboard) = match copy() {
+                    Ok(res) => res,
+                    Err(cause) => {
+                        // nothing refers to the temp file yet, so clean up right away
+                        // instead of leaving it to the replay on the next start
+                        std::fs::remove_file(&temp_data_path).ok();
+                        import.done()?;
+                        return Err(cause);
+                    }
+                };
                 progress.blocking_send(ImportProgress::OutboardDone { id, hash })?;
+                let intent = self.begin(Intent::Complete {
+                    hash,
+                    size,
+                    data: Some(temp_data_path.clone()),
+                    outboard: None,
+                })?;
+                import.done()?;
                 let data_path = self.owned_data_path(&hash);
                 std::fs::rename(temp_data_path, data_path)?;
-                (hash, CompleteEntry::new_default(size), outboard)
+                (
+                    hash,
+                    CompleteEntry::new_default(size),
+                    outboard,
+                    Some(intent),
+                )
             }
         };
         if let Some(outboard) = outboard.as_ref() {
-            let outboard_path = self.owned_outboard_path(&hash);
-            std::fs::write(outboard_path, outboard)?;
+            self.write_outboard(&hash, outboard)?;
+        }
+        if let Some(intent) = intent {
+            if self.fsync_policy().sync_complete() {
+                fsync::sync_dir(&self.0.options.complete_path)?;
+            }
+            intent.done()?;
         }
         let size = new.size;
-        let mut state = self.0.state.write().unwrap();
-        let entry = state.complete.entry(hash).or_default();
-        let n = entry.external.len();
-        entry.union_with(new)?;
-        if entry.external.len() != n {
-            let path = self.0.options.paths_path(hash);
-            std::fs::write(path, entry.external_to_bytes())?;
-        }
-        if let Some(outboard) = outboard {
-            state.outboard.insert(hash, outboard.into());
+        let deduplicated = {
+            let mut state = self.0.state.write().unwrap();
+            let deduplicated = state.complete.contains_key(&hash);
+            let entry = state.complete.entry(hash).or_default();
+            let n = entry.external.len();
+            entry.union_with(new)?;
+            if entry.external.len() != n {
+                let path = self.0.options.paths_path(hash);
+                let sync = self.fsync_policy().sync_complete();
+                fsync::write_file(&path, &entry.external_to_bytes(), sync)?;
+            }
+            if let Some(outboard) = outboard {
+                state.outboard.insert(hash, outboard.into());
+            }
+            deduplicated
+        };
+        self.notify(StoreEvent::Added(hash));
+        if deduplicated {
+            progress.blocking_send(ImportProgress::Deduplicated { id, size })?;
         }
         Ok((hash, size))
     }
 
     fn import_bytes_sync(&self, data: Bytes) -> io::Result<Hash> {
-        let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        self.check_disk_space()?;
+        let (outboard, hash) = self.outboard_hasher().outboard(&data);
         let hash = hash.into();
+        let size = data.len() as u64;
+        let sync = self.fsync_policy().sync_complete();
+        let intent = self.begin(Intent::Complete {
+            hash,
+            size,
+            data: None,
+            outboard: None,
+        })?;
         let data_path = self.owned_data_path(&hash);
-        std::fs::write(data_path, &data)?;
+        let key = self.0.options.key.as_ref();
+        fsync::write_file(&data_path, &encryption::seal(key, &data), sync)?;
         if outboard.len() > 8 {
-            let outboard_path = self.owned_outboard_path(&hash);
-            std::fs::write(outboard_path, &outboard)?;
+            self.write_outboard(&hash, &outboard)?;
         }
-        let size = data.len() as u64;
+        if sync {
+            fsync::sync_dir(&self.0.options.complete_path)?;
+        }
+        intent.done()?;
         let mut state = self.0.state.write().unwrap();
         let entry = state.complete.entry(hash).or_default();
         entry.union_with(CompleteEntry::new_default(size))?;
@@ -781,6 +1699,7 @@ impl Store {
         if size < self.0.options.inline_threshold {
             state.data.insert(hash, data.to_vec().into());
         }
+        self.notify(StoreEvent::Added(hash));
         Ok(hash)
     }
 
@@ -790,6 +1709,7 @@ impl Store {
         target: PathBuf,
         mode: ExportMode,
         progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
+        cancel: CancellationToken,
     ) -> io::Result<()> {
         tracing::trace!("exporting {} to {} ({:?})", hash, target.display(), mode);
 
@@ -825,9 +1745,13 @@ impl Store {
             let size = entry.size;
             (source, size, entry.owned_data)
         };
+        // owned data of encrypted stores has to be decrypted, so it is never moved
+        let key = self.0.options.key.as_ref().filter(|_| owned);
+        let movable = owned && key.is_none();
+        cancel::check(&cancel)?;
         // copy all the things
         let stable = mode == ExportMode::TryReference;
-        let path_bytes = if size >= self.0.options.move_threshold && stable && owned {
+        let path_bytes = if size >= self.0.options.move_threshold && stable && movable {
             tracing::info!("moving {} to {}", source.display(), target.display());
             if let Err(e) = std::fs::rename(source, &target) {
                 tracing::error!("rename failed: {}", e);
@@ -845,9 +1769,14 @@ impl Store {
             Some(entry.external_to_bytes())
         } else {
             tracing::info!("copying {} to {}", source.display(), target.display());
-            progress(0)?;
-            // todo: progress
-            std::fs::copy(&source, &target)?;
+            let file = std::fs::File::open(&source)?;
+            match key {
+                Some(key) => {
+                    let reader = DecryptingReader::new(file, key.clone());
+                    cancel::copy_to_file(reader, &target, &cancel, &progress)?
+                }
+                None => cancel::copy_to_file(file, &target, &cancel, &progress)?,
+            };
             progress(size)?;
             let mut state = self.0.state.write().unwrap();
             let Some(entry) = state.complete.get_mut(&hash) else {
@@ -865,7 +1794,8 @@ impl Store {
         };
         if let Some(path_bytes) = path_bytes {
             let pp = self.paths_path(hash);
-            std::fs::write(pp, path_bytes)?;
+            let sync = self.fsync_policy().sync_complete();
+            fsync::write_file(&pp, &path_bytes, sync)?;
         }
         Ok(())
     }
@@ -875,16 +1805,64 @@ impl Store {
         complete_path: PathBuf,
         partial_path: PathBuf,
         rt: iroh_bytes::util::runtime::Handle,
+        wrapper: Option<&dyn KeyWrapper>,
     ) -> anyhow::Result<Self> {
         tracing::info!(
             "loading database from {} {}",
             complete_path.display(),
             partial_path.display()
         );
+        let wal_path = complete_path.join(FileName::Meta(WAL_META.to_vec()).to_string());
+        let key_path = complete_path.join(FileName::Meta(KEY_META.to_vec()).to_string());
+        let key = match wrapper {
+            Some(wrapper) => {
+                if !key_path.exists() {
+                    // existing files would be read as encrypted, and fail to decrypt
+                    anyhow::ensure!(
+                        !has_entries(&complete_path)?
+                            && !has_entries(&partial_path)?
+                            && Wal::pending(&wal_path)?.is_empty(),
+                        "can not encrypt a store that already contains data"
+                    );
+                }
+                Some(DataKey::load_or_create(&key_path, wrapper)?)
+            }
+            None => {
+                anyhow::ensure!(
+                    !key_path.exists(),
+                    "the store is encrypted and can only be loaded with a key"
+                );
+                None
+            }
+        };
+        let mut recovered = Vec::new();
+        for intent in Wal::pending(&wal_path)? {
+            if let Some(entry) = recover(&complete_path, intent, key.as_ref())? {
+                recovered.push(entry);
+            }
+        }
+        let wal = Wal::create(&wal_path)?;
+        // the recovered entries are verified after loading, so they stay in the log until then
+        let mut recovering = BTreeMap::new();
+        for (hash, size) in recovered {
+            let intent = wal.begin(Intent::Complete {
+                hash,
+                size,
+                data: None,
+                outboard: None,
+            })?;
+            recovering.insert(hash, (size, intent.detach()));
+        }
         let mut partial_index =
             BTreeMap::<Hash, BTreeMap<[u8; 16], (Option<PathBuf>, Option<PathBuf>)>>::new();
-        let mut full_index =
-            BTreeMap::<Hash, (Option<PathBuf>, Option<PathBuf>, Option<PathBuf>)>::new();
+        let mut full_index = BTreeMap::<
+            Hash,
+            (
+                Option<PathBuf>,
+                Option<(PathBuf, OutboardFormat)>,
+                Option<PathBuf>,
+            ),
+        >::new();
         let mut outboard = BTreeMap::new();
         for entry in std::fs::read_dir(&partial_path)? {
             let entry = entry?;
@@ -938,7 +1916,14 @@ impl Store {
                         }
                         FileName::Outboard(hash) => {
                             let (_, outboard, _) = full_index.entry(hash).or_default();
-                            *outboard = Some(path);
+                            // prefer pre-order outboards, they don't need conversion
+                            *outboard = Some((path, OutboardFormat::PreOrder));
+                        }
+                        FileName::PostOrderOutboard(hash) => {
+                            let (_, outboard, _) = full_index.entry(hash).or_default();
+                            if outboard.is_none() {
+                                *outboard = Some((path, OutboardFormat::PostOrder));
+                            }
                         }
                         FileName::Paths(hash) => {
                             let (_, _, paths) = full_index.entry(hash).or_default();
@@ -954,6 +1939,9 @@ impl Store {
         // figure out what we have completely
         let mut complete = BTreeMap::new();
         for (hash, (data_path, outboard_path, paths_path)) in full_index {
+            if recovering.contains_key(&hash) {
+                continue;
+            }
             let external: BTreeSet<PathBuf> = if let Some(paths_path) = paths_path {
                 let paths = std::fs::read(paths_path)?;
                 postcard::from_bytes(&paths)?
@@ -966,7 +1954,7 @@ impl Store {
                     tracing::warn!("unable to open owned data file {}. removing {}", data_path.display(), hex::encode(hash));
                     continue
                 };
-                meta.len()
+                encryption::file_len(key.as_ref(), meta.len())
             } else if let Some(external) = external.iter().next() {
                 let Ok(meta) = std::fs::metadata(external) else {
                     tracing::warn!("unable to open external data file {}. removing {}", external.display(), hex::encode(hash));
@@ -981,8 +1969,10 @@ impl Store {
                 continue;
             };
             if needs_outboard(size) {
-                if let Some(outboard_path) = outboard_path {
+                if let Some((outboard_path, format)) = outboard_path {
                     let outboard_data = std::fs::read(outboard_path)?;
+                    let outboard_data = encryption::open(key.as_ref(), outboard_data)?;
+                    let outboard_data = to_pre_order(format, &outboard_data)?;
                     outboard.insert(hash, outboard_data.into());
                 } else {
                     tracing::error!("missing outboard file for {}", hex::encode(hash));
@@ -1040,11 +2030,15 @@ impl Store {
                     return None
                 };
                 let mut expected_size = [0u8; 8];
-                let Ok(_) = outboard_file.read_at(0, &mut expected_size) else {
+                let read = match &key {
+                    Some(key) => DecryptingReader::new(outboard_file, key.clone()).read_exact(&mut expected_size),
+                    None => outboard_file.read_at(0, &mut expected_size).map(|_| ()),
+                };
+                let Ok(_) = read else {
                     tracing::warn!("partial outboard file is missing length {}", outboard_path.display());
                     return None
                 };
-                let current_size = data_meta.len();
+                let current_size = encryption::file_len(key.as_ref(), data_meta.len());
                 let expected_size = u64::from_le_bytes(expected_size);
                 Some((current_size, expected_size, uuid))
             }).max_by_key(|x| x.0)
@@ -1053,13 +2047,7 @@ impl Store {
             };
             if let Some((current_size, expected_size, uuid)) = best {
                 if current_size > 0 {
-                    partial.insert(
-                        hash,
-                        PartialEntryData {
-                            size: expected_size,
-                            uuid: *uuid,
-                        },
-                    );
+                    partial.insert(hash, PartialEntryData::new(expected_size, *uuid));
                 }
             }
             // remove all other entries
@@ -1087,56 +2075,426 @@ impl Store {
         for hash in partial.keys() {
             tracing::info!("partial {}", hash);
         }
-        Ok(Self(Arc::new(Inner {
+        let pins_path = complete_path.join(FileName::Meta(PINS_META.to_vec()).to_string());
+        let pins = load_pins(&pins_path)?;
+        let db = Self(Arc::new(Inner {
             state: RwLock::new(State {
                 complete,
                 partial,
                 outboard,
                 data: Default::default(),
+                pins,
             }),
+            disk_space: RwLock::new(None),
+            disk_space_events: broadcast::channel(16).0,
+            events: broadcast::channel(64).0,
+            outboard_format: RwLock::new(OutboardFormat::PreOrder),
+            fsync_policy: RwLock::new(FsyncPolicy::default()),
+            wal,
+            ring: RwLock::new(None),
+            handles: Arc::new(HandleCache::new(HandleLimits::default())),
+            coalescer: RwLock::new(Some(Default::default())),
+            chunk_cache: RwLock::new(None),
+            outboard_hasher: Default::default(),
+            trash_retention: RwLock::new(DEFAULT_TRASH_RETENTION),
             options: Options {
                 complete_path,
                 partial_path,
                 move_threshold: 1024 * 128,
                 inline_threshold: 1024 * 16,
                 rt: rt.main().clone(),
+                key,
             },
-        })))
+        }));
+        if !recovering.is_empty() {
+            tracing::info!("verifying {} recovered entries", recovering.len());
+            let entries = recovering
+                .into_iter()
+                .map(|(hash, (size, id))| (hash, size, id))
+                .collect();
+            let db = db.clone();
+            rt.main()
+                .spawn_blocking(move || db.verify_recovered_sync(entries));
+        }
+        Ok(db)
     }
 
     /// Blocking load a database from disk.
     pub fn load_blocking(
         complete_path: impl AsRef<Path>,
         partial_path: impl AsRef<Path>,
-        rt: &iroh_bytes::util::runtime::Handle,
+        rt: impl Into<iroh_bytes::util::runtime::Handle>,
     ) -> anyhow::Result<Self> {
         let complete_path = complete_path.as_ref().to_path_buf();
         let partial_path = partial_path.as_ref().to_path_buf();
-        let rt = rt.clone();
-        let db = Self::load_sync(complete_path, partial_path, rt)?;
+        let rt = rt.into();
+        let db = Self::load_sync(complete_path, partial_path, rt, None)?;
         Ok(db)
     }
 
     /// Load a database from disk.
+    ///
+    /// Fails if the database is encrypted, see [`Store::load_encrypted`]. Accepts a plain
+    /// tokio runtime handle as well as an iroh runtime.
     pub async fn load(
         complete_path: impl AsRef<Path>,
         partial_path: impl AsRef<Path>,
-        rt: &iroh_bytes::util::runtime::Handle,
+        rt: impl Into<iroh_bytes::util::runtime::Handle>,
+    ) -> anyhow::Result<Self> {
+        let complete_path = complete_path.as_ref().to_path_buf();
+        let partial_path = partial_path.as_ref().to_path_buf();
+        Self::load_impl(complete_path, partial_path, &rt.into(), None).await
+    }
+
+    /// Load an encrypted database from disk, or create one.
+    ///
+    /// The data key of the database is unwrapped with `wrapper`, so this fails if the
+    /// database was encrypted with a different passphrase or key encryption key. A new
+    /// data key is created for a database that does not contain any data yet, existing
+    /// unencrypted databases can not be encrypted.
+    ///
+    /// Data files and outboards owned by the database are encrypted, see the
+    /// [module docs](self) for what is not.
+    pub async fn load_encrypted(
+        complete_path: impl AsRef<Path>,
+        partial_path: impl AsRef<Path>,
+        rt: impl Into<iroh_bytes::util::runtime::Handle>,
+        wrapper: impl KeyWrapper,
     ) -> anyhow::Result<Self> {
         let complete_path = complete_path.as_ref().to_path_buf();
         let partial_path = partial_path.as_ref().to_path_buf();
+        let rt = rt.into();
+        Self::load_impl(complete_path, partial_path, &rt, Some(Box::new(wrapper))).await
+    }
+
+    async fn load_impl(
+        complete_path: PathBuf,
+        partial_path: PathBuf,
+        rt: &iroh_bytes::util::runtime::Handle,
+        wrapper: Option<Box<dyn KeyWrapper>>,
+    ) -> anyhow::Result<Self> {
         let rtc = rt.clone();
         let db = rt
             .main()
-            .spawn_blocking(move || Self::load_sync(complete_path, partial_path, rtc))
+            .spawn_blocking(move || {
+                Self::load_sync(complete_path, partial_path, rtc, wrapper.as_deref())
+            })
             .await??;
+        // close idle handles even if the store is not used, until the store is dropped
+        let handles = Arc::downgrade(&db.0.handles);
+        rt.main().spawn(async move {
+            loop {
+                tokio::time::sleep(SWEEP_INTERVAL).await;
+                let Some(handles) = handles.upgrade() else {
+                    break;
+                };
+                handles.evict_idle();
+            }
+        });
+        // purge the trash while the node runs, not only when entries are deleted
+        let inner = Arc::downgrade(&db.0);
+        rt.main().spawn(async move {
+            loop {
+                tokio::time::sleep(TRASH_PURGE_INTERVAL).await;
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                let db = Store(inner);
+                let purged = tokio::task::spawn_blocking(move || db.purge_trash()).await;
+                if let Err(cause) = flatten_to_io(purged) {
+                    tracing::warn!("failed to purge the trash: {}", cause);
+                }
+            }
+        });
         Ok(db)
     }
 
+    /// Whether the data and outboards of the store are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.0.options.key.is_some()
+    }
+
+    /// Configure disk space watermarks for this store.
+    ///
+    /// When free disk space on the file system containing the complete data drops
+    /// below the low watermark, new imports and partial entries are refused with an
+    /// [`iroh_bytes::baomap::InsufficientSpace`] error until free space recovers above
+    /// the high watermark. Pass `None` to disable admission control.
+    pub fn set_watermarks(&self, watermarks: Option<Watermarks>) {
+        let monitor = watermarks.map(|w| {
+            DiskSpaceMonitor::new(
+                self.0.options.complete_path.clone(),
+                w,
+                self.0.disk_space_events.clone(),
+            )
+        });
+        *self.0.disk_space.write().unwrap() = monitor;
+    }
+
+    fn notify(&self, event: StoreEvent) {
+        // no subscribers is not an error
+        self.0.events.send(event).ok();
+    }
+
+    /// Subscribe to events emitted when free disk space crosses a watermark.
+    pub fn disk_space_events(&self) -> broadcast::Receiver<DiskSpaceEvent> {
+        self.0.disk_space_events.subscribe()
+    }
+
+    fn check_disk_space(&self) -> io::Result<()> {
+        match self.0.disk_space.read().unwrap().as_ref() {
+            Some(monitor) => monitor.check(),
+            None => Ok(()),
+        }
+    }
+
     fn owned_data_path(&self, hash: &Hash) -> PathBuf {
         self.0.options.owned_data_path(hash)
     }
 
+    /// Set the format in which complete outboards are written.
+    ///
+    /// Existing outboard files are not converted, and outboard files of all formats
+    /// are read on startup. Only pre-order and post-order outboards are supported,
+    /// since data files are kept as they are so they can be exported and referenced
+    /// in place.
+    pub fn set_outboard_format(&self, format: OutboardFormat) -> io::Result<()> {
+        if format == OutboardFormat::Combined {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the flat store does not support the combined encoding",
+            ));
+        }
+        *self.0.outboard_format.write().unwrap() = format;
+        Ok(())
+    }
+
+    /// The format in which complete outboards are written.
+    pub fn outboard_format(&self) -> OutboardFormat {
+        *self.0.outboard_format.read().unwrap()
+    }
+
+    /// Write a complete pre-order outboard in the configured format.
+    fn write_outboard(&self, hash: &Hash, outboard: &[u8]) -> io::Result<()> {
+        let sync = self.fsync_policy().sync_complete();
+        let key = self.0.options.key.as_ref();
+        match self.outboard_format() {
+            OutboardFormat::PreOrder => fsync::write_file(
+                &self.owned_outboard_path(hash),
+                &encryption::seal(key, outboard),
+                sync,
+            ),
+            format => fsync::write_file(
+                &self.0.options.owned_post_order_outboard_path(hash),
+                &encryption::seal(key, &from_pre_order(format, outboard)?),
+                sync,
+            ),
+        }
+    }
+
+    /// Set when written data, outboards and metadata are synced to stable storage.
+    ///
+    /// See [`FsyncPolicy`] for the trade-offs. Partial entries that are already being
+    /// written keep the policy they were created with.
+    pub fn set_fsync_policy(&self, policy: FsyncPolicy) {
+        *self.0.fsync_policy.write().unwrap() = policy;
+    }
+
+    /// When written data, outboards and metadata are synced to stable storage.
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        *self.0.fsync_policy.read().unwrap()
+    }
+
+    /// Set how complete data files are read and partial entries are written, and return
+    /// the backend actually used.
+    ///
+    /// If io_uring is not available, because of the platform, the `io-uring` feature or
+    /// the kernel, this falls back to [`IoBackend::Std`]. Complete outboards, reads of
+    /// partial entries and the complete data of encrypted stores are always read with the
+    /// default backend. Readers and writers that are already open keep their backend.
+    pub fn set_io_backend(&self, backend: IoBackend) -> IoBackend {
+        let ring = match backend {
+            IoBackend::Std => None,
+            IoBackend::Uring => match Ring::new(uring::DEFAULT_QUEUE_DEPTH) {
+                Ok(ring) => Some(ring),
+                Err(e) => {
+                    tracing::warn!("io_uring not available, using the default backend: {}", e);
+                    None
+                }
+            },
+        };
+        let used = if ring.is_some() {
+            IoBackend::Uring
+        } else {
+            IoBackend::Std
+        };
+        *self.0.ring.write().unwrap() = ring;
+        used
+    }
+
+    /// Limit the number of file handles kept open for reading, and how long they are kept.
+    ///
+    /// Handles are shared between all readers of the data and outboard of an entry.
+    pub fn set_file_handle_limits(&self, limits: HandleLimits) {
+        self.0.handles.set_limits(limits);
+    }
+
+    /// The limits for the file handles kept open for reading.
+    pub fn file_handle_limits(&self) -> HandleLimits {
+        self.0.handles.limits()
+    }
+
+    /// Enable or disable coalescing of identical concurrent reads, enabled by default.
+    ///
+    /// With coalescing, concurrent responses for the same ranges of a complete entry share
+    /// a single read from disk. Readers that are already open are not affected.
+    pub fn set_coalesce_reads(&self, enabled: bool) {
+        *self.0.coalescer.write().unwrap() = enabled.then(Default::default);
+    }
+
+    /// Whether identical concurrent reads are coalesced.
+    pub fn coalesce_reads(&self) -> bool {
+        self.0.coalescer.read().unwrap().is_some()
+    }
+
+    /// Cache up to `size` bytes of recently read ranges of complete entries in memory.
+    ///
+    /// A size of 0 disables the cache, which is the default. Changing the size drops
+    /// everything that is cached. Readers that are already open keep using the old cache.
+    pub fn set_chunk_cache_size(&self, size: u64) {
+        let cache = (size > 0).then(|| Arc::new(ChunkCache::new(size)));
+        *self.0.chunk_cache.write().unwrap() = cache;
+    }
+
+    /// The size of the chunk cache in bytes, 0 if it is disabled.
+    pub fn chunk_cache_size(&self) -> u64 {
+        self.0
+            .chunk_cache
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |cache| cache.capacity())
+    }
+
+    /// Compute the outboards of large imports on up to `threads` threads.
+    ///
+    /// With 0 threads, which is the default, a thread per core is used. With 1 thread,
+    /// imports are hashed on a single thread.
+    pub fn set_hash_threads(&self, threads: usize) -> io::Result<()> {
+        let hasher = OutboardHasher::with_threads(threads)?;
+        *self.0.outboard_hasher.write().unwrap() = hasher;
+        Ok(())
+    }
+
+    /// The hasher used to compute the outboards of imports.
+    pub fn outboard_hasher(&self) -> OutboardHasher {
+        self.0.outboard_hasher.read().unwrap().clone()
+    }
+
+    /// Keep deleted entries in the trash for `retention`, so they can be restored.
+    ///
+    /// Defaults to [`DEFAULT_TRASH_RETENTION`]. With a retention of zero, deleted entries
+    /// are removed immediately.
+    pub fn set_trash_retention(&self, retention: Duration) {
+        *self.0.trash_retention.write().unwrap() = retention;
+    }
+
+    /// How long deleted entries are kept in the trash.
+    pub fn trash_retention(&self) -> Duration {
+        *self.0.trash_retention.read().unwrap()
+    }
+
+    /// Permanently remove entries that have been in the trash for longer than the retention.
+    ///
+    /// This happens whenever an entry is deleted, and every [`TRASH_PURGE_INTERVAL`] while
+    /// the store is loaded. Returns the number of removed entries.
+    pub fn purge_trash(&self) -> io::Result<usize> {
+        let retention = self.trash_retention();
+        let now = SystemTime::now();
+        let mut purged = 0;
+        for (hash, deleted, dir) in self.trash_entries()? {
+            if deleted + retention <= now {
+                tracing::info!("purging {} from the trash", hash);
+                std::fs::remove_dir_all(dir)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// How complete data files are read and partial entries are written.
+    pub fn io_backend(&self) -> IoBackend {
+        if self.0.ring.read().unwrap().is_some() {
+            IoBackend::Uring
+        } else {
+            IoBackend::Std
+        }
+    }
+
+    /// Record the intent of an operation in the write-ahead log.
+    fn begin(&self, intent: Intent) -> io::Result<InFlight<'_>> {
+        self.0.wal.begin(intent)
+    }
+
+    /// Verify the entries whose completion was interrupted, see [`recover`].
+    ///
+    /// Each entry is added to the store once its outboard has been recomputed, and its
+    /// intent `id` is marked as done afterwards, so an interrupted verification is
+    /// replayed on the next start.
+    fn verify_recovered_sync(&self, entries: Vec<(Hash, u64, u64)>) {
+        for (hash, size, id) in entries {
+            if let Err(cause) = self.verify_recovered_entry_sync(hash, size) {
+                tracing::error!("failed to recover {}: {}", hash, cause);
+                continue;
+            }
+            if let Err(cause) = self.0.wal.done(id) {
+                tracing::error!("failed to update the write-ahead log: {}", cause);
+            }
+        }
+    }
+
+    fn verify_recovered_entry_sync(&self, hash: Hash, size: u64) -> io::Result<()> {
+        let options = &self.0.options;
+        let key = options.key.as_ref();
+        let data_path = options.owned_data_path(&hash);
+        let outboard_path = options.owned_outboard_path(&hash);
+        let post_order_path = options.owned_post_order_outboard_path(&hash);
+        let (actual, outboard) = match key {
+            Some(key) => {
+                let file = std::fs::File::open(&data_path)?;
+                let reader = DecryptingReader::new(file, key.clone());
+                compute_outboard_sequential(reader, size, |_| Ok(()))?
+            }
+            None => compute_outboard(&self.outboard_hasher(), &data_path, size, |_| Ok(()))?,
+        };
+        if actual != hash {
+            tracing::warn!("removing incomplete entry {}", hash);
+            for path in [data_path, outboard_path, post_order_path] {
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            return Ok(());
+        }
+        // the outboard is rewritten in pre-order
+        if let Some(outboard) = &outboard {
+            fsync::write_file(&outboard_path, &encryption::seal(key, outboard), true)?;
+        }
+        if post_order_path.exists() {
+            std::fs::remove_file(post_order_path)?;
+        }
+        let mut state = self.0.state.write().unwrap();
+        let entry = state.complete.entry(hash).or_default();
+        entry.union_with(CompleteEntry::new_default(size))?;
+        if let Some(outboard) = outboard {
+            state.outboard.insert(hash, outboard.into());
+        }
+        drop(state);
+        tracing::info!("recovered {}", hash);
+        self.notify(StoreEvent::Added(hash));
+        Ok(())
+    }
+
     fn owned_outboard_path(&self, hash: &Hash) -> PathBuf {
         self.0.options.owned_outboard_path(hash)
     }
@@ -1146,6 +2504,72 @@ impl Store {
     }
 }
 
+/// Whether `dir` contains files of any entries.
+fn has_entries(dir: &Path) -> io::Result<bool> {
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        match name.to_str().map(FileName::from_str) {
+            Some(Ok(FileName::Meta(_))) => {}
+            Some(Ok(_)) => return Ok(true),
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Clean up or complete an operation that was interrupted by a crash.
+///
+/// Files of an interrupted import are removed. Files of an interrupted completion are
+/// moved into place if they have not been yet. If the data has the expected size, the
+/// hash and size of the entry are returned, and its outboard has to be recomputed from
+/// the data before it can be used, see [`Store::verify_recovered_sync`]. This reads all
+/// of the data, so it is not done here, while the store is being loaded. If the data is
+/// incomplete, all files of the entry are removed, since the operation that was writing
+/// them never succeeded.
+fn recover(
+    complete_path: &Path,
+    intent: Intent,
+    key: Option<&DataKey>,
+) -> io::Result<Option<(Hash, u64)>> {
+    tracing::info!("recovering interrupted operation {:?}", intent);
+    match intent {
+        Intent::Import { temp } => {
+            if temp.exists() {
+                std::fs::remove_file(temp)?;
+            }
+            Ok(None)
+        }
+        Intent::Complete {
+            hash,
+            size,
+            data,
+            outboard,
+        } => {
+            let data_path = complete_path.join(FileName::Data(hash).to_string());
+            let outboard_path = complete_path.join(FileName::Outboard(hash).to_string());
+            let post_order_path = complete_path.join(FileName::PostOrderOutboard(hash).to_string());
+            if let Some(source) = data.filter(|p| p.exists()) {
+                std::fs::rename(source, &data_path)?;
+            }
+            if let Some(source) = outboard.filter(|p| p.exists()) {
+                std::fs::remove_file(source)?;
+            }
+            match std::fs::metadata(&data_path) {
+                Ok(meta) if encryption::file_len(key, meta.len()) == size => Ok(Some((hash, size))),
+                _ => {
+                    tracing::warn!("removing incomplete entry {}", hash);
+                    for path in [data_path, outboard_path, post_order_path] {
+                        if path.exists() {
+                            std::fs::remove_file(path)?;
+                        }
+                    }
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
 /// Synchronously compute the outboard of a file, and return hash and outboard.
 ///
 /// It is assumed that the file is not modified while this is running.
@@ -1155,7 +2579,10 @@ impl Store {
 ///
 /// If the size of the file is changed while this is running, an error will be
 /// returned.
+///
+/// Files that are large enough for the hasher are hashed on multiple threads.
 fn compute_outboard(
+    hasher: &OutboardHasher,
     path: &Path,
     size: u64,
     progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
@@ -1163,13 +2590,29 @@ fn compute_outboard(
     let span = trace_span!("outboard.compute", path = %path.display());
     let _guard = span.enter();
     let file = std::fs::File::open(path)?;
+    if hasher.is_parallel(size) {
+        let (ob, hash) = hasher.outboard_file(&file, size, &progress)?;
+        tracing::trace!(%hash, "done");
+        let ob = if ob.len() > 8 { Some(ob) } else { None };
+        return Ok((hash.into(), ob));
+    }
+    compute_outboard_sequential(file, size, progress)
+}
+
+/// Synchronously compute the outboard of the first `size` bytes of `reader` on the current
+/// thread, and return hash and outboard.
+fn compute_outboard_sequential(
+    reader: impl io::Read,
+    size: u64,
+    progress: impl Fn(u64) -> io::Result<()>,
+) -> io::Result<(Hash, Option<Vec<u8>>)> {
     // compute outboard size so we can pre-allocate the buffer.
     let outboard_size = usize::try_from(bao_tree::io::outboard_size(size, IROH_BLOCK_SIZE))
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "size too large"))?;
     let mut outboard = Vec::with_capacity(outboard_size);
 
     // wrap the reader in a progress reader, so we can report progress.
-    let reader = ProgressReader2::new(file, progress);
+    let reader = ProgressReader2::new(reader, progress);
     // wrap the reader in a buffered reader, so we read in large chunks
     // this reduces the number of io ops and also the number of progress reports
     let mut reader = BufReader::with_capacity(1024 * 1024, reader);
@@ -1223,6 +2666,8 @@ pub enum FileName {
     /// We can have multiple files with the same outboard, in case the outboard
     /// does not contain hashes. But we don't store those outboards.
     Outboard(Hash),
+    /// File is storing an outboard in post-order
+    PostOrderOutboard(Hash),
     /// External paths for the hash
     Paths(Hash),
     /// File is going to be used to store metadata
@@ -1245,6 +2690,69 @@ impl FileName {
 /// size of 4, unlike the bao crate which uses 0.
 const OUTBOARD_EXT: &str = "obao4";
 
+/// The extension for post-order outboard files, with the same chunk group size.
+const POST_ORDER_OUTBOARD_EXT: &str = "pobao4";
+
+/// Load the pins from `path`, moving a corrupt pins file aside instead of failing.
+fn load_pins(path: &Path) -> io::Result<BTreeMap<String, Pin>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    match postcard::from_bytes(&data) {
+        Ok(pins) => Ok(pins),
+        Err(cause) => {
+            let mut aside = path.as_os_str().to_owned();
+            aside.push(".corrupt");
+            tracing::error!(
+                "failed to parse the pins file {}, moving it to {:?} and starting without pins: {}",
+                path.display(),
+                aside,
+                cause
+            );
+            std::fs::rename(path, aside)?;
+            Ok(BTreeMap::new())
+        }
+    }
+}
+
+/// The name of the [`FileName::Meta`] file storing the pins.
+const PINS_META: &[u8] = b"pins";
+
+/// The name of the [`FileName::Meta`] file storing the write-ahead log.
+const WAL_META: &[u8] = b"wal";
+
+/// The name of the [`FileName::Meta`] file storing the wrapped data key.
+const KEY_META: &[u8] = b"key";
+
+/// The directory in the complete path that quarantined files are moved to.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// The directory in the complete path that deleted files are moved to.
+const TRASH_DIR: &str = "trash";
+
+/// How long deleted entries are kept in the trash by default.
+pub const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Interval in which expired entries are purged from the trash of a loaded store.
+pub const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 10);
+
+/// The name of the trash directory for `hash` deleted at `deleted`.
+fn trash_name(hash: Hash, deleted: SystemTime) -> String {
+    let secs = deleted.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}-{}", hex::encode(hash), secs.as_secs())
+}
+
+/// Parse the name of a trash directory, see [`trash_name`].
+fn parse_trash_name(name: &str) -> Option<(Hash, SystemTime)> {
+    let (hash_text, secs) = name.split_once('-')?;
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(hash_text, &mut hash).ok()?;
+    let deleted = UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?);
+    Some((hash.into(), deleted))
+}
+
 impl fmt::Display for FileName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -1265,6 +2773,9 @@ impl fmt::Display for FileName {
             }
             Self::Data(hash) => write!(f, "{}.data", hex::encode(hash)),
             Self::Outboard(hash) => write!(f, "{}.{}", hex::encode(hash), OUTBOARD_EXT),
+            Self::PostOrderOutboard(hash) => {
+                write!(f, "{}.{}", hex::encode(hash), POST_ORDER_OUTBOARD_EXT)
+            }
             Self::Meta(name) => write!(f, "{}.meta", hex::encode(name)),
         }
     }
@@ -1302,6 +2813,8 @@ impl FromStr for FileName {
                 Ok(Self::Data(hash.into()))
             } else if ext == OUTBOARD_EXT {
                 Ok(Self::Outboard(hash.into()))
+            } else if ext == POST_ORDER_OUTBOARD_EXT {
+                Ok(Self::PostOrderOutboard(hash.into()))
             } else if ext == "paths" {
                 Ok(Self::Paths(hash.into()))
             } else {
@@ -1334,6 +2847,9 @@ impl fmt::Debug for FileName {
                 .field(&DD(hex::encode(guid)))
                 .finish(),
             Self::Outboard(hash) => f.debug_tuple("Outboard").field(&DD(hash)).finish(),
+            Self::PostOrderOutboard(hash) => {
+                f.debug_tuple("PostOrderOutboard").field(&DD(hash)).finish()
+            }
             Self::Meta(arg0) => f.debug_tuple("Meta").field(&DD(hex::encode(arg0))).finish(),
             Self::Paths(arg0) => f
                 .debug_tuple("Paths")
@@ -1351,6 +2867,7 @@ impl FileName {
             FileName::Data(_) => false,
             FileName::PartialOutboard(_, _) => true,
             FileName::Outboard(_) => false,
+            FileName::PostOrderOutboard(_) => false,
             FileName::Meta(_) => false,
             FileName::Paths(_) => false,
         }
@@ -1364,6 +2881,7 @@ impl FileName {
             FileName::PartialOutboard(hash, _) => hash.as_bytes(),
             FileName::Meta(data) => data.as_slice(),
             FileName::Outboard(_) => &[],
+            FileName::PostOrderOutboard(_) => &[],
             FileName::Paths(_) => &[],
         }
     }
@@ -1382,6 +2900,7 @@ mod tests {
         prop_oneof![
             arb_hash().prop_map(FileName::Data),
             arb_hash().prop_map(FileName::Outboard),
+            arb_hash().prop_map(FileName::PostOrderOutboard),
             arb_hash().prop_map(FileName::Paths),
             (arb_hash(), any::<[u8; 16]>())
                 .prop_map(|(hash, uuid)| FileName::PartialData(hash, uuid)),
@@ -1400,6 +2919,355 @@ mod tests {
         assert!(FileName::from_str("1234ABDC-1234.outboard").is_err());
     }
 
+    #[tokio::test]
+    async fn encrypted_store() -> anyhow::Result<()> {
+        use super::super::encryption::Passphrase;
+        use baomap::Store as _;
+        use std::num::NonZeroU32;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let passphrase = |p: &str| Passphrase::new(p).iterations(NonZeroU32::new(1).unwrap());
+        let data = Bytes::from(vec![42u8; 100_000]);
+
+        let db = Store::load_encrypted(path, path, &rt, passphrase("secret")).await?;
+        assert!(db.is_encrypted());
+        let hash = db.import_bytes(data.clone()).await?;
+        drop(db);
+        let raw = std::fs::read(path.join(FileName::Data(hash).to_string()))?;
+        assert!(!raw.windows(64).any(|w| w == &data[..64]));
+
+        assert!(Store::load(path, path, &rt).await.is_err());
+        assert!(Store::load_encrypted(path, path, &rt, passphrase("wrong"))
+            .await
+            .is_err());
+        let db = Store::load_encrypted(path, path, &rt, passphrase("secret")).await?;
+        let entry = db.get(&hash).unwrap();
+        assert_eq!(entry.size(), data.len() as u64);
+        let mut reader = entry.data_reader().await?;
+        assert_eq!(reader.read_at(0, data.len()).await?, data);
+        let target = path.join("export");
+        db.export(hash, target.clone(), ExportMode::Copy, |_| Ok(()))
+            .await?;
+        assert_eq!(std::fs::read(target)?, data);
+
+        // an existing unencrypted store can not be encrypted
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        let db = Store::load(path, path, &rt).await?;
+        db.import_bytes(data).await?;
+        drop(db);
+        assert!(Store::load_encrypted(path, path, &rt, passphrase("secret"))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_deduplicated() -> anyhow::Result<()> {
+        use baomap::Store as _;
+        use iroh_bytes::util::progress::FlumeProgressSender;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("store");
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let db = Store::load(&path, &path, &rt).await?;
+        let source = dir.path().join("source");
+        std::fs::write(&source, vec![1u8; 100_000])?;
+        let deduplicated = |progress: flume::Receiver<ImportProgress>| {
+            progress
+                .drain()
+                .filter_map(|msg| match msg {
+                    ImportProgress::Deduplicated { size, .. } => Some(size),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for mode in [ImportMode::Copy, ImportMode::TryReference] {
+            let (tx, rx) = flume::unbounded();
+            db.import(source.clone(), mode, FlumeProgressSender::new(tx))
+                .await?;
+            let expected = match mode {
+                ImportMode::Copy => vec![],
+                ImportMode::TryReference => vec![100_000],
+            };
+            assert_eq!(deduplicated(rx), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trash() -> anyhow::Result<()> {
+        use baomap::Store as _;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let db = Store::load(path, path, &rt).await?;
+        let data = Bytes::from(vec![7u8; 100_000]);
+        let hash = db.import_bytes(data.clone()).await?;
+
+        db.delete(hash).await?;
+        assert!(db.get(&hash).is_none());
+        assert!(!path.join(FileName::Data(hash).to_string()).exists());
+        assert!(db.restore(hash).await?);
+        let mut reader = db.get(&hash).unwrap().data_reader().await?;
+        assert_eq!(reader.read_at(0, data.len()).await?, data);
+        assert!(!db.restore(hash).await?);
+
+        // entries are loaded from restored files like any other entry
+        db.delete(hash).await?;
+        drop(db);
+        let db = Store::load(path, path, &rt).await?;
+        assert!(db.get(&hash).is_none());
+        assert!(db.restore(hash).await?);
+        assert_eq!(db.get(&hash).unwrap().size(), data.len() as u64);
+
+        db.set_trash_retention(Duration::ZERO);
+        db.delete(hash).await?;
+        assert!(!db.restore(hash).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recover_interrupted_completion() -> anyhow::Result<()> {
+        use baomap::Store as _;
+        use std::time::Instant;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let db = Store::load(path, path, &rt).await?;
+        let good = db.import_bytes(Bytes::from(vec![1u8; 100_000])).await?;
+        let bad = db.import_bytes(Bytes::from(vec![2u8; 100_000])).await?;
+        drop(db);
+
+        // simulate a crash while completing both entries, before the outboards were written
+        let wal_path = path.join(FileName::Meta(WAL_META.to_vec()).to_string());
+        let wal = Wal::create(&wal_path)?;
+        for hash in [good, bad] {
+            let intent = wal.begin(Intent::Complete {
+                hash,
+                size: 100_000,
+                data: None,
+                outboard: None,
+            })?;
+            intent.detach();
+            std::fs::remove_file(path.join(FileName::Outboard(hash).to_string()))?;
+        }
+        drop(wal);
+        std::fs::write(
+            path.join(FileName::Data(bad).to_string()),
+            vec![3u8; 100_000],
+        )?;
+
+        // the entries are verified in the background after loading
+        let db = Store::load(path, path, &rt).await?;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while db.get(&good).is_none() || !Wal::pending(&wal_path)?.is_empty() {
+            anyhow::ensure!(Instant::now() < deadline, "recovery did not finish");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(path.join(FileName::Outboard(good).to_string()).exists());
+        assert!(db.get(&bad).is_none());
+        assert!(!path.join(FileName::Data(bad).to_string()).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_names() -> anyhow::Result<()> {
+        use baomap::Store as _;
+        use iroh_bytes::keyed::{NameResolver, NamespaceKey, StoreNames};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let db = Store::load(path, path, &rt).await?;
+        let key = NamespaceKey::generate();
+        let before = db.import_bytes(Bytes::from_static(b"before")).await?;
+        let names = StoreNames::new(key.clone(), db.clone());
+        assert_eq!(names.resolve(key.name(&before)).await?, Some(before));
+
+        // the index follows the store in the background
+        let resolves_to = |name: Hash, expected: Option<Hash>| {
+            let names = names.clone();
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while names.resolve(name).await? != expected {
+                    tokio::task::yield_now().await;
+                }
+                anyhow::Ok(())
+            })
+        };
+        let after = db.import_bytes(Bytes::from_static(b"after")).await?;
+        resolves_to(key.name(&after), Some(after)).await??;
+        db.delete(before).await?;
+        resolves_to(key.name(&before), None).await??;
+        assert_eq!(names.resolve(before).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_pinned() -> anyhow::Result<()> {
+        use baomap::Store as _;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let db = Store::load(path, path, &rt).await?;
+        let hash = db.import_bytes(Bytes::from(vec![7u8; 100_000])).await?;
+        let pin = Pin {
+            hash,
+            recursive: false,
+            expires: None,
+        };
+        db.set_pin("keep".to_string(), pin.clone()).await?;
+
+        let err = db.delete(hash).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(db.get(&hash).is_some());
+        assert!(path.join(FileName::Data(hash).to_string()).exists());
+
+        // an expired pin no longer protects the blob
+        let expired = Pin {
+            expires: Some(SystemTime::UNIX_EPOCH),
+            ..pin
+        };
+        db.set_pin("keep".to_string(), expired).await?;
+        db.delete(hash).await?;
+        assert!(db.get(&hash).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_pins() -> anyhow::Result<()> {
+        use baomap::Store as _;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let db = Store::load(path, path, &rt).await?;
+        let hash = db.import_bytes(Bytes::from(vec![7u8; 100_000])).await?;
+        let pin = Pin {
+            hash,
+            recursive: false,
+            expires: None,
+        };
+        db.set_pin("keep".to_string(), pin).await?;
+        drop(db);
+
+        // a truncated pins file
+        let pins_path = path.join(FileName::Meta(PINS_META.to_vec()).to_string());
+        let data = std::fs::read(&pins_path)?;
+        std::fs::write(&pins_path, &data[..data.len() / 2])?;
+        let db = Store::load(path, path, &rt).await?;
+        assert_eq!(db.pins().count(), 0);
+        assert!(db.get(&hash).is_some());
+        let mut aside = pins_path.into_os_string();
+        aside.push(".corrupt");
+        assert_eq!(std::fs::read(aside)?, &data[..data.len() / 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_import_partial() -> anyhow::Result<()> {
+        use baomap::Store as _;
+
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let data = Bytes::from((0..100_000u32).map(|i| i as u8).collect::<Vec<_>>());
+        let dir = tempfile::tempdir()?;
+        let source = Store::load(dir.path(), dir.path(), &rt).await?;
+        let hash = source.import_bytes(data.clone()).await?;
+        let mut outboard = source.get(&hash).unwrap().outboard().await?.data;
+        let outboard_size = outboard.len().await?;
+        let outboard = outboard.read_at(0, outboard_size as usize).await?;
+
+        // a download that got the first 32 chunks
+        let dir = tempfile::tempdir()?;
+        let db = Store::load(dir.path(), dir.path(), &rt).await?;
+        let entry = db.get_or_create_partial(hash, data.len() as u64)?;
+        let mut writer = entry.data_writer().await?;
+        writer.write_at(0, &data[..32 * 1024]).await?;
+        let mut writer = entry.outboard_mut().await?.data;
+        writer.write_bytes_at(0, outboard).await?;
+        let ranges = entry.available_ranges().await?;
+        assert_eq!(ranges, RangeSet2::from(..ChunkNum(32)));
+
+        let export = tempfile::tempdir()?;
+        let target = export.path().join("blob");
+        db.export_partial(hash, target.clone()).await?;
+        assert_eq!(std::fs::metadata(&target)?.len(), data.len() as u64);
+        assert!(sparse::sidecar_path(&target).exists());
+
+        let dir = tempfile::tempdir()?;
+        let db = Store::load(dir.path(), dir.path(), &rt).await?;
+        assert_eq!(db.import_partial(target.clone()).await?, hash);
+        let entry = db.get_partial(&hash).unwrap();
+        assert_eq!(entry.available_ranges().await?, ranges);
+        let mut reader = entry.data_reader().await?;
+        assert_eq!(reader.read_at(0, 32 * 1024).await?, data.slice(..32 * 1024));
+        assert!(db.import_partial(target.clone()).await.is_err());
+
+        // corrupted data is rejected
+        let mut file = std::fs::read(&target)?;
+        file[1000] ^= 1;
+        std::fs::write(&target, file)?;
+        let dir = tempfile::tempdir()?;
+        let db = Store::load(dir.path(), dir.path(), &rt).await?;
+        assert!(db.import_partial(target).await.is_err());
+        assert!(db.get_partial(&hash).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn validate() -> anyhow::Result<()> {
+        use baomap::Store as _;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        let rt = iroh_bytes::util::runtime::Handle::from_currrent(1)?;
+        let db = Store::load(path, path, &rt).await?;
+        let hash = db.import_bytes(Bytes::from(vec![7u8; 100_000])).await?;
+
+        let run = |db: Store| async move {
+            let (tx, mut rx) = mpsc::channel(8);
+            db.validate(tx).await?;
+            let mut events = Vec::new();
+            while let Some(event) = rx.recv().await {
+                events.push(event);
+            }
+            anyhow::Ok(events)
+        };
+        let events = run(db.clone()).await?;
+        assert!(matches!(events[0], ValidateProgress::Starting { total: 1 }));
+        assert!(matches!(events[1], ValidateProgress::Entry { hash: h, .. } if h == hash));
+        assert!(matches!(
+            events[2],
+            ValidateProgress::Done { error: None, .. }
+        ));
+        assert!(matches!(events[3], ValidateProgress::AllDone));
+
+        // reload, so no cached data hides the corruption
+        drop(db);
+        let data_path = path.join(FileName::Data(hash).to_string());
+        let mut data = std::fs::read(&data_path)?;
+        data[1000] ^= 1;
+        std::fs::write(&data_path, data)?;
+        let db = Store::load(path, path, &rt).await?;
+        let events = run(db.clone()).await?;
+        assert!(matches!(
+            events[2],
+            ValidateProgress::Done { error: Some(_), .. }
+        ));
+
+        // validation stops once nobody listens
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        assert!(db.validate(tx).await.is_err());
+        Ok(())
+    }
+
     proptest! {
         #[test]
         fn filename_roundtrip(name in arb_filename()) {
@@ -0,0 +1,290 @@
+//! Durability configuration for persistent stores.
+//!
+//! Syncing written data to stable storage is what makes it survive a power loss, but it
+//! is also one of the most expensive things a store does. [`FsyncPolicy`] lets the
+//! operator choose where on this trade-off a node sits.
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
+use serde::{Deserialize, Serialize};
+
+use super::uring;
+
+/// When written data, outboards and metadata are synced to stable storage.
+///
+/// The policy affects what survives a power loss, not what survives a crash of the
+/// process: written data is in the operating system's buffers either way. Data that
+/// did not make it to disk is detected when loading the store or by validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncPolicy {
+    /// Sync after every single write, including every chunk of a download.
+    ///
+    /// A power loss loses at most the write in progress, at the cost of a sync per
+    /// chunk, which limits throughput to what the disk can sync. Meant for archival
+    /// nodes on slow networks, where every downloaded byte is expensive.
+    PerWrite,
+    /// Sync files once they are complete.
+    ///
+    /// Imported data, outboards of complete entries, completed downloads and metadata
+    /// like pins are synced before they become visible. Downloads in progress may lose
+    /// recent writes on a power loss, and have to fetch them again. A good default for
+    /// nodes that must not lose what they acknowledged as stored.
+    OnComplete,
+    /// Never sync, leave flushing to the operating system.
+    ///
+    /// The fastest option, and the default. A power loss can lose or corrupt recently
+    /// written entries, even complete ones. Meant for seeders on fast storage whose
+    /// content can be recovered from elsewhere.
+    #[default]
+    Never,
+}
+
+impl FsyncPolicy {
+    /// Whether complete files and metadata are synced.
+    pub(crate) fn sync_complete(self) -> bool {
+        !matches!(self, FsyncPolicy::Never)
+    }
+
+    /// Whether every write is synced.
+    pub(crate) fn sync_writes(self) -> bool {
+        matches!(self, FsyncPolicy::PerWrite)
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsyncPolicy::PerWrite => write!(f, "per-write"),
+            FsyncPolicy::OnComplete => write!(f, "on-complete"),
+            FsyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per-write" => Ok(FsyncPolicy::PerWrite),
+            "on-complete" => Ok(FsyncPolicy::OnComplete),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => anyhow::bail!("unknown fsync policy: {}", s),
+        }
+    }
+}
+
+/// Write a whole file, syncing it if `sync` is set.
+pub(crate) fn write_file(path: &Path, data: &[u8], sync: bool) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    if sync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Atomically replace the contents of a file.
+///
+/// The data is written to a temporary file next to `path`, which is synced and then
+/// renamed over `path`, so a crash leaves either the old or the new contents. The rename
+/// itself is synced if `sync` is set.
+pub(crate) fn replace_file(path: &Path, data: &[u8], sync: bool) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    // the temp file is always synced, otherwise the rename could be durable before the data
+    write_file(Path::new(&tmp), data, true)?;
+    std::fs::rename(&tmp, path)?;
+    if sync {
+        if let Some(dir) = path.parent() {
+            sync_dir(dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sync an existing file.
+pub(crate) fn sync_file(path: &Path) -> io::Result<()> {
+    // some platforms can only sync files that are open for writing
+    OpenOptions::new().write(true).open(path)?.sync_all()
+}
+
+/// Sync a directory, so renames of files in it are durable.
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Sync a directory, so renames of files in it are durable.
+///
+/// Directories can not be synced on this platform, renames are durable once the
+/// metadata is flushed by the file system.
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// A file writer that optionally syncs after every write.
+#[derive(Debug)]
+pub struct SyncingFile {
+    file: Backend,
+    sync_writes: bool,
+}
+
+impl SyncingFile {
+    pub(crate) fn new(file: iroh_io::File, sync_writes: bool) -> Self {
+        Self {
+            file: Backend::Std(file),
+            sync_writes,
+        }
+    }
+
+    /// A file that is written through an io_uring.
+    pub(crate) fn uring(file: uring::File, sync_writes: bool) -> Self {
+        Self {
+            file: Backend::Uring(file),
+            sync_writes,
+        }
+    }
+}
+
+/// The file of a [`SyncingFile`].
+#[derive(Debug)]
+enum Backend {
+    Std(iroh_io::File),
+    Uring(uring::File),
+}
+
+impl AsyncSliceReader for Backend {
+    type ReadAtFuture<'a> = BoxFuture<'a, io::Result<Bytes>>;
+    fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
+        match self {
+            Backend::Std(file) => file.read_at(offset, len).boxed(),
+            Backend::Uring(file) => file.read_at(offset, len),
+        }
+    }
+
+    type LenFuture<'a> = BoxFuture<'a, io::Result<u64>>;
+    fn len(&mut self) -> Self::LenFuture<'_> {
+        match self {
+            Backend::Std(file) => file.len().boxed(),
+            Backend::Uring(file) => file.len(),
+        }
+    }
+}
+
+impl AsyncSliceWriter for Backend {
+    type WriteAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Self::WriteAtFuture<'_> {
+        self.write_bytes_at(offset, Bytes::copy_from_slice(data))
+    }
+
+    type WriteBytesAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_bytes_at(&mut self, offset: u64, data: Bytes) -> Self::WriteBytesAtFuture<'_> {
+        match self {
+            Backend::Std(file) => file.write_bytes_at(offset, data).boxed(),
+            Backend::Uring(file) => file.write_bytes_at(offset, data),
+        }
+    }
+
+    type SetLenFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn set_len(&mut self, len: u64) -> Self::SetLenFuture<'_> {
+        match self {
+            Backend::Std(file) => file.set_len(len).boxed(),
+            Backend::Uring(file) => file.set_len(len),
+        }
+    }
+
+    type SyncFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn sync(&mut self) -> Self::SyncFuture<'_> {
+        match self {
+            Backend::Std(file) => file.sync().boxed(),
+            Backend::Uring(file) => file.sync(),
+        }
+    }
+}
+
+impl AsyncSliceReader for SyncingFile {
+    type ReadAtFuture<'a> = BoxFuture<'a, io::Result<Bytes>>;
+    fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
+        self.file.read_at(offset, len)
+    }
+
+    type LenFuture<'a> = BoxFuture<'a, io::Result<u64>>;
+    fn len(&mut self) -> Self::LenFuture<'_> {
+        self.file.len()
+    }
+}
+
+impl AsyncSliceWriter for SyncingFile {
+    type WriteAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Self::WriteAtFuture<'_> {
+        self.write_bytes_at(offset, Bytes::copy_from_slice(data))
+    }
+
+    type WriteBytesAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_bytes_at(&mut self, offset: u64, data: Bytes) -> Self::WriteBytesAtFuture<'_> {
+        async move {
+            self.file.write_bytes_at(offset, data).await?;
+            if self.sync_writes {
+                self.file.sync().await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    type SetLenFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn set_len(&mut self, len: u64) -> Self::SetLenFuture<'_> {
+        async move {
+            self.file.set_len(len).await?;
+            if self.sync_writes {
+                self.file.sync().await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    type SyncFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn sync(&mut self) -> Self::SyncFuture<'_> {
+        self.file.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for policy in [
+            FsyncPolicy::PerWrite,
+            FsyncPolicy::OnComplete,
+            FsyncPolicy::Never,
+        ] {
+            assert_eq!(policy.to_string().parse::<FsyncPolicy>().unwrap(), policy);
+        }
+        assert!("always".parse::<FsyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn replace() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("meta");
+        replace_file(&path, b"old", true)?;
+        replace_file(&path, b"new", false)?;
+        assert_eq!(std::fs::read(&path)?, b"new");
+        // only the file itself is left behind
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
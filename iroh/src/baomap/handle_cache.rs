@@ -0,0 +1,367 @@
+//! A cache of open file handles for persistent stores.
+//!
+//! Opening a file for every request gets expensive when serving thousands of concurrent
+//! requests, and can exhaust the descriptor limit of the process. The [`HandleCache`]
+//! keeps recently used read handles open, keyed by hash, and shares them between all
+//! readers of the data and outboard of an entry.
+//!
+//! Handles are closed when the cache is full and they are the least recently used, or
+//! when they have not been used for longer than the idle time to live. A handle that is
+//! evicted while readers are using it stays open until the last of them is dropped.
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bao_tree::io::sync::ReadAt;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use iroh_bytes::Hash;
+use iroh_io::AsyncSliceReader;
+
+use super::flatten_to_io;
+
+/// Interval in which idle handles are closed, in addition to closing them on access.
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Limits for the open file handles of a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandleLimits {
+    /// Maximum number of handles kept open.
+    ///
+    /// With 0, no handles are cached and every reader opens its own file.
+    pub max_open: usize,
+    /// Time after which an unused handle is closed.
+    pub idle_ttl: Duration,
+}
+
+impl Default for HandleLimits {
+    fn default() -> Self {
+        Self {
+            max_open: 1024,
+            idle_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Which file of an entry a handle is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum FileKind {
+    Data,
+    Outboard,
+}
+
+type Key = (Hash, FileKind);
+
+#[derive(Debug)]
+struct Slot {
+    path: PathBuf,
+    file: Arc<fs::File>,
+    tick: u64,
+    last_used: Instant,
+}
+
+#[derive(Debug)]
+struct Inner {
+    limits: HandleLimits,
+    slots: HashMap<Key, Slot>,
+    /// Keys by the tick of their last use, least recently used first.
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &Key) {
+        if let Some(slot) = self.slots.remove(key) {
+            self.lru.remove(&slot.tick);
+        }
+    }
+
+    /// Close handles that have been idle for too long, and handles over the limit.
+    fn evict(&mut self, now: Instant, max_open: usize) {
+        while let Some((_, key)) = self.lru.first_key_value() {
+            let slot = &self.slots[key];
+            let idle = now.saturating_duration_since(slot.last_used) >= self.limits.idle_ttl;
+            if !idle && self.slots.len() <= max_open {
+                break;
+            }
+            let key = *key;
+            self.remove(&key);
+        }
+    }
+}
+
+/// An LRU cache of open read handles, keyed by hash.
+#[derive(Debug)]
+pub(crate) struct HandleCache(Mutex<Inner>);
+
+impl HandleCache {
+    pub fn new(limits: HandleLimits) -> Self {
+        Self(Mutex::new(Inner {
+            limits,
+            slots: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }))
+    }
+
+    pub fn limits(&self) -> HandleLimits {
+        self.0.lock().unwrap().limits
+    }
+
+    /// Change the limits, closing handles that are over the new limits.
+    pub fn set_limits(&self, limits: HandleLimits) {
+        let mut inner = self.0.lock().unwrap();
+        inner.limits = limits;
+        inner.evict(Instant::now(), limits.max_open);
+    }
+
+    /// Close handles that have been idle for longer than the time to live.
+    pub fn evict_idle(&self) {
+        let mut inner = self.0.lock().unwrap();
+        let max_open = inner.limits.max_open;
+        inner.evict(Instant::now(), max_open);
+    }
+
+    /// Close the handles for all files of an entry, e.g. because the files were moved.
+    pub fn invalidate(&self, hash: Hash) {
+        let mut inner = self.0.lock().unwrap();
+        inner.remove(&(hash, FileKind::Data));
+        inner.remove(&(hash, FileKind::Outboard));
+    }
+
+    /// Number of open handles in the cache.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().slots.len()
+    }
+
+    /// Get the cached handle for a file, if it is open.
+    ///
+    /// A cached handle for a different path, e.g. the partial data file of an entry that
+    /// is now complete, is not returned.
+    fn cached(&self, key: Key, path: &Path) -> Option<Arc<fs::File>> {
+        let mut inner = self.0.lock().unwrap();
+        let now = Instant::now();
+        let max_open = inner.limits.max_open;
+        inner.evict(now, max_open);
+        inner.tick += 1;
+        let tick = inner.tick;
+        let slot = inner.slots.get_mut(&key)?;
+        if slot.path != path {
+            return None;
+        }
+        let old_tick = std::mem::replace(&mut slot.tick, tick);
+        slot.last_used = now;
+        let file = slot.file.clone();
+        inner.lru.remove(&old_tick);
+        inner.lru.insert(tick, key);
+        Some(file)
+    }
+
+    fn insert(&self, key: Key, path: PathBuf, file: Arc<fs::File>) {
+        let mut inner = self.0.lock().unwrap();
+        let max_open = inner.limits.max_open;
+        if max_open == 0 {
+            return;
+        }
+        inner.remove(&key);
+        // make room for the new handle
+        inner.evict(Instant::now(), max_open - 1);
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.lru.insert(tick, key);
+        inner.slots.insert(
+            key,
+            Slot {
+                path,
+                file,
+                tick,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    /// Get a handle for a file, opening it if it is not cached.
+    pub async fn open(
+        self: Arc<Self>,
+        hash: Hash,
+        kind: FileKind,
+        path: PathBuf,
+    ) -> io::Result<Arc<fs::File>> {
+        let key = (hash, kind);
+        if let Some(file) = self.cached(key, &path) {
+            return Ok(file);
+        }
+        let file = flatten_to_io(
+            tokio::task::spawn_blocking({
+                let path = path.clone();
+                move || fs::File::open(path)
+            })
+            .await,
+        )?;
+        let file = Arc::new(file);
+        self.insert(key, path, file.clone());
+        Ok(file)
+    }
+}
+
+/// A reader for a file handle that can be shared with other readers.
+#[derive(Debug)]
+pub struct CachedFile {
+    file: Arc<fs::File>,
+    /// Whether the file no longer changes, so its size is only read once.
+    fixed: bool,
+    /// The size of a fixed file, once it has been read.
+    len: Option<u64>,
+}
+
+impl CachedFile {
+    /// A reader for a file that is still being written, e.g. of a partial entry.
+    pub(crate) fn new(file: Arc<fs::File>) -> Self {
+        Self {
+            file,
+            fixed: false,
+            len: None,
+        }
+    }
+
+    /// A reader for a file that no longer changes, e.g. of a complete entry.
+    pub(crate) fn fixed(file: Arc<fs::File>) -> Self {
+        Self {
+            file,
+            fixed: true,
+            len: None,
+        }
+    }
+}
+
+impl AsyncSliceReader for CachedFile {
+    type ReadAtFuture<'a> = BoxFuture<'a, io::Result<Bytes>>;
+
+    fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
+        let file = self.file.clone();
+        let size = self.len;
+        async move {
+            let (data, size) = flatten_to_io(
+                tokio::task::spawn_blocking(move || {
+                    let size = match size {
+                        Some(size) => size,
+                        None => file.metadata()?.len(),
+                    };
+                    // reads past the end are clipped, read_to_end asks for usize::MAX bytes
+                    let available = size.saturating_sub(offset);
+                    let len =
+                        usize::try_from(available).map_or(len, |available| available.min(len));
+                    let mut buf = vec![0u8; len];
+                    let mut filled = 0;
+                    while filled < len {
+                        match file.read_at(offset + filled as u64, &mut buf[filled..]) {
+                            Ok(0) => break,
+                            Ok(n) => filled += n,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    buf.truncate(filled);
+                    Ok((Bytes::from(buf), size))
+                })
+                .await,
+            )?;
+            if self.fixed {
+                self.len = Some(size);
+            }
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    type LenFuture<'a> = BoxFuture<'a, io::Result<u64>>;
+
+    fn len(&mut self) -> Self::LenFuture<'_> {
+        let file = self.file.clone();
+        async move {
+            let len = match self.len {
+                Some(len) => len,
+                None => flatten_to_io(
+                    tokio::task::spawn_blocking(move || Ok(file.metadata()?.len())).await,
+                )?,
+            };
+            if self.fixed {
+                self.len = Some(len);
+            }
+            Ok(len)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lru_and_idle_eviction() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let hashes = (0u8..3).map(|i| Hash::new([i])).collect::<Vec<_>>();
+        let paths = (0..3)
+            .map(|i| dir.path().join(format!("{i}.data")))
+            .collect::<Vec<_>>();
+        for path in &paths {
+            std::fs::write(path, b"hello")?;
+        }
+        let cache = Arc::new(HandleCache::new(HandleLimits {
+            max_open: 2,
+            idle_ttl: Duration::from_secs(3600),
+        }));
+        let open = |i: usize| {
+            cache
+                .clone()
+                .open(hashes[i], FileKind::Data, paths[i].clone())
+        };
+
+        let a = open(0).await?;
+        assert!(Arc::ptr_eq(&a, &open(0).await?));
+        open(1).await?;
+        // 0 was used more recently than 1, so 1 is evicted
+        open(0).await?;
+        open(2).await?;
+        assert_eq!(cache.len(), 2);
+        assert!(Arc::ptr_eq(&a, &open(0).await?));
+
+        // a different path for the same hash is not served from the cache
+        let other = cache
+            .clone()
+            .open(hashes[0], FileKind::Data, paths[1].clone())
+            .await?;
+        assert!(!Arc::ptr_eq(&a, &other));
+
+        let mut reader = CachedFile::new(other.clone());
+        assert_eq!(reader.len().await?, 5);
+        assert_eq!(&reader.read_at(1, usize::MAX).await?[..], b"ello");
+
+        // the size of a growing file is read again, the size of a fixed file is cached
+        let mut fixed = CachedFile::fixed(other);
+        assert_eq!(fixed.len().await?, 5);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&paths[1])?
+            .write_all(b"!")?;
+        assert_eq!(&reader.read_at(1, usize::MAX).await?[..], b"ello!");
+        assert_eq!(&fixed.read_at(1, usize::MAX).await?[..], b"ello");
+
+        cache.invalidate(hashes[0]);
+        assert_eq!(cache.len(), 1);
+        cache.set_limits(HandleLimits {
+            max_open: 2,
+            idle_ttl: Duration::ZERO,
+        });
+        assert_eq!(cache.len(), 0);
+        Ok(())
+    }
+}
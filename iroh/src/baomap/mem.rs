@@ -1,14 +1,14 @@
 //! A full in memory database for iroh-bytes
 //!
 //! Main entry point is [Store].
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io;
-use std::io::Write;
 use std::num::TryFromIntError;
 use std::ops::DerefMut;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::SystemTime;
 
 use bao_tree::blake3;
 use bao_tree::io::fsm::Outboard;
@@ -24,11 +24,15 @@ use futures::future::BoxFuture;
 use futures::FutureExt;
 use iroh_bytes::baomap;
 use iroh_bytes::baomap::range_collections::RangeSet2;
+use iroh_bytes::baomap::AppendableEntry;
+use iroh_bytes::baomap::AppendableStore;
 use iroh_bytes::baomap::ExportMode;
 use iroh_bytes::baomap::ImportMode;
 use iroh_bytes::baomap::ImportProgress;
 use iroh_bytes::baomap::PartialMap;
 use iroh_bytes::baomap::PartialMapEntry;
+use iroh_bytes::baomap::Pin;
+use iroh_bytes::baomap::StoreEvent;
 use iroh_bytes::baomap::ValidateProgress;
 use iroh_bytes::baomap::{Map, MapEntry, ReadableStore};
 use iroh_bytes::util::progress::IdGenerator;
@@ -38,9 +42,15 @@ use iroh_bytes::util::runtime;
 use iroh_bytes::{Hash, IROH_BLOCK_SIZE};
 use iroh_io::AsyncSliceReader;
 use iroh_io::AsyncSliceWriter;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
 
+use super::append::IncrementalOutboard;
+use super::cancel;
 use super::flatten_to_io;
+use super::outboard_hasher::OutboardHasher;
+use super::validation;
+use super::verified::{TrackedWriter, VerifiedRanges};
 
 /// A mutable file like object that can be used for partial entries.
 #[derive(Debug, Clone, Default)]
@@ -190,12 +200,22 @@ pub struct Store(Arc<Inner>);
 struct Inner {
     rt: runtime::Handle,
     state: RwLock<State>,
+    outboard_hasher: RwLock<OutboardHasher>,
+    events: broadcast::Sender<StoreEvent>,
 }
 
 #[derive(Debug, Clone, Default)]
 struct State {
     complete: BTreeMap<Hash, (Bytes, PreOrderOutboard<Bytes>)>,
-    partial: BTreeMap<Hash, (MutableMemFile, PreOrderOutboard<MutableMemFile>)>,
+    partial: BTreeMap<
+        Hash,
+        (
+            MutableMemFile,
+            PreOrderOutboard<MutableMemFile>,
+            VerifiedRanges,
+        ),
+    >,
+    pins: BTreeMap<String, Pin>,
 }
 
 /// The [MapEntry] implementation for [Store].
@@ -204,6 +224,8 @@ pub struct Entry {
     hash: blake3::Hash,
     outboard: PreOrderOutboard<MemFile>,
     data: MemFile,
+    complete: bool,
+    verified: Option<VerifiedRanges>,
 }
 
 impl MapEntry<Store> for Entry {
@@ -212,13 +234,22 @@ impl MapEntry<Store> for Entry {
     }
 
     fn available_ranges(&self) -> BoxFuture<'_, io::Result<RangeSet2<ChunkNum>>> {
-        futures::future::ok(RangeSet2::all()).boxed()
+        match &self.verified {
+            Some(verified) => verified
+                .get(self.outboard.clone(), self.data.clone(), self.size())
+                .boxed(),
+            None => futures::future::ok(RangeSet2::all()).boxed(),
+        }
     }
 
     fn size(&self) -> u64 {
         self.outboard.tree().size().0
     }
 
+    fn is_complete(&self) -> bool {
+        self.complete
+    }
+
     fn outboard(&self) -> BoxFuture<'_, io::Result<PreOrderOutboard<MemFile>>> {
         futures::future::ok(self.outboard.clone()).boxed()
     }
@@ -234,6 +265,7 @@ pub struct PartialEntry {
     hash: blake3::Hash,
     outboard: PreOrderOutboard<MutableMemFile>,
     data: MutableMemFile,
+    verified: VerifiedRanges,
 }
 
 impl MapEntry<Store> for PartialEntry {
@@ -242,13 +274,19 @@ impl MapEntry<Store> for PartialEntry {
     }
 
     fn available_ranges(&self) -> BoxFuture<'_, io::Result<RangeSet2<bao_tree::ChunkNum>>> {
-        futures::future::ok(RangeSet2::all()).boxed()
+        self.verified
+            .get(self.outboard.clone(), self.data.clone(), self.size())
+            .boxed()
     }
 
     fn size(&self) -> u64 {
         self.outboard.tree().size().0
     }
 
+    fn is_complete(&self) -> bool {
+        false
+    }
+
     fn outboard(&self) -> BoxFuture<'_, io::Result<PreOrderOutboard<MemFile>>> {
         futures::future::ok(PreOrderOutboard {
             root: self.outboard.root,
@@ -280,8 +318,10 @@ impl Map for Store {
                     data: outboard.data.clone().into(),
                 },
                 data: data.clone().into(),
+                complete: true,
+                verified: None,
             })
-        } else if let Some((data, outboard)) = state.partial.get(hash) {
+        } else if let Some((data, outboard, verified)) = state.partial.get(hash) {
             Some(Entry {
                 hash: (*hash).into(),
                 outboard: PreOrderOutboard {
@@ -290,6 +330,8 @@ impl Map for Store {
                     data: outboard.data.clone().into(),
                 },
                 data: data.clone().into(),
+                complete: false,
+                verified: Some(verified.clone()),
             })
         } else {
             None
@@ -313,11 +355,20 @@ impl ReadableStore for Store {
     }
 
     fn roots(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
-        Box::new(std::iter::empty())
+        let now = SystemTime::now();
+        let state = self.0.state.read().unwrap();
+        let roots = state
+            .pins
+            .values()
+            .filter(|pin| pin.recursive && !pin.is_expired(now))
+            .filter(|pin| state.complete.contains_key(&pin.hash))
+            .map(|pin| pin.hash)
+            .collect::<BTreeSet<_>>();
+        Box::new(roots.into_iter())
     }
 
-    fn validate(&self, _tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>> {
-        futures::future::err(anyhow::anyhow!("validate not implemented")).boxed()
+    fn validate(&self, tx: mpsc::Sender<ValidateProgress>) -> BoxFuture<'_, anyhow::Result<()>> {
+        validation::validate_store(self, tx).boxed()
     }
 
     fn partial_blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
@@ -326,6 +377,16 @@ impl ReadableStore for Store {
         Box::new(hashes.into_iter())
     }
 
+    fn pins(&self) -> Box<dyn Iterator<Item = (String, Pin)> + Send + Sync + 'static> {
+        let state = self.0.state.read().unwrap();
+        let pins = state.pins.clone();
+        Box::new(pins.into_iter())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<StoreEvent> {
+        self.0.events.subscribe()
+    }
+
     fn export(
         &self,
         hash: Hash,
@@ -334,29 +395,28 @@ impl ReadableStore for Store {
         progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
     ) -> BoxFuture<'_, io::Result<()>> {
         let this = self.clone();
-        self.0
-            .rt
-            .main()
-            .spawn_blocking(move || this.export_sync(hash, target, mode, progress))
-            .map(flatten_to_io)
-            .boxed()
+        cancel::spawn_blocking(self.0.rt.main(), move |cancel| {
+            this.export_sync(hash, target, mode, progress, cancel)
+        })
+        .boxed()
     }
 }
 
 impl PartialMap for Store {
-    type OutboardMut = PreOrderOutboard<MutableMemFile>;
+    type OutboardMut = PreOrderOutboard<TrackedWriter<MutableMemFile>>;
 
-    type DataWriter = MutableMemFile;
+    type DataWriter = TrackedWriter<MutableMemFile>;
 
     type PartialEntry = PartialEntry;
 
     fn get_partial(&self, hash: &Hash) -> Option<PartialEntry> {
         let state = self.0.state.read().unwrap();
-        let (data, outboard) = state.partial.get(hash)?;
+        let (data, outboard, verified) = state.partial.get(hash)?;
         Some(PartialEntry {
             hash: (*hash).into(),
             outboard: outboard.clone(),
             data: data.clone(),
+            verified: verified.clone(),
         })
     }
 
@@ -372,13 +432,15 @@ impl PartialMap for Store {
             tree,
             data: outboard.clone(),
         };
+        let verified = VerifiedRanges::default();
         // insert into the partial map, replacing any existing entry
         self.0
             .state
             .write()
             .unwrap()
             .partial
-            .insert(hash, (data.clone(), ob2.clone()));
+            .insert(hash, (data.clone(), ob2.clone(), verified.clone()));
+        self.notify(StoreEvent::Added(hash));
         Ok(PartialEntry {
             hash: hash.into(),
             outboard: PreOrderOutboard {
@@ -387,6 +449,7 @@ impl PartialMap for Store {
                 data: outboard,
             },
             data,
+            verified,
         })
     }
 
@@ -404,6 +467,7 @@ impl PartialMap for Store {
             };
             state.partial.remove(&hash);
             state.complete.insert(hash, (data, outboard));
+            self.notify(StoreEvent::Added(hash));
             Ok(())
         }
         .boxed()
@@ -418,28 +482,25 @@ impl baomap::Store for Store {
         progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
     ) -> BoxFuture<'_, io::Result<(Hash, u64)>> {
         let this = self.clone();
-        self.0
-            .rt
-            .main()
-            .spawn_blocking(move || {
-                let id = progress.new_id();
-                progress.blocking_send(ImportProgress::Found {
-                    id,
-                    path: path.clone(),
-                })?;
-                progress.try_send(ImportProgress::CopyProgress { id, offset: 0 })?;
-                // todo: provide progress for reading into mem
-                let bytes: Bytes = std::fs::read(path)?.into();
-                progress.blocking_send(ImportProgress::Size {
-                    id,
-                    size: bytes.len() as u64,
-                })?;
-                let size = bytes.len() as u64;
-                let hash = this.import_bytes_sync(bytes, progress)?;
-                Ok((hash, size))
-            })
-            .map(flatten_to_io)
-            .boxed()
+        cancel::spawn_blocking(self.0.rt.main(), move |cancel| {
+            let id = progress.new_id();
+            progress.blocking_send(ImportProgress::Found {
+                id,
+                path: path.clone(),
+            })?;
+            progress.try_send(ImportProgress::CopyProgress { id, offset: 0 })?;
+            // todo: provide progress for reading into mem
+            let bytes: Bytes = std::fs::read(path)?.into();
+            cancel::check(&cancel)?;
+            progress.blocking_send(ImportProgress::Size {
+                id,
+                size: bytes.len() as u64,
+            })?;
+            let size = bytes.len() as u64;
+            let hash = this.import_bytes_sync(bytes, progress)?;
+            Ok((hash, size))
+        })
+        .boxed()
     }
 
     fn import_bytes(&self, bytes: Bytes) -> BoxFuture<'_, io::Result<Hash>> {
@@ -451,42 +512,138 @@ impl baomap::Store for Store {
             .map(flatten_to_io)
             .boxed()
     }
+
+    fn set_pin(&self, name: String, pin: Pin) -> BoxFuture<'_, io::Result<()>> {
+        self.0.state.write().unwrap().pins.insert(name, pin);
+        self.notify(StoreEvent::PinsChanged);
+        futures::future::ok(()).boxed()
+    }
+
+    fn remove_pin(&self, name: String) -> BoxFuture<'_, io::Result<Option<Pin>>> {
+        let pin = self.0.state.write().unwrap().pins.remove(&name);
+        self.notify(StoreEvent::PinsChanged);
+        futures::future::ok(pin).boxed()
+    }
+
+    fn quarantine(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        self.0.state.write().unwrap().complete.remove(&hash);
+        self.notify(StoreEvent::Removed(hash));
+        futures::future::ok(()).boxed()
+    }
+
+    fn delete(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let now = SystemTime::now();
+        let mut state = self.0.state.write().unwrap();
+        if let Some((name, _)) = state.pins.iter().find(|(_, pin)| pin.protects(&hash, now)) {
+            return futures::future::err(Pin::protected_error(&hash, name)).boxed();
+        }
+        // there is no trash, keeping deleted data would defeat the purpose of deleting it
+        state.complete.remove(&hash);
+        self.notify(StoreEvent::Removed(hash));
+        futures::future::ok(()).boxed()
+    }
+
+    fn restore(&self, hash: Hash) -> BoxFuture<'_, io::Result<bool>> {
+        let _ = hash;
+        futures::future::ok(false).boxed()
+    }
+
+    fn export_partial(&self, hash: Hash, target: PathBuf) -> BoxFuture<'_, io::Result<()>> {
+        // partial entries of an in memory store do not outlive the process
+        let _ = (hash, target);
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn import_partial(&self, path: PathBuf) -> BoxFuture<'_, io::Result<Hash>> {
+        let _ = path;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
 }
 
 impl Store {
     /// Create a new in memory database, using the given runtime.
-    pub fn new(rt: runtime::Handle) -> Self {
+    ///
+    /// Accepts a plain tokio runtime handle as well, see [`runtime`].
+    pub fn new(rt: impl Into<runtime::Handle>) -> Self {
         Self(Arc::new(Inner {
-            rt,
+            rt: rt.into(),
             state: RwLock::new(State::default()),
+            outboard_hasher: Default::default(),
+            events: broadcast::channel(64).0,
         }))
     }
 
+    /// Compute the outboards of large imports on up to `threads` threads.
+    ///
+    /// With 0 threads, which is the default, a thread per core is used. With 1 thread,
+    /// imports are hashed on a single thread.
+    pub fn set_hash_threads(&self, threads: usize) -> io::Result<()> {
+        let hasher = OutboardHasher::with_threads(threads)?;
+        *self.0.outboard_hasher.write().unwrap() = hasher;
+        Ok(())
+    }
+
+    /// Flip the byte at `offset` of the complete blob `hash`, keeping its outboard.
+    ///
+    /// This simulates bit rot: the blob no longer matches its hash, so serving it fails
+    /// verification. Returns false if the blob is not complete or is too small.
+    #[cfg(any(test, feature = "test"))]
+    pub fn corrupt(&self, hash: &Hash, offset: u64) -> bool {
+        let mut state = self.0.state.write().unwrap();
+        let Some((data, _)) = state.complete.get_mut(hash) else {
+            return false;
+        };
+        let Some(offset) = usize::try_from(offset).ok().filter(|o| *o < data.len()) else {
+            return false;
+        };
+        let mut corrupted = BytesMut::from(&data[..]);
+        corrupted[offset] ^= 0xff;
+        *data = corrupted.freeze();
+        true
+    }
+
+    /// Returns true if the data was already in the store.
+    fn insert_complete_sync(&self, hash: blake3::Hash, data: Bytes, outboard: Bytes) -> bool {
+        let tree = BaoTree::new(ByteNum(data.len() as u64), IROH_BLOCK_SIZE);
+        let outboard = PreOrderOutboard {
+            root: hash,
+            tree,
+            data: outboard,
+        };
+        let existed = self
+            .0
+            .state
+            .write()
+            .unwrap()
+            .complete
+            .insert(hash.into(), (data, outboard))
+            .is_some();
+        self.notify(StoreEvent::Added(hash.into()));
+        existed
+    }
+
+    fn notify(&self, event: StoreEvent) {
+        // no subscribers is not an error
+        self.0.events.send(event).ok();
+    }
+
     fn import_bytes_sync(
         &self,
         bytes: Bytes,
         progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
     ) -> io::Result<Hash> {
-        let size = bytes.len() as u64;
         let id = progress.new_id();
         progress.blocking_send(ImportProgress::OutboardProgress { id, offset: 0 })?;
-        let (outboard, hash) = bao_tree::io::outboard(&bytes, IROH_BLOCK_SIZE);
+        let hasher = self.0.outboard_hasher.read().unwrap().clone();
+        let (outboard, hash) = hasher.outboard(&bytes);
         progress.blocking_send(ImportProgress::OutboardDone {
             id,
             hash: hash.into(),
         })?;
-        let tree = BaoTree::new(ByteNum(size), IROH_BLOCK_SIZE);
-        let outboard = PreOrderOutboard {
-            root: hash,
-            tree,
-            data: outboard.into(),
-        };
-        self.0
-            .state
-            .write()
-            .unwrap()
-            .complete
-            .insert(hash.into(), (bytes, outboard));
+        let size = bytes.len() as u64;
+        if self.insert_complete_sync(hash, bytes, outboard.into()) {
+            progress.blocking_send(ImportProgress::Deduplicated { id, size })?;
+        }
         Ok(hash.into())
     }
 
@@ -496,6 +653,7 @@ impl Store {
         target: PathBuf,
         _mode: ExportMode,
         progress: impl Fn(u64) -> io::Result<()> + Send + Sync + 'static,
+        cancel: CancellationToken,
     ) -> io::Result<()> {
         tracing::trace!("exporting {} to {}", hash, target.display());
 
@@ -513,32 +671,104 @@ impl Store {
         })?;
         // create the directory in which the target file is
         std::fs::create_dir_all(parent)?;
-        let state = self.0.state.read().unwrap();
-        let (data, _) = state
-            .complete
-            .get(&hash)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "hash not found"))?;
-
-        let mut file = std::fs::File::create(target)?;
-        let mut offset = 0;
-        for chunk in data.chunks(1024 * 1024) {
-            progress(offset)?;
-            file.write_all(chunk)?;
-            offset += chunk.len() as u64;
-        }
-        file.flush()?;
-        drop(file);
+        let data = {
+            let state = self.0.state.read().unwrap();
+            let (data, _) = state
+                .complete
+                .get(&hash)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "hash not found"))?;
+            data.clone()
+        };
+        cancel::copy_to_file(&data[..], &target, &cancel, progress)?;
         Ok(())
     }
 }
 
 impl PartialMapEntry<Store> for PartialEntry {
-    fn outboard_mut(&self) -> BoxFuture<'_, io::Result<PreOrderOutboard<MutableMemFile>>> {
-        futures::future::ok(self.outboard.clone()).boxed()
+    fn outboard_mut(&self) -> BoxFuture<'_, io::Result<<Store as PartialMap>::OutboardMut>> {
+        futures::future::ok(PreOrderOutboard {
+            root: self.outboard.root,
+            tree: self.outboard.tree,
+            data: TrackedWriter::outboard(self.outboard.data.clone(), self.verified.clone()),
+        })
+        .boxed()
     }
 
-    fn data_writer(&self) -> BoxFuture<'_, io::Result<MutableMemFile>> {
-        futures::future::ok(self.data.clone()).boxed()
+    fn data_writer(&self) -> BoxFuture<'_, io::Result<<Store as PartialMap>::DataWriter>> {
+        futures::future::ok(TrackedWriter::data(
+            self.data.clone(),
+            self.verified.clone(),
+        ))
+        .boxed()
+    }
+}
+
+/// The [AppendableEntry] implementation for [Store].
+///
+/// Blocks are hashed as they are appended, so large appends should be done from a
+/// blocking context.
+///
+/// Sealing hands the buffer to the store instead of copying it, and sealing again
+/// without appending in between returns the previous hash. Appending after a seal has
+/// to copy the sealed data once, since the store keeps every sealed version.
+#[derive(Debug)]
+pub struct AppendEntry {
+    store: Store,
+    /// Data appended so far, empty while the data is sealed.
+    data: BytesMut,
+    /// The data of the last seal and its hash, if nothing was appended since.
+    sealed: Option<(Bytes, Hash)>,
+    outboard: IncrementalOutboard,
+}
+
+impl AppendEntry {
+    fn seal_sync(&mut self) -> io::Result<Hash> {
+        if let Some((_, hash)) = &self.sealed {
+            return Ok(*hash);
+        }
+        let (hash, outboard) = self.outboard.seal(&self.data)?;
+        let data = std::mem::take(&mut self.data).freeze();
+        self.store
+            .insert_complete_sync(hash, data.clone(), outboard.into());
+        self.sealed = Some((data, hash.into()));
+        Ok(hash.into())
+    }
+}
+
+impl AppendableEntry for AppendEntry {
+    fn size(&self) -> u64 {
+        match &self.sealed {
+            Some((data, _)) => data.len() as u64,
+            None => self.data.len() as u64,
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) -> BoxFuture<'_, io::Result<()>> {
+        if let Some((sealed, _)) = self.sealed.take() {
+            // the sealed buffer is shared with the store, continue in a copy
+            self.data = BytesMut::with_capacity(sealed.len() + data.len());
+            self.data.extend_from_slice(&sealed);
+        }
+        self.data.extend_from_slice(data);
+        self.outboard.update(&self.data);
+        futures::future::ok(()).boxed()
+    }
+
+    fn seal(&mut self) -> BoxFuture<'_, io::Result<Hash>> {
+        futures::future::ready(self.seal_sync()).boxed()
+    }
+}
+
+impl AppendableStore for Store {
+    type AppendableEntry = AppendEntry;
+
+    fn create_appendable(&self) -> io::Result<AppendEntry> {
+        Ok(AppendEntry {
+            store: self.clone(),
+            data: BytesMut::new(),
+            sealed: None,
+            outboard: IncrementalOutboard::new(),
+        })
     }
 }
 
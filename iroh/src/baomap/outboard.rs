@@ -0,0 +1,320 @@
+//! Outboard encodings and conversions between them.
+//!
+//! All stores serve data using pre-order outboards, since that is what the bao
+//! encoding used on the wire is based on. For storage, other encodings can be more
+//! convenient:
+//!
+//! - **pre-order**: the 8 byte little endian size, followed by the hash pairs of the
+//!   tree in pre-order. This is the default.
+//! - **post-order**: the hash pairs in post-order, followed by the 8 byte little endian
+//!   size. A post-order outboard can be written incrementally while the data is hashed,
+//!   since every pair is written after the pairs of its children, so it is append
+//!   friendly.
+//! - **combined**: the 8 byte little endian size, followed by the tree in pre-order with
+//!   the data interleaved, i.e. the standard bao encoding of the entire blob.
+//!
+//! The conversions in this module only shuffle bytes, they do not verify hashes.
+//! All outboards use the iroh block size, see [`IROH_BLOCK_SIZE`].
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use iroh_bytes::IROH_BLOCK_SIZE;
+use serde::{Deserialize, Serialize};
+
+/// Size of a hash pair in an outboard.
+const PAIR_SIZE: usize = 64;
+
+/// Size of the length prefix or suffix of an outboard.
+const SIZE_LEN: usize = 8;
+
+/// An encoding for outboard data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutboardFormat {
+    /// Size prefix followed by the hash pairs in pre-order.
+    #[default]
+    PreOrder,
+    /// Hash pairs in post-order followed by a size suffix.
+    PostOrder,
+    /// Size prefix followed by the hash pairs in pre-order, interleaved with the data.
+    Combined,
+}
+
+impl fmt::Display for OutboardFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutboardFormat::PreOrder => write!(f, "pre-order"),
+            OutboardFormat::PostOrder => write!(f, "post-order"),
+            OutboardFormat::Combined => write!(f, "combined"),
+        }
+    }
+}
+
+impl FromStr for OutboardFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pre-order" => Ok(OutboardFormat::PreOrder),
+            "post-order" => Ok(OutboardFormat::PostOrder),
+            "combined" => Ok(OutboardFormat::Combined),
+            _ => anyhow::bail!("unknown outboard format: {}", s),
+        }
+    }
+}
+
+/// Convert a pre-order outboard to a post-order outboard.
+pub fn pre_order_to_post_order(outboard: &[u8]) -> io::Result<Vec<u8>> {
+    let (size, pairs) = split_prefix(outboard)?;
+    let blocks = blocks(size);
+    check_pairs(pairs, blocks)?;
+    let mut res = Vec::with_capacity(outboard.len());
+    pre_to_post(pairs, blocks, &mut res);
+    res.extend_from_slice(&size.to_le_bytes());
+    Ok(res)
+}
+
+/// Convert a post-order outboard to a pre-order outboard.
+pub fn post_order_to_pre_order(outboard: &[u8]) -> io::Result<Vec<u8>> {
+    let (pairs, size) = split_suffix(outboard)?;
+    let blocks = blocks(size);
+    check_pairs(pairs, blocks)?;
+    let mut res = Vec::with_capacity(outboard.len());
+    res.extend_from_slice(&size.to_le_bytes());
+    post_to_pre(pairs, blocks, &mut res);
+    Ok(res)
+}
+
+/// Interleave data with its pre-order outboard, producing the combined encoding.
+pub fn encode_combined(data: &[u8], outboard: &[u8]) -> io::Result<Vec<u8>> {
+    let (size, pairs) = split_prefix(outboard)?;
+    if size != data.len() as u64 {
+        return Err(invalid("outboard size does not match data size"));
+    }
+    let blocks = blocks(size);
+    check_pairs(pairs, blocks)?;
+    let mut res = Vec::with_capacity(data.len() + outboard.len());
+    res.extend_from_slice(&size.to_le_bytes());
+    encode(data, pairs, blocks, &mut res);
+    Ok(res)
+}
+
+/// Split the combined encoding into the data and its pre-order outboard.
+pub fn decode_combined(encoded: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let (size, rest) = split_prefix(encoded)?;
+    let blocks = blocks(size);
+    let size_usize = usize::try_from(size).map_err(|_| invalid("size too large"))?;
+    let pairs_len = pairs_len(blocks)?;
+    if rest.len() != size_usize + pairs_len {
+        return Err(invalid("encoded length does not match size"));
+    }
+    let mut data = Vec::with_capacity(size_usize);
+    let mut outboard = Vec::with_capacity(SIZE_LEN + pairs_len);
+    outboard.extend_from_slice(&size.to_le_bytes());
+    decode(rest, blocks, &mut data, &mut outboard);
+    Ok((data, outboard))
+}
+
+/// Convert an outboard in the given format to a pre-order outboard.
+///
+/// The combined format is not an outboard, so it is rejected.
+pub fn to_pre_order(format: OutboardFormat, outboard: &[u8]) -> io::Result<Vec<u8>> {
+    match format {
+        OutboardFormat::PreOrder => Ok(outboard.to_vec()),
+        OutboardFormat::PostOrder => post_order_to_pre_order(outboard),
+        OutboardFormat::Combined => Err(invalid("combined encoding is not an outboard")),
+    }
+}
+
+/// Convert a pre-order outboard to an outboard in the given format.
+///
+/// The combined format is not an outboard, so it is rejected.
+pub fn from_pre_order(format: OutboardFormat, outboard: &[u8]) -> io::Result<Vec<u8>> {
+    match format {
+        OutboardFormat::PreOrder => Ok(outboard.to_vec()),
+        OutboardFormat::PostOrder => pre_order_to_post_order(outboard),
+        OutboardFormat::Combined => Err(invalid("combined encoding is not an outboard")),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn split_prefix(data: &[u8]) -> io::Result<(u64, &[u8])> {
+    if data.len() < SIZE_LEN {
+        return Err(invalid("outboard too short"));
+    }
+    let (size, rest) = data.split_at(SIZE_LEN);
+    Ok((u64::from_le_bytes(size.try_into().unwrap()), rest))
+}
+
+fn split_suffix(data: &[u8]) -> io::Result<(&[u8], u64)> {
+    if data.len() < SIZE_LEN {
+        return Err(invalid("outboard too short"));
+    }
+    let (rest, size) = data.split_at(data.len() - SIZE_LEN);
+    Ok((rest, u64::from_le_bytes(size.try_into().unwrap())))
+}
+
+/// Number of leaf blocks of the tree for the given data size.
+pub(super) fn blocks(size: u64) -> u64 {
+    let block_size = IROH_BLOCK_SIZE.bytes() as u64;
+    ((size + block_size - 1) / block_size).max(1)
+}
+
+fn pairs_len(blocks: u64) -> io::Result<usize> {
+    usize::try_from(blocks - 1)
+        .ok()
+        .and_then(|n| n.checked_mul(PAIR_SIZE))
+        .ok_or_else(|| invalid("size too large"))
+}
+
+fn check_pairs(pairs: &[u8], blocks: u64) -> io::Result<()> {
+    if pairs.len() != pairs_len(blocks)? {
+        return Err(invalid("outboard length does not match size"));
+    }
+    Ok(())
+}
+
+/// Number of blocks in the left subtree of a tree with the given number of blocks.
+///
+/// The tree is left-full, so this is the largest power of two smaller than `blocks`.
+pub(super) fn left_blocks(blocks: u64) -> u64 {
+    debug_assert!(blocks >= 2);
+    1 << (63 - (blocks - 1).leading_zeros())
+}
+
+fn pre_to_post(pairs: &[u8], blocks: u64, res: &mut Vec<u8>) {
+    if blocks < 2 {
+        return;
+    }
+    let left = left_blocks(blocks);
+    let (pair, children) = pairs.split_at(PAIR_SIZE);
+    let (l, r) = children.split_at((left as usize - 1) * PAIR_SIZE);
+    pre_to_post(l, left, res);
+    pre_to_post(r, blocks - left, res);
+    res.extend_from_slice(pair);
+}
+
+fn post_to_pre(pairs: &[u8], blocks: u64, res: &mut Vec<u8>) {
+    if blocks < 2 {
+        return;
+    }
+    let left = left_blocks(blocks);
+    let (children, pair) = pairs.split_at(pairs.len() - PAIR_SIZE);
+    let (l, r) = children.split_at((left as usize - 1) * PAIR_SIZE);
+    res.extend_from_slice(pair);
+    post_to_pre(l, left, res);
+    post_to_pre(r, blocks - left, res);
+}
+
+fn encode(data: &[u8], pairs: &[u8], blocks: u64, res: &mut Vec<u8>) {
+    if blocks < 2 {
+        res.extend_from_slice(data);
+        return;
+    }
+    let left = left_blocks(blocks);
+    let (pair, children) = pairs.split_at(PAIR_SIZE);
+    let (l, r) = children.split_at((left as usize - 1) * PAIR_SIZE);
+    let (ld, rd) = data.split_at(left as usize * IROH_BLOCK_SIZE.bytes());
+    res.extend_from_slice(pair);
+    encode(ld, l, left, res);
+    encode(rd, r, blocks - left, res);
+}
+
+/// Decode a subtree of the combined encoding.
+///
+/// `encoded` must contain exactly the subtree, which is checked by the caller.
+fn decode(encoded: &[u8], blocks: u64, data: &mut Vec<u8>, pairs: &mut Vec<u8>) {
+    if blocks < 2 {
+        data.extend_from_slice(encoded);
+        return;
+    }
+    let left = left_blocks(blocks);
+    let left_size = left as usize * IROH_BLOCK_SIZE.bytes();
+    let left_len = left_size + (left as usize - 1) * PAIR_SIZE;
+    let (pair, children) = encoded.split_at(PAIR_SIZE);
+    let (l, r) = children.split_at(left_len);
+    pairs.extend_from_slice(pair);
+    decode(l, left, data, pairs);
+    decode(r, blocks - left, data, pairs);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn post_order(data: &[u8]) -> Vec<u8> {
+        let mut res = Vec::new();
+        bao_tree::io::sync::outboard_post_order(
+            &mut Cursor::new(data),
+            data.len() as u64,
+            IROH_BLOCK_SIZE,
+            &mut res,
+        )
+        .unwrap();
+        res
+    }
+
+    const SIZES: &[usize] = &[
+        0,
+        1,
+        16 * 1024,
+        16 * 1024 + 1,
+        3 * 16 * 1024,
+        100_000,
+        1 << 20,
+    ];
+
+    #[test]
+    fn left_blocks_is_largest_power_of_two() {
+        assert_eq!(left_blocks(2), 1);
+        assert_eq!(left_blocks(3), 2);
+        assert_eq!(left_blocks(4), 2);
+        assert_eq!(left_blocks(5), 4);
+        assert_eq!(left_blocks(8), 4);
+        assert_eq!(left_blocks(9), 8);
+    }
+
+    #[test]
+    fn pre_post_conversion() {
+        for &size in SIZES {
+            let data = test_data(size);
+            let (pre, _) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+            let post = post_order(&data);
+            assert_eq!(pre_order_to_post_order(&pre).unwrap(), post, "size {size}");
+            assert_eq!(post_order_to_pre_order(&post).unwrap(), pre, "size {size}");
+        }
+    }
+
+    #[test]
+    fn combined_roundtrip() {
+        for &size in SIZES {
+            let data = test_data(size);
+            let (pre, _) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+            let encoded = encode_combined(&data, &pre).unwrap();
+            assert_eq!(encoded.len(), data.len() + pre.len());
+            let (data2, pre2) = decode_combined(&encoded).unwrap();
+            assert_eq!(data2, data, "size {size}");
+            assert_eq!(pre2, pre, "size {size}");
+        }
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let data = test_data(100_000);
+        let (pre, _) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        assert!(pre_order_to_post_order(&pre[..pre.len() - 1]).is_err());
+        assert!(encode_combined(&data[1..], &pre).is_err());
+        let encoded = encode_combined(&data, &pre).unwrap();
+        assert!(decode_combined(&encoded[..encoded.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Multi-threaded outboard computation for large imports.
+//!
+//! Computing the outboard of a blob hashes all of its data, which on a single thread
+//! is limited to the hashing speed of one core. The bao tree of a blob splits into
+//! independent subtrees, so the [`OutboardHasher`] hashes large blobs by computing the
+//! subtrees on a rayon thread pool and combining the results.
+//!
+//! The resulting outboards are identical to the ones computed on a single thread.
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bao_tree::blake3;
+use bao_tree::blake3::guts::{hash_subtree, parent_cv};
+use bao_tree::io::sync::ReadAt;
+use iroh_bytes::IROH_BLOCK_SIZE;
+
+use super::outboard::{blocks, left_blocks};
+
+/// Data of at least this size is hashed on multiple threads by default.
+pub const DEFAULT_PARALLEL_THRESHOLD: u64 = 1024 * 1024 * 16;
+
+/// Subtrees up to this size are hashed on a single thread.
+const SEQUENTIAL_SIZE: u64 = 1024 * 1024;
+
+/// Computes outboards, on multiple threads for large data.
+#[derive(Debug, Clone)]
+pub struct OutboardHasher {
+    /// The pool to hash on, the global rayon pool if `None`.
+    pool: Option<Arc<rayon::ThreadPool>>,
+    /// Data of at least this size is hashed on multiple threads.
+    threshold: u64,
+}
+
+impl Default for OutboardHasher {
+    fn default() -> Self {
+        Self {
+            pool: None,
+            threshold: DEFAULT_PARALLEL_THRESHOLD,
+        }
+    }
+}
+
+impl OutboardHasher {
+    /// Create a hasher that uses up to `threads` threads.
+    ///
+    /// With 0 threads, the global rayon pool is used, which has a thread per core. With
+    /// 1 thread, all data is hashed on the calling thread.
+    pub fn with_threads(threads: usize) -> io::Result<Self> {
+        match threads {
+            0 => Ok(Self::default()),
+            1 => Ok(Self {
+                pool: None,
+                threshold: u64::MAX,
+            }),
+            n => {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .thread_name(|i| format!("iroh-hash-{i}"))
+                    .build()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                Ok(Self {
+                    pool: Some(Arc::new(pool)),
+                    threshold: DEFAULT_PARALLEL_THRESHOLD,
+                })
+            }
+        }
+    }
+
+    /// Set the size from which data is hashed on multiple threads.
+    pub fn threshold(mut self, threshold: u64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Compute the pre-order outboard and the hash of in-memory data.
+    pub fn outboard(&self, data: &[u8]) -> (Vec<u8>, blake3::Hash) {
+        let size = data.len() as u64;
+        if size < self.threshold {
+            return bao_tree::io::outboard(data, IROH_BLOCK_SIZE);
+        }
+        let noop = |_: u64| io::Result::Ok(());
+        self.compute(&Source::Mem(data), size, &Progress::new(&noop))
+            .expect("hashing in-memory data does not fail")
+    }
+
+    /// Whether data of the given size is hashed on multiple threads.
+    pub fn is_parallel(&self, size: u64) -> bool {
+        size >= self.threshold
+    }
+
+    /// Compute the pre-order outboard and the hash of the first `size` bytes of a file.
+    ///
+    /// `progress` is called with the number of bytes hashed so far.
+    pub fn outboard_file(
+        &self,
+        file: &File,
+        size: u64,
+        progress: &(dyn Fn(u64) -> io::Result<()> + Sync),
+    ) -> io::Result<(Vec<u8>, blake3::Hash)> {
+        self.compute(&Source::File(file), size, &Progress::new(progress))
+    }
+
+    fn compute(
+        &self,
+        source: &Source,
+        size: u64,
+        progress: &Progress,
+    ) -> io::Result<(Vec<u8>, blake3::Hash)> {
+        let run = || subtree(source, 0, size, 0, true, progress);
+        let (hash, pairs) = match &self.pool {
+            Some(pool) => pool.install(run)?,
+            None => run()?,
+        };
+        let mut outboard = Vec::with_capacity(8 + pairs.len());
+        outboard.extend_from_slice(&size.to_le_bytes());
+        outboard.extend_from_slice(&pairs);
+        Ok((outboard, hash))
+    }
+}
+
+/// Where the data to hash comes from.
+enum Source<'a> {
+    Mem(&'a [u8]),
+    File(&'a File),
+}
+
+impl Source<'_> {
+    /// Call `f` with the data in the given range.
+    fn with_range<T>(&self, offset: u64, len: u64, f: impl FnOnce(&[u8]) -> T) -> io::Result<T> {
+        match self {
+            Source::Mem(data) => Ok(f(&data[offset as usize..(offset + len) as usize])),
+            Source::File(file) => {
+                let mut buf = vec![0u8; len as usize];
+                let mut filled = 0;
+                while filled < buf.len() {
+                    match file.read_at(offset + filled as u64, &mut buf[filled..]) {
+                        Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                        Ok(n) => filled += n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(f(&buf))
+            }
+        }
+    }
+}
+
+/// Reports the number of bytes hashed so far, from multiple threads.
+struct Progress<'a> {
+    done: AtomicU64,
+    cb: &'a (dyn Fn(u64) -> io::Result<()> + Sync),
+}
+
+impl<'a> Progress<'a> {
+    fn new(cb: &'a (dyn Fn(u64) -> io::Result<()> + Sync)) -> Self {
+        Self {
+            done: AtomicU64::new(0),
+            cb,
+        }
+    }
+
+    fn add(&self, len: u64) -> io::Result<()> {
+        let done = self.done.fetch_add(len, Ordering::Relaxed) + len;
+        (self.cb)(done)
+    }
+}
+
+/// Hash the subtree of `size` bytes at `offset`, splitting large subtrees across threads.
+///
+/// Returns the hash of the subtree and its hash pairs in pre-order.
+fn subtree(
+    source: &Source,
+    offset: u64,
+    size: u64,
+    start_chunk: u64,
+    is_root: bool,
+    progress: &Progress,
+) -> io::Result<(blake3::Hash, Vec<u8>)> {
+    let blocks = blocks(size);
+    if size <= SEQUENTIAL_SIZE || blocks < 2 {
+        let res = source.with_range(offset, size, |data| {
+            let mut pairs = Vec::new();
+            let hash = hash_blocks(data, start_chunk, is_root, &mut pairs);
+            (hash, pairs)
+        })?;
+        progress.add(size)?;
+        return Ok(res);
+    }
+    let left_size = left_blocks(blocks) * IROH_BLOCK_SIZE.bytes() as u64;
+    let right_start_chunk = start_chunk + left_size / blake3::guts::CHUNK_LEN as u64;
+    let (left, right) = rayon::join(
+        || subtree(source, offset, left_size, start_chunk, false, progress),
+        || {
+            let right_size = size - left_size;
+            let right_offset = offset + left_size;
+            subtree(
+                source,
+                right_offset,
+                right_size,
+                right_start_chunk,
+                false,
+                progress,
+            )
+        },
+    );
+    let (left_hash, left_pairs) = left?;
+    let (right_hash, right_pairs) = right?;
+    let mut pairs = Vec::with_capacity(64 + left_pairs.len() + right_pairs.len());
+    pairs.extend_from_slice(left_hash.as_bytes());
+    pairs.extend_from_slice(right_hash.as_bytes());
+    pairs.extend_from_slice(&left_pairs);
+    pairs.extend_from_slice(&right_pairs);
+    Ok((parent_cv(&left_hash, &right_hash, is_root), pairs))
+}
+
+/// Hash a subtree on the current thread, appending its hash pairs in pre-order.
+fn hash_blocks(data: &[u8], start_chunk: u64, is_root: bool, pairs: &mut Vec<u8>) -> blake3::Hash {
+    let blocks = blocks(data.len() as u64);
+    if blocks < 2 {
+        return hash_subtree(start_chunk, data, is_root);
+    }
+    let left_size = left_blocks(blocks) as usize * IROH_BLOCK_SIZE.bytes();
+    let (left, right) = data.split_at(left_size);
+    let right_start_chunk = start_chunk + (left_size / blake3::guts::CHUNK_LEN) as u64;
+    // the pair goes before the pairs of the children
+    let at = pairs.len();
+    pairs.extend_from_slice(&[0u8; 64]);
+    let left_hash = hash_blocks(left, start_chunk, false, pairs);
+    let right_hash = hash_blocks(right, right_start_chunk, false, pairs);
+    pairs[at..at + 32].copy_from_slice(left_hash.as_bytes());
+    pairs[at + 32..at + 64].copy_from_slice(right_hash.as_bytes());
+    parent_cv(&left_hash, &right_hash, is_root)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const SIZES: &[usize] = &[
+        0,
+        1,
+        16 * 1024,
+        16 * 1024 + 1,
+        3 * 16 * 1024,
+        (1 << 20) + 1,
+        (3 << 20) + 12345,
+    ];
+
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn same_as_sequential() -> io::Result<()> {
+        let hashers = [
+            OutboardHasher::default().threshold(0),
+            OutboardHasher::with_threads(3)?.threshold(0),
+        ];
+        for &size in SIZES {
+            let data = test_data(size);
+            let expected = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+            for hasher in &hashers {
+                assert_eq!(hasher.outboard(&data), expected, "size {size}");
+            }
+            let mut file = tempfile::tempfile()?;
+            file.write_all(&data)?;
+            let done = AtomicU64::new(0);
+            let progress = |offset: u64| -> io::Result<()> {
+                done.fetch_max(offset, Ordering::Relaxed);
+                Ok(())
+            };
+            let res = hashers[1].outboard_file(&file, size as u64, &progress)?;
+            assert_eq!(res, expected, "size {size}");
+            assert_eq!(done.load(Ordering::Relaxed), size as u64);
+        }
+        Ok(())
+    }
+}
@@ -24,12 +24,15 @@ use futures::{
 use iroh_bytes::{
     baomap::{
         self, range_collections::RangeSet2, ExportMode, ImportMode, ImportProgress, Map, MapEntry,
-        PartialMap, PartialMapEntry, ReadableStore, ValidateProgress,
+        PartialMap, PartialMapEntry, Pin, ReadableStore, StoreEvent, ValidateProgress,
     },
     util::progress::{IdGenerator, ProgressSender},
     Hash, IROH_BLOCK_SIZE,
 };
-use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{broadcast, mpsc},
+};
 
 /// A readonly in memory database for iroh-bytes.
 ///
@@ -162,6 +165,10 @@ impl MapEntry<Store> for Entry {
         self.data.len() as u64
     }
 
+    fn is_complete(&self) -> bool {
+        true
+    }
+
     fn available_ranges(&self) -> BoxFuture<'_, io::Result<RangeSet2<ChunkNum>>> {
         futures::future::ok(RangeSet2::all()).boxed()
     }
@@ -243,6 +250,15 @@ impl ReadableStore for Store {
     fn partial_blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
         Box::new(std::iter::empty())
     }
+
+    fn pins(&self) -> Box<dyn Iterator<Item = (String, Pin)> + Send + Sync + 'static> {
+        Box::new(std::iter::empty())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<StoreEvent> {
+        // the store never changes, so the sender can be dropped right away
+        broadcast::channel(1).1
+    }
 }
 
 impl MapEntry<Store> for PartialEntry {
@@ -261,6 +277,11 @@ impl MapEntry<Store> for PartialEntry {
         unreachable!()
     }
 
+    fn is_complete(&self) -> bool {
+        // this is unreachable, since PartialEntry can not be created
+        unreachable!()
+    }
+
     fn outboard(&self) -> BoxFuture<'_, io::Result<PreOrderMemOutboard<Bytes>>> {
         // this is unreachable, since PartialEntry can not be created
         unreachable!()
@@ -300,4 +321,39 @@ impl baomap::Store for Store {
         let _ = bytes;
         async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
     }
+
+    fn set_pin(&self, name: String, pin: Pin) -> BoxFuture<'_, io::Result<()>> {
+        let _ = (name, pin);
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn remove_pin(&self, name: String) -> BoxFuture<'_, io::Result<Option<Pin>>> {
+        let _ = name;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn quarantine(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let _ = hash;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn delete(&self, hash: Hash) -> BoxFuture<'_, io::Result<()>> {
+        let _ = hash;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn restore(&self, hash: Hash) -> BoxFuture<'_, io::Result<bool>> {
+        let _ = hash;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn export_partial(&self, hash: Hash, target: PathBuf) -> BoxFuture<'_, io::Result<()>> {
+        let _ = (hash, target);
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
+
+    fn import_partial(&self, path: PathBuf) -> BoxFuture<'_, io::Result<Hash>> {
+        let _ = path;
+        async move { Err(io::Error::new(io::ErrorKind::Other, "not implemented")) }.boxed()
+    }
 }
@@ -0,0 +1,121 @@
+//! Partial entries exported as sparse files.
+//!
+//! An interrupted download can be moved to another machine by exporting the partial
+//! entry: the verified ranges of the data are written to a sparse file of the full size
+//! of the blob, and a sidecar next to it records the hash, the verified ranges and the
+//! pre-order outboard. Importing the pair into another store verifies the data against
+//! the outboard, and the download can then be resumed from there.
+//!
+//! The sidecar of `<file>` is `<file>.iroh-partial`, see [`sidecar_path`].
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bao_tree::io::outboard::PreOrderOutboard;
+use bao_tree::{BaoTree, ByteNum, ChunkNum};
+use bytes::Bytes;
+use iroh_bytes::baomap::range_collections::{range_set::RangeSetRange, RangeSet2};
+use iroh_bytes::protocol::RangeSpec;
+use iroh_bytes::{Hash, IROH_BLOCK_SIZE};
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
+use serde::{Deserialize, Serialize};
+
+/// Extension appended to the name of a sparse file to get the name of its sidecar.
+pub const SIDECAR_EXTENSION: &str = "iroh-partial";
+
+/// Maximum number of bytes copied at once.
+const COPY_BUF_SIZE: u64 = 1024 * 1024;
+
+/// The path of the sidecar of the sparse file at `path`.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIDECAR_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// The sidecar of a sparse file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sidecar {
+    /// The hash of the blob.
+    pub hash: Hash,
+    /// The size of the blob.
+    pub size: u64,
+    /// The chunk ranges of the sparse file that have been verified.
+    pub ranges: RangeSpec,
+    /// The pre-order outboard of the blob, including the size prefix.
+    pub outboard: Vec<u8>,
+}
+
+impl Sidecar {
+    /// Reads the sidecar of the sparse file at `path`.
+    pub async fn read(path: &Path) -> io::Result<Self> {
+        let bytes = tokio::fs::read(sidecar_path(path)).await?;
+        let sidecar: Self = postcard::from_bytes(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let prefix = sidecar.size.to_le_bytes();
+        if sidecar.outboard.get(..prefix.len()) != Some(&prefix[..]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "outboard does not match the size of the blob",
+            ));
+        }
+        Ok(sidecar)
+    }
+
+    /// Writes the sidecar of the sparse file at `path`.
+    pub async fn write(&self, path: &Path) -> io::Result<()> {
+        let bytes =
+            postcard::to_stdvec(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        tokio::fs::write(sidecar_path(path), bytes).await
+    }
+
+    /// The verified chunk ranges.
+    pub fn chunk_ranges(&self) -> RangeSet2<ChunkNum> {
+        self.ranges.to_chunk_ranges()
+    }
+
+    /// Checks that the verified ranges of `data` match the hash.
+    pub async fn verify(&self, data: &mut impl AsyncSliceReader) -> io::Result<()> {
+        let outboard = PreOrderOutboard {
+            root: self.hash.into(),
+            tree: BaoTree::new(ByteNum(self.size), IROH_BLOCK_SIZE),
+            data: Bytes::from(self.outboard.clone()),
+        };
+        bao_tree::io::fsm::encode_ranges_validated(
+            data,
+            outboard,
+            &self.chunk_ranges(),
+            &mut tokio::io::sink(),
+        )
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Copies the `ranges` of a blob of `size` bytes from `source` to the same offsets in
+/// `target`, leaving everything else untouched.
+pub async fn copy_ranges(
+    source: &mut impl AsyncSliceReader,
+    target: &mut impl AsyncSliceWriter,
+    ranges: &RangeSet2<ChunkNum>,
+    size: u64,
+) -> io::Result<()> {
+    for range in ranges.iter() {
+        let (start, end) = match range {
+            RangeSetRange::Range(r) => (r.start.to_bytes().0, r.end.to_bytes().0),
+            RangeSetRange::RangeFrom(r) => (r.start.to_bytes().0, size),
+        };
+        let mut offset = start;
+        let end = end.min(size);
+        while offset < end {
+            let len = (end - offset).min(COPY_BUF_SIZE) as usize;
+            let buf = source.read_at(offset, len).await?;
+            if buf.len() != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            target.write_bytes_at(offset, buf).await?;
+            offset += len as u64;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,602 @@
+//! An io_uring based file reader for the flat store.
+//!
+//! Busy providers read many small chunks from many files concurrently. With the default
+//! backend every read is a blocking `pread` on the tokio blocking pool. With io_uring,
+//! reads are queued on a ring shared by all readers and submitted in batches by a single
+//! driver thread, which saves both syscalls and thread hand-offs. The writes and syncs of
+//! partial entries go through the same ring.
+//!
+//! The driver keeps the file of an operation open until the kernel completes it. If the
+//! ring fails with operations in flight, their buffers and files are leaked rather than
+//! released while the kernel might still use them.
+//!
+//! This is only available on Linux with the `io-uring` feature. Elsewhere, and if the
+//! kernel does not support io_uring, [`Ring::new`] fails and the store falls back to the
+//! default backend.
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
+use serde::{Deserialize, Serialize};
+
+/// Number of reads that can be in flight on a ring at the same time.
+pub const DEFAULT_QUEUE_DEPTH: u32 = 256;
+
+/// How a store reads complete data files and writes partial entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoBackend {
+    /// Blocking reads on the tokio blocking pool.
+    #[default]
+    Std,
+    /// Reads and writes submitted to a shared io_uring, see the [module docs](self).
+    Uring,
+}
+
+impl fmt::Display for IoBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoBackend::Std => write!(f, "std"),
+            IoBackend::Uring => write!(f, "uring"),
+        }
+    }
+}
+
+impl FromStr for IoBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(IoBackend::Std),
+            "uring" => Ok(IoBackend::Uring),
+            _ => anyhow::bail!("unknown io backend: {}", s),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod imp {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use io_uring::{opcode, squeue, types, IoUring};
+    use tokio::sync::oneshot;
+
+    /// An operation submitted to the driver thread.
+    struct Op {
+        /// The file, kept open until the kernel is done with the operation.
+        file: Arc<fs::File>,
+        offset: u64,
+        kind: Kind,
+    }
+
+    enum Kind {
+        Read {
+            /// Buffer with the capacity of the read, filled up to its length.
+            buf: Vec<u8>,
+            reply: oneshot::Sender<io::Result<Bytes>>,
+        },
+        Write {
+            data: Bytes,
+            /// Number of bytes of `data` that were written.
+            written: usize,
+            reply: oneshot::Sender<io::Result<()>>,
+        },
+        Fsync {
+            /// Whether the fsync was submitted, and its completion answers it.
+            submitted: bool,
+            reply: oneshot::Sender<io::Result<()>>,
+        },
+    }
+
+    impl Op {
+        fn fail(self, err: io::Error) {
+            match self.kind {
+                Kind::Read { reply, .. } => {
+                    reply.send(Err(err)).ok();
+                }
+                Kind::Write { reply, .. } | Kind::Fsync { reply, .. } => {
+                    reply.send(Err(err)).ok();
+                }
+            }
+        }
+
+        /// Fail an operation that the kernel might still be working on.
+        ///
+        /// Its buffer and file descriptor are leaked, since the kernel might still use them.
+        fn abandon(self, err: io::Error) {
+            let Op { file, kind, .. } = self;
+            match kind {
+                Kind::Read { buf, reply } => {
+                    reply.send(Err(err)).ok();
+                    std::mem::forget(buf);
+                }
+                Kind::Write { data, reply, .. } => {
+                    reply.send(Err(err)).ok();
+                    std::mem::forget(data);
+                }
+                Kind::Fsync { reply, .. } => {
+                    reply.send(Err(err)).ok();
+                }
+            }
+            std::mem::forget(file);
+        }
+    }
+
+    /// A handle to an io_uring and the thread driving it.
+    ///
+    /// Cloning a ring returns a handle to the same ring. The driver thread stops once
+    /// all handles and all files opened on the ring are dropped.
+    #[derive(Debug, Clone)]
+    pub struct Ring(flume::Sender<Op>);
+
+    impl Ring {
+        /// Create a new ring with the given queue depth and start its driver thread.
+        pub fn new(depth: u32) -> io::Result<Self> {
+            let ring = IoUring::new(depth)?;
+            let (tx, rx) = flume::unbounded();
+            std::thread::Builder::new()
+                .name("iroh-uring".into())
+                .spawn(move || drive(ring, rx, depth as usize))?;
+            Ok(Self(tx))
+        }
+
+        fn send(&self, op: Op) {
+            if let Err(flume::SendError(op)) = self.0.send(op) {
+                op.fail(driver_stopped());
+            }
+        }
+
+        pub(super) fn read(
+            &self,
+            file: Arc<fs::File>,
+            offset: u64,
+            len: usize,
+        ) -> oneshot::Receiver<io::Result<Bytes>> {
+            let (reply, rx) = oneshot::channel();
+            let buf = Vec::with_capacity(len);
+            self.send(Op {
+                file,
+                offset,
+                kind: Kind::Read { buf, reply },
+            });
+            rx
+        }
+
+        pub(super) fn write(
+            &self,
+            file: Arc<fs::File>,
+            offset: u64,
+            data: Bytes,
+        ) -> oneshot::Receiver<io::Result<()>> {
+            let (reply, rx) = oneshot::channel();
+            self.send(Op {
+                file,
+                offset,
+                kind: Kind::Write {
+                    data,
+                    written: 0,
+                    reply,
+                },
+            });
+            rx
+        }
+
+        pub(super) fn fsync(&self, file: Arc<fs::File>) -> oneshot::Receiver<io::Result<()>> {
+            let (reply, rx) = oneshot::channel();
+            self.send(Op {
+                file,
+                offset: 0,
+                kind: Kind::Fsync {
+                    submitted: false,
+                    reply,
+                },
+            });
+            rx
+        }
+    }
+
+    pub(super) fn driver_stopped() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "io_uring driver stopped")
+    }
+
+    fn drive(mut ring: IoUring, rx: flume::Receiver<Op>, depth: usize) {
+        let mut in_flight = HashMap::<u64, Op>::new();
+        let mut next_id = 0u64;
+        loop {
+            if in_flight.is_empty() {
+                // idle, wait for work
+                match rx.recv() {
+                    Ok(op) => submit(&mut ring, &mut in_flight, &mut next_id, op),
+                    Err(_) => return,
+                }
+            }
+            // pick up more work without blocking
+            while in_flight.len() < depth {
+                match rx.try_recv() {
+                    Ok(op) => submit(&mut ring, &mut in_flight, &mut next_id, op),
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = ring.submit_and_wait(1) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                tracing::error!("io_uring submit failed, stopping the driver: {}", e);
+                for (_, op) in in_flight.drain() {
+                    op.abandon(io::Error::new(e.kind(), e.to_string()));
+                }
+                return;
+            }
+            let completed = ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect::<Vec<_>>();
+            for (id, res) in completed {
+                let Some(op) = in_flight.remove(&id) else {
+                    continue;
+                };
+                complete(&mut ring, &mut in_flight, &mut next_id, op, res);
+            }
+        }
+    }
+
+    /// Handle the completion of an operation, resubmitting it if it is not done.
+    fn complete(
+        ring: &mut IoUring,
+        in_flight: &mut HashMap<u64, Op>,
+        next_id: &mut u64,
+        mut op: Op,
+        res: i32,
+    ) {
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            if err.kind() == io::ErrorKind::Interrupted {
+                if let Kind::Fsync { submitted, .. } = &mut op.kind {
+                    *submitted = false;
+                }
+                submit(ring, in_flight, next_id, op);
+            } else {
+                op.fail(err);
+            }
+            return;
+        }
+        let n = res as usize;
+        let eof = match &mut op.kind {
+            Kind::Read { buf, .. } => {
+                // SAFETY: the kernel initialized the n bytes after the filled part
+                unsafe { buf.set_len(buf.len() + n) };
+                n == 0
+            }
+            Kind::Write { written, .. } => {
+                if n == 0 {
+                    op.fail(io::ErrorKind::WriteZero.into());
+                    return;
+                }
+                *written += n;
+                false
+            }
+            Kind::Fsync { .. } => false,
+        };
+        if eof {
+            // reply with what was read
+            done(op);
+        } else {
+            // complete operations are answered by submit, short reads and writes continue
+            submit(ring, in_flight, next_id, op);
+        }
+    }
+
+    fn submit(ring: &mut IoUring, in_flight: &mut HashMap<u64, Op>, next_id: &mut u64, mut op: Op) {
+        let fd = types::Fd(op.file.as_raw_fd());
+        let offset = op.offset;
+        let entry = match &mut op.kind {
+            Kind::Read { buf, .. } if buf.len() == buf.capacity() => None,
+            Kind::Read { buf, .. } => {
+                let filled = buf.len();
+                let remaining = (buf.capacity() - filled).min(u32::MAX as usize) as u32;
+                // SAFETY: filled is within the capacity of the buffer
+                let ptr = unsafe { buf.as_mut_ptr().add(filled) };
+                let entry = opcode::Read::new(fd, ptr, remaining)
+                    .offset(offset + filled as u64)
+                    .build();
+                Some(entry)
+            }
+            Kind::Write { data, written, .. } if *written == data.len() => None,
+            Kind::Write { data, written, .. } => {
+                let remaining = (data.len() - *written).min(u32::MAX as usize) as u32;
+                let entry = opcode::Write::new(fd, data[*written..].as_ptr(), remaining)
+                    .offset(offset + *written as u64)
+                    .build();
+                Some(entry)
+            }
+            Kind::Fsync { submitted, .. } if *submitted => None,
+            Kind::Fsync { submitted, .. } => {
+                *submitted = true;
+                Some(opcode::Fsync::new(fd).build())
+            }
+        };
+        let Some(entry) = entry else {
+            done(op);
+            return;
+        };
+        let id = *next_id;
+        *next_id += 1;
+        push(ring, in_flight, id, op, entry.user_data(id));
+    }
+
+    /// Answer an operation that is complete.
+    fn done(op: Op) {
+        match op.kind {
+            Kind::Read { buf, reply } => {
+                reply.send(Ok(buf.into())).ok();
+            }
+            Kind::Write { reply, .. } | Kind::Fsync { reply, .. } => {
+                reply.send(Ok(())).ok();
+            }
+        }
+    }
+
+    fn push(
+        ring: &mut IoUring,
+        in_flight: &mut HashMap<u64, Op>,
+        id: u64,
+        op: Op,
+        entry: squeue::Entry,
+    ) {
+        // SAFETY: the buffer and the file are kept alive in in_flight until the operation
+        // completes, or leaked if the ring fails before
+        let pushed = unsafe { ring.submission().push(&entry) };
+        if pushed.is_err() {
+            // the submission queue is full, flush it and try again
+            if let Err(e) = ring.submit() {
+                op.fail(e);
+                return;
+            }
+            // SAFETY: as above
+            if unsafe { ring.submission().push(&entry) }.is_err() {
+                op.fail(io::Error::new(
+                    io::ErrorKind::Other,
+                    "io_uring submission queue full",
+                ));
+                return;
+            }
+        }
+        in_flight.insert(id, op);
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+mod imp {
+    use std::fs;
+    use std::io;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tokio::sync::oneshot;
+
+    /// A handle to an io_uring, not supported on this platform.
+    #[derive(Debug, Clone)]
+    pub enum Ring {}
+
+    impl Ring {
+        /// Always fails, io_uring is not supported on this platform.
+        pub fn new(_depth: u32) -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring requires Linux and the io-uring feature",
+            ))
+        }
+
+        pub(super) fn read(
+            &self,
+            _file: Arc<fs::File>,
+            _offset: u64,
+            _len: usize,
+        ) -> oneshot::Receiver<io::Result<Bytes>> {
+            match *self {}
+        }
+
+        pub(super) fn write(
+            &self,
+            _file: Arc<fs::File>,
+            _offset: u64,
+            _data: Bytes,
+        ) -> oneshot::Receiver<io::Result<()>> {
+            match *self {}
+        }
+
+        pub(super) fn fsync(&self, _file: Arc<fs::File>) -> oneshot::Receiver<io::Result<()>> {
+            match *self {}
+        }
+    }
+
+    pub(super) fn driver_stopped() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "io_uring driver stopped")
+    }
+}
+
+pub use imp::Ring;
+
+/// A file read and written through a [`Ring`].
+#[derive(Debug)]
+pub struct File {
+    file: std::sync::Arc<std::fs::File>,
+    /// The size of a file that does not change, `None` for files that are written.
+    size: Option<u64>,
+    ring: Ring,
+}
+
+impl File {
+    /// Open a file for reading on the given ring.
+    ///
+    /// The file must not change in size while it is open, which holds for the complete
+    /// data files of the flat store.
+    pub async fn open(ring: Ring, path: PathBuf) -> io::Result<Self> {
+        let (file, size) = blocking(move || {
+            let file = std::fs::File::open(path)?;
+            let size = file.metadata()?.len();
+            Ok((file, size))
+        })
+        .await?;
+        Ok(Self {
+            file: std::sync::Arc::new(file),
+            size: Some(size),
+            ring,
+        })
+    }
+
+    /// Open or create a file for reading and writing on the given ring.
+    pub async fn create(ring: Ring, path: PathBuf) -> io::Result<Self> {
+        let file = blocking(move || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)
+        })
+        .await?;
+        Ok(Self {
+            file: std::sync::Arc::new(file),
+            size: None,
+            ring,
+        })
+    }
+
+    /// Read an already open file on the given ring.
+    ///
+    /// `size` must be the size of the file, which must not change while it is read.
+    pub(crate) fn from_std(ring: Ring, file: std::sync::Arc<std::fs::File>, size: u64) -> Self {
+        Self {
+            file,
+            size: Some(size),
+            ring,
+        }
+    }
+
+    async fn current_len(&self) -> io::Result<u64> {
+        match self.size {
+            Some(size) => Ok(size),
+            None => {
+                let file = self.file.clone();
+                blocking(move || Ok(file.metadata()?.len())).await
+            }
+        }
+    }
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
+impl AsyncSliceReader for File {
+    type ReadAtFuture<'a> = BoxFuture<'a, io::Result<Bytes>>;
+
+    fn read_at(&mut self, offset: u64, len: usize) -> Self::ReadAtFuture<'_> {
+        Box::pin(async move {
+            // reads past the end are clipped, read_to_end asks for usize::MAX bytes
+            let available = self.current_len().await?.saturating_sub(offset);
+            let len = usize::try_from(available).map_or(len, |available| available.min(len));
+            let rx = self.ring.read(self.file.clone(), offset, len);
+            rx.await.map_err(|_| imp::driver_stopped())?
+        })
+    }
+
+    type LenFuture<'a> = BoxFuture<'a, io::Result<u64>>;
+
+    fn len(&mut self) -> Self::LenFuture<'_> {
+        Box::pin(self.current_len())
+    }
+}
+
+impl AsyncSliceWriter for File {
+    type WriteAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Self::WriteAtFuture<'_> {
+        self.write_bytes_at(offset, Bytes::copy_from_slice(data))
+    }
+
+    type WriteBytesAtFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn write_bytes_at(&mut self, offset: u64, data: Bytes) -> Self::WriteBytesAtFuture<'_> {
+        let rx = self.ring.write(self.file.clone(), offset, data);
+        Box::pin(async move { rx.await.map_err(|_| imp::driver_stopped())? })
+    }
+
+    type SetLenFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn set_len(&mut self, len: u64) -> Self::SetLenFuture<'_> {
+        // there is no truncate operation in older kernels
+        let file = self.file.clone();
+        Box::pin(blocking(move || file.set_len(len)))
+    }
+
+    type SyncFuture<'a> = BoxFuture<'a, io::Result<()>>;
+    fn sync(&mut self) -> Self::SyncFuture<'_> {
+        let rx = self.ring.fsync(self.file.clone());
+        Box::pin(async move { rx.await.map_err(|_| imp::driver_stopped())? })
+    }
+}
+
+#[cfg(all(test, target_os = "linux", feature = "io-uring"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_at() -> io::Result<()> {
+        let ring = match Ring::new(4) {
+            Ok(ring) => ring,
+            // the kernel might not support io_uring, e.g. in a container
+            Err(_) => return Ok(()),
+        };
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("data");
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data)?;
+        let mut file = File::open(ring, path).await?;
+        assert_eq!(file.len().await?, data.len() as u64);
+        let reads = [(0, 10), (1000, 16384), (99_990, 100), (200_000, 10)];
+        for (offset, len) in reads {
+            let res = file.read_at(offset, len).await?;
+            let start = (offset as usize).min(data.len());
+            let end = (start + len).min(data.len());
+            assert_eq!(&res[..], &data[start..end]);
+        }
+        let res = file.read_at(0, usize::MAX).await?;
+        assert_eq!(&res[..], &data[..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_at() -> io::Result<()> {
+        let ring = match Ring::new(4) {
+            Ok(ring) => ring,
+            Err(_) => return Ok(()),
+        };
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("data");
+        let mut file = File::create(ring.clone(), path.clone()).await?;
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        // out of order, so the file grows while it is written
+        file.write_at(50_000, &data[50_000..]).await?;
+        file.write_bytes_at(0, Bytes::copy_from_slice(&data[..50_000]))
+            .await?;
+        file.sync().await?;
+        assert_eq!(file.len().await?, data.len() as u64);
+        assert_eq!(&file.read_at(0, usize::MAX).await?[..], &data[..]);
+        assert_eq!(std::fs::read(&path)?, data);
+
+        file.set_len(10).await?;
+        assert_eq!(&file.read_at(0, usize::MAX).await?[..], &data[..10]);
+        Ok(())
+    }
+}
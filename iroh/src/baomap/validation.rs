@@ -0,0 +1,246 @@
+//! Scheduled background validation of stored blobs.
+//!
+//! Data at rest can silently rot. The background validator periodically walks over all
+//! complete blobs of a store and checks their data against the outboard. To not compete
+//! with serving requests, it only spends a configurable fraction of the time validating
+//! and sleeps in between blobs.
+//!
+//! Corrupted blobs are reported as [`ValidationEvent`]s, and can optionally be
+//! quarantined using [`Store::quarantine`], so they are no longer served to peers.
+use std::future::Future;
+use std::io;
+use std::time::{Duration, Instant};
+
+use bao_tree::io::EncodeError;
+use iroh_bytes::baomap::{
+    range_collections::RangeSet2, Map, MapEntry, ReadableStore, Store, ValidateProgress,
+};
+use iroh_bytes::Hash;
+use tokio::sync::mpsc;
+
+/// When and how fast to validate stored blobs in the background.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValidationSchedule {
+    /// Time between the start of two validation passes.
+    ///
+    /// The first pass starts one interval after the validator is started. If a pass
+    /// takes longer than the interval, the next pass starts right after it.
+    pub interval: Duration,
+    /// Fraction of the time spent validating during a pass, between 0 and 1.
+    ///
+    /// With a duty cycle of 0.1, validating a blob that takes one second is followed
+    /// by nine seconds of sleep.
+    pub duty_cycle: f64,
+    /// Whether to quarantine corrupted blobs.
+    pub quarantine: bool,
+}
+
+impl Default for ValidationSchedule {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60 * 60 * 24),
+            duty_cycle: 0.1,
+            quarantine: false,
+        }
+    }
+}
+
+/// Events emitted by the background validator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationEvent {
+    /// The data of a blob does not match its hash.
+    Corrupted {
+        /// The hash of the blob
+        hash: Hash,
+        /// Whether the blob was quarantined
+        quarantined: bool,
+    },
+    /// A blob could not be read, so it was not validated.
+    Failed {
+        /// The hash of the blob
+        hash: Hash,
+        /// The error reading the blob
+        error: String,
+    },
+    /// A validation pass over all blobs is done.
+    PassDone {
+        /// Number of blobs validated
+        validated: u64,
+        /// Number of corrupted blobs found
+        corrupted: u64,
+        /// Duration of the pass, including the time spent sleeping
+        elapsed: Duration,
+    },
+}
+
+/// Check the data of a complete entry against its outboard.
+///
+/// Returns `Ok(false)` if the data does not match the hash, and an error if the entry
+/// could not be read at all.
+pub async fn validate_entry<D: Map>(entry: &D::Entry) -> io::Result<bool> {
+    let outboard = entry.outboard().await?;
+    let mut data = entry.data_reader().await?;
+    let res = bao_tree::io::fsm::encode_ranges_validated(
+        &mut data,
+        outboard,
+        &RangeSet2::all(),
+        tokio::io::sink(),
+    )
+    .await;
+    match res {
+        Ok(()) => Ok(true),
+        // truncated data is corrupted data
+        Err(EncodeError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(EncodeError::Io(e)) => Err(e),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Validate all complete blobs of `db` once, reporting the progress to `tx`.
+///
+/// This is [`ReadableStore::validate`] for the stores of this crate. It runs on the task
+/// of the caller, so it stops at the next read once its future is dropped, and once the
+/// receiver of `tx` is dropped.
+pub async fn validate_store<D: ReadableStore>(
+    db: &D,
+    tx: mpsc::Sender<ValidateProgress>,
+) -> anyhow::Result<()> {
+    let hashes = db.blobs().collect::<Vec<_>>();
+    let total = hashes.len() as u64;
+    tx.send(ValidateProgress::Starting { total }).await?;
+    for (id, hash) in (0..).zip(hashes) {
+        // the blob might have been removed since listing
+        let Some(entry) = db.get(&hash) else {
+            continue;
+        };
+        if !entry.is_complete() {
+            continue;
+        }
+        let size = entry.size();
+        tx.send(ValidateProgress::Entry {
+            id,
+            hash,
+            path: None,
+            size,
+        })
+        .await?;
+        let error = match validate_entry::<D>(&entry).await {
+            Ok(true) => None,
+            Ok(false) => Some("data does not match the hash".to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        tx.send(ValidateProgress::Done { id, error }).await?;
+    }
+    tx.send(ValidateProgress::AllDone).await?;
+    Ok(())
+}
+
+/// Run validation passes over `db` according to `schedule`, forever.
+///
+/// Every event is passed to `on_event`, and awaited before continuing.
+pub async fn run<D, F, Fut>(db: D, schedule: ValidationSchedule, on_event: F)
+where
+    D: Store,
+    F: Fn(ValidationEvent) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    tokio::time::sleep(schedule.interval).await;
+    loop {
+        let start = Instant::now();
+        let (validated, corrupted) = pass(&db, &schedule, &on_event).await;
+        let elapsed = start.elapsed();
+        tracing::info!(
+            "validated {} blobs in {:?}, {} corrupted",
+            validated,
+            elapsed,
+            corrupted
+        );
+        on_event(ValidationEvent::PassDone {
+            validated,
+            corrupted,
+            elapsed,
+        })
+        .await;
+        tokio::time::sleep(schedule.interval.saturating_sub(elapsed)).await;
+    }
+}
+
+async fn pass<D, F, Fut>(db: &D, schedule: &ValidationSchedule, on_event: &F) -> (u64, u64)
+where
+    D: Store,
+    F: Fn(ValidationEvent) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let duty_cycle = schedule.duty_cycle.clamp(0.01, 1.0);
+    let mut validated = 0;
+    let mut corrupted = 0;
+    for hash in db.blobs() {
+        // the blob might have been removed since listing
+        let Some(entry) = db.get(&hash) else {
+            continue;
+        };
+        if !entry.is_complete() {
+            continue;
+        }
+        let t0 = Instant::now();
+        match validate_entry::<D>(&entry).await {
+            Ok(true) => {
+                validated += 1;
+                #[cfg(feature = "metrics")]
+                iroh_metrics::inc!(crate::metrics::Metrics, blobs_validated);
+            }
+            Ok(false) => {
+                validated += 1;
+                corrupted += 1;
+                tracing::error!("blob {} is corrupted", hash);
+                #[cfg(feature = "metrics")]
+                iroh_metrics::inc!(crate::metrics::Metrics, blobs_corrupted);
+                let quarantined = if schedule.quarantine {
+                    match db.quarantine(hash).await {
+                        Ok(()) => true,
+                        Err(e) => {
+                            tracing::error!("failed to quarantine {}: {}", hash, e);
+                            false
+                        }
+                    }
+                } else {
+                    false
+                };
+                on_event(ValidationEvent::Corrupted { hash, quarantined }).await;
+            }
+            Err(e) => {
+                tracing::warn!("failed to read blob {} for validation: {}", hash, e);
+                on_event(ValidationEvent::Failed {
+                    hash,
+                    error: e.to_string(),
+                })
+                .await;
+            }
+        }
+        let busy = t0.elapsed();
+        tokio::time::sleep(busy.mul_f64((1.0 - duty_cycle) / duty_cycle)).await;
+    }
+    (validated, corrupted)
+}
+
+#[cfg(all(test, feature = "mem-db"))]
+mod tests {
+    use super::*;
+    use crate::baomap::mem;
+    use iroh_bytes::util::runtime;
+
+    #[tokio::test]
+    async fn pass_over_valid_blobs() {
+        let rt = runtime::Handle::from_currrent(1).unwrap();
+        let db = mem::Store::new(rt);
+        db.import_bytes(vec![1u8; 100_000].into()).await.unwrap();
+        db.import_bytes(vec![2u8; 10].into()).await.unwrap();
+        let schedule = ValidationSchedule {
+            duty_cycle: 1.0,
+            ..Default::default()
+        };
+        let (validated, corrupted) = pass(&db, &schedule, &|_| async {}).await;
+        assert_eq!(validated, 2);
+        assert_eq!(corrupted, 0);
+    }
+}
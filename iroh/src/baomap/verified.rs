@@ -0,0 +1,419 @@
+//! Tracking of the verified ranges of partial entries
+//!
+//! A chunk of a partial entry can be served once its data verifies against the hashes in the
+//! outboard. Finding those chunks means hashing the data, which is too expensive to do for
+//! every request. [`VerifiedRanges`] does it once per entry and afterwards only verifies the
+//! data that was written since, which it learns about from the [`TrackedWriter`]s the stores
+//! hand out for partial entries. Reading never hashes data that was already verified.
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use bao_tree::{
+    io::{fsm::Outboard, EncodeError},
+    ByteNum, ChunkNum,
+};
+use bytes::Bytes;
+use iroh_bytes::{
+    baomap::range_collections::{range_set::RangeSetRange, RangeSet2},
+    IROH_BLOCK_SIZE,
+};
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
+
+/// The verified ranges of a partial entry, shared by all handles to the entry.
+#[derive(Debug, Clone, Default)]
+pub struct VerifiedRanges(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    state: Mutex<State>,
+    // serializes verification, so concurrent requests hash new data only once
+    verify: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug)]
+struct State {
+    // the verified ranges, none until the entry was verified for the first time
+    verified: Option<RangeSet2<ChunkNum>>,
+    // blocks of the data that were written since the last verification
+    written: RangeSet2<ChunkNum>,
+    // blocks that were written but did not verify, retried once the outboard changes
+    unverified: RangeSet2<ChunkNum>,
+    // whether the outboard was written since the last verification
+    outboard_written: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            verified: None,
+            written: RangeSet2::empty(),
+            unverified: RangeSet2::empty(),
+            outboard_written: false,
+        }
+    }
+}
+
+/// A completed change to the data or outboard of a partial entry.
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    Data { offset: u64, len: u64 },
+    DataLen(u64),
+    Outboard,
+    OutboardLen,
+}
+
+impl VerifiedRanges {
+    /// Get the ranges of the entry that can be served.
+    ///
+    /// The first call verifies all data of the entry, later calls only verify what was
+    /// written in between. Data is written to a partial entry after the outboard part that
+    /// covers it, so new data usually verifies right away. Data that does not is retried
+    /// after the next write to the outboard.
+    pub async fn get(
+        &self,
+        mut outboard: impl Outboard,
+        mut data: impl AsyncSliceReader,
+        size: u64,
+    ) -> io::Result<RangeSet2<ChunkNum>> {
+        let _guard = self.0.verify.lock().await;
+        let todo = {
+            let mut guard = self.0.state.lock().unwrap();
+            let state = &mut *guard;
+            let mut todo = std::mem::replace(&mut state.written, RangeSet2::empty());
+            if std::mem::take(&mut state.outboard_written) {
+                let unverified = std::mem::replace(&mut state.unverified, RangeSet2::empty());
+                todo.union_with(&unverified);
+            }
+            match &state.verified {
+                Some(verified) if todo.is_empty() => return Ok(verified.clone()),
+                Some(_) => Some(todo),
+                None => {
+                    // everything is verified below
+                    state.unverified = RangeSet2::empty();
+                    None
+                }
+            }
+        };
+        let data_size = data.len().await?;
+        let valid_from_data = if data_size >= size {
+            RangeSet2::from(..ByteNum(size).chunks())
+        } else {
+            RangeSet2::from(..ByteNum(data_size).full_chunks())
+        };
+        let candidates = match todo {
+            Some(mut todo) => {
+                todo.intersection_with(&valid_from_data);
+                todo
+            }
+            None => {
+                let valid_from_outboard = bao_tree::io::fsm::valid_ranges(&mut outboard).await?;
+                valid_from_data.intersection(&valid_from_outboard)
+            }
+        };
+        let ok = verify(&mut outboard, &mut data, &candidates).await?;
+        let mut guard = self.0.state.lock().unwrap();
+        let state = &mut *guard;
+        let mut failed = match state.verified {
+            Some(_) => candidates,
+            // data without an outboard yet is retried as well
+            None => valid_from_data,
+        };
+        failed.difference_with(&ok);
+        state.unverified.union_with(&failed);
+        let verified = state.verified.get_or_insert_with(RangeSet2::empty);
+        verified.union_with(&ok);
+        Ok(verified.clone())
+    }
+
+    fn record(&self, change: Change) {
+        let mut guard = self.0.state.lock().unwrap();
+        let state = &mut *guard;
+        match change {
+            Change::Data { offset, len } => {
+                let block_chunks = 1u64 << IROH_BLOCK_SIZE.0;
+                let start = ByteNum(offset).full_chunks().0 / block_chunks * block_chunks;
+                let end = ByteNum(offset + len).chunks().0;
+                let end = (end + block_chunks - 1) / block_chunks * block_chunks;
+                let range = RangeSet2::from(ChunkNum(start)..ChunkNum(end));
+                // the new data replaces verified data, so it has to be verified again
+                if let Some(verified) = &mut state.verified {
+                    verified.difference_with(&range);
+                }
+                state.unverified.difference_with(&range);
+                state.written.union_with(&range);
+            }
+            Change::DataLen(len) => {
+                let truncated = RangeSet2::from(ByteNum(len).full_chunks()..);
+                if let Some(verified) = &mut state.verified {
+                    verified.difference_with(&truncated);
+                }
+                state.unverified.difference_with(&truncated);
+            }
+            Change::Outboard => state.outboard_written = true,
+            Change::OutboardLen => *state = State::default(),
+        }
+    }
+}
+
+/// Verify the data in the given ranges against the outboard.
+///
+/// A range that does not verify is split until the blocks that do are found.
+async fn verify(
+    mut outboard: impl Outboard,
+    mut data: impl AsyncSliceReader,
+    candidates: &RangeSet2<ChunkNum>,
+) -> io::Result<RangeSet2<ChunkNum>> {
+    let block_chunks = 1u64 << IROH_BLOCK_SIZE.0;
+    let mut todo = candidates
+        .iter()
+        .filter_map(|range| match range {
+            RangeSetRange::Range(range) => Some((range.start.0, range.end.0)),
+            // the candidates are bounded by the data
+            RangeSetRange::RangeFrom(_) => None,
+        })
+        .collect::<Vec<_>>();
+    todo.reverse();
+    let mut verified = RangeSet2::empty();
+    while let Some((start, end)) = todo.pop() {
+        let range = RangeSet2::from(ChunkNum(start)..ChunkNum(end));
+        let res = bao_tree::io::fsm::encode_ranges_validated(
+            &mut data,
+            &mut outboard,
+            &range,
+            tokio::io::sink(),
+        )
+        .await;
+        match res {
+            Ok(()) => {
+                verified.union_with(&range);
+                continue;
+            }
+            Err(EncodeError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e),
+            Err(_) => {}
+        }
+        // split at a block boundary, a range within a single block can not be split
+        let mid = (start + (end - start) / 2) / block_chunks * block_chunks;
+        let mid = if mid <= start {
+            mid + block_chunks
+        } else {
+            mid
+        };
+        if mid < end {
+            todo.push((mid, end));
+            todo.push((start, mid));
+        }
+    }
+    Ok(verified)
+}
+
+/// A writer for the data or outboard of a partial entry that records the writes in the
+/// [`VerifiedRanges`] of the entry.
+#[derive(Debug)]
+pub struct TrackedWriter<W> {
+    inner: W,
+    ranges: VerifiedRanges,
+    outboard: bool,
+}
+
+impl<W> TrackedWriter<W> {
+    /// Track writes to the data of an entry.
+    pub fn data(inner: W, ranges: VerifiedRanges) -> Self {
+        Self {
+            inner,
+            ranges,
+            outboard: false,
+        }
+    }
+
+    /// Track writes to the outboard of an entry.
+    pub fn outboard(inner: W, ranges: VerifiedRanges) -> Self {
+        Self {
+            inner,
+            ranges,
+            outboard: true,
+        }
+    }
+
+    fn written(&self, offset: u64, len: u64) -> Change {
+        if self.outboard {
+            Change::Outboard
+        } else {
+            Change::Data { offset, len }
+        }
+    }
+
+    fn len_set(&self, len: u64) -> Change {
+        if self.outboard {
+            Change::OutboardLen
+        } else {
+            Change::DataLen(len)
+        }
+    }
+}
+
+impl<W: AsyncSliceWriter> AsyncSliceWriter for TrackedWriter<W> {
+    type WriteAtFuture<'a>
+        = TrackedFuture<'a, W::WriteAtFuture<'a>>
+    where
+        W: 'a;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Self::WriteAtFuture<'_> {
+        let change = self.written(offset, data.len() as u64);
+        TrackedFuture::new(self.inner.write_at(offset, data), &self.ranges, change)
+    }
+
+    type WriteBytesAtFuture<'a>
+        = TrackedFuture<'a, W::WriteBytesAtFuture<'a>>
+    where
+        W: 'a;
+    fn write_bytes_at(&mut self, offset: u64, data: Bytes) -> Self::WriteBytesAtFuture<'_> {
+        let change = self.written(offset, data.len() as u64);
+        TrackedFuture::new(
+            self.inner.write_bytes_at(offset, data),
+            &self.ranges,
+            change,
+        )
+    }
+
+    type SetLenFuture<'a>
+        = TrackedFuture<'a, W::SetLenFuture<'a>>
+    where
+        W: 'a;
+    fn set_len(&mut self, len: u64) -> Self::SetLenFuture<'_> {
+        let change = self.len_set(len);
+        TrackedFuture::new(self.inner.set_len(len), &self.ranges, change)
+    }
+
+    type SyncFuture<'a>
+        = W::SyncFuture<'a>
+    where
+        W: 'a;
+    fn sync(&mut self) -> Self::SyncFuture<'_> {
+        self.inner.sync()
+    }
+}
+
+/// The future of a write to a [`TrackedWriter`].
+///
+/// The write is recorded once it completed, so a concurrent verification does not see
+/// data that is not there yet.
+#[derive(Debug)]
+pub struct TrackedFuture<'a, F> {
+    inner: Pin<Box<F>>,
+    ranges: &'a VerifiedRanges,
+    change: Option<Change>,
+}
+
+impl<'a, F> TrackedFuture<'a, F> {
+    fn new(inner: F, ranges: &'a VerifiedRanges, change: Change) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            ranges,
+            change: Some(change),
+        }
+    }
+}
+
+impl<'a, F: Future<Output = io::Result<()>>> Future for TrackedFuture<'a, F> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let res = futures::ready!(self.inner.as_mut().poll(cx));
+        if res.is_ok() {
+            if let Some(change) = self.change.take() {
+                self.ranges.record(change);
+            }
+        }
+        Poll::Ready(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bao_tree::io::outboard::PreOrderOutboard;
+    use bao_tree::{BaoTree, ByteNum, ChunkNum};
+    use bytes::{Bytes, BytesMut};
+    use iroh_bytes::baomap::range_collections::RangeSet2;
+    use iroh_bytes::IROH_BLOCK_SIZE;
+    use iroh_io::AsyncSliceWriter;
+
+    use super::{TrackedWriter, VerifiedRanges};
+
+    #[tokio::test]
+    async fn available_ranges_are_verified() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let size = data.len() as u64;
+        let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        let outboard = PreOrderOutboard {
+            root: hash,
+            tree: BaoTree::new(ByteNum(size), IROH_BLOCK_SIZE),
+            data: Bytes::from(outboard),
+        };
+        let ranges = |data: &[u8]| {
+            VerifiedRanges::default().get(outboard.clone(), Bytes::copy_from_slice(data), size)
+        };
+
+        assert_eq!(
+            ranges(&data).await.unwrap(),
+            RangeSet2::from(..ChunkNum(98))
+        );
+
+        // a hole in the data, as in a sparse file, is not available
+        let mut sparse = data.clone();
+        sparse[32 * 1024..48 * 1024].fill(0);
+        let expected =
+            RangeSet2::from(..ChunkNum(32)).union(&RangeSet2::from(ChunkNum(48)..ChunkNum(98)));
+        assert_eq!(ranges(&sparse).await.unwrap(), expected);
+
+        // neither is the part of a block that was only partially written
+        assert_eq!(
+            ranges(&data[..60_000]).await.unwrap(),
+            RangeSet2::from(..ChunkNum(48))
+        );
+    }
+
+    #[tokio::test]
+    async fn only_written_data_is_verified_again() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let size = data.len() as u64;
+        let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        let outboard = PreOrderOutboard {
+            root: hash,
+            tree: BaoTree::new(ByteNum(size), IROH_BLOCK_SIZE),
+            data: Bytes::from(outboard),
+        };
+        let verified = VerifiedRanges::default();
+        let mut file = BytesMut::from(&data[..]);
+        file[32 * 1024..48 * 1024].fill(0);
+        let expected =
+            RangeSet2::from(..ChunkNum(32)).union(&RangeSet2::from(ChunkNum(48)..ChunkNum(98)));
+        let cache = &verified;
+        let get =
+            move |file: &BytesMut| cache.get(outboard.clone(), Bytes::copy_from_slice(file), size);
+        assert_eq!(get(&file).await.unwrap(), expected);
+
+        // filling the hole makes it available
+        let mut writer = TrackedWriter::data(&mut file, verified.clone());
+        writer
+            .write_at(32 * 1024, &data[32 * 1024..48 * 1024])
+            .await
+            .unwrap();
+        assert_eq!(get(&file).await.unwrap(), RangeSet2::from(..ChunkNum(98)));
+
+        // data that was verified before is not hashed again
+        let zeros = BytesMut::zeroed(data.len());
+        assert_eq!(get(&zeros).await.unwrap(), RangeSet2::from(..ChunkNum(98)));
+
+        // but data that is overwritten is
+        let mut writer = TrackedWriter::data(&mut file, verified.clone());
+        writer.write_at(0, &[0u8; 1024]).await.unwrap();
+        assert_eq!(
+            get(&file).await.unwrap(),
+            RangeSet2::from(ChunkNum(16)..ChunkNum(98))
+        );
+    }
+}
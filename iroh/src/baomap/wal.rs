@@ -0,0 +1,292 @@
+//! A small write-ahead log of in-flight operations for persistent stores.
+//!
+//! Operations that move or write several files, like importing a file or completing a
+//! partial entry, record their intent in the log before touching any file, and mark it
+//! as done afterwards. After a crash, the intents that were never marked as done are
+//! replayed on startup, so the store can clean up or complete the interrupted
+//! operations instead of leaving orphaned or inconsistent files behind.
+//!
+//! The log is a sequence of records, each a little endian `u32` length followed by a
+//! postcard encoded [`Record`]. A torn record at the end of the log, e.g. from a crash
+//! while appending, is ignored. The log is truncated whenever no operation is in flight,
+//! so it stays small.
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use iroh_bytes::Hash;
+use serde::{Deserialize, Serialize};
+
+/// An operation that is recorded in the log while it is in flight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Intent {
+    /// Data is being copied into a temp file to be imported.
+    Import {
+        /// The temp file
+        temp: PathBuf,
+    },
+    /// The data and outboard of a complete entry are being moved or written into place.
+    Complete {
+        /// The hash of the entry
+        hash: Hash,
+        /// The size of the entry
+        size: u64,
+        /// The file the data is moved from, `None` if it is written directly
+        data: Option<PathBuf>,
+        /// The file the outboard is moved from, `None` if it is written directly
+        outboard: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    id: u64,
+    /// The intent of a new operation, `None` if the operation is done.
+    intent: Option<Intent>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    file: File,
+    next_id: u64,
+    /// Operations that are in flight.
+    in_flight: BTreeMap<u64, Intent>,
+    /// Operations that failed, to be replayed on the next start.
+    failed: BTreeMap<u64, Intent>,
+}
+
+/// The write-ahead log.
+#[derive(Debug)]
+pub(crate) struct Wal(Mutex<Inner>);
+
+impl Wal {
+    /// Read the intents of the operations that were in flight when the log was last used.
+    ///
+    /// Returns an empty list if the log does not exist.
+    pub fn pending(path: &Path) -> io::Result<Vec<Intent>> {
+        let mut data = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut pending = BTreeMap::new();
+        let mut rest = &data[..];
+        while rest.len() >= 4 {
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            let Some(record) = rest.get(4..4 + len) else {
+                tracing::warn!("ignoring torn record at the end of {}", path.display());
+                break;
+            };
+            let Ok(record) = postcard::from_bytes::<Record>(record) else {
+                tracing::warn!("ignoring invalid record at the end of {}", path.display());
+                break;
+            };
+            match record.intent {
+                Some(intent) => pending.insert(record.id, intent),
+                None => pending.remove(&record.id),
+            };
+            rest = &rest[4 + len..];
+        }
+        Ok(pending.into_values().collect())
+    }
+
+    /// Create a new, empty log, replacing an existing one.
+    ///
+    /// Any pending intents must have been replayed before.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.set_len(0)?;
+        file.sync_all()?;
+        // a newly created log is lost in a crash unless its directory entry is durable
+        if let Some(dir) = path.parent() {
+            super::fsync::sync_dir(dir)?;
+        }
+        Ok(Self(Mutex::new(Inner {
+            file,
+            next_id: 0,
+            in_flight: BTreeMap::new(),
+            failed: BTreeMap::new(),
+        })))
+    }
+
+    /// Record the intent of a new operation.
+    ///
+    /// The intent is durable once this returns, regardless of the fsync policy of the
+    /// store, since the operation mutates the store right after. Call [`InFlight::done`]
+    /// once the operation has completed. If the operation fails, the guard can just be
+    /// dropped, and the intent is replayed on the next start.
+    pub fn begin(&self, intent: Intent) -> io::Result<InFlight<'_>> {
+        let mut inner = self.0.lock().unwrap();
+        let id = inner.next_id;
+        let record = Record {
+            id,
+            intent: Some(intent.clone()),
+        };
+        write_record(&mut inner.file, &record)?;
+        inner.file.sync_data()?;
+        inner.next_id += 1;
+        inner.in_flight.insert(id, intent);
+        Ok(InFlight {
+            wal: self,
+            id,
+            done: false,
+        })
+    }
+
+    /// Mark the operation `id` as done, see [`InFlight::detach`].
+    ///
+    /// Like the intent, the mark is durable once this returns.
+    pub fn done(&self, id: u64) -> io::Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.in_flight.remove(&id);
+        if inner.in_flight.is_empty() {
+            // start over, keeping only the intents of failed operations
+            inner.file.set_len(0)?;
+            let failed = std::mem::take(&mut inner.failed);
+            for (id, intent) in &failed {
+                let record = Record {
+                    id: *id,
+                    intent: Some(intent.clone()),
+                };
+                write_record(&mut inner.file, &record)?;
+            }
+            inner.failed = failed;
+            // the intents of failed operations must not be lost with the truncation
+            return inner.file.sync_data();
+        }
+        write_record(&mut inner.file, &Record { id, intent: None })?;
+        inner.file.sync_data()
+    }
+
+    fn failed(&self, id: u64) {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(intent) = inner.in_flight.remove(&id) {
+            inner.failed.insert(id, intent);
+        }
+    }
+}
+
+/// An operation recorded in the [`Wal`].
+#[derive(Debug)]
+#[must_use = "the intent is replayed on the next start unless it is marked as done"]
+pub(crate) struct InFlight<'a> {
+    wal: &'a Wal,
+    id: u64,
+    done: bool,
+}
+
+impl InFlight<'_> {
+    /// Mark the operation as done.
+    pub fn done(mut self) -> io::Result<()> {
+        self.done = true;
+        self.wal.done(self.id)
+    }
+
+    /// Keep the operation in flight beyond the guard, e.g. to finish it on another thread.
+    ///
+    /// Returns the id to pass to [`Wal::done`] once the operation has completed.
+    pub fn detach(mut self) -> u64 {
+        self.done = true;
+        self.id
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.wal.failed(self.id);
+        }
+    }
+}
+
+fn write_record(file: &mut File, record: &Record) -> io::Result<()> {
+    let data =
+        postcard::to_stdvec(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut buf = Vec::with_capacity(4 + data.len());
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&data);
+    file.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(name: &str) -> Intent {
+        Intent::Import {
+            temp: PathBuf::from(name),
+        }
+    }
+
+    #[test]
+    fn replay_pending() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        assert!(Wal::pending(&path)?.is_empty());
+
+        let wal = Wal::create(&path)?;
+        let a = wal.begin(import("a"))?;
+        let b = wal.begin(import("b"))?;
+        let c = wal.begin(import("c"))?;
+        a.done()?;
+        // b fails, c is interrupted by a crash
+        drop(b);
+        std::mem::forget(c);
+        assert_eq!(Wal::pending(&path)?, vec![import("b"), import("c")]);
+
+        // a torn record at the end is ignored
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[100, 0, 0, 0, 1])?;
+        assert_eq!(Wal::pending(&path)?, vec![import("b"), import("c")]);
+
+        let wal = Wal::create(&path)?;
+        assert!(Wal::pending(&path)?.is_empty());
+        // failed intents survive truncation of the log
+        let d = wal.begin(import("d"))?;
+        drop(d);
+        let e = wal.begin(import("e"))?;
+        e.done()?;
+        assert_eq!(Wal::pending(&path)?, vec![import("d")]);
+        Ok(())
+    }
+
+    #[test]
+    fn torn_records() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let wal = Wal::create(&path)?;
+        std::mem::forget(wal.begin(import("a"))?);
+        let len = std::fs::metadata(&path)?.len();
+        std::mem::forget(wal.begin(import("b"))?);
+        let full = std::fs::metadata(&path)?.len();
+        drop(wal);
+
+        // every prefix of the second record, including a partial length, is ignored
+        let file = OpenOptions::new().write(true).open(&path)?;
+        for torn in (len..full).rev() {
+            file.set_len(torn)?;
+            assert_eq!(Wal::pending(&path)?, vec![import("a")]);
+        }
+
+        // a record that can not be decoded ends the log
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[2, 0, 0, 0, 0xff, 0xff])?;
+        write_record(
+            &mut file,
+            &Record {
+                id: 7,
+                intent: Some(import("c")),
+            },
+        )?;
+        assert_eq!(Wal::pending(&path)?, vec![import("a")]);
+
+        // a new log starts from scratch after the torn one
+        let wal = Wal::create(&path)?;
+        std::mem::forget(wal.begin(import("d"))?);
+        assert_eq!(Wal::pending(&path)?, vec![import("d")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,756 @@
+//! Import and export of [CAR](https://ipld.io/specs/transport/car/carv1/) archives.
+//!
+//! This allows data that has been packaged for IPFS tooling to be served by iroh,
+//! and iroh collections to be handed to IPFS tooling.
+//!
+//! # Hash mapping
+//!
+//! An iroh [`Hash`] corresponds to a CIDv1 with the `raw` codec (`0x55`) and a
+//! 32 byte `blake3` multihash (`0x1e`), see [`Hash::as_cid_bytes`]. Only raw
+//! leaves are supported: the data of a block is stored as a blob as is, without
+//! interpreting any IPLD structure.
+//!
+//! On export, a collection is written as a CARv1 archive with a single root, the
+//! CID of the collection blob itself. The first block is the collection, followed
+//! by one raw block per distinct child blob. Each blob is written as a single
+//! block, so very large blobs will produce blocks that exceed the block size limits
+//! of some IPFS implementations.
+//!
+//! On import, the data of every block is verified against the multihash of its CID
+//! and added to the store. Only blake3 and sha2-256 multihashes are supported, blocks
+//! with other hash functions are rejected. Blocks are added with their blake3 hash, so
+//! blocks with sha2-256 CIDs (e.g. CIDv0) are rehashed. Large blocks are streamed to
+//! the store through a temporary file instead of being held in memory. If the archive
+//! has a single root that is an iroh collection whose children are all contained in
+//! the archive, that collection is used as is. Otherwise a new collection is created
+//! that contains all blocks, named after their original CID in lower case base32
+//! multibase encoding.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bao_tree::blake3;
+use bytes::Bytes;
+use iroh_bytes::baomap::{ImportMode, Map, MapEntry, Store};
+use iroh_bytes::util::progress::IgnoreProgressSender;
+use iroh_bytes::Hash;
+use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
+use rand::Rng;
+use sha2::Digest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Take};
+
+use crate::collection::{Blob, Collection};
+
+/// The maximum size of the header of a CAR archive.
+const MAX_HEADER_SIZE: u64 = 1024 * 1024;
+/// The maximum size of a single block in a CAR archive.
+pub const MAX_BLOCK_SIZE: u64 = 1024 * 1024 * 1024;
+/// Blocks up to this size are imported from memory, larger ones through a temporary file.
+const MAX_IN_MEMORY_BLOCK_SIZE: u64 = 1024 * 1024;
+/// The size of the chunks in which blobs are copied into an archive.
+const EXPORT_CHUNK_SIZE: usize = 1024 * 64;
+/// The maximum nesting of cbor items in a header.
+const MAX_CBOR_DEPTH: usize = 16;
+
+/// The DAG-CBOR tag for CIDs.
+const CBOR_TAG_CID: u64 = 42;
+
+/// The multihash code of blake3.
+const MULTIHASH_BLAKE3: u64 = 0x1e;
+/// The multihash code of sha2-256, the hash function of CIDv0.
+const MULTIHASH_SHA2_256: u64 = 0x12;
+
+/// A single block of a CAR archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarBlock {
+    /// The binary CID of the block.
+    pub cid: Vec<u8>,
+    /// The data of the block.
+    pub data: Bytes,
+}
+
+impl CarBlock {
+    /// The iroh hash of the block, if the CID is a blake3 raw CID.
+    pub fn hash(&self) -> Option<Hash> {
+        Hash::from_cid_bytes(&self.cid).ok()
+    }
+}
+
+/// Format a binary CID as a lower case base32 multibase string.
+///
+/// For blake3 raw CIDs this is the same as the [`std::fmt::Display`] impl of [`Hash`].
+pub fn cid_to_string(cid: &[u8]) -> String {
+    let mut res = String::from("b");
+    res.push_str(&data_encoding::BASE32_NOPAD.encode(cid).to_ascii_lowercase());
+    res
+}
+
+/// A reader for CARv1 archives.
+#[derive(Debug)]
+pub struct CarReader<R> {
+    reader: R,
+    roots: Vec<Vec<u8>>,
+}
+
+impl<R: AsyncRead + Unpin> CarReader<R> {
+    /// Create a new reader, reading the header of the archive.
+    pub async fn new(mut reader: R) -> Result<Self> {
+        let len = read_varint(&mut reader)
+            .await?
+            .context("unexpected end of file reading car header")?;
+        anyhow::ensure!(len <= MAX_HEADER_SIZE, "car header too large: {}", len);
+        let mut header = vec![0u8; len as usize];
+        reader.read_exact(&mut header).await?;
+        let roots = decode_header(&header)?;
+        Ok(Self { reader, roots })
+    }
+
+    /// The binary CIDs of the roots of the archive.
+    pub fn roots(&self) -> &[Vec<u8>] {
+        &self.roots
+    }
+
+    /// Read the next block, returning `None` at the end of the archive.
+    pub async fn next_block(&mut self) -> Result<Option<CarBlock>> {
+        let Some((cid, mut reader)) = self.next_block_reader().await? else {
+            return Ok(None);
+        };
+        let len = reader.limit();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        anyhow::ensure!(
+            data.len() as u64 == len,
+            "unexpected end of file in car block"
+        );
+        Ok(Some(CarBlock {
+            cid,
+            data: data.into(),
+        }))
+    }
+
+    /// Read the CID of the next block, returning `None` at the end of the archive.
+    ///
+    /// The data of the block is returned as a reader, which must be read to the end
+    /// before the next block is read.
+    pub async fn next_block_reader(&mut self) -> Result<Option<(Vec<u8>, Take<&mut R>)>> {
+        let Some(len) = read_varint(&mut self.reader).await? else {
+            return Ok(None);
+        };
+        anyhow::ensure!(len <= MAX_BLOCK_SIZE, "car block too large: {}", len);
+        let cid = read_cid(&mut self.reader).await?;
+        let data_len = len
+            .checked_sub(cid.len() as u64)
+            .context("car block shorter than its cid")?;
+        Ok(Some((cid, (&mut self.reader).take(data_len))))
+    }
+}
+
+/// A writer for CARv1 archives where all blocks are blake3 raw leaves.
+#[derive(Debug)]
+pub struct CarWriter<W> {
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> CarWriter<W> {
+    /// Create a new writer, writing the header with the given roots.
+    pub async fn new(mut writer: W, roots: &[Hash]) -> Result<Self> {
+        let roots = roots
+            .iter()
+            .map(|x| x.as_cid_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let header = encode_header(&roots);
+        let mut buf = Vec::with_capacity(header.len() + 10);
+        write_varint(&mut buf, header.len() as u64);
+        buf.extend_from_slice(&header);
+        writer.write_all(&buf).await?;
+        Ok(Self { writer })
+    }
+
+    /// Write a block. The hash must be the blake3 hash of the data.
+    pub async fn write_block(&mut self, hash: Hash, data: &[u8]) -> Result<()> {
+        let cid = hash.as_cid_bytes();
+        let mut buf = Vec::with_capacity(cid.len() + 10);
+        write_varint(&mut buf, (cid.len() + data.len()) as u64);
+        buf.extend_from_slice(&cid);
+        self.writer.write_all(&buf).await?;
+        self.writer.write_all(data).await?;
+        Ok(())
+    }
+
+    /// Write a block of `size` bytes, streaming the data from `reader`.
+    ///
+    /// The hash must be the blake3 hash of the data.
+    pub async fn write_block_from<R: AsyncSliceReader>(
+        &mut self,
+        hash: Hash,
+        size: u64,
+        mut reader: R,
+    ) -> Result<()> {
+        let cid = hash.as_cid_bytes();
+        let mut buf = Vec::with_capacity(cid.len() + 10);
+        write_varint(&mut buf, cid.len() as u64 + size);
+        buf.extend_from_slice(&cid);
+        self.writer.write_all(&buf).await?;
+        let mut offset = 0u64;
+        while offset < size {
+            let len = EXPORT_CHUNK_SIZE.min((size - offset) as usize);
+            let chunk = reader.read_at(offset, len).await?;
+            anyhow::ensure!(!chunk.is_empty(), "data of {} shorter than expected", hash);
+            self.writer.write_all(&chunk).await?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Flush the archive, returning the inner writer.
+    pub async fn finish(mut self) -> Result<W> {
+        self.writer.flush().await?;
+        Ok(self.writer)
+    }
+}
+
+/// Import a CAR archive into the store.
+///
+/// Returns the hash of the resulting collection and the collection itself. See the
+/// module docs for how blocks are mapped to collection entries. Large blocks are
+/// written to a temporary file in `temp_dir` before they are imported.
+pub async fn import_car<D: Store, R: AsyncRead + Unpin>(
+    db: &D,
+    reader: R,
+    temp_dir: &Path,
+) -> Result<(Hash, Collection)> {
+    let mut car = CarReader::new(reader).await?;
+    let mut blobs = BTreeMap::new();
+    let mut total_blobs_size = 0u64;
+    while let Some((cid, mut data)) = car.next_block_reader().await? {
+        let name = cid_to_string(&cid);
+        if blobs.contains_key(&name) {
+            // duplicate blocks are allowed in car files
+            tokio::io::copy(&mut data, &mut tokio::io::sink()).await?;
+            continue;
+        }
+        let size = data.limit();
+        let hash = import_block(db, &cid, data, temp_dir)
+            .await
+            .with_context(|| format!("block {name}"))?;
+        total_blobs_size += size;
+        blobs.insert(name, hash);
+    }
+    // an archive that was exported by iroh has the collection as its only root
+    if let [root] = car.roots() {
+        if let Some(res) = existing_collection(db, root).await {
+            return Ok(res);
+        }
+    }
+    let blobs = blobs
+        .into_iter()
+        .map(|(name, hash)| Blob { name, hash })
+        .collect();
+    let collection = Collection::new(blobs, total_blobs_size)?;
+    let hash = db.import_bytes(collection.to_bytes()?.into()).await?;
+    Ok((hash, collection))
+}
+
+/// Verify the data of a block against its CID and add it to the store.
+async fn import_block<D: Store, R: AsyncRead + Unpin>(
+    db: &D,
+    cid: &[u8],
+    mut data: Take<R>,
+    temp_dir: &Path,
+) -> Result<Hash> {
+    let mut verifier = BlockVerifier::new(cid)?;
+    let size = data.limit();
+    if size <= MAX_IN_MEMORY_BLOCK_SIZE {
+        let mut buf = Vec::with_capacity(size as usize);
+        data.read_to_end(&mut buf).await?;
+        anyhow::ensure!(
+            buf.len() as u64 == size,
+            "unexpected end of file in car block"
+        );
+        verifier.update(&buf);
+        verifier.verify()?;
+        return Ok(db.import_bytes(buf.into()).await?);
+    }
+    tokio::fs::create_dir_all(temp_dir).await?;
+    let suffix = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+    let path = temp_dir.join(format!("{suffix}.car-block"));
+    let res = import_block_file(db, data, verifier, &path).await;
+    tokio::fs::remove_file(&path).await.ok();
+    res
+}
+
+/// Write the data of a block to `path` while verifying it, and import the file.
+async fn import_block_file<D: Store, R: AsyncRead + Unpin>(
+    db: &D,
+    mut data: Take<R>,
+    mut verifier: BlockVerifier,
+    path: &Path,
+) -> Result<Hash> {
+    let size = data.limit();
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut written = 0u64;
+    loop {
+        let n = data.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        verifier.update(&buf[..n]);
+        file.write_all(&buf[..n]).await?;
+        written += n as u64;
+    }
+    file.flush().await?;
+    drop(file);
+    anyhow::ensure!(written == size, "unexpected end of file in car block");
+    verifier.verify()?;
+    let progress = IgnoreProgressSender::default();
+    let (hash, _) = db
+        .import(path.to_owned(), ImportMode::Copy, progress)
+        .await?;
+    Ok(hash)
+}
+
+/// Hashes the data of a block with the hash function of its CID.
+enum BlockHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha2_256(sha2::Sha256),
+}
+
+/// Verifies the data of a block against the multihash of its CID.
+struct BlockVerifier {
+    hasher: BlockHasher,
+    digest: Vec<u8>,
+}
+
+impl BlockVerifier {
+    /// Create a verifier for a block, failing if the hash function is not supported.
+    fn new(cid: &[u8]) -> Result<Self> {
+        let (code, digest, _) = parse_cid(cid)?;
+        let hasher = match (code, digest.len()) {
+            (MULTIHASH_BLAKE3, 32) => BlockHasher::Blake3(Box::new(blake3::Hasher::new())),
+            (MULTIHASH_SHA2_256, 32) => BlockHasher::Sha2_256(sha2::Sha256::new()),
+            _ => anyhow::bail!(
+                "unsupported multihash 0x{:x} with a digest of {} bytes",
+                code,
+                digest.len()
+            ),
+        };
+        Ok(Self {
+            hasher,
+            digest: digest.to_vec(),
+        })
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            BlockHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            BlockHasher::Sha2_256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Fail if the data does not match the multihash.
+    fn verify(self) -> Result<()> {
+        let actual = match self.hasher {
+            BlockHasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            BlockHasher::Sha2_256(hasher) => hasher.finalize().to_vec(),
+        };
+        anyhow::ensure!(actual == self.digest, "data does not match the cid");
+        Ok(())
+    }
+}
+
+/// Try to interpret the root of an archive as an iroh collection.
+async fn existing_collection<D: Map>(db: &D, root: &[u8]) -> Option<(Hash, Collection)> {
+    let hash = Hash::from_cid_bytes(root).ok()?;
+    let entry = db.get(&hash)?;
+    let data = entry.data_reader().await.ok()?.read_to_end().await.ok()?;
+    let collection = Collection::from_bytes(&data).ok()?;
+    if collection.blobs().iter().all(|x| db.get(&x.hash).is_some()) {
+        Some((hash, collection))
+    } else {
+        None
+    }
+}
+
+/// Export the collection with the given hash as a CAR archive.
+///
+/// Returns the number of blocks written, including the collection itself.
+pub async fn export_car<D: Map, W: AsyncWrite + Unpin>(
+    db: &D,
+    hash: Hash,
+    writer: W,
+) -> Result<u64> {
+    let entry = db.get(&hash).context("collection not found")?;
+    let data = entry.data_reader().await?.read_to_end().await?;
+    let collection = Collection::from_bytes(&data).context("invalid collection")?;
+    let mut car = CarWriter::new(writer, &[hash]).await?;
+    car.write_block(hash, &data).await?;
+    let mut written = BTreeSet::new();
+    written.insert(hash);
+    for blob in collection.blobs() {
+        if !written.insert(blob.hash) {
+            continue;
+        }
+        let entry = db
+            .get(&blob.hash)
+            .with_context(|| format!("blob {} not found", blob.hash))?;
+        let reader = entry.data_reader().await?;
+        car.write_block_from(blob.hash, entry.size(), reader)
+            .await?;
+    }
+    car.finish().await?;
+    Ok(written.len() as u64)
+}
+
+/// Read an unsigned LEB128 varint, returning `None` on a clean end of file.
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<u64>> {
+    let mut res = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte).await? == 0 {
+            anyhow::ensure!(i == 0, "unexpected end of file in varint");
+            return Ok(None);
+        }
+        res |= u64::from(byte[0] & 0x7f) << (i * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(res));
+        }
+    }
+    anyhow::bail!("varint too long")
+}
+
+/// Decode an unsigned LEB128 varint from the start of a slice.
+///
+/// Returns the value and the number of bytes consumed.
+fn decode_varint(data: &[u8]) -> Result<(u64, usize)> {
+    let mut res = 0u64;
+    for (i, byte) in data.iter().take(10).enumerate() {
+        res |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok((res, i + 1));
+        }
+    }
+    anyhow::bail!("invalid varint")
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Parse the binary CID at the start of `data`.
+///
+/// Returns the code and the digest of its multihash, and the length of the CID.
+fn parse_cid(data: &[u8]) -> Result<(u64, &[u8], usize)> {
+    // CIDv0 is a bare sha2-256 multihash
+    if data.len() >= 34 && data[0] == 0x12 && data[1] == 0x20 {
+        return Ok((MULTIHASH_SHA2_256, &data[2..34], 34));
+    }
+    let mut offset = 0;
+    let (version, n) = decode_varint(data)?;
+    anyhow::ensure!(version == 1, "unsupported cid version {}", version);
+    offset += n;
+    // codec
+    let (_, n) = decode_varint(&data[offset..])?;
+    offset += n;
+    let (code, n) = decode_varint(&data[offset..])?;
+    offset += n;
+    let (len, n) = decode_varint(&data[offset..])?;
+    offset += n;
+    let end = offset
+        .checked_add(usize::try_from(len)?)
+        .context("invalid cid")?;
+    anyhow::ensure!(end <= data.len(), "truncated cid");
+    Ok((code, &data[offset..end], end))
+}
+
+/// Read the binary CID at the start of a block section.
+async fn read_cid<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut cid = Vec::new();
+    let first = read_cid_varint(reader, &mut cid).await?;
+    // CIDv0 is a bare sha2-256 multihash, CIDv1 has a codec and a multihash code
+    let varints = if first == MULTIHASH_SHA2_256 { 1 } else { 3 };
+    let mut len = 0;
+    for _ in 0..varints {
+        len = read_cid_varint(reader, &mut cid).await?;
+    }
+    anyhow::ensure!(len <= 64, "cid digest too long: {}", len);
+    let start = cid.len();
+    cid.resize(start + len as usize, 0);
+    reader.read_exact(&mut cid[start..]).await?;
+    // validates the version and the structure
+    parse_cid(&cid)?;
+    Ok(cid)
+}
+
+/// Read a varint of a CID, appending its bytes to `cid`.
+async fn read_cid_varint<R: AsyncRead + Unpin>(reader: &mut R, cid: &mut Vec<u8>) -> Result<u64> {
+    let start = cid.len();
+    loop {
+        let byte = reader.read_u8().await?;
+        cid.push(byte);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        anyhow::ensure!(cid.len() - start < 10, "invalid varint in cid");
+    }
+    Ok(decode_varint(&cid[start..])?.0)
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    if value < 24 {
+        out.push(major | value as u8);
+    } else if value <= u8::MAX as u64 {
+        out.push(major | 24);
+        out.push(value as u8);
+    } else if value <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn cbor_text(out: &mut Vec<u8>, text: &str) {
+    cbor_head(out, 3, text.len() as u64);
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// Encode a CARv1 header as DAG-CBOR.
+///
+/// Map keys are sorted by length first, as required by DAG-CBOR.
+fn encode_header(roots: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    cbor_head(&mut out, 5, 2);
+    cbor_text(&mut out, "roots");
+    cbor_head(&mut out, 4, roots.len() as u64);
+    for root in roots {
+        cbor_head(&mut out, 6, CBOR_TAG_CID);
+        // cids in DAG-CBOR are prefixed with the identity multibase prefix
+        cbor_head(&mut out, 2, root.len() as u64 + 1);
+        out.push(0);
+        out.extend_from_slice(root);
+    }
+    cbor_text(&mut out, "version");
+    cbor_head(&mut out, 0, 1);
+    out
+}
+
+/// Minimal CBOR decoder, sufficient for CARv1 headers.
+struct CborDecoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> CborDecoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        anyhow::ensure!(n <= self.data.len(), "unexpected end of cbor data");
+        let (res, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(res)
+    }
+
+    fn head(&mut self) -> Result<(u8, u64)> {
+        let first = self.take(1)?[0];
+        let major = first >> 5;
+        let value = match first & 0x1f {
+            x @ 0..=23 => x as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into()?) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into()?) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into()?),
+            _ => anyhow::bail!("unsupported cbor item"),
+        };
+        Ok((major, value))
+    }
+
+    fn text(&mut self) -> Result<&'a str> {
+        let (major, len) = self.head()?;
+        anyhow::ensure!(major == 3, "expected cbor text");
+        Ok(std::str::from_utf8(self.take(usize::try_from(len)?)?)?)
+    }
+
+    /// Skip an item, which may be nested up to `depth` levels.
+    fn skip(&mut self, depth: usize) -> Result<()> {
+        let (major, value) = self.head()?;
+        if matches!(major, 4..=6) {
+            anyhow::ensure!(depth > 0, "cbor nested too deeply");
+        }
+        match major {
+            2 | 3 => {
+                self.take(usize::try_from(value)?)?;
+            }
+            4 => {
+                for _ in 0..value {
+                    self.skip(depth - 1)?;
+                }
+            }
+            5 => {
+                for _ in 0..value.saturating_mul(2) {
+                    self.skip(depth - 1)?;
+                }
+            }
+            6 => self.skip(depth - 1)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Decode a CARv1 header, returning the binary CIDs of the roots.
+fn decode_header(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut dec = CborDecoder { data };
+    let (major, entries) = dec.head()?;
+    anyhow::ensure!(major == 5, "car header must be a map");
+    let mut roots = None;
+    let mut version = None;
+    for _ in 0..entries {
+        match dec.text()? {
+            "roots" => {
+                let (major, len) = dec.head()?;
+                anyhow::ensure!(major == 4, "car roots must be an array");
+                let mut res = Vec::new();
+                for _ in 0..len {
+                    let tag = dec.head()?;
+                    anyhow::ensure!(tag == (6, CBOR_TAG_CID), "car root must be a cid");
+                    let (major, len) = dec.head()?;
+                    anyhow::ensure!(major == 2, "cid must be a byte string");
+                    let bytes = dec.take(usize::try_from(len)?)?;
+                    anyhow::ensure!(bytes.first() == Some(&0), "invalid cid prefix");
+                    res.push(bytes[1..].to_vec());
+                }
+                roots = Some(res);
+            }
+            "version" => {
+                let (major, value) = dec.head()?;
+                anyhow::ensure!(major == 0, "car version must be an integer");
+                version = Some(value);
+            }
+            _ => dec.skip(MAX_CBOR_DEPTH)?,
+        }
+    }
+    anyhow::ensure!(version == Some(1), "unsupported car version {:?}", version);
+    roots.context("car header has no roots")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_roundtrip() {
+        for value in [0u64, 1, 127, 128, 300, 16384, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(decode_varint(&buf).unwrap(), (value, buf.len()));
+        }
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let roots = vec![Hash::new(b"a").as_cid_bytes().to_vec()];
+        let header = encode_header(&roots);
+        assert_eq!(decode_header(&header).unwrap(), roots);
+    }
+
+    #[test]
+    fn decode_go_car_header() {
+        // header with a CIDv0 root and the keys in go-car order
+        let mut header = vec![0xa2];
+        cbor_text(&mut header, "roots");
+        header.push(0x81);
+        header.extend_from_slice(&[0xd8, 0x2a, 0x58, 0x23, 0x00, 0x12, 0x20]);
+        header.extend_from_slice(&[7u8; 32]);
+        cbor_text(&mut header, "version");
+        header.push(0x01);
+        let roots = decode_header(&header).unwrap();
+        assert_eq!(roots.len(), 1);
+        let (code, digest, len) = parse_cid(&roots[0]).unwrap();
+        assert_eq!(
+            (code, digest, len),
+            (MULTIHASH_SHA2_256, &[7u8; 32][..], 34)
+        );
+    }
+
+    #[test]
+    fn decode_deeply_nested_header() {
+        // an unknown key whose value is nested far deeper than the stack allows
+        let mut header = vec![0xa1];
+        cbor_text(&mut header, "extra");
+        header.extend(std::iter::repeat(0x81).take(1_000_000));
+        header.push(0x00);
+        assert!(decode_header(&header).is_err());
+    }
+
+    #[test]
+    fn verify_blocks() {
+        let data = b"hello";
+        let blake3 = Hash::new(data).as_cid_bytes().to_vec();
+        let mut verifier = BlockVerifier::new(&blake3).unwrap();
+        verifier.update(data);
+        verifier.verify().unwrap();
+        let mut verifier = BlockVerifier::new(&blake3).unwrap();
+        verifier.update(b"jello");
+        assert!(verifier.verify().is_err());
+
+        // CIDv0
+        let mut sha2 = vec![0x12, 0x20];
+        sha2.extend_from_slice(&sha2::Sha256::digest(data));
+        let mut verifier = BlockVerifier::new(&sha2).unwrap();
+        verifier.update(data);
+        verifier.verify().unwrap();
+
+        // CIDv1, raw codec, sha1
+        let mut sha1 = vec![0x01, 0x55, 0x11, 0x14];
+        sha1.extend_from_slice(&[0u8; 20]);
+        assert!(BlockVerifier::new(&sha1).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_cids() -> Result<()> {
+        let mut sha2 = vec![0x12, 0x20];
+        sha2.extend_from_slice(&[7u8; 32]);
+        let blake3 = Hash::new(b"hello").as_cid_bytes().to_vec();
+        for cid in [sha2, blake3] {
+            let mut section = cid.clone();
+            section.extend_from_slice(b"data");
+            assert_eq!(read_cid(&mut section.as_slice()).await?, cid);
+        }
+        assert!(read_cid(&mut [0x02u8, 0x55].as_slice()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn car_roundtrip() -> Result<()> {
+        let blocks = [
+            Bytes::from_static(b"hello"),
+            Bytes::from(vec![1u8; 100_000]),
+        ];
+        let hashes = blocks.iter().map(Hash::new).collect::<Vec<_>>();
+        let mut car = CarWriter::new(Vec::new(), &hashes[..1]).await?;
+        for (hash, data) in hashes.iter().zip(blocks.iter()) {
+            car.write_block(*hash, data).await?;
+        }
+        let bytes = car.finish().await?;
+
+        let mut car = CarReader::new(bytes.as_slice()).await?;
+        assert_eq!(car.roots(), &[hashes[0].as_cid_bytes().to_vec()]);
+        for (hash, data) in hashes.iter().zip(blocks.iter()) {
+            let block = car.next_block().await?.unwrap();
+            assert_eq!(block.hash(), Some(*hash));
+            assert_eq!(&block.data, data);
+            assert_eq!(cid_to_string(&block.cid), hash.to_string());
+        }
+        assert!(car.next_block().await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+//! A typed client for the RPC interface of a running node.
+//!
+//! [`Iroh`] wraps an [`RpcClient`] for the [`ProviderService`] with a method per request,
+//! so applications controlling a node don't have to deal with the messages of
+//! [`crate::rpc_protocol`] directly.
+//!
+//! The client can talk to the node over any transport. Two are provided:
+//!
+//! - the local socket of the node, see [`crate::local_rpc`], with [`Iroh::connect_local`],
+//! - QUIC on a localhost port, with [`Iroh::connect_quinn`]. This needs the `rpc-quinn`
+//!   feature.
+//!
+//! For tests, [`mock::Iroh`] runs a node in process.
+//!
+//! Both transports open a new stream or connection for every request, and the QUIC
+//! transport reconnects when the connection is lost. A client keeps working across
+//! restarts of the node, requests fail while it is down.
+//!
+//! The client only needs [`crate::rpc_protocol`], so it is available with the default
+//! features disabled. The provider itself, `crate::node` and the stores, is behind the
+//! `node` feature.
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use iroh_bytes::provider::ShareProgress;
+use iroh_bytes::Hash;
+use quic_rpc::{RpcClient, ServiceConnection};
+
+use crate::local_rpc::LocalConnection;
+use crate::rpc_protocol::{
+    AddrsRequest, BlobDiffRequest, BlobDiffResponse, BlobReadAtRequest, BlobReadAtResponse,
+    DeleteBlobRequest, ExportPartialRequest, IdRequest, IdResponse, ImportPartialRequest,
+    ListBlobsRequest, ListBlobsResponse, ListCollectionsRequest, ListCollectionsResponse,
+    NodeEvent, NodePaths, NodeStatusRequest, NodeStatusResponse, PathsRequest, PinAddRequest,
+    PinAddResponse, PinListRequest, PinListResponse, PinRemoveRequest, PinRemoveResponse,
+    ProvideProgress, ProvideRequest, ProviderRequest, ProviderResponse, ProviderService,
+    RestoreBlobRequest, SetLogFilterRequest, ShareRequest, ShutdownRequest, SubscribeRequest,
+    ValidateProgress, ValidateRequest, VersionRequest,
+};
+
+#[cfg(feature = "mem-db")]
+pub mod mock;
+
+/// The default port of the QUIC transport.
+pub const DEFAULT_RPC_PORT: u16 = 0x1337;
+/// The ALPN of the QUIC transport.
+pub const RPC_ALPN: [u8; 17] = *b"n0/provider-rpc/1";
+
+/// How long [`Iroh::connect`] waits for the node to answer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An RPC connection over the local socket of a node.
+pub type LocalRpcConnection = LocalConnection<ProviderResponse, ProviderRequest>;
+
+/// An RPC connection over QUIC on a localhost port.
+#[cfg(feature = "rpc-quinn")]
+pub type QuinnRpcConnection =
+    quic_rpc::transport::quinn::QuinnConnection<ProviderResponse, ProviderRequest>;
+
+/// A typed client for a running node.
+#[derive(Debug, Clone)]
+pub struct Iroh<C> {
+    rpc: RpcClient<ProviderService, C>,
+}
+
+impl<C: ServiceConnection<ProviderService>> Iroh<C> {
+    /// A client using an existing RPC client, e.g. `Node::controller` of an in-process node.
+    pub fn new(rpc: RpcClient<ProviderService, C>) -> Self {
+        Self { rpc }
+    }
+
+    /// A client using `connection`, checking that the node is running.
+    pub async fn connect(connection: C) -> Result<Self> {
+        let client = Self::new(RpcClient::new(connection));
+        tokio::time::timeout(CONNECT_TIMEOUT, client.version())
+            .await
+            .context("iroh node is not running")??;
+        Ok(client)
+    }
+
+    /// The underlying RPC client, for requests without a method.
+    pub fn rpc(&self) -> &RpcClient<ProviderService, C> {
+        &self.rpc
+    }
+
+    /// Consumes the client, returning the underlying RPC client.
+    pub fn into_rpc(self) -> RpcClient<ProviderService, C> {
+        self.rpc
+    }
+
+    /// The version of the node.
+    pub async fn version(&self) -> Result<String> {
+        Ok(self.rpc.rpc(VersionRequest).await?.version)
+    }
+
+    /// The identity and listening addresses of the node.
+    pub async fn id(&self) -> Result<IdResponse> {
+        Ok(self.rpc.rpc(IdRequest).await?)
+    }
+
+    /// The addresses the node listens on.
+    pub async fn addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self.rpc.rpc(AddrsRequest).await?.addrs)
+    }
+
+    /// The status of the node.
+    pub async fn status(&self) -> Result<NodeStatusResponse> {
+        Ok(self.rpc.rpc(NodeStatusRequest).await?)
+    }
+
+    /// The directories of the node, if the application running it reported them.
+    pub async fn paths(&self) -> Result<Option<NodePaths>> {
+        Ok(self.rpc.rpc(PathsRequest).await?.paths)
+    }
+
+    /// Replaces the log filter of the node, in the syntax of `RUST_LOG`.
+    pub async fn set_log_filter(&self, filter: impl Into<String>) -> Result<()> {
+        let filter = filter.into();
+        Ok(self.rpc.rpc(SetLogFilterRequest { filter }).await??)
+    }
+
+    /// Shuts the node down, `force` does not wait for connections to close.
+    pub async fn shutdown(&self, force: bool) -> Result<()> {
+        self.rpc.rpc(ShutdownRequest { force }).await?;
+        Ok(())
+    }
+
+    /// Adds the file or directory described by `request` as a collection.
+    pub async fn provide(
+        &self,
+        request: ProvideRequest,
+    ) -> Result<BoxStream<'static, Result<ProvideProgress>>> {
+        self.server_streaming(request).await
+    }
+
+    /// Downloads the data described by `request` into the store of the node.
+    pub async fn share(
+        &self,
+        request: ShareRequest,
+    ) -> Result<BoxStream<'static, Result<ShareProgress>>> {
+        self.server_streaming(request).await
+    }
+
+    /// Lists the complete blobs in the store.
+    pub async fn list_blobs(&self) -> Result<BoxStream<'static, Result<ListBlobsResponse>>> {
+        self.server_streaming(ListBlobsRequest).await
+    }
+
+    /// Lists the collections in the store.
+    pub async fn list_collections(
+        &self,
+    ) -> Result<BoxStream<'static, Result<ListCollectionsResponse>>> {
+        self.server_streaming(ListCollectionsRequest).await
+    }
+
+    /// Validates the store, removing invalid data with `repair`.
+    pub async fn validate(
+        &self,
+        repair: bool,
+    ) -> Result<BoxStream<'static, Result<ValidateProgress>>> {
+        self.server_streaming(ValidateRequest { repair }).await
+    }
+
+    /// Adds a pin, replacing an existing pin with the same name.
+    pub async fn pin_add(&self, request: PinAddRequest) -> Result<PinAddResponse> {
+        Ok(self.rpc.rpc(request).await??)
+    }
+
+    /// Removes the pin called `name`.
+    pub async fn pin_remove(&self, name: String) -> Result<PinRemoveResponse> {
+        Ok(self.rpc.rpc(PinRemoveRequest { name }).await??)
+    }
+
+    /// Lists the pins.
+    pub async fn pin_list(&self) -> Result<BoxStream<'static, Result<PinListResponse>>> {
+        self.server_streaming(PinListRequest).await
+    }
+
+    /// Deletes a blob, stores with a trash keep it for a while, see [`Iroh::restore_blob`].
+    pub async fn delete_blob(&self, hash: Hash) -> Result<()> {
+        self.rpc.rpc(DeleteBlobRequest { hash }).await??;
+        Ok(())
+    }
+
+    /// Restores a deleted blob from the trash of the store.
+    pub async fn restore_blob(&self, hash: Hash) -> Result<()> {
+        self.rpc.rpc(RestoreBlobRequest { hash }).await??;
+        Ok(())
+    }
+
+    /// Exports a partially downloaded blob as a sparse file at `path` on the node.
+    pub async fn export_partial(&self, hash: Hash, path: PathBuf) -> Result<()> {
+        Ok(self.rpc.rpc(ExportPartialRequest { hash, path }).await??)
+    }
+
+    /// Imports a sparse file at `path` on the node, returning the hash of the blob.
+    pub async fn import_partial(&self, path: PathBuf) -> Result<Hash> {
+        Ok(self.rpc.rpc(ImportPartialRequest { path }).await??.hash)
+    }
+
+    /// Reads `len` bytes of a complete blob starting at `offset`, or the rest of the blob
+    /// without `len`, from the store of the node.
+    ///
+    /// The range is cut off at the end of the blob.
+    pub async fn read_at(&self, hash: Hash, offset: u64, len: Option<u64>) -> Result<BlobReader> {
+        let mut stream = self
+            .server_streaming(BlobReadAtRequest { hash, offset, len })
+            .await?;
+        let size = match stream.next().await {
+            Some(item) => match item?? {
+                BlobReadAtResponse::Entry { size } => size,
+                BlobReadAtResponse::Data { .. } => return Err(anyhow!("expected blob size")),
+            },
+            None => return Err(anyhow!("read of blob {hash} ended without a response")),
+        };
+        let stream = stream
+            .map(|item| match item?? {
+                BlobReadAtResponse::Data { chunk } => Ok(chunk),
+                BlobReadAtResponse::Entry { .. } => Err(anyhow!("expected blob data")),
+            })
+            .boxed();
+        Ok(BlobReader { size, stream })
+    }
+
+    /// Compares the collections `from` and `to` in the store of the node.
+    ///
+    /// Yields the entries that differ, ordered by name. Only the collection blobs have to
+    /// be in the store.
+    pub async fn blob_diff(
+        &self,
+        from: Hash,
+        to: Hash,
+    ) -> Result<BoxStream<'static, Result<BlobDiffResponse>>> {
+        let stream = self.server_streaming(BlobDiffRequest { from, to }).await?;
+        Ok(stream.map(|item| Ok(item??)).boxed())
+    }
+
+    /// Reads a whole complete blob from the store of the node into memory.
+    pub async fn read_to_bytes(&self, hash: Hash) -> Result<Bytes> {
+        self.read_at(hash, 0, None).await?.read_to_bytes().await
+    }
+
+    /// Subscribes to the events of the node, see [`NodeEvent`].
+    pub async fn subscribe(&self) -> Result<BoxStream<'static, Result<NodeEvent>>> {
+        self.server_streaming(SubscribeRequest).await
+    }
+
+    async fn server_streaming<M>(&self, msg: M) -> Result<BoxStream<'static, Result<M::Response>>>
+    where
+        M: quic_rpc::message::ServerStreamingMsg<ProviderService>,
+    {
+        let stream = self.rpc.server_streaming(msg).await?;
+        Ok(stream.map(|item| item.map_err(Into::into)).boxed())
+    }
+}
+
+/// The data of a range of a blob, read with [`Iroh::read_at`].
+///
+/// A stream of the pieces of the range, in order.
+pub struct BlobReader {
+    size: u64,
+    stream: BoxStream<'static, Result<Bytes>>,
+}
+
+impl std::fmt::Debug for BlobReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlobReader")
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BlobReader {
+    /// The size of the whole blob, not just of the range that is read.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads the rest of the range into memory.
+    pub async fn read_to_bytes(self) -> Result<Bytes> {
+        let buf = self
+            .stream
+            .try_fold(BytesMut::new(), |mut buf, chunk| async move {
+                buf.extend_from_slice(&chunk);
+                Ok(buf)
+            })
+            .await?;
+        Ok(buf.freeze())
+    }
+}
+
+impl Stream for BlobReader {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+impl Iroh<LocalRpcConnection> {
+    /// Connects to the node listening on the local socket at `path`.
+    pub async fn connect_local(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::connect(LocalConnection::new(path)).await
+    }
+}
+
+#[cfg(feature = "rpc-quinn")]
+impl Iroh<QuinnRpcConnection> {
+    /// Connects to the node listening on `rpc_port` on localhost.
+    pub async fn connect_quinn(rpc_port: u16) -> Result<Self> {
+        Self::connect(quinn_connection(rpc_port)?).await
+    }
+}
+
+/// A QUIC connection to the node listening on `rpc_port` on localhost.
+///
+/// Nothing is connected until the first request. Must be called within a tokio runtime.
+#[cfg(feature = "rpc-quinn")]
+pub fn quinn_connection(rpc_port: u16) -> Result<QuinnRpcConnection> {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::Arc;
+
+    let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into();
+    let keypair = iroh_net::tls::Keypair::generate();
+    let tls_client_config =
+        iroh_net::tls::make_client_config(&keypair, None, vec![RPC_ALPN.to_vec()], false)?;
+    let mut client_config = quinn::ClientConfig::new(Arc::new(tls_client_config));
+    let mut endpoint = quinn::Endpoint::client(bind_addr)?;
+    let mut transport_config = quinn::TransportConfig::default();
+    transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
+    client_config.transport_config(Arc::new(transport_config));
+    endpoint.set_default_client_config(client_config);
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), rpc_port);
+    Ok(QuinnRpcConnection::new(
+        endpoint,
+        addr,
+        "localhost".to_string(),
+    ))
+}
+
+#[cfg(all(test, unix, feature = "node"))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use iroh_bytes::util::runtime;
+
+    use super::*;
+    use crate::baomap::readonly_mem;
+    use crate::local_rpc::LocalServerEndpoint;
+    use crate::node::Node;
+
+    #[tokio::test]
+    async fn test_connect_local() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("rpc.sock");
+        assert!(Iroh::connect_local(&path).await.is_err());
+
+        let rt = runtime::Handle::from_currrent(1)?;
+        let node = Node::builder(readonly_mem::Store::default())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .rpc_endpoint(LocalServerEndpoint::bind(&path)?)
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        let client = Iroh::connect_local(&path).await?;
+        assert_eq!(client.version().await?, env!("CARGO_PKG_VERSION"));
+        assert_eq!(*client.id().await?.peer_id, node.peer_id());
+        assert!(client.paths().await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+//! An in-process node to test applications using the client.
+//!
+//! [`Iroh`] runs a node with an in memory store, bound to localhost, and talks to it over
+//! an in-process channel instead of a socket. It dereferences to the [`super::Iroh`]
+//! client, so code written against the client can be tested with it unchanged:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use iroh::bytes::baomap::Store;
+//!
+//! let iroh = iroh::client::mock::Iroh::new().await?;
+//! let hash = iroh.store().import_bytes("hello".into()).await?;
+//! let ticket = iroh.node().ticket(hash).await?;
+//! println!("{ticket}");
+//! # Ok(())
+//! # }
+//! ```
+use std::net::Ipv4Addr;
+use std::ops::Deref;
+
+use anyhow::Result;
+use iroh_bytes::util::runtime;
+use quic_rpc::transport::flume::{self, FlumeConnection};
+use quic_rpc::RpcClient;
+
+use crate::baomap::mem;
+use crate::node::Node;
+use crate::rpc_protocol::{ProviderRequest, ProviderResponse};
+
+/// The in-process RPC connection of the mock node.
+pub type MockConnection = FlumeConnection<ProviderResponse, ProviderRequest>;
+
+/// A client connected to an in-process node, see the [module docs](self).
+///
+/// The node is shut down when this is dropped.
+#[derive(Debug)]
+pub struct Iroh {
+    client: super::Iroh<MockConnection>,
+    node: Node<mem::Store>,
+    store: mem::Store,
+}
+
+impl Iroh {
+    /// Spawns a node on the current tokio runtime.
+    pub async fn new() -> Result<Self> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        Self::with_runtime(&rt).await
+    }
+
+    /// Spawns a node on `rt`.
+    pub async fn with_runtime(rt: &runtime::Handle) -> Result<Self> {
+        let store = mem::Store::new(rt.clone());
+        let (server, client) = flume::connection(1);
+        let node = Node::builder(store.clone())
+            .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+            .rpc_endpoint(server)
+            .runtime(rt)
+            .spawn()
+            .await?;
+        let client = super::Iroh::new(RpcClient::new(client));
+        Ok(Self {
+            client,
+            node,
+            store,
+        })
+    }
+
+    /// The node, e.g. to create tickets for its blobs.
+    pub fn node(&self) -> &Node<mem::Store> {
+        &self.node
+    }
+
+    /// The store of the node, to add or inspect data directly.
+    pub fn store(&self) -> &mem::Store {
+        &self.store
+    }
+}
+
+impl Deref for Iroh {
+    type Target = super::Iroh<MockConnection>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl Drop for Iroh {
+    fn drop(&mut self) {
+        self.node.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use iroh_bytes::baomap::Store;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock() -> Result<()> {
+        let iroh = Iroh::new().await?;
+        assert_eq!(iroh.version().await?, env!("CARGO_PKG_VERSION"));
+
+        let hash = iroh.store().import_bytes("hello".into()).await?;
+        let blobs = iroh.list_blobs().await?.collect::<Vec<_>>().await;
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].as_ref().unwrap().hash, hash);
+        assert_eq!(iroh.node().ticket(hash).await?.hash(), hash);
+        Ok(())
+    }
+}
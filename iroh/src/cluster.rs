@@ -0,0 +1,481 @@
+//! Placement of content across a cluster of nodes.
+//!
+//! A cluster is a fixed set of nodes that share a consistent hash ring. Every hash is
+//! assigned to the first `replicas` distinct members found walking the ring clockwise
+//! from the position of the hash, its designated replicas. Adding or removing a member
+//! only moves the hashes next to its positions on the ring.
+//!
+//! Requesters do not have to know the ring. A routed get request, created with
+//! [`routed_get_request`] and sent as a custom get request to any member, makes that
+//! member fetch the content from the designated replicas before serving it. A member that
+//! is not a designated replica itself only keeps the content it fetched for
+//! [`ROUTED_CACHE_TTL`], unless it is pinned in the meantime.
+//!
+//! A [`Cluster`] keeps the ring of its configuration, and rebuilds it when the members
+//! change with [`Cluster::set_config`].
+//!
+//! All members must be configured with the same list of members, including themselves,
+//! otherwise they disagree about the placement of content.
+//!
+//! Members that only accept expiring tokens, see [`crate::util::expiring_token`], fetch
+//! from each other with tokens they sign themselves, see [`ClusterConfig::signed_fetches`].
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use iroh_bytes::protocol::{GetRequest, RequestToken};
+use iroh_bytes::provider::CustomGetHandler;
+use iroh_bytes::Hash;
+use iroh_net::tls::PeerId;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// Prefix of the data of a routed get request, to tell it apart from other custom requests.
+const ROUTED_GET_PREFIX: &[u8] = b"/iroh-cluster/0";
+
+/// Number of positions of every member on the ring.
+///
+/// More positions spread the content more evenly across members.
+const VIRTUAL_NODES: u32 = 64;
+
+/// How long a member keeps content it fetched for a routed get request, if it is not one
+/// of the designated replicas of the content.
+pub const ROUTED_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long the token of a [signed fetch](ClusterConfig::signed_fetches) is valid.
+///
+/// Tokens are only checked when a request starts, so this only has to cover the clock
+/// skew between the members.
+pub(crate) const FETCH_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// A member of a cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterMember {
+    /// The peer id of the member.
+    pub peer: PeerId,
+    /// Direct addresses of the member.
+    pub addrs: Vec<SocketAddr>,
+    /// DERP region of the member.
+    pub derp_region: Option<u16>,
+}
+
+/// The members of a cluster, and how many of them store each hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterConfig {
+    /// All members of the cluster, including the local node.
+    pub members: Vec<ClusterMember>,
+    /// Number of designated replicas of every hash.
+    pub replicas: usize,
+    /// Whether members send a token they signed themselves when fetching from each other.
+    ///
+    /// Members that only accept expiring tokens need this, and trust the keys of the other
+    /// members, see [`ExpiringTokenAuthHandler::trust`].
+    ///
+    /// [`ExpiringTokenAuthHandler::trust`]: crate::util::expiring_token::ExpiringTokenAuthHandler::trust
+    pub signed_fetches: bool,
+}
+
+/// A cluster configuration with its hash ring.
+#[derive(Debug)]
+pub struct Cluster(RwLock<Placement>);
+
+#[derive(Debug)]
+struct Placement {
+    config: ClusterConfig,
+    ring: HashRing,
+}
+
+impl Placement {
+    fn new(config: ClusterConfig) -> Self {
+        let ring = HashRing::new(config.members.iter().map(|member| member.peer));
+        Self { config, ring }
+    }
+}
+
+impl Cluster {
+    /// Creates a cluster with the given configuration.
+    pub fn new(config: ClusterConfig) -> Self {
+        Self(RwLock::new(Placement::new(config)))
+    }
+
+    /// The current configuration.
+    pub fn config(&self) -> ClusterConfig {
+        self.0.read().unwrap().config.clone()
+    }
+
+    /// Replaces the configuration, rebuilding the ring.
+    ///
+    /// All members must switch to the same configuration, otherwise they disagree about
+    /// the placement of content.
+    pub fn set_config(&self, config: ClusterConfig) {
+        *self.0.write().unwrap() = Placement::new(config);
+    }
+
+    /// The designated replicas of a hash, in the order they should be asked for it.
+    pub fn replicas(&self, hash: &Hash) -> Vec<ClusterMember> {
+        let placement = self.0.read().unwrap();
+        let members = &placement.config.members;
+        placement
+            .ring
+            .replicas(hash, placement.config.replicas)
+            .into_iter()
+            .filter_map(|peer| members.iter().find(|member| member.peer == peer).cloned())
+            .collect()
+    }
+
+    /// Whether `peer` is a designated replica of `hash`.
+    pub fn is_replica(&self, peer: &PeerId, hash: &Hash) -> bool {
+        let placement = self.0.read().unwrap();
+        let replicas = placement.ring.replicas(hash, placement.config.replicas);
+        replicas.contains(peer)
+    }
+}
+
+/// A consistent hash ring of peers.
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    /// Positions on the ring, sorted.
+    points: Vec<(u64, PeerId)>,
+}
+
+impl HashRing {
+    /// Creates a ring of the given peers.
+    pub fn new(peers: impl IntoIterator<Item = PeerId>) -> Self {
+        let mut points = Vec::new();
+        for peer in peers {
+            for i in 0..VIRTUAL_NODES {
+                let mut data = [0u8; 36];
+                data[..32].copy_from_slice(peer.as_bytes());
+                data[32..].copy_from_slice(&i.to_le_bytes());
+                points.push((position(&Hash::new(data)), peer));
+            }
+        }
+        points.sort_by(|(a, peer_a), (b, peer_b)| {
+            a.cmp(b)
+                .then_with(|| peer_a.as_bytes().cmp(peer_b.as_bytes()))
+        });
+        Self { points }
+    }
+
+    /// The first `n` distinct peers following the position of the hash on the ring.
+    pub fn replicas(&self, hash: &Hash, n: usize) -> Vec<PeerId> {
+        let start = self
+            .points
+            .partition_point(|(pos, _)| *pos < position(hash));
+        let mut peers = Vec::with_capacity(n);
+        for (_, peer) in self.points[start..].iter().chain(&self.points[..start]) {
+            if peers.len() == n {
+                break;
+            }
+            if !peers.contains(peer) {
+                peers.push(*peer);
+            }
+        }
+        peers
+    }
+}
+
+fn position(hash: &Hash) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RoutedGet {
+    hash: Hash,
+    recursive: bool,
+}
+
+/// Creates the data of a custom get request for a hash that is routed through the cluster.
+///
+/// If `recursive` is true the hash is fetched as a collection, with all its children.
+pub fn routed_get_request(hash: Hash, recursive: bool) -> Bytes {
+    let routed = postcard::to_stdvec(&RoutedGet { hash, recursive }).expect("serializing to a vec");
+    [ROUTED_GET_PREFIX, &routed].concat().into()
+}
+
+/// The hash of a routed get request, `None` if `data` is not a routed get request.
+pub(crate) fn routed_get_hash(data: &[u8]) -> Option<Hash> {
+    let data = data.strip_prefix(ROUTED_GET_PREFIX)?;
+    postcard::from_bytes::<RoutedGet>(data)
+        .ok()
+        .map(|routed| routed.hash)
+}
+
+/// What to fetch from a cluster member for a routed get request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RoutedFetch {
+    pub hash: Hash,
+    /// Whether the hash is fetched as a collection, with all its children.
+    pub recursive: bool,
+    /// Whether the local node is not a designated replica of the hash, so the fetched
+    /// content is only kept for [`ROUTED_CACHE_TTL`].
+    pub temporary: bool,
+}
+
+/// Fetches a hash from a cluster member into the local store.
+pub(crate) type Fetch =
+    Arc<dyn Fn(ClusterMember, RoutedFetch) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// A custom get handler that answers routed get requests.
+///
+/// Content is fetched from the designated replicas other than the local node until one
+/// of them succeeds, then served from the local store. Other custom requests are passed
+/// on to the wrapped handler.
+#[derive(derive_more::Debug)]
+pub(crate) struct ClusterGetHandler {
+    local: PeerId,
+    cluster: Arc<Cluster>,
+    inner: Arc<dyn CustomGetHandler>,
+    #[debug("..")]
+    fetch: Fetch,
+}
+
+impl ClusterGetHandler {
+    pub(crate) fn new(
+        local: PeerId,
+        cluster: Arc<Cluster>,
+        inner: Arc<dyn CustomGetHandler>,
+        fetch: Fetch,
+    ) -> Self {
+        Self {
+            local,
+            cluster,
+            inner,
+            fetch,
+        }
+    }
+}
+
+impl CustomGetHandler for ClusterGetHandler {
+    fn handle(
+        &self,
+        token: Option<RequestToken>,
+        request: Bytes,
+    ) -> BoxFuture<'static, Result<GetRequest>> {
+        let Some(data) = request.strip_prefix(ROUTED_GET_PREFIX) else {
+            return self.inner.handle(token, request);
+        };
+        let RoutedGet { hash, recursive } = match postcard::from_bytes(data) {
+            Ok(routed) => routed,
+            Err(cause) => {
+                return futures::future::err(anyhow!("invalid routed get request: {cause}")).boxed()
+            }
+        };
+        let mut replicas = self.cluster.replicas(&hash);
+        let len = replicas.len();
+        replicas.retain(|member| member.peer != self.local);
+        let routed = RoutedFetch {
+            hash,
+            recursive,
+            temporary: replicas.len() == len,
+        };
+        let fetch = self.fetch.clone();
+        async move {
+            let mut result = Ok(());
+            for member in replicas {
+                let peer = member.peer;
+                result = fetch(member, routed)
+                    .await
+                    .with_context(|| format!("failed to fetch {hash} from {peer}"));
+                match &result {
+                    Ok(()) => break,
+                    Err(cause) => debug!("{:#}", cause),
+                }
+            }
+            result?;
+            Ok(if recursive {
+                GetRequest::all(hash)
+            } else {
+                GetRequest::single(hash)
+            })
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use iroh_net::tls::Keypair;
+
+    use super::*;
+
+    /// Answers every request with a get request for the request data as a hash.
+    #[derive(Debug)]
+    struct EchoHandler;
+
+    impl CustomGetHandler for EchoHandler {
+        fn handle(
+            &self,
+            _token: Option<RequestToken>,
+            request: Bytes,
+        ) -> BoxFuture<'static, Result<GetRequest>> {
+            futures::future::ok(GetRequest::single(Hash::new(request))).boxed()
+        }
+    }
+
+    fn member() -> ClusterMember {
+        ClusterMember {
+            peer: Keypair::generate().public().into(),
+            addrs: Vec::new(),
+            derp_region: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn cluster_get_handler() -> Result<()> {
+        let members = (0..4).map(|_| member()).collect::<Vec<_>>();
+        let cluster = Arc::new(Cluster::new(ClusterConfig {
+            members: members.clone(),
+            replicas: 2,
+            signed_fetches: false,
+        }));
+        // the peers asked, with the fetch, and which peers fail
+        let calls = Arc::new(Mutex::new(Vec::<(PeerId, RoutedFetch)>::new()));
+        let failing = Arc::new(Mutex::new(Vec::<PeerId>::new()));
+        let fetch: Fetch = {
+            let calls = calls.clone();
+            let failing = failing.clone();
+            Arc::new(move |member, routed| {
+                calls.lock().unwrap().push((member.peer, routed));
+                let failed = failing.lock().unwrap().contains(&member.peer);
+                async move {
+                    anyhow::ensure!(!failed, "unreachable");
+                    Ok(())
+                }
+                .boxed()
+            })
+        };
+        let hash = Hash::new(b"content");
+        let replicas = cluster
+            .replicas(&hash)
+            .into_iter()
+            .map(|m| m.peer)
+            .collect::<Vec<_>>();
+        let outsider = members
+            .iter()
+            .find(|m| !replicas.contains(&m.peer))
+            .unwrap()
+            .peer;
+        let handler =
+            ClusterGetHandler::new(outsider, cluster.clone(), Arc::new(EchoHandler), fetch);
+
+        // other custom requests go to the wrapped handler
+        let other = handler.handle(None, Bytes::from_static(b"other")).await?;
+        assert_eq!(other, GetRequest::single(Hash::new(b"other")));
+        assert!(calls.lock().unwrap().is_empty());
+
+        // a member that is not a replica fetches from the first replica, temporarily
+        let request = handler.handle(None, routed_get_request(hash, true)).await?;
+        assert_eq!(request, GetRequest::all(hash));
+        let routed = RoutedFetch {
+            hash,
+            recursive: true,
+            temporary: true,
+        };
+        assert_eq!(*calls.lock().unwrap(), vec![(replicas[0], routed)]);
+
+        // failed replicas are skipped, and it is an error if all fail
+        calls.lock().unwrap().clear();
+        failing.lock().unwrap().push(replicas[0]);
+        handler
+            .handle(None, routed_get_request(hash, false))
+            .await?;
+        failing.lock().unwrap().push(replicas[1]);
+        assert!(handler
+            .handle(None, routed_get_request(hash, false))
+            .await
+            .is_err());
+        let asked = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+        assert_eq!(asked, [replicas[0], replicas[1], replicas[0], replicas[1]]);
+
+        // a replica asks the other replica, and keeps the content
+        calls.lock().unwrap().clear();
+        failing.lock().unwrap().clear();
+        let fetch = handler.fetch.clone();
+        let handler = ClusterGetHandler::new(replicas[0], cluster, Arc::new(EchoHandler), fetch);
+        handler
+            .handle(None, routed_get_request(hash, false))
+            .await?;
+        let routed = RoutedFetch {
+            hash,
+            recursive: false,
+            temporary: false,
+        };
+        assert_eq!(*calls.lock().unwrap(), vec![(replicas[1], routed)]);
+
+        assert!(handler
+            .handle(None, [ROUTED_GET_PREFIX, b"garbage"].concat().into())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hash_ring_placement() {
+        let peers = (0..5)
+            .map(|_| Keypair::generate().public().into())
+            .collect::<Vec<PeerId>>();
+        let ring = HashRing::new(peers.clone());
+        let hashes = (0..1000u32)
+            .map(|i| Hash::new(i.to_le_bytes()))
+            .collect::<Vec<_>>();
+
+        for hash in &hashes {
+            let replicas = ring.replicas(hash, 3);
+            assert_eq!(replicas.len(), 3);
+            assert!(replicas.iter().all(|peer| peers.contains(peer)));
+            assert_ne!(replicas[0], replicas[1]);
+            assert_ne!(replicas[1], replicas[2]);
+            assert_ne!(replicas[0], replicas[2]);
+        }
+        // asking for more replicas than there are peers returns all peers
+        assert_eq!(ring.replicas(&hashes[0], 10).len(), 5);
+
+        // removing a peer only moves the hashes it was the first replica of
+        let removed = peers[0];
+        let smaller = HashRing::new(peers[1..].iter().copied());
+        for hash in &hashes {
+            let before = ring.replicas(hash, 1)[0];
+            let after = smaller.replicas(hash, 1)[0];
+            if before != removed {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn cluster_set_config() {
+        let members = (0..3).map(|_| member()).collect::<Vec<_>>();
+        let cluster = Cluster::new(ClusterConfig {
+            members: members.clone(),
+            replicas: 3,
+            signed_fetches: false,
+        });
+        let hash = Hash::new(b"content");
+        assert_eq!(cluster.replicas(&hash).len(), 3);
+        assert!(cluster.is_replica(&members[0].peer, &hash));
+
+        cluster.set_config(ClusterConfig {
+            members: members[1..].to_vec(),
+            replicas: 3,
+            signed_fetches: false,
+        });
+        let replicas = cluster.replicas(&hash);
+        assert_eq!(replicas.len(), 2);
+        assert!(!cluster.is_replica(&members[0].peer, &hash));
+        assert!(replicas.iter().all(|m| m.peer != members[0].peer));
+    }
+}
@@ -1,23 +1,41 @@
 //! The collection type used by iroh
-use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Component, Path};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, ensure, Context, Result};
 use futures::{
     future::{self, LocalBoxFuture},
     FutureExt,
 };
+use iroh_bytes::baomap::{Map, MapEntry, Store};
 use iroh_bytes::collection::{CollectionParser, CollectionStats, LinkStream};
 use iroh_bytes::Hash;
 use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
 use serde::{Deserialize, Serialize};
 
+/// The maximum size of a serialized [`Collection`].
+///
+/// Collections are loaded into memory as a whole, so they are limited to a size that is
+/// safe to load even when the collection comes from an untrusted provider.
+pub const MAX_COLLECTION_SIZE: usize = 256 * 1024 * 1024;
+
 /// A collection of blobs
 ///
 /// Note that the format is subject to change.
+///
+/// The [`Metadata`] of the entries is optional, and serialized after the collection, so
+/// readers that don't know about it can still read the collection.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Collection {
     /// Links to the blobs in this collection
     pub(crate) blobs: Vec<Blob>,
     /// The total size of the raw_data referred to by all links
     pub(crate) total_blobs_size: u64,
+    /// Metadata of the blobs, by name
+    #[serde(skip)]
+    pub(crate) metadata: BTreeMap<String, Metadata>,
 }
 
 impl Collection {
@@ -31,18 +49,48 @@ impl Collection {
         Ok(Self {
             blobs,
             total_blobs_size,
+            metadata: BTreeMap::new(),
         })
     }
 
+    /// Sets the metadata of the blobs, by name.
+    ///
+    /// Fails if there is metadata for a name that is not in the collection.
+    pub fn with_metadata(mut self, metadata: BTreeMap<String, Metadata>) -> Result<Self> {
+        for name in metadata.keys() {
+            ensure!(
+                self.blobs
+                    .binary_search_by(|blob| blob.name.as_str().cmp(name))
+                    .is_ok(),
+                "metadata for unknown blob {name:?}"
+            );
+        }
+        self.metadata = metadata;
+        Ok(self)
+    }
+
     /// Serialize this collection to a std `Vec<u8>`
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        Ok(postcard::to_stdvec(self)?)
+        let mut data = postcard::to_stdvec(self)?;
+        if !self.metadata.is_empty() {
+            data.extend(postcard::to_stdvec(&self.metadata)?);
+        }
+        Ok(data)
     }
 
     /// Deserialize a collection from a byte slice
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        let c: Collection =
-            postcard::from_bytes(data).context("failed to deserialize Collection data")?;
+        ensure!(
+            data.len() <= MAX_COLLECTION_SIZE,
+            "collection of {} bytes exceeds the maximum of {MAX_COLLECTION_SIZE}",
+            data.len()
+        );
+        let (mut c, rest): (Collection, _) =
+            postcard::take_from_bytes(data).context("failed to deserialize Collection data")?;
+        if !rest.is_empty() {
+            c.metadata =
+                postcard::from_bytes(rest).context("failed to deserialize Collection metadata")?;
+        }
         Ok(c)
     }
 
@@ -65,6 +113,229 @@ impl Collection {
     pub fn total_entries(&self) -> u64 {
         self.blobs.len() as u64
     }
+
+    /// Metadata of the blobs in this collection, by name
+    ///
+    /// Empty unless the collection was created with metadata.
+    pub fn metadata(&self) -> &BTreeMap<String, Metadata> {
+        &self.metadata
+    }
+
+    /// The entries that differ between this collection and `new`, matched by name and
+    /// ordered by name.
+    ///
+    /// Only the hashes are compared, so this does not need any of the blobs.
+    pub fn diff(&self, new: &Collection) -> Vec<EntryChange> {
+        let old_blobs: BTreeMap<&str, Hash> = self
+            .blobs
+            .iter()
+            .map(|blob| (blob.name.as_str(), blob.hash))
+            .collect();
+        let new_blobs: BTreeMap<&str, Hash> = new
+            .blobs
+            .iter()
+            .map(|blob| (blob.name.as_str(), blob.hash))
+            .collect();
+        let mut changes = Vec::new();
+        for (&name, &hash) in &old_blobs {
+            match new_blobs.get(name) {
+                None => changes.push(EntryChange::Removed {
+                    name: name.to_string(),
+                    hash,
+                }),
+                Some(&to) if to != hash => changes.push(EntryChange::Changed {
+                    name: name.to_string(),
+                    from: hash,
+                    to,
+                }),
+                Some(_) => {}
+            }
+        }
+        for (&name, &hash) in &new_blobs {
+            if !old_blobs.contains_key(name) {
+                changes.push(EntryChange::Added {
+                    name: name.to_string(),
+                    hash,
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.name().cmp(b.name()));
+        changes
+    }
+}
+
+/// Builds a new collection, usually a new version of an existing one.
+///
+/// Start from an existing collection in a store with [`CollectionBuilder::from_existing`],
+/// add, remove and rename entries, and [`store`](CollectionBuilder::store) the new
+/// version. Unchanged entries keep their hashes, so only the new entries and the
+/// collection blob itself have to be imported.
+#[derive(Clone, Debug, Default)]
+pub struct CollectionBuilder {
+    /// Hash and size of the entries, by name
+    entries: BTreeMap<String, (Hash, u64)>,
+    metadata: BTreeMap<String, Metadata>,
+}
+
+impl CollectionBuilder {
+    /// Starts an empty collection.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from the collection `hash` in `db`, including its metadata.
+    ///
+    /// The sizes of the entries are taken from `db`, so all entries have to be in there.
+    pub async fn from_existing<D: Map>(db: &D, hash: Hash) -> Result<Self> {
+        use iroh_io::AsyncSliceReaderExt;
+
+        let entry = db
+            .get(&hash)
+            .filter(|entry| entry.is_complete())
+            .with_context(|| format!("collection {hash} not found"))?;
+        let bytes = entry.data_reader().await?.read_to_end().await?;
+        let collection = Collection::from_bytes(&bytes)
+            .with_context(|| format!("{hash} is not a collection"))?;
+        let mut entries = BTreeMap::new();
+        for Blob { name, hash } in collection.blobs {
+            let size = match db.get(&hash) {
+                Some(entry) => entry.size(),
+                None => bail!("blob {hash} of entry {name:?} not found"),
+            };
+            entries.insert(name, (hash, size));
+        }
+        Ok(Self {
+            entries,
+            metadata: collection.metadata,
+        })
+    }
+
+    /// The hash of the entry `name`, if there is one.
+    pub fn get(&self, name: &str) -> Option<Hash> {
+        self.entries.get(name).map(|(hash, _)| *hash)
+    }
+
+    /// Adds the blob `hash` of `size` bytes as `name`.
+    ///
+    /// Replaces an existing entry of the same name, including its metadata, and returns
+    /// its hash.
+    pub fn insert(&mut self, name: impl Into<String>, hash: Hash, size: u64) -> Option<Hash> {
+        let name = name.into();
+        self.metadata.remove(&name);
+        self.entries
+            .insert(name, (hash, size))
+            .map(|(hash, _)| hash)
+    }
+
+    /// Sets the metadata of the entry `name`.
+    pub fn set_metadata(&mut self, name: &str, metadata: Metadata) -> Result<()> {
+        ensure!(self.entries.contains_key(name), "no entry {name:?}");
+        self.metadata.insert(name.to_string(), metadata);
+        Ok(())
+    }
+
+    /// Removes the entry `name`, returning its hash.
+    pub fn remove(&mut self, name: &str) -> Option<Hash> {
+        self.metadata.remove(name);
+        self.entries.remove(name).map(|(hash, _)| hash)
+    }
+
+    /// Renames the entry `from` to `to`, keeping its metadata.
+    ///
+    /// Fails if there is no entry `from`, or there already is an entry `to`.
+    pub fn rename(&mut self, from: &str, to: impl Into<String>) -> Result<()> {
+        let to = to.into();
+        ensure!(
+            !self.entries.contains_key(&to),
+            "entry {to:?} already exists"
+        );
+        let entry = self
+            .entries
+            .remove(from)
+            .with_context(|| format!("no entry {from:?}"))?;
+        if let Some(metadata) = self.metadata.remove(from) {
+            self.metadata.insert(to.clone(), metadata);
+        }
+        self.entries.insert(to, entry);
+        Ok(())
+    }
+
+    /// Builds the collection.
+    pub fn build(self) -> Result<Collection> {
+        let total_blobs_size = self.entries.values().map(|(_, size)| size).sum();
+        let blobs = self
+            .entries
+            .into_iter()
+            .map(|(name, (hash, _))| Blob { name, hash })
+            .collect();
+        Collection::new(blobs, total_blobs_size)?.with_metadata(self.metadata)
+    }
+
+    /// Builds the collection and imports it into `db`, returning its hash.
+    ///
+    /// The blobs of the entries are not checked, they are expected to be in `db` already.
+    pub async fn store<D: Store>(self, db: &D) -> Result<(Hash, Collection)> {
+        let collection = self.build()?;
+        let hash = db.import_bytes(collection.to_bytes()?.into()).await?;
+        Ok((hash, collection))
+    }
+}
+
+/// An entry that differs between two collections, see [`Collection::diff`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryChange {
+    /// The entry is only in the new collection
+    Added {
+        /// The name of the entry
+        name: String,
+        /// The hash of the entry in the new collection
+        hash: Hash,
+    },
+    /// The entry is only in the old collection
+    Removed {
+        /// The name of the entry
+        name: String,
+        /// The hash of the entry in the old collection
+        hash: Hash,
+    },
+    /// The entry has a different hash in the new collection
+    Changed {
+        /// The name of the entry
+        name: String,
+        /// The hash of the entry in the old collection
+        from: Hash,
+        /// The hash of the entry in the new collection
+        to: Hash,
+    },
+}
+
+impl EntryChange {
+    /// The name of the entry.
+    pub fn name(&self) -> &str {
+        match self {
+            EntryChange::Added { name, .. }
+            | EntryChange::Removed { name, .. }
+            | EntryChange::Changed { name, .. } => name,
+        }
+    }
+
+    /// The hash of the entry in the old collection, if it is in there.
+    pub fn from(&self) -> Option<Hash> {
+        match self {
+            EntryChange::Added { .. } => None,
+            EntryChange::Removed { hash, .. } => Some(*hash),
+            EntryChange::Changed { from, .. } => Some(*from),
+        }
+    }
+
+    /// The hash of the entry in the new collection, if it is in there.
+    pub fn to(&self) -> Option<Hash> {
+        match self {
+            EntryChange::Added { hash, .. } => Some(*hash),
+            EntryChange::Removed { .. } => None,
+            EntryChange::Changed { to, .. } => Some(*to),
+        }
+    }
 }
 
 /// A blob entry of a collection
@@ -76,10 +347,122 @@ pub struct Blob {
     pub hash: Hash,
 }
 
+/// File system metadata of a collection entry
+///
+/// This preserves what is needed to recreate a directory tree like tar does, apart from
+/// ownership.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The target if the entry is a symlink
+    ///
+    /// The blob of a symlink contains the target as well, so readers that ignore metadata
+    /// get a file containing the target.
+    pub symlink: Option<String>,
+    /// Whether the file is executable
+    pub executable: bool,
+    /// Modification time, in seconds since the unix epoch
+    pub mtime: Option<u64>,
+}
+
+impl Metadata {
+    /// Reads the metadata of the file or symlink at `path`, without following symlinks.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let meta = std::fs::symlink_metadata(path)?;
+        let symlink = if meta.file_type().is_symlink() {
+            let target = std::fs::read_link(path)?.into_os_string();
+            let target = target.into_string().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "symlink target is not unicode")
+            })?;
+            Some(target)
+        } else {
+            None
+        };
+        #[cfg(unix)]
+        let executable = {
+            use std::os::unix::fs::PermissionsExt;
+            symlink.is_none() && meta.permissions().mode() & 0o111 != 0
+        };
+        #[cfg(not(unix))]
+        let executable = false;
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+        Ok(Self {
+            symlink,
+            executable,
+            mtime,
+        })
+    }
+
+    /// Applies the metadata to the blob exported to `path`, within the export directory
+    /// `root`.
+    ///
+    /// A symlink replaces the file containing its target. Symlinks should be applied after
+    /// all files are exported, so that no file is written through them. The metadata of a
+    /// downloaded collection can not be trusted, so a symlink target that is absolute or
+    /// points outside of `root` is an error. On platforms without symlinks or an executable
+    /// bit those are ignored.
+    pub fn apply(&self, root: &Path, path: &Path) -> io::Result<()> {
+        if let Some(target) = &self.symlink {
+            check_symlink_target(root, path, target)?;
+            #[cfg(unix)]
+            {
+                std::fs::remove_file(path)?;
+                std::os::unix::fs::symlink(target, path)?;
+            }
+            #[cfg(not(unix))]
+            let _ = target;
+            return Ok(());
+        }
+        #[cfg(unix)]
+        if self.executable {
+            use std::os::unix::fs::PermissionsExt;
+            let mut permissions = std::fs::metadata(path)?.permissions();
+            permissions.set_mode(permissions.mode() | 0o111);
+            std::fs::set_permissions(path, permissions)?;
+        }
+        if let Some(mtime) = self.mtime {
+            crate::util::fs::set_mtime(path, mtime)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the symlink `link` within `root` resolves to a path within `root`.
+///
+/// The check is lexical, symlinks are only created once all files are exported, and each
+/// of them is checked, so none of the components of the target can be a symlink that
+/// leaves `root`.
+fn check_symlink_target(root: &Path, link: &Path, target: &str) -> io::Result<()> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("symlink target {target} is outside of the export directory"),
+        )
+    };
+    let dir = link
+        .parent()
+        .and_then(|dir| dir.strip_prefix(root).ok())
+        .ok_or_else(invalid)?;
+    let mut depth = dir.components().count();
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => depth = depth.checked_sub(1).ok_or_else(invalid)?,
+            Component::RootDir | Component::Prefix(_) => return Err(invalid()),
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use bao_tree::blake3;
+    use proptest::prelude::*;
 
     #[test]
     fn roundtrip_blob() {
@@ -97,6 +480,189 @@ mod tests {
         let deserialize_b: Blob = postcard::from_bytes(&buf).unwrap();
         assert_eq!(b, deserialize_b);
     }
+
+    #[test]
+    fn collection_diff() {
+        let blob = |name: &str, hash: u8| Blob {
+            name: name.to_string(),
+            hash: [hash; 32].into(),
+        };
+        let old = Collection::new(vec![blob("a", 1), blob("b", 2), blob("c", 3)], 0).unwrap();
+        let new = Collection::new(vec![blob("b", 2), blob("c", 4), blob("d", 5)], 0).unwrap();
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                EntryChange::Removed {
+                    name: "a".to_string(),
+                    hash: [1; 32].into(),
+                },
+                EntryChange::Changed {
+                    name: "c".to_string(),
+                    from: [3; 32].into(),
+                    to: [4; 32].into(),
+                },
+                EntryChange::Added {
+                    name: "d".to_string(),
+                    hash: [5; 32].into(),
+                },
+            ]
+        );
+        assert!(old.diff(&old).is_empty());
+        let reverse = new.diff(&old);
+        assert_eq!(reverse[1].from(), Some([4; 32].into()));
+        assert_eq!(reverse[1].to(), Some([3; 32].into()));
+    }
+
+    #[cfg(feature = "mem-db")]
+    #[tokio::test]
+    async fn collection_builder() {
+        let db = crate::baomap::mem::Store::new(tokio::runtime::Handle::current());
+        let mut hashes = Vec::new();
+        for data in [&b"a"[..], b"bb", b"ccc"] {
+            hashes.push(db.import_bytes(data.to_vec().into()).await.unwrap());
+        }
+        let mut builder = CollectionBuilder::new();
+        builder.insert("a", hashes[0], 1);
+        builder.insert("b", hashes[1], 2);
+        builder
+            .set_metadata(
+                "b",
+                Metadata {
+                    executable: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let (hash, v1) = builder.store(&db).await.unwrap();
+        assert_eq!(v1.total_blobs_size(), 3);
+
+        let mut builder = CollectionBuilder::from_existing(&db, hash).await.unwrap();
+        assert_eq!(builder.get("a"), Some(hashes[0]));
+        assert_eq!(builder.remove("a"), Some(hashes[0]));
+        builder.rename("b", "bin/b").unwrap();
+        assert!(builder.rename("missing", "other").is_err());
+        assert_eq!(builder.insert("c", hashes[2], 3), None);
+        let (_, v2) = builder.store(&db).await.unwrap();
+        assert_eq!(v2.total_blobs_size(), 5);
+        assert!(v2.metadata()["bin/b"].executable);
+        let changes = v1.diff(&v2);
+        assert_eq!(changes.len(), 4);
+        assert!(changes
+            .iter()
+            .all(|change| !matches!(change, EntryChange::Changed { .. })));
+    }
+
+    #[test]
+    fn collection_limit() {
+        assert!(Collection::from_bytes(&vec![0u8; MAX_COLLECTION_SIZE + 1]).is_err());
+    }
+
+    fn blob() -> impl Strategy<Value = Blob> {
+        (".*", any::<[u8; 32]>()).prop_map(|(name, hash)| Blob {
+            name,
+            hash: hash.into(),
+        })
+    }
+
+    fn metadata() -> impl Strategy<Value = Metadata> {
+        (
+            proptest::option::of(".*"),
+            any::<bool>(),
+            any::<Option<u64>>(),
+        )
+            .prop_map(|(symlink, executable, mtime)| Metadata {
+                symlink,
+                executable,
+                mtime,
+            })
+    }
+
+    #[test]
+    fn metadata_compat() {
+        let blobs = vec![Blob {
+            name: "run.sh".to_string(),
+            hash: [0u8; 32].into(),
+        }];
+        let plain = Collection::new(blobs, 0).unwrap();
+        let metadata = BTreeMap::from([("run.sh".to_string(), Metadata::default())]);
+        let with_metadata = plain.clone().with_metadata(metadata).unwrap();
+        let bytes = with_metadata.to_bytes().unwrap();
+        assert!(bytes.starts_with(&plain.to_bytes().unwrap()));
+        assert_eq!(Collection::from_bytes(&bytes).unwrap(), with_metadata);
+        // readers that don't know about metadata ignore it
+        let old: (Vec<Blob>, u64) = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(old.0, plain.blobs);
+
+        let unknown = BTreeMap::from([("other".to_string(), Metadata::default())]);
+        assert!(plain.with_metadata(unknown).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn metadata_roundtrip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("run.sh");
+        std::fs::write(&file, b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("run.sh", &link).unwrap();
+        let file_meta = Metadata::read(&file).unwrap();
+        assert!(file_meta.executable);
+        assert!(file_meta.mtime.is_some());
+        let link_meta = Metadata::read(&link).unwrap();
+        assert_eq!(link_meta.symlink.as_deref(), Some("run.sh"));
+
+        let out = dir.path().join("out");
+        std::fs::create_dir(&out).unwrap();
+        std::fs::write(out.join("run.sh"), b"#!/bin/sh").unwrap();
+        std::fs::write(out.join("link"), b"run.sh").unwrap();
+        file_meta.apply(&out, &out.join("run.sh")).unwrap();
+        link_meta.apply(&out, &out.join("link")).unwrap();
+        assert_eq!(Metadata::read(&out.join("run.sh")).unwrap(), file_meta);
+        assert_eq!(
+            std::fs::read_link(out.join("link")).unwrap(),
+            Path::new("run.sh")
+        );
+    }
+
+    #[test]
+    fn symlink_targets_stay_in_export_dir() {
+        let root = Path::new("out");
+        let check =
+            |link: &str, target: &str| check_symlink_target(root, &root.join(link), target).is_ok();
+        assert!(check("link", "run.sh"));
+        assert!(check("bin/link", "../run.sh"));
+        assert!(check("bin/link", "./../lib/./x"));
+        assert!(!check("link", "../run.sh"));
+        assert!(!check("bin/link", "../../run.sh"));
+        assert!(!check("bin/link", "../x/../../run.sh"));
+        assert!(!check("link", "/etc/passwd"));
+    }
+
+    proptest! {
+        #[test]
+        fn collection_roundtrip(
+            blobs in proptest::collection::vec(blob(), 0..16),
+            total_blobs_size in any::<u64>(),
+            metadata in proptest::collection::btree_map(".*", metadata(), 0..4),
+        ) {
+            let collection = Collection {
+                blobs,
+                total_blobs_size,
+                metadata,
+            };
+            let back = Collection::from_bytes(&collection.to_bytes().unwrap()).unwrap();
+            prop_assert_eq!(back, collection);
+        }
+
+        #[test]
+        fn collection_from_arbitrary_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+            // must not panic
+            Collection::from_bytes(&bytes).ok();
+        }
+    }
 }
 
 /// Parser for the current iroh default collections
@@ -156,6 +722,11 @@ impl CollectionParser for IrohCollectionParser {
         mut reader: R,
     ) -> LocalBoxFuture<'a, anyhow::Result<(Box<dyn LinkStream>, CollectionStats)>> {
         async move {
+            let len = reader.len().await?;
+            ensure!(
+                len <= MAX_COLLECTION_SIZE as u64,
+                "collection of {len} bytes exceeds the maximum of {MAX_COLLECTION_SIZE}"
+            );
             // read to end
             let data = reader.read_to_end().await?;
             // parse the collection and just take the hashes
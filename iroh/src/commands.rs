@@ -1,33 +1,53 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
-use std::{net::SocketAddr, path::PathBuf};
+use std::time::{Duration, SystemTime};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use clap::{Parser, Subcommand};
 use futures::StreamExt;
-use iroh::dial::Ticket;
+use indicatif::{HumanBytes, HumanDuration};
+use iroh::baomap::outboard::OutboardFormat;
+use iroh::client::{Iroh, LocalRpcConnection, QuinnRpcConnection, DEFAULT_RPC_PORT};
+use iroh::dial::{Ticket, TicketOptions};
 use iroh::rpc_protocol::*;
-use iroh_bytes::{protocol::RequestToken, util::runtime, Hash};
+use iroh::util::{
+    checksum::ChecksumAlgorithm,
+    download_queue::DownloadPriority,
+    expiring_token,
+    retry::{ErrorClass, RetryPolicy},
+};
+use iroh_bytes::{
+    keyed::HashDomain,
+    protocol::{ListRequest, RequestToken},
+    util::runtime,
+    Hash,
+};
+use iroh_net::magic_endpoint::qlog::QlogConfig;
 use iroh_net::tls::{Keypair, PeerId};
-use quic_rpc::transport::quinn::QuinnConnection;
+use quic_rpc::transport::combined::CombinedConnection;
 use quic_rpc::RpcClient;
 
 use crate::config::Config;
 
 use self::provide::{ProvideOptions, ProviderRpcPort};
 
-const DEFAULT_RPC_PORT: u16 = 0x1337;
-const RPC_ALPN: [u8; 17] = *b"n0/provider-rpc/1";
-const MAX_RPC_CONNECTIONS: u32 = 16;
-const MAX_RPC_STREAMS: u64 = 1024;
-
 pub mod add;
+pub mod admin;
+pub mod blob;
+pub mod config;
 pub mod doctor;
 pub mod get;
+pub mod introspect;
 pub mod list;
+pub mod migrate;
+pub mod peers;
+pub mod progress;
 pub mod provide;
+pub mod repl;
+pub mod seed;
+pub mod status;
 pub mod validate;
 
 /// Send data.
@@ -54,12 +74,50 @@ pub struct Cli {
     #[cfg(feature = "metrics")]
     #[clap(long)]
     pub metrics_addr: Option<SocketAddr>,
+    /// Path to an additional config file, which takes precedence over the default one.
     #[clap(long)]
     pub cfg: Option<PathBuf>,
+    /// The data directory, takes precedence over `IROH_DATA_DIR` and the config.
+    #[clap(long)]
+    pub data_dir: Option<PathBuf>,
+    /// The cache directory, takes precedence over `IROH_CACHE_DIR` and the config.
+    #[clap(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// Use RPC over this Unix domain socket, or named pipe on Windows, instead of the RPC
+    /// port. Takes precedence over `IROH_RPC_SOCKET` and the config.
+    ///
+    /// The provider listens on the socket in addition to the RPC port.
+    #[clap(long)]
+    pub rpc_socket: Option<PathBuf>,
+    /// Write qlog traces of QUIC connections into this directory.
+    ///
+    /// The provider traces the connections it opens, `get` and `ls` the connection to the
+    /// provider.
+    #[clap(long)]
+    pub qlog_dir: Option<PathBuf>,
+    /// Only trace connections with this peer, can be given multiple times.
+    #[clap(long, requires = "qlog_dir")]
+    pub qlog_peer: Vec<PeerId>,
+    /// Only trace connections using this ALPN, can be given multiple times.
+    #[clap(long, requires = "qlog_dir")]
+    pub qlog_alpn: Vec<String>,
 }
 
 impl Cli {
+    /// The qlog config from the `--qlog-*` flags, if enabled.
+    fn qlog(&self) -> Option<QlogConfig> {
+        let mut qlog = QlogConfig::new(self.qlog_dir.clone()?);
+        for peer in &self.qlog_peer {
+            qlog = qlog.peer(*peer);
+        }
+        for alpn in &self.qlog_alpn {
+            qlog = qlog.alpn(alpn.as_bytes());
+        }
+        Some(qlog)
+    }
+
     pub async fn run(self, rt: &runtime::Handle, config: &Config) -> Result<()> {
+        let qlog = self.qlog();
         match self.command {
             Commands::Share {
                 hash,
@@ -72,6 +130,11 @@ impl Cli {
                 derp_region,
                 mut out,
                 stable: in_place,
+                checksum,
+                metadata,
+                retries,
+                limits,
+                priority,
             } => {
                 if let Some(out) = out.as_mut() {
                     tracing::info!("canonicalizing output path");
@@ -79,8 +142,9 @@ impl Cli {
                     tracing::info!("output path is {} -> {}", out.display(), absolute.display());
                     *out = absolute;
                 }
-                let client = make_rpc_client(rpc_port).await?;
-                let (peer, addr, token, derp_region, hash, recursive) =
+                let client = make_rpc_client(rpc_port, config.rpc_socket.as_deref()).await?;
+                let domain = ticket.as_ref().map(Ticket::domain).unwrap_or_default();
+                let (peer, addr, token, derp_region, hash, recursive, alternatives) =
                     if let Some(ticket) = ticket.as_ref() {
                         (
                             ticket.peer(),
@@ -89,6 +153,7 @@ impl Cli {
                             ticket.derp_region(),
                             ticket.hash(),
                             ticket.recursive(),
+                            ticket.providers()[1..].to_vec(),
                         )
                     } else {
                         (
@@ -98,6 +163,7 @@ impl Cli {
                             derp_region,
                             hash.unwrap(),
                             recursive.unwrap_or_default(),
+                            Vec::new(),
                         )
                     };
                 let mut stream = client
@@ -107,9 +173,18 @@ impl Cli {
                         peer,
                         addrs: addr,
                         derp_region,
+                        alternatives,
                         token: token.cloned(),
+                        domain,
                         out: out.map(|x| x.display().to_string()),
                         in_place,
+                        checksums: checksum,
+                        metadata,
+                        retry: retries
+                            .map(|retries| RetryPolicy::default().with_retries(retries))
+                            .unwrap_or_default(),
+                        limits: limits.into(),
+                        priority,
                     })
                     .await?;
                 while let Some(item) = stream.next().await {
@@ -127,14 +202,30 @@ impl Cli {
                 token,
                 out,
                 single,
+                metadata,
+                limits,
             } => {
                 let get = if let Some(ticket) = ticket {
+                    let mut opts = ticket.as_get_options(Keypair::generate(), config.derp_map());
+                    // fill in dialing info left out of the ticket
+                    if opts.addrs.is_empty() {
+                        opts.addrs = addrs;
+                    }
+                    if opts.derp_region.is_none() {
+                        opts.derp_region = region;
+                    }
+                    opts.qlog = qlog;
+                    opts.dns_resolver = config.dns_resolver()?;
                     self::get::GetInteractive {
                         rt: rt.clone(),
                         hash: ticket.hash(),
-                        opts: ticket.as_get_options(Keypair::generate(), config.derp_map()),
+                        opts,
+                        alternatives: ticket.providers()[1..].to_vec(),
                         token: ticket.token().cloned(),
+                        domain: ticket.domain(),
                         single: !ticket.recursive(),
+                        metadata,
+                        limits: limits.into(),
                     }
                 } else if let (Some(peer), Some(hash)) = (peer, hash) {
                     self::get::GetInteractive {
@@ -147,28 +238,85 @@ impl Cli {
                             derp_region: region,
                             derp_map: config.derp_map(),
                             keypair: Keypair::generate(),
+                            qlog,
+                            dns_resolver: config.dns_resolver()?,
                         },
+                        alternatives: Vec::new(),
                         token,
+                        domain: HashDomain::Plain,
                         single,
+                        metadata,
+                        limits: limits.into(),
                     }
                 } else {
                     anyhow::bail!("Either ticket or hash and peer must be specified")
                 };
+                let token = get.token.clone();
                 tokio::select! {
                     biased;
-                    res = get.get_interactive(out) => res,
+                    res = get.get_interactive(out) => {
+                        res.map_err(|err| explain_expired(err, token.as_ref()))
+                    }
                     _ = tokio::signal::ctrl_c() => {
                         println!("Ending transfer early...");
                         Ok(())
                     }
                 }
             }
+            Commands::Ls {
+                peer,
+                addrs,
+                token,
+                region,
+            } => {
+                let opts = iroh::dial::Options {
+                    addrs,
+                    peer_id: peer,
+                    keylog: self.keylog,
+                    derp_region: region,
+                    derp_map: config.derp_map(),
+                    keypair: Keypair::generate(),
+                    qlog,
+                    dns_resolver: config.dns_resolver()?,
+                };
+                let connection = iroh::dial::dial(opts).await?;
+                let request = ListRequest {
+                    token: token.clone(),
+                };
+                let response = iroh_bytes::get::list(&connection, request)
+                    .await
+                    .map_err(|err| explain_expired(err.into(), token.as_ref()))?;
+                for entry in response.entries {
+                    let kind = if entry.collection {
+                        "collection"
+                    } else {
+                        "blob"
+                    };
+                    println!(
+                        "{} {} {} {}",
+                        entry.name.as_deref().unwrap_or("-"),
+                        kind,
+                        HumanBytes(entry.size),
+                        entry.hash
+                    );
+                }
+                Ok(())
+            }
             Commands::Provide {
                 path,
                 addr,
                 rpc_port,
                 request_token,
+                ticket_ttl,
+                clock_skew,
                 in_place,
+                filter,
+                manifest,
+                ticket_info,
+                serve_partial,
+                serve_listing,
+                keyed,
+                outboard_format,
             } => {
                 let request_token = match request_token {
                     Some(RequestTokenOptions::Random) => Some(RequestToken::generate()),
@@ -179,25 +327,72 @@ impl Cli {
                     rt,
                     path,
                     in_place,
+                    filter.into(),
+                    manifest,
                     ProvideOptions {
                         addr,
                         rpc_port,
                         keylog: self.keylog,
                         request_token,
+                        ticket_ttl: ticket_ttl.map(Duration::from_secs),
+                        clock_skew: Duration::from_secs(clock_skew),
                         derp_map: config.derp_map(),
+                        watermarks: config.watermarks(),
+                        validation: config.validation_schedule(),
+                        memory_limits: config.memory_limits(),
+                        log_filter: crate::logging::filter_handler(),
+                        fsync_policy: config.fsync_policy,
+                        io_backend: config.io_backend,
+                        outboard_format: outboard_format.unwrap_or(config.outboard_format),
+                        file_handles: config.file_handle_limits(),
+                        coalesce_reads: config.coalesce_reads,
+                        chunk_cache_bytes: config.chunk_cache_bytes,
+                        hash_threads: config.hash_threads,
+                        trash_retention: config.trash_retention(),
+                        encrypt_store: config.encrypt_store,
+                        mirrors: config.mirrors()?,
+                        cluster: config.cluster()?,
+                        ticket_options: ticket_info.into(),
+                        serve_partial,
+                        serve_listing,
+                        keyed,
+                        paths: config.paths()?,
+                        rpc_socket: config.rpc_socket.clone(),
+                        qlog,
+                        dns_resolver: config.dns_resolver()?,
                     },
                 )
                 .await
             }
             Commands::List(cmd) => cmd.run().await,
+            Commands::Blob(cmd) => cmd.run(config).await,
+            Commands::Peers(cmd) => cmd.run().await,
+            Commands::Admin(cmd) => cmd.run().await,
             Commands::Validate { rpc_port, repair } => self::validate::run(rpc_port, repair).await,
             Commands::Shutdown { force, rpc_port } => {
-                let client = make_rpc_client(rpc_port).await?;
+                let client = make_rpc_client(rpc_port, config.rpc_socket.as_deref()).await?;
                 client.rpc(ShutdownRequest { force }).await?;
                 Ok(())
             }
+            Commands::Status { rpc_port } => self::status::run(rpc_port).await,
+            Commands::Events { rpc_port, json } => {
+                let client = make_rpc_client(rpc_port, config.rpc_socket.as_deref()).await?;
+                let mut events = client.server_streaming(SubscribeRequest).await?;
+                while let Some(event) = events.next().await {
+                    let event = event?;
+                    if json {
+                        println!("{}", serde_json::to_string(&event)?);
+                    } else {
+                        println!("{event:?}");
+                    }
+                }
+                Ok(())
+            }
+            Commands::Migrate { dry_run, backup } => {
+                self::migrate::run(config, dry_run, backup).await
+            }
             Commands::Id { rpc_port } => {
-                let client = make_rpc_client(rpc_port).await?;
+                let client = make_rpc_client(rpc_port, config.rpc_socket.as_deref()).await?;
                 let response = client.rpc(IdRequest).await?;
 
                 println!("Listening address: {:#?}", response.listen_addrs);
@@ -208,20 +403,48 @@ impl Cli {
                 path,
                 rpc_port,
                 in_place,
-            } => self::add::run(path, in_place, rpc_port).await,
+                metadata,
+                filter,
+            } => self::add::run(path, in_place, metadata, filter.into(), rpc_port).await,
             Commands::Addresses { rpc_port } => {
-                let client = make_rpc_client(rpc_port).await?;
+                let client = make_rpc_client(rpc_port, config.rpc_socket.as_deref()).await?;
                 let response = client.rpc(AddrsRequest).await?;
                 println!("Listening addresses: {:?}", response.addrs);
                 Ok(())
             }
+            Commands::PeerScores { rpc_port } => {
+                let client = make_rpc_client(rpc_port, config.rpc_socket.as_deref()).await?;
+                let response = client.rpc(PeerScoresRequest).await?;
+                for (peer, score) in response.scores {
+                    println!(
+                        "{}: score {}, {} successes, {} verification failures",
+                        peer, score.score, score.successes, score.verification_failures
+                    );
+                    for q in score.quarantined {
+                        println!("  quarantined {} in {}: {:?}", q.hash, q.root, q.ranges);
+                    }
+                }
+                Ok(())
+            }
+            Commands::Config(cmd) => cmd.run(config, self.cfg.as_deref()),
             Commands::Doctor { command } => self::doctor::run(command, config).await,
+            Commands::Console { rpc_port, script } => {
+                self::repl::run(rpc_port, script, rt, config).await
+            }
+            Commands::Completions { shell } => {
+                self::introspect::completions(shell);
+                Ok(())
+            }
+            Commands::CliSchema { json } => self::introspect::schema(json),
         }
     }
 }
 #[derive(Subcommand, Debug, Clone)]
 #[allow(clippy::large_enum_variant)]
 pub enum Commands {
+    /// Check and print the configuration.
+    #[clap(subcommand)]
+    Config(self::config::Commands),
     /// Diagnostic commands for the derp relay protocol.
     Doctor {
         /// Commands for doctor - defined in the mod
@@ -242,6 +465,8 @@ pub enum Commands {
         /// will not change.
         #[clap(long, default_value_t = false)]
         in_place: bool,
+        #[clap(flatten)]
+        filter: self::add::FilterArgs,
         #[clap(long, short)]
         /// Listening address to bind to
         #[clap(long, short, default_value_t = SocketAddr::from(iroh::node::DEFAULT_BIND_ADDR))]
@@ -254,10 +479,69 @@ pub enum Commands {
         /// Pass "random" to generate a random token, or base32-encoded bytes to use as a token
         #[clap(long)]
         request_token: Option<RequestTokenOptions>,
+        /// Make the printed tickets expire after this many seconds
+        ///
+        /// The tickets carry a token signed by the provider, requests with an expired token
+        /// are refused.
+        #[clap(long, conflicts_with = "request_token")]
+        ticket_ttl: Option<u64>,
+        /// Seconds an expired ticket is still accepted, to tolerate clocks that are off
+        #[clap(
+            long,
+            requires = "ticket_ttl",
+            default_value_t = expiring_token::DEFAULT_CLOCK_SKEW.as_secs()
+        )]
+        clock_skew: u64,
+        /// Path to a manifest of content to serve
+        ///
+        /// All entries of the manifest are imported and pinned when the provider starts. The
+        /// provider stops if content listed by hash is not in the database.
+        #[clap(long)]
+        manifest: Option<PathBuf>,
+        /// Dialing info to embed in the printed tickets
+        ///
+        /// "all" embeds the DERP region and the direct addresses, "derp" only the DERP
+        /// region and "peer" only the peer id.  Smaller tickets reveal less about the
+        /// network location of the provider, but take longer to dial.
+        #[clap(long, default_value_t = TicketInfoOptions::All)]
+        ticket_info: TicketInfoOptions,
+        /// Serve blobs that are still being downloaded
+        ///
+        /// Other peers requesting a blob that is being fetched get the ranges that have
+        /// already been verified, so popular downloads are shared between all downloaders.
+        #[clap(long, default_value_t = false)]
+        serve_partial: bool,
+        /// Let peers list the collections and pinned blobs of the provider
+        ///
+        /// Everyone who may fetch data can then see all content of the provider, combine
+        /// this with --request-token for private groups.
+        #[clap(long, default_value_t = false)]
+        serve_listing: bool,
+        /// Serve the content under names in a keyed namespace instead of its hashes
+        ///
+        /// Tickets contain the names, and requests for plain hashes are refused, so content
+        /// can only be fetched with a ticket. The key is kept in the data directory.
+        #[clap(long, default_value_t = false)]
+        keyed: bool,
+        /// How the store writes complete outboards: "pre-order" or "post-order"
+        ///
+        /// Overrides the outboard_format of the config. Existing outboards are kept in the
+        /// format they were written in.
+        #[clap(long)]
+        outboard_format: Option<OutboardFormat>,
     },
     /// List availble content on the provider.
     #[clap(subcommand)]
     List(self::list::Commands),
+    /// Import and export blobs in other formats.
+    #[clap(subcommand)]
+    Blob(self::blob::Commands),
+    /// Inspect and manage the peers of the running provider.
+    #[clap(subcommand)]
+    Peers(self::peers::Commands),
+    /// Operate the running provider.
+    #[clap(subcommand)]
+    Admin(self::admin::Commands),
     /// Validate hashes on the running provider.
     Validate {
         /// RPC port of the provider
@@ -279,12 +563,70 @@ pub enum Commands {
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
     },
+    /// Show the status of the running provider.
+    ///
+    /// Shows the addresses, home DERP region, NAT report, known peers and store size.
+    Status {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Print the events of the running provider as they happen.
+    ///
+    /// Prints added blobs, connected peers, served requests and the progress of downloads
+    /// until the provider shuts down.
+    Events {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+        /// Print every event as a line of JSON.
+        #[clap(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Open an interactive console for the running provider.
+    ///
+    /// Any iroh command talking to the provider can be typed into the console, without
+    /// the `iroh` prefix. Commands use the RPC port of the console unless given another
+    /// one. The console keeps a history and completes commands and flags.
+    Console {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+        /// Run the commands read from stdin, stopping at the first command that fails.
+        #[clap(long)]
+        script: bool,
+    },
+    /// Print the completion script for a shell.
+    ///
+    /// For example, `iroh completions bash > /etc/bash_completion.d/iroh`.
+    Completions {
+        /// The shell to generate completions for
+        shell: clap_complete::Shell,
+    },
+    /// Print all commands with their arguments.
+    CliSchema {
+        /// Print the commands as JSON, for tools wrapping the command line.
+        #[clap(long)]
+        json: bool,
+    },
     /// Identify the running provider.
     Id {
         /// RPC port of the provider
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
     },
+    /// Upgrade the data directory to the layout of this release.
+    ///
+    /// The provider does this on start, this allows to check what would change first. The
+    /// provider must not be running.
+    Migrate {
+        /// Print the migrations that would run, without changing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Back up the whole data directory, including the blob store, before migrating it
+        #[clap(long)]
+        backup: bool,
+    },
     /// Add data from PATH to the running provider's database.
     Add {
         /// The path to the file or folder to add
@@ -295,6 +637,13 @@ pub enum Commands {
         /// will not change.
         #[clap(long, default_value_t = false)]
         in_place: bool,
+        /// Keep symlinks, the executable bit and modification times
+        ///
+        /// They are restored when the collection is exported.
+        #[clap(long, default_value_t = false)]
+        metadata: bool,
+        #[clap(flatten)]
+        filter: self::add::FilterArgs,
         /// RPC port
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
@@ -313,12 +662,17 @@ pub enum Commands {
         )]
         peer: Option<PeerId>,
         /// Addresses of the provider
+        ///
+        /// Used with a ticket only if the ticket does not contain any addresses.
         #[clap(long, short)]
         addrs: Vec<SocketAddr>,
         /// base32-encoded Request token to use for authentication, if any
         #[clap(long)]
         token: Option<RequestToken>,
         /// DERP region of the provider
+        ///
+        /// Used with a ticket only if the ticket does not contain a DERP region.  Without any
+        /// addresses or DERP region the provider is looked up in all DERP regions.
         #[clap(long)]
         region: Option<u16>,
         /// Directory in which to save the file(s), defaults to writing to STDOUT
@@ -330,13 +684,36 @@ pub enum Commands {
         /// in the directory will be left untouched.
         #[clap(long, short)]
         out: Option<PathBuf>,
-        #[clap(conflicts_with_all = &["hash", "peer", "addrs", "token"])]
+        #[clap(conflicts_with_all = &["hash", "peer", "token"])]
         /// Ticket containing everything to retrieve the data from a provider.
         #[clap(long)]
         ticket: Option<Ticket>,
         /// True to download a single blob, false (default) to download a collection and its children.
         #[clap(long, default_value_t = false)]
         single: bool,
+        /// Restore symlinks, executable bits and modification times stored in the collection.
+        ///
+        /// Symlinks pointing outside of the output directory are refused.
+        #[clap(long, default_value_t = false)]
+        metadata: bool,
+        #[clap(flatten)]
+        limits: self::get::LimitArgs,
+    },
+    /// List the collections and pinned blobs a provider offers
+    ///
+    /// The provider must have been started with --serve-listing.
+    Ls {
+        /// PeerId of the provider
+        peer: PeerId,
+        /// Addresses of the provider
+        #[clap(long, short)]
+        addrs: Vec<SocketAddr>,
+        /// base32-encoded Request token to use for authentication, if any
+        #[clap(long)]
+        token: Option<RequestToken>,
+        /// DERP region of the provider
+        #[clap(long)]
+        region: Option<u16>,
     },
     /// Download data to the running provider's database and provide it.
     ///
@@ -380,6 +757,29 @@ pub enum Commands {
         /// and iroh will assume that it will not change.
         #[clap(long, default_value_t = false)]
         stable: bool,
+        /// Write checksum manifests (sha256, sha1) next to the exported data.
+        ///
+        /// Can be given multiple times. Implies copying the data.
+        #[clap(long)]
+        checksum: Vec<ChecksumAlgorithm>,
+        /// Restore symlinks, executable bits and modification times stored in the collection.
+        ///
+        /// Symlinks pointing outside of the output directory are refused.
+        #[clap(long, default_value_t = false)]
+        metadata: bool,
+        /// Number of times to retry the download if the connection fails, none by default.
+        ///
+        /// Retries resume from the data that was already received.
+        #[clap(long)]
+        retries: Option<u32>,
+        #[clap(flatten)]
+        limits: self::get::LimitArgs,
+        /// Priority of the download: interactive, normal or background.
+        ///
+        /// Queued downloads start in the order of their priority, and running downloads
+        /// yield bandwidth to downloads of a higher priority.
+        #[clap(long, default_value_t = DownloadPriority::Normal)]
+        priority: DownloadPriority,
         /// RPC port
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
@@ -390,40 +790,49 @@ pub enum Commands {
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
     },
+    /// List the scores of the peers the provider downloaded from.
+    ///
+    /// Peers lose score when they send data that fails verification.
+    PeerScores {
+        /// RPC port
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
 }
 
-async fn make_rpc_client(
-    rpc_port: u16,
-) -> anyhow::Result<RpcClient<ProviderService, QuinnConnection<ProviderResponse, ProviderRequest>>>
-{
-    let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into();
-    let endpoint = create_quinn_client(bind_addr, vec![RPC_ALPN.to_vec()], false)?;
-    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), rpc_port);
-    let server_name = "localhost".to_string();
-    let connection = QuinnConnection::new(endpoint, addr, server_name);
-    let client = RpcClient::<ProviderService, _>::new(connection);
-    // Do a version request to check if the server is running.
-    let _version = tokio::time::timeout(Duration::from_secs(1), client.rpc(VersionRequest))
-        .await
-        .context("iroh server is not running")??;
-    Ok(client)
+type RpcConnection =
+    CombinedConnection<LocalRpcConnection, QuinnRpcConnection, ProviderResponse, ProviderRequest>;
+
+/// Adds a hint to an error caused by the provider refusing an expired ticket.
+fn explain_expired(err: anyhow::Error, token: Option<&RequestToken>) -> anyhow::Error {
+    if ErrorClass::classify(&err) != ErrorClass::Expired {
+        return err;
+    }
+    match token.and_then(expiring_token::expires) {
+        Some(expires) => {
+            let ago = SystemTime::now()
+                .duration_since(expires)
+                .unwrap_or_default();
+            err.context(format!(
+                "the ticket expired {} ago, ask the provider for a new one",
+                HumanDuration(ago)
+            ))
+        }
+        None => err.context("the ticket expired, ask the provider for a new one"),
+    }
 }
 
-pub fn create_quinn_client(
-    bind_addr: SocketAddr,
-    alpn_protocols: Vec<Vec<u8>>,
-    keylog: bool,
-) -> Result<quinn::Endpoint> {
-    let keypair = iroh_net::tls::Keypair::generate();
-    let tls_client_config =
-        iroh_net::tls::make_client_config(&keypair, None, alpn_protocols, keylog)?;
-    let mut client_config = quinn::ClientConfig::new(Arc::new(tls_client_config));
-    let mut endpoint = quinn::Endpoint::client(bind_addr)?;
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
-    client_config.transport_config(Arc::new(transport_config));
-    endpoint.set_default_client_config(client_config);
-    Ok(endpoint)
+/// Makes an RPC client, using the local `socket` if one is configured, otherwise `rpc_port`.
+async fn make_rpc_client(
+    rpc_port: u16,
+    socket: Option<&Path>,
+) -> anyhow::Result<RpcClient<ProviderService, RpcConnection>> {
+    let connection = match socket {
+        Some(path) => RpcConnection::new(Some(LocalRpcConnection::new(path)), None),
+        None => RpcConnection::new(None, Some(iroh::client::quinn_connection(rpc_port)?)),
+    };
+    let client = Iroh::connect(connection).await?;
+    Ok(client.into_rpc())
 }
 
 #[cfg(feature = "metrics")]
@@ -459,6 +868,47 @@ pub enum RequestTokenOptions {
     Token(RequestToken),
 }
 
+/// Dialing info to embed in a ticket, see [`TicketOptions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TicketInfoOptions {
+    All,
+    Derp,
+    Peer,
+}
+
+impl fmt::Display for TicketInfoOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => write!(f, "all"),
+            Self::Derp => write!(f, "derp"),
+            Self::Peer => write!(f, "peer"),
+        }
+    }
+}
+
+impl FromStr for TicketInfoOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "all" => Ok(Self::All),
+            "derp" => Ok(Self::Derp),
+            "peer" => Ok(Self::Peer),
+            _ => anyhow::bail!("invalid ticket info {s:?}, expected all, derp or peer"),
+        }
+    }
+}
+
+impl From<TicketInfoOptions> for TicketOptions {
+    fn from(options: TicketInfoOptions) -> Self {
+        match options {
+            TicketInfoOptions::All => TicketOptions::default(),
+            TicketInfoOptions::Derp => TicketOptions::default().direct_addrs(false),
+            TicketInfoOptions::Peer => TicketOptions::peer_only(),
+        }
+    }
+}
+
 impl FromStr for RequestTokenOptions {
     type Err = anyhow::Error;
 
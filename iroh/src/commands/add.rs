@@ -1,18 +1,48 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet},
     path::PathBuf,
-    time::Duration,
 };
 
 use anyhow::{Context, Result};
+use clap::Args;
 use futures::{Stream, StreamExt};
-use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
-use iroh::rpc_protocol::ProvideRequest;
+use indicatif::HumanBytes;
+use iroh::{rpc_protocol::ProvideRequest, util::fs::ImportFilter};
 use iroh_bytes::{provider::ProvideProgress, Hash};
 
-use crate::commands::make_rpc_client;
+use crate::commands::{make_rpc_client, progress::MultiBar};
 
-pub async fn run(path: PathBuf, in_place: bool, rpc_port: u16) -> Result<()> {
+/// Which files of a directory to add.
+#[derive(Args, Debug, Clone)]
+pub struct FilterArgs {
+    /// Only add files matching this glob, relative to the directory
+    #[clap(long)]
+    include: Vec<String>,
+    /// Skip files matching this glob, relative to the directory, e.g. 'target/**'
+    #[clap(long)]
+    exclude: Vec<String>,
+    /// Also add files ignored by .ignore and .gitignore files
+    #[clap(long, default_value_t = false)]
+    no_ignore: bool,
+}
+
+impl From<FilterArgs> for ImportFilter {
+    fn from(args: FilterArgs) -> Self {
+        ImportFilter {
+            include: args.include,
+            exclude: args.exclude,
+            ignore_files: !args.no_ignore,
+        }
+    }
+}
+
+pub async fn run(
+    path: PathBuf,
+    in_place: bool,
+    metadata: bool,
+    filter: ImportFilter,
+    rpc_port: u16,
+) -> Result<()> {
     let client = make_rpc_client(rpc_port).await?;
     let absolute = path.canonicalize()?;
     println!("Adding {} as {}...", path.display(), absolute.display());
@@ -20,6 +50,8 @@ pub async fn run(path: PathBuf, in_place: bool, rpc_port: u16) -> Result<()> {
         .server_streaming(ProvideRequest {
             path: absolute,
             in_place,
+            metadata,
+            filter,
         })
         .await?;
     let (hash, entries) = aggregate_add_response(stream).await?;
@@ -32,6 +64,7 @@ pub struct ProvideResponseEntry {
     pub name: String,
     pub size: u64,
     pub hash: Hash,
+    pub deduplicated: bool,
 }
 
 pub async fn aggregate_add_response<S, E>(
@@ -44,13 +77,14 @@ where
     let mut stream = stream;
     let mut collection_hash = None;
     let mut collections = BTreeMap::<u64, (String, u64, Option<Hash>)>::new();
-    let mut mp = Some(ProvideProgressState::new());
+    let mut deduplicated = BTreeSet::new();
+    let mut mp = Some(MultiBar::new("Adding"));
     while let Some(item) = stream.next().await {
         match item? {
             ProvideProgress::Found { name, id, size } => {
                 tracing::trace!("Found({id},{name},{size})");
                 if let Some(mp) = mp.as_mut() {
-                    mp.found(name.clone(), id, size);
+                    mp.found(id, name.clone(), size);
                 }
                 collections.insert(id, (name, size, None));
             }
@@ -63,7 +97,7 @@ where
             ProvideProgress::Done { hash, id } => {
                 tracing::trace!("Done({id},{hash:?})");
                 if let Some(mp) = mp.as_mut() {
-                    mp.done(id, hash);
+                    mp.done(id);
                 }
                 match collections.get_mut(&id) {
                     Some((_, _, ref mut h)) => {
@@ -74,17 +108,21 @@ where
                     }
                 }
             }
+            ProvideProgress::Deduplicated { id, size } => {
+                tracing::trace!("Deduplicated({id},{size})");
+                deduplicated.insert(id);
+            }
             ProvideProgress::AllDone { hash } => {
                 tracing::trace!("AllDone({hash:?})");
                 if let Some(mp) = mp.take() {
-                    mp.all_done();
+                    mp.finish();
                 }
                 collection_hash = Some(hash);
                 break;
             }
             ProvideProgress::Abort(e) => {
                 if let Some(mp) = mp.take() {
-                    mp.error();
+                    mp.finish();
                 }
                 anyhow::bail!("Error while adding data: {e}");
             }
@@ -93,9 +131,14 @@ where
     let hash = collection_hash.context("Missing hash for collection")?;
     let entries = collections
         .into_iter()
-        .map(|(_, (name, size, hash))| {
+        .map(|(id, (name, size, hash))| {
             let hash = hash.context(format!("Missing hash for {name}"))?;
-            Ok(ProvideResponseEntry { name, size, hash })
+            Ok(ProvideResponseEntry {
+                name,
+                size,
+                hash,
+                deduplicated: deduplicated.contains(&id),
+            })
         })
         .collect::<Result<Vec<_>>>()?;
     Ok((hash, entries))
@@ -103,59 +146,28 @@ where
 
 pub fn print_add_response(hash: Hash, entries: Vec<ProvideResponseEntry>) {
     let mut total_size = 0;
-    for ProvideResponseEntry { name, size, hash } in entries {
-        total_size += size;
-        println!("- {}: {} {:#}", name, HumanBytes(size), hash);
+    let mut deduplicated_size = 0;
+    for entry in entries {
+        total_size += entry.size;
+        let note = if entry.deduplicated {
+            deduplicated_size += entry.size;
+            " (deduplicated)"
+        } else {
+            ""
+        };
+        println!(
+            "- {}: {} {:#}{note}",
+            entry.name,
+            HumanBytes(entry.size),
+            entry.hash
+        );
     }
-    println!("Total: {}", HumanBytes(total_size));
+    println!(
+        "Total: {}, {} new, {} deduplicated",
+        HumanBytes(total_size),
+        HumanBytes(total_size - deduplicated_size),
+        HumanBytes(deduplicated_size)
+    );
     println!();
     println!("Collection: {}", hash);
 }
-
-#[derive(Debug)]
-pub struct ProvideProgressState {
-    mp: MultiProgress,
-    pbs: HashMap<u64, ProgressBar>,
-}
-
-impl ProvideProgressState {
-    fn new() -> Self {
-        Self {
-            mp: MultiProgress::new(),
-            pbs: HashMap::new(),
-        }
-    }
-
-    fn found(&mut self, name: String, id: u64, size: u64) {
-        let pb = self.mp.add(ProgressBar::new(size));
-        pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.green} [{bar:40.cyan/blue}] {msg} {bytes}/{total_bytes} ({bytes_per_sec}, eta {eta})").unwrap()
-            .progress_chars("=>-"));
-        pb.set_message(name);
-        pb.set_length(size);
-        pb.set_position(0);
-        pb.enable_steady_tick(Duration::from_millis(500));
-        self.pbs.insert(id, pb);
-    }
-
-    fn progress(&mut self, id: u64, progress: u64) {
-        if let Some(pb) = self.pbs.get_mut(&id) {
-            pb.set_position(progress);
-        }
-    }
-
-    fn done(&mut self, id: u64, _hash: Hash) {
-        if let Some(pb) = self.pbs.remove(&id) {
-            pb.finish_and_clear();
-            self.mp.remove(&pb);
-        }
-    }
-
-    fn all_done(self) {
-        self.mp.clear().ok();
-    }
-
-    fn error(self) {
-        self.mp.clear().ok();
-    }
-}
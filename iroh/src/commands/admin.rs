@@ -0,0 +1,37 @@
+use anyhow::Result;
+use clap::Subcommand;
+use iroh::rpc_protocol::SetLogFilterRequest;
+
+use super::{make_rpc_client, DEFAULT_RPC_PORT};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// Replace the log filter of the running provider.
+    ///
+    /// Takes a filter in the syntax of RUST_LOG, e.g. "info,iroh_net=debug". It applies
+    /// to stderr and the log file until the provider restarts.
+    SetLogFilter {
+        /// The new log filter
+        filter: String,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Commands::SetLogFilter { filter, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client
+                    .rpc(SetLogFilterRequest {
+                        filter: filter.clone(),
+                    })
+                    .await??;
+                println!("Log filter set to {filter}");
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use futures::StreamExt;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use iroh::dial::Ticket;
+use iroh::rpc_protocol::{
+    BlobDiffRequest, BlobReadAtRequest, BlobReadAtResponse, DeleteBlobRequest, ExportCarRequest,
+    ExportPartialRequest, FetchUrlRequest, ImportCarRequest, ImportPartialRequest, PinAddRequest,
+    PinListRequest, PinRemoveRequest, ProvideProgress, RestoreBlobRequest,
+};
+use iroh_bytes::protocol::{ProbeRequest, ProbeResponse};
+use iroh_bytes::Hash;
+use iroh_net::tls::Keypair;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use super::{make_rpc_client, DEFAULT_RPC_PORT};
+use crate::config::Config;
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// Import a CAR archive into the running provider's database as a collection.
+    ImportCar {
+        /// Path to the CAR archive
+        path: PathBuf,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Export a collection from the running provider's database as a CAR archive.
+    ExportCar {
+        /// Hash of the collection to export
+        hash: Hash,
+        /// Path to write the CAR archive to
+        out: PathBuf,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Download the content at URL into the running provider's database as a blob.
+    ///
+    /// Interrupted downloads are resumed if the origin supports range requests.
+    FetchUrl {
+        /// The http or https url to download
+        url: Url,
+        /// The expected hash of the content
+        ///
+        /// The download is rejected if the content does not match.
+        #[clap(long)]
+        hash: Option<Hash>,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Delete a blob from the running provider's database.
+    ///
+    /// The blob is moved to the trash, and can be restored until the trash retention of
+    /// the provider expires.
+    Delete {
+        /// Hash of the blob to delete
+        hash: Hash,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Restore a deleted blob from the trash of the running provider's database.
+    Restore {
+        /// Hash of the blob to restore
+        hash: Hash,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Export a partially downloaded blob from the running provider's database.
+    ///
+    /// Writes the downloaded parts to a sparse file, and the ranges that have been verified
+    /// to a sidecar next to it with the extension `.iroh-partial`. Import both on another
+    /// machine to resume the download there.
+    ExportPartial {
+        /// Hash of the blob to export
+        hash: Hash,
+        /// Path to write the sparse file to
+        out: PathBuf,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Import a sparse file written by `export-partial` into the running provider's
+    /// database.
+    ///
+    /// The data is verified before it is imported. Fetch the blob again to resume the
+    /// download.
+    ImportPartial {
+        /// Path of the sparse file, its sidecar is expected next to it
+        path: PathBuf,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Write a range of a blob in the running provider's database to stdout.
+    Read {
+        /// Hash of the blob to read
+        hash: Hash,
+        /// Offset of the first byte to read
+        #[clap(long, default_value_t = 0)]
+        offset: u64,
+        /// Number of bytes to read, reads to the end of the blob if not set
+        #[clap(long)]
+        len: Option<u64>,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Compare two collections in the running provider's database.
+    ///
+    /// Prints the entries that were added, removed or changed from the first to the second
+    /// collection. Only the two collection blobs are needed, sizes are shown for the
+    /// entries whose blobs are in the database.
+    Diff {
+        /// Hash of the old collection
+        from: Hash,
+        /// Hash of the new collection
+        to: Hash,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Manage pins, which protect blobs from garbage collection.
+    #[clap(subcommand)]
+    Pin(PinCommands),
+    /// Ask the providers of a ticket what they have of its blob, without downloading it.
+    ///
+    /// Prints for every provider whether it has the blob, its size and the chunk ranges it
+    /// can serve.
+    Probe {
+        /// Ticket of the blob
+        ticket: Ticket,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum PinCommands {
+    /// Pin a blob.
+    Add {
+        /// Hash of the blob to pin
+        hash: Hash,
+        /// Name of the pin, defaults to the hash
+        ///
+        /// An existing pin with the same name is replaced.
+        #[clap(long)]
+        name: Option<String>,
+        /// Also protect all children of the collection
+        #[clap(long, short, default_value_t = false)]
+        recursive: bool,
+        /// Remove the pin after this many seconds
+        #[clap(long)]
+        ttl: Option<u64>,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Remove a pin.
+    Rm {
+        /// Name of the pin
+        name: String,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// List all pins.
+    Ls {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self, config: &Config) -> Result<()> {
+        match self {
+            Commands::ImportCar { path, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let path = path.canonicalize()?;
+                let response = client.rpc(ImportCarRequest { path }).await??;
+                println!(
+                    "Imported {} blob(s) ({})",
+                    response.total_blobs_count,
+                    HumanBytes(response.total_blobs_size)
+                );
+                println!("Collection: {}", response.hash);
+            }
+            Commands::ExportCar {
+                hash,
+                out,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let path = std::env::current_dir()?.join(out);
+                let response = client
+                    .rpc(ExportCarRequest {
+                        hash,
+                        path: path.clone(),
+                    })
+                    .await??;
+                println!(
+                    "Exported {} block(s) to {}",
+                    response.blocks,
+                    path.display()
+                );
+            }
+            Commands::FetchUrl {
+                url,
+                hash,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut stream = client
+                    .server_streaming(FetchUrlRequest { url, hash })
+                    .await?;
+                let pb = ProgressBar::hidden();
+                pb.set_style(ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{bar:40.cyan/blue}] {msg} {bytes}/{total_bytes} ({bytes_per_sec}, eta {eta})").unwrap()
+                    .progress_chars("=>-"));
+                let mut hash = None;
+                while let Some(item) = stream.next().await {
+                    match item? {
+                        ProvideProgress::Found { name, size, .. } => {
+                            pb.set_draw_target(indicatif::ProgressDrawTarget::stderr());
+                            pb.set_message(name);
+                            pb.set_length(size);
+                            pb.reset();
+                        }
+                        ProvideProgress::Progress { offset, .. } => pb.set_position(offset),
+                        ProvideProgress::Done { .. } | ProvideProgress::Deduplicated { .. } => {}
+                        ProvideProgress::AllDone { hash: h } => {
+                            hash = Some(h);
+                            break;
+                        }
+                        ProvideProgress::Abort(e) => {
+                            pb.finish_and_clear();
+                            anyhow::bail!("Error while fetching: {e}");
+                        }
+                    }
+                }
+                pb.finish_and_clear();
+                let hash = hash.context("Missing hash for blob")?;
+                println!("Blob: {}", hash);
+            }
+            Commands::Delete { hash, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client.rpc(DeleteBlobRequest { hash }).await??;
+                println!("Deleted {hash}");
+            }
+            Commands::Restore { hash, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client.rpc(RestoreBlobRequest { hash }).await??;
+                println!("Restored {hash}");
+            }
+            Commands::ExportPartial {
+                hash,
+                out,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let path = std::env::current_dir()?.join(out);
+                client
+                    .rpc(ExportPartialRequest {
+                        hash,
+                        path: path.clone(),
+                    })
+                    .await??;
+                println!("Exported {hash} to {}", path.display());
+            }
+            Commands::ImportPartial { path, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let path = path.canonicalize()?;
+                let response = client.rpc(ImportPartialRequest { path }).await??;
+                println!("Imported {}", response.hash);
+            }
+            Commands::Read {
+                hash,
+                offset,
+                len,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut stream = client
+                    .server_streaming(BlobReadAtRequest { hash, offset, len })
+                    .await?;
+                let mut stdout = tokio::io::stdout();
+                while let Some(item) = stream.next().await {
+                    if let BlobReadAtResponse::Data { chunk } = item?? {
+                        stdout.write_all(&chunk).await?;
+                    }
+                }
+                stdout.flush().await?;
+            }
+            Commands::Diff { from, to, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut stream = client
+                    .server_streaming(BlobDiffRequest { from, to })
+                    .await?;
+                let size = |size: Option<u64>| match size {
+                    Some(size) => HumanBytes(size).to_string(),
+                    None => "unknown size".to_string(),
+                };
+                let (mut added, mut removed, mut changed) = (0, 0, 0);
+                while let Some(item) = stream.next().await {
+                    let entry = item??;
+                    match (entry.from, entry.to) {
+                        (None, Some(hash)) => {
+                            added += 1;
+                            println!("+ {} ({hash}, {})", entry.name, size(entry.to_size));
+                        }
+                        (Some(hash), None) => {
+                            removed += 1;
+                            println!("- {} ({hash}, {})", entry.name, size(entry.from_size));
+                        }
+                        (Some(from), Some(to)) => {
+                            changed += 1;
+                            println!(
+                                "~ {} ({from} -> {to}, {} -> {})",
+                                entry.name,
+                                size(entry.from_size),
+                                size(entry.to_size)
+                            );
+                        }
+                        (None, None) => {}
+                    }
+                }
+                println!("{added} added, {removed} removed, {changed} changed");
+            }
+            Commands::Pin(cmd) => cmd.run().await?,
+            Commands::Probe { ticket } => {
+                let options = ticket.as_all_get_options(Keypair::generate(), config.derp_map());
+                for opts in options {
+                    let peer = opts.peer_id;
+                    let request = ProbeRequest {
+                        hash: ticket.hash(),
+                        token: ticket.token().cloned(),
+                    };
+                    let probe = async {
+                        let connection = iroh::dial::dial(opts).await?;
+                        anyhow::Ok(iroh_bytes::get::probe(&connection, request).await?)
+                    };
+                    match probe.await {
+                        Ok(ProbeResponse { size: None, .. }) => println!("{peer}: not found"),
+                        Ok(ProbeResponse {
+                            size: Some(size),
+                            complete: true,
+                            ..
+                        }) => println!("{peer}: complete, {}", HumanBytes(size)),
+                        Ok(ProbeResponse {
+                            size: Some(size),
+                            ranges,
+                            ..
+                        }) => println!(
+                            "{peer}: partial, {}, chunks {:?}",
+                            HumanBytes(size),
+                            ranges.to_chunk_ranges()
+                        ),
+                        Err(cause) => println!("{peer}: failed: {cause:#}"),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PinCommands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            PinCommands::Add {
+                hash,
+                name,
+                recursive,
+                ttl,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let response = client
+                    .rpc(PinAddRequest {
+                        hash,
+                        name,
+                        recursive,
+                        ttl: ttl.map(Duration::from_secs),
+                    })
+                    .await??;
+                println!("Pinned {hash} as {}", response.name);
+            }
+            PinCommands::Rm { name, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let response = client.rpc(PinRemoveRequest { name }).await??;
+                println!("Unpinned {}", response.pin.hash);
+            }
+            PinCommands::Ls { rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut response = client.server_streaming(PinListRequest).await?;
+                while let Some(item) = response.next().await {
+                    let item = item?;
+                    let mut flags = Vec::new();
+                    if item.pin.recursive {
+                        flags.push("recursive".to_string());
+                    }
+                    if item.expired {
+                        flags.push("expired".to_string());
+                    } else if let Some(expires) = item.pin.expires {
+                        let remaining = expires
+                            .duration_since(SystemTime::now())
+                            .unwrap_or_default();
+                        let remaining = Duration::from_secs(remaining.as_secs());
+                        flags.push(format!("expires in {}", HumanDuration(remaining)));
+                    }
+                    if flags.is_empty() {
+                        println!("{}: {}", item.name, item.pin.hash);
+                    } else {
+                        println!("{}: {} ({})", item.name, item.pin.hash, flags.join(", "));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
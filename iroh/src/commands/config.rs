@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::Subcommand;
+
+use crate::config::{iroh_config_path, Config, CONFIG_FILE_NAME, ENV_PREFIX};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// Validate the configuration and list where it was loaded from.
+    Check,
+    /// Print the effective configuration, after merging the files, environment variables and
+    /// defaults, as TOML.
+    Show,
+}
+
+impl Commands {
+    pub fn run(self, config: &Config, cfg: Option<&Path>) -> Result<()> {
+        match self {
+            Commands::Check => {
+                let default_path = iroh_config_path(CONFIG_FILE_NAME)?;
+                for path in [Some(default_path.as_path()), cfg].into_iter().flatten() {
+                    let state = if path.exists() { "loaded" } else { "not found" };
+                    println!("{}: {state}", path.display());
+                }
+                let prefix = format!("{ENV_PREFIX}__");
+                for (key, _) in std::env::vars().filter(|(key, _)| key.starts_with(&prefix)) {
+                    println!("{key}: loaded");
+                }
+                config.validate()?;
+                println!("configuration is valid");
+                Ok(())
+            }
+            Commands::Show => {
+                let text = toml::to_string_pretty(config).context("failed to serialize config")?;
+                print!("{text}");
+                Ok(())
+            }
+        }
+    }
+}
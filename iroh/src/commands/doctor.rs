@@ -4,20 +4,23 @@ use std::{
     collections::HashMap,
     net::SocketAddr,
     num::NonZeroU16,
+    path::PathBuf,
     time::{Duration, Instant},
 };
 
-use crate::config::{iroh_config_path, iroh_data_root, Config, CONFIG_FILE_NAME, ENV_PREFIX};
+use crate::config::{iroh_config_path, Config, CONFIG_FILE_NAME, ENV_PREFIX};
 
 use anyhow::Context;
 use clap::Subcommand;
 use indicatif::{HumanBytes, MultiProgress, ProgressBar};
-use iroh::util::progress::ProgressWriter;
+use iroh::{data_dir::DataDir, util::progress::ProgressWriter};
 use iroh_net::{
     config,
     defaults::{DEFAULT_DERP_STUN_PORT, TEST_REGION_ID},
     derp::{DerpMap, UseIpv4, UseIpv6},
+    dns::DnsResolver,
     key::node::SecretKey,
+    magicsock::{PacketCapture, PacketPath, DEFAULT_CAPTURE_CAPACITY},
     netcheck, portmapper,
     tls::{Keypair, PeerId, PublicKey},
     MagicEndpoint,
@@ -29,6 +32,8 @@ use tokio::{io::AsyncWriteExt, sync};
 use iroh_metrics::core::Core;
 use iroh_net::metrics::MagicsockMetrics;
 
+mod plot;
+
 #[derive(Debug, Clone, derive_more::Display)]
 pub enum PrivateKey {
     /// Generate random private key
@@ -114,6 +119,77 @@ pub enum Commands {
         #[clap(long)]
         derp_region: Option<u16>,
     },
+    /// Connect to an iroh doctor accept node, recording metadata of all packets.
+    ///
+    /// For every packet sent or received, this records when, on which path, to or from
+    /// which peer and how large it was, and for disco packets the message type. No payloads
+    /// are recorded.
+    Capture {
+        /// hex peer id of the node to connect to
+        dial: String,
+
+        /// One or more remote endpoints to use when dialing
+        #[clap(long)]
+        remote_endpoint: Vec<SocketAddr>,
+
+        /// Our own private key, in hex. If not specified, a random key will be generated.
+        #[clap(long, default_value_t = PrivateKey::Random)]
+        private_key: PrivateKey,
+
+        /// Use a local derp relay
+        ///
+        /// Overrides the `derp_region` field.
+        #[clap(long)]
+        local_derper: bool,
+
+        /// The DERP region the peer you are dialing can be found on.
+        ///
+        /// If `local_derper` is true, this field is ignored.
+        #[clap(long)]
+        derp_region: Option<u16>,
+
+        /// How long to capture for, in seconds.
+        #[clap(long, default_value_t = 30)]
+        duration: u64,
+
+        /// Maximum number of packets to keep, older packets are dropped.
+        #[clap(long, default_value_t = DEFAULT_CAPTURE_CAPACITY)]
+        capacity: usize,
+
+        /// Write the capture to this file in the pcapng format, instead of printing it.
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Wait for a connection from iroh doctor connect, and measure it over time.
+    ///
+    /// At every interval, this samples the latency to the home DERP region, whether the
+    /// peer is reached directly, i.e. hole punching succeeded, and the throughput to the
+    /// peer. The samples are printed as CSV, and plotted when the measurement ends.
+    Plot {
+        /// Our own private key, in hex. If not specified, the locally configured key will be used.
+        #[clap(long, default_value_t = PrivateKey::Local)]
+        private_key: PrivateKey,
+
+        /// Use a local derp relay
+        #[clap(long)]
+        local_derper: bool,
+
+        /// Seconds between samples.
+        #[clap(long, default_value_t = 5)]
+        interval: u64,
+
+        /// Number of samples to take. If not specified, samples are taken until interrupted.
+        #[clap(long)]
+        samples: Option<u64>,
+
+        /// Number of bytes to send to the remote for each throughput sample
+        #[clap(long, default_value_t = 1024 * 1024)]
+        size: u64,
+
+        /// Also write the samples to this file as CSV.
+        #[clap(long)]
+        csv: Option<PathBuf>,
+    },
     /// Probe the port mapping protocols.
     PortMapProbe {
         /// Whether to enable UPnP.
@@ -237,7 +313,8 @@ async fn send_blocks(
 
 async fn report(stun_host: Option<String>, stun_port: u16, config: &Config) -> anyhow::Result<()> {
     let port_mapper = portmapper::Client::default().await;
-    let mut client = netcheck::Client::new(Some(port_mapper)).await?;
+    let mut client =
+        netcheck::Client::with_dns_resolver(Some(port_mapper), config.dns_resolver()?).await?;
 
     let dm = match stun_host {
         Some(host_name) => {
@@ -491,6 +568,8 @@ const DR_DERP_ALPN: [u8; 11] = *b"n0/drderp/1";
 async fn make_endpoint(
     private_key: SecretKey,
     derp_map: Option<DerpMap>,
+    dns_resolver: DnsResolver,
+    packet_capture: Option<PacketCapture>,
 ) -> anyhow::Result<MagicEndpoint> {
     tracing::info!(
         "public key: {}",
@@ -516,16 +595,19 @@ async fn make_endpoint(
     transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
     transport_config.max_idle_timeout(Some(Duration::from_secs(10).try_into().unwrap()));
 
-    let endpoint = MagicEndpoint::builder()
+    let mut builder = MagicEndpoint::builder()
         .keypair(private_key.into())
         .alpns(vec![DR_DERP_ALPN.to_vec()])
         .derp_map(derp_map)
+        .dns_resolver(dns_resolver)
         .transport_config(transport_config)
         .on_net_info(Box::new(on_net_info))
         .on_endpoints(Box::new(on_endpoints))
-        .on_derp_active(Box::new(on_derp_active))
-        .bind(0)
-        .await?;
+        .on_derp_active(Box::new(on_derp_active));
+    if let Some(packet_capture) = packet_capture {
+        builder = builder.packet_capture(packet_capture);
+    }
+    let endpoint = builder.bind(0).await?;
 
     tokio::time::timeout(Duration::from_secs(10), on_derp_r.recv())
         .await
@@ -540,9 +622,19 @@ async fn connect(
     remote_endpoints: Vec<SocketAddr>,
     derp_region: Option<u16>,
     derp_map: Option<DerpMap>,
+    dns_resolver: DnsResolver,
 ) -> anyhow::Result<()> {
-    let endpoint = make_endpoint(private_key.clone(), derp_map).await?;
+    let endpoint = make_endpoint(private_key.clone(), derp_map, dns_resolver, None).await?;
+    dial_and_test(&endpoint, dial, remote_endpoints, derp_region).await
+}
 
+/// Dial an iroh doctor accept node and handle the test requests it sends.
+async fn dial_and_test(
+    endpoint: &MagicEndpoint,
+    dial: String,
+    remote_endpoints: Vec<SocketAddr>,
+    derp_region: Option<u16>,
+) -> anyhow::Result<()> {
     let bytes = hex::decode(dial)?;
     let bytes: [u8; 32] = bytes.try_into().ok().context("unexpected key length")?;
     let peer_id = PeerId::from(PublicKey::from_bytes(&bytes).context("failed to parse PeerId")?);
@@ -553,6 +645,9 @@ async fn connect(
         .await;
     match conn {
         Ok(connection) => {
+            if let Ok(Some(info)) = endpoint.connection_info(peer_id).await {
+                println!("connected via {:?}", info.conn_type);
+            }
             if let Err(cause) = passive_side(connection).await {
                 eprintln!("error handling connection: {cause}");
             }
@@ -565,6 +660,61 @@ async fn connect(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+async fn capture(
+    dial: String,
+    private_key: SecretKey,
+    remote_endpoints: Vec<SocketAddr>,
+    derp_region: Option<u16>,
+    derp_map: Option<DerpMap>,
+    dns_resolver: DnsResolver,
+    duration: Duration,
+    capacity: usize,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let packet_capture = PacketCapture::new(capacity);
+    let endpoint = make_endpoint(
+        private_key,
+        derp_map,
+        dns_resolver,
+        Some(packet_capture.clone()),
+    )
+    .await?;
+    let test = dial_and_test(&endpoint, dial, remote_endpoints, derp_region);
+    if let Ok(res) = tokio::time::timeout(duration, test).await {
+        res?;
+    }
+
+    let packets = packet_capture.packets();
+    match output {
+        Some(path) => {
+            let file = std::fs::File::create(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            packet_capture.write_pcapng(std::io::BufWriter::new(file))?;
+            println!("wrote {} packets to {}", packets.len(), path.display());
+        }
+        None => {
+            for packet in &packets {
+                println!("{packet}");
+            }
+        }
+    }
+    let derp = packets
+        .iter()
+        .filter(|p| matches!(p.path, PacketPath::Derp(_)))
+        .count();
+    let disco = packets.iter().filter(|p| p.disco.is_some()).count();
+    println!(
+        "captured {} packets: {} udp, {} derp, {} disco, {} dropped",
+        packets.len(),
+        packets.len() - derp,
+        derp,
+        disco,
+        packet_capture.dropped()
+    );
+    Ok(())
+}
+
 /// format a socket addr so that it does not have to be escaped on the console
 fn format_addr(addr: SocketAddr) -> String {
     if addr.is_ipv6() {
@@ -578,21 +728,10 @@ async fn accept(
     private_key: SecretKey,
     config: TestConfig,
     derp_map: Option<DerpMap>,
+    dns_resolver: DnsResolver,
 ) -> anyhow::Result<()> {
-    let endpoint = make_endpoint(private_key.clone(), derp_map).await?;
-
-    let endpoints = endpoint.local_endpoints().await?;
-    let remote_addrs = endpoints
-        .iter()
-        .map(|endpoint| format!("--remote-endpoint {}", format_addr(endpoint.addr)))
-        .collect::<Vec<_>>()
-        .join(" ");
-    println!(
-            "Run\n\niroh doctor connect {} {}\n\nin another terminal or on another machine to connect by key and addr.",
-            hex::encode(private_key.public_key().as_bytes()),
-            remote_addrs,
-        );
-    println!("Omit the --remote-endpoint args to connect just by key.");
+    let endpoint = make_endpoint(private_key.clone(), derp_map, dns_resolver, None).await?;
+    print_connect_instructions(&endpoint, &private_key).await?;
     while let Some(connecting) = endpoint.accept().await {
         match connecting.await {
             Ok(connection) => {
@@ -611,6 +750,51 @@ async fn accept(
     Ok(())
 }
 
+async fn plot(
+    private_key: SecretKey,
+    derp_map: Option<DerpMap>,
+    dns_resolver: DnsResolver,
+    config: plot::PlotConfig,
+) -> anyhow::Result<()> {
+    let endpoint = make_endpoint(
+        private_key.clone(),
+        derp_map.clone(),
+        dns_resolver.clone(),
+        None,
+    )
+    .await?;
+    print_connect_instructions(&endpoint, &private_key).await?;
+    let connection = loop {
+        let connecting = endpoint.accept().await.context("endpoint closed")?;
+        match connecting.await {
+            Ok(connection) => break connection,
+            Err(cause) => eprintln!("error accepting connection {cause}"),
+        }
+    };
+    let peer = iroh_net::magic_endpoint::get_peer_id(&connection).await?;
+    println!("\nAccepted connection from {peer}. Measuring, press Ctrl-C to stop.\n");
+    plot::plot(&endpoint, connection, peer, derp_map, dns_resolver, config).await
+}
+
+async fn print_connect_instructions(
+    endpoint: &MagicEndpoint,
+    private_key: &SecretKey,
+) -> anyhow::Result<()> {
+    let endpoints = endpoint.local_endpoints().await?;
+    let remote_addrs = endpoints
+        .iter()
+        .map(|endpoint| format!("--remote-endpoint {}", format_addr(endpoint.addr)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    println!(
+            "Run\n\niroh doctor connect {} {}\n\nin another terminal or on another machine to connect by key and addr.",
+            hex::encode(private_key.public_key().as_bytes()),
+            remote_addrs,
+        );
+    println!("Omit the --remote-endpoint args to connect just by key.");
+    Ok(())
+}
+
 async fn port_map(protocol: &str, local_port: NonZeroU16, timeout: Duration) -> anyhow::Result<()> {
     // create the config that enables exlusively the required protocol
     let mut enable_upnp = false;
@@ -658,12 +842,14 @@ async fn port_map_probe(config: portmapper::Config) -> anyhow::Result<()> {
 
 async fn derp_regions(config: Config) -> anyhow::Result<()> {
     let key = iroh_net::key::node::SecretKey::generate();
+    let dns_resolver = config.dns_resolver()?;
     let mut set = tokio::task::JoinSet::new();
     if config.derp_regions.is_empty() {
         println!("No DERP Regions specified in the config file.");
     }
     for region in config.derp_regions.into_iter() {
         let secret_key = key.clone();
+        let dns_resolver = dns_resolver.clone();
         set.spawn(async move {
             let mut region_details = RegionDetails {
                 latency: None,
@@ -672,6 +858,7 @@ async fn derp_regions(config: Config) -> anyhow::Result<()> {
                 hosts: region.nodes.iter().map(|n| n.url.clone()).collect(),
             };
             let client = match iroh_net::derp::http::ClientBuilder::new()
+                .dns_resolver(dns_resolver)
                 .get_region(move || {
                     let region = region.clone();
                     Box::pin(async move { Some(region) })
@@ -769,7 +956,7 @@ impl std::fmt::Display for RegionDetails {
     }
 }
 
-fn create_secret_key(private_key: PrivateKey) -> anyhow::Result<SecretKey> {
+fn create_secret_key(private_key: PrivateKey, config: &Config) -> anyhow::Result<SecretKey> {
     Ok(match private_key {
         PrivateKey::Random => SecretKey::generate(),
         PrivateKey::Hex(hex) => {
@@ -778,8 +965,7 @@ fn create_secret_key(private_key: PrivateKey) -> anyhow::Result<SecretKey> {
             SecretKey::from(bytes)
         }
         PrivateKey::Local => {
-            let iroh_data_root = iroh_data_root()?;
-            let path = iroh_data_root.join("keypair");
+            let path = DataDir::new(config.data_root()?).keypair_path();
             if path.exists() {
                 let bytes = std::fs::read(&path)?;
                 let keypair = Keypair::try_from_openssh(bytes)?;
@@ -813,8 +999,45 @@ pub async fn run(command: Commands, config: &Config) -> anyhow::Result<()> {
             } else {
                 (config.derp_map(), derp_region)
             };
-            let private_key = create_secret_key(private_key)?;
-            connect(dial, private_key, remote_endpoint, derp_region, derp_map).await
+            let private_key = create_secret_key(private_key, config)?;
+            connect(
+                dial,
+                private_key,
+                remote_endpoint,
+                derp_region,
+                derp_map,
+                config.dns_resolver()?,
+            )
+            .await
+        }
+        Commands::Capture {
+            dial,
+            remote_endpoint,
+            private_key,
+            local_derper,
+            derp_region,
+            duration,
+            capacity,
+            output,
+        } => {
+            let (derp_map, derp_region) = if local_derper {
+                (Some(configure_local_derp_map()), Some(TEST_REGION_ID))
+            } else {
+                (config.derp_map(), derp_region)
+            };
+            let private_key = create_secret_key(private_key, config)?;
+            capture(
+                dial,
+                private_key,
+                remote_endpoint,
+                derp_region,
+                derp_map,
+                config.dns_resolver()?,
+                Duration::from_secs(duration),
+                capacity,
+                output,
+            )
+            .await
         }
         Commands::Accept {
             private_key,
@@ -827,9 +1050,33 @@ pub async fn run(command: Commands, config: &Config) -> anyhow::Result<()> {
             } else {
                 config.derp_map()
             };
-            let private_key = create_secret_key(private_key)?;
+            let private_key = create_secret_key(private_key, config)?;
+            let dns_resolver = config.dns_resolver()?;
             let config = TestConfig { size, iterations };
-            accept(private_key, config, derp_map).await
+            accept(private_key, config, derp_map, dns_resolver).await
+        }
+        Commands::Plot {
+            private_key,
+            local_derper,
+            interval,
+            samples,
+            size,
+            csv,
+        } => {
+            let derp_map = if local_derper {
+                Some(configure_local_derp_map())
+            } else {
+                config.derp_map()
+            };
+            let private_key = create_secret_key(private_key, config)?;
+            let dns_resolver = config.dns_resolver()?;
+            let config = plot::PlotConfig {
+                interval: Duration::from_secs(interval),
+                samples,
+                size,
+                csv,
+            };
+            plot(private_key, derp_map, dns_resolver, config).await
         }
         Commands::PortMap {
             protocol,
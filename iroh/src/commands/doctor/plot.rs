@@ -0,0 +1,278 @@
+//! Continuous measurement of the connection to a peer, rendered as plots or CSV.
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use indicatif::{HumanBytes, ProgressBar};
+use iroh_net::{derp::DerpMap, dns::DnsResolver, tls::PeerId, MagicEndpoint};
+
+use super::{send_test, TestConfig};
+
+/// Height of the plots, in lines.
+const PLOT_HEIGHT: usize = 10;
+/// Maximum width of the plots, in columns. Longer series are averaged into buckets.
+const PLOT_WIDTH: usize = 72;
+
+/// What to measure, and how often.
+#[derive(Debug)]
+pub struct PlotConfig {
+    /// Time between samples.
+    pub interval: Duration,
+    /// Number of samples to take, until interrupted if `None`.
+    pub samples: Option<u64>,
+    /// Number of bytes to send to measure the throughput.
+    pub size: u64,
+    /// Write the samples to this file as CSV.
+    pub csv: Option<PathBuf>,
+}
+
+/// One measurement of the connection.
+#[derive(Debug, Clone, Default)]
+struct Sample {
+    /// Time since the measurement started.
+    elapsed: Duration,
+    /// Time to connect to the home DERP region.
+    derp_latency: Option<Duration>,
+    /// Whether the peer is reached on a direct path, i.e. hole punching succeeded.
+    direct: bool,
+    /// Round trip time to the peer.
+    peer_latency: Option<Duration>,
+    /// Bytes per second sent to the peer.
+    throughput: Option<f64>,
+}
+
+impl Sample {
+    const CSV_HEADER: &'static str =
+        "elapsed_secs,derp_latency_ms,direct,peer_latency_ms,throughput_bytes_per_sec";
+
+    fn to_csv(&self) -> String {
+        let ms = |d: Option<Duration>| {
+            d.map(|d| format!("{:.1}", d.as_secs_f64() * 1000.0))
+                .unwrap_or_default()
+        };
+        format!(
+            "{:.1},{},{},{},{}",
+            self.elapsed.as_secs_f64(),
+            ms(self.derp_latency),
+            self.direct,
+            ms(self.peer_latency),
+            self.throughput
+                .map(|t| format!("{t:.0}"))
+                .unwrap_or_default()
+        )
+    }
+}
+
+/// Measure the connection to `peer` until the configured number of samples is taken or
+/// the measurement is interrupted, then print plots of the samples.
+pub async fn plot(
+    endpoint: &MagicEndpoint,
+    connection: quinn::Connection,
+    peer: PeerId,
+    derp_map: Option<DerpMap>,
+    dns_resolver: DnsResolver,
+    config: PlotConfig,
+) -> anyhow::Result<()> {
+    let mut csv = match &config.csv {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            let mut file = std::io::BufWriter::new(file);
+            writeln!(file, "{}", Sample::CSV_HEADER)?;
+            Some(file)
+        }
+        None => None,
+    };
+    let test_config = TestConfig {
+        size: config.size,
+        iterations: None,
+    };
+    let started = Instant::now();
+    let mut samples = Vec::new();
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    println!("{}", Sample::CSV_HEADER);
+    while config.samples.map_or(true, |n| (samples.len() as u64) < n) {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        let measuring = measure(
+            endpoint,
+            &connection,
+            peer,
+            derp_map.as_ref(),
+            &dns_resolver,
+            &test_config,
+            started,
+        );
+        let sample = tokio::select! {
+            sample = measuring => sample,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        println!("{}", sample.to_csv());
+        if let Some(csv) = csv.as_mut() {
+            writeln!(csv, "{}", sample.to_csv())?;
+            csv.flush()?;
+        }
+        samples.push(sample);
+    }
+    connection.close(0u32.into(), b"done");
+    print_plots(&samples);
+    Ok(())
+}
+
+async fn measure(
+    endpoint: &MagicEndpoint,
+    connection: &quinn::Connection,
+    peer: PeerId,
+    derp_map: Option<&DerpMap>,
+    dns_resolver: &DnsResolver,
+    config: &TestConfig,
+    started: Instant,
+) -> Sample {
+    let mut sample = Sample {
+        elapsed: started.elapsed(),
+        ..Default::default()
+    };
+    if let (Some(derp_map), Some(region)) = (derp_map, endpoint.my_derp().await) {
+        sample.derp_latency = derp_latency(derp_map, dns_resolver, region).await;
+    }
+    if let Ok(Some(info)) = endpoint.connection_info(peer).await {
+        sample.direct = info.has_direct_connection;
+        sample.peer_latency = info.latency;
+    }
+    // the progress of a single test is not interesting, only its result
+    match send_test(connection, config, &ProgressBar::hidden()).await {
+        Ok(elapsed) => sample.throughput = Some(config.size as f64 / elapsed.as_secs_f64()),
+        Err(cause) => tracing::warn!("throughput test failed: {cause:#}"),
+    }
+    sample
+}
+
+/// Time to connect a new client to a DERP region.
+async fn derp_latency(
+    derp_map: &DerpMap,
+    dns_resolver: &DnsResolver,
+    region_id: u16,
+) -> Option<Duration> {
+    let region = derp_map.get_region(region_id)?.clone();
+    let client = iroh_net::derp::http::ClientBuilder::new()
+        .dns_resolver(dns_resolver.clone())
+        .get_region(move || {
+            let region = region.clone();
+            Box::pin(async move { Some(region) })
+        })
+        .build(iroh_net::key::node::SecretKey::generate())
+        .ok()?;
+    let start = Instant::now();
+    let res = tokio::time::timeout(Duration::from_secs(2), client.connect()).await;
+    client.close().await;
+    match res {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        _ => None,
+    }
+}
+
+fn print_plots(samples: &[Sample]) {
+    if samples.is_empty() {
+        return;
+    }
+    let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+    let derp = samples
+        .iter()
+        .map(|s| ms(s.derp_latency))
+        .collect::<Vec<_>>();
+    let peer = samples
+        .iter()
+        .map(|s| ms(s.peer_latency))
+        .collect::<Vec<_>>();
+    let throughput = samples.iter().map(|s| s.throughput).collect::<Vec<_>>();
+    println!();
+    print!("{}", ascii_plot("DERP latency (ms)", &derp));
+    println!();
+    print!("{}", ascii_plot("Peer latency (ms)", &peer));
+    println!();
+    print!("{}", ascii_plot("Throughput (bytes/s)", &throughput));
+    let direct = samples.iter().filter(|s| s.direct).count();
+    let total = samples.iter().filter_map(|s| s.throughput).sum::<f64>();
+    let measured = throughput.iter().flatten().count().max(1);
+    println!();
+    println!(
+        "Direct connection in {direct} of {} samples ({:.0}%), average throughput {}/s",
+        samples.len(),
+        direct as f64 * 100.0 / samples.len() as f64,
+        HumanBytes((total / measured as f64) as u64)
+    );
+}
+
+/// Render a series as an ASCII plot, with gaps for missing values.
+///
+/// Series longer than [`PLOT_WIDTH`] are averaged into buckets of consecutive values.
+fn ascii_plot(title: &str, values: &[Option<f64>]) -> String {
+    let buckets = bucket(values, PLOT_WIDTH);
+    let max = buckets.iter().flatten().copied().fold(0.0, f64::max);
+    let mut out = format!("{title}\n");
+    if max <= 0.0 {
+        out.push_str("  no data\n");
+        return out;
+    }
+    for row in (1..=PLOT_HEIGHT).rev() {
+        let threshold = max * (row as f64 - 0.5) / PLOT_HEIGHT as f64;
+        let label = if row == PLOT_HEIGHT {
+            format!("{max:>10.1}")
+        } else {
+            " ".repeat(10)
+        };
+        let line = buckets
+            .iter()
+            .map(|value| match value {
+                Some(value) if *value >= threshold => '*',
+                _ => ' ',
+            })
+            .collect::<String>();
+        out.push_str(&format!("{label} |{}\n", line.trim_end()));
+    }
+    out.push_str(&format!("{:>10.1} +{}\n", 0.0, "-".repeat(buckets.len())));
+    out
+}
+
+/// Average consecutive values into at most `width` buckets, ignoring missing values.
+fn bucket(values: &[Option<f64>], width: usize) -> Vec<Option<f64>> {
+    let size = (values.len() + width - 1) / width.max(1);
+    values
+        .chunks(size.max(1))
+        .map(|chunk| {
+            let present = chunk.iter().flatten().collect::<Vec<_>>();
+            if present.is_empty() {
+                None
+            } else {
+                Some(present.iter().copied().sum::<f64>() / present.len() as f64)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plot_buckets_and_gaps() {
+        let values = (0..200).map(|i| Some(i as f64)).collect::<Vec<_>>();
+        let buckets = bucket(&values, 72);
+        assert!(buckets.len() <= 72);
+        assert_eq!(buckets[0], Some(1.0));
+
+        let values = [Some(1.0), None, Some(2.0)];
+        assert_eq!(bucket(&values, 72), vec![Some(1.0), None, Some(2.0)]);
+        let plot = ascii_plot("test", &values);
+        let lines = plot.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "test");
+        // the maximum reaches the top row, the gap is empty on every row
+        assert!(lines[1].ends_with("|  *"));
+        assert!(lines[PLOT_HEIGHT].ends_with("|* *"));
+        assert_eq!(ascii_plot("empty", &[None]), "empty\n  no data\n");
+    }
+}
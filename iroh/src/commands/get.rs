@@ -1,7 +1,8 @@
-use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{Context as _, Result};
+use clap::Args;
 use console::style;
 use futures::StreamExt;
 use indicatif::{
@@ -9,8 +10,14 @@ use indicatif::{
 };
 use iroh::{
     collection::{Collection, IrohCollectionParser},
+    dial::ProviderAddr,
     rpc_protocol::ShareRequest,
-    util::{io::pathbuf_from_name, progress::ProgressSliceWriter},
+    util::{
+        download_queue::DownloadPriority,
+        io::pathbuf_from_name,
+        limits::{DownloadBudget, DownloadLimits},
+        progress::ProgressSliceWriter,
+    },
 };
 use iroh_bytes::{baomap::range_collections::RangeSet2, provider::ShareProgress};
 use iroh_bytes::{
@@ -18,20 +25,61 @@ use iroh_bytes::{
         self,
         fsm::{self, ConnectedNext, EndBlobNext},
     },
-    protocol::{GetRequest, RangeSpecSeq, Request, RequestToken},
+    keyed::HashDomain,
+    protocol::{GetRequest, KeyedGetRequest, RangeSpecSeq, RequestToken},
     Hash,
 };
 use iroh_io::ConcatenateSliceWriter;
 use tokio::sync::mpsc;
 
+use super::progress::MultiBar;
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub struct GetInteractive {
     pub rt: iroh_bytes::util::runtime::Handle,
     pub hash: Hash,
     pub opts: iroh::dial::Options,
+    pub alternatives: Vec<ProviderAddr>,
     pub token: Option<RequestToken>,
+    pub domain: HashDomain,
     pub single: bool,
+    pub metadata: bool,
+    pub limits: DownloadLimits,
+}
+
+/// Limits for downloads from untrusted providers.
+#[derive(Args, Debug, Clone)]
+pub struct LimitArgs {
+    /// Abort if the download is larger than this many bytes
+    #[clap(long)]
+    max_size: Option<u64>,
+    /// Abort if the collection has more than this many files
+    #[clap(long)]
+    max_files: Option<u64>,
+    /// Abort if a single file is larger than this many bytes
+    #[clap(long)]
+    max_file_size: Option<u64>,
+    /// Abort after reading this many bytes from providers, over all attempts
+    #[clap(long)]
+    max_bytes_read: Option<u64>,
+    /// Abort if the download is not complete after this many seconds
+    #[clap(long)]
+    timeout: Option<u64>,
+}
+
+impl From<LimitArgs> for DownloadLimits {
+    fn from(args: LimitArgs) -> Self {
+        DownloadLimits {
+            max_total_size: args.max_size,
+            max_blobs: args.max_files,
+            max_blob_size: args.max_file_size,
+            max_bytes_read: args.max_bytes_read,
+            deadline: args
+                .timeout
+                .map(|secs| SystemTime::now() + Duration::from_secs(secs)),
+        }
+    }
 }
 
 /// Write the given data.
@@ -40,10 +88,18 @@ pub fn write(data: impl AsRef<str>) {
 }
 
 impl GetInteractive {
-    fn new_request(&self, query: RangeSpecSeq) -> Request {
-        GetRequest::new(self.hash, query)
-            .with_token(self.token.clone())
-            .into()
+    /// Start the request for `query`, by name if the hash is in a keyed namespace.
+    fn start(&self, connection: quinn::Connection, query: RangeSpecSeq) -> fsm::AtInitial {
+        match self.domain {
+            HashDomain::Plain => {
+                let request = GetRequest::new(self.hash, query).with_token(self.token.clone());
+                fsm::start(connection, request.into())
+            }
+            HashDomain::Keyed(name) => {
+                let request = KeyedGetRequest::new(name, query).with_token(self.token.clone());
+                fsm::start_named(connection, request, self.hash)
+            }
+        }
     }
 
     /// Get into a file or directory
@@ -90,44 +146,66 @@ impl GetInteractive {
                 peer: self.opts.peer_id,
                 addrs: self.opts.addrs,
                 derp_region: self.opts.derp_region,
+                alternatives: self.alternatives,
                 token: self.token,
+                domain: self.domain,
                 in_place: true,
                 out: Some(out),
+                checksums: vec![],
+                metadata: self.metadata,
+                retry: Default::default(),
+                limits: self.limits,
+                priority: DownloadPriority::Interactive,
             })
             .await?;
-        let pb = make_download_pb();
-        let mut sizes = BTreeMap::new();
+        let mut bars = Some(MultiBar::new("Downloading"));
+        let mut request_id = None;
         while let Some(x) = stream.next().await {
             match x? {
+                ShareProgress::Started { request_id: id } => {
+                    tracing::debug!("download request {id}");
+                    request_id = Some(id);
+                }
                 ShareProgress::Connected => {
                     write(format!("{} Requesting ...", style("[2/3]").bold().dim()));
                 }
+                ShareProgress::Retry {
+                    attempt,
+                    cause,
+                    delay,
+                } => {
+                    write(format!(
+                        "Attempt {} failed: {}, retrying in {}",
+                        attempt,
+                        cause,
+                        HumanDuration(delay)
+                    ));
+                }
                 ShareProgress::FoundCollection {
                     total_blobs_size,
                     num_blobs,
                     ..
                 } => {
-                    init_download_progress(
-                        &pb,
+                    write(format!("{} Downloading ...", style("[3/3]").bold().dim()));
+                    write(format!(
+                        "  {} file(s) with total transfer size {}",
                         num_blobs.unwrap_or_default(),
-                        total_blobs_size.unwrap_or_default(),
-                    )?;
+                        HumanBytes(total_blobs_size.unwrap_or_default())
+                    ));
                 }
-                ShareProgress::Found { id, size, .. } => {
-                    sizes.insert(id, (size, 0));
+                ShareProgress::Found { id, hash, size } => {
+                    if let Some(bars) = bars.as_mut() {
+                        bars.found(id, hash.to_string(), size);
+                    }
                 }
                 ShareProgress::Progress { id, offset } => {
-                    if let Some((_, current)) = sizes.get_mut(&id) {
-                        *current = offset;
-                        let total = sizes.values().map(|(_, current)| current).sum::<u64>();
-                        pb.set_position(total);
+                    if let Some(bars) = bars.as_mut() {
+                        bars.progress(id, offset);
                     }
                 }
                 ShareProgress::Done { id } => {
-                    if let Some((size, current)) = sizes.get_mut(&id) {
-                        *current = *size;
-                        let total = sizes.values().map(|(_, current)| current).sum::<u64>();
-                        pb.set_position(total);
+                    if let Some(bars) = bars.as_mut() {
+                        bars.done(id);
                     }
                 }
                 ShareProgress::NetworkDone {
@@ -135,7 +213,9 @@ impl GetInteractive {
                     elapsed,
                     ..
                 } => {
-                    pb.finish_and_clear();
+                    if let Some(bars) = bars.take() {
+                        bars.finish();
+                    }
                     write(format!(
                         "Transferred {} in {}, {}/s",
                         HumanBytes(bytes_read),
@@ -146,9 +226,21 @@ impl GetInteractive {
                 ShareProgress::AllDone => {
                     break;
                 }
+                ShareProgress::Abort(cause) => {
+                    if let Some(bars) = bars.take() {
+                        bars.finish();
+                    }
+                    match request_id {
+                        Some(id) => anyhow::bail!("download failed (request {id}): {cause}"),
+                        None => anyhow::bail!("download failed: {cause}"),
+                    }
+                }
                 _ => {}
             }
         }
+        if let Some(bars) = bars.take() {
+            bars.finish();
+        }
         tokio::fs::remove_dir_all(temp_dir).await?;
         Ok(())
     }
@@ -157,7 +249,9 @@ impl GetInteractive {
         if let Some(out_dir) = out_dir {
             self.get_to_dir(out_dir).await
         } else {
-            self.get_to_stdout().await
+            // the node enforces the deadline of downloads to a directory
+            let limits = self.limits;
+            limits.with_deadline(self.get_to_stdout()).await
         }
     }
 
@@ -174,18 +268,29 @@ impl GetInteractive {
         };
 
         let pb = make_download_pb();
-        let request = self.new_request(query).with_token(self.token.clone());
-        let connection = iroh::dial::dial(self.opts).await?;
-        let response = fsm::start(connection, request);
+        let mut opts = vec![self.opts.clone()];
+        opts.extend(
+            self.alternatives
+                .iter()
+                .map(|provider| iroh::dial::Options {
+                    peer_id: provider.peer,
+                    addrs: provider.addrs.clone(),
+                    derp_region: provider.derp_region,
+                    ..self.opts.clone()
+                }),
+        );
+        let (connection, _peer) = iroh::dial::dial_any(opts).await?;
+        let response = self.start(connection, query);
         let connected = response.next().await?;
         write(format!("{} Requesting ...", style("[2/3]").bold().dim()));
         let ConnectedNext::StartRoot(curr) = connected.next().await? else {
             anyhow::bail!("expected root to be present");
         };
+        let mut budget = self.limits.budget();
         let stats = if self.single {
-            get_to_stdout_single(curr).await?
+            get_to_stdout_single(curr, &mut budget).await?
         } else {
-            get_to_stdout_multi(curr, pb.clone()).await?
+            get_to_stdout_multi(curr, &mut budget, pb.clone()).await?
         };
         pb.finish_and_clear();
         write(format!(
@@ -199,8 +304,14 @@ impl GetInteractive {
     }
 }
 
-async fn get_to_stdout_single(curr: get::fsm::AtStartRoot) -> Result<get::Stats> {
-    let curr = curr.next();
+async fn get_to_stdout_single(
+    curr: get::fsm::AtStartRoot,
+    budget: &mut DownloadBudget,
+) -> Result<get::Stats> {
+    let (curr, size) = curr.next().next().await?;
+    budget.blob(size)?;
+    // downloads to stdout always read whole blobs
+    budget.read(size)?;
     let mut writer = ConcatenateSliceWriter::new(tokio::io::stdout());
     let curr = curr.write_all(&mut writer).await?;
     let EndBlobNext::Closing(curr) = curr.next() else {
@@ -209,13 +320,21 @@ async fn get_to_stdout_single(curr: get::fsm::AtStartRoot) -> Result<get::Stats>
     Ok(curr.next().await?)
 }
 
-async fn get_to_stdout_multi(curr: get::fsm::AtStartRoot, pb: ProgressBar) -> Result<get::Stats> {
+async fn get_to_stdout_multi(
+    curr: get::fsm::AtStartRoot,
+    budget: &mut DownloadBudget,
+    pb: ProgressBar,
+) -> Result<get::Stats> {
     let (mut next, collection) = {
-        let curr = curr.next();
+        // check the announced size before buffering the collection
+        let (curr, size) = curr.next().next().await?;
+        budget.blob(size)?;
+        budget.read(size)?;
         let (curr, collection_data) = curr.concatenate_into_vec().await?;
         let collection = Collection::from_bytes(&collection_data)?;
         let count = collection.total_entries();
         let missing_bytes = collection.total_blobs_size();
+        budget.collection(count, Some(missing_bytes))?;
         write(format!("{} Downloading ...", style("[3/3]").bold().dim()));
         write(format!(
             "  {} file(s) with total transfer size {}",
@@ -248,7 +367,9 @@ async fn get_to_stdout_multi(curr: get::fsm::AtStartRoot, pb: ProgressBar) -> Re
         };
         pb.set_message(format!("Receiving '{}'...", name.display()));
         pb.reset();
-        let header = start.next(blob.hash);
+        let (content, size) = start.next(blob.hash).next().await?;
+        budget.blob(size)?;
+        budget.read(size)?;
         let (on_write, mut receive_on_write) = mpsc::channel(1);
         let pb2 = pb.clone();
         // create task that updates the progress bar
@@ -259,7 +380,7 @@ async fn get_to_stdout_multi(curr: get::fsm::AtStartRoot, pb: ProgressBar) -> Re
         });
         let mut io_writer =
             ProgressSliceWriter::new(ConcatenateSliceWriter::new(tokio::io::stdout()), on_write);
-        let curr = header.write_all(&mut io_writer).await?;
+        let curr = content.write_all(&mut io_writer).await?;
         drop(io_writer);
         // wait for the progress task to finish, only after dropping the writer
         progress_task.await.ok();
@@ -288,17 +409,3 @@ fn make_download_pb() -> ProgressBar {
     );
     pb
 }
-
-fn init_download_progress(pb: &ProgressBar, count: u64, missing_bytes: u64) -> Result<()> {
-    write(format!("{} Downloading ...", style("[3/3]").bold().dim()));
-    write(format!(
-        "  {} file(s) with total transfer size {}",
-        count,
-        HumanBytes(missing_bytes)
-    ));
-    pb.set_length(missing_bytes);
-    pb.reset();
-    pb.set_draw_target(ProgressDrawTarget::stderr());
-
-    Ok(())
-}
@@ -0,0 +1,150 @@
+//! Introspection of the command line interface, for shells and other tools wrapping it.
+use anyhow::Result;
+use clap::{ArgAction, CommandFactory};
+use clap_complete::Shell;
+use serde::Serialize;
+
+use super::Cli;
+
+/// Description of a command, with its arguments and subcommands.
+#[derive(Debug, Serialize)]
+struct CommandSchema {
+    name: String,
+    about: Option<String>,
+    args: Vec<ArgSchema>,
+    subcommands: Vec<CommandSchema>,
+}
+
+/// Description of an argument of a command.
+#[derive(Debug, Serialize)]
+struct ArgSchema {
+    id: String,
+    /// `None` for positional arguments.
+    long: Option<String>,
+    short: Option<char>,
+    help: Option<String>,
+    required: bool,
+    /// Whether the argument is a flag, not taking a value.
+    flag: bool,
+    /// Whether the argument can be given multiple times.
+    multiple: bool,
+    default_values: Vec<String>,
+    possible_values: Vec<String>,
+}
+
+impl CommandSchema {
+    fn new(cmd: &clap::Command) -> Self {
+        Self {
+            name: cmd.get_name().to_string(),
+            about: cmd.get_about().map(|about| about.to_string()),
+            args: cmd
+                .get_arguments()
+                .filter(|arg| !arg.is_hide_set())
+                .map(ArgSchema::new)
+                .collect(),
+            subcommands: cmd
+                .get_subcommands()
+                .filter(|sub| !sub.is_hide_set())
+                .map(CommandSchema::new)
+                .collect(),
+        }
+    }
+
+    fn print_tree(&self, path: &str) {
+        let path = if path.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{path} {}", self.name)
+        };
+        println!("{path}");
+        for arg in &self.args {
+            let name = match (&arg.long, arg.short) {
+                (Some(long), _) => format!("--{long}"),
+                (None, Some(short)) => format!("-{short}"),
+                (None, None) => format!("<{}>", arg.id),
+            };
+            let value = if arg.flag { "" } else { " <value>" };
+            println!("    {name}{value}");
+        }
+        for sub in &self.subcommands {
+            sub.print_tree(&path);
+        }
+    }
+}
+
+impl ArgSchema {
+    fn new(arg: &clap::Arg) -> Self {
+        Self {
+            id: arg.get_id().to_string(),
+            long: arg.get_long().map(ToString::to_string),
+            short: arg.get_short(),
+            help: arg.get_help().map(|help| help.to_string()),
+            required: arg.is_required_set(),
+            flag: matches!(
+                arg.get_action(),
+                ArgAction::SetTrue
+                    | ArgAction::SetFalse
+                    | ArgAction::Count
+                    | ArgAction::Help
+                    | ArgAction::Version
+            ),
+            multiple: matches!(arg.get_action(), ArgAction::Append | ArgAction::Count),
+            default_values: arg
+                .get_default_values()
+                .iter()
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect(),
+            possible_values: arg
+                .get_possible_values()
+                .iter()
+                .map(|value| value.get_name().to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Print the completion script of the given shell.
+pub fn completions(shell: Shell) {
+    let mut cmd = Cli::command();
+    clap_complete::generate(shell, &mut cmd, "iroh", &mut std::io::stdout());
+}
+
+/// Print the tree of commands and their arguments.
+pub fn schema(json: bool) -> Result<()> {
+    let mut cmd = Cli::command();
+    // fill in the generated help and version flags
+    cmd.build();
+    let schema = CommandSchema::new(&cmd);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+    } else {
+        schema.print_tree("");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_contains_all_commands() {
+        let mut cmd = Cli::command();
+        cmd.build();
+        let schema = CommandSchema::new(&cmd);
+        let names = schema
+            .subcommands
+            .iter()
+            .map(|sub| sub.name.as_str())
+            .collect::<Vec<_>>();
+        for name in ["provide", "get", "blob", "completions", "cli-schema"] {
+            assert!(names.contains(&name), "{name} missing from {names:?}");
+        }
+        let get = schema.subcommands.iter().find(|sub| sub.name == "get");
+        let args = &get.unwrap().args;
+        let out = args.iter().find(|arg| arg.id == "out").unwrap();
+        assert_eq!(out.long.as_deref(), Some("out"));
+        assert!(!out.flag);
+        assert!(serde_json::to_string(&schema).is_ok());
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use iroh::data_dir::{DataDir, MigrateOptions, Migrated};
+
+use crate::config::Config;
+
+pub async fn run(config: &Config, dry_run: bool, backup: bool) -> Result<()> {
+    let data_dir = DataDir::new(config.data_root()?);
+    let opts = MigrateOptions { dry_run, backup };
+    let migrated = {
+        let data_dir = data_dir.clone();
+        tokio::task::spawn_blocking(move || data_dir.migrate(opts)).await??
+    };
+    if migrated.applied.is_empty() {
+        println!(
+            "{} is up to date, layout version {}",
+            data_dir.root().display(),
+            migrated.to
+        );
+    } else if dry_run {
+        println!(
+            "Would migrate {} from layout version {} to {}:",
+            data_dir.root().display(),
+            migrated.from,
+            migrated.to
+        );
+        for migration in &migrated.applied {
+            println!("  {}", migration.description);
+        }
+    } else {
+        print_migrated(&data_dir, &migrated);
+    }
+    Ok(())
+}
+
+/// Prints the migrations that were applied, if any.
+pub fn print_migrated(data_dir: &DataDir, migrated: &Migrated) {
+    if migrated.applied.is_empty() {
+        return;
+    }
+    println!(
+        "Migrated {} from layout version {} to {}:",
+        data_dir.root().display(),
+        migrated.from,
+        migrated.to
+    );
+    for migration in &migrated.applied {
+        println!("  {}", migration.description);
+    }
+    if let Some(backup) = &migrated.backup {
+        println!("The previous layout was backed up to {}", backup.display());
+    }
+}
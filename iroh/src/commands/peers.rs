@@ -0,0 +1,179 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use clap::Subcommand;
+use indicatif::HumanBytes;
+use iroh::rpc_protocol::{
+    HolePunchStatsRequest, PeerAddRequest, PeerForgetRequest, PeerPingRequest, PeersListRequest,
+    RelayUsageRequest,
+};
+use iroh_net::magicsock::OutcomeCounts;
+use iroh_net::tls::PeerId;
+
+use super::{make_rpc_client, status::fmt_conn_type, DEFAULT_RPC_PORT};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// List the peers known to the running provider.
+    List {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Show how often connections to peers were upgraded to direct paths.
+    ///
+    /// Outcomes are broken down by the NAT type of the provider and of the peer at the
+    /// time of the attempt.
+    Connectivity {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Show how much traffic to and from each peer was relayed over DERP.
+    ///
+    /// Peers with a lot of relayed traffic are the ones worth getting a direct path to.
+    RelayUsage {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Ping a peer from the running provider.
+    Ping {
+        /// PeerId of the peer
+        peer: PeerId,
+        /// Ping by opening a QUIC connection instead of sending a disco ping
+        #[clap(long, default_value_t = false)]
+        quic: bool,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Add addresses to dial a peer with.
+    Add {
+        /// PeerId of the peer
+        peer: PeerId,
+        /// Direct addresses of the peer
+        #[clap(long, short)]
+        addrs: Vec<SocketAddr>,
+        /// DERP region of the peer
+        #[clap(long)]
+        region: Option<u16>,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Forget the addresses and connection state of a peer.
+    Forget {
+        /// PeerId of the peer
+        peer: PeerId,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Commands::List { rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let response = client.rpc(PeersListRequest).await?;
+                for peer in response.peers {
+                    match peer.peer_id {
+                        Some(peer_id) => println!("{peer_id}"),
+                        None => println!("{}", peer.node_key),
+                    }
+                    println!("  path: {}", fmt_conn_type(peer.conn_type));
+                    if let Some(latency) = peer.latency {
+                        println!("  latency: {}ms", latency.as_millis());
+                    }
+                    if let Some(region) = peer.derp_region {
+                        println!("  DERP region: {region}");
+                    }
+                    for addr in peer.addrs {
+                        println!("  addr: {addr}");
+                    }
+                }
+                Ok(())
+            }
+            Commands::Connectivity { rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let stats = client.rpc(HolePunchStatsRequest).await?.stats;
+                println!("local NAT: {}", stats.local);
+                println!("total: {}", fmt_outcomes(&stats.total));
+                for outcomes in stats.by_nat_type {
+                    println!(
+                        "  {} <-> {}: {}",
+                        outcomes.local,
+                        outcomes.remote,
+                        fmt_outcomes(&outcomes.counts)
+                    );
+                }
+                Ok(())
+            }
+            Commands::RelayUsage { rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let usage = client.rpc(RelayUsageRequest).await?.usage;
+                for peer in usage {
+                    println!(
+                        "{}: sent {} in {} packets, received {} in {} packets",
+                        peer.peer,
+                        HumanBytes(peer.bytes_sent),
+                        peer.packets_sent,
+                        HumanBytes(peer.bytes_recv),
+                        peer.packets_recv
+                    );
+                }
+                Ok(())
+            }
+            Commands::Ping {
+                peer,
+                quic,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let response = client.rpc(PeerPingRequest { peer, quic }).await??;
+                println!(
+                    "pong from {peer} via {} in {}ms",
+                    fmt_conn_type(response.conn_type),
+                    response.latency.as_millis()
+                );
+                Ok(())
+            }
+            Commands::Add {
+                peer,
+                addrs,
+                region,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client
+                    .rpc(PeerAddRequest {
+                        peer,
+                        addrs,
+                        derp_region: region,
+                    })
+                    .await??;
+                println!("Added {peer}");
+                Ok(())
+            }
+            Commands::Forget { peer, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                client.rpc(PeerForgetRequest { peer }).await??;
+                println!("Forgot {peer}");
+                Ok(())
+            }
+        }
+    }
+}
+
+fn fmt_outcomes(counts: &OutcomeCounts) -> String {
+    let ratio = match counts.direct_ratio() {
+        Some(ratio) => format!("{:.0}% direct", ratio * 100.0),
+        None => "no attempts".to_string(),
+    };
+    format!(
+        "{ratio} (ipv4 {}, ipv6 {}, relay only {})",
+        counts.direct_ipv4, counts.direct_ipv6, counts.relay_only
+    )
+}
@@ -0,0 +1,166 @@
+//! Rendering of the progress of transfers with several files.
+//!
+//! On a terminal every file in flight gets its own bar, above a bar for the whole
+//! transfer showing the throughput and the estimated time left. Otherwise, e.g. when
+//! stderr is redirected to a log file, the renderer is compact: it prints a line for
+//! every finished file and a summary of the whole transfer every few seconds.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+
+/// How often the compact renderer prints a summary.
+const COMPACT_INTERVAL: Duration = Duration::from_secs(5);
+
+const FILE_STYLE: &str =
+    "{spinner:.green} [{bar:40.cyan/blue}] {msg} {bytes}/{total_bytes} ({bytes_per_sec})";
+const TOTAL_STYLE: &str =
+    "{prefix:.bold} [{wide_bar:.green/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, eta {eta})";
+
+#[derive(Debug)]
+struct File {
+    name: String,
+    size: u64,
+    offset: u64,
+    bar: Option<ProgressBar>,
+}
+
+/// Progress of a transfer of several files, identified by the ids of progress events.
+#[derive(Debug)]
+pub struct MultiBar {
+    mp: MultiProgress,
+    /// The bar of the whole transfer, `None` in compact mode.
+    total: Option<ProgressBar>,
+    files: HashMap<u64, File>,
+    total_size: u64,
+    /// Bytes of files that are done.
+    done_size: u64,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl MultiBar {
+    /// Creates a renderer, compact if stderr is not a terminal.
+    pub fn new(prefix: &'static str) -> Self {
+        let compact = !console::Term::stderr().features().is_attended();
+        Self::with_mode(prefix, compact)
+    }
+
+    /// Creates a renderer, compact or with bars.
+    pub fn with_mode(prefix: &'static str, compact: bool) -> Self {
+        let mp = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+        let total = (!compact).then(|| {
+            let pb = mp.add(ProgressBar::new(0));
+            pb.set_style(ProgressStyle::with_template(TOTAL_STYLE).unwrap());
+            pb.set_prefix(prefix);
+            pb.enable_steady_tick(Duration::from_millis(500));
+            pb
+        });
+        let now = Instant::now();
+        Self {
+            mp,
+            total,
+            files: HashMap::new(),
+            total_size: 0,
+            done_size: 0,
+            started: now,
+            last_report: now,
+        }
+    }
+
+    /// A file of the transfer was found.
+    pub fn found(&mut self, id: u64, name: String, size: u64) {
+        self.total_size += size;
+        let bar = self.total.as_ref().map(|total| {
+            total.set_length(self.total_size);
+            // new files go above the bar of the whole transfer
+            let pb = self.mp.insert_before(total, ProgressBar::new(size));
+            pb.set_style(
+                ProgressStyle::with_template(FILE_STYLE)
+                    .unwrap()
+                    .progress_chars("=>-"),
+            );
+            pb.set_message(name.clone());
+            pb
+        });
+        let file = File {
+            name,
+            size,
+            offset: 0,
+            bar,
+        };
+        self.files.insert(id, file);
+    }
+
+    /// A file of the transfer made progress.
+    pub fn progress(&mut self, id: u64, offset: u64) {
+        if let Some(file) = self.files.get_mut(&id) {
+            file.offset = offset;
+            if let Some(bar) = &file.bar {
+                bar.set_position(offset);
+            }
+        }
+        self.update();
+    }
+
+    /// A file of the transfer is done.
+    pub fn done(&mut self, id: u64) {
+        if let Some(file) = self.files.remove(&id) {
+            self.done_size += file.size;
+            match file.bar {
+                Some(bar) => {
+                    bar.finish_and_clear();
+                    self.mp.remove(&bar);
+                }
+                None => eprintln!("done {} ({})", file.name, HumanBytes(file.size)),
+            }
+        }
+        self.update();
+    }
+
+    /// The whole transfer is done, or failed.
+    pub fn finish(self) {
+        for file in self.files.values() {
+            if let Some(bar) = &file.bar {
+                bar.finish_and_clear();
+            }
+        }
+        if let Some(total) = &self.total {
+            total.finish_and_clear();
+        }
+        self.mp.clear().ok();
+    }
+
+    fn position(&self) -> u64 {
+        self.done_size + self.files.values().map(|file| file.offset).sum::<u64>()
+    }
+
+    fn update(&mut self) {
+        let position = self.position();
+        if let Some(total) = &self.total {
+            total.set_position(position);
+            return;
+        }
+        if self.last_report.elapsed() < COMPACT_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = position as f64 / elapsed;
+        let eta = if rate > 0.0 {
+            let left = self.total_size.saturating_sub(position) as f64 / rate;
+            HumanDuration(Duration::from_secs_f64(left)).to_string()
+        } else {
+            "unknown".to_string()
+        };
+        eprintln!(
+            "{}/{} ({}/s, eta {})",
+            HumanBytes(position),
+            HumanBytes(self.total_size),
+            HumanBytes(rate as u64),
+            eta
+        );
+    }
+}
@@ -1,44 +1,82 @@
 use std::{
     fmt,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::SocketAddr,
     path::PathBuf,
     str::FromStr,
     sync::Arc,
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{ensure, Context, Result};
 use iroh::{
-    baomap::flat,
+    baomap::{
+        disk_space::Watermarks, encryption::Passphrase, flat, fsync::FsyncPolicy,
+        handle_cache::HandleLimits, outboard::OutboardFormat, uring::IoBackend,
+        validation::ValidationSchedule,
+    },
+    cluster::ClusterConfig,
     collection::IrohCollectionParser,
-    node::{Node, StaticTokenAuthHandler},
-    rpc_protocol::{ProvideRequest, ProviderRequest, ProviderResponse, ProviderService},
+    data_dir::{DataDir, MigrateOptions},
+    dial::{Ticket, TicketOptions},
+    mirror::MirrorConfig,
+    node::{LogFilterHandler, Node, NodePaths, StaticTokenAuthHandler},
+    rpc_protocol::ProvideRequest,
+    util::{expiring_token::ExpiringTokenAuthHandler, fs::ImportFilter, memory::MemoryLimits},
 };
-use iroh_bytes::{baomap::Store, protocol::RequestToken, util::runtime};
-use iroh_net::{derp::DerpMap, tls::Keypair};
-use quic_rpc::{transport::quinn::QuinnServerEndpoint, ServiceEndpoint};
-use tokio::io::AsyncWriteExt;
+use iroh_bytes::{
+    baomap::Store, protocol::RequestToken, provider::RequestAuthorizationHandler, util::runtime,
+};
+use iroh_net::{derp::DerpMap, dns::DnsResolver, magic_endpoint::qlog::QlogConfig, tls::Keypair};
 use tracing::{info_span, Instrument};
 
-use crate::config::iroh_data_root;
-
 use super::{
     add::{aggregate_add_response, print_add_response},
-    MAX_RPC_CONNECTIONS, MAX_RPC_STREAMS, RPC_ALPN,
+    seed::{self, Manifest},
 };
 
+/// Environment variable with the passphrase of an encrypted store.
+const STORE_PASSPHRASE_ENV: &str = "IROH_STORE_PASSPHRASE";
+
 #[derive(Debug)]
 pub struct ProvideOptions {
     pub addr: SocketAddr,
     pub rpc_port: ProviderRpcPort,
     pub keylog: bool,
     pub request_token: Option<RequestToken>,
+    pub ticket_ttl: Option<Duration>,
+    pub clock_skew: Duration,
     pub derp_map: Option<DerpMap>,
+    pub watermarks: Option<Watermarks>,
+    pub validation: Option<ValidationSchedule>,
+    pub memory_limits: Option<MemoryLimits>,
+    pub log_filter: Option<Arc<dyn LogFilterHandler>>,
+    pub fsync_policy: FsyncPolicy,
+    pub io_backend: IoBackend,
+    pub outboard_format: OutboardFormat,
+    pub file_handles: HandleLimits,
+    pub coalesce_reads: bool,
+    pub chunk_cache_bytes: u64,
+    pub hash_threads: Option<usize>,
+    pub trash_retention: Duration,
+    pub encrypt_store: bool,
+    pub mirrors: Vec<MirrorConfig>,
+    pub cluster: Option<ClusterConfig>,
+    pub ticket_options: TicketOptions,
+    pub serve_partial: bool,
+    pub serve_listing: bool,
+    pub keyed: bool,
+    pub paths: NodePaths,
+    pub rpc_socket: Option<PathBuf>,
+    pub qlog: Option<QlogConfig>,
+    pub dns_resolver: DnsResolver,
 }
 
 pub async fn run(
     rt: &runtime::Handle,
     path: Option<PathBuf>,
     in_place: bool,
+    filter: ImportFilter,
+    manifest: Option<PathBuf>,
     opts: ProvideOptions,
 ) -> Result<()> {
     if let Some(ref path) = path {
@@ -48,32 +86,79 @@ pub async fn run(
             path.display()
         );
     }
+    // load the manifest before starting the node, so errors are reported early
+    let manifest = match manifest {
+        Some(path) => Some(Manifest::load(&path).await?),
+        None => None,
+    };
 
-    let mut iroh_data_root = iroh_data_root()?;
-    if !iroh_data_root.is_absolute() {
-        iroh_data_root = std::env::current_dir()?.join(iroh_data_root);
+    let data_dir = DataDir::new(&opts.paths.data_dir);
+    let migrated = {
+        let data_dir = data_dir.clone();
+        tokio::task::spawn_blocking(move || data_dir.migrate(MigrateOptions::default())).await??
+    };
+    super::migrate::print_migrated(&data_dir, &migrated);
+    let blobs_path = data_dir.blobs_path();
+    let db = if opts.encrypt_store {
+        let passphrase = std::env::var(STORE_PASSPHRASE_ENV)
+            .with_context(|| format!("{STORE_PASSPHRASE_ENV} must be set to encrypt the store"))?;
+        flat::Store::load_encrypted(&blobs_path, &blobs_path, rt, Passphrase::new(passphrase)).await
+    } else {
+        flat::Store::load(&blobs_path, &blobs_path, rt).await
     }
-    tokio::fs::create_dir_all(&iroh_data_root).await?;
-    let db = flat::Store::load(&iroh_data_root, &iroh_data_root, rt)
-        .await
-        .with_context(|| {
-            format!(
-                "Failed to load iroh database from {}",
-                iroh_data_root.display()
-            )
-        })?;
-    let key = Some(iroh_data_root.join("keypair"));
+    .with_context(|| format!("Failed to load iroh database from {}", blobs_path.display()))?;
+    db.set_watermarks(opts.watermarks);
+    db.set_fsync_policy(opts.fsync_policy);
+    db.set_io_backend(opts.io_backend);
+    db.set_outboard_format(opts.outboard_format)?;
+    db.set_file_handle_limits(opts.file_handles);
+    db.set_coalesce_reads(opts.coalesce_reads);
+    db.set_chunk_cache_size(opts.chunk_cache_bytes);
+    db.set_hash_threads(opts.hash_threads.unwrap_or(0))?;
+    db.set_trash_retention(opts.trash_retention);
+    db.purge_trash()?;
+    let keypair = data_dir.load_keypair()?;
     let token = opts.request_token.clone();
-    let provider = provide(db.clone(), rt, key, opts).await?;
+    let ticket_ttl = opts.ticket_ttl;
+    let ticket_options = opts.ticket_options;
+    let provider = provide(db.clone(), rt, keypair, opts).await?;
     let controller = provider.controller();
+    if let Some(ttl) = ticket_ttl {
+        println!("Tickets expire {}s after they are printed", ttl.as_secs());
+    }
+    // the static token is accepted when tickets expire as well
     if let Some(t) = token.as_ref() {
         println!("Request token: {}", t);
     }
 
-    // task that will add data to the provider, either from a file or from stdin
-    let fut = {
+    // task that will make sure all manifest entries are present
+    let seed_fut = manifest.map(|(manifest, base)| {
         let provider = provider.clone();
+        let token = token.clone();
         tokio::spawn(
+            async move {
+                let controller = provider.controller();
+                let entries = seed::apply(&controller, &manifest, &base).await?;
+                for (tag, hash) in entries {
+                    let ticket = provider.ticket_with_options(hash, ticket_options).await?;
+                    let token = ticket_token(&provider, &ticket, &token, ticket_ttl);
+                    let ticket = ticket.with_token(token);
+                    println!("{tag}: {hash}");
+                    println!("  ticket: {ticket}");
+                }
+                anyhow::Ok(())
+            }
+            .instrument(info_span!("provider-seed")),
+        )
+    });
+
+    // task that will add data to the provider, either from a file or from stdin
+    let fut = if path.is_none() && seed_fut.is_some() {
+        // only serve the manifest
+        None
+    } else {
+        let provider = provider.clone();
+        Some(tokio::spawn(
             async move {
                 let (path, tmp_path) = if let Some(path) = path {
                     let absolute = path.canonicalize()?;
@@ -92,12 +177,19 @@ pub async fn run(
                 };
                 // tell the provider to add the data
                 let stream = controller
-                    .server_streaming(ProvideRequest { path, in_place })
+                    .server_streaming(ProvideRequest {
+                        path,
+                        in_place,
+                        metadata: false,
+                        filter,
+                    })
                     .await?;
                 match aggregate_add_response(stream).await {
                     Ok((hash, entries)) => {
                         print_add_response(hash, entries);
-                        let ticket = provider.ticket(hash).await?.with_token(token);
+                        let ticket = provider.ticket_with_options(hash, ticket_options).await?;
+                        let token = ticket_token(&provider, &ticket, &token, ticket_ttl);
+                        let ticket = ticket.with_token(token);
                         println!("All-in-one ticket: {ticket}");
                         anyhow::Ok(tmp_path)
                     }
@@ -108,10 +200,25 @@ pub async fn run(
                 }
             }
             .instrument(info_span!("provider-add")),
-        )
+        ))
+    };
+
+    // resolves only if applying the manifest failed
+    let mut seed_fut = seed_fut;
+    let seed_failed = async {
+        let res = match seed_fut.as_mut() {
+            Some(fut) => fut.await,
+            None => std::future::pending().await,
+        };
+        match res {
+            Ok(Ok(())) => std::future::pending().await,
+            Ok(Err(e)) => e,
+            Err(e) => e.into(),
+        }
     };
 
     let provider2 = provider.clone();
+    let mut result = Ok(());
     tokio::select! {
         biased;
         _ = tokio::signal::ctrl_c() => {
@@ -121,42 +228,103 @@ pub async fn run(
         res = provider => {
             res?;
         }
+        e = seed_failed => {
+            provider2.shutdown();
+            result = Err(e.context("Failed to apply manifest"));
+        }
     }
 
     // the future holds a reference to the temp file, so we need to
     // keep it for as long as the provider is running. The drop(fut)
     // makes this explicit.
-    fut.abort();
-    drop(fut);
-    Ok(())
+    if let Some(fut) = fut {
+        fut.abort();
+        drop(fut);
+    }
+    if let Some(seed_fut) = seed_fut {
+        seed_fut.abort();
+    }
+    result
+}
+
+/// The token for a ticket printed now, signed by the provider for the hash of the ticket
+/// if tickets expire.
+fn ticket_token<D: Store>(
+    provider: &Node<D>,
+    ticket: &Ticket,
+    token: &Option<RequestToken>,
+    ticket_ttl: Option<Duration>,
+) -> Option<RequestToken> {
+    match ticket_ttl {
+        Some(ttl) => Some(provider.expiring_token(ticket.hash(), SystemTime::now() + ttl)),
+        None => token.clone(),
+    }
 }
 
 async fn provide<D: Store>(
     db: D,
     rt: &runtime::Handle,
-    key: Option<PathBuf>,
-    opts: ProvideOptions,
+    keypair: Keypair,
+    mut opts: ProvideOptions,
 ) -> Result<Node<D>> {
-    let keypair = get_keypair(key).await?;
-
+    let auth_handler: Arc<dyn RequestAuthorizationHandler> = match opts.ticket_ttl {
+        Some(_) => {
+            // mirrors authorize with the static token, cluster members sign their fetches
+            let mut handler = ExpiringTokenAuthHandler::new(keypair.public())
+                .clock_skew(opts.clock_skew)
+                .static_token(opts.request_token);
+            if let Some(cluster) = opts.cluster.as_mut() {
+                cluster.signed_fetches = true;
+                for member in &cluster.members {
+                    handler = handler.trust(member.peer.into());
+                }
+            }
+            Arc::new(handler)
+        }
+        None => Arc::new(StaticTokenAuthHandler::new(opts.request_token)),
+    };
+    let data_dir = DataDir::new(&opts.paths.data_dir);
+    let downloads_path = data_dir.downloads_path();
     let mut builder = Node::builder(db)
         .collection_parser(IrohCollectionParser)
-        .custom_auth_handler(Arc::new(StaticTokenAuthHandler::new(opts.request_token)))
-        .keylog(opts.keylog);
+        .custom_auth_handler(auth_handler)
+        .keylog(opts.keylog)
+        .serve_partial(opts.serve_partial)
+        .serve_listing(opts.serve_listing)
+        .persist_downloads(downloads_path)
+        .paths(opts.paths);
+    if opts.keyed {
+        builder = builder.keyed_namespace(data_dir.load_namespace_key()?);
+    }
     if let Some(dm) = opts.derp_map {
         builder = builder.derp_map(dm);
     }
-    let builder = builder.bind_addr(opts.addr).runtime(rt);
+    if let Some(qlog) = opts.qlog {
+        builder = builder.qlog(qlog);
+    }
+    builder = builder.dns_resolver(opts.dns_resolver);
+    if let Some(schedule) = opts.validation {
+        builder = builder.background_validation(schedule);
+    }
+    if let Some(limits) = opts.memory_limits {
+        builder = builder.memory_limits(limits);
+    }
+    if let Some(handler) = opts.log_filter {
+        builder = builder.log_filter_handler(handler);
+    }
+    for mirror in opts.mirrors {
+        builder = builder.mirror(mirror);
+    }
+    if let Some(cluster) = opts.cluster {
+        builder = builder.cluster(cluster);
+    }
+    let builder = builder.bind_addr(opts.addr).runtime(rt).keypair(keypair);
 
-    let provider = if let Some(rpc_port) = opts.rpc_port.into() {
-        let rpc_endpoint = make_rpc_endpoint(&keypair, rpc_port)?;
-        builder
-            .rpc_endpoint(rpc_endpoint)
-            .keypair(keypair)
-            .spawn()
-            .await?
+    let rpc_port: Option<u16> = opts.rpc_port.into();
+    let provider = if rpc_port.is_some() || opts.rpc_socket.is_some() {
+        builder.rpc(rpc_port, opts.rpc_socket)?.spawn().await?
     } else {
-        builder.keypair(keypair).spawn().await?
+        builder.spawn().await?
     };
     let eps = provider.local_endpoints().await?;
     println!("Listening addresses:");
@@ -173,70 +341,6 @@ async fn provide<D: Store>(
     Ok(provider)
 }
 
-async fn get_keypair(key: Option<PathBuf>) -> Result<Keypair> {
-    match key {
-        Some(key_path) => {
-            if key_path.exists() {
-                let keystr = tokio::fs::read(key_path).await?;
-                let keypair = Keypair::try_from_openssh(keystr).context("invalid keyfile")?;
-                Ok(keypair)
-            } else {
-                let keypair = Keypair::generate();
-                let ser_key = keypair.to_openssh()?;
-
-                // Try to canoncialize if possible
-                let key_path = key_path.canonicalize().unwrap_or(key_path);
-                let key_path_parent = key_path.parent().ok_or_else(|| {
-                    anyhow!("no parent directory found for '{}'", key_path.display())
-                })?;
-                tokio::fs::create_dir_all(&key_path_parent).await?;
-
-                // write to tempfile
-                let (file, temp_file_path) = tempfile::NamedTempFile::new_in(key_path_parent)
-                    .context("unable to create tempfile")?
-                    .into_parts();
-                let mut file = tokio::fs::File::from_std(file);
-                file.write_all(ser_key.as_bytes())
-                    .await
-                    .context("unable to write keyfile")?;
-                file.flush().await?;
-                drop(file);
-
-                // move file
-                tokio::fs::rename(temp_file_path, key_path)
-                    .await
-                    .context("failed to rename keyfile")?;
-
-                Ok(keypair)
-            }
-        }
-        None => {
-            // No path provided, just generate one
-            Ok(Keypair::generate())
-        }
-    }
-}
-
-/// Makes a an RPC endpoint that uses a QUIC transport
-fn make_rpc_endpoint(
-    keypair: &Keypair,
-    rpc_port: u16,
-) -> Result<impl ServiceEndpoint<ProviderService>> {
-    let rpc_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, rpc_port));
-    let rpc_quinn_endpoint = quinn::Endpoint::server(
-        iroh::node::make_server_config(
-            keypair,
-            MAX_RPC_STREAMS,
-            MAX_RPC_CONNECTIONS,
-            vec![RPC_ALPN.to_vec()],
-        )?,
-        rpc_addr,
-    )?;
-    let rpc_endpoint =
-        QuinnServerEndpoint::<ProviderRequest, ProviderResponse>::new(rpc_quinn_endpoint)?;
-    Ok(rpc_endpoint)
-}
-
 #[derive(Debug, Clone)]
 pub enum ProviderRpcPort {
     Enabled(u16),
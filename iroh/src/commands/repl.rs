@@ -0,0 +1,181 @@
+//! The `iroh console` command, an interactive shell for a running provider.
+use anyhow::{Context as _, Result};
+use clap::{CommandFactory, Parser};
+use futures::FutureExt;
+use iroh::data_dir::DataDir;
+use iroh_bytes::util::runtime;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use tokio::io::AsyncBufReadExt;
+
+use super::{make_rpc_client, Cli};
+use crate::config::Config;
+
+const PROMPT: &str = "iroh> ";
+
+/// Commands that can not be run from the console.
+const UNSUPPORTED: [&str; 2] = ["console", "provide"];
+
+pub async fn run(rpc_port: u16, script: bool, rt: &runtime::Handle, config: &Config) -> Result<()> {
+    // fail early if there is no provider to talk to
+    make_rpc_client(rpc_port).await?;
+    if script {
+        run_script(rpc_port, rt, config).await
+    } else {
+        run_interactive(rpc_port, rt, config).await
+    }
+}
+
+/// Run the commands read from stdin, stopping at the first command that fails.
+async fn run_script(rpc_port: u16, rt: &runtime::Handle, config: &Config) -> Result<()> {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut line_no = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_no += 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        run_line(line, rpc_port, rt, config)
+            .await
+            .with_context(|| format!("line {line_no}: {line}"))?;
+    }
+    Ok(())
+}
+
+async fn run_interactive(rpc_port: u16, rt: &runtime::Handle, config: &Config) -> Result<()> {
+    let mut editor = Editor::<ConsoleHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(ConsoleHelper));
+    let history = config
+        .data_root()
+        .ok()
+        .map(|root| DataDir::new(root).console_history_path());
+    if let Some(history) = &history {
+        // there is no history the first time the console is used
+        editor.load_history(history).ok();
+    }
+    println!("Connected to the provider on RPC port {rpc_port}.");
+    println!("Type `help` for a list of commands, `exit` to leave.");
+    loop {
+        // reading a line blocks, the runtime has to keep driving the RPC connection meanwhile
+        let line = match tokio::task::block_in_place(|| editor.readline(PROMPT)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(cause) => return Err(cause.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+        if line == "exit" || line == "quit" {
+            break;
+        }
+        if let Err(cause) = run_line(line, rpc_port, rt, config).await {
+            eprintln!("Error: {cause:#}");
+        }
+    }
+    if let Some(history) = &history {
+        if let Some(parent) = history.parent() {
+            std::fs::create_dir_all(parent).ok();
+        }
+        if let Err(cause) = editor.save_history(history) {
+            tracing::warn!("failed to save console history: {cause}");
+        }
+    }
+    Ok(())
+}
+
+/// Parse and run a single console line as an `iroh` command.
+///
+/// Commands talking to a provider default to the RPC port of the console.
+async fn run_line(line: &str, rpc_port: u16, rt: &runtime::Handle, config: &Config) -> Result<()> {
+    let mut args = shell_words::split(line)?;
+    if let Some(cmd) = args.first() {
+        anyhow::ensure!(
+            !UNSUPPORTED.contains(&cmd.as_str()),
+            "`{cmd}` can not be run from the console"
+        );
+    }
+    if takes_rpc_port(&args) && !args.iter().any(|arg| arg.starts_with("--rpc-port")) {
+        args.push("--rpc-port".to_string());
+        args.push(rpc_port.to_string());
+    }
+    let cli = match Cli::try_parse_from(std::iter::once("iroh".to_string()).chain(args)) {
+        Ok(cli) => cli,
+        // help and version are reported as errors, but are not failures
+        Err(err) if !err.use_stderr() => {
+            err.print()?;
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // Cli::run runs this console, so the future has to be boxed to not be infinitely sized
+    cli.run(rt, config).boxed_local().await
+}
+
+/// Whether the subcommand named by the leading arguments has an `--rpc-port` flag.
+fn takes_rpc_port(args: &[String]) -> bool {
+    let mut cmd = Cli::command();
+    for arg in args {
+        match cmd.find_subcommand(arg) {
+            Some(sub) => cmd = sub.clone(),
+            None => break,
+        }
+    }
+    cmd.get_arguments().any(|arg| arg.get_id() == "rpc_port")
+}
+
+/// Completes subcommand names, and flags when the word starts with `-`.
+struct ConsoleHelper;
+
+impl Completer for ConsoleHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let mut cmd = Cli::command();
+        for arg in line[..start].split_whitespace() {
+            if let Some(sub) = cmd.find_subcommand(arg) {
+                cmd = sub.clone();
+            }
+        }
+        let mut candidates = if word.starts_with('-') {
+            cmd.get_arguments()
+                .filter_map(|arg| arg.get_long())
+                .map(|long| format!("--{long}"))
+                .collect::<Vec<_>>()
+        } else {
+            cmd.get_subcommands()
+                .map(|sub| sub.get_name().to_string())
+                .filter(|name| start > 0 || !UNSUPPORTED.contains(&name.as_str()))
+                .collect::<Vec<_>>()
+        };
+        candidates.retain(|candidate| candidate.starts_with(word));
+        candidates.sort();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
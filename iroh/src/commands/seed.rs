@@ -0,0 +1,247 @@
+//! Declarative seeding of a provider from a manifest file.
+//!
+//! A manifest is a TOML file listing content that a provider should serve:
+//!
+//! ```toml
+//! [[entry]]
+//! tag = "dataset"
+//! path = "data/dataset"
+//! in_place = true
+//!
+//! [[entry]]
+//! tag = "model"
+//! hash = "bafkr4i..."
+//! recursive = true
+//! ```
+//!
+//! Path entries are imported as collections, relative paths are resolved against the
+//! directory containing the manifest. Hash entries must already be in the database.
+//! The manifest is applied every time the provider starts, importing content that is
+//! unchanged is cheap since the database is content addressed.
+//!
+//! Every entry is persisted as a pin that never expires, named after the tag of the entry,
+//! so that its content is protected from garbage collection. A pin that already exists is
+//! replaced.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use iroh::rpc_protocol::{
+    ListBlobsRequest, PinAddRequest, PinListRequest, ProvideRequest, ProviderService,
+};
+use iroh_bytes::Hash;
+use quic_rpc::{RpcClient, ServiceConnection};
+use serde::Deserialize;
+
+use super::add::aggregate_add_response;
+
+/// A seeding manifest.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    /// The entries of the manifest
+    #[serde(default, rename = "entry")]
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A single entry of a seeding manifest.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestEntry {
+    /// Tag to identify the entry by
+    pub tag: String,
+    /// Path to a file or directory to import
+    pub path: Option<PathBuf>,
+    /// Hash of content that is expected to be present
+    pub hash: Option<String>,
+    /// Import the path in place
+    #[serde(default)]
+    pub in_place: bool,
+    /// Whether the hash is a collection whose children are protected as well
+    ///
+    /// Path entries are always imported as collections.
+    #[serde(default)]
+    pub recursive: bool,
+}
+
+/// Where the content of a manifest entry comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Import from a path
+    Path(PathBuf),
+    /// Expect the hash to be present
+    Hash(Hash),
+}
+
+impl ManifestEntry {
+    fn source(&self, base: &Path) -> Result<Source> {
+        match (&self.path, &self.hash) {
+            (Some(path), None) => Ok(Source::Path(base.join(path))),
+            (None, Some(hash)) => Ok(Source::Hash(hash.parse()?)),
+            _ => anyhow::bail!("entry {} must have exactly one of path or hash", self.tag),
+        }
+    }
+}
+
+impl Manifest {
+    /// Load and validate a manifest from a file.
+    pub async fn load(path: &Path) -> Result<(Self, PathBuf)> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("unable to read manifest {}", path.display()))?;
+        let manifest = Self::parse(&text)?;
+        let base = path
+            .canonicalize()?
+            .parent()
+            .context("manifest has no parent directory")?
+            .to_owned();
+        Ok((manifest, base))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let manifest: Manifest = toml::from_str(text).context("invalid manifest")?;
+        let mut tags = BTreeSet::new();
+        for entry in &manifest.entries {
+            anyhow::ensure!(tags.insert(&entry.tag), "duplicate tag {}", entry.tag);
+            entry.source(Path::new("/"))?;
+        }
+        Ok(manifest)
+    }
+}
+
+/// Ensure that all entries of the manifest are present on the provider, and pinned.
+///
+/// Returns the tag and hash of every entry. Fails if the content of a hash entry is not
+/// in the database, after pinning the content of all other entries.
+pub async fn apply<C: ServiceConnection<ProviderService>>(
+    client: &RpcClient<ProviderService, C>,
+    manifest: &Manifest,
+    base: &Path,
+) -> Result<Vec<(String, Hash)>> {
+    let mut pins = BTreeMap::new();
+    let mut stream = client.server_streaming(PinListRequest).await?;
+    while let Some(item) = stream.next().await {
+        let item = item?;
+        if item.pin.expires.is_none() {
+            pins.insert(item.name, (item.pin.hash, item.pin.recursive));
+        }
+    }
+    let mut blobs = None;
+    let mut res = Vec::new();
+    let mut missing = Vec::new();
+    for entry in &manifest.entries {
+        match entry.source(base)? {
+            Source::Path(path) => {
+                let path = path
+                    .canonicalize()
+                    .with_context(|| format!("entry {}: {}", entry.tag, path.display()))?;
+                let stream = client
+                    .server_streaming(ProvideRequest {
+                        path,
+                        in_place: entry.in_place,
+                        metadata: false,
+                        filter: Default::default(),
+                    })
+                    .await?;
+                let (hash, _) = aggregate_add_response(stream).await?;
+                set_pin(client, &pins, &entry.tag, hash, true).await?;
+                res.push((entry.tag.clone(), hash));
+            }
+            Source::Hash(hash) => {
+                if blobs.is_none() {
+                    let mut stream = client.server_streaming(ListBlobsRequest).await?;
+                    let mut set = BTreeSet::new();
+                    while let Some(item) = stream.next().await {
+                        set.insert(item?.hash);
+                    }
+                    blobs = Some(set);
+                }
+                if blobs.as_ref().map_or(false, |x| x.contains(&hash)) {
+                    set_pin(client, &pins, &entry.tag, hash, entry.recursive).await?;
+                    res.push((entry.tag.clone(), hash));
+                } else {
+                    missing.push(format!("{}: {}", entry.tag, hash));
+                }
+            }
+        }
+    }
+    anyhow::ensure!(
+        missing.is_empty(),
+        "content missing from the database for {}",
+        missing.join(", ")
+    );
+    Ok(res)
+}
+
+/// Point the pin `name` at `hash`, unless it already does.
+async fn set_pin<C: ServiceConnection<ProviderService>>(
+    client: &RpcClient<ProviderService, C>,
+    pins: &BTreeMap<String, (Hash, bool)>,
+    name: &str,
+    hash: Hash,
+    recursive: bool,
+) -> Result<()> {
+    if pins.get(name) == Some(&(hash, recursive)) {
+        return Ok(());
+    }
+    client
+        .rpc(PinAddRequest {
+            hash,
+            name: Some(name.to_string()),
+            recursive,
+            ttl: None,
+        })
+        .await?
+        .with_context(|| format!("unable to pin {hash} as {name}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_manifest() {
+        let hash = Hash::new(b"hello");
+        let text = format!(
+            r#"
+            [[entry]]
+            tag = "a"
+            path = "data"
+
+            [[entry]]
+            tag = "b"
+            hash = "{hash}"
+            "#
+        );
+        let manifest = Manifest::parse(&text).unwrap();
+        let base = Path::new("/base");
+        assert_eq!(
+            manifest.entries[0].source(base).unwrap(),
+            Source::Path(PathBuf::from("/base/data"))
+        );
+        assert_eq!(
+            manifest.entries[1].source(base).unwrap(),
+            Source::Hash(hash)
+        );
+    }
+
+    #[test]
+    fn parse_manifest_invalid() {
+        let duplicate = r#"
+            [[entry]]
+            tag = "a"
+            path = "x"
+            [[entry]]
+            tag = "a"
+            path = "y"
+            "#;
+        assert!(Manifest::parse(duplicate).is_err());
+        let both = r#"
+            [[entry]]
+            tag = "a"
+            path = "x"
+            hash = "y"
+            "#;
+        assert!(Manifest::parse(both).is_err());
+    }
+}
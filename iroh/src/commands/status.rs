@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use indicatif::{HumanBytes, HumanDuration};
+use iroh::{
+    mirror::MirrorStatus,
+    rpc_protocol::{
+        MirrorStatusRequest, NodeStatusRequest, NodeStatusResponse, PathsRequest, PeerStatus,
+    },
+};
+use iroh_net::magicsock::ConnectionType;
+
+use super::make_rpc_client;
+
+pub async fn run(rpc_port: u16) -> Result<()> {
+    let client = make_rpc_client(rpc_port).await?;
+    let status = client.rpc(NodeStatusRequest).await?;
+    print_status(&status);
+    if let Some(paths) = client.rpc(PathsRequest).await?.paths {
+        println!("Config directory: {}", paths.config_dir.display());
+        println!("Data directory: {}", paths.data_dir.display());
+        println!("Cache directory: {}", paths.cache_dir.display());
+    }
+    let mirrors = client.rpc(MirrorStatusRequest).await?;
+    if !mirrors.mirrors.is_empty() {
+        println!("Mirrors:");
+        for mirror in &mirrors.mirrors {
+            println!("  {}", fmt_mirror(mirror));
+        }
+    }
+    Ok(())
+}
+
+fn print_status(status: &NodeStatusResponse) {
+    println!("PeerID: {}", status.peer_id);
+    println!("Version: {}", status.version);
+    println!(
+        "Uptime: {}",
+        HumanDuration(Duration::from_secs(status.uptime.as_secs()))
+    );
+    println!("Listening addresses:");
+    for addr in &status.listen_addrs {
+        println!("  {addr}");
+    }
+    match status.derp_region {
+        Some(region) => println!("Home DERP region: {region}"),
+        None => println!("Home DERP region: none"),
+    }
+    match &status.nat {
+        Some(nat) => {
+            println!("NAT:");
+            println!("  UDP: {}", fmt_check(nat.working_udp));
+            println!("  IPv6: {}", fmt_check(nat.working_ipv6));
+            println!(
+                "  mapping varies by destination: {}",
+                fmt_check(nat.mapping_varies_by_dest_ip)
+            );
+            println!("  port mapping: {}", nat.have_port_map);
+        }
+        None => println!("NAT: no report yet"),
+    }
+    let connected = status.peers.iter().filter(|p| p.is_connected()).count();
+    println!(
+        "Peers: {} known, {} connected",
+        status.peers.len(),
+        connected
+    );
+    for peer in &status.peers {
+        println!("  {}", fmt_peer(peer));
+    }
+    println!(
+        "Store: {} blobs ({}), {} incomplete",
+        status.blobs,
+        HumanBytes(status.blobs_size),
+        status.partial_blobs
+    );
+}
+
+fn fmt_check(check: Option<bool>) -> &'static str {
+    match check {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    }
+}
+
+fn fmt_peer(peer: &PeerStatus) -> String {
+    let id = match peer.peer_id {
+        Some(peer_id) => peer_id.to_string(),
+        None => peer.node_key.to_string(),
+    };
+    let path = fmt_conn_type(peer.conn_type);
+    match peer.latency {
+        Some(latency) => format!("{id}: {path}, {}ms", latency.as_millis()),
+        None => format!("{id}: {path}"),
+    }
+}
+
+fn fmt_mirror(mirror: &MirrorStatus) -> String {
+    let state = if mirror.connected {
+        "connected"
+    } else {
+        "disconnected"
+    };
+    let mut line = format!(
+        "{} ({state}): {} mirrored, {} failed, {} pending, lag {}",
+        mirror.peer,
+        mirror.mirrored,
+        mirror.failed,
+        mirror.pending,
+        HumanDuration(mirror.lag)
+    );
+    if let Some(prefix) = &mirror.prefix {
+        line.push_str(&format!(", prefix {prefix:?}"));
+    }
+    if let Some(err) = &mirror.last_error {
+        line.push_str(&format!(", last error: {err}"));
+    }
+    line
+}
+
+pub(super) fn fmt_conn_type(conn_type: ConnectionType) -> String {
+    match conn_type {
+        ConnectionType::Direct(addr) => format!("direct {addr}"),
+        ConnectionType::Relay(region) => format!("relay region {region}"),
+        ConnectionType::Mixed(addr, region) => format!("direct {addr} or relay region {region}"),
+        ConnectionType::None => "no path".to_string(),
+    }
+}
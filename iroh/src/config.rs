@@ -1,33 +1,201 @@
 //! Configuration for the iroh CLI.
+//!
+//! Every setting is taken from the first of these that sets it:
+//!
+//! 1. a command line flag, e.g. `--data-dir`
+//! 2. an environment variable, e.g. `IROH_DATA_DIR` or `IROH__DATA_DIR`
+//! 3. the file given with `--cfg`
+//! 4. the `iroh.config.toml` file in the config directory, see [`iroh_config_root`]
+//! 5. the default
+//!
+//! The config directory itself can only be set with `IROH_CONFIG_DIR`.
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
+    net::SocketAddr,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use config::{Environment, File, Value};
+use iroh::baomap::{
+    disk_space::Watermarks, flat, fsync::FsyncPolicy, handle_cache::HandleLimits,
+    outboard::OutboardFormat, uring::IoBackend, validation::ValidationSchedule,
+};
+use iroh::cluster::{ClusterConfig, ClusterMember};
+use iroh::mirror::MirrorConfig;
+use iroh::node::NodePaths;
+use iroh::util::memory::MemoryLimits;
 use iroh_net::{
     defaults::{default_eu_derp_region, default_na_derp_region},
     derp::{DerpMap, DerpRegion},
+    dns::{DnsConfig, DnsResolver},
 };
 use serde::{Deserialize, Serialize};
 use tracing::debug;
+use tracing_subscriber::EnvFilter;
+
+use crate::logging::{self, LogFileConfig};
 
 /// CONFIG_FILE_NAME is the name of the optional config file located in the iroh home directory
 pub const CONFIG_FILE_NAME: &str = "iroh.config.toml";
 /// ENV_PREFIX should be used along side the config field name to set a config field using
-/// environment variables
-/// For example, `IROH_PATH=/path/to/config` would set the value of the `Config.path` field
+/// environment variables, separated by `__`.
+/// For example, `IROH__HASH_THREADS=4` would set the value of the `Config.hash_threads` field
 pub const ENV_PREFIX: &str = "IROH";
 
 /// The configuration for the iroh cli.
+///
+/// Unknown fields are rejected, so that a misspelled field is an error instead of being
+/// silently ignored. Use [`Config::validate`] to check the values.
 #[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     /// The regions for DERP to use.
     pub derp_regions: Vec<DerpRegion>,
+    /// How DERP and STUN hostnames are resolved.
+    ///
+    /// `kind` is "system", "nameservers" with `addrs`, or "https" with `addrs` and
+    /// `tls_name` for DNS-over-HTTPS. Defaults to the system resolver.
+    pub dns: DnsConfig,
+    /// Free disk space in bytes below which the provider refuses new data.
+    pub disk_space_low_watermark: Option<u64>,
+    /// Free disk space in bytes above which the provider accepts new data again.
+    ///
+    /// Defaults to the low watermark.
+    pub disk_space_high_watermark: Option<u64>,
+    /// Resident memory in bytes above which the provider stops accepting connections and
+    /// drops its caches.
+    pub memory_high_limit: Option<u64>,
+    /// Resident memory in bytes below which the provider accepts connections again.
+    ///
+    /// Defaults to 90% of the high limit.
+    pub memory_low_limit: Option<u64>,
+    /// Seconds between background validation passes over the stored blobs.
+    ///
+    /// Background validation is disabled if not set.
+    pub validation_interval_secs: Option<u64>,
+    /// Percentage of the time spent validating during a pass.
+    ///
+    /// Defaults to 10.
+    pub validation_duty_cycle_percent: Option<u8>,
+    /// Whether to quarantine blobs that fail background validation.
+    pub validation_quarantine: bool,
+    /// When the store syncs written data to disk: "per-write", "on-complete" or "never".
+    pub fsync_policy: FsyncPolicy,
+    /// How the store reads complete data files and writes downloads: "std" or "uring".
+    ///
+    /// "uring" needs Linux and the io-uring feature, and falls back to "std" otherwise.
+    pub io_backend: IoBackend,
+    /// How the store writes complete outboards: "pre-order" or "post-order".
+    ///
+    /// Existing outboards are kept in the format they were written in.
+    pub outboard_format: OutboardFormat,
+    /// Maximum number of files the store keeps open for reading.
+    ///
+    /// Defaults to 1024. With 0, every reader opens its own file.
+    pub max_open_files: Option<usize>,
+    /// Seconds after which a file the store keeps open for reading is closed if unused.
+    ///
+    /// Defaults to 60.
+    pub open_file_idle_secs: Option<u64>,
+    /// Whether identical concurrent reads of the store share a single read from disk.
+    pub coalesce_reads: bool,
+    /// Bytes of recently read data the store caches in memory, 0 to disable the cache.
+    pub chunk_cache_bytes: u64,
+    /// Number of threads used to hash large imports.
+    ///
+    /// Defaults to a thread per core. With 1, imports are hashed on a single thread.
+    pub hash_threads: Option<usize>,
+    /// Seconds deleted blobs are kept in the trash, so they can be restored.
+    ///
+    /// Defaults to a day. With 0, deleted blobs are removed immediately.
+    pub trash_retention_secs: Option<u64>,
+    /// Whether to encrypt the store with a passphrase.
+    ///
+    /// The passphrase is read from the `IROH_STORE_PASSPHRASE` environment variable. Only
+    /// a new, empty store can be encrypted, and an encrypted store can not be loaded
+    /// without the passphrase.
+    pub encrypt_store: bool,
+    /// Other nodes whose content the provider mirrors.
+    pub mirrors: Vec<MirrorEntry>,
+    /// The cluster the provider is a member of, if any.
+    pub cluster: Option<ClusterEntry>,
+    /// Whether and how `iroh provide` writes its logs to a file, see [`crate::logging`].
+    pub log_file: Option<LogFileEntry>,
+    /// The data directory, see [`Config::data_root`].
+    pub data_dir: Option<PathBuf>,
+    /// The cache directory, see [`Config::cache_root`].
+    pub cache_dir: Option<PathBuf>,
+    /// Serve and connect to RPC on this Unix domain socket, or named pipe on Windows,
+    /// instead of QUIC on the RPC port, see [`iroh::local_rpc`].
+    pub rpc_socket: Option<PathBuf>,
+}
+
+/// Cluster membership, see [`iroh::cluster`].
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterEntry {
+    /// Number of designated replicas of every hash.
+    pub replicas: usize,
+    /// All members of the cluster, including this node.
+    pub members: Vec<ClusterMemberEntry>,
+}
+
+/// A member of a cluster.
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterMemberEntry {
+    /// The peer id of the member.
+    pub peer: String,
+    /// Direct addresses of the member.
+    #[serde(default)]
+    pub addrs: Vec<SocketAddr>,
+    /// DERP region of the member.
+    pub derp_region: Option<u16>,
+}
+
+/// The log file of the provider.
+///
+/// An empty `[log_file]` section writes the logs to `logs/iroh.log` in the data directory.
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileEntry {
+    /// The log file, relative to the data directory.
+    pub path: Option<PathBuf>,
+    /// Size in bytes after which the log file is rotated.
+    ///
+    /// Defaults to 10 MiB.
+    pub max_size: Option<u64>,
+    /// Seconds after which the log file is rotated, regardless of its size.
+    pub max_age_secs: Option<u64>,
+    /// Number of rotated log files that are kept.
+    ///
+    /// Defaults to 5.
+    pub max_files: Option<usize>,
+    /// Filter for the log file, in the syntax of `RUST_LOG`, which takes precedence.
+    ///
+    /// Defaults to "info".
+    pub filter: Option<String>,
+}
+
+/// A node to mirror, see [`iroh::mirror`].
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorEntry {
+    /// The peer id of the node.
+    pub peer: String,
+    /// Direct addresses of the node.
+    #[serde(default)]
+    pub addrs: Vec<SocketAddr>,
+    /// DERP region of the node.
+    pub derp_region: Option<u16>,
+    /// Request token for the node.
+    pub token: Option<String>,
+    /// Only mirror pins whose names start with this prefix, all content if unset.
+    pub prefix: Option<String>,
 }
 
 impl Default for Config {
@@ -35,6 +203,30 @@ impl Default for Config {
         Self {
             // TODO(ramfox): this should probably just be a derp map
             derp_regions: [default_na_derp_region(), default_eu_derp_region()].into(),
+            dns: DnsConfig::default(),
+            disk_space_low_watermark: None,
+            disk_space_high_watermark: None,
+            memory_high_limit: None,
+            memory_low_limit: None,
+            validation_interval_secs: None,
+            validation_duty_cycle_percent: None,
+            validation_quarantine: false,
+            fsync_policy: FsyncPolicy::default(),
+            io_backend: IoBackend::default(),
+            outboard_format: OutboardFormat::default(),
+            max_open_files: None,
+            open_file_idle_secs: None,
+            coalesce_reads: true,
+            chunk_cache_bytes: 0,
+            hash_threads: None,
+            trash_retention_secs: None,
+            encrypt_store: false,
+            mirrors: Vec::new(),
+            cluster: None,
+            log_file: None,
+            data_dir: None,
+            cache_dir: None,
+            rpc_socket: None,
         }
     }
 }
@@ -44,13 +236,12 @@ impl Config {
     ///
     /// Later items in the *file_paths* slice will have a higher priority than earlier ones.
     ///
-    /// Environment variables are expected to start with the *env_prefix*. Nested fields can be
-    /// accessed using `.`, if your environment allows env vars with `.`
+    /// Environment variables are expected to start with the *env_prefix*, followed by the
+    /// field name. Both the prefix and nested fields are separated by `__`, e.g.
+    /// `IROH__CLUSTER__REPLICAS`.
     ///
-    /// Note: For the metrics configuration env vars, it is recommended to use the metrics
-    /// specific prefix `IROH_METRICS` to set a field in the metrics config. You can use the
-    /// above dot notation to set a metrics field, eg, `IROH_CONFIG_METRICS.SERVICE_NAME`, but
-    /// only if your environment allows it
+    /// Fails if a source contains a field the config does not know. The values are not
+    /// validated, see [`Config::validate`].
     pub fn load<S, V>(
         file_paths: &[Option<&Path>],
         env_prefix: &str,
@@ -88,6 +279,129 @@ impl Config {
         Ok(cfg)
     }
 
+    /// Checks the config for values that are out of range or inconsistent.
+    ///
+    /// All problems are reported at once, one per line of the error.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        let mut region_ids = HashSet::new();
+        for region in &self.derp_regions {
+            if !region_ids.insert(region.region_id) {
+                problems.push(format!(
+                    "derp region {} is configured more than once",
+                    region.region_id
+                ));
+            }
+            if region.nodes.is_empty() {
+                problems.push(format!("derp region {} has no nodes", region.region_id));
+            }
+            for node in &region.nodes {
+                if node.region_id != region.region_id {
+                    problems.push(format!(
+                        "derp node {} has region_id {} but is listed in region {}",
+                        node.name, node.region_id, region.region_id
+                    ));
+                }
+            }
+        }
+
+        if let Err(err) = self.dns.validate() {
+            problems.push(format!("dns: {err}"));
+        }
+
+        match (self.disk_space_low_watermark, self.disk_space_high_watermark) {
+            (None, Some(_)) => problems.push(
+                "disk_space_high_watermark is set without disk_space_low_watermark".to_string(),
+            ),
+            (Some(low), Some(high)) if high < low => problems.push(format!(
+                "disk_space_high_watermark ({high}) must not be below disk_space_low_watermark ({low})"
+            )),
+            _ => {}
+        }
+
+        match (self.memory_high_limit, self.memory_low_limit) {
+            (None, Some(_)) => {
+                problems.push("memory_low_limit is set without memory_high_limit".to_string())
+            }
+            (Some(high), Some(low)) if low > high => problems.push(format!(
+                "memory_low_limit ({low}) must not be above memory_high_limit ({high})"
+            )),
+            _ => {}
+        }
+
+        if let Some(percent) = self.validation_duty_cycle_percent {
+            if !(1..=100).contains(&percent) {
+                problems.push(format!(
+                    "validation_duty_cycle_percent must be between 1 and 100, got {percent}"
+                ));
+            }
+        }
+        if self.validation_interval_secs == Some(0) {
+            problems.push("validation_interval_secs must be at least 1".to_string());
+        }
+        if self.hash_threads == Some(0) {
+            problems.push("hash_threads must be at least 1".to_string());
+        }
+
+        if let Err(err) = self.mirrors() {
+            problems.push(format!("{err:#}"));
+        }
+        for entry in &self.mirrors {
+            if let Some(region) = entry.derp_region {
+                if !region_ids.contains(&region) {
+                    problems.push(format!(
+                        "mirror {} uses derp region {region}, which is not configured",
+                        entry.peer
+                    ));
+                }
+            }
+        }
+
+        if let Some(cluster) = &self.cluster {
+            if let Err(err) = self.cluster() {
+                problems.push(format!("{err:#}"));
+            }
+            let members = cluster.members.len();
+            if cluster.replicas == 0 || cluster.replicas > members {
+                problems.push(format!(
+                    "cluster.replicas must be between 1 and the number of members ({members}), got {}",
+                    cluster.replicas
+                ));
+            }
+            let mut peers = HashSet::new();
+            for member in &cluster.members {
+                if !peers.insert(&member.peer) {
+                    problems.push(format!(
+                        "cluster member {} is listed more than once",
+                        member.peer
+                    ));
+                }
+            }
+        }
+
+        if let Some(log_file) = &self.log_file {
+            if log_file.max_size == Some(0) {
+                problems.push("log_file.max_size must be at least 1".to_string());
+            }
+            if log_file.max_age_secs == Some(0) {
+                problems.push("log_file.max_age_secs must be at least 1".to_string());
+            }
+            if let Some(filter) = &log_file.filter {
+                if let Err(err) = EnvFilter::try_new(filter) {
+                    problems.push(format!("log_file.filter is invalid: {err}"));
+                }
+            }
+        }
+
+        ensure!(
+            problems.is_empty(),
+            "invalid configuration:\n  - {}",
+            problems.join("\n  - ")
+        );
+        Ok(())
+    }
+
     /// Constructs a `DerpMap` based on the current configuration.
     pub fn derp_map(&self) -> Option<DerpMap> {
         if self.derp_regions.is_empty() {
@@ -97,6 +411,184 @@ impl Config {
         let dm: DerpMap = self.derp_regions.iter().cloned().into();
         Some(dm)
     }
+
+    /// Constructs the resolver for DERP and STUN hostnames from the `dns` section.
+    pub fn dns_resolver(&self) -> Result<DnsResolver> {
+        DnsResolver::new(self.dns.clone()).context("dns")
+    }
+
+    /// Constructs the disk space watermarks for the store, if configured.
+    pub fn watermarks(&self) -> Option<Watermarks> {
+        let low = self.disk_space_low_watermark?;
+        let high = self.disk_space_high_watermark.unwrap_or(low);
+        Some(Watermarks::new(low, high))
+    }
+
+    /// Constructs the memory limits of the provider, if configured.
+    pub fn memory_limits(&self) -> Option<MemoryLimits> {
+        let high = self.memory_high_limit?;
+        let low = self.memory_low_limit.unwrap_or(high / 10 * 9);
+        Some(MemoryLimits::new(high, low))
+    }
+
+    /// Constructs the background validation schedule, if configured.
+    pub fn validation_schedule(&self) -> Option<ValidationSchedule> {
+        let interval = Duration::from_secs(self.validation_interval_secs?);
+        let duty_cycle = match self.validation_duty_cycle_percent {
+            Some(percent) => f64::from(percent.clamp(1, 100)) / 100.0,
+            None => ValidationSchedule::default().duty_cycle,
+        };
+        Some(ValidationSchedule {
+            interval,
+            duty_cycle,
+            quarantine: self.validation_quarantine,
+        })
+    }
+
+    /// Constructs the configurations of the nodes to mirror.
+    pub fn mirrors(&self) -> Result<Vec<MirrorConfig>> {
+        self.mirrors
+            .iter()
+            .map(|entry| {
+                let peer = entry
+                    .peer
+                    .parse()
+                    .with_context(|| format!("invalid mirror peer id {}", entry.peer))?;
+                let token = entry
+                    .token
+                    .as_deref()
+                    .map(str::parse)
+                    .transpose()
+                    .context("invalid mirror request token")?;
+                Ok(MirrorConfig {
+                    peer,
+                    addrs: entry.addrs.clone(),
+                    derp_region: entry.derp_region,
+                    token,
+                    prefix: entry.prefix.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Constructs the configuration of the cluster, if any.
+    pub fn cluster(&self) -> Result<Option<ClusterConfig>> {
+        let Some(cluster) = &self.cluster else {
+            return Ok(None);
+        };
+        let members = cluster
+            .members
+            .iter()
+            .map(|entry| {
+                let peer = entry
+                    .peer
+                    .parse()
+                    .with_context(|| format!("invalid cluster member peer id {}", entry.peer))?;
+                Ok(ClusterMember {
+                    peer,
+                    addrs: entry.addrs.clone(),
+                    derp_region: entry.derp_region,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(ClusterConfig {
+            members,
+            replicas: cluster.replicas,
+            // only needed with expiring tokens, which are enabled on the command line
+            signed_fetches: false,
+        }))
+    }
+
+    /// Constructs the configuration of the log file, if any.
+    pub fn log_file(&self) -> Result<Option<LogFileConfig>> {
+        let Some(entry) = &self.log_file else {
+            return Ok(None);
+        };
+        let path = entry
+            .path
+            .clone()
+            .unwrap_or_else(|| Path::new("logs").join("iroh.log"));
+        Ok(Some(LogFileConfig {
+            path: self.data_root()?.join(path),
+            max_size: entry.max_size.unwrap_or(logging::DEFAULT_MAX_SIZE),
+            max_age: entry.max_age_secs.map(Duration::from_secs),
+            max_files: entry.max_files.unwrap_or(logging::DEFAULT_MAX_FILES),
+            filter: entry
+                .filter
+                .clone()
+                .unwrap_or_else(|| logging::DEFAULT_FILTER.to_string()),
+        }))
+    }
+
+    /// Returns the path to the iroh data directory.
+    ///
+    /// This is [`Config::data_dir`] if set, which includes the `--data-dir` flag and the
+    /// `IROH_DATA_DIR` environment variable, see [`path_overrides`]. Otherwise the returned
+    /// value depends on the operating system according to the following table.
+    ///
+    /// | Platform | Value                                              | Example                                       |
+    /// | -------- | -------------------------------------------------- | --------------------------------------------- |
+    /// | Linux    | `$XDG_DATA_HOME`/iroh or `$HOME`/.local/share/iroh | /home/alice/.local/share/iroh                 |
+    /// | macOS    | `$HOME`/Library/Application Support/iroh           | /Users/Alice/Library/Application Support/iroh |
+    /// | Windows  | `{FOLDERID_RoamingAppData}`/iroh                   | C:\Users\Alice\AppData\Roaming\iroh           |
+    pub fn data_root(&self) -> Result<PathBuf> {
+        let path = match &self.data_dir {
+            Some(path) => path.clone(),
+            None => dirs_next::data_dir()
+                .ok_or_else(|| {
+                    anyhow!("operating environment provides no directory for application data")
+                })?
+                .join(IROH_DIR),
+        };
+        absolute(path)
+    }
+
+    /// Returns the path to the iroh cache directory.
+    ///
+    /// This is [`Config::cache_dir`] if set, which includes the `--cache-dir` flag and the
+    /// `IROH_CACHE_DIR` environment variable, see [`path_overrides`]. Otherwise the returned
+    /// value depends on the operating system according to the following table.
+    ///
+    /// | Platform | Value                                         | Example                                  |
+    /// | -------- | --------------------------------------------- | ---------------------------------------- |
+    /// | Linux    | `$XDG_CACHE_HOME`/iroh or `$HOME`/.cache/iroh | /home/alice/.cache/iroh                  |
+    /// | macOS    | `$HOME`/Library/Caches/iroh                   | /Users/Alice/Library/Caches/iroh         |
+    /// | Windows  | `{FOLDERID_LocalAppData}`/iroh                | C:\Users\Alice\AppData\Local\iroh        |
+    pub fn cache_root(&self) -> Result<PathBuf> {
+        let path = match &self.cache_dir {
+            Some(path) => path.clone(),
+            None => dirs_next::cache_dir()
+                .ok_or_else(|| anyhow!("operating environment provides no directory for caches"))?
+                .join(IROH_DIR),
+        };
+        absolute(path)
+    }
+
+    /// The resolved config, data and cache directories.
+    pub fn paths(&self) -> Result<NodePaths> {
+        Ok(NodePaths {
+            config_dir: absolute(iroh_config_root()?)?,
+            data_dir: self.data_root()?,
+            cache_dir: self.cache_root()?,
+        })
+    }
+
+    /// Limits for the files the store keeps open for reading.
+    pub fn file_handle_limits(&self) -> HandleLimits {
+        let default = HandleLimits::default();
+        HandleLimits {
+            max_open: self.max_open_files.unwrap_or(default.max_open),
+            idle_ttl: self
+                .open_file_idle_secs
+                .map_or(default.idle_ttl, Duration::from_secs),
+        }
+    }
+
+    /// How long the store keeps deleted blobs in the trash.
+    pub fn trash_retention(&self) -> Duration {
+        self.trash_retention_secs
+            .map_or(flat::DEFAULT_TRASH_RETENTION, Duration::from_secs)
+    }
 }
 
 /// Name of directory that wraps all iroh files in a given application directory
@@ -108,11 +600,11 @@ const IROH_DIR: &str = "iroh";
 /// Otherwise the returned value depends on the operating system according to the following
 /// table.
 ///
-/// | Platform | Value                                 | Example                          |
-/// | -------- | ------------------------------------- | -------------------------------- |
-/// | Linux    | `$XDG_CONFIG_HOME` or `$HOME`/.config/iroh | /home/alice/.config/iroh              |
-/// | macOS    | `$HOME`/Library/Application Support/iroh   | /Users/Alice/Library/Application Support/iroh |
-/// | Windows  | `{FOLDERID_RoamingAppData}`/iroh           | C:\Users\Alice\AppData\Roaming\iroh   |
+/// | Platform | Value                                           | Example                                       |
+/// | -------- | ----------------------------------------------- | --------------------------------------------- |
+/// | Linux    | `$XDG_CONFIG_HOME`/iroh or `$HOME`/.config/iroh | /home/alice/.config/iroh                      |
+/// | macOS    | `$HOME`/Library/Application Support/iroh        | /Users/Alice/Library/Application Support/iroh |
+/// | Windows  | `{FOLDERID_RoamingAppData}`/iroh                | C:\Users\Alice\AppData\Roaming\iroh           |
 pub fn iroh_config_root() -> Result<PathBuf> {
     if let Some(val) = env::var_os("IROH_CONFIG_DIR") {
         return Ok(PathBuf::from(val));
@@ -128,63 +620,36 @@ pub fn iroh_config_path(file_name: impl AsRef<Path>) -> Result<PathBuf> {
     Ok(path)
 }
 
-/// Returns the path to the user's iroh data directory.
-///
-/// If the `IROH_DATA_DIR` environment variable is set it will be used unconditionally.
-/// Otherwise the returned value depends on the operating system according to the following
-/// table.
+/// Overrides of the paths in the config, from the command line flags and the environment.
 ///
-/// | Platform | Value                                         | Example                                  |
-/// | -------- | --------------------------------------------- | ---------------------------------------- |
-/// | Linux    | `$XDG_DATA_HOME`/iroh or `$HOME`/.local/share/iroh | /home/alice/.local/share/iroh                 |
-/// | macOS    | `$HOME`/Library/Application Support/iroh      | /Users/Alice/Library/Application Support/iroh |
-/// | Windows  | `{FOLDERID_RoamingAppData}/iroh`              | C:\Users\Alice\AppData\Roaming\iroh           |
-pub fn iroh_data_root() -> Result<PathBuf> {
-    if let Some(val) = env::var_os("IROH_DATA_DIR") {
-        return Ok(PathBuf::from(val));
+/// A flag takes precedence over the `IROH_DATA_DIR`, `IROH_CACHE_DIR` and `IROH_RPC_SOCKET`
+/// environment variables, which take precedence over the config files.
+pub fn path_overrides(
+    data_dir: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    rpc_socket: Option<PathBuf>,
+) -> HashMap<&'static str, String> {
+    let mut overrides = HashMap::new();
+    let paths = [
+        ("data_dir", data_dir, "IROH_DATA_DIR"),
+        ("cache_dir", cache_dir, "IROH_CACHE_DIR"),
+        ("rpc_socket", rpc_socket, "IROH_RPC_SOCKET"),
+    ];
+    for (field, flag, var) in paths {
+        if let Some(path) = flag.or_else(|| env::var_os(var).map(PathBuf::from)) {
+            overrides.insert(field, path.to_string_lossy().into_owned());
+        }
     }
-    let path = dirs_next::data_dir().ok_or_else(|| {
-        anyhow!("operating environment provides no directory for application data")
-    })?;
-    Ok(path.join(IROH_DIR))
+    overrides
 }
 
-/// Path that leads to a file in the iroh data directory.
-#[allow(dead_code)]
-pub fn iroh_data_path(file_name: &Path) -> Result<PathBuf> {
-    let path = iroh_data_root()?.join(file_name);
-    Ok(path)
-}
-
-/// Returns the path to the user's iroh cache directory.
-///
-/// If the `IROH_CACHE_DIR` environment variable is set it will be used unconditionally.
-/// Otherwise the returned value depends on the operating system according to the following
-/// table.
-///
-/// | Platform | Value                                         | Example                                  |
-/// | -------- | --------------------------------------------- | ---------------------------------------- |
-/// | Linux    | `$XDG_CACHE_HOME`/iroh or `$HOME`/.cache/iroh | /home/.cache/iroh                        |
-/// | macOS    | `$HOME`/Library/Caches/iroh                   | /Users/Alice/Library/Caches/iroh         |
-/// | Windows  | `{FOLDERID_LocalAppData}/iroh`                | C:\Users\Alice\AppData\Roaming\iroh      |
-#[allow(dead_code)]
-pub fn iroh_cache_root() -> Result<PathBuf> {
-    if let Some(val) = env::var_os("IROH_CACHE_DIR") {
-        return Ok(PathBuf::from(val));
+/// Makes a relative path absolute, relative to the current directory.
+fn absolute(path: PathBuf) -> Result<PathBuf> {
+    if path.is_absolute() {
+        return Ok(path);
     }
-    let path = dirs_next::cache_dir().ok_or_else(|| {
-        anyhow!("operating environment provides no directory for application data")
-    })?;
-    Ok(path.join(IROH_DIR))
+    Ok(env::current_dir()?.join(path))
 }
-
-/// Path that leads to a file in the iroh cache directory.
-#[allow(dead_code)]
-pub fn iroh_cache_path(file_name: &Path) -> Result<PathBuf> {
-    let path = iroh_cache_root()?.join(file_name);
-    Ok(path)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -194,5 +659,116 @@ mod tests {
         let config = Config::load::<String, String>(&[][..], "__FOO", Default::default()).unwrap();
 
         assert_eq!(config.derp_regions.len(), 2);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        std::fs::write(&path, "hash_thread = 4\n").unwrap();
+        let err =
+            Config::load::<String, String>(&[Some(path.as_path())], "__FOO", Default::default())
+                .unwrap_err();
+        assert!(err.to_string().contains("hash_thread"), "{err}");
+
+        std::fs::write(&path, "hash_threads = 4\n").unwrap();
+        let config =
+            Config::load::<String, String>(&[Some(path.as_path())], "__FOO", Default::default())
+                .unwrap();
+        assert_eq!(config.hash_threads, Some(4));
+    }
+
+    #[test]
+    fn test_dns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        std::fs::write(
+            &path,
+            "[dns]\nkind = \"https\"\naddrs = [\"1.1.1.1:443\"]\ntls_name = \"cloudflare-dns.com\"\n",
+        )
+        .unwrap();
+        let config =
+            Config::load::<String, String>(&[Some(path.as_path())], "__FOO", Default::default())
+                .unwrap();
+        assert_eq!(
+            config.dns,
+            DnsConfig::Https {
+                addrs: vec!["1.1.1.1:443".parse().unwrap()],
+                tls_name: "cloudflare-dns.com".to_string(),
+            }
+        );
+
+        let config = Config {
+            dns: DnsConfig::Nameservers { addrs: Vec::new() },
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_env_override() {
+        std::env::set_var("IROH_TEST_ENV_OVERRIDE__CHUNK_CACHE_BYTES", "1024");
+        let config =
+            Config::load::<String, String>(&[][..], "IROH_TEST_ENV_OVERRIDE", Default::default())
+                .unwrap();
+        assert_eq!(config.chunk_cache_bytes, 1024);
+    }
+
+    #[test]
+    fn test_path_precedence() {
+        let config = Config {
+            data_dir: Some("/from/config".into()),
+            ..Default::default()
+        };
+        assert_eq!(config.data_root().unwrap(), Path::new("/from/config"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        std::fs::write(&path, "data_dir = \"/from/config\"\n").unwrap();
+        let overrides = path_overrides(Some("/from/flag".into()), None, None);
+        let config = Config::load(&[Some(path.as_path())], "__FOO", overrides).unwrap();
+        assert_eq!(config.data_root().unwrap(), Path::new("/from/flag"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Config {
+            disk_space_low_watermark: Some(100),
+            disk_space_high_watermark: Some(10),
+            memory_high_limit: Some(10),
+            memory_low_limit: Some(100),
+            validation_duty_cycle_percent: Some(0),
+            hash_threads: Some(0),
+            log_file: Some(LogFileEntry {
+                path: None,
+                max_size: Some(0),
+                max_age_secs: None,
+                max_files: None,
+                filter: Some("iroh=loud".to_string()),
+            }),
+            cluster: Some(ClusterEntry {
+                replicas: 2,
+                members: vec![ClusterMemberEntry {
+                    peer: "not a peer id".to_string(),
+                    addrs: Vec::new(),
+                    derp_region: None,
+                }],
+            }),
+            ..Default::default()
+        };
+        let err = config.validate().unwrap_err().to_string();
+        for field in [
+            "disk_space_high_watermark",
+            "memory_low_limit",
+            "validation_duty_cycle_percent",
+            "hash_threads",
+            "log_file.max_size",
+            "log_file.filter",
+            "invalid cluster member peer id",
+            "cluster.replicas",
+        ] {
+            assert!(err.contains(field), "{field} missing from {err}");
+        }
     }
 }
@@ -0,0 +1,414 @@
+//! Versioned layout of the iroh data directory.
+//!
+//! The data directory holds the keypair of the node and the blob store. Its layout is
+//! versioned with a marker file, and [`DataDir::migrate`] upgrades a directory written by an
+//! older release to the current layout, one [`Migration`] at a time.
+//!
+//! A directory without a marker is either new, and gets the current layout, or was written
+//! before the layout was versioned, which is version 0.
+//!
+//! Migrations only move files within the directory and are safe to run again, so by
+//! default nothing is copied. A full backup of the directory, including the blob store,
+//! can be requested with [`MigrateOptions::backup`].
+//!
+//! Layout version 1:
+//!
+//! ```text
+//! version           the layout version, as a decimal number
+//! keypair           the keypair of the node
+//! namespace_key     the key of the keyed namespace, if the node serves one
+//! console_history   the history of the iroh console
+//! downloads         the downloads that have not completed yet
+//! blobs/            the flat blob store, complete and partial files
+//! ```
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use iroh_bytes::keyed::NamespaceKey;
+use iroh_net::tls::Keypair;
+
+/// Name of the file containing the layout version.
+pub const VERSION_FILE: &str = "version";
+/// Name of the file containing the keypair.
+pub const KEYPAIR_FILE: &str = "keypair";
+/// Name of the file containing the key of the keyed namespace.
+pub const NAMESPACE_KEY_FILE: &str = "namespace_key";
+/// Name of the file containing the history of the iroh console.
+pub const CONSOLE_HISTORY_FILE: &str = "console_history";
+/// Name of the file containing the downloads that have not completed yet.
+pub const DOWNLOADS_FILE: &str = "downloads";
+/// Name of the directory containing the blob store.
+pub const BLOBS_DIR: &str = "blobs";
+
+/// The layout version written by this release.
+pub const CURRENT_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// An upgrade of the layout from one version to the next.
+///
+/// Migrations must be safe to run again after being interrupted, since the version marker
+/// is only updated once a migration completes.
+#[derive(Debug)]
+pub struct Migration {
+    /// What the migration does.
+    pub description: &'static str,
+    run: fn(&Path) -> Result<()>,
+}
+
+/// All migrations, the migration at index `i` upgrades version `i` to `i + 1`.
+const MIGRATIONS: &[Migration] = &[Migration {
+    description: "move the blob store into the blobs directory",
+    run: move_blobs,
+}];
+
+/// Options for [`DataDir::migrate`].
+#[derive(Debug, Clone, Copy)]
+pub struct MigrateOptions {
+    /// Report the migrations that would run, without changing anything.
+    pub dry_run: bool,
+    /// Back up the data directory before migrating it, see [`DataDir::backup_path`].
+    ///
+    /// The backup is a full copy, so it needs as much free space as the directory uses,
+    /// blob store included.
+    pub backup: bool,
+}
+
+impl Default for MigrateOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            backup: false,
+        }
+    }
+}
+
+/// The outcome of [`DataDir::migrate`].
+#[derive(Debug)]
+pub struct Migrated {
+    /// Layout version before migrating.
+    pub from: u32,
+    /// Layout version after migrating, or that it would have with a dry run.
+    pub to: u32,
+    /// The migrations that were run.
+    pub applied: Vec<&'static Migration>,
+    /// The backup of the directory before migrating, if one was made.
+    pub backup: Option<PathBuf>,
+}
+
+/// The iroh data directory.
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+impl DataDir {
+    /// A data directory at `root`, which does not need to exist yet.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The root of the data directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path of the keypair file.
+    pub fn keypair_path(&self) -> PathBuf {
+        self.root.join(KEYPAIR_FILE)
+    }
+
+    /// Loads the keypair of the node, generating and saving a new one if there is none.
+    ///
+    /// A new keypair is written to a temporary file first, so an interrupted write never
+    /// leaves a corrupt keypair behind.
+    pub fn load_keypair(&self) -> Result<Keypair> {
+        let path = self.keypair_path();
+        if path.exists() {
+            let keystr = std::fs::read(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            return Keypair::try_from_openssh(keystr).context("invalid keyfile");
+        }
+        let keypair = Keypair::generate();
+        let ser_key = keypair.to_openssh()?;
+        std::fs::create_dir_all(&self.root)
+            .with_context(|| format!("failed to create {}", self.root.display()))?;
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, ser_key.as_bytes()).context("unable to write keyfile")?;
+        std::fs::rename(&temp_path, &path).context("failed to rename keyfile")?;
+        Ok(keypair)
+    }
+
+    /// Path of the namespace key file.
+    pub fn namespace_key_path(&self) -> PathBuf {
+        self.root.join(NAMESPACE_KEY_FILE)
+    }
+
+    /// Loads the key of the keyed namespace, generating and saving a new one if there is
+    /// none.
+    ///
+    /// Changing the key renames all content, so tickets handed out before stop working.
+    pub fn load_namespace_key(&self) -> Result<NamespaceKey> {
+        let path = self.namespace_key_path();
+        if path.exists() {
+            let bytes = std::fs::read(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let bytes = bytes
+                .try_into()
+                .ok()
+                .context("invalid namespace key file")?;
+            return Ok(NamespaceKey::from_bytes(bytes));
+        }
+        let key = NamespaceKey::generate();
+        std::fs::create_dir_all(&self.root)
+            .with_context(|| format!("failed to create {}", self.root.display()))?;
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, key.as_bytes()).context("unable to write namespace key")?;
+        std::fs::rename(&temp_path, &path).context("failed to rename namespace key file")?;
+        Ok(key)
+    }
+
+    /// Path of the console history file.
+    pub fn console_history_path(&self) -> PathBuf {
+        self.root.join(CONSOLE_HISTORY_FILE)
+    }
+
+    /// Path of the file with the downloads that have not completed yet.
+    pub fn downloads_path(&self) -> PathBuf {
+        self.root.join(DOWNLOADS_FILE)
+    }
+
+    /// Path of the blob store directory.
+    pub fn blobs_path(&self) -> PathBuf {
+        self.root.join(BLOBS_DIR)
+    }
+
+    /// Path of the backup made before migrating from `version`.
+    ///
+    /// Backups are siblings of the data directory, e.g. `iroh.backup-v0` for `iroh`.
+    pub fn backup_path(&self, version: u32) -> PathBuf {
+        let mut name = self.root.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".backup-v{version}"));
+        self.root.with_file_name(name)
+    }
+
+    /// The layout version of the directory, `None` if it does not exist or is empty.
+    pub fn version(&self) -> Result<Option<u32>> {
+        let path = self.root.join(VERSION_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let version = text
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid version in {}", path.display()))?;
+                Ok(Some(version))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match std::fs::read_dir(&self.root) {
+                Ok(mut entries) if entries.next().is_some() => Ok(Some(0)),
+                Ok(_) => Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Creates the directory with the current layout, or upgrades it to the current layout.
+    ///
+    /// Fails if the directory was written by a newer release, which this release can not
+    /// read.
+    pub fn migrate(&self, opts: MigrateOptions) -> Result<Migrated> {
+        let Some(from) = self.version()? else {
+            if !opts.dry_run {
+                std::fs::create_dir_all(self.blobs_path())?;
+                self.write_version(CURRENT_VERSION)?;
+            }
+            return Ok(Migrated {
+                from: CURRENT_VERSION,
+                to: CURRENT_VERSION,
+                applied: Vec::new(),
+                backup: None,
+            });
+        };
+        ensure!(
+            from <= CURRENT_VERSION,
+            "{} has layout version {from}, but this release only supports up to version {CURRENT_VERSION}. Upgrade iroh to use it.",
+            self.root.display()
+        );
+        let applied = MIGRATIONS[from as usize..].iter().collect::<Vec<_>>();
+        let mut backup = None;
+        if !applied.is_empty() && !opts.dry_run {
+            if opts.backup {
+                let path = self.backup_path(from);
+                if path.exists() {
+                    bail!(
+                        "backup {} already exists, remove it or migrate without a backup",
+                        path.display()
+                    );
+                }
+                copy_dir(&self.root, &path)
+                    .with_context(|| format!("failed to back up to {}", path.display()))?;
+                backup = Some(path);
+            }
+            for (version, migration) in (from..).zip(&applied) {
+                (migration.run)(&self.root).with_context(|| {
+                    format!(
+                        "failed to migrate from version {version}: {}",
+                        migration.description
+                    )
+                })?;
+                self.write_version(version + 1)?;
+            }
+        }
+        Ok(Migrated {
+            from,
+            to: CURRENT_VERSION,
+            applied,
+            backup,
+        })
+    }
+
+    fn write_version(&self, version: u32) -> Result<()> {
+        let tmp = self.root.join(format!("{VERSION_FILE}.tmp"));
+        std::fs::write(&tmp, format!("{version}\n"))?;
+        std::fs::rename(tmp, self.root.join(VERSION_FILE))?;
+        Ok(())
+    }
+}
+
+/// Version 0 to 1: the store lived in the root of the data directory, next to the keypair.
+fn move_blobs(root: &Path) -> Result<()> {
+    let blobs = root.join(BLOBS_DIR);
+    std::fs::create_dir_all(&blobs)?;
+    for entry in std::fs::read_dir(root)? {
+        let name = entry?.file_name();
+        if [KEYPAIR_FILE, VERSION_FILE, CONSOLE_HISTORY_FILE, BLOBS_DIR]
+            .contains(&name.to_str().unwrap_or_default())
+        {
+            continue;
+        }
+        std::fs::rename(root.join(&name), blobs.join(&name))?;
+    }
+    Ok(())
+}
+
+/// Copies the tree at `src` to `dst`.
+///
+/// Files are copied rather than hard linked, so that writes to the data directory, e.g. by
+/// a store that modifies its files in place, never change the backup.
+fn copy_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().join("iroh"));
+        assert_eq!(data_dir.version().unwrap(), None);
+
+        let migrated = data_dir.migrate(Default::default()).unwrap();
+        assert!(migrated.applied.is_empty());
+        assert_eq!(data_dir.version().unwrap(), Some(CURRENT_VERSION));
+        assert!(data_dir.blobs_path().is_dir());
+    }
+
+    #[test]
+    fn test_load_keypair() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().join("iroh"));
+        let keypair = data_dir.load_keypair().unwrap();
+        assert!(data_dir.keypair_path().is_file());
+        let loaded = data_dir.load_keypair().unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+    }
+
+    #[test]
+    fn test_load_namespace_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().join("iroh"));
+        let key = data_dir.load_namespace_key().unwrap();
+        assert_eq!(data_dir.load_namespace_key().unwrap(), key);
+
+        std::fs::write(data_dir.namespace_key_path(), b"short").unwrap();
+        assert!(data_dir.load_namespace_key().is_err());
+    }
+
+    #[test]
+    fn test_migrate_unversioned() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().join("iroh"));
+        std::fs::create_dir_all(data_dir.root()).unwrap();
+        std::fs::write(data_dir.keypair_path(), b"key").unwrap();
+        std::fs::write(data_dir.console_history_path(), b"list").unwrap();
+        std::fs::write(data_dir.root().join("blob.data"), b"data").unwrap();
+        assert_eq!(data_dir.version().unwrap(), Some(0));
+
+        let opts = MigrateOptions {
+            dry_run: true,
+            backup: true,
+        };
+        let migrated = data_dir.migrate(opts).unwrap();
+        assert_eq!(migrated.applied.len(), MIGRATIONS.len());
+        assert_eq!(data_dir.version().unwrap(), Some(0));
+        assert!(!data_dir.blobs_path().exists());
+
+        let opts = MigrateOptions {
+            dry_run: false,
+            backup: true,
+        };
+        let migrated = data_dir.migrate(opts).unwrap();
+        assert_eq!((migrated.from, migrated.to), (0, CURRENT_VERSION));
+        assert_eq!(data_dir.version().unwrap(), Some(CURRENT_VERSION));
+        assert_eq!(std::fs::read(data_dir.keypair_path()).unwrap(), b"key");
+        assert!(data_dir.console_history_path().exists());
+        let moved = data_dir.blobs_path().join("blob.data");
+        assert_eq!(std::fs::read(&moved).unwrap(), b"data");
+        let backup = migrated.backup.unwrap();
+        assert_eq!(backup, data_dir.backup_path(0));
+        assert_eq!(std::fs::read(backup.join("blob.data")).unwrap(), b"data");
+        // the backup does not share files with the data directory
+        std::fs::write(&moved, b"edit").unwrap();
+        assert_eq!(std::fs::read(backup.join("blob.data")).unwrap(), b"data");
+
+        // a second migration is a no-op
+        let migrated = data_dir.migrate(Default::default()).unwrap();
+        assert!(migrated.applied.is_empty());
+        assert!(migrated.backup.is_none());
+    }
+
+    #[test]
+    fn test_migrate_without_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().join("iroh"));
+        std::fs::create_dir_all(data_dir.root()).unwrap();
+        std::fs::write(data_dir.root().join("blob.data"), b"data").unwrap();
+
+        let migrated = data_dir.migrate(Default::default()).unwrap();
+        assert_eq!(migrated.applied.len(), MIGRATIONS.len());
+        assert!(migrated.backup.is_none());
+        assert!(!data_dir.backup_path(0).exists());
+        let moved = data_dir.blobs_path().join("blob.data");
+        assert_eq!(std::fs::read(moved).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_newer_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path());
+        data_dir.write_version(CURRENT_VERSION + 1).unwrap();
+        assert!(data_dir.migrate(Default::default()).is_err());
+    }
+}
@@ -1,19 +1,38 @@
 //! The ticket type for the provider.
 //!
-//! This is in it's own module to enforce the invariant that you can not construct a ticket
-//! with an empty address list.
+//! A ticket always contains the peer id of the provider. The DERP region and the direct
+//! addresses of the provider can be left out using [`TicketOptions`], in which case the
+//! provider is looked up in the DERP regions when dialing.
+//!
+//! A ticket can list several providers of the same content, see [`Ticket::merge`]. They
+//! are dialed in parallel with [`dial_any`], and the first one to answer is used.
+//!
+//! A ticket for private content also contains the name of the hash in the keyed namespace
+//! of its providers, declared by the [`HashDomain`] of the ticket. The content is
+//! requested by that name and verified against the hash.
+//!
+//! Serialized tickets start with a version byte, so that the layout can change without
+//! breaking tickets that are already out there. Tickets from before the version byte was
+//! added, which name a single provider, are still accepted.
 
 use std::fmt::{self, Display};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Duration;
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use futures::FutureExt;
+use iroh_bytes::keyed::HashDomain;
 use iroh_bytes::protocol::RequestToken;
 use iroh_bytes::Hash;
 use iroh_net::derp::DerpMap;
+use iroh_net::dns::DnsResolver;
+use iroh_net::magic_endpoint::qlog::QlogConfig;
 use iroh_net::tls::{Keypair, PeerId};
 use serde::{Deserialize, Serialize};
 
+use crate::util::expiring_token;
+
 /// Options for the client
 #[derive(Clone, Debug)]
 pub struct Options {
@@ -29,31 +48,195 @@ pub struct Options {
     pub derp_map: Option<DerpMap>,
     /// The DERP region of the node
     pub derp_region: Option<u16>,
+    /// Write a qlog trace of the connection, if selected by this config
+    pub qlog: Option<QlogConfig>,
+    /// The resolver for DERP and STUN hostnames
+    pub dns_resolver: DnsResolver,
 }
 
+/// How long to try each DERP region when looking up a peer without any dialing info.
+const DISCOVERY_DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Create a new endpoint and dial a peer, returning the connection
 ///
 /// Note that this will create an entirely new endpoint, so it should be only
 /// used for short lived connections. If you want to connect to multiple peers,
 /// it is preferable to create an endpoint and use `connect` on the endpoint.
+///
+/// If neither addresses nor a DERP region are given, the peer is looked up by dialing it
+/// through each region of the DERP map in turn.
 pub async fn dial(opts: Options) -> anyhow::Result<quinn::Connection> {
-    let endpoint = iroh_net::MagicEndpoint::builder()
-        .keypair(opts.keypair)
-        .derp_map(opts.derp_map)
-        .keylog(opts.keylog)
-        .bind(0)
-        .await?;
+    if !opts.addrs.is_empty() || opts.derp_region.is_some() {
+        return dial_with(&opts, opts.derp_region).await;
+    }
+    let Some(derp_map) = &opts.derp_map else {
+        bail!("no addresses or DERP region to dial the provider");
+    };
+    // The magic socket does not update the region of a known peer, so every region is
+    // tried on a fresh endpoint.
+    for region in derp_map.region_ids() {
+        tracing::debug!("looking up provider {} in region {region}", opts.peer_id);
+        match tokio::time::timeout(DISCOVERY_DIAL_TIMEOUT, dial_with(&opts, Some(region))).await {
+            Ok(Ok(conn)) => return Ok(conn),
+            Ok(Err(err)) => tracing::debug!("provider not reachable via region {region}: {err:#}"),
+            Err(_) => tracing::debug!("provider not found in region {region}"),
+        }
+    }
+    bail!("failed to find provider in any DERP region")
+}
+
+/// Dial several providers in parallel, returning the first connection that succeeds.
+///
+/// Providers that can not be reached are skipped, the dial only fails if none of them
+/// can be reached.
+pub async fn dial_any(opts: Vec<Options>) -> anyhow::Result<(quinn::Connection, PeerId)> {
+    ensure!(!opts.is_empty(), "no providers to dial");
+    let dials = opts.into_iter().map(|opts| {
+        async move {
+            let peer_id = opts.peer_id;
+            dial(opts)
+                .await
+                .map(|conn| (conn, peer_id))
+                .with_context(|| format!("failed to dial {peer_id}"))
+        }
+        .boxed()
+    });
+    let (res, _pending) = futures::future::select_ok(dials).await?;
+    Ok(res)
+}
+
+async fn dial_with(opts: &Options, derp_region: Option<u16>) -> anyhow::Result<quinn::Connection> {
+    let mut builder = iroh_net::MagicEndpoint::builder()
+        .keypair(opts.keypair.clone())
+        .derp_map(opts.derp_map.clone())
+        .dns_resolver(opts.dns_resolver.clone())
+        .keylog(opts.keylog);
+    if let Some(qlog) = &opts.qlog {
+        builder = builder.qlog(qlog.clone());
+    }
+    let endpoint = builder.bind(0).await?;
     endpoint
         .connect(
             opts.peer_id,
             &iroh_bytes::protocol::ALPN,
-            opts.derp_region,
+            derp_region,
             &opts.addrs,
         )
         .await
         .context("failed to connect to provider")
 }
 
+/// What dialing info to embed in a [`Ticket`].
+///
+/// The peer id of the provider is always embedded. By default the DERP region and the
+/// direct addresses are embedded too, leaving them out makes the ticket smaller and avoids
+/// sharing the network location of the provider, at the cost of a slower lookup when
+/// dialing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TicketOptions {
+    derp_region: bool,
+    direct_addrs: bool,
+}
+
+impl Default for TicketOptions {
+    fn default() -> Self {
+        Self {
+            derp_region: true,
+            direct_addrs: true,
+        }
+    }
+}
+
+impl TicketOptions {
+    /// Only embed the peer id of the provider.
+    pub fn peer_only() -> Self {
+        Self {
+            derp_region: false,
+            direct_addrs: false,
+        }
+    }
+
+    /// Whether to embed the DERP region of the provider.
+    pub fn derp_region(mut self, derp_region: bool) -> Self {
+        self.derp_region = derp_region;
+        self
+    }
+
+    /// Whether to embed the direct addresses of the provider.
+    pub fn direct_addrs(mut self, direct_addrs: bool) -> Self {
+        self.direct_addrs = direct_addrs;
+        self
+    }
+}
+
+/// The dialing info of one provider in a [`Ticket`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProviderAddr {
+    /// The peer ID identifying the provider.
+    pub peer: PeerId,
+    /// The socket addresses the provider is listening on.
+    pub addrs: Vec<SocketAddr>,
+    /// DERP region of the provider
+    pub derp_region: Option<u16>,
+}
+
+impl ProviderAddr {
+    /// Remove the dialing info not selected by `options`.
+    fn with_options(self, options: TicketOptions) -> Self {
+        Self {
+            addrs: if options.direct_addrs {
+                self.addrs
+            } else {
+                Vec::new()
+            },
+            derp_region: self.derp_region.filter(|_| options.derp_region),
+            ..self
+        }
+    }
+}
+
+/// The maximum size of a serialized [`Ticket`].
+///
+/// Tickets are pasted by users and arrive from untrusted sources. A ticket with a handful
+/// of providers is a few hundred bytes, this leaves plenty of room for merged tickets.
+pub const MAX_TICKET_SIZE: usize = 16 * 1024;
+
+/// The version of the layout of a serialized [`Ticket`], its first byte.
+const TICKET_VERSION: u8 = 1;
+
+/// The first byte of every ticket serialized before the version byte was added.
+///
+/// Legacy tickets start with the postcard length prefix of their hash, so versions must stay
+/// below this value.
+const LEGACY_TICKET_PREFIX: u8 = 32;
+
+/// The layout of a [`Ticket`] before it was versioned, with a single provider.
+#[derive(Debug, Deserialize)]
+struct LegacyTicket {
+    hash: Hash,
+    peer: PeerId,
+    token: Option<RequestToken>,
+    addrs: Vec<SocketAddr>,
+    recursive: bool,
+    derp_region: Option<u16>,
+}
+
+impl From<LegacyTicket> for Ticket {
+    fn from(legacy: LegacyTicket) -> Self {
+        Self {
+            hash: legacy.hash,
+            providers: vec![ProviderAddr {
+                peer: legacy.peer,
+                addrs: legacy.addrs,
+                derp_region: legacy.derp_region,
+            }],
+            token: legacy.token,
+            recursive: legacy.recursive,
+            domain: HashDomain::Plain,
+        }
+    }
+}
+
 /// A token containing everything to get a file from the provider.
 ///
 /// It is a single item which can be easily serialized and deserialized.  The [`Display`]
@@ -62,18 +245,16 @@ pub async fn dial(opts: Options) -> anyhow::Result<quinn::Connection> {
 pub struct Ticket {
     /// The hash to retrieve.
     hash: Hash,
-    /// The peer ID identifying the provider.
-    peer: PeerId,
+    /// The providers of the hash, never empty.
+    ///
+    /// The first provider is the primary one, the one the ticket was created for.
+    providers: Vec<ProviderAddr>,
     /// Optional Request token.
     token: Option<RequestToken>,
-    /// The socket addresses the provider is listening on.
-    ///
-    /// This will never be empty.
-    addrs: Vec<SocketAddr>,
     /// True to treat the hash as a collection and retrieve all blobs in it.
     recursive: bool,
-    /// DERP region of the provider
-    derp_region: Option<u16>,
+    /// Whether the hash is requested by itself, or by a name in a keyed namespace.
+    domain: HashDomain,
 }
 
 impl Ticket {
@@ -86,27 +267,99 @@ impl Ticket {
         recursive: bool,
         derp_region: Option<u16>,
     ) -> Result<Self> {
-        ensure!(!addrs.is_empty(), "addrs list can not be empty");
         Ok(Self {
             hash,
-            peer,
-            addrs,
+            providers: vec![ProviderAddr {
+                peer,
+                addrs,
+                derp_region,
+            }],
             token,
             recursive,
-            derp_region,
+            domain: HashDomain::Plain,
         })
     }
 
+    /// Combines tickets for the same content into one ticket listing all their providers.
+    ///
+    /// The tickets must agree on the hash and its domain, whether it is a collection, and
+    /// the request token. Expiring tokens signed by different providers are combined
+    /// instead, see [`expiring_token::combine`]. Providers listed more than once are only
+    /// kept once, with the dialing info of all their entries combined.
+    pub fn merge(tickets: impl IntoIterator<Item = Ticket>) -> Result<Self> {
+        let mut tickets = tickets.into_iter();
+        let mut merged = tickets.next().context("no tickets to merge")?;
+        for ticket in tickets {
+            ensure!(
+                ticket.hash == merged.hash,
+                "tickets are for different hashes: {} and {}",
+                merged.hash,
+                ticket.hash
+            );
+            ensure!(
+                ticket.domain == merged.domain,
+                "tickets disagree about the domain of {}",
+                merged.hash
+            );
+            ensure!(
+                ticket.recursive == merged.recursive,
+                "tickets disagree about whether {} is a collection",
+                merged.hash
+            );
+            if ticket.token != merged.token {
+                merged.token = match (&merged.token, &ticket.token) {
+                    (Some(a), Some(b)) => Some(
+                        expiring_token::combine([a, b])
+                            .context("tickets have different request tokens")?,
+                    ),
+                    _ => bail!("tickets have different request tokens"),
+                };
+            }
+            for provider in ticket.providers {
+                merged.add_provider(provider);
+            }
+        }
+        Ok(merged)
+    }
+
+    fn add_provider(&mut self, provider: ProviderAddr) {
+        match self.providers.iter_mut().find(|p| p.peer == provider.peer) {
+            Some(existing) => {
+                for addr in provider.addrs {
+                    if !existing.addrs.contains(&addr) {
+                        existing.addrs.push(addr);
+                    }
+                }
+                existing.derp_region = existing.derp_region.or(provider.derp_region);
+            }
+            None => self.providers.push(provider),
+        }
+    }
+
     /// Deserializes from bytes.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        let slf: Ticket = postcard::from_bytes(bytes)?;
-        ensure!(!slf.addrs.is_empty(), "Invalid address list in ticket");
+        ensure!(
+            bytes.len() <= MAX_TICKET_SIZE,
+            "ticket of {} bytes exceeds the maximum of {MAX_TICKET_SIZE}",
+            bytes.len()
+        );
+        let slf: Ticket = match bytes.split_first() {
+            Some((&TICKET_VERSION, rest)) => postcard::from_bytes(rest)?,
+            Some((&LEGACY_TICKET_PREFIX, _)) => postcard::from_bytes::<LegacyTicket>(bytes)?.into(),
+            Some((version, _)) => bail!("unsupported ticket version {version}"),
+            None => bail!("empty ticket"),
+        };
+        ensure!(!slf.providers.is_empty(), "ticket without providers");
+        if let Some(token) = &slf.token {
+            // deserialization does not check the size limit of the token
+            RequestToken::new(token.as_bytes().clone())?;
+        }
         Ok(slf)
     }
 
-    /// Serializes to bytes.
+    /// Serializes to bytes, prefixed with the version of the layout.
     pub fn to_bytes(&self) -> Vec<u8> {
-        postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible")
+        postcard::to_stdvec(&(TICKET_VERSION, self)).expect("postcard::to_stdvec is infallible")
     }
 
     /// The hash of the item this ticket can retrieve.
@@ -114,9 +367,18 @@ impl Ticket {
         self.hash
     }
 
-    /// The [`PeerId`] of the provider for this ticket.
+    /// The [`PeerId`] of the primary provider for this ticket.
     pub fn peer(&self) -> PeerId {
-        self.peer
+        self.primary().peer
+    }
+
+    /// All providers of this ticket, the primary provider first.
+    pub fn providers(&self) -> &[ProviderAddr] {
+        &self.providers
+    }
+
+    fn primary(&self) -> &ProviderAddr {
+        &self.providers[0]
     }
 
     /// The [`RequestToken`] for this ticket.
@@ -139,50 +401,75 @@ impl Ticket {
         Self { recursive, ..self }
     }
 
-    /// The addresses on which the provider can be reached.
+    /// The domain of the hash of this ticket.
+    pub fn domain(&self) -> HashDomain {
+        self.domain
+    }
+
+    /// Set the domain of the hash of this ticket.
+    pub fn with_domain(self, domain: HashDomain) -> Self {
+        Self { domain, ..self }
+    }
+
+    /// The addresses on which the primary provider can be reached.
     ///
-    /// This is guaranteed to be non-empty.
+    /// Empty if the ticket was created without direct addresses.
     pub fn addrs(&self) -> &[SocketAddr] {
-        &self.addrs
+        &self.primary().addrs
     }
 
-    /// DERP region of the provider
+    /// Remove the dialing info not selected by `options`, from all providers.
+    pub fn with_options(self, options: TicketOptions) -> Self {
+        Self {
+            providers: self
+                .providers
+                .into_iter()
+                .map(|provider| provider.with_options(options))
+                .collect(),
+            ..self
+        }
+    }
+
+    /// DERP region of the primary provider
     pub fn derp_region(&self) -> Option<u16> {
-        self.derp_region
+        self.primary().derp_region
     }
 
     /// Get the contents of the ticket, consuming it.
-    pub fn into_parts(
-        self,
-    ) -> (
-        Hash,
-        PeerId,
-        Vec<SocketAddr>,
-        Option<RequestToken>,
-        bool,
-        Option<u16>,
-    ) {
+    pub fn into_parts(self) -> (Hash, Vec<ProviderAddr>, Option<RequestToken>, bool) {
         let Ticket {
             hash,
-            peer,
+            providers,
             token,
-            addrs,
             recursive,
-            derp_region,
+            ..
         } = self;
-        (hash, peer, addrs, token, recursive, derp_region)
+        (hash, providers, token, recursive)
     }
 
-    /// Convert this ticket into a [`Options`], adding the given keypair.
+    /// Convert this ticket into a [`Options`] for the primary provider, adding the given
+    /// keypair.
     pub fn as_get_options(&self, keypair: Keypair, derp_map: Option<DerpMap>) -> Options {
-        Options {
-            peer_id: self.peer,
-            addrs: self.addrs.clone(),
-            keypair,
-            keylog: true,
-            derp_region: self.derp_region,
-            derp_map,
-        }
+        self.as_all_get_options(keypair, derp_map).swap_remove(0)
+    }
+
+    /// Convert this ticket into [`Options`] for every provider, adding the given keypair.
+    ///
+    /// Use [`dial_any`] to dial them.
+    pub fn as_all_get_options(&self, keypair: Keypair, derp_map: Option<DerpMap>) -> Vec<Options> {
+        self.providers
+            .iter()
+            .map(|provider| Options {
+                peer_id: provider.peer,
+                addrs: provider.addrs.clone(),
+                keypair: keypair.clone(),
+                keylog: true,
+                derp_region: provider.derp_region,
+                derp_map: derp_map.clone(),
+                qlog: None,
+                dns_resolver: DnsResolver::default(),
+            })
+            .collect()
     }
 }
 
@@ -201,6 +488,10 @@ impl FromStr for Ticket {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ensure!(
+            s.len() <= data_encoding::BASE32_NOPAD.encode_len(MAX_TICKET_SIZE),
+            "ticket is too long"
+        );
         let bytes = data_encoding::BASE32_NOPAD.decode(s.to_ascii_uppercase().as_bytes())?;
         let slf = Self::from_bytes(&bytes)?;
         Ok(slf)
@@ -210,7 +501,8 @@ impl FromStr for Ticket {
 #[cfg(test)]
 mod tests {
     use bao_tree::blake3;
-    use iroh_net::tls::Keypair;
+    use iroh_net::tls::{Keypair, PublicKey, SecretKey};
+    use proptest::prelude::*;
 
     use super::*;
 
@@ -224,11 +516,14 @@ mod tests {
         let derp_region = Some(0);
         let ticket = Ticket {
             hash,
-            peer,
-            addrs: vec![addr],
+            providers: vec![ProviderAddr {
+                peer,
+                addrs: vec![addr],
+                derp_region,
+            }],
             token: Some(token),
             recursive: true,
-            derp_region,
+            domain: HashDomain::Plain,
         };
         let base32 = ticket.to_string();
         println!("Ticket: {base32}");
@@ -237,4 +532,158 @@ mod tests {
         let ticket2: Ticket = base32.parse().unwrap();
         assert_eq!(ticket2, ticket);
     }
+
+    #[test]
+    fn test_ticket_legacy() {
+        // a ticket serialized before the version byte was added
+        let legacy = "eadqobyha4dqobyha4dqobyha4dqobyha4dqobyha4dqobyha4dqoigxlkmadavrbk35ks762pewibz2b3qxf462uyrsllycdjupob2rdiaqgaicamaqa7yaaaa5ecibaeaq";
+        let key = hex::decode("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+            .unwrap();
+        let peer = PeerId::from(PublicKey::from_bytes(&key.try_into().unwrap()).unwrap());
+        let ticket: Ticket = legacy.parse().unwrap();
+        assert_eq!(ticket.hash(), Hash::from([7u8; 32]));
+        assert_eq!(ticket.peer(), peer);
+        assert_eq!(ticket.providers().len(), 1);
+        assert_eq!(ticket.token().unwrap().as_bytes().as_ref(), &[1, 2, 3]);
+        assert_eq!(
+            ticket.addrs(),
+            &[SocketAddr::from_str("127.0.0.1:1234").unwrap()]
+        );
+        assert!(ticket.recursive());
+        assert_eq!(ticket.derp_region(), Some(1));
+        assert_eq!(ticket.domain(), HashDomain::Plain);
+
+        // tickets are written in the current layout, which is versioned
+        let bytes = ticket.to_bytes();
+        assert_eq!(bytes[0], TICKET_VERSION);
+        assert_eq!(Ticket::from_bytes(&bytes).unwrap(), ticket);
+        assert_ne!(ticket.to_string(), legacy);
+
+        let mut unknown = bytes;
+        unknown[0] = TICKET_VERSION + 1;
+        assert!(Ticket::from_bytes(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_ticket_options() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let ticket = Ticket::new(hash, peer, vec![addr], None, true, Some(1)).unwrap();
+
+        let derp_only = ticket
+            .clone()
+            .with_options(TicketOptions::default().direct_addrs(false));
+        assert!(derp_only.addrs().is_empty());
+        assert_eq!(derp_only.derp_region(), Some(1));
+
+        let peer_only = ticket.clone().with_options(TicketOptions::peer_only());
+        assert!(peer_only.addrs().is_empty());
+        assert_eq!(peer_only.derp_region(), None);
+        assert!(peer_only.to_string().len() < ticket.to_string().len());
+
+        let parsed: Ticket = peer_only.to_string().parse().unwrap();
+        assert_eq!(parsed, peer_only);
+    }
+
+    #[test]
+    fn test_ticket_merge() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer_a = PeerId::from(Keypair::generate().public());
+        let peer_b = PeerId::from(Keypair::generate().public());
+        let addr_a = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let addr_b = SocketAddr::from_str("127.0.0.1:1235").unwrap();
+        let a = Ticket::new(hash, peer_a, vec![addr_a], None, true, Some(1)).unwrap();
+        let b = Ticket::new(hash, peer_b, vec![addr_b], None, true, None).unwrap();
+        let a2 = Ticket::new(hash, peer_a, vec![addr_b], None, true, None).unwrap();
+
+        let merged = Ticket::merge([a.clone(), b.clone(), a2]).unwrap();
+        assert_eq!(merged.peer(), peer_a);
+        assert_eq!(merged.providers().len(), 2);
+        assert_eq!(merged.addrs(), &[addr_a, addr_b]);
+        assert_eq!(merged.derp_region(), Some(1));
+        assert_eq!(merged.providers()[1].peer, peer_b);
+        assert_eq!(
+            merged.as_all_get_options(Keypair::generate(), None).len(),
+            2
+        );
+
+        let parsed: Ticket = merged.to_string().parse().unwrap();
+        assert_eq!(parsed, merged);
+
+        let other = Ticket::new(Hash::new(b"other"), peer_b, vec![], None, true, None).unwrap();
+        assert!(Ticket::merge([a.clone(), other]).is_err());
+        let single = a.clone().with_recursive(false);
+        assert!(Ticket::merge([a.clone(), single]).is_err());
+        let keyed = a.clone().with_domain(HashDomain::Keyed(Hash::new(b"name")));
+        assert!(Ticket::merge([a.clone(), keyed]).is_err());
+        assert!(Ticket::merge([]).is_err());
+
+        // expiring tokens of different providers are combined, other tokens must agree
+        let expires = std::time::SystemTime::now();
+        let token_a = expiring_token::create(&Keypair::generate(), hash, expires);
+        let token_b = expiring_token::create(&Keypair::generate(), hash, expires);
+        let a = a.with_token(Some(token_a.clone()));
+        let merged =
+            Ticket::merge([a.clone(), b.clone().with_token(Some(token_b.clone()))]).unwrap();
+        let combined = expiring_token::combine([&token_a, &token_b]).unwrap();
+        assert_eq!(merged.token(), Some(&combined));
+        let static_token = b.with_token(Some(RequestToken::generate()));
+        assert!(Ticket::merge([a, static_token]).is_err());
+    }
+
+    #[test]
+    fn test_ticket_limits() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer = PeerId::from(Keypair::generate().public());
+        let addrs = vec![SocketAddr::from_str("127.0.0.1:1234").unwrap(); 4096];
+        let ticket = Ticket::new(hash, peer, addrs, None, true, None).unwrap();
+        assert!(ticket.to_bytes().len() > MAX_TICKET_SIZE);
+        assert!(Ticket::from_bytes(&ticket.to_bytes()).is_err());
+        assert!(ticket.to_string().parse::<Ticket>().is_err());
+    }
+
+    fn provider_addr() -> impl Strategy<Value = ProviderAddr> {
+        (
+            any::<[u8; 32]>(),
+            proptest::collection::vec(any::<SocketAddr>(), 0..4),
+            any::<Option<u16>>(),
+        )
+            .prop_map(|(secret, addrs, derp_region)| ProviderAddr {
+                peer: PeerId::from(Keypair::from(SecretKey::from_bytes(&secret)).public()),
+                addrs,
+                derp_region,
+            })
+    }
+
+    fn ticket() -> impl Strategy<Value = Ticket> {
+        (
+            any::<[u8; 32]>(),
+            proptest::collection::vec(provider_addr(), 1..4),
+            proptest::option::of(proptest::collection::vec(any::<u8>(), 0..64)),
+            any::<bool>(),
+            any::<Option<[u8; 32]>>(),
+        )
+            .prop_map(|(hash, providers, token, recursive, name)| Ticket {
+                hash: hash.into(),
+                providers,
+                token: token.map(|token| RequestToken::new(token).unwrap()),
+                recursive,
+                domain: name.map_or(HashDomain::Plain, |name| HashDomain::Keyed(name.into())),
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn ticket_roundtrip(ticket in ticket()) {
+            prop_assert_eq!(&Ticket::from_bytes(&ticket.to_bytes()).unwrap(), &ticket);
+            prop_assert_eq!(ticket.to_string().parse::<Ticket>().unwrap(), ticket);
+        }
+
+        #[test]
+        fn ticket_from_arbitrary_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+            // must not panic
+            Ticket::from_bytes(&bytes).ok();
+        }
+    }
 }
@@ -0,0 +1,562 @@
+//! Import blobs from HTTP origins.
+//!
+//! This allows a provider to mirror existing web content. If the hash of the content is
+//! known and the origin reports its size, the content is written straight into a partial
+//! entry of the store for that hash, hashed into an outboard while it is being
+//! downloaded, and the entry is completed once the content matches the hash.
+//!
+//! Partial entries are addressed by hash, so content with an unknown hash is downloaded
+//! into a temporary file instead, hashed with blake3 while it is being downloaded, and
+//! then imported into the store.
+//!
+//! Downloads are resumable: the temporary file is named after the url, and if it
+//! already exists when a download is started, only the remaining range is requested
+//! from the origin. The prefix that is already on disk, or in the partial entry, is
+//! rehashed before the download continues.
+//!
+//! A download is only resumed if the origin gave a validator for the content, a strong
+//! `ETag` or a `Last-Modified` date, which is kept next to the temporary file and sent
+//! as `If-Range`, so that content that changed in between is downloaded from scratch
+//! instead of being spliced onto a stale prefix. The download also starts from scratch
+//! if the origin does not support range requests, answers with a range that does not
+//! start at the end of the prefix, or rejects the range altogether.
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use bao_tree::blake3;
+use bao_tree::io::fsm::OutboardMut;
+use bao_tree::io::outboard::PreOrderMemOutboard;
+use bao_tree::io::sync::Outboard;
+use bytes::Bytes;
+use iroh_bytes::baomap::{
+    ImportMode, ImportProgress, MapEntry, PartialMap, PartialMapEntry, Store,
+};
+use iroh_bytes::util::progress::{IdGenerator, ProgressSender};
+use iroh_bytes::{Hash, IROH_BLOCK_SIZE};
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
+use rand::Rng;
+use reqwest::{header, Response, StatusCode};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+use crate::baomap::outboard::post_order_to_pre_order;
+
+/// Size of the pieces in which the prefix of a resumed download is read back for hashing.
+const PREFIX_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Temp files of the downloads that are running in this process.
+static IN_USE: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Options for [`fetch_url`].
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// The expected hash of the content, if known.
+    ///
+    /// The download is rejected before it is imported if the content does not match.
+    pub expected_hash: Option<Hash>,
+    /// Directory in which to keep partial downloads.
+    pub temp_dir: PathBuf,
+}
+
+/// Download the content at `url` and import it into the store as a blob.
+///
+/// Progress of the download is reported as [`ImportProgress::CopyProgress`] on its
+/// own id, followed by the regular progress of the import if the content had to be
+/// downloaded into a temporary file. Returns the hash and size of the imported blob.
+pub async fn fetch_url<D: Store>(
+    db: &D,
+    url: Url,
+    opts: FetchOptions,
+    progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
+) -> Result<(Hash, u64)> {
+    anyhow::ensure!(
+        matches!(url.scheme(), "http" | "https"),
+        "unsupported url scheme {}",
+        url.scheme()
+    );
+    tokio::fs::create_dir_all(&opts.temp_dir).await?;
+    let temp = TempFile::acquire(&opts.temp_dir, &url);
+    let path = temp.path.clone();
+    let id = progress.new_id();
+    progress
+        .send(ImportProgress::Found {
+            id,
+            path: path.clone(),
+        })
+        .await?;
+
+    if let Some(expected) = opts.expected_hash {
+        if let Some(size) = download_into_partial(db, &url, expected, &path, id, &progress).await? {
+            temp.remove().await;
+            return Ok((expected, size));
+        }
+        // the origin did not report the size, which a partial entry needs
+        tracing::debug!(
+            "no content length for {}, downloading into {}",
+            url,
+            path.display()
+        );
+    }
+    let hash = download(&url, &path, id, &progress).await?;
+    if let Some(expected) = opts.expected_hash {
+        if expected != hash {
+            temp.remove().await;
+            anyhow::bail!(
+                "content of {} has hash {}, expected {}",
+                url,
+                hash,
+                expected
+            );
+        }
+    }
+    let (imported, size) = db.import(path, ImportMode::Copy, progress).await?;
+    temp.remove().await;
+    anyhow::ensure!(
+        imported == hash,
+        "hash mismatch after import, got {} expected {}",
+        imported,
+        hash
+    );
+    Ok((hash, size))
+}
+
+/// The temp file for a url. This is stable so that downloads can be resumed.
+fn temp_path(temp_dir: &Path, url: &Url) -> PathBuf {
+    let name = Hash::new(url.as_str().as_bytes()).to_hex();
+    temp_dir.join(format!("{name}.fetch"))
+}
+
+/// The file next to the temp file `path` that holds the validator of the content.
+fn validator_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".validator");
+    name.into()
+}
+
+/// The file next to the temp file `path` that holds the number of bytes that were
+/// written to the partial entry, see [`download_into_partial`].
+fn written_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".written");
+    name.into()
+}
+
+/// The temp file of a download.
+///
+/// Concurrent downloads of the same url in this process would write to the same file, so
+/// all but the first get a unique file, which is not resumed.
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn acquire(temp_dir: &Path, url: &Url) -> Self {
+        let path = temp_path(temp_dir, url);
+        let mut in_use = IN_USE.lock().unwrap();
+        let path = if in_use.contains(&path) {
+            let suffix = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+            path.with_extension(format!("{suffix}.fetch"))
+        } else {
+            path
+        };
+        in_use.insert(path.clone());
+        Self { path }
+    }
+
+    /// Remove the file and its sidecars, after it was imported or rejected.
+    async fn remove(&self) {
+        tokio::fs::remove_file(&self.path).await.ok();
+        tokio::fs::remove_file(validator_path(&self.path))
+            .await
+            .ok();
+        tokio::fs::remove_file(written_path(&self.path)).await.ok();
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        IN_USE.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// Send the request for `url`, resuming at `offset` if the validator stored for `path`
+/// allows it.
+///
+/// Returns the response and the offset at which its body starts, which is 0 if the
+/// download starts from scratch.
+async fn request(url: &Url, path: &Path, offset: u64) -> Result<(Response, u64)> {
+    let client = reqwest::Client::new();
+    let validator = match offset {
+        0 => None,
+        _ => read_validator(path).await?,
+    };
+    let mut request = client.get(url.clone());
+    if let Some(validator) = &validator {
+        tracing::debug!("resuming download of {} at {}", url, offset);
+        request = request
+            .header(header::RANGE, format!("bytes={offset}-"))
+            .header(header::IF_RANGE, validator.clone());
+    }
+    let mut response = request.send().await?;
+    let resumed = match response.status() {
+        StatusCode::PARTIAL_CONTENT if content_range_start(response.headers()) == Some(offset) => {
+            true
+        }
+        StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+            // the range does not fit the prefix, so the content must have changed
+            tracing::debug!("restarting download of {}", url);
+            response = client.get(url.clone()).send().await?;
+            false
+        }
+        _ => false,
+    };
+    if resumed {
+        return Ok((response, offset));
+    }
+    let status = response.status();
+    anyhow::ensure!(
+        status.is_success(),
+        "unexpected response status {} for {}",
+        status,
+        url
+    );
+    // a full response, because the origin does not support ranges, the validator did
+    // not match, or there was nothing to resume
+    write_validator(path, &response).await?;
+    Ok((response, 0))
+}
+
+/// Download into `path`, resuming if possible, and return the blake3 hash of the content.
+async fn download(
+    url: &Url,
+    path: &Path,
+    id: u64,
+    progress: &impl ProgressSender<Msg = ImportProgress>,
+) -> Result<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    let prefix = rehash_prefix(path, &mut hasher).await?;
+    let (mut response, mut offset) = request(url, path, prefix).await?;
+    let resumed = offset > 0;
+    if !resumed {
+        hasher.reset();
+    }
+    if let Some(remaining) = response.content_length() {
+        progress
+            .send(ImportProgress::Size {
+                id,
+                size: offset + remaining,
+            })
+            .await?;
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(path)
+        .await?;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        offset += chunk.len() as u64;
+        progress.try_send(ImportProgress::CopyProgress { id, offset })?;
+    }
+    file.sync_all().await?;
+    Ok(hasher.finalize().into())
+}
+
+/// Download the content with the known `hash` straight into a partial entry of the store,
+/// and complete the entry once the content matches the hash.
+///
+/// The data is hashed into an outboard while it is written. An interrupted download is
+/// resumed from the partial entry, using the number of bytes written so far, which is
+/// kept next to `path`.
+///
+/// Returns the size of the content, or `None` if the origin did not report it, in which
+/// case nothing was written.
+async fn download_into_partial<D: Store>(
+    db: &D,
+    url: &Url,
+    hash: Hash,
+    path: &Path,
+    id: u64,
+    progress: &impl ProgressSender<Msg = ImportProgress>,
+) -> Result<Option<u64>> {
+    let written = match db.get_partial(&hash) {
+        Some(_) => read_written(path).await?,
+        None => 0,
+    };
+    let (mut response, mut offset) = request(url, path, written).await?;
+    let Some(remaining) = response.content_length() else {
+        return Ok(None);
+    };
+    let size = offset + remaining;
+    progress.send(ImportProgress::Size { id, size }).await?;
+    let entry = db.get_or_create_partial(hash, size)?;
+    let mut data = entry.data_writer().await?;
+
+    let (tx, hashing) = spawn_outboard(size);
+    let res = async {
+        if offset > 0 {
+            // hash the prefix that is already in the entry
+            let mut reader = entry.data_reader().await?;
+            let mut pos = 0;
+            while pos < offset {
+                let len = (offset - pos).min(PREFIX_CHUNK_SIZE) as usize;
+                let chunk = reader.read_at(pos, len).await?;
+                anyhow::ensure!(!chunk.is_empty(), "partial entry is shorter than recorded");
+                pos += chunk.len() as u64;
+                tx.send(chunk).await.ok();
+            }
+        }
+        while let Some(chunk) = response.chunk().await? {
+            data.write_bytes_at(offset, chunk.clone()).await?;
+            offset += chunk.len() as u64;
+            anyhow::ensure!(offset <= size, "origin sent more data than announced");
+            tx.send(chunk).await.ok();
+            progress.try_send(ImportProgress::CopyProgress { id, offset })?;
+        }
+        anyhow::ensure!(offset == size, "download ended at {offset} of {size} bytes");
+        data.sync().await?;
+        anyhow::Ok(())
+    }
+    .await;
+    drop(tx);
+    if let Err(cause) = res {
+        // the data that made it into the entry does not have to be downloaded again
+        write_written(path, offset).await.ok();
+        return Err(cause);
+    }
+    let (root, outboard) = hashing.await?.context("failed to hash the download")?;
+    if Hash::from(root) != hash {
+        // the content changed, the next attempt starts from scratch
+        tokio::fs::remove_file(written_path(path)).await.ok();
+        anyhow::bail!(
+            "content of {} has hash {}, expected {}",
+            url,
+            Hash::from(root),
+            hash
+        );
+    }
+    let outboard = PreOrderMemOutboard::new(root, IROH_BLOCK_SIZE, outboard)?;
+    let mut outboard_mut = entry.outboard_mut().await?;
+    for node in outboard.tree().pre_order_nodes_iter() {
+        if let Some(pair) = outboard.load(node)? {
+            outboard_mut.save(node, &pair).await?;
+        }
+    }
+    outboard_mut.sync().await?;
+    db.insert_complete(entry).await?;
+    Ok(Some(size))
+}
+
+/// Compute the hash and pre-order outboard of `size` bytes of data sent over the returned
+/// channel, on the blocking pool.
+fn spawn_outboard(
+    size: u64,
+) -> (
+    tokio::sync::mpsc::Sender<Bytes>,
+    tokio::task::JoinHandle<io::Result<(blake3::Hash, Vec<u8>)>>,
+) {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let hashing = tokio::task::spawn_blocking(move || {
+        let reader = ChunkReader {
+            rx,
+            current: Bytes::new(),
+        };
+        let mut post_order = Vec::new();
+        let root = bao_tree::io::sync::outboard_post_order(
+            reader,
+            size,
+            IROH_BLOCK_SIZE,
+            &mut post_order,
+        )?;
+        let outboard = post_order_to_pre_order(&post_order)?;
+        Ok((root, outboard))
+    });
+    (tx, hashing)
+}
+
+/// Reads the chunks sent over a channel, to hash the data while it is downloaded.
+struct ChunkReader {
+    rx: tokio::sync::mpsc::Receiver<Bytes>,
+    current: Bytes,
+}
+
+impl io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.current = chunk,
+                // the download ended
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+/// The validator of the content of a response, to resume its download with `If-Range`.
+///
+/// Weak entity tags can not be used with `If-Range`.
+fn validator(headers: &header::HeaderMap) -> Option<&header::HeaderValue> {
+    let etag = headers
+        .get(header::ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"));
+    etag.or_else(|| headers.get(header::LAST_MODIFIED))
+}
+
+/// Store the validator of a full response next to the temp file, or remove an old one.
+///
+/// Without a validator the download is not resumed.
+async fn write_validator(path: &Path, response: &Response) -> Result<()> {
+    let validator_path = validator_path(path);
+    match validator(response.headers()) {
+        Some(validator) => tokio::fs::write(validator_path, validator.as_bytes()).await?,
+        None => {
+            if let Err(e) = tokio::fs::remove_file(validator_path).await {
+                anyhow::ensure!(e.kind() == std::io::ErrorKind::NotFound, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn read_validator(path: &Path) -> Result<Option<header::HeaderValue>> {
+    match tokio::fs::read(validator_path(path)).await {
+        Ok(bytes) => Ok(header::HeaderValue::from_bytes(&bytes).ok()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The number of bytes written to the partial entry for the temp file `path`, 0 if unknown.
+async fn read_written(path: &Path) -> Result<u64> {
+    match tokio::fs::read_to_string(written_path(path)).await {
+        Ok(text) => Ok(text.trim().parse().unwrap_or_default()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn write_written(path: &Path, written: u64) -> Result<()> {
+    tokio::fs::write(written_path(path), format!("{written}\n")).await?;
+    Ok(())
+}
+
+/// The first byte of a `Content-Range: bytes <start>-<end>/<size>` header.
+fn content_range_start(headers: &header::HeaderMap) -> Option<u64> {
+    let value = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Feed an existing partial download into the hasher, returning its size.
+async fn rehash_prefix(path: &Path, hasher: &mut blake3::Hasher) -> Result<u64> {
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).context("unable to open partial download"),
+    };
+    let mut buf = vec![0u8; 1024 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_path_is_stable() {
+        let dir = PathBuf::from("/tmp");
+        let a: Url = "https://example.com/a".parse().unwrap();
+        let b: Url = "https://example.com/b".parse().unwrap();
+        assert_eq!(temp_path(&dir, &a), temp_path(&dir, &a));
+        assert_ne!(temp_path(&dir, &a), temp_path(&dir, &b));
+    }
+
+    #[tokio::test]
+    async fn rehash_prefix_matches() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("partial");
+        let data = vec![7u8; 3 * 1024 * 1024 + 17];
+        tokio::fs::write(&path, &data).await?;
+        let mut hasher = blake3::Hasher::new();
+        let size = rehash_prefix(&path, &mut hasher).await?;
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(Hash::from(hasher.finalize()), Hash::new(&data));
+
+        let mut hasher = blake3::Hasher::new();
+        let size = rehash_prefix(&dir.path().join("missing"), &mut hasher).await?;
+        assert_eq!(size, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outboard_of_chunks() -> Result<()> {
+        let data = Bytes::from(vec![7u8; 3 * 1024 * 1024 + 17]);
+        let (tx, hashing) = spawn_outboard(data.len() as u64);
+        for chunk in data.chunks(100_000) {
+            tx.send(data.slice_ref(chunk)).await?;
+        }
+        drop(tx);
+        let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        assert_eq!(hashing.await??, (hash, outboard));
+
+        // missing data is an error, not a wrong hash
+        let (tx, hashing) = spawn_outboard(data.len() as u64);
+        tx.send(data.slice(..1000)).await?;
+        drop(tx);
+        assert!(hashing.await?.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_temp_files() {
+        let dir = PathBuf::from("/tmp");
+        let url: Url = "https://example.com/concurrent".parse().unwrap();
+        let first = TempFile::acquire(&dir, &url);
+        let second = TempFile::acquire(&dir, &url);
+        assert_eq!(first.path, temp_path(&dir, &url));
+        assert_ne!(second.path, first.path);
+        drop(first);
+        drop(second);
+        assert_eq!(TempFile::acquire(&dir, &url).path, temp_path(&dir, &url));
+    }
+
+    #[test]
+    fn resume_headers() {
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(content_range_start(&headers), None);
+        headers.insert(header::CONTENT_RANGE, "bytes 100-199/200".parse().unwrap());
+        assert_eq!(content_range_start(&headers), Some(100));
+        headers.insert(header::CONTENT_RANGE, "bytes */200".parse().unwrap());
+        assert_eq!(content_range_start(&headers), None);
+
+        assert!(validator(&headers).is_none());
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        headers.insert(header::LAST_MODIFIED, date.parse().unwrap());
+        assert_eq!(validator(&headers).unwrap(), date);
+        // weak entity tags can not be used for ranges
+        headers.insert(header::ETAG, "W/\"1\"".parse().unwrap());
+        assert_eq!(validator(&headers).unwrap(), date);
+        headers.insert(header::ETAG, "\"1\"".parse().unwrap());
+        assert_eq!(validator(&headers).unwrap(), "\"1\"");
+    }
+}
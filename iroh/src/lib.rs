@@ -4,12 +4,27 @@
 pub use iroh_bytes as bytes;
 pub use iroh_net as net;
 
+#[cfg(feature = "node")]
 pub mod baomap;
 #[cfg(feature = "iroh-collection")]
+pub mod car;
+pub mod client;
+#[cfg(feature = "node")]
+pub mod cluster;
+#[cfg(feature = "iroh-collection")]
 pub mod collection;
+pub mod data_dir;
 pub mod dial;
+#[cfg(feature = "node")]
+pub mod fetch;
+pub mod local_rpc;
+#[cfg(feature = "node")]
+pub mod mirror;
+#[cfg(feature = "node")]
 pub mod node;
 pub mod rpc_protocol;
+#[cfg(any(test, feature = "test"))]
+pub mod testutil;
 pub mod util;
 
 /// Expose metrics module
@@ -0,0 +1,323 @@
+//! An RPC transport over Unix domain sockets, or named pipes on Windows.
+//!
+//! This is an alternative to QUIC on a localhost port for tools running on the same machine
+//! as the node. Every channel is a separate connection to the socket, carrying length
+//! prefixed postcard messages.
+//!
+//! There is no authentication, access is controlled by the permissions of the socket. On
+//! Unix the socket is only accessible by the user that created it. On Windows remote
+//! clients are rejected, and only the creator of the pipe and administrators can write to
+//! it.
+use std::{fmt, io, marker::PhantomData, path::PathBuf, pin::Pin, sync::Arc};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use quic_rpc::{
+    transport::{Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint},
+    RpcMessage,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+/// Maximum size of a single message.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Send side of a channel.
+pub type SendSink<Out> = Pin<Box<dyn Sink<Out, Error = io::Error> + Send + 'static>>;
+/// Receive side of a channel.
+pub type RecvStream<In> = Pin<Box<dyn Stream<Item = io::Result<In>> + Send + 'static>>;
+
+/// Frames a connection as a channel.
+fn channel<In: RpcMessage, Out: RpcMessage>(
+    stream: impl AsyncRead + AsyncWrite + Send + 'static,
+) -> (SendSink<Out>, RecvStream<In>) {
+    let codec = || {
+        LengthDelimitedCodec::builder()
+            .max_frame_length(MAX_FRAME_SIZE)
+            .new_codec()
+    };
+    let (read, write) = tokio::io::split(stream);
+    let recv = FramedRead::new(read, codec()).map(|frame| {
+        postcard::from_bytes(&frame?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    });
+    let send = FramedWrite::new(write, codec()).with(|msg: Out| {
+        let frame = postcard::to_stdvec(&msg)
+            .map(Bytes::from)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        futures::future::ready(frame)
+    });
+    (Box::pin(send), Box::pin(recv))
+}
+
+/// A server endpoint listening on a local socket.
+///
+/// The socket is removed when the last clone of the endpoint is dropped.
+pub struct LocalServerEndpoint<In, Out> {
+    listener: Arc<imp::Listener>,
+    local_addr: [LocalAddr; 1],
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> LocalServerEndpoint<In, Out> {
+    /// Listens on the socket at `path`.
+    ///
+    /// On Windows `path` is the name of the pipe, e.g. `\\.\pipe\iroh`. A socket left over
+    /// by a process that did not shut down cleanly is replaced, it is an error if another
+    /// process is listening on it.
+    pub fn bind(path: impl Into<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            listener: Arc::new(imp::Listener::bind(path.into())?),
+            local_addr: [LocalAddr::Mem],
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In, Out> Clone for LocalServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            listener: self.listener.clone(),
+            local_addr: self.local_addr.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for LocalServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalServerEndpoint")
+            .field("path", &self.listener.path)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for LocalServerEndpoint<In, Out> {
+    type OpenError = io::Error;
+    type SendError = io::Error;
+    type RecvError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for LocalServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for LocalServerEndpoint<In, Out> {
+    type AcceptBiFut = BoxFuture<'static, io::Result<(SendSink<Out>, RecvStream<In>)>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let listener = self.listener.clone();
+        async move {
+            let stream = listener.accept().await?;
+            Ok(channel(stream))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.local_addr
+    }
+}
+
+/// A connection to a [`LocalServerEndpoint`].
+pub struct LocalConnection<In, Out> {
+    path: Arc<PathBuf>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> LocalConnection<In, Out> {
+    /// A connection to the socket at `path`.
+    ///
+    /// Nothing is connected until a channel is opened.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Arc::new(path.into()),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> Clone for LocalConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for LocalConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalConnection")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for LocalConnection<In, Out> {
+    type OpenError = io::Error;
+    type SendError = io::Error;
+    type RecvError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for LocalConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for LocalConnection<In, Out> {
+    type OpenBiFut = BoxFuture<'static, io::Result<(SendSink<Out>, RecvStream<In>)>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let path = self.path.clone();
+        async move {
+            let stream = imp::connect(&path).await?;
+            Ok(channel(stream))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    #[derive(Debug)]
+    pub(super) struct Listener {
+        inner: UnixListener,
+        pub(super) path: PathBuf,
+    }
+
+    impl Listener {
+        pub(super) fn bind(path: PathBuf) -> io::Result<Self> {
+            if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("another process is listening on {}", path.display()),
+                ));
+            }
+            // bind in a fresh directory that only we can enter, and restrict the
+            // permissions before moving the socket into place, so it is never reachable
+            // by others with the default permissions
+            let mut dir = path.clone().into_os_string();
+            dir.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+            let dir = PathBuf::from(dir);
+            std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+            let tmp = dir.join("sock");
+            let res = UnixListener::bind(&tmp).and_then(|inner| {
+                std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+                std::fs::rename(&tmp, &path)?;
+                Ok(inner)
+            });
+            std::fs::remove_file(&tmp).ok();
+            std::fs::remove_dir(&dir).ok();
+            Ok(Self { inner: res?, path })
+        }
+
+        pub(super) async fn accept(&self) -> io::Result<UnixStream> {
+            let (stream, _addr) = self.inner.accept().await?;
+            Ok(stream)
+        }
+    }
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+
+    pub(super) async fn connect(path: &Path) -> io::Result<UnixStream> {
+        UnixStream::connect(path).await
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use tokio::net::windows::named_pipe::{
+        ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+    };
+    use tokio::sync::Mutex;
+
+    /// All instances of the pipe are busy.
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    #[derive(Debug)]
+    pub(super) struct Listener {
+        /// The instance of the pipe the next client connects to.
+        next: Mutex<NamedPipeServer>,
+        pub(super) path: PathBuf,
+    }
+
+    impl Listener {
+        pub(super) fn bind(path: PathBuf) -> io::Result<Self> {
+            let next = ServerOptions::new()
+                .first_pipe_instance(true)
+                .reject_remote_clients(true)
+                .create(&path)?;
+            Ok(Self {
+                next: Mutex::new(next),
+                path,
+            })
+        }
+
+        pub(super) async fn accept(&self) -> io::Result<NamedPipeServer> {
+            let mut next = self.next.lock().await;
+            next.connect().await?;
+            let new = ServerOptions::new()
+                .reject_remote_clients(true)
+                .create(&self.path)?;
+            Ok(std::mem::replace(&mut *next, new))
+        }
+    }
+
+    pub(super) async fn connect(path: &Path) -> io::Result<NamedPipeClient> {
+        loop {
+            match ClientOptions::new().open(path) {
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_rpc() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rpc.sock");
+        let server = LocalServerEndpoint::<u64, u64>::bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let client = LocalConnection::<u64, u64>::new(&path);
+        let echo = tokio::spawn(async move {
+            let (mut send, mut recv) = server.accept_bi().await.unwrap();
+            let msg = recv.next().await.unwrap().unwrap();
+            send.send(msg + 1).await.unwrap();
+            server
+        });
+        let (mut send, mut recv) = client.open_bi().await.unwrap();
+        send.send(41).await.unwrap();
+        assert_eq!(recv.next().await.unwrap().unwrap(), 42);
+
+        let server = echo.await.unwrap();
+        assert!(LocalServerEndpoint::<u64, u64>::bind(&path).is_err());
+        drop(server);
+        assert!(!path.exists());
+    }
+}
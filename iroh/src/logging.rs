@@ -0,0 +1,304 @@
+//! Logging for the iroh CLI.
+//!
+//! Logs go to stderr, filtered by `RUST_LOG`. A provider that runs in the background
+//! without a service manager loses those logs when it restarts, so `iroh provide` can also
+//! write them to a file, configured in the `log_file` section of the config.
+//!
+//! The log file is rotated once it exceeds a size or an age: `iroh.log` is renamed to
+//! `iroh.log.1`, older files move up by one, and files beyond the retention are removed.
+//!
+//! The filters can be replaced while the provider runs, with `iroh admin set-log-filter`.
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use iroh::node::LogFilterHandler;
+use tracing_subscriber::{prelude::*, reload, EnvFilter};
+
+/// Default size in bytes after which the log file is rotated.
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default number of rotated log files that are kept.
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+/// Default filter for the log file, if `RUST_LOG` is not set.
+pub const DEFAULT_FILTER: &str = "info";
+
+/// Where and how the logs are written to a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFileConfig {
+    /// The log file.
+    pub path: PathBuf,
+    /// Size in bytes after which the log file is rotated.
+    pub max_size: u64,
+    /// Age after which the log file is rotated, regardless of its size.
+    pub max_age: Option<Duration>,
+    /// Number of rotated log files that are kept.
+    pub max_files: usize,
+    /// Filter for the log file, in the syntax of `RUST_LOG`.
+    pub filter: String,
+}
+
+/// The filter reloads of the logging set up by [`init`].
+static FILTER_RELOAD: Mutex<Option<Arc<FilterReload>>> = Mutex::new(None);
+
+/// Sets up logging to stderr, and to a file if `log_file` is set.
+pub fn init(log_file: Option<LogFileConfig>) -> Result<()> {
+    let (stderr_filter, stderr_reload) = reload::Layer::new(EnvFilter::from_default_env());
+    let (file_layer, file_reload) = match log_file {
+        Some(config) => {
+            // RUST_LOG is more specific than the config, e.g. when debugging an issue
+            let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+                Ok(filter) => EnvFilter::try_new(filter)?,
+                Err(_) => EnvFilter::try_new(&config.filter)?,
+            };
+            let (filter, reload) = reload::Layer::new(filter);
+            let file = RollingFile::open(config)?;
+            let layer = tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .with_filter(filter);
+            (Some(layer), Some(reload))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_filter(stderr_filter),
+        )
+        .with(file_layer)
+        .init();
+
+    let mut layers = vec![LayerFilter::new(stderr_reload)];
+    if let Some(file_reload) = file_reload {
+        layers.push(LayerFilter::new(file_reload));
+    }
+    *FILTER_RELOAD.lock().unwrap() = Some(Arc::new(FilterReload { layers }));
+    Ok(())
+}
+
+/// Replaces the filters of the logging set up by [`init`], if it was.
+pub fn filter_handler() -> Option<Arc<dyn LogFilterHandler>> {
+    let reload = FILTER_RELOAD.lock().unwrap().clone()?;
+    Some(reload)
+}
+
+/// The reload handle of the filter of one layer.
+///
+/// The handles of the layers differ in their subscriber type, so they are erased to closures.
+struct LayerFilter {
+    reload: Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>,
+    current: Box<dyn Fn() -> Result<String, reload::Error> + Send + Sync>,
+}
+
+impl LayerFilter {
+    fn new<S: 'static>(handle: reload::Handle<EnvFilter, S>) -> Self {
+        let current = handle.clone();
+        Self {
+            reload: Box::new(move |filter| handle.reload(filter)),
+            current: Box::new(move || current.with_current(|filter| filter.to_string())),
+        }
+    }
+}
+
+/// Replaces the filters of stderr and the log file with the same filter.
+struct FilterReload {
+    layers: Vec<LayerFilter>,
+}
+
+impl fmt::Debug for FilterReload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FilterReload")
+            .field("layers", &self.layers.len())
+            .finish()
+    }
+}
+
+impl LogFilterHandler for FilterReload {
+    fn set_filter(&self, filter: &str) -> Result<()> {
+        // `EnvFilter` can not be cloned, so the filter of every layer is parsed up front,
+        // nothing is replaced unless all of them parse
+        let filters = self
+            .layers
+            .iter()
+            .map(|_| EnvFilter::try_new(filter))
+            .collect::<Result<Vec<_>, _>>()
+            .context("invalid log filter")?;
+        let previous = self
+            .layers
+            .iter()
+            .map(|layer| (layer.current)())
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, (layer, filter)) in self.layers.iter().zip(filters).enumerate() {
+            if let Err(err) = (layer.reload)(filter) {
+                // put back the filters already replaced, so no layer is left with the new filter
+                for (layer, previous) in self.layers[..i].iter().zip(&previous) {
+                    if let Ok(filter) = EnvFilter::try_new(previous) {
+                        (layer.reload)(filter).ok();
+                    }
+                }
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A log file that is rotated by size and age.
+#[derive(Debug)]
+pub struct RollingFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+    created: SystemTime,
+}
+
+impl RollingFile {
+    /// Opens the log file for appending, creating it and its directory if needed.
+    pub fn open(config: LogFileConfig) -> Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create log directory {}", dir.display()))?;
+        }
+        let (file, size, created) = open_append(&config.path)
+            .with_context(|| format!("failed to open log file {}", config.path.display()))?;
+        Ok(Self {
+            config,
+            file,
+            size,
+            created,
+        })
+    }
+
+    fn should_rotate(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_large = self.size + len as u64 > self.config.max_size;
+        let too_old = self.config.max_age.map_or(false, |max_age| {
+            self.created.elapsed().unwrap_or_default() >= max_age
+        });
+        too_large || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        let rotated = |i: usize| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{i}"));
+            PathBuf::from(name)
+        };
+        // the oldest file is dropped, the others move up by one
+        remove_if_exists(&rotated(self.config.max_files.max(1)))?;
+        for i in (1..self.config.max_files).rev() {
+            rename_if_exists(&rotated(i), &rotated(i + 1))?;
+        }
+        if self.config.max_files == 0 {
+            remove_if_exists(path)?;
+        } else {
+            rename_if_exists(path, &rotated(1))?;
+        }
+        let (file, size, created) = open_append(path)?;
+        self.file = file;
+        self.size = size;
+        self.created = created;
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Opens `path` for appending, returning its size and creation time.
+fn open_append(path: &Path) -> io::Result<(File, u64, SystemTime)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let meta = file.metadata()?;
+    // not every platform and file system records when a file was created
+    let created = meta.created().unwrap_or_else(|_| SystemTime::now());
+    Ok((file, meta.len(), created))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("iroh.log");
+        let config = LogFileConfig {
+            path: path.clone(),
+            max_size: 10,
+            max_age: None,
+            max_files: 2,
+            filter: DEFAULT_FILTER.to_string(),
+        };
+        let mut file = RollingFile::open(config.clone()).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        drop(file);
+        let read = |name: &str| fs::read_to_string(path.with_file_name(name)).unwrap();
+        assert_eq!(read("iroh.log"), "four\nfive\n");
+        assert_eq!(read("iroh.log.1"), "three\n");
+        assert_eq!(read("iroh.log.2"), "one\ntwo\n");
+
+        // the size of the existing file counts after a restart
+        let mut file = RollingFile::open(config).unwrap();
+        file.write_all(b"six\n").unwrap();
+        assert_eq!(read("iroh.log"), "six\n");
+        assert_eq!(read("iroh.log.1"), "four\nfive\n");
+        assert_eq!(read("iroh.log.2"), "three\n");
+        assert!(!path.with_file_name("iroh.log.3").exists());
+    }
+
+    #[test]
+    fn test_rotate_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("iroh.log");
+        let config = LogFileConfig {
+            path: path.clone(),
+            max_size: DEFAULT_MAX_SIZE,
+            max_age: Some(Duration::ZERO),
+            max_files: 0,
+            filter: DEFAULT_FILTER.to_string(),
+        };
+        let mut file = RollingFile::open(config).unwrap();
+        file.write_all(b"one\n").unwrap();
+        file.write_all(b"two\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two\n");
+        assert!(!path.with_file_name("iroh.log.1").exists());
+    }
+}
@@ -1,15 +1,15 @@
-use std::{collections::HashMap, time::Duration};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use tracing_subscriber::{prelude::*, EnvFilter};
 
 mod commands;
 mod config;
+mod logging;
 
 use crate::{
-    commands::{init_metrics_collection, Cli},
-    config::{iroh_config_path, Config, CONFIG_FILE_NAME, ENV_PREFIX},
+    commands::{init_metrics_collection, Cli, Commands},
+    config::{iroh_config_path, path_overrides, Config, CONFIG_FILE_NAME, ENV_PREFIX},
 };
 
 fn main() -> Result<()> {
@@ -30,10 +30,6 @@ async fn main_impl() -> Result<()> {
     let tokio = tokio::runtime::Handle::current();
     let tpc = tokio_util::task::LocalPoolHandle::new(num_cpus::get());
     let rt = iroh::bytes::util::runtime::Handle::new(tokio, tpc);
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
-        .with(EnvFilter::from_default_env())
-        .init();
 
     let cli = Cli::parse();
 
@@ -45,9 +41,22 @@ async fn main_impl() -> Result<()> {
         // env var prefix for this config
         ENV_PREFIX,
         // map of present command line arguments
-        // args.make_overrides_map(),
-        HashMap::<String, String>::new(),
+        path_overrides(
+            cli.data_dir.clone(),
+            cli.cache_dir.clone(),
+            cli.rpc_socket.clone(),
+        ),
     )?;
+    // `iroh config` reports the problems itself, and can show an invalid config.
+    if !matches!(cli.command, Commands::Config(_)) {
+        config.validate()?;
+    }
+    // only the provider runs long enough to need a log file
+    let log_file = match cli.command {
+        Commands::Provide { .. } => config.log_file()?,
+        _ => None,
+    };
+    logging::init(log_file)?;
 
     #[cfg(feature = "metrics")]
     let metrics_fut = init_metrics_collection(cli.metrics_addr, &rt);
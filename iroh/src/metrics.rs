@@ -10,6 +10,16 @@ pub struct Metrics {
     pub requests_total: Counter,
     pub bytes_sent: Counter,
     pub bytes_received: Counter,
+    pub disk_space_low: Counter,
+    pub writes_rejected_disk_space: Counter,
+    pub memory_pressure: Counter,
+    pub blobs_validated: Counter,
+    pub blobs_corrupted: Counter,
+    pub reads_coalesced: Counter,
+    pub chunk_cache_hits: Counter,
+    pub chunk_cache_misses: Counter,
+    pub mirror_blobs_fetched: Counter,
+    pub mirror_fetch_failures: Counter,
 }
 
 impl Default for Metrics {
@@ -18,6 +28,28 @@ impl Default for Metrics {
             requests_total: Counter::new("Total number of requests received"),
             bytes_sent: Counter::new("Number of bytes streamed"),
             bytes_received: Counter::new("Number of bytes received"),
+            disk_space_low: Counter::new(
+                "Number of times free disk space dropped below the low watermark",
+            ),
+            writes_rejected_disk_space: Counter::new(
+                "Number of writes refused because of insufficient disk space",
+            ),
+            memory_pressure: Counter::new(
+                "Number of times memory usage exceeded the high limit and load was shed",
+            ),
+            blobs_validated: Counter::new("Number of blobs validated in the background"),
+            blobs_corrupted: Counter::new(
+                "Number of corrupted blobs found by background validation",
+            ),
+            reads_coalesced: Counter::new(
+                "Number of store reads answered with the result of an identical read",
+            ),
+            chunk_cache_hits: Counter::new("Number of store reads served from the chunk cache"),
+            chunk_cache_misses: Counter::new("Number of store reads missing the chunk cache"),
+            mirror_blobs_fetched: Counter::new("Number of pins mirrored from other nodes"),
+            mirror_fetch_failures: Counter::new(
+                "Number of pins that failed to be mirrored from other nodes",
+            ),
         }
     }
 }
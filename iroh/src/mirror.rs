@@ -0,0 +1,484 @@
+//! Mirroring the content of another node.
+//!
+//! A node can mirror another node, the source. The mirror subscribes to the source,
+//! which announces its content, and downloads what is announced as soon as it shows up.
+//!
+//! Without a prefix the whole store of the source is mirrored: every complete blob is
+//! announced, and so is every pin. With a prefix, only pins whose names start with it
+//! are announced, which allows mirroring a pinned subset of the content.
+//!
+//! The subscription uses its own ALPN, [`ALPN`]. The source announces everything that
+//! matches when the subscription starts, and then follows the events of its store, see
+//! [`ReadableStore::subscribe`], to announce new blobs and new or changed pins. Removed
+//! content is not mirrored, the mirror keeps what it has.
+//!
+//! The content of a pin is pinned on the mirror as
+//! `mirror/<source peer id>/<source pin name>`, so it is kept. Blobs announced without
+//! a pin are stored but not pinned.
+//!
+//! If the source serves a keyed namespace, see [`iroh_bytes::keyed`], it announces the
+//! names of the pinned hashes as well, and the mirror requests the content by name.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use iroh_bytes::baomap::{MapEntry, Pin, ReadableStore, Store, StoreEvent};
+use iroh_bytes::keyed::NamespaceKey;
+use iroh_bytes::protocol::{CustomGetRequest, Request, RequestToken};
+use iroh_bytes::provider::RequestAuthorizationHandler;
+use iroh_bytes::Hash;
+use iroh_net::tls::PeerId;
+use iroh_net::MagicEndpoint;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+pub use crate::rpc_protocol::MirrorStatus;
+
+/// The ALPN of the subscription protocol.
+pub const ALPN: &[u8] = b"/iroh-mirror/1";
+
+/// Interval in which the source sends a keep alive, so idle subscriptions are not
+/// closed.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay before the mirror subscribes again after a subscription ended.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Number of announcements the mirror queues before it stops reading from the source.
+///
+/// Once the queue is full, flow control holds back the source until the mirror catches up.
+const ANNOUNCEMENT_QUEUE: usize = 64;
+
+/// Maximum size of a subscription message.
+const MAX_MESSAGE_SIZE: usize = 1024 * 64;
+
+/// Which node to mirror, and which of its content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorConfig {
+    /// The peer id of the source.
+    pub peer: PeerId,
+    /// Direct addresses of the source.
+    pub addrs: Vec<SocketAddr>,
+    /// DERP region of the source.
+    pub derp_region: Option<u16>,
+    /// Token to authorize the subscription with.
+    pub token: Option<RequestToken>,
+    /// Only mirror pins whose names start with this prefix, all content if `None`.
+    pub prefix: Option<String>,
+}
+
+impl MirrorConfig {
+    /// The name of the local pin for a pin of the source.
+    pub fn local_pin_name(&self, name: &str) -> String {
+        format!("mirror/{}/{}", self.peer, name)
+    }
+}
+
+/// Sent by the mirror to start a subscription.
+#[derive(Debug, Serialize, Deserialize)]
+struct Subscribe {
+    token: Option<RequestToken>,
+    prefix: Option<String>,
+}
+
+/// Sent by the source for every new blob and every new or changed pin.
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+    /// The name of the pin, `None` for a blob announced without one.
+    name: Option<String>,
+    hash: Hash,
+    /// The name of the hash in the keyed namespace of the source, if it has one.
+    keyed: Option<Hash>,
+    recursive: bool,
+}
+
+/// A message from the source to the mirror.
+#[derive(Debug, Serialize, Deserialize)]
+enum Notification {
+    Announce(Announcement),
+    KeepAlive,
+}
+
+#[derive(Debug)]
+struct TrackerInner {
+    status: MirrorStatus,
+    /// When the pending pins were announced, oldest first.
+    pending: VecDeque<Instant>,
+}
+
+/// Tracks the status of a mirror, shared between the mirror task and the node.
+#[derive(Debug, Clone)]
+pub(crate) struct Tracker(Arc<Mutex<TrackerInner>>);
+
+impl Tracker {
+    pub fn new(config: &MirrorConfig) -> Self {
+        Self(Arc::new(Mutex::new(TrackerInner {
+            status: MirrorStatus {
+                peer: config.peer,
+                prefix: config.prefix.clone(),
+                connected: false,
+                announced: 0,
+                mirrored: 0,
+                failed: 0,
+                pending: 0,
+                lag: Duration::ZERO,
+                last_error: None,
+            },
+            pending: VecDeque::new(),
+        })))
+    }
+
+    pub fn status(&self) -> MirrorStatus {
+        let inner = self.0.lock().unwrap();
+        let mut status = inner.status.clone();
+        status.pending = inner.pending.len() as u64;
+        status.lag = inner
+            .pending
+            .front()
+            .map_or(Duration::ZERO, |announced| announced.elapsed());
+        status
+    }
+
+    fn connected(&self, connected: bool) {
+        let mut inner = self.0.lock().unwrap();
+        inner.status.connected = connected;
+        if !connected {
+            // pending pins are announced again by the next subscription
+            inner.pending.clear();
+        }
+    }
+
+    fn announced(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.status.announced += 1;
+        inner.pending.push_back(Instant::now());
+    }
+
+    fn processed(&self, res: &Result<bool>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.pending.pop_front();
+        match res {
+            Ok(true) => {
+                inner.status.mirrored += 1;
+                #[cfg(feature = "metrics")]
+                iroh_metrics::inc!(crate::metrics::Metrics, mirror_blobs_fetched);
+            }
+            Ok(false) => {}
+            Err(cause) => {
+                inner.status.failed += 1;
+                inner.status.last_error = Some(cause.to_string());
+                #[cfg(feature = "metrics")]
+                iroh_metrics::inc!(crate::metrics::Metrics, mirror_fetch_failures);
+            }
+        }
+    }
+
+    fn error(&self, cause: &anyhow::Error) {
+        self.0.lock().unwrap().status.last_error = Some(cause.to_string());
+    }
+}
+
+/// Serve a subscription to the content of this node.
+///
+/// Subscriptions are authorized like a custom get request with the [`ALPN`] as data.
+pub(crate) async fn serve<D: Store>(
+    connecting: iroh_net::magic_endpoint::Connecting,
+    db: D,
+    namespace: Option<NamespaceKey>,
+    auth_handler: Arc<dyn RequestAuthorizationHandler>,
+) -> Result<()> {
+    let connection = connecting.await?;
+    let (send, mut recv) = connection.accept_bi().await?;
+    let subscribe: Subscribe = read_msg(&mut recv)
+        .await?
+        .context("no subscription received")?;
+    let request = Request::CustomGet(CustomGetRequest {
+        token: subscribe.token.clone(),
+        data: Bytes::from_static(ALPN),
+    });
+    auth_handler.authorize(subscribe.token, &request).await?;
+    debug!("serving mirror subscription for {:?}", subscribe.prefix);
+    // subscribe before listing, so nothing added in between is missed
+    let mut events = db.subscribe();
+    let mut announcer = Announcer {
+        send,
+        namespace,
+        blobs: subscribe.prefix.is_none().then(HashSet::new),
+        prefix: subscribe.prefix.unwrap_or_default(),
+        pins: HashMap::new(),
+    };
+    announcer.announce_all(&db).await?;
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+    keep_alive.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => Some(event),
+            _ = keep_alive.tick() => None,
+            // the mirror went away
+            _ = announcer.send.stopped() => return Ok(()),
+        };
+        match event {
+            Some(Ok(StoreEvent::Added(hash))) => announcer.announce_blob(&db, hash).await?,
+            Some(Ok(StoreEvent::Removed(hash))) => announcer.removed(hash),
+            Some(Ok(StoreEvent::PinsChanged)) => announcer.announce_pins(&db).await?,
+            Some(Err(RecvError::Lagged(_))) => {
+                // skip the queued events, they are older than the listing
+                events = events.resubscribe();
+                announcer.announce_all(&db).await?;
+            }
+            Some(Err(RecvError::Closed)) => return Ok(()),
+            None => write_msg(&mut announcer.send, &Notification::KeepAlive).await?,
+        }
+    }
+}
+
+/// The source side of a subscription, remembers what was announced already.
+struct Announcer {
+    send: quinn::SendStream,
+    namespace: Option<NamespaceKey>,
+    prefix: String,
+    /// Pins announced by name.
+    pins: HashMap<String, (Hash, bool)>,
+    /// Complete blobs announced, `None` if only pins are mirrored.
+    blobs: Option<HashSet<Hash>>,
+}
+
+impl Announcer {
+    async fn announce_all<D: Store>(&mut self, db: &D) -> Result<()> {
+        self.announce_pins(db).await?;
+        if self.blobs.is_some() {
+            for hash in db.blobs() {
+                self.announce_blob(db, hash).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Announce the pins that are new or changed.
+    async fn announce_pins<D: Store>(&mut self, db: &D) -> Result<()> {
+        let now = SystemTime::now();
+        for (name, pin) in db.pins() {
+            if pin.is_expired(now) || !name.starts_with(&self.prefix) {
+                continue;
+            }
+            let current = (pin.hash, pin.recursive);
+            if self.pins.get(&name) == Some(&current) {
+                continue;
+            }
+            self.announce(Some(name.clone()), pin.hash, pin.recursive)
+                .await?;
+            self.pins.insert(name, current);
+        }
+        Ok(())
+    }
+
+    /// Announce a blob if the whole store is mirrored and the blob is complete.
+    async fn announce_blob<D: Store>(&mut self, db: &D, hash: Hash) -> Result<()> {
+        let Some(blobs) = &self.blobs else {
+            return Ok(());
+        };
+        let complete = db.get(&hash).map_or(false, |entry| entry.is_complete());
+        if !complete || blobs.contains(&hash) {
+            return Ok(());
+        }
+        self.announce(None, hash, false).await?;
+        if let Some(blobs) = &mut self.blobs {
+            blobs.insert(hash);
+        }
+        Ok(())
+    }
+
+    /// Forget a removed blob, so it is announced again if it is added back.
+    fn removed(&mut self, hash: Hash) {
+        if let Some(blobs) = &mut self.blobs {
+            blobs.remove(&hash);
+        }
+    }
+
+    async fn announce(&mut self, name: Option<String>, hash: Hash, recursive: bool) -> Result<()> {
+        let announcement = Announcement {
+            name,
+            hash,
+            keyed: self.namespace.as_ref().map(|key| key.name(&hash)),
+            recursive,
+        };
+        write_msg(&mut self.send, &Notification::Announce(announcement)).await
+    }
+}
+
+/// Mirror a source until the future is dropped.
+///
+/// `fetch` downloads a hash over a connection to the source, by its name if one is given,
+/// including the children of the collection if the flag is set.
+pub(crate) async fn run<D, F, Fut>(
+    endpoint: MagicEndpoint,
+    db: D,
+    config: MirrorConfig,
+    tracker: Tracker,
+    fetch: F,
+) where
+    D: Store,
+    F: Fn(quinn::Connection, Hash, Option<Hash>, bool) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    loop {
+        let res = subscribe(&endpoint, &db, &config, &tracker, &fetch).await;
+        tracker.connected(false);
+        if let Err(cause) = res {
+            warn!("mirror of {} failed: {:#}", config.peer, cause);
+            tracker.error(&cause);
+        }
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+async fn subscribe<D, F, Fut>(
+    endpoint: &MagicEndpoint,
+    db: &D,
+    config: &MirrorConfig,
+    tracker: &Tracker,
+    fetch: &F,
+) -> Result<()>
+where
+    D: Store,
+    F: Fn(quinn::Connection, Hash, Option<Hash>, bool) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let connection = endpoint
+        .connect(config.peer, ALPN, config.derp_region, &config.addrs)
+        .await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let subscribe = Subscribe {
+        token: config.token.clone(),
+        prefix: config.prefix.clone(),
+    };
+    write_msg(&mut send, &subscribe).await?;
+    send.finish().await?;
+    tracker.connected(true);
+
+    let (tx, rx) = flume::bounded(ANNOUNCEMENT_QUEUE);
+    let receive = async move {
+        while let Some(notification) = read_msg(&mut recv).await? {
+            let Notification::Announce(announcement) = notification else {
+                continue;
+            };
+            tracker.announced();
+            if tx.send_async(announcement).await.is_err() {
+                break;
+            }
+        }
+        Err::<(), _>(anyhow::anyhow!("subscription closed by the source"))
+    };
+    let process = async move {
+        let mut bytes_connection = None;
+        while let Ok(announcement) = rx.recv_async().await {
+            let res = mirror_one(
+                endpoint,
+                db,
+                config,
+                &mut bytes_connection,
+                fetch,
+                announcement,
+            )
+            .await;
+            if res.is_err() {
+                // dial again for the next download
+                bytes_connection = None;
+            }
+            tracker.processed(&res);
+        }
+        anyhow::Ok(())
+    };
+    futures::future::try_join(receive, process).await?;
+    Ok(())
+}
+
+/// Mirror the content of a single announcement, returning whether anything was downloaded.
+async fn mirror_one<D, F, Fut>(
+    endpoint: &MagicEndpoint,
+    db: &D,
+    config: &MirrorConfig,
+    connection: &mut Option<quinn::Connection>,
+    fetch: &F,
+    announcement: Announcement,
+) -> Result<bool>
+where
+    D: Store,
+    F: Fn(quinn::Connection, Hash, Option<Hash>, bool) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let Announcement {
+        name,
+        hash,
+        keyed,
+        recursive,
+    } = announcement;
+    let local_name = name.as_deref().map(|name| config.local_pin_name(name));
+    let pinned = match &local_name {
+        Some(local_name) => db
+            .pins()
+            .any(|(n, pin)| &n == local_name && pin.hash == hash && pin.recursive == recursive),
+        None => true,
+    };
+    let complete = db.get(&hash).map_or(false, |entry| entry.is_complete());
+    // the children of a collection might be missing, so collections are always fetched
+    if pinned && complete && !recursive {
+        return Ok(false);
+    }
+    let conn = match connection {
+        Some(conn) => conn.clone(),
+        None => {
+            let conn = endpoint
+                .connect(
+                    config.peer,
+                    &iroh_bytes::protocol::ALPN,
+                    config.derp_region,
+                    &config.addrs,
+                )
+                .await?;
+            connection.insert(conn).clone()
+        }
+    };
+    fetch(conn, hash, keyed, recursive)
+        .await
+        .with_context(|| match &name {
+            Some(name) => format!("failed to mirror {name} ({hash})"),
+            None => format!("failed to mirror {hash}"),
+        })?;
+    if let Some(local_name) = local_name {
+        let pin = Pin {
+            hash,
+            recursive,
+            expires: None,
+        };
+        db.set_pin(local_name, pin).await?;
+    }
+    Ok(true)
+}
+
+async fn write_msg<T: Serialize>(writer: &mut (impl AsyncWrite + Unpin), msg: &T) -> Result<()> {
+    let data = postcard::to_stdvec(msg)?;
+    anyhow::ensure!(data.len() <= MAX_MESSAGE_SIZE, "message too large");
+    writer.write_u32_le(data.len() as u32).await?;
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+/// Read a message, or `None` if the stream ended.
+async fn read_msg<T: DeserializeOwned>(reader: &mut (impl AsyncRead + Unpin)) -> Result<Option<T>> {
+    let len = match reader.read_u32_le().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    anyhow::ensure!(len <= MAX_MESSAGE_SIZE, "message too large");
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(postcard::from_bytes(&data)?))
+}
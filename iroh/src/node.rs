@@ -5,25 +5,48 @@
 //! You can monitor what is happening in the node using [`Node::subscribe`].
 //!
 //! To shut down the node, call [`Node::shutdown`].
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::future::Future;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr};
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Poll;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::dial::Ticket;
+use crate::baomap::validation::{self, ValidationEvent, ValidationSchedule};
+use crate::cluster::{self, Cluster, ClusterConfig, ClusterGetHandler, ClusterMember, RoutedFetch};
+use crate::dial::{Ticket, TicketOptions};
+use crate::mirror::{self, MirrorConfig};
+pub use crate::rpc_protocol::NodePaths;
 use crate::rpc_protocol::{
-    AddrsRequest, AddrsResponse, IdRequest, IdResponse, ListBlobsRequest, ListBlobsResponse,
-    ListCollectionsRequest, ListCollectionsResponse, ListIncompleteBlobsRequest,
-    ListIncompleteBlobsResponse, ProvideRequest, ProviderRequest, ProviderResponse,
-    ProviderService, ShareRequest, ShutdownRequest, ValidateRequest, VersionRequest,
-    VersionResponse, WatchRequest, WatchResponse,
+    AddrsRequest, AddrsResponse, BlobDiffRequest, BlobDiffResponse, BlobReadAtRequest,
+    BlobReadAtResponse, BlobUpdateResponse, ClusterReplicasRequest, ClusterReplicasResponse,
+    DeleteBlobRequest, ExportCarRequest, ExportCarResponse, ExportPartialRequest, FetchUrlRequest,
+    HolePunchStatsRequest, HolePunchStatsResponse, IdRequest, IdResponse, ImportCarRequest,
+    ImportCarResponse, ImportPartialRequest, ImportPartialResponse, ListBlobsRequest,
+    ListBlobsResponse, ListCollectionsRequest, ListCollectionsResponse, ListIncompleteBlobsRequest,
+    ListIncompleteBlobsResponse, MirrorStatusRequest, MirrorStatusResponse, NatSummary, NodeEvent,
+    NodeStatusRequest, NodeStatusResponse, PathsRequest, PathsResponse, PeerAddRequest,
+    PeerForgetRequest, PeerPingRequest, PeerPingResponse, PeerScoresRequest, PeerScoresResponse,
+    PeerStatus, PeersListRequest, PeersListResponse, PinAddRequest, PinAddResponse, PinListRequest,
+    PinListResponse, PinRemoveRequest, PinRemoveResponse, ProvideRequest, ProviderRequest,
+    ProviderResponse, ProviderService, RelayUsageRequest, RelayUsageResponse, RestoreBlobRequest,
+    SetLogFilterRequest, ShareRequest, ShutdownRequest, SubscribeRequest, ValidateRequest,
+    VersionRequest, VersionResponse, WatchRequest, WatchResponse,
 };
+use crate::util::checksum::{
+    check_name, export_with_checksums, ChecksumAlgorithm, ChecksumManifest,
+};
+use crate::util::download_queue::{DownloadPriority, DownloadQueue, PendingDownloads};
+use crate::util::limits::{DownloadBudget, DownloadLimits, LimitExceeded};
+use crate::util::memory::{self, MemoryEvent, MemoryLimits};
+use crate::util::peer_scores::{PeerScores, VerificationFailed};
 use crate::util::progress::ProgressSliceWriter2;
+use crate::util::retry::ErrorClass;
 use anyhow::{Context, Result};
 use bao_tree::io::fsm::OutboardMut;
 use bao_tree::{ByteNum, ChunkNum};
@@ -32,25 +55,34 @@ use futures::future::{BoxFuture, Shared};
 use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
 use iroh_bytes::baomap::{
     range_collections::{range_set::RangeSetRange, RangeSet2},
-    ExportMode, Map, MapEntry, PartialMapEntry, ReadableStore, Store, ValidateProgress,
+    ExportMode, Map, MapEntry, PartialMapEntry, ReadableStore, Store, StoreEvent, ValidateProgress,
 };
 use iroh_bytes::collection::{CollectionParser, NoCollectionParser};
-use iroh_bytes::get::fsm::{AtBlobHeader, AtEndBlob, ConnectedNext, EndBlobNext};
+use iroh_bytes::get::fsm::{AtBlobHeader, AtEndBlob, AtInitial, ConnectedNext, EndBlobNext};
 use iroh_bytes::get::{self, Stats};
-use iroh_bytes::protocol::{GetRequest, RangeSpecSeq};
+use iroh_bytes::keyed::{HashDomain, NameResolver, NamespaceKey, StoreNames};
+use iroh_bytes::protocol::{GetRequest, KeyedGetRequest, RangeSpecSeq};
 use iroh_bytes::provider::ShareProgress;
-use iroh_bytes::util::progress::{FlumeProgressSender, IdGenerator, ProgressSender};
+use iroh_bytes::util::progress::{
+    FlumeProgressSender, IdGenerator, IgnoreProgressSender, ProgressSender,
+};
 use iroh_bytes::IROH_BLOCK_SIZE;
 use iroh_bytes::{
-    protocol::{Closed, Request, RequestToken},
-    provider::{CustomGetHandler, ProvideProgress, RequestAuthorizationHandler},
+    protocol::{ErrorCode, ListEntry, ProbeRequest, Request, RequestToken},
+    provider::{
+        CustomGetHandler, FairScheduler, ListHandler, NoListHandler, ProvideProgress,
+        RequestAuthorizationHandler, WriteTimeouts,
+    },
     util::runtime,
-    util::Hash,
+    util::{panic_message, Hash, RequestId, RpcResult},
 };
 use iroh_io::AsyncSliceReader;
 use iroh_net::{
     config::Endpoint,
     derp::DerpMap,
+    dns::DnsResolver,
+    magic_endpoint::{qlog::QlogConfig, Connecting, ConnectionLimits},
+    magicsock::ConnectionType,
     tls::{self, Keypair, PeerId},
     MagicEndpoint,
 };
@@ -58,14 +90,16 @@ use quic_rpc::server::RpcChannel;
 use quic_rpc::transport::flume::FlumeConnection;
 use quic_rpc::transport::misc::DummyServerEndpoint;
 use quic_rpc::{RpcClient, RpcServer, ServiceConnection, ServiceEndpoint};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, watch, RwLock};
 use tokio::task::JoinError;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, trace};
+use tracing::{debug, trace, Instrument};
 
 const MAX_CONNECTIONS: u32 = 1024;
 const MAX_STREAMS: u64 = 10;
 const HEALTH_POLL_WAIT: Duration = Duration::from_secs(1);
+/// Number of [`NodeEvent`]s buffered for subscribers before the slowest one lags.
+const EVENTS_CAPACITY: usize = 1024;
 
 /// Default bind address for the node.
 /// 11204 is "iroh" in leetspeak <https://simple.wikipedia.org/wiki/Leet>
@@ -74,11 +108,44 @@ pub const DEFAULT_BIND_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::LOCALHOST, 11204);
 /// How long we wait at most for some endpoints to be discovered.
 const ENDPOINT_WAIT: Duration = Duration::from_secs(5);
 
+/// Maximum number of connections to the QUIC RPC transport.
+#[cfg(feature = "rpc-quinn")]
+const MAX_RPC_CONNECTIONS: u32 = 16;
+/// Maximum number of concurrent requests on a QUIC RPC connection.
+#[cfg(feature = "rpc-quinn")]
+const MAX_RPC_STREAMS: u64 = 1024;
+
+/// The RPC endpoint made by [`Builder::rpc`], serving on a local socket, a QUIC port on
+/// localhost, or both.
+#[cfg(feature = "rpc-quinn")]
+pub type RpcEndpoint = quic_rpc::transport::combined::CombinedServerEndpoint<
+    crate::local_rpc::LocalServerEndpoint<ProviderRequest, ProviderResponse>,
+    quic_rpc::transport::quinn::QuinnServerEndpoint<ProviderRequest, ProviderResponse>,
+    ProviderRequest,
+    ProviderResponse,
+>;
+
 /// Builder for the [`Node`].
 ///
 /// You must supply a blob store. Various store implementations are available
 /// in [`crate::baomap`]. Everything else is optional.
 ///
+/// A node that keeps its identity and data across restarts, and can be controlled with the
+/// [`crate::client`], is set up like the `iroh provide` command does:
+///
+/// ```ignore
+/// let data_dir = DataDir::new(root);
+/// data_dir.migrate(MigrateOptions::default())?;
+/// let db = flat::Store::load(data_dir.blobs_path(), data_dir.blobs_path(), &rt).await?;
+/// let node = Node::builder(db)
+///     .collection_parser(IrohCollectionParser)
+///     .keypair(data_dir.load_keypair()?)
+///     .runtime(&rt)
+///     .rpc(Some(DEFAULT_RPC_PORT), None)?
+///     .spawn()
+///     .await?;
+/// ```
+///
 /// Finally you can create and run the node by calling [`Builder::spawn`].
 ///
 /// The returned [`Node`] is awaitable to know when it finishes.  It can be terminated
@@ -99,10 +166,27 @@ where
     auth_handler: Arc<dyn RequestAuthorizationHandler>,
     derp_map: Option<DerpMap>,
     collection_parser: C,
+    write_timeouts: WriteTimeouts,
+    scheduler: Option<FairScheduler>,
+    serve_partial: bool,
+    serve_listing: bool,
+    connection_limits: ConnectionLimits,
+    qlog: Option<QlogConfig>,
+    dns_resolver: Option<DnsResolver>,
+    validation: Option<ValidationSchedule>,
+    memory_limits: Option<MemoryLimits>,
+    max_concurrent_downloads: Option<usize>,
+    download_limits: DownloadLimits,
+    downloads_path: Option<PathBuf>,
+    namespace: Option<NamespaceKey>,
+    log_filter_handler: Option<Arc<dyn LogFilterHandler>>,
+    mirrors: Vec<MirrorConfig>,
+    cluster: Option<ClusterConfig>,
+    paths: Option<NodePaths>,
     rt: Option<runtime::Handle>,
 }
 
-const PROTOCOLS: [&[u8]; 1] = [&iroh_bytes::protocol::ALPN];
+const PROTOCOLS: [&[u8]; 2] = [&iroh_bytes::protocol::ALPN, mirror::ALPN];
 
 /// A noop authorization handler that does not do any authorization.
 ///
@@ -133,6 +217,24 @@ impl RequestAuthorizationHandler for NoopRequestAuthorizationHandler {
 #[derive(Debug)]
 struct NoopCustomGetHandler;
 
+/// Lists the collections and pinned blobs of the store, see [`Builder::serve_listing`].
+#[derive(derive_more::Debug)]
+struct StoreListHandler<D, C> {
+    // the collection parser is not Sync, but the list handler has to be
+    #[debug("..")]
+    handler: std::sync::Mutex<RpcHandler<D, C>>,
+}
+
+impl<D: Store, C: CollectionParser> ListHandler for StoreListHandler<D, C> {
+    fn list(
+        &self,
+        _token: Option<RequestToken>,
+    ) -> BoxFuture<'static, anyhow::Result<Vec<ListEntry>>> {
+        let handler = self.handler.lock().unwrap().clone();
+        handler.listing().boxed()
+    }
+}
+
 impl CustomGetHandler for NoopCustomGetHandler {
     fn handle(
         &self,
@@ -156,6 +258,23 @@ impl<D: Map> Builder<D> {
             custom_get_handler: Arc::new(NoopCustomGetHandler),
             auth_handler: Arc::new(NoopRequestAuthorizationHandler),
             collection_parser: NoCollectionParser,
+            write_timeouts: WriteTimeouts::default(),
+            scheduler: None,
+            serve_partial: false,
+            serve_listing: false,
+            connection_limits: ConnectionLimits::default(),
+            qlog: None,
+            dns_resolver: None,
+            validation: None,
+            memory_limits: None,
+            max_concurrent_downloads: None,
+            download_limits: DownloadLimits::default(),
+            downloads_path: None,
+            namespace: None,
+            log_filter_handler: None,
+            mirrors: Vec::new(),
+            cluster: None,
+            paths: None,
             rt: None,
         }
     }
@@ -183,10 +302,64 @@ where
             rpc_endpoint: value,
             derp_map: self.derp_map,
             collection_parser: self.collection_parser,
+            write_timeouts: self.write_timeouts,
+            scheduler: self.scheduler,
+            serve_partial: self.serve_partial,
+            serve_listing: self.serve_listing,
+            connection_limits: self.connection_limits,
+            qlog: self.qlog,
+            dns_resolver: self.dns_resolver,
+            validation: self.validation,
+            memory_limits: self.memory_limits,
+            max_concurrent_downloads: self.max_concurrent_downloads,
+            download_limits: self.download_limits,
+            downloads_path: self.downloads_path,
+            namespace: self.namespace,
+            log_filter_handler: self.log_filter_handler,
+            mirrors: self.mirrors,
+            cluster: self.cluster,
+            paths: self.paths,
             rt: self.rt,
         }
     }
 
+    /// Serves RPC on a QUIC port on localhost, a local socket, or both.
+    ///
+    /// The QUIC transport is secured with the keypair of the builder, so set
+    /// [`Self::keypair`] first. Fails if the port or socket can not be bound.
+    #[cfg(feature = "rpc-quinn")]
+    pub fn rpc(
+        self,
+        port: Option<u16>,
+        socket: Option<PathBuf>,
+    ) -> Result<Builder<D, RpcEndpoint, C>> {
+        let quinn = match port {
+            Some(port) => {
+                let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+                let server_config = make_server_config(
+                    &self.keypair,
+                    MAX_RPC_STREAMS,
+                    MAX_RPC_CONNECTIONS,
+                    vec![crate::client::RPC_ALPN.to_vec()],
+                )?;
+                let endpoint = quinn::Endpoint::server(server_config, addr)?;
+                Some(quic_rpc::transport::quinn::QuinnServerEndpoint::new(
+                    endpoint,
+                )?)
+            }
+            None => None,
+        };
+        let local = match socket {
+            Some(path) => Some(
+                crate::local_rpc::LocalServerEndpoint::bind(&path)
+                    .with_context(|| format!("failed to listen on {}", path.display()))?,
+            ),
+            None => None,
+        };
+        let endpoint = quic_rpc::transport::combined::CombinedServerEndpoint::new(local, quinn);
+        Ok(self.rpc_endpoint(endpoint))
+    }
+
     /// Configure the collection parser, changing the type of the builder to the new collection parser type.
     pub fn collection_parser<C2: CollectionParser>(
         self,
@@ -203,6 +376,23 @@ where
             auth_handler: self.auth_handler,
             rpc_endpoint: self.rpc_endpoint,
             derp_map: self.derp_map,
+            write_timeouts: self.write_timeouts,
+            scheduler: self.scheduler,
+            serve_partial: self.serve_partial,
+            serve_listing: self.serve_listing,
+            connection_limits: self.connection_limits,
+            qlog: self.qlog,
+            dns_resolver: self.dns_resolver,
+            validation: self.validation,
+            memory_limits: self.memory_limits,
+            max_concurrent_downloads: self.max_concurrent_downloads,
+            download_limits: self.download_limits,
+            downloads_path: self.downloads_path,
+            namespace: self.namespace,
+            log_filter_handler: self.log_filter_handler,
+            mirrors: self.mirrors,
+            cluster: self.cluster,
+            paths: self.paths,
             rt: self.rt,
         }
     }
@@ -229,6 +419,170 @@ where
         }
     }
 
+    /// Configures the timeouts for writing responses to requesters.
+    ///
+    /// By default requests can take arbitrarily long, but a request is aborted if the
+    /// requester does not accept any data for [`iroh_bytes::provider::DEFAULT_STALL_TIMEOUT`].
+    pub fn write_timeouts(mut self, timeouts: WriteTimeouts) -> Self {
+        self.write_timeouts = timeouts;
+        self
+    }
+
+    /// Lets the responses to all requesters take turns writing, so that small fetches
+    /// complete quickly while large transfers are running.
+    ///
+    /// Without a scheduler, every response writes as fast as its requester accepts data.
+    /// Use [`FairScheduler::priority_handler`] to give some responses more turns.
+    pub fn fair_scheduler(mut self, scheduler: FairScheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Serve blobs that are still being downloaded.
+    ///
+    /// When enabled, the ranges of a partial blob that have already been verified are
+    /// served to other peers requesting the same blob, so a popular download spreads
+    /// over all peers downloading it. Requesters receive only the available ranges and
+    /// have to fetch the rest from another peer.
+    ///
+    /// Disabled by default.
+    pub fn serve_partial(mut self, serve_partial: bool) -> Self {
+        self.serve_partial = serve_partial;
+        self
+    }
+
+    /// Answer list requests from authorized peers.
+    ///
+    /// Peers can then list the collections and pinned blobs of the node, with the names
+    /// of their pins and their sizes. Use together with a [`Self::custom_auth_handler`]
+    /// to restrict who may browse the node.
+    ///
+    /// Disabled by default.
+    pub fn serve_listing(mut self, serve_listing: bool) -> Self {
+        self.serve_listing = serve_listing;
+        self
+    }
+
+    /// Periodically validate the stored blobs in the background.
+    ///
+    /// See [`ValidationSchedule`] for the options. Corrupted blobs are reported as
+    /// [`Event::Validation`] events. Disabled by default.
+    pub fn background_validation(mut self, schedule: ValidationSchedule) -> Self {
+        self.validation = Some(schedule);
+        self
+    }
+
+    /// Shed load when the process uses too much memory.
+    ///
+    /// Above the high limit, new connections are not accepted and the store drops its
+    /// caches, until memory usage is below the low limit again. Crossing a limit is
+    /// reported as an [`Event::Memory`] event. See [`crate::util::memory`] for details.
+    /// Disabled by default.
+    pub fn memory_limits(mut self, limits: MemoryLimits) -> Self {
+        self.memory_limits = Some(limits);
+        self
+    }
+
+    /// Run at most `max` downloads at a time.
+    ///
+    /// Further downloads are queued, and start in the order of their
+    /// [`DownloadPriority`]. Unlimited by default. See [`crate::util::download_queue`] for
+    /// how priorities share the bandwidth of running downloads.
+    pub fn max_concurrent_downloads(mut self, max: usize) -> Self {
+        self.max_concurrent_downloads = Some(max);
+        self
+    }
+
+    /// Limits for the downloads the node starts by itself, for mirrors and clusters.
+    ///
+    /// The deadline of the limits is ignored, since it is a point in time. Downloads
+    /// requested over RPC carry their own limits. Unlimited by default.
+    pub fn download_limits(mut self, limits: DownloadLimits) -> Self {
+        self.download_limits = DownloadLimits {
+            deadline: None,
+            ..limits
+        };
+        self
+    }
+
+    /// Persist the downloads that have not completed yet in the file at `path`.
+    ///
+    /// Downloads that were still running or queued when the node stopped are resumed when
+    /// it is spawned again, from the data already in the store. Not persisted by default.
+    pub fn persist_downloads(mut self, path: impl Into<PathBuf>) -> Self {
+        self.downloads_path = Some(path.into());
+        self
+    }
+
+    /// Serve the content of the store under its names in the keyed namespace of `key`.
+    ///
+    /// Requests for plain hashes are refused, so only requesters that were given a name,
+    /// e.g. in a ticket, can fetch the content. See [`iroh_bytes::keyed`].
+    pub fn keyed_namespace(mut self, key: NamespaceKey) -> Self {
+        self.namespace = Some(key);
+        self
+    }
+
+    /// Let RPC clients replace the log filter of the process.
+    ///
+    /// The node does not set up logging itself, so changing the log filter over RPC fails
+    /// unless the application provides this handler.
+    pub fn log_filter_handler(mut self, handler: Arc<dyn LogFilterHandler>) -> Self {
+        self.log_filter_handler = Some(handler);
+        self
+    }
+
+    /// Mirror the content of another node.
+    ///
+    /// The content of the source is downloaded as soon as it shows up, and the content
+    /// of its pins is pinned locally. See [`crate::mirror`] for details. Can be called
+    /// multiple times to mirror several nodes.
+    pub fn mirror(mut self, config: MirrorConfig) -> Self {
+        self.mirrors.push(config);
+        self
+    }
+
+    /// Makes the node a member of a cluster.
+    ///
+    /// Routed get requests are answered by fetching the content from its designated
+    /// replicas first. See [`crate::cluster`] for details. The membership can be changed
+    /// while the node runs with [`Node::cluster`].
+    pub fn cluster(mut self, config: ClusterConfig) -> Self {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// Sets the paths the node was started with, reported over RPC.
+    pub fn paths(mut self, paths: NodePaths) -> Self {
+        self.paths = Some(paths);
+        self
+    }
+
+    /// Configures limits on incoming connections.
+    ///
+    /// See [`ConnectionLimits`] for details. The total number of connections is always
+    /// limited to at most 1024.
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = limits;
+        self
+    }
+
+    /// Writes qlog traces of the connections the node opens to other nodes.
+    ///
+    /// See [`QlogConfig`] for how connections are selected.
+    pub fn qlog(mut self, config: QlogConfig) -> Self {
+        self.qlog = Some(config);
+        self
+    }
+
+    /// Sets the resolver for the hostnames of DERP and STUN servers.
+    ///
+    /// By default the nameservers of the system configuration are used.
+    pub fn dns_resolver(mut self, resolver: DnsResolver) -> Self {
+        self.dns_resolver = Some(resolver);
+        self
+    }
+
     /// Binds the node service to a different socket.
     ///
     /// By default it binds to `127.0.0.1:11204`.
@@ -255,9 +609,10 @@ where
 
     /// Sets the tokio runtime to use.
     ///
-    /// If not set, the current runtime will be picked up.
-    pub fn runtime(mut self, rt: &runtime::Handle) -> Self {
-        self.rt = Some(rt.clone());
+    /// Accepts a plain tokio runtime handle as well as an iroh runtime. If not set, the
+    /// current runtime will be picked up.
+    pub fn runtime(mut self, rt: impl Into<runtime::Handle>) -> Self {
+        self.rt = Some(rt.into());
         self
     }
 
@@ -268,7 +623,10 @@ where
     /// get information about it.
     pub async fn spawn(self) -> Result<Node<D>> {
         trace!("spawning node");
-        let rt = self.rt.context("runtime not set")?;
+        let rt = match self.rt {
+            Some(rt) => rt,
+            None => runtime::Handle::try_current().context("not running in a tokio runtime")?,
+        };
 
         let (endpoints_update_s, endpoints_update_r) = flume::bounded(1);
         let mut transport_config = quinn::TransportConfig::default();
@@ -276,30 +634,59 @@ where
             .max_concurrent_bidi_streams(MAX_STREAMS.try_into()?)
             .max_concurrent_uni_streams(0u32.into());
 
-        let endpoint = MagicEndpoint::builder()
+        let mut builder = MagicEndpoint::builder()
             .keypair(self.keypair.clone())
             .alpns(PROTOCOLS.iter().map(|p| p.to_vec()).collect())
             .keylog(self.keylog)
             .derp_map(self.derp_map)
             .transport_config(transport_config)
             .concurrent_connections(MAX_CONNECTIONS)
+            .connection_limits(self.connection_limits)
             .on_endpoints(Box::new(move |eps| {
                 if !endpoints_update_s.is_disconnected() && !eps.is_empty() {
                     endpoints_update_s.send(()).ok();
                 }
-            }))
-            .bind(self.bind_addr.port())
-            .await?;
+            }));
+        if let Some(qlog) = self.qlog {
+            builder = builder.qlog(qlog);
+        }
+        if let Some(dns_resolver) = self.dns_resolver {
+            builder = builder.dns_resolver(dns_resolver);
+        }
+        let endpoint = builder.bind(self.bind_addr.port()).await?;
         trace!("created quinn endpoint");
 
         let (cb_sender, cb_receiver) = mpsc::channel(8);
         let cancel_token = CancellationToken::new();
+        let (shedding, shedding_rx) = watch::channel(false);
 
         debug!("rpc listening on: {:?}", self.rpc_endpoint.local_addr());
         let (internal_rpc, controller) = quic_rpc::transport::flume::connection(1);
         let rt2 = rt.clone();
         let rt3 = rt.clone();
         let callbacks = Callbacks::default();
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        callbacks
+            .push(Box::new({
+                let events = events.clone();
+                move |event| {
+                    if let Some(event) = node_event(event) {
+                        // no subscribers is not an error
+                        events.send(event).ok();
+                    }
+                    async {}.boxed()
+                }
+            }))
+            .await;
+        let mirrors = self.mirrors.iter().map(mirror::Tracker::new).collect();
+        let pending = match self.downloads_path {
+            Some(path) => Some(PendingDownloads::load(path).context("loading pending downloads")?),
+            None => None,
+        };
+        let names = self
+            .namespace
+            .clone()
+            .map(|key| Arc::new(StoreNames::new(key, self.db.clone())) as Arc<dyn NameResolver>);
         let inner = Arc::new(NodeInner {
             db: self.db,
             endpoint: endpoint.clone(),
@@ -308,8 +695,48 @@ where
             cancel_token,
             callbacks: callbacks.clone(),
             cb_sender,
+            events,
+            peer_scores: Default::default(),
+            downloads: DownloadQueue::new(self.max_concurrent_downloads),
+            download_limits: self.download_limits,
+            pending,
+            namespace: self.namespace,
+            mirrors,
+            cluster: self.cluster.map(|config| Arc::new(Cluster::new(config))),
+            paths: self.paths,
+            log_filter_handler: self.log_filter_handler,
             rt,
+            started: Instant::now(),
         });
+        let mirror_handler = RpcHandler {
+            inner: inner.clone(),
+            collection_parser: self.collection_parser.clone(),
+        };
+        let custom_get_handler = match inner.cluster.clone() {
+            Some(cluster) => {
+                // the collection parser is not Sync, but the get handler has to be
+                let handler = std::sync::Mutex::new(mirror_handler.clone());
+                let fetch: cluster::Fetch = Arc::new(move |member, routed| {
+                    let handler = handler.lock().unwrap().clone();
+                    handler.cluster_fetch(member, routed).boxed()
+                });
+                let local = inner.keypair.public().into();
+                Arc::new(ClusterGetHandler::new(
+                    local,
+                    cluster,
+                    self.custom_get_handler,
+                    fetch,
+                ))
+            }
+            None => self.custom_get_handler,
+        };
+        let list_handler: Arc<dyn ListHandler> = if self.serve_listing {
+            Arc::new(StoreListHandler {
+                handler: std::sync::Mutex::new(mirror_handler.clone()),
+            })
+        } else {
+            Arc::new(NoListHandler)
+        };
         let task = {
             let handler = RpcHandler {
                 inner: inner.clone(),
@@ -323,14 +750,102 @@ where
                     handler,
                     self.rpc_endpoint,
                     internal_rpc,
-                    self.custom_get_handler,
+                    custom_get_handler,
+                    list_handler,
+                    names,
                     self.auth_handler,
                     self.collection_parser,
+                    self.write_timeouts,
+                    self.scheduler,
+                    self.serve_partial,
+                    shedding_rx,
                     rt3,
                 )
                 .await
             })
         };
+        if let Some(schedule) = self.validation {
+            let db = inner.db.clone();
+            let callbacks = inner.callbacks.clone();
+            let cancel_token = inner.cancel_token.clone();
+            inner.rt.main().spawn(async move {
+                let validate = validation::run(db, schedule, |event| {
+                    let callbacks = callbacks.clone();
+                    async move { callbacks.send(Event::Validation(event)).await }
+                });
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {}
+                    _ = validate => {}
+                }
+            });
+        }
+        if let Some(limits) = self.memory_limits {
+            let db = inner.db.clone();
+            let callbacks = inner.callbacks.clone();
+            let cancel_token = inner.cancel_token.clone();
+            inner.rt.main().spawn(async move {
+                let watchdog = memory::run(
+                    limits,
+                    memory::resident_memory,
+                    shedding,
+                    || db.shrink_caches(),
+                    |event| {
+                        let callbacks = callbacks.clone();
+                        async move { callbacks.send(Event::Memory(event)).await }
+                    },
+                );
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {}
+                    _ = watchdog => {}
+                }
+            });
+        }
+        for (config, tracker) in self.mirrors.into_iter().zip(inner.mirrors.clone()) {
+            let handler = mirror_handler.clone();
+            let cancel_token = inner.cancel_token.clone();
+            // downloads are not Send, so mirrors run on the local pool like shares
+            inner.rt.local_pool().spawn_pinned(move || async move {
+                let endpoint = handler.inner.endpoint.clone();
+                let db = handler.inner.db.clone();
+                // downloads are authorized like the subscription
+                let token = config.token.clone();
+                let mirror = mirror::run(
+                    endpoint,
+                    db,
+                    config,
+                    tracker,
+                    |conn, hash, name, recursive| {
+                        let progress = IgnoreProgressSender::default();
+                        handler
+                            .clone()
+                            .get(
+                                conn,
+                                hash,
+                                name,
+                                token.clone(),
+                                recursive,
+                                handler.inner.download_limits.budget(),
+                                progress,
+                            )
+                            .map_ok(|_stats| ())
+                    },
+                );
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {}
+                    _ = mirror => {}
+                }
+            });
+        }
+        if let Some(pending) = &inner.pending {
+            for (request_id, msg) in pending.entries() {
+                let handler = mirror_handler.clone();
+                inner.rt.main().spawn(async move {
+                    tracing::info!("resuming download {}", request_id);
+                    let mut progress = handler.share(msg, request_id).boxed();
+                    while progress.next().await.is_some() {}
+                });
+            }
+        }
         let node = Node {
             inner,
             task: task.map_err(Arc::new).boxed().shared(),
@@ -356,8 +871,14 @@ where
         rpc: E,
         internal_rpc: impl ServiceEndpoint<ProviderService>,
         custom_get_handler: Arc<dyn CustomGetHandler>,
+        list_handler: Arc<dyn ListHandler>,
+        names: Option<Arc<dyn NameResolver>>,
         auth_handler: Arc<dyn RequestAuthorizationHandler>,
         collection_parser: C,
+        write_timeouts: WriteTimeouts,
+        scheduler: Option<FairScheduler>,
+        serve_partial: bool,
+        mut shedding: watch::Receiver<bool>,
         rt: runtime::Handle,
     ) {
         let rpc = RpcServer::new(rpc);
@@ -399,28 +920,34 @@ where
                         }
                     }
                 },
-                // handle incoming p2p connections
-                Some(mut connecting) = server.accept() => {
-
-                    let alpn = match get_alpn(&mut connecting).await {
-                        Ok(alpn) => alpn,
-                        Err(err) => {
-                            tracing::error!("invalid handshake: {:?}", err);
-                            continue;
+                // resume accepting connections once memory pressure is gone. This is
+                // disabled if there is no memory watchdog.
+                Ok(()) = shedding.changed() => {}
+                // handle incoming p2p connections, unless shedding load
+                Some(connecting) = server.accept(), if !*shedding.borrow() => {
+                    let remote_addr = connecting.remote_address();
+                    let serve = serve_connection(
+                        connecting,
+                        handler.inner.db.clone(),
+                        callbacks.clone(),
+                        collection_parser.clone(),
+                        custom_get_handler.clone(),
+                        list_handler.clone(),
+                        names.clone(),
+                        handler.inner.namespace.clone(),
+                        auth_handler.clone(),
+                        write_timeouts,
+                        scheduler.clone(),
+                        serve_partial,
+                        rt.clone(),
+                    );
+                    // a panic while serving one connection must not affect the others
+                    rt.main().spawn(async move {
+                        if let Err(payload) = AssertUnwindSafe(serve).catch_unwind().await {
+                            let message = panic_message(&*payload);
+                            tracing::error!(%remote_addr, "connection handler panicked: {message}");
                         }
-                    };
-                    if alpn.as_bytes() == iroh_bytes::protocol::ALPN.as_ref() {
-                        let db = handler.inner.db.clone();
-                        let custom_get_handler = custom_get_handler.clone();
-                        let auth_handler = auth_handler.clone();
-                        let collection_parser = collection_parser.clone();
-                        let rt2 = rt.clone();
-                        let callbacks = callbacks.clone();
-                        rt.main().spawn(iroh_bytes::provider::handle_connection(connecting, db, callbacks, collection_parser, custom_get_handler, auth_handler, rt2));
-                    } else {
-                        tracing::error!("unknown protocol: {}", alpn);
-                        continue;
-                    }
+                    });
                 }
                 // Handle new callbacks
                 Some(cb) = cb_receiver.recv() => {
@@ -434,7 +961,7 @@ where
         // connections: Operations will immediately fail with
         // ConnectionError::LocallyClosed.  All streams are interrupted, this is not
         // graceful.
-        let error_code = Closed::ProviderTerminating;
+        let error_code = ErrorCode::ShuttingDown;
         server
             .close(error_code.into(), error_code.reason())
             .await
@@ -442,7 +969,56 @@ where
     }
 }
 
-async fn get_alpn(connecting: &mut quinn::Connecting) -> Result<String> {
+/// Negotiates the protocol of an incoming connection and serves it.
+#[allow(clippy::too_many_arguments)]
+async fn serve_connection<D: Store, C: CollectionParser>(
+    mut connecting: Connecting,
+    db: D,
+    callbacks: Callbacks,
+    collection_parser: C,
+    custom_get_handler: Arc<dyn CustomGetHandler>,
+    list_handler: Arc<dyn ListHandler>,
+    names: Option<Arc<dyn NameResolver>>,
+    namespace: Option<NamespaceKey>,
+    auth_handler: Arc<dyn RequestAuthorizationHandler>,
+    write_timeouts: WriteTimeouts,
+    scheduler: Option<FairScheduler>,
+    serve_partial: bool,
+    rt: runtime::Handle,
+) {
+    let alpn = match get_alpn(&mut connecting).await {
+        Ok(alpn) => alpn,
+        Err(err) => {
+            tracing::error!("invalid handshake: {:?}", err);
+            return;
+        }
+    };
+    if alpn.as_bytes() == iroh_bytes::protocol::ALPN.as_ref() {
+        iroh_bytes::provider::handle_connection(
+            connecting,
+            db,
+            callbacks,
+            collection_parser,
+            custom_get_handler,
+            list_handler,
+            names,
+            auth_handler,
+            write_timeouts,
+            scheduler,
+            serve_partial,
+            rt,
+        )
+        .await
+    } else if alpn.as_bytes() == mirror::ALPN {
+        if let Err(cause) = mirror::serve(connecting, db, namespace, auth_handler).await {
+            debug!("mirror subscription ended: {:#}", cause);
+        }
+    } else {
+        tracing::error!("unknown protocol: {}", alpn);
+    }
+}
+
+async fn get_alpn(connecting: &mut Connecting) -> Result<String> {
     let data = connecting.handshake_data().await?;
     match data.downcast::<quinn::crypto::rustls::HandshakeData>() {
         Ok(data) => match data.protocol {
@@ -453,6 +1029,59 @@ async fn get_alpn(connecting: &mut quinn::Connecting) -> Result<String> {
     }
 }
 
+/// Start `request`, by `name` if the content is in a keyed namespace.
+fn start_get(
+    conn: quinn::Connection,
+    request: GetRequest,
+    name: Option<Hash>,
+    token: Option<RequestToken>,
+) -> AtInitial {
+    let request = request.with_token(token);
+    match name {
+        Some(name) => {
+            let root = request.hash;
+            let request = KeyedGetRequest::new(name, request.ranges).with_token(request.token);
+            get::fsm::start_named(conn, request, root)
+        }
+        None => get::fsm::start(conn, request.into()),
+    }
+}
+
+/// The event streamed to subscribers for a node event, if any.
+fn node_event(event: Event) -> Option<NodeEvent> {
+    use iroh_bytes::provider::Event::*;
+    match event {
+        Event::ByteProvide(CollectionAdded { hash }) => Some(NodeEvent::BlobAdded { hash }),
+        Event::ByteProvide(ClientConnected { connection_id }) => {
+            Some(NodeEvent::ClientConnected { connection_id })
+        }
+        Event::ByteProvide(TransferCollectionCompleted {
+            connection_id,
+            request_id,
+        }) => Some(NodeEvent::TransferCompleted {
+            connection_id,
+            request_id,
+        }),
+        Event::ByteProvide(TransferAborted {
+            connection_id,
+            request_id,
+        }) => Some(NodeEvent::TransferAborted {
+            connection_id,
+            request_id,
+        }),
+        Event::ByteProvide(RequestPanicked {
+            connection_id,
+            request_id,
+            message,
+        }) => Some(NodeEvent::RequestPanicked {
+            connection_id,
+            request_id,
+            message,
+        }),
+        _ => None,
+    }
+}
+
 type EventCallback = Box<dyn Fn(Event) -> BoxFuture<'static, ()> + 'static + Sync + Send>;
 
 #[derive(Default, derive_more::Debug, Clone)]
@@ -463,7 +1092,6 @@ impl Callbacks {
         self.0.write().await.push(cb);
     }
 
-    #[allow(dead_code)]
     async fn send(&self, event: Event) {
         let cbs = self.0.read().await;
         for cb in &*cbs {
@@ -511,7 +1139,26 @@ struct NodeInner<D> {
     cb_sender: mpsc::Sender<Box<dyn Fn(Event) -> BoxFuture<'static, ()> + Send + Sync + 'static>>,
     #[allow(dead_code)]
     callbacks: Callbacks,
+    events: broadcast::Sender<NodeEvent>,
+    peer_scores: PeerScores,
+    downloads: DownloadQueue,
+    /// Limits for the downloads of mirrors and clusters.
+    download_limits: DownloadLimits,
+    pending: Option<PendingDownloads<ShareRequest>>,
+    namespace: Option<NamespaceKey>,
+    mirrors: Vec<mirror::Tracker>,
+    cluster: Option<Arc<Cluster>>,
+    paths: Option<NodePaths>,
+    log_filter_handler: Option<Arc<dyn LogFilterHandler>>,
     rt: runtime::Handle,
+    started: Instant,
+}
+
+/// Replaces the log filter of the process running a node, see
+/// [`Builder::log_filter_handler`].
+pub trait LogFilterHandler: Send + Sync + Debug + 'static {
+    /// Replaces the log filter with `filter`, in the syntax of `RUST_LOG`.
+    fn set_filter(&self, filter: &str) -> anyhow::Result<()>;
 }
 
 /// Events emitted by the [`Node`] informing about the current status.
@@ -519,6 +1166,10 @@ struct NodeInner<D> {
 pub enum Event {
     /// Events from the iroh-bytes transfer protocol.
     ByteProvide(iroh_bytes::provider::Event),
+    /// Events from the background validation of stored blobs.
+    Validation(ValidationEvent),
+    /// Events from the memory watchdog.
+    Memory(MemoryEvent),
 }
 
 impl<D: ReadableStore> Node<D> {
@@ -565,6 +1216,13 @@ impl<D: ReadableStore> Node<D> {
         Ok(())
     }
 
+    /// The cluster the node is a member of, see [`Builder::cluster`].
+    ///
+    /// Use [`Cluster::set_config`] to change the membership while the node runs.
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.inner.cluster.as_deref()
+    }
+
     /// Returns a handle that can be used to do RPC calls to the node internally.
     pub fn controller(
         &self,
@@ -574,12 +1232,34 @@ impl<D: ReadableStore> Node<D> {
 
     /// Return a single token containing everything needed to get a hash.
     ///
-    /// See [`Ticket`] for more details of how it can be used.
+    /// See [`Ticket`] for more details of how it can be used. If the node serves a keyed
+    /// namespace, the ticket also contains the name of the hash, to request it by.
     pub async fn ticket(&self, hash: Hash) -> Result<Ticket> {
         // TODO: Verify that the hash exists in the db?
         let addrs = self.local_endpoint_addresses().await?;
         let region = self.inner.endpoint.my_derp().await;
-        Ticket::new(hash, self.peer_id(), addrs, None, true, region)
+        let domain = match &self.inner.namespace {
+            Some(key) => HashDomain::Keyed(key.name(&hash)),
+            None => HashDomain::Plain,
+        };
+        Ok(Ticket::new(hash, self.peer_id(), addrs, None, true, region)?.with_domain(domain))
+    }
+
+    /// Return a token to get a hash, embedding only the dialing info selected by `options`.
+    pub async fn ticket_with_options(&self, hash: Hash, options: TicketOptions) -> Result<Ticket> {
+        Ok(self.ticket(hash).await?.with_options(options))
+    }
+
+    /// Return a request token for `hash` that expires at `expires`, signed by this node.
+    ///
+    /// The token only authorizes requests for `hash`, which is the name of the content if
+    /// the node has a keyed namespace.
+    ///
+    /// Only nodes that authorize requests with an
+    /// [`ExpiringTokenAuthHandler`](crate::util::expiring_token::ExpiringTokenAuthHandler)
+    /// accept these tokens.
+    pub fn expiring_token(&self, hash: Hash, expires: SystemTime) -> RequestToken {
+        crate::util::expiring_token::create(&self.inner.keypair, hash, expires)
     }
 
     /// Return the DERP region that this provider is connected to
@@ -614,6 +1294,21 @@ impl<D: Map> NodeInner<D> {
         Ok(endpoints.into_iter().map(|x| x.addr).collect())
     }
 
+    async fn peers(&self) -> Result<Vec<PeerStatus>> {
+        let infos = self.endpoint.connection_infos().await?;
+        Ok(infos
+            .into_iter()
+            .map(|info| PeerStatus {
+                peer_id: self.endpoint.known_peer_id(&info.public_key),
+                node_key: info.public_key,
+                derp_region: info.derp_addr,
+                addrs: info.addrs,
+                conn_type: info.conn_type,
+                latency: info.latency,
+            })
+            .collect())
+    }
+
     fn local_address(&self) -> Result<Vec<SocketAddr>> {
         let (v4, v6) = self.endpoint.local_addr()?;
         let mut addrs = vec![v4];
@@ -688,6 +1383,54 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         })
     }
 
+    /// The collections and pinned blobs, named after their pins.
+    ///
+    /// With a keyed namespace, the entries are listed under their names in the namespace.
+    async fn listing(self) -> anyhow::Result<Vec<ListEntry>> {
+        let now = SystemTime::now();
+        let pins = self
+            .inner
+            .db
+            .pins()
+            .filter(|(_, pin)| !pin.is_expired(now))
+            .collect::<Vec<_>>();
+        let name = |hash: Hash| {
+            pins.iter()
+                .find(|(_, pin)| pin.hash == hash)
+                .map(|(name, _)| name.clone())
+        };
+        let db = self.inner.db.clone();
+        let mut entries = self
+            .list_collections(ListCollectionsRequest)
+            .map(|collection| ListEntry {
+                hash: collection.hash,
+                name: name(collection.hash),
+                size: collection.total_blobs_size.unwrap_or_default(),
+                collection: true,
+            })
+            .collect::<Vec<_>>()
+            .await;
+        for (name, pin) in &pins {
+            if entries.iter().any(|entry| entry.hash == pin.hash) {
+                continue;
+            }
+            if let Some(entry) = db.get(&pin.hash) {
+                entries.push(ListEntry {
+                    hash: pin.hash,
+                    name: Some(name.clone()),
+                    size: entry.size(),
+                    collection: false,
+                });
+            }
+        }
+        if let Some(key) = &self.inner.namespace {
+            for entry in &mut entries {
+                entry.hash = key.name(&entry.hash);
+            }
+        }
+        Ok(entries)
+    }
+
     fn list_collections(
         self,
         _msg: ListCollectionsRequest,
@@ -728,7 +1471,8 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         let db = self.inner.db.clone();
         self.rt().main().spawn(async move {
             if let Err(e) = db.validate(tx).await {
-                tx2.send(ValidateProgress::Abort(e.into())).await.unwrap();
+                // the client might be gone, which is what stopped the validation
+                tx2.send(ValidateProgress::Abort(e.into())).await.ok();
             }
         });
         tokio_stream::wrappers::ReceiverStream::new(rx)
@@ -750,13 +1494,18 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         self,
         conn: quinn::Connection,
         hash: Hash,
+        name: Option<Hash>,
+        token: Option<RequestToken>,
         recursive: bool,
+        mut budget: DownloadBudget,
         sender: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<Stats> {
         let res = if recursive {
-            self.get_collection(conn, &hash, sender).await
+            self.get_collection(conn, &hash, name, token, &mut budget, sender)
+                .await
         } else {
-            self.get_blob(conn, &hash, sender).await
+            self.get_blob(conn, &hash, name, token, &mut budget, sender)
+                .await
         };
         if let Err(e) = res.as_ref() {
             tracing::error!("get failed: {}", e);
@@ -764,16 +1513,148 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         res
     }
 
+    /// Fetch a hash from a cluster member into the store, for a routed get request.
+    ///
+    /// Content fetched for a [temporary](RoutedFetch::temporary) request is deleted again
+    /// after [`cluster::ROUTED_CACHE_TTL`], unless the node has become one of its replicas
+    /// or it got pinned in the meantime.
+    async fn cluster_fetch(self, member: ClusterMember, routed: RoutedFetch) -> Result<()> {
+        let RoutedFetch {
+            hash, recursive, ..
+        } = routed;
+        let complete = self
+            .inner
+            .db
+            .get(&hash)
+            .map_or(false, |entry| entry.is_complete());
+        // the children of a collection might be missing, so collections are always fetched
+        if complete && !recursive {
+            return Ok(());
+        }
+        // replication yields to the downloads users are waiting for
+        let permit = self
+            .inner
+            .downloads
+            .acquire(DownloadPriority::Background)
+            .await;
+        let conn = self
+            .inner
+            .endpoint
+            .connect(
+                member.peer,
+                &iroh_bytes::protocol::ALPN,
+                member.derp_region,
+                &member.addrs,
+            )
+            .await?;
+        permit.set_connection(&conn);
+        let signed = self
+            .inner
+            .cluster
+            .as_ref()
+            .map_or(false, |cluster| cluster.config().signed_fetches);
+        let token = signed.then(|| {
+            let expires = SystemTime::now() + cluster::FETCH_TOKEN_TTL;
+            crate::util::expiring_token::create(&self.inner.keypair, hash, expires)
+        });
+        // only entries that are new to the store are evicted later
+        let mut added = self.inner.db.subscribe();
+        let rt = self.inner.rt.clone();
+        let handler = self.clone();
+        // downloads are not Send, so they run on the local pool like shares
+        rt.local_pool()
+            .spawn_pinned(move || async move {
+                let _permit = permit;
+                let progress = IgnoreProgressSender::default();
+                let budget = handler.inner.download_limits.budget();
+                handler
+                    .get(conn, hash, None, token, recursive, budget, progress)
+                    .await
+            })
+            .await??;
+        if !routed.temporary {
+            return Ok(());
+        }
+        let mut fetched = Vec::new();
+        loop {
+            match added.try_recv() {
+                Ok(StoreEvent::Added(added)) => fetched.push(added),
+                Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        // other downloads might have added entries at the same time
+        let mut content = vec![hash];
+        if recursive {
+            content.extend(self.clone().collection_children(hash).await);
+        }
+        fetched.retain(|hash| content.contains(hash));
+        if !fetched.is_empty() {
+            // the handler is not Sync, so the eviction can not run on the main runtime
+            rt.local_pool()
+                .spawn_pinned(move || self.evict_routed(fetched));
+        }
+        Ok(())
+    }
+
+    /// Delete content fetched for a temporary routed get request once it has expired.
+    async fn evict_routed(self, hashes: Vec<Hash>) {
+        tokio::time::sleep(cluster::ROUTED_CACHE_TTL).await;
+        let Some(cluster) = self.inner.cluster.clone() else {
+            return;
+        };
+        let local = self.inner.keypair.public().into();
+        for hash in hashes {
+            if cluster.is_replica(&local, &hash) || self.pinned_by(hash).await.is_some() {
+                continue;
+            }
+            // the store refuses to delete pinned roots
+            if let Err(cause) = self.inner.db.delete(hash).await {
+                debug!("not evicting {}: {}", hash, cause);
+            }
+        }
+    }
+
+    /// The children of a complete collection, empty if it can not be parsed.
+    async fn collection_children(self, hash: Hash) -> Vec<Hash> {
+        let Some(entry) = self.inner.db.get(&hash) else {
+            return Vec::new();
+        };
+        let cp = self.collection_parser.clone();
+        self.rt()
+            .local_pool()
+            .spawn_pinned(move || async move {
+                let mut children = Vec::new();
+                let Ok(reader) = entry.data_reader().await else {
+                    return children;
+                };
+                let Ok((mut collection, _stats)) = cp.parse(0, reader).await else {
+                    return children;
+                };
+                while let Ok(Some(child)) = collection.next().await {
+                    children.push(child);
+                }
+                children
+            })
+            .await
+            .unwrap_or_default()
+    }
+
     async fn export(
         self,
         out: String,
         hash: Hash,
         recursive: bool,
         stable: bool,
+        checksums: Vec<ChecksumAlgorithm>,
+        apply_metadata: bool,
         progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<()> {
         let db = &self.inner.db;
         let path = PathBuf::from(&out);
+        // computing checksums requires streaming the data, so the data is always
+        // copied in that case
+        let mut manifest = ChecksumManifest::default();
         let mode = if stable {
             ExportMode::TryReference
         } else {
@@ -791,6 +1672,9 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                 let mut reader = collection.data_reader().await?;
                 let bytes: Bytes = reader.read_to_end().await?;
                 let collection = Collection::from_bytes(&bytes).context("invalid collection")?;
+                for blob in collection.blobs() {
+                    check_name(&blob.name, &checksums)?;
+                }
                 for Blob { hash, name } in collection.blobs() {
                     let path = path.join(pathbuf_from_name(name));
                     if let Some(parent) = path.parent() {
@@ -799,11 +1683,27 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                     tracing::trace!("exporting blob {} to {}", hash, path.display());
                     let id = progress.new_id();
                     let progress1 = progress.clone();
-                    db.export(*hash, path, mode, move |offset| {
+                    let on_progress = move |offset| -> io::Result<()> {
                         Ok(progress1.try_send(ShareProgress::ExportProgress { id, offset })?)
-                    })
-                    .await?;
+                    };
+                    if checksums.is_empty() {
+                        db.export(*hash, path, mode, on_progress).await?;
+                    } else {
+                        let sums = export_with_checksums(db, *hash, &path, &checksums, on_progress)
+                            .await?;
+                        manifest.add(name, sums);
+                    }
                 }
+                // only once all files are written, so none is written through a symlink
+                if apply_metadata {
+                    for (name, metadata) in collection.metadata() {
+                        let target = path.join(pathbuf_from_name(name));
+                        metadata
+                            .apply(&path, &target)
+                            .with_context(|| format!("failed to apply metadata to {name}"))?;
+                    }
+                }
+                manifest.write(&path).await?;
             }
             #[cfg(not(feature = "iroh-collection"))]
             anyhow::bail!("recursive export not supported without iroh-collection feature");
@@ -820,10 +1720,22 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                 })
                 .await?;
             let progress1 = progress.clone();
-            db.export(hash, path, mode, move |offset| {
+            let on_progress = move |offset| -> io::Result<()> {
                 Ok(progress1.try_send(ShareProgress::ExportProgress { id, offset })?)
-            })
-            .await?;
+            };
+            if checksums.is_empty() {
+                db.export(hash, path, mode, on_progress).await?;
+            } else {
+                let name = path
+                    .file_name()
+                    .context("out path has no file name")?
+                    .to_string_lossy()
+                    .into_owned();
+                check_name(&name, &checksums)?;
+                let sums = export_with_checksums(db, hash, &path, &checksums, on_progress).await?;
+                manifest.add(&name, sums);
+                manifest.write(parent).await?;
+            }
         }
         anyhow::Ok(())
     }
@@ -835,6 +1747,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
     async fn get_blob_inner(
         db: &D,
         header: AtBlobHeader,
+        budget: &mut DownloadBudget,
         sender: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<AtEndBlob> {
         use iroh_io::AsyncSliceWriter;
@@ -842,6 +1755,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         let hash = header.hash();
         // read the size
         let (content, size) = header.next().await?;
+        budget.blob(size)?;
         // create the temp file pair
         let entry = db.get_or_create_partial(hash, size)?;
         // open the data file in any case
@@ -855,7 +1769,11 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         let id = sender.new_id();
         sender.send(ShareProgress::Found { id, hash, size }).await?;
         let sender2 = sender.clone();
-        let on_write = move |offset: u64, _length: usize| {
+        let budget2 = budget.clone();
+        let on_write = move |offset: u64, length: usize| {
+            budget2
+                .read(length as u64)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
             // if try send fails it means that the receiver has been dropped.
             // in that case we want to abort the write_all_with_outboard.
             sender2
@@ -868,9 +1786,10 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         };
         let mut pw = ProgressSliceWriter2::new(df, on_write);
         // use the convenience method to write all to the two vfs objects
-        let end = content
-            .write_all_with_outboard(of.as_mut(), &mut pw)
-            .await?;
+        let end = match content.write_all_with_outboard(of.as_mut(), &mut pw).await {
+            Ok(end) => end,
+            Err(cause) => return Err(Self::decode_error(hash, &entry, cause).await),
+        };
         // sync the data file
         pw.sync().await?;
         // sync the outboard file, if we wrote one
@@ -891,6 +1810,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         db: &D,
         header: AtBlobHeader,
         entry: D::PartialEntry,
+        budget: &mut DownloadBudget,
         sender: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<AtEndBlob> {
         // TODO: the data we get is validated at this point, but we need to check
@@ -900,6 +1820,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         let hash = header.hash();
         // read the size
         let (content, size) = header.next().await?;
+        budget.blob(size)?;
         // open the data file in any case
         let df = entry.data_writer().await?;
         let mut of = if needs_outboard(size) {
@@ -911,7 +1832,11 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         let id = sender.new_id();
         sender.send(ShareProgress::Found { id, hash, size }).await?;
         let sender2 = sender.clone();
-        let on_write = move |offset: u64, _length: usize| {
+        let budget2 = budget.clone();
+        let on_write = move |offset: u64, length: usize| {
+            budget2
+                .read(length as u64)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
             // if try send fails it means that the receiver has been dropped.
             // in that case we want to abort the write_all_with_outboard.
             sender2
@@ -924,9 +1849,10 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         };
         let mut pw = ProgressSliceWriter2::new(df, on_write);
         // use the convenience method to write all to the two vfs objects
-        let end = content
-            .write_all_with_outboard(of.as_mut(), &mut pw)
-            .await?;
+        let end = match content.write_all_with_outboard(of.as_mut(), &mut pw).await {
+            Ok(end) => end,
+            Err(cause) => return Err(Self::decode_error(hash, &entry, cause).await),
+        };
         // sync the data file
         pw.sync().await?;
         // sync the outboard file
@@ -941,6 +1867,33 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         Ok(end)
     }
 
+    /// Convert an error from writing a blob, noting which ranges failed verification.
+    async fn decode_error(
+        hash: Hash,
+        entry: &D::PartialEntry,
+        cause: bao_tree::io::DecodeError,
+    ) -> anyhow::Error {
+        // a limit that was exceeded while writing is reported as is
+        if let bao_tree::io::DecodeError::Io(error) = &cause {
+            if let Some(exceeded) = error
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<LimitExceeded>())
+            {
+                return exceeded.clone().into();
+            }
+        }
+        let cause = anyhow::Error::from(cause);
+        if ErrorClass::classify(&cause) != ErrorClass::Verification {
+            return cause;
+        }
+        // data is verified before it is written, so everything that is still missing
+        // was not verified
+        let ranges = Self::get_missing_ranges_blob(entry)
+            .await
+            .unwrap_or_else(|_| RangeSet2::all());
+        cause.context(VerificationFailed { hash, ranges })
+    }
+
     async fn get_missing_ranges_blob(
         entry: &D::PartialEntry,
     ) -> anyhow::Result<RangeSet2<ChunkNum>> {
@@ -971,6 +1924,9 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         &self,
         conn: quinn::Connection,
         hash: &Hash,
+        name: Option<Hash>,
+        token: Option<RequestToken>,
+        budget: &mut DownloadBudget,
         progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<Stats> {
         let db = &self.inner.db;
@@ -983,7 +1939,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                 .unwrap_or_else(RangeSet2::all);
             let request = GetRequest::new(*hash, RangeSpecSeq::new([required_ranges]));
             // full request
-            let request = get::fsm::start(conn, iroh_bytes::protocol::Request::Get(request));
+            let request = start_get(conn, request, name, token);
             // create a new bidi stream
             let connected = request.next().await?;
             // next step. we have requested a single hash, so this must be StartRoot
@@ -994,13 +1950,10 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
             let header = start.next();
             // do the ceremony of getting the blob and adding it to the database
 
-            Self::get_blob_inner_partial(db, header, entry, progress).await?
+            Self::get_blob_inner_partial(db, header, entry, budget, progress).await?
         } else {
             // full request
-            let request = get::fsm::start(
-                conn,
-                iroh_bytes::protocol::Request::Get(GetRequest::single(*hash)),
-            );
+            let request = start_get(conn, GetRequest::single(*hash), name, token);
             // create a new bidi stream
             let connected = request.next().await?;
             // next step. we have requested a single hash, so this must be StartRoot
@@ -1010,7 +1963,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
             // move to the header
             let header = start.next();
             // do the ceremony of getting the blob and adding it to the database
-            Self::get_blob_inner(db, header, progress).await?
+            Self::get_blob_inner(db, header, budget, progress).await?
         };
 
         // we have requested a single hash, so we must be at closing
@@ -1059,6 +2012,9 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         &self,
         conn: quinn::Connection,
         root_hash: &Hash,
+        name: Option<Hash>,
+        token: Option<RequestToken>,
+        budget: &mut DownloadBudget,
         sender: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<Stats> {
         use tracing::info as log;
@@ -1079,6 +2035,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
             while let Some(hash) = collection.next().await? {
                 children.push(hash);
             }
+            budget.collection(children.len() as u64, stats.total_blob_size)?;
             let missing_info = self.get_missing_ranges_collection(&children).await?;
             if missing_info.iter().all(|x| matches!(x, BlobInfo::Complete)) {
                 log!("nothing to do");
@@ -1089,7 +2046,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                 .collect::<Vec<_>>();
             log!("requesting chunks {:?}", missing_iter);
             let request = GetRequest::new(*root_hash, RangeSpecSeq::new(missing_iter));
-            let request = get::fsm::start(conn, request.into());
+            let request = start_get(conn, request, name, token);
             // create a new bidi stream
             let connected = request.next().await?;
             log!("connected");
@@ -1118,9 +2075,12 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                 );
                 let header = start.next(child_hash);
                 let end_blob = match info {
-                    BlobInfo::Missing => Self::get_blob_inner(db, header, sender.clone()).await?,
+                    BlobInfo::Missing => {
+                        Self::get_blob_inner(db, header, budget, sender.clone()).await?
+                    }
                     BlobInfo::Partial { entry, .. } => {
-                        Self::get_blob_inner_partial(db, header, entry.clone(), sender.clone())
+                        let entry = entry.clone();
+                        Self::get_blob_inner_partial(db, header, entry, budget, sender.clone())
                             .await?
                     }
                     BlobInfo::Complete => anyhow::bail!("got data we have not requested"),
@@ -1130,10 +2090,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         } else {
             tracing::info!("don't have collection - doing full download");
             // don't have the collection, so probably got nothing
-            let request = get::fsm::start(
-                conn,
-                iroh_bytes::protocol::Request::Get(GetRequest::all(*root_hash)),
-            );
+            let request = start_get(conn, GetRequest::all(*root_hash), name, token);
             // create a new bidi stream
             let connected = request.next().await?;
             // next step. we have requested a single hash, so this must be StartRoot
@@ -1143,7 +2100,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
             // move to the header
             let header = start.next();
             // read the blob and add it to the database
-            let end_root = Self::get_blob_inner(db, header, sender.clone()).await?;
+            let end_root = Self::get_blob_inner(db, header, budget, sender.clone()).await?;
             // read the collection fully for now
             let entry = db.get(root_hash).context("just downloaded")?;
             let reader = entry.data_reader().await?;
@@ -1159,6 +2116,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
             while let Some(hash) = collection.next().await? {
                 children.push(hash);
             }
+            budget.collection(children.len() as u64, stats.total_blob_size)?;
             let mut next = end_root.next();
             // read all the children
             loop {
@@ -1173,7 +2131,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                     None => break start.finish(),
                 };
                 let header = start.next(child_hash);
-                let end_blob = Self::get_blob_inner(db, header, sender.clone()).await?;
+                let end_blob = Self::get_blob_inner(db, header, budget, sender.clone()).await?;
                 next = end_blob.next();
             }
         };
@@ -1182,58 +2140,310 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         anyhow::Ok(stats)
     }
 
+    /// Checks that a provider has the root of a share request before downloading from it.
+    ///
+    /// Used to pick among alternative providers, so providers that are reachable but don't
+    /// have the data don't win the race. Providers that don't understand probes are
+    /// assumed to have the data.
+    async fn probe_provider(
+        conn: &quinn::Connection,
+        msg: &ShareRequest,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        let request = ProbeRequest {
+            hash: msg.hash,
+            token: msg.token.clone(),
+        };
+        let probe = tokio::time::timeout(timeout, get::probe(conn, request)).await;
+        match probe {
+            Ok(Ok(response)) if response.size.is_none() => {
+                Err(get::GetResponseError::Remote(ErrorCode::NotFound).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Download the data for a share request, retrying according to its
+    /// [`RetryPolicy`](crate::util::retry::RetryPolicy).
+    ///
+    /// Every attempt starts by looking at the partial entries in the store, so a retry
+    /// resumes where the previous attempt stopped.
+    ///
+    /// The download waits for its turn in the download queue of the node first. The
+    /// deadline of its [`DownloadLimits`] covers the wait and all attempts.
+    ///
+    /// Returns the root hash of the data, the hash of the request.
+    async fn download(
+        self,
+        msg: ShareRequest,
+        progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
+    ) -> anyhow::Result<(Hash, Stats)> {
+        let limits = msg.limits;
+        limits
+            .with_deadline(self.download_attempts(msg, progress))
+            .await
+    }
+
+    async fn download_attempts(
+        self,
+        msg: ShareRequest,
+        progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
+    ) -> anyhow::Result<(Hash, Stats)> {
+        let permit = self.inner.downloads.acquire(msg.priority).await;
+        let budget = msg.limits.budget();
+        let policy = &msg.retry;
+        let scores = &self.inner.peer_scores;
+        let providers = std::iter::once((msg.peer, msg.derp_region, &msg.addrs))
+            .chain(
+                msg.alternatives
+                    .iter()
+                    .map(|p| (p.peer, p.derp_region, &p.addrs)),
+            )
+            .collect::<Vec<_>>();
+        // with alternatives, only providers that have the data take part in the race
+        let probe = providers.len() > 1;
+        let name = msg.domain.name();
+        // providers that failed in the middle of a transfer, skipped until all of them did
+        let mut failed_over = HashSet::new();
+        let mut attempt = 1;
+        loop {
+            // the peer that was dialed, once one could be reached
+            let mut peer = None;
+            let res = async {
+                let msg = &msg;
+                let dials = providers
+                    .iter()
+                    .filter(|(peer, _, _)| {
+                        !failed_over.contains(peer) && !scores.is_avoided(peer, &msg.hash)
+                    })
+                    .map(|&(peer, derp_region, addrs)| {
+                        async move {
+                            let conn = tokio::time::timeout(
+                                policy.dial_timeout,
+                                self.inner.endpoint.connect(
+                                    peer,
+                                    &iroh_bytes::protocol::ALPN,
+                                    derp_region,
+                                    addrs,
+                                ),
+                            )
+                            .await
+                            .context("dial timed out")??;
+                            // a provider with a keyed namespace does not answer probes
+                            if probe && name.is_none() {
+                                Self::probe_provider(&conn, msg, policy.dial_timeout).await?;
+                            }
+                            anyhow::Ok((peer, conn))
+                        }
+                        .boxed_local()
+                    })
+                    .collect::<Vec<_>>();
+                anyhow::ensure!(
+                    !dials.is_empty(),
+                    "all providers sent invalid data for {} before",
+                    msg.hash
+                );
+                // providers are dialed in parallel, the first one to answer is used
+                let ((dialed, conn), _pending) = futures::future::select_ok(dials).await?;
+                peer = Some(dialed);
+                permit.set_connection(&conn);
+                progress.send(ShareProgress::Connected).await?;
+                self.clone()
+                    .get(
+                        conn,
+                        msg.hash,
+                        name,
+                        msg.token.clone(),
+                        msg.recursive,
+                        budget.attempt(),
+                        progress.clone(),
+                    )
+                    .await
+                    .map(|stats| (msg.hash, stats))
+            }
+            .await;
+            let cause = match res {
+                Ok(res) => {
+                    if let Some(peer) = peer {
+                        scores.record_success(peer);
+                    }
+                    return Ok(res);
+                }
+                Err(cause) => cause,
+            };
+            if let (Some(peer), Some(failure)) = (peer, cause.downcast_ref::<VerificationFailed>())
+            {
+                scores.record_verification_failure(peer, msg.hash, failure);
+            }
+            let class = ErrorClass::classify(&cause);
+            // a provider that fails in the middle of a transfer is replaced by the next one
+            // right away, which resumes from the partial entry
+            let provider_failed = matches!(
+                class,
+                ErrorClass::ConnectionLost
+                    | ErrorClass::DialTimeout
+                    | ErrorClass::Verification
+                    | ErrorClass::NotFound
+                    | ErrorClass::RateLimited
+            );
+            if let Some(peer) = peer.filter(|_| provider_failed) {
+                failed_over.insert(peer);
+                if providers.iter().any(|(p, _, _)| !failed_over.contains(p)) {
+                    tracing::warn!(
+                        "download from {} failed ({}): {}, trying the next provider",
+                        peer,
+                        class,
+                        cause
+                    );
+                    continue;
+                }
+                // every provider failed, a retry starts over with all of them
+                failed_over.clear();
+            }
+            if !policy.should_retry(attempt, class) {
+                return Err(cause);
+            }
+            let delay = policy.backoff(attempt);
+            tracing::warn!(
+                "download attempt {} failed ({}): {}, retrying in {:?}",
+                attempt,
+                class,
+                cause,
+                delay
+            );
+            progress
+                .send(ShareProgress::Retry {
+                    attempt,
+                    cause: cause.to_string(),
+                    delay,
+                })
+                .await?;
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
     async fn share0(
         self,
         msg: ShareRequest,
+        request_id: RequestId,
         progress: impl ProgressSender<Msg = ShareProgress> + IdGenerator,
     ) -> anyhow::Result<()> {
         let local = self.inner.rt.local_pool().clone();
-        let hash = msg.hash;
         tracing::info!("share: {:?}", msg);
-        let conn = self
-            .inner
-            .endpoint
-            .connect(
-                msg.peer,
-                &iroh_bytes::protocol::ALPN,
-                msg.derp_region,
-                &msg.addrs,
-            )
-            .await?;
-        progress.send(ShareProgress::Connected).await?;
+        if let Some(pending) = &self.inner.pending {
+            pending
+                .insert(request_id, msg.clone())
+                .context("failed to persist download")?;
+        }
         let progress2 = progress.clone();
         let progress3 = progress.clone();
         let this = self.clone();
-        let download =
-            local.spawn_pinned(move || self.get(conn, msg.hash, msg.recursive, progress2));
-        let _export = local.spawn_pinned(move || async move {
-            let stats = download.await.unwrap()?;
-            progress
-                .send(ShareProgress::NetworkDone {
-                    bytes_written: stats.bytes_written,
-                    bytes_read: stats.bytes_read,
-                    elapsed: stats.elapsed,
-                })
-                .await?;
-            if let Some(out) = msg.out {
-                if let Err(cause) = this
-                    .export(out, hash, msg.recursive, msg.in_place, progress3)
-                    .await
-                {
-                    progress.send(ShareProgress::Abort(cause.into())).await?;
+        let msg2 = msg.clone();
+        // the tasks are part of the span of the request
+        let span = tracing::Span::current();
+        let download = local.spawn_pinned({
+            let span = span.clone();
+            move || self.download(msg2, progress2).instrument(span)
+        });
+        let _export = local.spawn_pinned(move || {
+            let inner = this.inner.clone();
+            async move {
+                let (hash, stats) = match download.await.unwrap() {
+                    Ok(res) => res,
+                    Err(cause) => {
+                        progress.send(ShareProgress::Abort(cause.into())).await?;
+                        return Ok(());
+                    }
+                };
+                progress
+                    .send(ShareProgress::NetworkDone {
+                        bytes_written: stats.bytes_written,
+                        bytes_read: stats.bytes_read,
+                        elapsed: stats.elapsed,
+                    })
+                    .await?;
+                if let Some(out) = msg.out {
+                    if let Err(cause) = this
+                        .export(
+                            out,
+                            hash,
+                            msg.recursive,
+                            msg.in_place,
+                            msg.checksums,
+                            msg.metadata,
+                            progress3,
+                        )
+                        .await
+                    {
+                        progress.send(ShareProgress::Abort(cause.into())).await?;
+                    }
                 }
+                progress.send(ShareProgress::AllDone).await?;
+                anyhow::Ok(())
             }
-            progress.send(ShareProgress::AllDone).await?;
-            anyhow::Ok(())
+            .map(move |res| {
+                // downloads interrupted by a shutdown are resumed on the next start
+                if let Some(pending) = inner.pending.as_ref() {
+                    if !inner.cancel_token.is_cancelled() {
+                        if let Err(cause) = pending.remove(request_id) {
+                            tracing::warn!("failed to persist download: {}", cause);
+                        }
+                    }
+                }
+                res
+            })
+            .instrument(span)
         });
         Ok(())
     }
 
-    fn share(self, msg: ShareRequest) -> impl Stream<Item = ShareProgress> {
+    fn share(self, msg: ShareRequest, request_id: RequestId) -> impl Stream<Item = ShareProgress> {
         async move {
             let (sender, receiver) = flume::bounded(1024);
-            let sender = FlumeProgressSender::new(sender);
-            if let Err(cause) = self.share0(msg, sender.clone()).await {
+            let events = self.inner.events.clone();
+            events
+                .send(NodeEvent::DownloadStarted {
+                    request_id,
+                    hash: msg.hash,
+                })
+                .ok();
+            // tee the progress of the download to the subscribers
+            let sender = FlumeProgressSender::new(sender).with_filter_map(move |progress| {
+                let event = match &progress {
+                    ShareProgress::Found { id, hash, size } => Some(NodeEvent::DownloadBlob {
+                        request_id,
+                        id: *id,
+                        hash: *hash,
+                        size: *size,
+                    }),
+                    ShareProgress::Progress { id, offset } => Some(NodeEvent::DownloadProgress {
+                        request_id,
+                        id: *id,
+                        offset: *offset,
+                    }),
+                    ShareProgress::NetworkDone { bytes_read, .. } => {
+                        Some(NodeEvent::DownloadCompleted {
+                            request_id,
+                            bytes_read: *bytes_read,
+                        })
+                    }
+                    ShareProgress::Abort(cause) => Some(NodeEvent::DownloadFailed {
+                        request_id,
+                        error: cause.to_string(),
+                    }),
+                    _ => None,
+                };
+                if let Some(event) = event {
+                    events.send(event).ok();
+                }
+                Some(progress)
+            });
+            sender
+                .send(ShareProgress::Started { request_id })
+                .await
+                .unwrap();
+            if let Err(cause) = self.share0(msg, request_id, sender.clone()).await {
                 sender
                     .send(ShareProgress::Abort(cause.into()))
                     .await
@@ -1250,7 +2460,7 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         msg: ProvideRequest,
         progress: flume::Sender<ProvideProgress>,
     ) -> anyhow::Result<()> {
-        use crate::collection::{Blob, Collection};
+        use crate::collection::{Blob, Collection, Metadata};
         use futures::TryStreamExt;
         use iroh_bytes::baomap::{ImportMode, ImportProgress};
         use std::{collections::BTreeMap, sync::Mutex};
@@ -1275,6 +2485,9 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                 Some(ProvideProgress::Progress { id, offset })
             }
             ImportProgress::OutboardDone { hash, id } => Some(ProvideProgress::Done { hash, id }),
+            ImportProgress::Deduplicated { id, size } => {
+                Some(ProvideProgress::Deduplicated { id, size })
+            }
             _ => None,
         });
         let root = msg.path;
@@ -1283,31 +2496,61 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
             root.is_dir() || root.is_file(),
             "path must be either a Directory or a File"
         );
-        let data_sources = crate::util::fs::scan_path(root)?;
+        let with_metadata = msg.metadata;
+        let data_sources = crate::util::fs::scan_path(root, with_metadata, &msg.filter)?;
         let mode = if msg.in_place {
             ImportMode::TryReference
         } else {
             ImportMode::Copy
         };
         const IO_PARALLELISM: usize = 4;
-        let result: Vec<(Blob, u64)> = futures::stream::iter(data_sources)
+        let result: Vec<(Blob, u64, Option<Metadata>)> = futures::stream::iter(data_sources)
             .map(|source| {
                 let import_progress = import_progress.clone();
                 let db = self.inner.db.clone();
                 async move {
                     let name = source.name().to_string();
-                    let (hash, size) = db
-                        .import(source.path().to_owned(), mode, import_progress)
-                        .await?;
-                    io::Result::Ok((Blob { hash, name }, size))
+                    let path = source.path().to_owned();
+                    let metadata = if with_metadata {
+                        Some(Metadata::read(&path)?)
+                    } else {
+                        None
+                    };
+                    let (hash, size) = match metadata.as_ref().and_then(|m| m.symlink.clone()) {
+                        // a symlink is stored as a blob containing its target
+                        Some(target) => {
+                            let id = import_progress.new_id();
+                            let size = target.len() as u64;
+                            import_progress
+                                .send(ImportProgress::Found { id, path })
+                                .await?;
+                            import_progress
+                                .send(ImportProgress::Size { id, size })
+                                .await?;
+                            let hash = db.import_bytes(target.into()).await?;
+                            import_progress
+                                .send(ImportProgress::OutboardDone { id, hash })
+                                .await?;
+                            (hash, size)
+                        }
+                        None => db.import(path, mode, import_progress).await?,
+                    };
+                    io::Result::Ok((Blob { hash, name }, size, metadata))
                 }
             })
             .buffered(IO_PARALLELISM)
             .try_collect::<Vec<_>>()
             .await?;
-        let total_blobs_size = result.iter().map(|(_, size)| *size).sum();
-        let blobs = result.into_iter().map(|(blob, _)| blob).collect::<Vec<_>>();
-        let collection = Collection::new(blobs, total_blobs_size)?;
+        let total_blobs_size = result.iter().map(|(_, size, _)| *size).sum();
+        let mut blobs = Vec::with_capacity(result.len());
+        let mut metadata = BTreeMap::new();
+        for (blob, _, meta) in result {
+            if let Some(meta) = meta {
+                metadata.insert(blob.name.clone(), meta);
+            }
+            blobs.push(blob);
+        }
+        let collection = Collection::new(blobs, total_blobs_size)?.with_metadata(metadata)?;
         let data = collection.to_bytes()?;
         let hash = self.inner.db.import_bytes(data.into()).await?;
         progress.send(ProvideProgress::AllDone { hash }).await?;
@@ -1331,6 +2574,322 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
         anyhow::bail!("collections not supported");
     }
 
+    fn fetch_url(self, msg: FetchUrlRequest) -> impl Stream<Item = ProvideProgress> {
+        let (tx, rx) = flume::bounded(32);
+        let tx2 = tx.clone();
+        self.rt().local_pool().spawn_pinned(|| async move {
+            if let Err(e) = self.fetch_url0(msg, tx).await {
+                tx2.send_async(ProvideProgress::Abort(e.into())).await.ok();
+            }
+        });
+        rx.into_stream()
+    }
+
+    async fn fetch_url0(
+        self,
+        msg: FetchUrlRequest,
+        progress: flume::Sender<ProvideProgress>,
+    ) -> anyhow::Result<()> {
+        use iroh_bytes::baomap::ImportProgress;
+
+        let progress = FlumeProgressSender::new(progress);
+        let name = msg.url.to_string();
+        let import_progress = progress.clone().with_filter_map(move |x| match x {
+            ImportProgress::Size { id, size } => Some(ProvideProgress::Found {
+                id,
+                name: name.clone(),
+                size,
+            }),
+            ImportProgress::CopyProgress { id, offset } => {
+                Some(ProvideProgress::Progress { id, offset })
+            }
+            ImportProgress::OutboardProgress { id, offset } => {
+                Some(ProvideProgress::Progress { id, offset })
+            }
+            ImportProgress::OutboardDone { hash, id } => Some(ProvideProgress::Done { hash, id }),
+            ImportProgress::Deduplicated { id, size } => {
+                Some(ProvideProgress::Deduplicated { id, size })
+            }
+            _ => None,
+        });
+        let opts = crate::fetch::FetchOptions {
+            expected_hash: msg.hash,
+            temp_dir: std::env::temp_dir().join("iroh-fetch"),
+        };
+        let (hash, _size) =
+            crate::fetch::fetch_url(&self.inner.db, msg.url, opts, import_progress).await?;
+        progress.send(ProvideProgress::AllDone { hash }).await?;
+        Ok(())
+    }
+
+    async fn import_car(self, msg: ImportCarRequest) -> RpcResult<ImportCarResponse> {
+        let local = self.inner.rt.local_pool().clone();
+        let res = local
+            .spawn_pinned(|| self.import_car0(msg))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|x| x);
+        Ok(res?)
+    }
+
+    #[cfg(feature = "iroh-collection")]
+    async fn import_car0(self, msg: ImportCarRequest) -> anyhow::Result<ImportCarResponse> {
+        anyhow::ensure!(msg.path.is_absolute(), "path must be absolute");
+        let file = tokio::fs::File::open(&msg.path).await?;
+        let reader = tokio::io::BufReader::new(file);
+        let temp_dir = std::env::temp_dir().join("iroh-car");
+        let (hash, collection) = crate::car::import_car(&self.inner.db, reader, &temp_dir).await?;
+        self.inner
+            .callbacks
+            .send(Event::ByteProvide(
+                iroh_bytes::provider::Event::CollectionAdded { hash },
+            ))
+            .await;
+        Ok(ImportCarResponse {
+            hash,
+            total_blobs_count: collection.total_entries(),
+            total_blobs_size: collection.total_blobs_size(),
+        })
+    }
+
+    #[cfg(not(feature = "iroh-collection"))]
+    async fn import_car0(self, _msg: ImportCarRequest) -> anyhow::Result<ImportCarResponse> {
+        anyhow::bail!("collections not supported");
+    }
+
+    async fn export_car(self, msg: ExportCarRequest) -> RpcResult<ExportCarResponse> {
+        let local = self.inner.rt.local_pool().clone();
+        let res = local
+            .spawn_pinned(|| self.export_car0(msg))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|x| x);
+        Ok(res?)
+    }
+
+    #[cfg(feature = "iroh-collection")]
+    async fn export_car0(self, msg: ExportCarRequest) -> anyhow::Result<ExportCarResponse> {
+        anyhow::ensure!(msg.path.is_absolute(), "path must be absolute");
+        if let Some(parent) = msg.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = tokio::fs::File::create(&msg.path).await?;
+        let writer = tokio::io::BufWriter::new(file);
+        let blocks = crate::car::export_car(&self.inner.db, msg.hash, writer).await?;
+        Ok(ExportCarResponse { blocks })
+    }
+
+    #[cfg(not(feature = "iroh-collection"))]
+    async fn export_car0(self, _msg: ExportCarRequest) -> anyhow::Result<ExportCarResponse> {
+        anyhow::bail!("collections not supported");
+    }
+
+    async fn pin_add(self, msg: PinAddRequest) -> RpcResult<PinAddResponse> {
+        let name = msg.name.unwrap_or_else(|| msg.hash.to_string());
+        let pin = iroh_bytes::baomap::Pin {
+            hash: msg.hash,
+            recursive: msg.recursive,
+            expires: msg.ttl.map(|ttl| SystemTime::now() + ttl),
+        };
+        self.inner.db.set_pin(name.clone(), pin).await?;
+        Ok(PinAddResponse { name })
+    }
+
+    async fn pin_remove(self, msg: PinRemoveRequest) -> RpcResult<PinRemoveResponse> {
+        let pin = self
+            .inner
+            .db
+            .remove_pin(msg.name.clone())
+            .await?
+            .with_context(|| format!("no pin named {}", msg.name))?;
+        Ok(PinRemoveResponse { pin })
+    }
+
+    async fn delete_blob(self, msg: DeleteBlobRequest) -> RpcResult<BlobUpdateResponse> {
+        // the store only knows about the roots of pins, children of collections are checked here
+        if let Some(name) = self.pinned_by(msg.hash).await {
+            return Err(anyhow::anyhow!("{} is protected by the pin {}", msg.hash, name).into());
+        }
+        self.inner.db.delete(msg.hash).await?;
+        Ok(BlobUpdateResponse { hash: msg.hash })
+    }
+
+    /// The name of an unexpired recursive pin of a collection that contains `hash`.
+    ///
+    /// Collections that are incomplete or can not be parsed are treated as empty.
+    async fn pinned_by(&self, hash: Hash) -> Option<String> {
+        let now = SystemTime::now();
+        let pins = self.inner.db.pins();
+        for (name, pin) in pins.filter(|(_, pin)| pin.recursive && !pin.is_expired(now)) {
+            let Some(entry) = self.inner.db.get(&pin.hash) else {
+                continue;
+            };
+            if !entry.is_complete() {
+                continue;
+            }
+            let cp = self.collection_parser.clone();
+            let contains = self
+                .rt()
+                .local_pool()
+                .spawn_pinned(move || async move {
+                    let reader = entry.data_reader().await.ok()?;
+                    let (mut collection, _stats) = cp.parse(0, reader).await.ok()?;
+                    while let Some(child) = collection.next().await.ok()? {
+                        if child == hash {
+                            return Some(());
+                        }
+                    }
+                    None
+                })
+                .await
+                .ok()
+                .flatten();
+            if contains.is_some() {
+                return Some(name);
+            }
+        }
+        None
+    }
+
+    async fn restore_blob(self, msg: RestoreBlobRequest) -> RpcResult<BlobUpdateResponse> {
+        let restored = self.inner.db.restore(msg.hash).await?;
+        if !restored {
+            return Err(anyhow::anyhow!("{} is not in the trash", msg.hash).into());
+        }
+        Ok(BlobUpdateResponse { hash: msg.hash })
+    }
+
+    async fn export_partial(self, msg: ExportPartialRequest) -> RpcResult<()> {
+        self.inner.db.export_partial(msg.hash, msg.path).await?;
+        Ok(())
+    }
+
+    async fn import_partial(self, msg: ImportPartialRequest) -> RpcResult<ImportPartialResponse> {
+        let hash = self.inner.db.import_partial(msg.path).await?;
+        Ok(ImportPartialResponse { hash })
+    }
+
+    fn blob_read_at(
+        self,
+        msg: BlobReadAtRequest,
+    ) -> impl Stream<Item = RpcResult<BlobReadAtResponse>> + Send + 'static {
+        let (tx, rx) = flume::bounded(32);
+        let tx2 = tx.clone();
+        self.rt().local_pool().spawn_pinned(|| async move {
+            if let Err(e) = self.blob_read_at0(msg, tx).await {
+                tx2.send_async(Err(e.into())).await.ok();
+            }
+        });
+        rx.into_stream()
+    }
+
+    async fn blob_read_at0(
+        self,
+        msg: BlobReadAtRequest,
+        tx: flume::Sender<RpcResult<BlobReadAtResponse>>,
+    ) -> anyhow::Result<()> {
+        /// Size of the data messages of a blob read
+        const MAX_CHUNK_SIZE: u64 = 1024 * 64;
+
+        let entry = match self.inner.db.get(&msg.hash) {
+            Some(entry) if entry.is_complete() => entry,
+            _ => anyhow::bail!("blob {} not found", msg.hash),
+        };
+        let size = entry.size();
+        tx.send_async(Ok(BlobReadAtResponse::Entry { size }))
+            .await?;
+        let mut reader = entry.data_reader().await?;
+        let start = msg.offset.min(size);
+        let end = match msg.len {
+            Some(len) => start.saturating_add(len).min(size),
+            None => size,
+        };
+        let mut offset = start;
+        while offset < end {
+            let len = (end - offset).min(MAX_CHUNK_SIZE) as usize;
+            let chunk = reader.read_at(offset, len).await?;
+            anyhow::ensure!(!chunk.is_empty(), "unexpected end of blob {}", msg.hash);
+            offset += chunk.len() as u64;
+            tx.send_async(Ok(BlobReadAtResponse::Data { chunk }))
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn blob_diff(
+        self,
+        msg: BlobDiffRequest,
+    ) -> impl Stream<Item = RpcResult<BlobDiffResponse>> + Send + 'static {
+        let (tx, rx) = flume::bounded(32);
+        let tx2 = tx.clone();
+        self.rt().local_pool().spawn_pinned(|| async move {
+            if let Err(e) = self.blob_diff0(msg, tx).await {
+                tx2.send_async(Err(e.into())).await.ok();
+            }
+        });
+        rx.into_stream()
+    }
+
+    #[cfg(feature = "iroh-collection")]
+    async fn blob_diff0(
+        self,
+        msg: BlobDiffRequest,
+        tx: flume::Sender<RpcResult<BlobDiffResponse>>,
+    ) -> anyhow::Result<()> {
+        use crate::collection::Collection;
+        use iroh_io::AsyncSliceReaderExt;
+
+        let db = &self.inner.db;
+        let mut collections = Vec::with_capacity(2);
+        for hash in [msg.from, msg.to] {
+            let entry = match db.get(&hash) {
+                Some(entry) if entry.is_complete() => entry,
+                _ => anyhow::bail!("collection {hash} not found"),
+            };
+            let bytes: Bytes = entry.data_reader().await?.read_to_end().await?;
+            let collection = Collection::from_bytes(&bytes)
+                .with_context(|| format!("{hash} is not a collection"))?;
+            collections.push(collection);
+        }
+        let size = |hash: Option<Hash>| match hash.and_then(|hash| db.get(&hash)) {
+            Some(entry) if entry.is_complete() => Some(entry.size()),
+            _ => None,
+        };
+        for change in collections[0].diff(&collections[1]) {
+            let (from, to) = (change.from(), change.to());
+            tx.send_async(Ok(BlobDiffResponse {
+                name: change.name().to_string(),
+                from,
+                to,
+                from_size: size(from),
+                to_size: size(to),
+            }))
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "iroh-collection"))]
+    async fn blob_diff0(
+        self,
+        _msg: BlobDiffRequest,
+        _tx: flume::Sender<RpcResult<BlobDiffResponse>>,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("collections not supported");
+    }
+
+    fn pin_list(
+        self,
+        _msg: PinListRequest,
+    ) -> impl Stream<Item = PinListResponse> + Send + 'static {
+        let now = SystemTime::now();
+        futures::stream::iter(self.inner.db.pins()).map(move |(name, pin)| PinListResponse {
+            expired: pin.is_expired(now),
+            name,
+            pin,
+        })
+    }
+
     async fn version(self, _: VersionRequest) -> VersionResponse {
         VersionResponse {
             version: env!("CARGO_PKG_VERSION").to_string(),
@@ -1356,6 +2915,135 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
                 .unwrap_or_default(),
         }
     }
+    async fn peer_scores(self, _: PeerScoresRequest) -> PeerScoresResponse {
+        PeerScoresResponse {
+            scores: self.inner.peer_scores.snapshot(),
+        }
+    }
+    async fn node_status(self, _: NodeStatusRequest) -> NodeStatusResponse {
+        let endpoint = &self.inner.endpoint;
+        let nat = endpoint
+            .net_info()
+            .await
+            .ok()
+            .flatten()
+            .map(|info| NatSummary {
+                working_udp: info.working_udp,
+                working_ipv6: info.working_ipv6,
+                mapping_varies_by_dest_ip: info.mapping_varies_by_dest_ip,
+                have_port_map: info.have_port_map,
+                preferred_derp: info.preferred_derp,
+            });
+        let peers = self.inner.peers().await.unwrap_or_default();
+        let db = &self.inner.db;
+        let (mut blobs, mut blobs_size) = (0, 0);
+        for hash in db.blobs() {
+            if let Some(entry) = db.get(&hash) {
+                blobs += 1;
+                blobs_size += entry.size();
+            }
+        }
+        NodeStatusResponse {
+            peer_id: Box::new(self.inner.keypair.public().into()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime: self.inner.started.elapsed(),
+            listen_addrs: self
+                .inner
+                .local_endpoint_addresses()
+                .await
+                .unwrap_or_default(),
+            derp_region: endpoint.my_derp().await,
+            nat,
+            peers,
+            blobs,
+            blobs_size,
+            partial_blobs: db.partial_blobs().count() as u64,
+        }
+    }
+    async fn paths(self, _: PathsRequest) -> PathsResponse {
+        PathsResponse {
+            paths: self.inner.paths.clone(),
+        }
+    }
+    async fn mirror_status(self, _: MirrorStatusRequest) -> MirrorStatusResponse {
+        MirrorStatusResponse {
+            mirrors: self.inner.mirrors.iter().map(|m| m.status()).collect(),
+        }
+    }
+
+    async fn cluster_replicas(self, msg: ClusterReplicasRequest) -> ClusterReplicasResponse {
+        let replicas = match &self.inner.cluster {
+            Some(cluster) => cluster
+                .replicas(&msg.hash)
+                .into_iter()
+                .map(|member| member.peer)
+                .collect(),
+            None => Vec::new(),
+        };
+        ClusterReplicasResponse { replicas }
+    }
+
+    async fn peers_list(self, _: PeersListRequest) -> PeersListResponse {
+        PeersListResponse {
+            peers: self.inner.peers().await.unwrap_or_default(),
+        }
+    }
+    async fn hole_punch_stats(self, _: HolePunchStatsRequest) -> HolePunchStatsResponse {
+        HolePunchStatsResponse {
+            stats: self.inner.endpoint.hole_punch_stats(),
+        }
+    }
+    async fn relay_usage(self, _: RelayUsageRequest) -> RelayUsageResponse {
+        RelayUsageResponse {
+            usage: self.inner.endpoint.relay_usage(),
+        }
+    }
+    async fn peer_ping(self, msg: PeerPingRequest) -> RpcResult<PeerPingResponse> {
+        let endpoint = &self.inner.endpoint;
+        if msg.quic {
+            let conn = endpoint
+                .connect(msg.peer, &iroh_bytes::protocol::ALPN, None, &[])
+                .await?;
+            let latency = conn.rtt();
+            conn.close(0u8.into(), b"ping");
+            let conn_type = endpoint
+                .connection_info(msg.peer)
+                .await?
+                .map_or(ConnectionType::None, |info| info.conn_type);
+            return Ok(PeerPingResponse { latency, conn_type });
+        }
+        let res = endpoint.ping(msg.peer).await?;
+        let conn_type = match (res.endpoint, res.derp_region_id) {
+            (Some(addr), _) => ConnectionType::Direct(addr),
+            (None, Some(region)) => ConnectionType::Relay(region),
+            (None, None) => ConnectionType::None,
+        };
+        Ok(PeerPingResponse {
+            latency: Duration::from_secs_f64(res.latency_seconds.unwrap_or_default()),
+            conn_type,
+        })
+    }
+    async fn peer_add(self, msg: PeerAddRequest) -> RpcResult<()> {
+        self.inner
+            .endpoint
+            .add_known_addrs(msg.peer, msg.derp_region, &msg.addrs)
+            .await?;
+        Ok(())
+    }
+    async fn peer_forget(self, msg: PeerForgetRequest) -> RpcResult<()> {
+        self.inner.endpoint.forget_peer(msg.peer).await?;
+        Ok(())
+    }
+    async fn set_log_filter(self, msg: SetLogFilterRequest) -> RpcResult<()> {
+        let handler = self
+            .inner
+            .log_filter_handler
+            .as_ref()
+            .context("this node does not support changing its log filter")?;
+        handler.set_filter(&msg.filter)?;
+        tracing::info!("log filter set to {}", msg.filter);
+        Ok(())
+    }
     async fn shutdown(self, request: ShutdownRequest) {
         if request.force {
             tracing::info!("hard shutdown requested");
@@ -1366,6 +3054,18 @@ impl<D: Store, C: CollectionParser> RpcHandler<D, C> {
             self.inner.cancel_token.cancel();
         }
     }
+    fn subscribe(self, _: SubscribeRequest) -> impl Stream<Item = NodeEvent> {
+        let events = self.inner.events.subscribe();
+        futures::stream::unfold(events, |mut events| async move {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => NodeEvent::Lagged { missed },
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            Some((event, events))
+        })
+    }
+
     fn watch(self, _: WatchRequest) -> impl Stream<Item = WatchResponse> {
         futures::stream::unfold((), |()| async move {
             tokio::time::sleep(HEALTH_POLL_WAIT).await;
@@ -1386,7 +3086,9 @@ fn handle_rpc_request<D: Store, E: ServiceEndpoint<ProviderService>, C: Collecti
     rt: &runtime::Handle,
 ) {
     let handler = handler.clone();
-    rt.main().spawn(async move {
+    let request_id = RequestId::generate();
+    let span = tracing::info_span!("rpc", %request_id);
+    let handling = async move {
         use ProviderRequest::*;
         tracing::info!(
             "handling rpc request: {:?} {}",
@@ -1410,18 +3112,64 @@ fn handle_rpc_request<D: Store, E: ServiceEndpoint<ProviderService>, C: Collecti
                 chan.server_streaming(msg, handler, RpcHandler::provide)
                     .await
             }
-            Share(msg) => chan.server_streaming(msg, handler, RpcHandler::share).await,
+            Share(msg) => {
+                chan.server_streaming(msg, handler, move |handler, msg| {
+                    handler.share(msg, request_id)
+                })
+                .await
+            }
             Watch(msg) => chan.server_streaming(msg, handler, RpcHandler::watch).await,
+            Subscribe(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::subscribe)
+                    .await
+            }
             Version(msg) => chan.rpc(msg, handler, RpcHandler::version).await,
             Id(msg) => chan.rpc(msg, handler, RpcHandler::id).await,
             Addrs(msg) => chan.rpc(msg, handler, RpcHandler::addrs).await,
+            PeerScores(msg) => chan.rpc(msg, handler, RpcHandler::peer_scores).await,
+            NodeStatus(msg) => chan.rpc(msg, handler, RpcHandler::node_status).await,
+            MirrorStatus(msg) => chan.rpc(msg, handler, RpcHandler::mirror_status).await,
+            Paths(msg) => chan.rpc(msg, handler, RpcHandler::paths).await,
+            ClusterReplicas(msg) => chan.rpc(msg, handler, RpcHandler::cluster_replicas).await,
+            PeersList(msg) => chan.rpc(msg, handler, RpcHandler::peers_list).await,
+            HolePunchStats(msg) => chan.rpc(msg, handler, RpcHandler::hole_punch_stats).await,
+            RelayUsage(msg) => chan.rpc(msg, handler, RpcHandler::relay_usage).await,
+            PeerPing(msg) => chan.rpc(msg, handler, RpcHandler::peer_ping).await,
+            PeerAdd(msg) => chan.rpc(msg, handler, RpcHandler::peer_add).await,
+            PeerForget(msg) => chan.rpc(msg, handler, RpcHandler::peer_forget).await,
+            SetLogFilter(msg) => chan.rpc(msg, handler, RpcHandler::set_log_filter).await,
             Shutdown(msg) => chan.rpc(msg, handler, RpcHandler::shutdown).await,
             Validate(msg) => {
                 chan.server_streaming(msg, handler, RpcHandler::validate)
                     .await
             }
+            ImportCar(msg) => chan.rpc(msg, handler, RpcHandler::import_car).await,
+            ExportCar(msg) => chan.rpc(msg, handler, RpcHandler::export_car).await,
+            FetchUrl(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::fetch_url)
+                    .await
+            }
+            PinAdd(msg) => chan.rpc(msg, handler, RpcHandler::pin_add).await,
+            PinRemove(msg) => chan.rpc(msg, handler, RpcHandler::pin_remove).await,
+            PinList(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::pin_list)
+                    .await
+            }
+            DeleteBlob(msg) => chan.rpc(msg, handler, RpcHandler::delete_blob).await,
+            RestoreBlob(msg) => chan.rpc(msg, handler, RpcHandler::restore_blob).await,
+            ExportPartial(msg) => chan.rpc(msg, handler, RpcHandler::export_partial).await,
+            ImportPartial(msg) => chan.rpc(msg, handler, RpcHandler::import_partial).await,
+            BlobReadAt(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::blob_read_at)
+                    .await
+            }
+            BlobDiff(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::blob_diff)
+                    .await
+            }
         }
-    });
+    };
+    rt.main().spawn(handling.instrument(span));
 }
 
 #[derive(Debug, Clone)]
@@ -1454,7 +3202,7 @@ pub fn make_server_config(
     max_connections: u32,
     alpn_protocols: Vec<Vec<u8>>,
 ) -> anyhow::Result<quinn::ServerConfig> {
-    let tls_server_config = tls::make_server_config(keypair, alpn_protocols, false)?;
+    let tls_server_config = tls::make_server_config(keypair, None, alpn_protocols, false)?;
     let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
     let mut transport_config = quinn::TransportConfig::default();
     transport_config
@@ -1556,6 +3304,95 @@ mod tests {
         assert!(!ticket.addrs().is_empty());
     }
 
+    #[tokio::test]
+    async fn test_blob_read_at() -> Result<()> {
+        let (db, hashes) = crate::baomap::readonly_mem::Store::new([("test", b"hello")]);
+        let hash: Hash = hashes["test"].into();
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&test_runtime())
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        let read = |offset, len| {
+            let controller = node.controller();
+            async move {
+                let mut stream = controller
+                    .server_streaming(BlobReadAtRequest { hash, offset, len })
+                    .await?;
+                let mut size = None;
+                let mut data = Vec::new();
+                while let Some(item) = stream.next().await {
+                    match item?? {
+                        BlobReadAtResponse::Entry { size: s } => size = Some(s),
+                        BlobReadAtResponse::Data { chunk } => data.extend_from_slice(&chunk),
+                    }
+                }
+                anyhow::Ok((size, data))
+            }
+        };
+        assert_eq!(read(1, Some(3)).await?, (Some(5), b"ell".to_vec()));
+        assert_eq!(read(2, None).await?, (Some(5), b"llo".to_vec()));
+        assert_eq!(read(3, Some(100)).await?, (Some(5), b"lo".to_vec()));
+        assert_eq!(read(10, None).await?, (Some(5), Vec::new()));
+
+        let missing = Hash::new(b"missing");
+        let mut stream = node
+            .controller()
+            .server_streaming(BlobReadAtRequest {
+                hash: missing,
+                offset: 0,
+                len: None,
+            })
+            .await?;
+        assert!(stream.next().await.unwrap()?.is_err());
+        Ok(())
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingLogFilter(std::sync::Mutex<Vec<String>>);
+
+    impl LogFilterHandler for RecordingLogFilter {
+        fn set_filter(&self, filter: &str) -> anyhow::Result<()> {
+            if filter.is_empty() {
+                bail!("empty filter");
+            }
+            self.0.lock().unwrap().push(filter.to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_log_filter() -> Result<()> {
+        let set_filter = |filter: &str| SetLogFilterRequest {
+            filter: filter.to_string(),
+        };
+        let (db, _) = crate::baomap::readonly_mem::Store::new([("test", b"hello")]);
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&test_runtime())
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+        assert!(node.controller().rpc(set_filter("debug")).await?.is_err());
+
+        let handler = Arc::new(RecordingLogFilter::default());
+        let (db, _) = crate::baomap::readonly_mem::Store::new([("test", b"hello")]);
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&test_runtime())
+            .log_filter_handler(handler.clone())
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+        let controller = node.controller();
+        controller.rpc(set_filter("iroh=debug")).await??;
+        assert!(controller.rpc(set_filter("")).await?.is_err());
+        assert_eq!(*handler.0.lock().unwrap(), vec!["iroh=debug".to_string()]);
+        Ok(())
+    }
+
     #[cfg(feature = "mem-db")]
     #[tokio::test]
     async fn test_node_add_collection_event() -> Result<()> {
@@ -1589,6 +3426,8 @@ mod tests {
                 .server_streaming(ProvideRequest {
                     path: Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md"),
                     in_place: false,
+                    metadata: false,
+                    filter: Default::default(),
                 })
                 .await?;
 
@@ -1614,4 +3453,107 @@ mod tests {
 
         Ok(())
     }
+
+    #[cfg(feature = "mem-db")]
+    #[tokio::test]
+    async fn test_node_subscribe() -> Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let db = crate::baomap::mem::Store::new(rt);
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&test_runtime())
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        let mut events = node.controller().server_streaming(SubscribeRequest).await?;
+        let mut progress = node
+            .controller()
+            .server_streaming(ProvideRequest {
+                path: Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md"),
+                in_place: false,
+                metadata: false,
+                filter: Default::default(),
+            })
+            .await?;
+        let mut got_hash = None;
+        while let Some(item) = progress.next().await {
+            if let ProvideProgress::AllDone { hash } = item? {
+                got_hash = Some(hash);
+            }
+        }
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.next())
+            .await
+            .context("timeout")?
+            .context("stream ended")??;
+        assert_eq!(
+            Some(event),
+            got_hash.map(|hash| NodeEvent::BlobAdded { hash })
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "mem-db")]
+    #[tokio::test]
+    async fn test_mirror() -> Result<()> {
+        let rt = runtime::Handle::from_currrent(1)?;
+        let source_db = crate::baomap::mem::Store::new(rt.clone());
+        let before = source_db
+            .import_bytes(Bytes::from_static(b"before"))
+            .await?;
+        let source = Node::builder(source_db.clone())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&test_runtime())
+            .spawn()
+            .await?;
+        let _source_guard = source.cancel_token().drop_guard();
+
+        let config = MirrorConfig {
+            peer: source.peer_id(),
+            addrs: source.local_endpoint_addresses().await?,
+            derp_region: None,
+            token: None,
+            prefix: None,
+        };
+        let mirror_db = crate::baomap::mem::Store::new(rt);
+        let mirror = Node::builder(mirror_db.clone())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .runtime(&test_runtime())
+            .mirror(config.clone())
+            .spawn()
+            .await?;
+        let _mirror_guard = mirror.cancel_token().drop_guard();
+
+        // unpinned content is mirrored as well, and pins are mirrored as they change
+        let after = source_db.import_bytes(Bytes::from_static(b"after")).await?;
+        let pin = iroh_bytes::baomap::Pin {
+            hash: after,
+            recursive: false,
+            expires: None,
+        };
+        source_db.set_pin("docs/after".to_string(), pin).await?;
+
+        let local_name = config.local_pin_name("docs/after");
+        let complete = |hash| mirror_db.get(&hash).map_or(false, |e| e.is_complete());
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let pinned = mirror_db
+                    .pins()
+                    .any(|(name, pin)| name == local_name && pin.hash == after);
+                if complete(before) && complete(after) && pinned {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .context("mirror did not catch up")?;
+
+        let status = mirror.controller().rpc(MirrorStatusRequest).await?;
+        assert_eq!(status.mirrors.len(), 1);
+        assert!(status.mirrors[0].connected);
+        assert_eq!(status.mirrors[0].failed, 0);
+        Ok(())
+    }
 }
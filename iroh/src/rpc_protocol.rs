@@ -7,19 +7,45 @@
 //! response, while others like provide have a stream of responses.
 //!
 //! Note that this is subject to change. The RPC protocol is not yet stable.
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
+use bytes::Bytes;
 use derive_more::{From, TryInto};
-use iroh_bytes::{protocol::RequestToken, provider::ShareProgress, Hash};
-use iroh_net::tls::PeerId;
+use iroh_bytes::{
+    keyed::HashDomain,
+    protocol::RequestToken,
+    provider::ShareProgress,
+    util::{RequestId, RpcResult},
+    Hash,
+};
+use iroh_net::{
+    derp::PeerUsage,
+    key::node::PublicKey,
+    magicsock::{ConnectionType, HolePunchStats},
+    tls::PeerId,
+};
 
 use quic_rpc::{
     message::{Msg, RpcMsg, ServerStreaming, ServerStreamingMsg},
     Service,
 };
 use serde::{Deserialize, Serialize};
+use url::Url;
 
-pub use iroh_bytes::{baomap::ValidateProgress, provider::ProvideProgress};
+use crate::dial::ProviderAddr;
+use crate::util::{
+    checksum::ChecksumAlgorithm,
+    download_queue::{DownloadPriority, Persist},
+    fs::ImportFilter,
+    limits::DownloadLimits,
+    peer_scores::PeerScore,
+    retry::RetryPolicy,
+};
+
+pub use iroh_bytes::{
+    baomap::{Pin, ValidateProgress},
+    provider::ProvideProgress,
+};
 
 /// A request to the node to provide the data at the given path
 ///
@@ -35,6 +61,11 @@ pub struct ProvideRequest {
     /// True if the provider can assume that the data will not change, so it
     /// can be shared in place.
     pub in_place: bool,
+    /// True to record symlinks, the executable bit and modification times in the
+    /// collection, so they are restored on export.
+    pub metadata: bool,
+    /// Which files to add if the path is a directory.
+    pub filter: ImportFilter,
 }
 
 impl Msg<ProviderService> for ProvideRequest {
@@ -60,9 +91,15 @@ pub struct ShareRequest {
     /// This optional field contains a request token that can be used to authorize
     /// the download request.
     pub token: Option<RequestToken>,
+    /// Whether the data is requested by its hash or by a name in the keyed namespace of
+    /// the providers, see [`iroh_bytes::keyed`].
+    pub domain: HashDomain,
     /// This optional field contains the derp region to use for contacting the peer
     /// over the DERP protocol.
     pub derp_region: Option<u16>,
+    /// Other providers of the same data. They are dialed in parallel with the peer,
+    /// and the data is downloaded from the first one that can be reached.
+    pub alternatives: Vec<ProviderAddr>,
     /// This optional field contains the path to store the data to. If it is not
     /// set, the data is dumped to stdout.
     pub out: Option<String>,
@@ -75,6 +112,30 @@ pub struct ShareRequest {
     ///
     /// This flag is only relevant if the out path is set.
     pub in_place: bool,
+    /// Checksum manifests to write next to the exported data, e.g. `SHA256SUMS`.
+    ///
+    /// Computing checksums requires copying the data, so this overrides `in_place`.
+    /// This field is only relevant if the out path is set.
+    pub checksums: Vec<ChecksumAlgorithm>,
+    /// Whether to apply the file system metadata of a collection to the exported files.
+    ///
+    /// The metadata comes from the provider, so this is off by default. Symlinks that
+    /// point outside of the out path are refused. This field is only relevant if the out
+    /// path is set.
+    pub metadata: bool,
+    /// Whether and when to retry the download if it fails.
+    pub retry: RetryPolicy,
+    /// Limits on the size of the download, checked before data is written.
+    #[serde(default)]
+    pub limits: DownloadLimits,
+    /// The priority of the download, relative to the other downloads of the node.
+    #[serde(default)]
+    pub priority: DownloadPriority,
+}
+
+/// Share requests are persisted while their download is pending.
+impl Persist for ShareRequest {
+    const VERSION: u32 = 1;
 }
 
 impl Msg<ProviderService> for ShareRequest {
@@ -100,6 +161,294 @@ impl ServerStreamingMsg<ProviderService> for ValidateRequest {
     type Response = ValidateProgress;
 }
 
+/// A request to pin a blob, protecting it from garbage collection
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinAddRequest {
+    /// The hash to pin
+    pub hash: Hash,
+    /// The name of the pin, the hash if `None`
+    ///
+    /// An existing pin with the same name is replaced.
+    pub name: Option<String>,
+    /// Also protect all children of the collection
+    pub recursive: bool,
+    /// How long the pin is valid, forever if `None`
+    pub ttl: Option<Duration>,
+}
+
+impl RpcMsg<ProviderService> for PinAddRequest {
+    type Response = RpcResult<PinAddResponse>;
+}
+
+/// The response to a pin add request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinAddResponse {
+    /// The name of the pin
+    pub name: String,
+}
+
+/// A request to remove a pin
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinRemoveRequest {
+    /// The name of the pin
+    pub name: String,
+}
+
+impl RpcMsg<ProviderService> for PinRemoveRequest {
+    type Response = RpcResult<PinRemoveResponse>;
+}
+
+/// The response to a pin remove request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinRemoveResponse {
+    /// The removed pin
+    pub pin: Pin,
+}
+
+/// List all pins
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinListRequest;
+
+/// A response to a pin list request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinListResponse {
+    /// The name of the pin
+    pub name: String,
+    /// The pin
+    pub pin: Pin,
+    /// Whether the pin has expired
+    pub expired: bool,
+}
+
+impl Msg<ProviderService> for PinListRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for PinListRequest {
+    type Response = PinListResponse;
+}
+
+/// A request to delete a blob from the store of the node
+///
+/// Stores with a trash keep the blob for a while, see [`RestoreBlobRequest`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteBlobRequest {
+    /// The hash of the blob
+    pub hash: Hash,
+}
+
+impl RpcMsg<ProviderService> for DeleteBlobRequest {
+    type Response = RpcResult<BlobUpdateResponse>;
+}
+
+/// A request to restore a deleted blob from the trash of the store
+///
+/// Fails if the blob is not in the trash, e.g. because it was deleted too long ago.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreBlobRequest {
+    /// The hash of the blob
+    pub hash: Hash,
+}
+
+impl RpcMsg<ProviderService> for RestoreBlobRequest {
+    type Response = RpcResult<BlobUpdateResponse>;
+}
+
+/// The response to a delete or restore blob request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlobUpdateResponse {
+    /// The hash of the deleted or restored blob
+    pub hash: Hash,
+}
+
+/// A request to export a partially downloaded blob as a sparse file
+///
+/// A sidecar recording the verified ranges is written next to the file, see
+/// `crate::baomap::sparse`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportPartialRequest {
+    /// The hash of the blob
+    pub hash: Hash,
+    /// The path to write the sparse file to.
+    ///
+    /// This should be an absolute path valid for the file system on which
+    /// the node runs.
+    pub path: PathBuf,
+}
+
+impl RpcMsg<ProviderService> for ExportPartialRequest {
+    type Response = RpcResult<()>;
+}
+
+/// A request to import a sparse file written by [`ExportPartialRequest`], so the
+/// download can be resumed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportPartialRequest {
+    /// The path of the sparse file, its sidecar is expected next to it.
+    ///
+    /// This should be an absolute path valid for the file system on which
+    /// the node runs.
+    pub path: PathBuf,
+}
+
+impl RpcMsg<ProviderService> for ImportPartialRequest {
+    type Response = RpcResult<ImportPartialResponse>;
+}
+
+/// The response to an import partial request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportPartialResponse {
+    /// The hash of the imported blob
+    pub hash: Hash,
+}
+
+/// A request to read a range of a complete blob from the store of the node
+///
+/// Will produce a [`BlobReadAtResponse::Entry`] with the size of the blob, followed by
+/// the data of the range in order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlobReadAtRequest {
+    /// The hash of the blob
+    pub hash: Hash,
+    /// The offset of the first byte to read
+    pub offset: u64,
+    /// The number of bytes to read, up to the end of the blob if not set.
+    ///
+    /// The range is cut off at the end of the blob.
+    pub len: Option<u64>,
+}
+
+impl Msg<ProviderService> for BlobReadAtRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for BlobReadAtRequest {
+    type Response = RpcResult<BlobReadAtResponse>;
+}
+
+/// A response to a blob read at request
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BlobReadAtResponse {
+    /// The blob was found
+    Entry {
+        /// The size of the whole blob
+        size: u64,
+    },
+    /// The next piece of the range
+    Data {
+        /// The bytes
+        chunk: Bytes,
+    },
+}
+
+/// A request to compare two collections in the store of the node
+///
+/// Will produce a [`BlobDiffResponse`] for every entry that differs, ordered by name. Only
+/// the two collection blobs have to be in the store, the sizes of entries whose blobs are
+/// missing are unknown.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlobDiffRequest {
+    /// The hash of the old collection
+    pub from: Hash,
+    /// The hash of the new collection
+    pub to: Hash,
+}
+
+impl Msg<ProviderService> for BlobDiffRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for BlobDiffRequest {
+    type Response = RpcResult<BlobDiffResponse>;
+}
+
+/// An entry that differs between two collections
+///
+/// Added entries have no `from` hash, removed entries no `to` hash.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlobDiffResponse {
+    /// The name of the entry
+    pub name: String,
+    /// The hash of the entry in the old collection
+    pub from: Option<Hash>,
+    /// The hash of the entry in the new collection
+    pub to: Option<Hash>,
+    /// The size of the entry in the old collection, if its blob is complete in the store
+    pub from_size: Option<u64>,
+    /// The size of the entry in the new collection, if its blob is complete in the store
+    pub to_size: Option<u64>,
+}
+
+/// A request to the node to download the content at an url and add it as a blob
+///
+/// Will produce a stream of [`ProvideProgress`] messages, ending with
+/// [`ProvideProgress::AllDone`] containing the hash of the blob.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchUrlRequest {
+    /// The http or https url to download.
+    pub url: Url,
+    /// The expected hash of the content, if known.
+    pub hash: Option<Hash>,
+}
+
+impl Msg<ProviderService> for FetchUrlRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for FetchUrlRequest {
+    type Response = ProvideProgress;
+}
+
+/// A request to the node to import a CAR archive as a collection
+///
+/// See `crate::car` for how the blocks of the archive are mapped to blobs.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportCarRequest {
+    /// The path to the CAR archive.
+    ///
+    /// This should be an absolute path valid for the file system on which
+    /// the node runs.
+    pub path: PathBuf,
+}
+
+impl RpcMsg<ProviderService> for ImportCarRequest {
+    type Response = RpcResult<ImportCarResponse>;
+}
+
+/// The response to an import car request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportCarResponse {
+    /// The hash of the resulting collection
+    pub hash: Hash,
+    /// The number of blobs in the collection
+    pub total_blobs_count: u64,
+    /// The total size of the blobs in the collection
+    pub total_blobs_size: u64,
+}
+
+/// A request to the node to export a collection as a CAR archive
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportCarRequest {
+    /// The hash of the collection to export
+    pub hash: Hash,
+    /// The path to write the CAR archive to.
+    ///
+    /// This should be an absolute path valid for the file system on which
+    /// the node runs.
+    pub path: PathBuf,
+}
+
+impl RpcMsg<ProviderService> for ExportCarRequest {
+    type Response = RpcResult<ExportCarResponse>;
+}
+
+/// The response to an export car request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportCarResponse {
+    /// The number of blocks written, including the collection itself
+    pub blocks: u64,
+}
+
 /// List all blobs, including collections
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ListBlobsRequest;
@@ -198,6 +547,18 @@ impl RpcMsg<ProviderService> for ShutdownRequest {
     type Response = ();
 }
 
+/// A request to replace the log filter of the node, see
+/// `crate::node::Builder::log_filter_handler`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetLogFilterRequest {
+    /// The new filter, in the syntax of `RUST_LOG`
+    pub filter: String,
+}
+
+impl RpcMsg<ProviderService> for SetLogFilterRequest {
+    type Response = RpcResult<()>;
+}
+
 /// A request to get information about the identity of the node
 ///
 /// See [`IdResponse`] for the response.
@@ -216,6 +577,278 @@ impl RpcMsg<ProviderService> for AddrsRequest {
     type Response = AddrsResponse;
 }
 
+/// A request to get the scores of the peers the node downloaded from
+///
+/// See [`PeerScoresResponse`] for the response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerScoresRequest;
+
+impl RpcMsg<ProviderService> for PeerScoresRequest {
+    type Response = PeerScoresResponse;
+}
+
+/// The response to a peer scores request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerScoresResponse {
+    /// The scores of all peers, lowest score first
+    pub scores: Vec<(PeerId, PeerScore)>,
+}
+
+/// A request to get the status of the node
+///
+/// See [`NodeStatusResponse`] for the response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeStatusRequest;
+
+impl RpcMsg<ProviderService> for NodeStatusRequest {
+    type Response = NodeStatusResponse;
+}
+
+/// The response to a node status request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeStatusResponse {
+    /// The peer id of the node
+    pub peer_id: Box<PeerId>,
+    /// The version of the node
+    pub version: String,
+    /// How long the node has been running
+    pub uptime: Duration,
+    /// The addresses of the node
+    pub listen_addrs: Vec<SocketAddr>,
+    /// The home DERP region of the node, if connected to one
+    pub derp_region: Option<u16>,
+    /// Summary of the last NAT report, if one completed
+    pub nat: Option<NatSummary>,
+    /// The peers known to the node
+    pub peers: Vec<PeerStatus>,
+    /// Number of complete blobs in the store
+    pub blobs: u64,
+    /// Total size of the complete blobs in the store
+    pub blobs_size: u64,
+    /// Number of incomplete blobs in the store
+    pub partial_blobs: u64,
+}
+
+/// Summary of the network conditions found by a netcheck
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NatSummary {
+    /// Whether UDP works, `None` if not checked
+    pub working_udp: Option<bool>,
+    /// Whether IPv6 works, `None` if not checked
+    pub working_ipv6: Option<bool>,
+    /// Whether the NAT mapping varies by destination, `None` if not checked
+    pub mapping_varies_by_dest_ip: Option<bool>,
+    /// Whether a port mapping (UPnP, PMP or PCP) is open
+    pub have_port_map: bool,
+    /// The DERP region with the lowest latency, zero if unknown
+    pub preferred_derp: u16,
+}
+
+/// A peer known to the node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerStatus {
+    /// The node key of the peer
+    pub node_key: PublicKey,
+    /// The peer id, if the node dialed the peer
+    pub peer_id: Option<PeerId>,
+    /// The DERP region of the peer, if known
+    pub derp_region: Option<u16>,
+    /// The direct addresses the peer might be reachable on
+    pub addrs: Vec<SocketAddr>,
+    /// The path used to send to the peer
+    pub conn_type: ConnectionType,
+    /// Latency of the path, if known
+    pub latency: Option<Duration>,
+}
+
+impl PeerStatus {
+    /// Whether there is a path to the peer.
+    pub fn is_connected(&self) -> bool {
+        self.conn_type != ConnectionType::None
+    }
+}
+
+/// A request to get the status of the mirrors of other nodes
+///
+/// See [`MirrorStatusResponse`] for the response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MirrorStatusRequest;
+
+impl RpcMsg<ProviderService> for MirrorStatusRequest {
+    type Response = MirrorStatusResponse;
+}
+
+/// The response to a mirror status request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MirrorStatusResponse {
+    /// The status of every configured mirror
+    pub mirrors: Vec<MirrorStatus>,
+}
+
+/// The state of the mirror of a source.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorStatus {
+    /// The peer id of the source.
+    pub peer: PeerId,
+    /// The prefix of the mirrored pins.
+    pub prefix: Option<String>,
+    /// Whether the mirror is subscribed to the source.
+    pub connected: bool,
+    /// Number of pins announced by the source.
+    pub announced: u64,
+    /// Number of pins whose content was mirrored.
+    pub mirrored: u64,
+    /// Number of pins whose content failed to download.
+    pub failed: u64,
+    /// Number of announced pins that are not processed yet.
+    pub pending: u64,
+    /// Time since the oldest pending pin was announced.
+    pub lag: Duration,
+    /// The last error, if any.
+    pub last_error: Option<String>,
+}
+
+/// A request for the directories the node uses
+///
+/// See [`PathsResponse`] for the response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PathsRequest;
+
+impl RpcMsg<ProviderService> for PathsRequest {
+    type Response = PathsResponse;
+}
+
+/// The response to a paths request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PathsResponse {
+    /// The directories of the node, if the application running it set them
+    pub paths: Option<NodePaths>,
+}
+
+/// The directories a node uses, as resolved by the application running it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodePaths {
+    /// The directory containing the configuration files.
+    pub config_dir: PathBuf,
+    /// The data directory, see [`crate::data_dir`].
+    pub data_dir: PathBuf,
+    /// The directory for data that can be recreated.
+    pub cache_dir: PathBuf,
+}
+
+/// A request for the designated replicas of a hash in the cluster of the node
+///
+/// See [`ClusterReplicasResponse`] for the response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClusterReplicasRequest {
+    /// The hash to place
+    pub hash: Hash,
+}
+
+impl RpcMsg<ProviderService> for ClusterReplicasRequest {
+    type Response = ClusterReplicasResponse;
+}
+
+/// The response to a cluster replicas request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClusterReplicasResponse {
+    /// The designated replicas, empty if the node is not a member of a cluster
+    pub replicas: Vec<PeerId>,
+}
+
+/// A request to list the peers known to the node
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersListRequest;
+
+impl RpcMsg<ProviderService> for PeersListRequest {
+    type Response = PeersListResponse;
+}
+
+/// The response to a peers list request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersListResponse {
+    /// The peers known to the node
+    pub peers: Vec<PeerStatus>,
+}
+
+/// A request for the outcomes of upgrading connections to peers to direct paths
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HolePunchStatsRequest;
+
+impl RpcMsg<ProviderService> for HolePunchStatsRequest {
+    type Response = HolePunchStatsResponse;
+}
+
+/// The response to a hole punch stats request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HolePunchStatsResponse {
+    /// The outcomes since the node started, by the NAT types of both sides
+    pub stats: HolePunchStats,
+}
+
+/// A request for the traffic relayed over DERP per peer
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelayUsageRequest;
+
+impl RpcMsg<ProviderService> for RelayUsageRequest {
+    type Response = RelayUsageResponse;
+}
+
+/// The response to a relay usage request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelayUsageResponse {
+    /// The relayed traffic since the node started, the peers with the most bytes first
+    pub usage: Vec<PeerUsage>,
+}
+
+/// A request to ping a peer
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerPingRequest {
+    /// The peer to ping
+    pub peer: PeerId,
+    /// Ping by opening a QUIC connection instead of a disco ping
+    pub quic: bool,
+}
+
+impl RpcMsg<ProviderService> for PeerPingRequest {
+    type Response = RpcResult<PeerPingResponse>;
+}
+
+/// The response to a peer ping request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerPingResponse {
+    /// The round trip time
+    pub latency: Duration,
+    /// The path the ping took
+    pub conn_type: ConnectionType,
+}
+
+/// A request to add dialing hints for a peer
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerAddRequest {
+    /// The peer to add
+    pub peer: PeerId,
+    /// Direct addresses of the peer
+    pub addrs: Vec<SocketAddr>,
+    /// DERP region of the peer
+    pub derp_region: Option<u16>,
+}
+
+impl RpcMsg<ProviderService> for PeerAddRequest {
+    type Response = RpcResult<()>;
+}
+
+/// A request to forget the cached state of a peer
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerForgetRequest {
+    /// The peer to forget
+    pub peer: PeerId,
+}
+
+impl RpcMsg<ProviderService> for PeerForgetRequest {
+    type Response = RpcResult<()>;
+}
+
 /// The response to a watch request
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WatchResponse {
@@ -249,6 +882,111 @@ impl ServerStreamingMsg<ProviderService> for WatchRequest {
     type Response = WatchResponse;
 }
 
+/// A request to subscribe to the events of the node.
+///
+/// The stream of [`NodeEvent`]s starts with the events after the subscription and lasts
+/// until the node shuts down. It is meant for clients reacting to changes in real time,
+/// instead of polling the list requests.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscribeRequest;
+
+impl Msg<ProviderService> for SubscribeRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for SubscribeRequest {
+    type Response = NodeEvent;
+}
+
+/// An event of the node, streamed to subscribers.
+///
+/// Downloads are identified by the request id of the share request that started them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum NodeEvent {
+    /// A blob or collection was added to the store.
+    BlobAdded {
+        /// The hash of the blob or collection.
+        hash: Hash,
+    },
+    /// A peer connected to the node.
+    ClientConnected {
+        /// The id of the connection.
+        connection_id: u64,
+    },
+    /// A request of a peer was served completely.
+    TransferCompleted {
+        /// The id of the connection.
+        connection_id: u64,
+        /// The id of the request.
+        request_id: RequestId,
+    },
+    /// A request of a peer was aborted.
+    TransferAborted {
+        /// The id of the connection.
+        connection_id: u64,
+        /// The id of the request.
+        request_id: RequestId,
+    },
+    /// The handler of a request of a peer panicked.
+    RequestPanicked {
+        /// The id of the connection.
+        connection_id: u64,
+        /// The id of the request.
+        request_id: RequestId,
+        /// The message of the panic.
+        message: String,
+    },
+    /// A download was started.
+    DownloadStarted {
+        /// The id of the download.
+        request_id: RequestId,
+        /// The hash that is downloaded.
+        hash: Hash,
+    },
+    /// A blob of a download was found, from now on referred to via `id`.
+    DownloadBlob {
+        /// The id of the download.
+        request_id: RequestId,
+        /// The id of the blob within the download.
+        id: u64,
+        /// The hash of the blob.
+        hash: Hash,
+        /// The size of the blob.
+        size: u64,
+    },
+    /// Progress downloading blob `id`.
+    DownloadProgress {
+        /// The id of the download.
+        request_id: RequestId,
+        /// The id of the blob within the download.
+        id: u64,
+        /// The offset of the progress, in bytes.
+        offset: u64,
+    },
+    /// A download completed, the data is in the store.
+    DownloadCompleted {
+        /// The id of the download.
+        request_id: RequestId,
+        /// The number of bytes read from the network.
+        bytes_read: u64,
+    },
+    /// A download failed.
+    DownloadFailed {
+        /// The id of the download.
+        request_id: RequestId,
+        /// The reason it failed.
+        error: String,
+    },
+    /// The subscriber did not keep up and missed events.
+    ///
+    /// A client that keeps state derived from the events should refresh it from the list
+    /// requests.
+    Lagged {
+        /// The number of missed events.
+        missed: u64,
+    },
+}
+
 /// The response to a version request
 #[derive(Serialize, Deserialize, Debug)]
 pub struct VersionResponse {
@@ -265,6 +1003,7 @@ pub struct ProviderService;
 #[derive(Debug, Serialize, Deserialize, From, TryInto)]
 pub enum ProviderRequest {
     Watch(WatchRequest),
+    Subscribe(SubscribeRequest),
     Version(VersionRequest),
     ListBlobs(ListBlobsRequest),
     ListIncompleteBlobs(ListIncompleteBlobsRequest),
@@ -273,8 +1012,32 @@ pub enum ProviderRequest {
     Share(ShareRequest),
     Id(IdRequest),
     Addrs(AddrsRequest),
+    PeerScores(PeerScoresRequest),
+    NodeStatus(NodeStatusRequest),
+    MirrorStatus(MirrorStatusRequest),
+    Paths(PathsRequest),
+    ClusterReplicas(ClusterReplicasRequest),
+    PeersList(PeersListRequest),
+    HolePunchStats(HolePunchStatsRequest),
+    RelayUsage(RelayUsageRequest),
+    PeerPing(PeerPingRequest),
+    PeerAdd(PeerAddRequest),
+    PeerForget(PeerForgetRequest),
     Shutdown(ShutdownRequest),
+    SetLogFilter(SetLogFilterRequest),
     Validate(ValidateRequest),
+    ImportCar(ImportCarRequest),
+    ExportCar(ExportCarRequest),
+    FetchUrl(FetchUrlRequest),
+    PinAdd(PinAddRequest),
+    PinRemove(PinRemoveRequest),
+    PinList(PinListRequest),
+    DeleteBlob(DeleteBlobRequest),
+    RestoreBlob(RestoreBlobRequest),
+    ExportPartial(ExportPartialRequest),
+    ImportPartial(ImportPartialRequest),
+    BlobReadAt(BlobReadAtRequest),
+    BlobDiff(BlobDiffRequest),
 }
 
 /// The response enum, listing all possible responses.
@@ -282,6 +1045,7 @@ pub enum ProviderRequest {
 #[derive(Debug, Serialize, Deserialize, From, TryInto)]
 pub enum ProviderResponse {
     Watch(WatchResponse),
+    Subscribe(NodeEvent),
     Version(VersionResponse),
     ListBlobs(ListBlobsResponse),
     ListIncompleteBlobs(ListIncompleteBlobsResponse),
@@ -290,8 +1054,27 @@ pub enum ProviderResponse {
     Share(ShareProgress),
     Id(IdResponse),
     Addrs(AddrsResponse),
+    PeerScores(PeerScoresResponse),
+    NodeStatus(NodeStatusResponse),
+    MirrorStatus(MirrorStatusResponse),
+    Paths(PathsResponse),
+    ClusterReplicas(ClusterReplicasResponse),
+    PeersList(PeersListResponse),
+    HolePunchStats(HolePunchStatsResponse),
+    RelayUsage(RelayUsageResponse),
+    PeerPing(RpcResult<PeerPingResponse>),
+    PeerUpdate(RpcResult<()>),
     Validate(ValidateProgress),
     Shutdown(()),
+    ImportCar(RpcResult<ImportCarResponse>),
+    ExportCar(RpcResult<ExportCarResponse>),
+    PinAdd(RpcResult<PinAddResponse>),
+    PinRemove(RpcResult<PinRemoveResponse>),
+    PinList(PinListResponse),
+    BlobUpdate(RpcResult<BlobUpdateResponse>),
+    ImportPartial(RpcResult<ImportPartialResponse>),
+    BlobReadAt(RpcResult<BlobReadAtResponse>),
+    BlobDiff(RpcResult<BlobDiffResponse>),
 }
 
 impl Service for ProviderService {
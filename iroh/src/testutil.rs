@@ -0,0 +1,385 @@
+//! Utilities to test transfers between a provider and a getter over an unreliable network.
+//!
+//! [`FaultyProxy`] forwards the UDP traffic between a getter and a provider and injects
+//! latency, packet loss, byte corruption and mid-stream resets. The getter dials the
+//! address of the proxy instead of the address of the provider.
+//!
+//! The assertion helpers check what the getter ended up with after a faulty transfer:
+//! whether the data arrived intact, whether an interrupted transfer was resumed instead
+//! of restarted, and whether a failure was classified as expected.
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use bao_tree::io::outboard_size;
+use iroh_bytes::baomap::{Map, MapEntry};
+use iroh_bytes::get::Stats;
+use iroh_bytes::{Hash, IROH_BLOCK_SIZE};
+use iroh_io::AsyncSliceReaderExt;
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::util::retry::ErrorClass;
+
+/// Large enough for any UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// The faults injected by a [`FaultyProxy`].
+///
+/// The default injects no faults.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Delay of every datagram, in both directions.
+    pub latency: Duration,
+    /// Probability to drop a datagram, between 0 and 1.
+    pub loss: f64,
+    /// Probability to flip a byte of a datagram, between 0 and 1.
+    ///
+    /// QUIC authenticates every packet, so the receiver discards corrupted datagrams and
+    /// the sender has to retransmit them.
+    pub corruption: f64,
+    /// Reset a session once this many bytes were forwarded from the provider on it.
+    ///
+    /// All further traffic of the session is dropped, so the getter only notices once
+    /// its connection times out. Dialing again starts a new session, which is forwarded
+    /// until it reaches the limit itself.
+    pub reset_after: Option<u64>,
+}
+
+/// Counts of what a [`FaultyProxy`] did with the datagrams it received.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProxyStats {
+    /// Datagrams forwarded, corrupted or not.
+    pub forwarded: u64,
+    /// Datagrams dropped, because of loss or a reset.
+    pub dropped: u64,
+    /// Datagrams forwarded with a flipped byte.
+    pub corrupted: u64,
+    /// Sessions that were reset.
+    pub resets: u64,
+    /// Bytes forwarded from the provider to the getter.
+    pub provider_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    forwarded: AtomicU64,
+    dropped: AtomicU64,
+    corrupted: AtomicU64,
+    resets: AtomicU64,
+    provider_bytes: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Shared {
+    faults: Mutex<Faults>,
+    counters: Counters,
+}
+
+/// A UDP proxy injecting faults between a getter and a provider.
+///
+/// Every source address the proxy receives from is a session of its own, with its own
+/// socket towards the provider. The proxy stops when dropped.
+#[derive(Debug)]
+pub struct FaultyProxy {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl FaultyProxy {
+    /// Start a proxy forwarding to the provider at `target`, on a local address of the
+    /// same address family.
+    pub async fn spawn(target: SocketAddr, faults: Faults) -> Result<Self> {
+        let socket = UdpSocket::bind(local_addr(&target)).await?;
+        let addr = socket.local_addr()?;
+        let shared = Arc::new(Shared {
+            faults: Mutex::new(faults),
+            counters: Counters::default(),
+        });
+        let task = tokio::spawn(run(Arc::new(socket), target, shared.clone()));
+        Ok(Self { addr, shared, task })
+    }
+
+    /// The address the getter has to dial.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Change the faults injected from now on.
+    pub fn set_faults(&self, faults: Faults) {
+        *self.shared.faults.lock().unwrap() = faults;
+    }
+
+    /// What the proxy did so far.
+    pub fn stats(&self) -> ProxyStats {
+        let counters = &self.shared.counters;
+        ProxyStats {
+            forwarded: counters.forwarded.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            corrupted: counters.corrupted.load(Ordering::Relaxed),
+            resets: counters.resets.load(Ordering::Relaxed),
+            provider_bytes: counters.provider_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for FaultyProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A getter talking to the provider through the proxy.
+#[derive(Debug)]
+struct Session {
+    upstream: Arc<UdpSocket>,
+    reset: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn local_addr(target: &SocketAddr) -> SocketAddr {
+    match target {
+        SocketAddr::V4(_) => (Ipv4Addr::LOCALHOST, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::LOCALHOST, 0).into(),
+    }
+}
+
+async fn run(socket: Arc<UdpSocket>, target: SocketAddr, shared: Arc<Shared>) {
+    let mut sessions = HashMap::<SocketAddr, Session>::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, getter) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(cause) => {
+                tracing::warn!("faulty proxy failed to receive: {cause}");
+                return;
+            }
+        };
+        if !sessions.contains_key(&getter) {
+            match open_session(&socket, getter, target, &shared).await {
+                Ok(session) => {
+                    sessions.insert(getter, session);
+                }
+                Err(cause) => {
+                    tracing::warn!("faulty proxy failed to open session for {getter}: {cause}");
+                    continue;
+                }
+            }
+        }
+        let session = &sessions[&getter];
+        if session.reset.load(Ordering::Relaxed) {
+            shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        forward(&session.upstream, target, buf[..len].to_vec(), &shared).await;
+    }
+}
+
+async fn open_session(
+    socket: &Arc<UdpSocket>,
+    getter: SocketAddr,
+    target: SocketAddr,
+    shared: &Arc<Shared>,
+) -> Result<Session> {
+    tracing::debug!("faulty proxy session for {getter}");
+    let upstream = Arc::new(UdpSocket::bind(local_addr(&target)).await?);
+    let reset = Arc::new(AtomicBool::new(false));
+    let task = tokio::spawn(run_session(
+        upstream.clone(),
+        socket.clone(),
+        getter,
+        reset.clone(),
+        shared.clone(),
+    ));
+    Ok(Session {
+        upstream,
+        reset,
+        task,
+    })
+}
+
+/// Forward the datagrams of the provider back to the getter.
+async fn run_session(
+    upstream: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    getter: SocketAddr,
+    reset: Arc<AtomicBool>,
+    shared: Arc<Shared>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut sent = 0u64;
+    loop {
+        let len = match upstream.recv(&mut buf).await {
+            Ok(len) => len,
+            Err(cause) => {
+                tracing::warn!("faulty proxy failed to receive from provider: {cause}");
+                return;
+            }
+        };
+        if reset.load(Ordering::Relaxed) {
+            shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let reset_after = shared.faults.lock().unwrap().reset_after;
+        if reset_after.map_or(false, |limit| sent >= limit) {
+            tracing::debug!("faulty proxy resets session for {getter} after {sent} bytes");
+            reset.store(true, Ordering::Relaxed);
+            shared.counters.resets.fetch_add(1, Ordering::Relaxed);
+            shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        sent += len as u64;
+        shared
+            .counters
+            .provider_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+        forward(&socket, getter, buf[..len].to_vec(), &shared).await;
+    }
+}
+
+/// Send a datagram, unless it is lost, after flipping a byte and delaying it if the
+/// faults say so.
+async fn forward(
+    socket: &Arc<UdpSocket>,
+    dest: SocketAddr,
+    mut datagram: Vec<u8>,
+    shared: &Shared,
+) {
+    let faults = shared.faults.lock().unwrap().clone();
+    let counters = &shared.counters;
+    {
+        // the rng is not Send, so it must not live across an await
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(faults.loss.clamp(0.0, 1.0)) {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if !datagram.is_empty() && rng.gen_bool(faults.corruption.clamp(0.0, 1.0)) {
+            let i = rng.gen_range(0..datagram.len());
+            datagram[i] ^= 0xff;
+            counters.corrupted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    counters.forwarded.fetch_add(1, Ordering::Relaxed);
+    if faults.latency.is_zero() {
+        socket.send_to(&datagram, dest).await.ok();
+    } else {
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(faults.latency).await;
+            socket.send_to(&datagram, dest).await.ok();
+        });
+    }
+}
+
+/// Assert that `db` has the complete blob `hash`, with the `expected` content.
+pub async fn assert_blob<D: Map>(db: &D, hash: Hash, expected: &[u8]) {
+    let entry = db
+        .get(&hash)
+        .unwrap_or_else(|| panic!("blob {hash} is missing"));
+    assert!(entry.is_complete(), "blob {hash} is incomplete");
+    let reader = entry.data_reader().await.expect("failed to open blob");
+    let data = reader.read_to_end().await.expect("failed to read blob");
+    assert!(
+        data == expected,
+        "blob {hash} has the wrong content, {} bytes instead of {}",
+        data.len(),
+        expected.len()
+    );
+}
+
+/// Assert that `db` has no complete blob `hash`, e.g. because it failed verification.
+pub fn assert_no_complete_blob<D: Map>(db: &D, hash: Hash) {
+    let complete = db.get(&hash).map_or(false, |entry| entry.is_complete());
+    assert!(!complete, "blob {hash} is complete");
+}
+
+/// Assert that a transfer of a blob of `size` bytes was resumed: it read something, but
+/// less than the whole blob with its outboard.
+pub fn assert_resumed(stats: &Stats, size: u64) {
+    let full = size + outboard_size(size, IROH_BLOCK_SIZE);
+    assert!(stats.bytes_read > 0, "resumed transfer read nothing");
+    assert!(
+        stats.bytes_read < full,
+        "transfer was restarted, it read {} bytes of {full}",
+        stats.bytes_read
+    );
+}
+
+/// Assert that a transfer failed with an error of the given class.
+pub fn assert_error_class<T: std::fmt::Debug>(res: &Result<T>, class: ErrorClass) {
+    match res {
+        Ok(value) => panic!("expected a {class:?} error, got {value:?}"),
+        Err(error) => assert_eq!(
+            ErrorClass::classify(error),
+            class,
+            "unexpected class of {error:#}"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn echo_server() -> Result<(SocketAddr, JoinHandle<()>)> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = socket.local_addr()?;
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                socket.send_to(&buf[..len], from).await.ok();
+            }
+        });
+        Ok((addr, task))
+    }
+
+    #[tokio::test]
+    async fn proxy_faults() -> Result<()> {
+        let (server, _task) = echo_server().await?;
+        let proxy = FaultyProxy::spawn(server, Faults::default()).await?;
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        client.connect(proxy.addr()).await?;
+        let mut buf = [0u8; 16];
+
+        client.send(b"hello").await?;
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf)).await??;
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(proxy.stats().provider_bytes, 5);
+
+        proxy.set_faults(Faults {
+            corruption: 1.0,
+            ..Default::default()
+        });
+        // corrupted in both directions
+        client.send(b"hello").await?;
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf)).await??;
+        assert_eq!(len, 5);
+        assert_eq!(proxy.stats().corrupted, 2);
+
+        proxy.set_faults(Faults {
+            reset_after: Some(15),
+            ..Default::default()
+        });
+        client.send(b"hello").await?;
+        tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf)).await??;
+        client.send(b"hello").await?;
+        let lost = tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf)).await;
+        assert!(lost.is_err());
+        let stats = proxy.stats();
+        assert_eq!(stats.resets, 1);
+        assert_eq!(stats.corrupted, 2);
+        Ok(())
+    }
+}
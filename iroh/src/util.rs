@@ -1,4 +1,11 @@
 //! utilites for io and for reporting progress
+pub mod checksum;
+pub mod download_queue;
+pub mod expiring_token;
 pub mod fs;
 pub mod io;
+pub mod limits;
+pub mod memory;
+pub mod peer_scores;
 pub mod progress;
+pub mod retry;
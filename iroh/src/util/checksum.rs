@@ -0,0 +1,267 @@
+//! Checksum manifests for exported data.
+//!
+//! Exported datasets can be accompanied by `SHA256SUMS` and `SHA1SUMS` files in the
+//! format used by `sha256sum` and `sha1sum`, so they can be verified by tools that
+//! don't speak BLAKE3. The checksums are computed while the data is copied out of
+//! the store, so the data is only read once.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use iroh_bytes::baomap::{Map, MapEntry};
+use iroh_bytes::Hash;
+use iroh_io::AsyncSliceReader;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use tokio::io::AsyncWriteExt;
+
+/// Size of the chunks in which data is copied out of the store.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// A checksum algorithm for export manifests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    /// SHA-256
+    Sha256,
+    /// SHA-1
+    Sha1,
+}
+
+impl ChecksumAlgorithm {
+    /// The conventional name of the manifest file for this algorithm.
+    pub fn manifest_name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "SHA256SUMS",
+            ChecksumAlgorithm::Sha1 => "SHA1SUMS",
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumAlgorithm::Sha256 => write!(f, "sha256"),
+            ChecksumAlgorithm::Sha1 => write!(f, "sha1"),
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sha256" | "sha-256" => Ok(ChecksumAlgorithm::Sha256),
+            "sha1" | "sha-1" => Ok(ChecksumAlgorithm::Sha1),
+            _ => anyhow::bail!("unknown checksum algorithm: {}", s),
+        }
+    }
+}
+
+/// Computes several checksums over the same data.
+#[derive(Debug, Default, Clone)]
+pub struct MultiHasher {
+    sha256: Option<sha2::Sha256>,
+    sha1: Option<sha1::Sha1>,
+}
+
+impl MultiHasher {
+    /// Create a new hasher for the given algorithms.
+    pub fn new(algorithms: &[ChecksumAlgorithm]) -> Self {
+        let mut res = Self::default();
+        for algorithm in algorithms {
+            match algorithm {
+                ChecksumAlgorithm::Sha256 => res.sha256 = Some(sha2::Sha256::new()),
+                ChecksumAlgorithm::Sha1 => res.sha1 = Some(sha1::Sha1::new()),
+            }
+        }
+        res
+    }
+
+    /// Feed data into all hashers.
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(h) = self.sha256.as_mut() {
+            h.update(data);
+        }
+        if let Some(h) = self.sha1.as_mut() {
+            h.update(data);
+        }
+    }
+
+    /// Finalize all hashers, returning the hex encoded checksums.
+    pub fn finalize(self) -> BTreeMap<ChecksumAlgorithm, String> {
+        let mut res = BTreeMap::new();
+        if let Some(h) = self.sha256 {
+            res.insert(ChecksumAlgorithm::Sha256, hex::encode(h.finalize()));
+        }
+        if let Some(h) = self.sha1 {
+            res.insert(ChecksumAlgorithm::Sha1, hex::encode(h.finalize()));
+        }
+        res
+    }
+}
+
+/// Fail if a file called `name` would be overwritten by the manifest of one of `algorithms`.
+///
+/// `name` is relative to the directory the manifests are written to. Names are compared
+/// case insensitively, since the file system might be.
+pub fn check_name(name: &str, algorithms: &[ChecksumAlgorithm]) -> io::Result<()> {
+    match algorithms
+        .iter()
+        .find(|algorithm| name.eq_ignore_ascii_case(algorithm.manifest_name()))
+    {
+        Some(algorithm) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{name} would be overwritten by the {algorithm} checksum manifest"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checksums of a set of exported files.
+#[derive(Debug, Default, Clone)]
+pub struct ChecksumManifest {
+    entries: BTreeMap<ChecksumAlgorithm, Vec<(String, String)>>,
+}
+
+impl ChecksumManifest {
+    /// Add the checksums for a file, with the name relative to the manifest directory.
+    pub fn add(&mut self, name: &str, checksums: BTreeMap<ChecksumAlgorithm, String>) {
+        for (algorithm, checksum) in checksums {
+            self.entries
+                .entry(algorithm)
+                .or_default()
+                .push((checksum, name.to_string()));
+        }
+    }
+
+    /// Render the manifest for one algorithm in `sha256sum` format.
+    pub fn render(&self, algorithm: ChecksumAlgorithm) -> String {
+        let mut res = String::new();
+        for (checksum, name) in self.entries.get(&algorithm).into_iter().flatten() {
+            res.push_str(checksum);
+            res.push_str("  ");
+            res.push_str(name);
+            res.push('\n');
+        }
+        res
+    }
+
+    /// Write one manifest file per algorithm into `dir`.
+    pub async fn write(&self, dir: &Path) -> io::Result<()> {
+        for algorithm in self.entries.keys() {
+            let path = dir.join(algorithm.manifest_name());
+            tokio::fs::write(path, self.render(*algorithm)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Export a blob to `target` by streaming it out of the store, computing checksums
+/// along the way.
+///
+/// The parent directory of `target` is created if it does not exist. `progress` is
+/// called with the number of bytes written so far.
+pub async fn export_with_checksums<D: Map>(
+    db: &D,
+    hash: Hash,
+    target: &Path,
+    algorithms: &[ChecksumAlgorithm],
+    progress: impl Fn(u64) -> io::Result<()>,
+) -> io::Result<BTreeMap<ChecksumAlgorithm, String>> {
+    let entry = db
+        .get(&hash)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "hash not found"))?;
+    let size = entry.size();
+    let mut reader = entry.data_reader().await?;
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut file = tokio::fs::File::create(target).await?;
+    let mut hasher = MultiHasher::new(algorithms);
+    let mut offset = 0u64;
+    while offset < size {
+        let len = COPY_CHUNK_SIZE.min((size - offset) as usize);
+        let chunk = reader.read_at(offset, len).await?;
+        if chunk.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "data shorter than expected",
+            ));
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        offset += chunk.len() as u64;
+        progress(offset)?;
+    }
+    file.flush().await?;
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_checksums() {
+        let mut hasher = MultiHasher::new(&[ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Sha1]);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let sums = hasher.finalize();
+        assert_eq!(
+            sums[&ChecksumAlgorithm::Sha256],
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert_eq!(
+            sums[&ChecksumAlgorithm::Sha1],
+            "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed"
+        );
+    }
+
+    #[test]
+    fn render_manifest() {
+        let mut manifest = ChecksumManifest::default();
+        let mut sums = BTreeMap::new();
+        sums.insert(ChecksumAlgorithm::Sha1, "abcd".to_string());
+        manifest.add("dir/file.txt", sums);
+        assert_eq!(
+            manifest.render(ChecksumAlgorithm::Sha1),
+            "abcd  dir/file.txt\n"
+        );
+        assert_eq!(manifest.render(ChecksumAlgorithm::Sha256), "");
+    }
+
+    #[test]
+    fn manifest_names() {
+        let both = [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Sha1];
+        assert!(check_name("SHA256SUMS", &both).is_err());
+        assert!(check_name("sha1sums", &both).is_err());
+        assert!(check_name("SHA1SUMS", &[ChecksumAlgorithm::Sha256]).is_ok());
+        assert!(check_name("dir/SHA256SUMS", &both).is_ok());
+        assert!(check_name("file.txt", &both).is_ok());
+    }
+
+    #[cfg(feature = "node")]
+    #[tokio::test]
+    async fn export_creates_parent() -> anyhow::Result<()> {
+        let (db, hashes) = crate::baomap::readonly_mem::Store::new([("test", b"hello world")]);
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("a").join("b").join("test");
+        let sums = export_with_checksums(
+            &db,
+            hashes["test"].into(),
+            &target,
+            &[ChecksumAlgorithm::Sha1],
+            |_| Ok(()),
+        )
+        .await?;
+        assert_eq!(std::fs::read(&target)?, b"hello world");
+        assert_eq!(
+            sums[&ChecksumAlgorithm::Sha1],
+            "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed"
+        );
+        Ok(())
+    }
+}
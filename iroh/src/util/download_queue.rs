@@ -0,0 +1,535 @@
+//! Queueing and prioritization of downloads.
+//!
+//! Every download of a node takes a permit from the [`DownloadQueue`] before it dials a
+//! provider. If the number of concurrent downloads is limited, downloads wait for a permit
+//! in the order of their [`DownloadPriority`], and in the order they were started within
+//! the same priority.
+//!
+//! Running downloads also share the downlink of the node. While a download of a higher
+//! priority is running, the connections of lower priority downloads get a small receive
+//! window, which limits how much data their providers may send before it is read. Their
+//! full window is restored once the higher priority downloads are done.
+//!
+//! The requests of downloads that have not completed yet can be persisted as
+//! [`PendingDownloads`], so that a restarted node resumes them. The progress of a download
+//! is kept in the partial entries of the store, so a resumed download only requests the
+//! ranges that are still missing. Every request is stored with the version of its layout,
+//! see [`Persist`], so that requests stored by an older version can still be read.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use iroh_bytes::util::RequestId;
+use quinn::VarInt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+/// Receive window of the connections of downloads that yield to a higher priority.
+pub const YIELDING_RECEIVE_WINDOW: u32 = 64 * 1024;
+
+/// The priority of a download.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum DownloadPriority {
+    /// Bulk transfers such as replication, which yield to all other downloads.
+    Background,
+    /// Regular downloads.
+    #[default]
+    Normal,
+    /// Downloads a user is waiting for.
+    Interactive,
+}
+
+impl fmt::Display for DownloadPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadPriority::Background => write!(f, "background"),
+            DownloadPriority::Normal => write!(f, "normal"),
+            DownloadPriority::Interactive => write!(f, "interactive"),
+        }
+    }
+}
+
+impl FromStr for DownloadPriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "background" => Ok(DownloadPriority::Background),
+            "normal" => Ok(DownloadPriority::Normal),
+            "interactive" => Ok(DownloadPriority::Interactive),
+            _ => anyhow::bail!("unknown download priority: {}", s),
+        }
+    }
+}
+
+/// Hands out permits to download, in the order of their priority.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadQueue(Arc<Mutex<QueueState>>);
+
+#[derive(Debug, Default)]
+struct QueueState {
+    /// Maximum number of concurrent downloads, unlimited if `None`.
+    max_concurrent: Option<usize>,
+    next_id: u64,
+    running: HashMap<u64, Running>,
+    /// Downloads waiting for a permit, highest priority first, then oldest first.
+    waiting: BTreeMap<(Reverse<DownloadPriority>, u64), oneshot::Sender<DownloadPermit>>,
+}
+
+#[derive(Debug)]
+struct Running {
+    priority: DownloadPriority,
+    conn: Option<quinn::Connection>,
+}
+
+impl DownloadQueue {
+    /// Creates a queue that runs at most `max_concurrent` downloads at a time.
+    pub fn new(max_concurrent: Option<usize>) -> Self {
+        let state = QueueState {
+            max_concurrent,
+            ..Default::default()
+        };
+        Self(Arc::new(Mutex::new(state)))
+    }
+
+    /// Number of running downloads.
+    pub fn running(&self) -> usize {
+        self.0.lock().unwrap().running.len()
+    }
+
+    /// Number of downloads waiting for a permit.
+    pub fn waiting(&self) -> usize {
+        self.0.lock().unwrap().waiting.len()
+    }
+
+    /// Waits for a permit to run a download of `priority`.
+    pub async fn acquire(&self, priority: DownloadPriority) -> DownloadPermit {
+        let rx = {
+            let mut state = self.0.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            if state.waiting.is_empty() && state.has_capacity() {
+                return self.start(&mut state, id, priority);
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.insert((Reverse(priority), id), tx);
+            rx
+        };
+        // the sender is only dropped together with the queue, which we hold on to
+        rx.await.expect("queue is alive")
+    }
+
+    fn start(&self, state: &mut QueueState, id: u64, priority: DownloadPriority) -> DownloadPermit {
+        state.running.insert(
+            id,
+            Running {
+                priority,
+                conn: None,
+            },
+        );
+        state.update_windows();
+        DownloadPermit {
+            queue: self.clone(),
+            id: Some(id),
+            priority,
+        }
+    }
+
+    fn release(&self, id: u64) {
+        let mut state = self.0.lock().unwrap();
+        state.running.remove(&id);
+        while state.has_capacity() {
+            let Some(((priority, id), tx)) = state.waiting.pop_first() else {
+                break;
+            };
+            let permit = self.start(&mut state, id, priority.0);
+            if let Err(mut permit) = tx.send(permit) {
+                // the download was cancelled while waiting
+                permit.id = None;
+                state.running.remove(&id);
+            }
+        }
+        state.update_windows();
+    }
+}
+
+impl QueueState {
+    fn has_capacity(&self) -> bool {
+        self.max_concurrent
+            .map_or(true, |max| self.running.len() < max)
+    }
+
+    /// Shrinks the receive windows of downloads below the highest running priority.
+    fn update_windows(&self) {
+        let Some(top) = self.running.values().map(|r| r.priority).max() else {
+            return;
+        };
+        for running in self.running.values() {
+            if let Some(conn) = &running.conn {
+                let window = if running.priority < top {
+                    VarInt::from_u32(YIELDING_RECEIVE_WINDOW)
+                } else {
+                    VarInt::MAX
+                };
+                conn.set_receive_window(window);
+            }
+        }
+    }
+}
+
+/// A permit to run a download, released when dropped.
+#[derive(Debug)]
+pub struct DownloadPermit {
+    queue: DownloadQueue,
+    /// `None` once released.
+    id: Option<u64>,
+    priority: DownloadPriority,
+}
+
+impl DownloadPermit {
+    /// The priority of the download.
+    pub fn priority(&self) -> DownloadPriority {
+        self.priority
+    }
+
+    /// Sets the connection the download currently uses, so its receive window can be
+    /// adjusted to the other running downloads.
+    pub fn set_connection(&self, conn: &quinn::Connection) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let mut state = self.queue.0.lock().unwrap();
+        if let Some(running) = state.running.get_mut(&id) {
+            running.conn = Some(conn.clone());
+        }
+        state.update_windows();
+    }
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.queue.release(id);
+        }
+    }
+}
+
+/// The requests of downloads that have not completed yet, persisted in a file.
+#[derive(Debug, Clone)]
+pub struct PendingDownloads<T> {
+    path: PathBuf,
+    entries: Arc<Mutex<Vec<(RequestId, T)>>>,
+}
+
+/// A request that can be persisted in [`PendingDownloads`].
+///
+/// Requests are stored with the [`VERSION`](Self::VERSION) of their layout. Adding a field
+/// to a request changes its layout, so the version has to be increased, and requests of
+/// the previous versions have to be decoded by [`Persist::upgrade`]. New fields should
+/// also have `#[serde(default)]`, for encodings that can tell that a field is missing.
+pub trait Persist: Serialize + DeserializeOwned + Clone {
+    /// The version of the current layout.
+    const VERSION: u32;
+
+    /// Decodes a request that was stored with an older `version` of the layout.
+    ///
+    /// Requests that can not be upgraded are dropped when loading.
+    fn upgrade(version: u32, data: &[u8]) -> anyhow::Result<Self> {
+        let _ = data;
+        anyhow::bail!("unsupported version {version} of a pending download")
+    }
+}
+
+/// The file format of [`PendingDownloads`].
+///
+/// The variant is the version of the format. A file that can not be decoded, e.g. because
+/// it was written by a newer version, is moved aside when loading.
+#[derive(Debug, Serialize, Deserialize)]
+enum PendingDownloadsFile<T> {
+    /// The requests without their version, in the first version of their layout.
+    V1(Vec<(RequestId, T)>),
+    /// The requests with the version of their layout.
+    V2(Vec<StoredRequest>),
+}
+
+/// A request in [`PendingDownloadsFile::V2`].
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredRequest {
+    id: RequestId,
+    /// The [`Persist::VERSION`] of the layout of `data`.
+    version: u32,
+    /// The postcard encoding of the request.
+    data: Vec<u8>,
+}
+
+impl StoredRequest {
+    fn decode<T: Persist>(&self) -> anyhow::Result<T> {
+        if self.version == T::VERSION {
+            Ok(postcard::from_bytes(&self.data)?)
+        } else {
+            T::upgrade(self.version, &self.data)
+        }
+    }
+}
+
+impl<T: Persist> PendingDownloads<T> {
+    /// Loads the pending downloads from `path`, none if the file does not exist yet.
+    ///
+    /// A file that can not be decoded is renamed to `<path>.bad` and the node starts
+    /// without pending downloads, instead of failing to start.
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let entries = match std::fs::read(&path) {
+            Ok(bytes) => match postcard::from_bytes(&bytes) {
+                Ok(PendingDownloadsFile::V1(entries)) if T::VERSION == 1 => entries,
+                Ok(PendingDownloadsFile::V1(_)) => {
+                    // the requests can not be decoded without their version
+                    warn!("dropping pending downloads stored without a version");
+                    Vec::new()
+                }
+                Ok(PendingDownloadsFile::V2(stored)) => stored
+                    .into_iter()
+                    .filter_map(|request| match request.decode() {
+                        Ok(decoded) => Some((request.id, decoded)),
+                        Err(err) => {
+                            warn!("dropping pending download {}: {:#}", request.id, err);
+                            None
+                        }
+                    })
+                    .collect(),
+                Err(err) => {
+                    let mut bad = path.as_os_str().to_owned();
+                    bad.push(".bad");
+                    let bad = PathBuf::from(bad);
+                    warn!(
+                        "failed to read pending downloads from {}, moving it to {}: {}",
+                        path.display(),
+                        bad.display(),
+                        err
+                    );
+                    std::fs::rename(&path, bad)?;
+                    Vec::new()
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path,
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    /// The pending downloads, in the order they were started.
+    pub fn entries(&self) -> Vec<(RequestId, T)> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Records that the download `id` was started, or replaces its request.
+    pub fn insert(&self, id: RequestId, request: T) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.iter_mut().find(|(other, _)| *other == id) {
+            Some(entry) => entry.1 = request,
+            None => entries.push((id, request)),
+        }
+        save(&self.path, &entries)
+    }
+
+    /// Records that the download `id` completed or failed for good.
+    pub fn remove(&self, id: RequestId) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(other, _)| *other != id);
+        save(&self.path, &entries)
+    }
+}
+
+/// Writes `entries` to a temporary file first, so a crash never leaves a truncated file.
+fn save<T: Persist>(path: &Path, entries: &[(RequestId, T)]) -> io::Result<()> {
+    let to_io = |err| io::Error::new(io::ErrorKind::Other, err);
+    let stored = entries
+        .iter()
+        .map(|(id, request)| {
+            Ok(StoredRequest {
+                id: *id,
+                version: T::VERSION,
+                data: postcard::to_stdvec(request).map_err(to_io)?,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let file = PendingDownloadsFile::<T>::V2(stored);
+    let bytes = postcard::to_stdvec(&file).map_err(to_io)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    impl Persist for String {
+        const VERSION: u32 = 1;
+    }
+
+    /// A request that gained a field in its second version.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Upgraded {
+        size: u32,
+        name: String,
+    }
+
+    impl Persist for Upgraded {
+        const VERSION: u32 = 2;
+
+        fn upgrade(version: u32, data: &[u8]) -> anyhow::Result<Self> {
+            anyhow::ensure!(version == 1, "unsupported version {version}");
+            Ok(Self {
+                size: postcard::from_bytes(data)?,
+                name: String::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn priority_order() {
+        assert!(DownloadPriority::Interactive > DownloadPriority::Normal);
+        assert!(DownloadPriority::Normal > DownloadPriority::Background);
+        assert_eq!(DownloadPriority::default(), DownloadPriority::Normal);
+        for priority in [
+            DownloadPriority::Background,
+            DownloadPriority::Normal,
+            DownloadPriority::Interactive,
+        ] {
+            assert_eq!(
+                priority.to_string().parse::<DownloadPriority>().unwrap(),
+                priority
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_order() {
+        let queue = DownloadQueue::new(Some(1));
+        let first = queue.acquire(DownloadPriority::Background).await;
+
+        let background = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(DownloadPriority::Background).await }
+        });
+        let cancelled = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(DownloadPriority::Interactive).await }
+        });
+        let interactive = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(DownloadPriority::Interactive).await }
+        });
+        while queue.waiting() < 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        cancelled.abort();
+        assert!(cancelled.await.unwrap_err().is_cancelled());
+
+        // the interactive download goes first, although it was queued last
+        drop(first);
+        let permit = interactive.await.unwrap();
+        assert_eq!(permit.priority(), DownloadPriority::Interactive);
+        assert_eq!(queue.running(), 1);
+        assert_eq!(queue.waiting(), 1);
+        drop(permit);
+        let permit = background.await.unwrap();
+        assert_eq!(permit.priority(), DownloadPriority::Background);
+        drop(permit);
+        assert_eq!((queue.running(), queue.waiting()), (0, 0));
+    }
+
+    #[test]
+    fn pending_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("downloads");
+        let pending = PendingDownloads::<String>::load(&path).unwrap();
+        assert!(pending.entries().is_empty());
+
+        let (a, b) = (RequestId::generate(), RequestId::generate());
+        pending.insert(a, "a".to_string()).unwrap();
+        pending.insert(b, "b".to_string()).unwrap();
+        pending.insert(a, "a2".to_string()).unwrap();
+        let pending = PendingDownloads::<String>::load(&path).unwrap();
+        assert_eq!(
+            pending.entries(),
+            vec![(a, "a2".to_string()), (b, "b".to_string())]
+        );
+
+        pending.remove(a).unwrap();
+        let pending = PendingDownloads::<String>::load(&path).unwrap();
+        assert_eq!(pending.entries(), vec![(b, "b".to_string())]);
+
+        // an unreadable file is moved aside
+        std::fs::write(&path, b"garbage").unwrap();
+        let pending = PendingDownloads::<String>::load(&path).unwrap();
+        assert!(pending.entries().is_empty());
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read(dir.path().join("downloads.bad")).unwrap(),
+            b"garbage"
+        );
+        pending.insert(a, "a".to_string()).unwrap();
+        let pending = PendingDownloads::<String>::load(&path).unwrap();
+        assert_eq!(pending.entries(), vec![(a, "a".to_string())]);
+
+        // files from before requests were stored with their version
+        let file = PendingDownloadsFile::V1(vec![(b, "b".to_string())]);
+        std::fs::write(&path, postcard::to_stdvec(&file).unwrap()).unwrap();
+        let pending = PendingDownloads::<String>::load(&path).unwrap();
+        assert_eq!(pending.entries(), vec![(b, "b".to_string())]);
+    }
+
+    #[test]
+    fn pending_download_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("downloads");
+        let (a, b) = (RequestId::generate(), RequestId::generate());
+        let file = PendingDownloadsFile::<Upgraded>::V2(vec![
+            StoredRequest {
+                id: a,
+                version: 1,
+                data: postcard::to_stdvec(&7u32).unwrap(),
+            },
+            StoredRequest {
+                id: b,
+                version: 3,
+                data: Vec::new(),
+            },
+        ]);
+        std::fs::write(&path, postcard::to_stdvec(&file).unwrap()).unwrap();
+
+        // old requests are upgraded, requests that can not be read are dropped
+        let pending = PendingDownloads::<Upgraded>::load(&path).unwrap();
+        let upgraded = Upgraded {
+            size: 7,
+            name: String::new(),
+        };
+        assert_eq!(pending.entries(), vec![(a, upgraded.clone())]);
+
+        // and stored in the current version
+        pending.remove(b).unwrap();
+        let pending = PendingDownloads::<Upgraded>::load(&path).unwrap();
+        assert_eq!(pending.entries(), vec![(a, upgraded)]);
+    }
+}
@@ -0,0 +1,331 @@
+//! Request tokens that expire.
+//!
+//! An expiring token carries the time it expires at, signed together with the hash it is
+//! for with the keypair of the provider, so the provider can check it without keeping any
+//! state. A token only authorizes requests for its hash, so a ticket can not be used to
+//! fetch other content of the provider. Tickets with such a token stop working once the
+//! token expires, and the provider resets requests with an expired token with
+//! [`ErrorCode::TokenExpired`] so that getters can tell an expired ticket apart from a
+//! wrong one.
+//!
+//! Tokens can also be created by another machine that holds the key of the provider,
+//! e.g. an application server handing out tickets, whose clock may be off. A token is
+//! therefore still accepted for a configurable clock skew after it expired.
+//!
+//! A ticket that lists several providers carries one token for all of them, so a token
+//! can hold a signature of each provider, see [`combine`]. Every provider checks the
+//! signature it can verify. Providers can also accept tokens signed by other keys they
+//! trust, e.g. the members of a cluster fetching content from each other, and the static
+//! token of the node, e.g. for mirrors, see [`ExpiringTokenAuthHandler`].
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, ensure, Context};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use iroh_bytes::protocol::{ErrorCode, Request, RequestToken};
+use iroh_bytes::provider::RequestAuthorizationHandler;
+use iroh_bytes::Hash;
+use iroh_net::tls::{Keypair, PublicKey, Signature};
+
+/// Default time a token is accepted after it expired.
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Domain separation for the signature.
+const SIGNATURE_CONTEXT: &[u8] = b"iroh-expiring-token";
+
+/// Length of the encoded expiry, in seconds since the unix epoch.
+const EXPIRES_LEN: usize = 8;
+
+/// Length of the signature of a single provider, its expiry followed by the signature.
+const RECORD_LEN: usize = EXPIRES_LEN + Signature::BYTE_SIZE;
+
+/// Creates a token for requests of `hash` that expires at `expires`, signed with `keypair`.
+///
+/// For a keyed namespace, `hash` is the name of the content.
+pub fn create(keypair: &Keypair, hash: Hash, expires: SystemTime) -> RequestToken {
+    let expires = expires
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_be_bytes();
+    let signature = keypair.sign(&signed_message(&expires, &hash));
+    let mut bytes = expires.to_vec();
+    bytes.extend_from_slice(&signature.to_bytes());
+    RequestToken::new(bytes).expect("token is short enough")
+}
+
+/// Combines expiring tokens for the same hash, signed by different providers, into one
+/// token that every one of them accepts.
+///
+/// Fails if one of the tokens is not an expiring token, or if the combined token is too
+/// large.
+pub fn combine<'a>(
+    tokens: impl IntoIterator<Item = &'a RequestToken>,
+) -> anyhow::Result<RequestToken> {
+    let mut bytes = Vec::new();
+    for token in tokens {
+        decode(token)?;
+        for record in token.as_bytes().chunks(RECORD_LEN) {
+            if !bytes.chunks(RECORD_LEN).any(|r| r == record) {
+                bytes.extend_from_slice(record);
+            }
+        }
+    }
+    ensure!(!bytes.is_empty(), "no tokens to combine");
+    RequestToken::new(bytes)
+}
+
+/// The time `token` expires at, if it is an expiring token.
+///
+/// For a token signed by several providers, this is the earliest of their expiry times.
+/// The signatures are not checked, this is for getters to explain why a request failed.
+pub fn expires(token: &RequestToken) -> Option<SystemTime> {
+    decode(token)
+        .ok()?
+        .into_iter()
+        .map(|record| record.expires)
+        .min()
+}
+
+fn signed_message(expires: &[u8], hash: &Hash) -> Vec<u8> {
+    let mut msg = SIGNATURE_CONTEXT.to_vec();
+    msg.extend_from_slice(expires);
+    msg.extend_from_slice(hash.as_bytes());
+    msg
+}
+
+/// The hash a request is for, `None` for requests that are not about a single hash.
+fn request_hash(request: &Request) -> Option<Hash> {
+    match request {
+        Request::Get(get) => Some(get.hash),
+        Request::Probe(probe) => Some(probe.hash),
+        Request::KeyedGet(get) => Some(get.name),
+        // routed gets are for a single hash as well
+        Request::CustomGet(get) => crate::cluster::routed_get_hash(&get.data),
+        Request::List(_) => None,
+    }
+}
+
+/// The signature of a single provider in an expiring token.
+struct Record<'a> {
+    expires: SystemTime,
+    /// The encoded expiry, as it was signed.
+    expires_bytes: &'a [u8],
+    signature: Signature,
+}
+
+fn decode(token: &RequestToken) -> anyhow::Result<Vec<Record<'_>>> {
+    let bytes = token.as_bytes();
+    if bytes.is_empty() || bytes.len() % RECORD_LEN != 0 {
+        bail!("not an expiring token");
+    }
+    bytes
+        .chunks(RECORD_LEN)
+        .map(|record| {
+            let (expires_bytes, signature) = record.split_at(EXPIRES_LEN);
+            let secs = u64::from_be_bytes(expires_bytes.try_into()?);
+            Ok(Record {
+                expires: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                expires_bytes,
+                signature: Signature::from_slice(signature)?,
+            })
+        })
+        .collect()
+}
+
+/// Authorizes requests with an unexpired token signed by the provider.
+///
+/// Tokens signed by [trusted](Self::trust) keys are accepted as well, and so is the
+/// [static token](Self::static_token) of the node, for any request.
+#[derive(Debug, Clone)]
+pub struct ExpiringTokenAuthHandler {
+    keys: Vec<PublicKey>,
+    static_token: Option<RequestToken>,
+    clock_skew: Duration,
+}
+
+impl ExpiringTokenAuthHandler {
+    /// Accepts tokens signed with the secret key of `key`.
+    pub fn new(key: PublicKey) -> Self {
+        Self {
+            keys: vec![key],
+            static_token: None,
+            clock_skew: DEFAULT_CLOCK_SKEW,
+        }
+    }
+
+    /// Also accepts tokens signed with the secret key of `key`.
+    ///
+    /// Members of a cluster trust each other, so they can fetch content from each other
+    /// with tokens they sign themselves.
+    pub fn trust(mut self, key: PublicKey) -> Self {
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        self
+    }
+
+    /// Also accepts `token` for any request, like
+    /// [`StaticTokenAuthHandler`](crate::node::StaticTokenAuthHandler).
+    ///
+    /// Mirrors authorize with the static token of their source.
+    pub fn static_token(mut self, token: Option<RequestToken>) -> Self {
+        self.static_token = token;
+        self
+    }
+
+    /// Sets how long a token is accepted after it expired.
+    ///
+    /// Defaults to [`DEFAULT_CLOCK_SKEW`].
+    pub fn clock_skew(mut self, clock_skew: Duration) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    /// Checks `token` for a request of `hash` at time `now`.
+    ///
+    /// Fails with [`ErrorCode::TokenExpired`] in the chain of causes if the token is valid
+    /// but expired.
+    pub fn check(
+        &self,
+        token: Option<&RequestToken>,
+        hash: Option<Hash>,
+        now: SystemTime,
+    ) -> anyhow::Result<()> {
+        let token = token.context("no token provided")?;
+        if self.static_token.as_ref() == Some(token) {
+            return Ok(());
+        }
+        let hash = hash.context("expiring tokens only authorize requests for a hash")?;
+        // the latest expiry of the signatures that are valid for this provider
+        let mut expires = None;
+        for record in decode(token)? {
+            let msg = signed_message(record.expires_bytes, &hash);
+            let valid = self
+                .keys
+                .iter()
+                .any(|key| key.verify_strict(&msg, &record.signature).is_ok());
+            if valid {
+                expires = expires.max(Some(record.expires));
+            }
+        }
+        let expires = expires.context("invalid token")?;
+        if now > expires + self.clock_skew {
+            let expired_for = now.duration_since(expires).unwrap_or_default();
+            return Err(anyhow::Error::new(ErrorCode::TokenExpired)
+                .context(format!("token expired {}s ago", expired_for.as_secs())));
+        }
+        Ok(())
+    }
+}
+
+impl RequestAuthorizationHandler for ExpiringTokenAuthHandler {
+    fn authorize(
+        &self,
+        token: Option<RequestToken>,
+        request: &Request,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let res = self.check(token.as_ref(), request_hash(request), SystemTime::now());
+        async move { res }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_bytes::protocol::CustomGetRequest;
+
+    use super::*;
+
+    fn code(error: &anyhow::Error) -> Option<ErrorCode> {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<ErrorCode>())
+            .copied()
+    }
+
+    #[test]
+    fn expiring_token() {
+        let keypair = Keypair::generate();
+        let handler =
+            ExpiringTokenAuthHandler::new(keypair.public()).clock_skew(Duration::from_secs(30));
+        let expires = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let hash = Some(Hash::new(b"content"));
+        let token = create(&keypair, hash.unwrap(), expires);
+        assert_eq!(super::expires(&token), Some(expires));
+
+        assert!(handler.check(Some(&token), hash, expires).is_ok());
+        let within_skew = expires + Duration::from_secs(30);
+        assert!(handler.check(Some(&token), hash, within_skew).is_ok());
+        let err = handler
+            .check(Some(&token), hash, within_skew + Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(code(&err), Some(ErrorCode::TokenExpired));
+
+        let other = create(&Keypair::generate(), hash.unwrap(), expires);
+        let err = handler.check(Some(&other), hash, expires).unwrap_err();
+        assert_eq!(code(&err), None);
+        let err = handler.check(None, hash, expires).unwrap_err();
+        assert_eq!(code(&err), None);
+        assert!(super::expires(&RequestToken::generate()).is_none());
+
+        // the token is bound to its hash
+        let err = handler
+            .check(Some(&token), Some(Hash::new(b"other")), expires)
+            .unwrap_err();
+        assert_eq!(code(&err), None);
+        assert!(handler.check(Some(&token), None, expires).is_err());
+    }
+
+    #[test]
+    fn combined_token() {
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        let hash = Hash::new(b"content");
+        let expires = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let later = expires + Duration::from_secs(100);
+        let token_a = create(&a, hash, expires);
+        let token_b = create(&b, hash, later);
+        let token = combine([&token_a, &token_b, &token_a]).unwrap();
+        assert_eq!(token.as_bytes().len(), 2 * RECORD_LEN);
+        assert_eq!(super::expires(&token), Some(expires));
+
+        // every provider checks its own signature
+        let handler_a = ExpiringTokenAuthHandler::new(a.public()).clock_skew(Duration::ZERO);
+        let handler_b = ExpiringTokenAuthHandler::new(b.public()).clock_skew(Duration::ZERO);
+        let now = expires + Duration::from_secs(50);
+        let err = handler_a.check(Some(&token), Some(hash), now).unwrap_err();
+        assert_eq!(code(&err), Some(ErrorCode::TokenExpired));
+        assert!(handler_b.check(Some(&token), Some(hash), now).is_ok());
+        assert!(ExpiringTokenAuthHandler::new(Keypair::generate().public())
+            .check(Some(&token), Some(hash), now)
+            .is_err());
+        assert!(combine([&token, &RequestToken::generate()]).is_err());
+    }
+
+    #[test]
+    fn trusted_keys_and_static_token() {
+        let keypair = Keypair::generate();
+        let member = Keypair::generate();
+        let hash = Some(Hash::new(b"content"));
+        let expires = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let static_token = RequestToken::generate();
+        let handler = ExpiringTokenAuthHandler::new(keypair.public());
+        let token = create(&member, hash.unwrap(), expires);
+        assert!(handler.check(Some(&token), hash, expires).is_err());
+        assert!(handler.check(Some(&static_token), None, expires).is_err());
+
+        let handler = handler
+            .trust(member.public())
+            .static_token(Some(static_token.clone()));
+        assert!(handler.check(Some(&token), hash, expires).is_ok());
+        assert!(handler.check(Some(&static_token), None, expires).is_ok());
+        assert!(handler.check(Some(&static_token), hash, expires).is_ok());
+
+        // a routed get is for its hash
+        let routed = Request::CustomGet(CustomGetRequest {
+            token: None,
+            data: crate::cluster::routed_get_request(hash.unwrap(), true),
+        });
+        assert_eq!(request_hash(&routed), hash);
+    }
+}
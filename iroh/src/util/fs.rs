@@ -1,11 +1,13 @@
 //! Utilities for filesystem operations.
 use std::{
     borrow::Cow,
+    io,
     path::{Component, Path, PathBuf},
 };
 
 use anyhow::Context;
-use walkdir::WalkDir;
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use serde::{Deserialize, Serialize};
 
 /// A data source
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -55,16 +57,65 @@ impl From<&std::path::Path> for DataSource {
     }
 }
 
+/// Which files of a directory to import.
+///
+/// Globs are matched against paths relative to the directory, with gitignore syntax, so
+/// `target/**` excludes everything in the `target` directory at the top level. A single
+/// file is always imported.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportFilter {
+    /// Only import files matching one of these globs, all files if empty.
+    pub include: Vec<String>,
+    /// Skip files matching any of these globs.
+    pub exclude: Vec<String>,
+    /// Skip files ignored by `.ignore` and `.gitignore` files, like git does.
+    pub ignore_files: bool,
+}
+
+impl ImportFilter {
+    /// A filter that skips the files ignored by `.ignore` and `.gitignore` files.
+    pub fn ignore_files() -> Self {
+        Self {
+            ignore_files: true,
+            ..Default::default()
+        }
+    }
+}
+
 /// Create data sources from a path.
-pub fn scan_path(root: PathBuf) -> anyhow::Result<Vec<DataSource>> {
+///
+/// Symlinks in a directory are skipped, unless `symlinks` is true.
+pub fn scan_path(
+    root: PathBuf,
+    symlinks: bool,
+    filter: &ImportFilter,
+) -> anyhow::Result<Vec<DataSource>> {
     Ok(if root.is_dir() {
-        let files = WalkDir::new(&root).into_iter();
+        let mut overrides = OverrideBuilder::new(&root);
+        for glob in &filter.include {
+            overrides.add(glob)?;
+        }
+        for glob in &filter.exclude {
+            overrides.add(&format!("!{glob}"))?;
+        }
+        let files = WalkBuilder::new(&root)
+            .standard_filters(false)
+            .parents(filter.ignore_files)
+            .ignore(filter.ignore_files)
+            .git_ignore(filter.ignore_files)
+            .git_exclude(filter.ignore_files)
+            .require_git(false)
+            .overrides(overrides.build()?)
+            .build();
         let data_sources = files
             .map(|entry| {
                 let entry = entry?;
                 let root = root.clone();
-                if !entry.file_type().is_file() {
-                    // Skip symlinks. Directories are handled by WalkDir.
+                let Some(file_type) = entry.file_type() else {
+                    return Ok(None);
+                };
+                if !file_type.is_file() && !(symlinks && file_type.is_symlink()) {
+                    // Directories are handled by the walker.
                     return Ok(None);
                 }
                 let path = entry.into_path();
@@ -85,6 +136,43 @@ pub fn scan_path(root: PathBuf) -> anyhow::Result<Vec<DataSource>> {
     })
 }
 
+/// Sets the modification time of the file at `path`, in seconds since the unix epoch.
+#[cfg(unix)]
+pub fn set_mtime(path: &Path, secs: u64) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let secs =
+        libc::time_t::try_from(secs).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: secs,
+            tv_nsec: 0,
+        },
+    ];
+    // SAFETY: path is a valid nul terminated string and times holds the access and
+    // modification time
+    let res = unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the modification time of the file at `path`, in seconds since the unix epoch.
+///
+/// Not supported on this platform, the time is left unchanged.
+#[cfg(not(unix))]
+pub fn set_mtime(_path: &Path, _secs: u64) -> io::Result<()> {
+    Ok(())
+}
+
 /// This function converts a canonicalized relative path to a string, returning
 /// an error if the path is not valid unicode.
 ///
@@ -114,9 +202,44 @@ pub fn canonicalize_path(path: impl AsRef<Path>) -> anyhow::Result<String> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn test_canonicalize_path() {
         assert_eq!(super::canonicalize_path("foo/bar").unwrap(), "foo/bar");
     }
+
+    #[test]
+    fn test_scan_path_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for name in ["a.txt", "b.rs", "secret.env", "target/out.txt", "src/c.rs"] {
+            let path = root.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        std::fs::write(root.join(".gitignore"), "*.env\n").unwrap();
+        let names = |filter: &ImportFilter| {
+            let mut names = scan_path(root.to_owned(), false, filter)
+                .unwrap()
+                .into_iter()
+                .map(|source| source.name().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        let all = names(&ImportFilter::default());
+        assert_eq!(all.len(), 6);
+        let filter = ImportFilter {
+            exclude: vec!["target/**".to_string()],
+            ..ImportFilter::ignore_files()
+        };
+        assert_eq!(names(&filter), [".gitignore", "a.txt", "b.rs", "src/c.rs"]);
+        let filter = ImportFilter {
+            include: vec!["*.rs".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(names(&filter), ["b.rs", "src/c.rs"]);
+    }
 }
@@ -0,0 +1,261 @@
+//! Limits on what a download may store, read and how long it may take.
+//!
+//! A ticket can point to a collection of any size, so downloads from untrusted tickets
+//! should be limited. [`DownloadLimits`] are checked against the sizes announced by the
+//! provider before any data of a blob is written, and against the size of the collection
+//! before any of its children are requested.
+//!
+//! Services calling iroh with their own deadlines can also bound the time and the bytes
+//! read from the network of a download, over all of its attempts. A download that exceeds
+//! them fails with [`LimitExceeded`], and is not retried. The data received until then
+//! stays in the store as a partial entry, so a later download of the same data resumes
+//! from it.
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+/// Limits for a download, no limits by default.
+///
+/// Limits that are missing when deserializing are not set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadLimits {
+    /// Maximum number of bytes of all blobs, including the collection itself.
+    pub max_total_size: Option<u64>,
+    /// Maximum number of blobs in a collection.
+    pub max_blobs: Option<u64>,
+    /// Maximum size of a single blob.
+    pub max_blob_size: Option<u64>,
+    /// Maximum number of bytes of blob data read from providers, over all attempts.
+    pub max_bytes_read: Option<u64>,
+    /// Time by which the download must be complete, including waiting for its turn and
+    /// retries.
+    pub deadline: Option<SystemTime>,
+}
+
+impl DownloadLimits {
+    /// Starts tracking a download against these limits.
+    pub fn budget(self) -> DownloadBudget {
+        DownloadBudget {
+            limits: self,
+            total: 0,
+            read: Default::default(),
+        }
+    }
+
+    /// The time left until the deadline, `None` without a deadline.
+    pub fn time_left(&self) -> Result<Option<Duration>, LimitExceeded> {
+        match self.deadline {
+            Some(deadline) => match deadline.duration_since(SystemTime::now()) {
+                Ok(left) => Ok(Some(left)),
+                Err(_) => Err(LimitExceeded::Deadline),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Runs `fut` to completion, unless the deadline passes first.
+    pub async fn with_deadline<T>(
+        &self,
+        fut: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        match self.time_left()? {
+            Some(left) => tokio::time::timeout(left, fut)
+                .await
+                .map_err(|_| LimitExceeded::Deadline)?,
+            None => fut.await,
+        }
+    }
+}
+
+/// A limit of [`DownloadLimits`] was exceeded.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum LimitExceeded {
+    /// The collection has too many blobs.
+    #[error("collection has {count} blobs, more than the limit of {limit}")]
+    Blobs {
+        /// The number of blobs in the collection.
+        count: u64,
+        /// The limit.
+        limit: u64,
+    },
+    /// A blob is too large.
+    #[error("blob of {size} bytes exceeds the limit of {limit} bytes")]
+    BlobSize {
+        /// The size of the blob.
+        size: u64,
+        /// The limit.
+        limit: u64,
+    },
+    /// All blobs together are too large.
+    #[error("download of {size} bytes exceeds the limit of {limit} bytes")]
+    TotalSize {
+        /// The size of the download so far, or of the whole collection.
+        size: u64,
+        /// The limit.
+        limit: u64,
+    },
+    /// Too many bytes were read from providers.
+    #[error("read {read} bytes, more than the limit of {limit} bytes")]
+    BytesRead {
+        /// The number of bytes read so far.
+        read: u64,
+        /// The limit.
+        limit: u64,
+    },
+    /// The download did not complete by its deadline.
+    #[error("download did not complete by its deadline")]
+    Deadline,
+}
+
+/// Tracks a download against its [`DownloadLimits`].
+///
+/// Clones share the number of bytes read.
+#[derive(Debug, Clone)]
+pub struct DownloadBudget {
+    limits: DownloadLimits,
+    total: u64,
+    read: Arc<AtomicU64>,
+}
+
+impl DownloadBudget {
+    /// Tracks another attempt of the same download.
+    ///
+    /// The sizes of the blobs are checked again, while the bytes read carry over.
+    pub fn attempt(&self) -> Self {
+        Self {
+            limits: self.limits,
+            total: 0,
+            read: self.read.clone(),
+        }
+    }
+
+    /// Checks a collection with `count` children of `total_size` bytes, if known.
+    pub fn collection(&self, count: u64, total_size: Option<u64>) -> Result<(), LimitExceeded> {
+        if let Some(limit) = self.limits.max_blobs {
+            if count > limit {
+                return Err(LimitExceeded::Blobs { count, limit });
+            }
+        }
+        if let (Some(limit), Some(size)) = (self.limits.max_total_size, total_size) {
+            let size = size.saturating_add(self.total);
+            if size > limit {
+                return Err(LimitExceeded::TotalSize { size, limit });
+            }
+        }
+        Ok(())
+    }
+
+    /// Accounts for a blob of `size` bytes.
+    pub fn blob(&mut self, size: u64) -> Result<(), LimitExceeded> {
+        if let Some(limit) = self.limits.max_blob_size {
+            if size > limit {
+                return Err(LimitExceeded::BlobSize { size, limit });
+            }
+        }
+        let total = self.total.saturating_add(size);
+        if let Some(limit) = self.limits.max_total_size {
+            if total > limit {
+                return Err(LimitExceeded::TotalSize { size: total, limit });
+            }
+        }
+        self.total = total;
+        Ok(())
+    }
+
+    /// Accounts for `n` bytes read from a provider.
+    pub fn read(&self, n: u64) -> Result<(), LimitExceeded> {
+        let read = self.read.fetch_add(n, Ordering::Relaxed).saturating_add(n);
+        match self.limits.max_bytes_read {
+            Some(limit) if read > limit => Err(LimitExceeded::BytesRead { read, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget() {
+        let mut unlimited = DownloadLimits::default().budget();
+        assert!(unlimited.collection(u64::MAX, Some(u64::MAX)).is_ok());
+        assert!(unlimited.blob(u64::MAX).is_ok());
+
+        let limits = DownloadLimits {
+            max_total_size: Some(100),
+            max_blobs: Some(2),
+            max_blob_size: Some(60),
+            ..Default::default()
+        };
+        let mut budget = limits.budget();
+        assert!(budget.collection(2, Some(100)).is_ok());
+        assert!(budget.collection(3, None).is_err());
+        assert!(budget.collection(2, Some(101)).is_err());
+        assert!(budget.blob(61).is_err());
+        assert!(budget.blob(60).is_ok());
+        assert!(budget.blob(40).is_ok());
+        assert!(matches!(
+            budget.blob(1),
+            Err(LimitExceeded::TotalSize {
+                size: 101,
+                limit: 100
+            })
+        ));
+    }
+
+    #[test]
+    fn bytes_read() {
+        let limits = DownloadLimits {
+            max_bytes_read: Some(100),
+            ..Default::default()
+        };
+        let mut budget = limits.budget();
+        assert!(budget.blob(80).is_ok());
+        assert!(budget.read(80).is_ok());
+        // a retry checks the sizes again, but counts the bytes read before
+        let mut retry = budget.attempt();
+        assert!(retry.blob(80).is_ok());
+        assert!(matches!(
+            retry.read(30),
+            Err(LimitExceeded::BytesRead {
+                read: 110,
+                limit: 100
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn deadline() {
+        let unlimited = DownloadLimits::default();
+        assert_eq!(unlimited.time_left().unwrap(), None);
+        assert_eq!(unlimited.with_deadline(async { Ok(1) }).await.unwrap(), 1);
+
+        let passed = DownloadLimits {
+            deadline: Some(SystemTime::now() - Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let err = passed.with_deadline(async { Ok(()) }).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(LimitExceeded::Deadline)
+        ));
+
+        let soon = DownloadLimits {
+            deadline: Some(SystemTime::now() + Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let err = soon
+            .with_deadline(futures::future::pending::<anyhow::Result<()>>())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(LimitExceeded::Deadline)
+        ));
+    }
+}
@@ -0,0 +1,207 @@
+//! Load shedding under memory pressure.
+//!
+//! The buffers of a busy node, chunks queued for slow requesters, progress channels and
+//! imports held in memory, all end up in the resident memory of the process. The memory
+//! watchdog samples it periodically. Once it exceeds the high limit, the node stops
+//! accepting new connections and the store drops its caches, until usage is below the low
+//! limit again. Connections that are already open are served as usual, so that their
+//! buffers are released as they complete.
+//!
+//! The resident memory is only known on Linux, on other platforms the watchdog does
+//! nothing.
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// Default time between two samples of the memory usage.
+pub const DEFAULT_MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// High and low limits for the resident memory of the process, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLimits {
+    /// Above this amount of memory, load is shed.
+    pub high: u64,
+    /// Load is no longer shed once memory usage is below this amount.
+    pub low: u64,
+    /// Time between two samples of the memory usage.
+    pub interval: Duration,
+}
+
+impl MemoryLimits {
+    /// Create new limits, sampled every [`DEFAULT_MEMORY_CHECK_INTERVAL`].
+    ///
+    /// The low limit is lowered to the high limit if it is above it.
+    pub fn new(high: u64, low: u64) -> Self {
+        Self {
+            high,
+            low: low.min(high),
+            interval: DEFAULT_MEMORY_CHECK_INTERVAL,
+        }
+    }
+
+    /// Compute whether load is shed given the memory usage and the previous state.
+    fn state(&self, usage: u64, shedding: bool) -> bool {
+        if shedding {
+            usage >= self.low
+        } else {
+            usage > self.high
+        }
+    }
+}
+
+/// Events emitted when the memory usage crosses a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryEvent {
+    /// Memory usage exceeded the high limit, new connections are not accepted.
+    High {
+        /// Resident memory in bytes
+        usage: u64,
+    },
+    /// Memory usage dropped below the low limit, new connections are accepted again.
+    Recovered {
+        /// Resident memory in bytes
+        usage: u64,
+    },
+}
+
+/// Resident memory of this process in bytes, if it is known on this platform.
+#[cfg(target_os = "linux")]
+pub fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+/// Resident memory of this process in bytes, if it is known on this platform.
+///
+/// Not supported on this platform, so the memory watchdog is effectively disabled.
+#[cfg(not(target_os = "linux"))]
+pub fn resident_memory() -> Option<u64> {
+    None
+}
+
+/// Samples the memory usage according to `limits`, forever.
+///
+/// `shedding` is set while load is shed, and every change is passed to `on_event`. `shed`
+/// is called for every sample while load is shed. Returns right away if the memory usage
+/// is not known.
+pub(crate) async fn run<F, Fut>(
+    limits: MemoryLimits,
+    usage: impl Fn() -> Option<u64>,
+    shedding: watch::Sender<bool>,
+    shed: impl Fn(),
+    on_event: F,
+) where
+    F: Fn(MemoryEvent) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut ticker = tokio::time::interval(limits.interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let Some(usage) = usage() else {
+            tracing::warn!("memory usage is not known on this platform, not watching it");
+            return;
+        };
+        let was_shedding = *shedding.borrow();
+        let is_shedding = limits.state(usage, was_shedding);
+        if is_shedding != was_shedding {
+            shedding.send_replace(is_shedding);
+            let event = if is_shedding {
+                tracing::warn!(
+                    "memory usage {} above high limit {}, not accepting connections",
+                    usage,
+                    limits.high
+                );
+                #[cfg(feature = "metrics")]
+                iroh_metrics::inc!(crate::metrics::Metrics, memory_pressure);
+                MemoryEvent::High { usage }
+            } else {
+                tracing::info!(
+                    "memory usage {} below low limit {}, accepting connections",
+                    usage,
+                    limits.low
+                );
+                MemoryEvent::Recovered { usage }
+            };
+            on_event(event).await;
+        }
+        if is_shedding {
+            shed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn hysteresis() {
+        let limits = MemoryLimits::new(200, 100);
+        assert!(!limits.state(150, false));
+        assert!(limits.state(250, false));
+        assert!(limits.state(150, true));
+        assert!(!limits.state(50, true));
+        assert_eq!(MemoryLimits::new(100, 200).low, 100);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn resident_memory_known() {
+        assert!(resident_memory().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn sheds_load() {
+        let limits = MemoryLimits {
+            interval: Duration::from_millis(10),
+            ..MemoryLimits::new(200, 100)
+        };
+        let usage = Arc::new(AtomicU64::new(50));
+        let sheds = Arc::new(AtomicUsize::new(0));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (tx, mut rx) = watch::channel(false);
+        let task = tokio::spawn({
+            let usage = usage.clone();
+            let sheds = sheds.clone();
+            let events = events.clone();
+            run(
+                limits,
+                move || Some(usage.load(Ordering::SeqCst)),
+                tx,
+                move || {
+                    sheds.fetch_add(1, Ordering::SeqCst);
+                },
+                move |event| {
+                    events.lock().unwrap().push(event);
+                    async {}
+                },
+            )
+        });
+        usage.store(300, Ordering::SeqCst);
+        rx.changed().await.unwrap();
+        assert!(*rx.borrow());
+        usage.store(150, Ordering::SeqCst);
+        tokio::time::sleep(limits.interval * 3).await;
+        assert!(*rx.borrow());
+        assert!(sheds.load(Ordering::SeqCst) >= 2);
+        usage.store(50, Ordering::SeqCst);
+        rx.changed().await.unwrap();
+        assert!(!*rx.borrow());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                MemoryEvent::High { usage: 300 },
+                MemoryEvent::Recovered { usage: 50 }
+            ]
+        );
+        task.abort();
+    }
+}
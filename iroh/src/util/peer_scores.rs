@@ -0,0 +1,181 @@
+//! Scoring of the peers data is downloaded from.
+//!
+//! Data received from a peer is verified against its bao outboard before it is
+//! written, so a peer can not poison the store. It can however waste bandwidth by
+//! sending invalid data over and over again. When verification fails, the ranges
+//! that could not be verified are quarantined for that peer, the peer's score is
+//! lowered, and the peer is no longer asked for the hash.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bao_tree::ChunkNum;
+use iroh_bytes::baomap::range_collections::RangeSet2;
+use iroh_bytes::protocol::RangeSpec;
+use iroh_bytes::Hash;
+use iroh_net::tls::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Score gained for a successful download.
+pub const SUCCESS_REWARD: i64 = 1;
+
+/// Score lost for sending data that fails verification.
+pub const VERIFICATION_FAILURE_PENALTY: i64 = 10;
+
+/// Maximum number of quarantine records kept per peer.
+const MAX_QUARANTINED: usize = 64;
+
+/// Error context attached to download errors when a blob fails verification.
+#[derive(Debug, thiserror::Error)]
+#[error("verification of {hash} failed")]
+pub struct VerificationFailed {
+    /// The hash of the blob that failed verification.
+    pub hash: Hash,
+    /// The ranges of the blob that were not yet verified when verification failed.
+    ///
+    /// This includes the offending chunks.
+    pub ranges: RangeSet2<ChunkNum>,
+}
+
+/// Ranges of a blob that failed verification when downloaded from a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quarantine {
+    /// The hash that was requested, e.g. a collection.
+    pub root: Hash,
+    /// The hash of the blob that failed verification.
+    pub hash: Hash,
+    /// The quarantined chunk ranges of the blob.
+    pub ranges: RangeSpec,
+}
+
+/// The score of a single peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerScore {
+    /// The current score. Starts at 0, and goes negative for misbehaving peers.
+    pub score: i64,
+    /// Number of successful downloads from this peer.
+    pub successes: u64,
+    /// Number of downloads from this peer that failed verification.
+    pub verification_failures: u64,
+    /// The most recent verification failures, oldest first.
+    pub quarantined: Vec<Quarantine>,
+}
+
+impl PeerScore {
+    /// Whether the peer sent invalid data for `hash`, either as the requested hash or as
+    /// part of it.
+    pub fn is_quarantined(&self, hash: &Hash) -> bool {
+        self.quarantined
+            .iter()
+            .any(|q| q.root == *hash || q.hash == *hash)
+    }
+}
+
+/// Scores of all peers the node downloaded from.
+#[derive(Debug, Clone, Default)]
+pub struct PeerScores(Arc<Mutex<HashMap<PeerId, PeerScore>>>);
+
+impl PeerScores {
+    /// Record a successful download from `peer`.
+    pub fn record_success(&self, peer: PeerId) {
+        let mut scores = self.0.lock().unwrap();
+        let score = scores.entry(peer).or_default();
+        score.score = score.score.saturating_add(SUCCESS_REWARD);
+        score.successes += 1;
+    }
+
+    /// Record that data for `root` downloaded from `peer` failed verification.
+    pub fn record_verification_failure(
+        &self,
+        peer: PeerId,
+        root: Hash,
+        failure: &VerificationFailed,
+    ) {
+        tracing::warn!(
+            "peer {} sent invalid data for {}, quarantining {:?}",
+            peer,
+            failure.hash,
+            failure.ranges
+        );
+        let mut scores = self.0.lock().unwrap();
+        let score = scores.entry(peer).or_default();
+        score.score = score.score.saturating_sub(VERIFICATION_FAILURE_PENALTY);
+        score.verification_failures += 1;
+        if score.quarantined.len() >= MAX_QUARANTINED {
+            score.quarantined.remove(0);
+        }
+        score.quarantined.push(Quarantine {
+            root,
+            hash: failure.hash,
+            ranges: RangeSpec::new(&failure.ranges),
+        });
+    }
+
+    /// Whether `peer` should be avoided when downloading `hash`.
+    pub fn is_avoided(&self, peer: &PeerId, hash: &Hash) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map_or(false, |score| score.is_quarantined(hash))
+    }
+
+    /// Get the score of a single peer.
+    pub fn get(&self, peer: &PeerId) -> Option<PeerScore> {
+        self.0.lock().unwrap().get(peer).cloned()
+    }
+
+    /// Get the scores of all peers, lowest score first.
+    pub fn snapshot(&self) -> Vec<(PeerId, PeerScore)> {
+        let mut res = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, score)| (*peer, score.clone()))
+            .collect::<Vec<_>>();
+        res.sort_by_key(|(_, score)| score.score);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_net::tls::Keypair;
+
+    #[test]
+    fn verification_failure() {
+        let scores = PeerScores::default();
+        let good: PeerId = Keypair::generate().public().into();
+        let bad: PeerId = Keypair::generate().public().into();
+        let root = Hash::from(bao_tree::blake3::hash(b"root"));
+        let child = Hash::from(bao_tree::blake3::hash(b"child"));
+        scores.record_success(good);
+        scores.record_success(bad);
+        scores.record_verification_failure(
+            bad,
+            root,
+            &VerificationFailed {
+                hash: child,
+                ranges: RangeSet2::from(ChunkNum(16)..),
+            },
+        );
+        assert!(scores.is_avoided(&bad, &root));
+        assert!(scores.is_avoided(&bad, &child));
+        assert!(!scores.is_avoided(&good, &root));
+
+        let bad_score = scores.get(&bad).unwrap();
+        assert_eq!(
+            bad_score.score,
+            SUCCESS_REWARD - VERIFICATION_FAILURE_PENALTY
+        );
+        assert_eq!(bad_score.verification_failures, 1);
+        assert_eq!(
+            bad_score.quarantined[0].ranges.to_chunk_ranges(),
+            RangeSet2::from(ChunkNum(16)..)
+        );
+        let snapshot = scores.snapshot();
+        assert_eq!(snapshot[0].0, bad);
+        assert_eq!(snapshot[1].0, good);
+    }
+}
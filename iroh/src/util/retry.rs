@@ -0,0 +1,283 @@
+//! Retry policies for downloads.
+//!
+//! Errors that happen during a download are sorted into a few [`ErrorClass`]es. A
+//! [`RetryPolicy`] decides which classes are worth another attempt, and how long to
+//! wait before it. Retries do not restart a download: every attempt starts by looking
+//! at the partial entries in the store and only requests the missing ranges.
+//!
+//! Retrying is opt-in: the default policy makes a single attempt, use
+//! [`RetryPolicy::with_retries`] to allow more.
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use iroh_bytes::protocol::ErrorCode;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// The class of an error that happened during a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ErrorClass {
+    /// The connection to the provider could not be established in time.
+    DialTimeout,
+    /// The connection or stream was lost in the middle of a transfer.
+    ConnectionLost,
+    /// The provider sent data that failed bao verification.
+    Verification,
+    /// The provider does not have the requested data.
+    NotFound,
+    /// The provider refused the request because it was not authorized.
+    Unauthorized,
+    /// The provider refused the request because its token has expired.
+    Expired,
+    /// The provider refused the request or connection because of its limits.
+    RateLimited,
+    /// Any other error, e.g. a local io error.
+    Other,
+}
+
+impl ErrorClass {
+    /// Classify an error by looking at its chain of causes.
+    pub fn classify(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return ErrorClass::DialTimeout;
+            }
+            if let Some(class) = Self::classify_cause(cause) {
+                return class;
+            }
+        }
+        ErrorClass::Other
+    }
+
+    /// The class of an error code sent by the provider.
+    pub fn from_code(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::ShuttingDown | ErrorCode::RequestTimeout | ErrorCode::Stalled => {
+                ErrorClass::ConnectionLost
+            }
+            ErrorCode::Unauthorized => ErrorClass::Unauthorized,
+            ErrorCode::TokenExpired => ErrorClass::Expired,
+            ErrorCode::NotFound => ErrorClass::NotFound,
+            ErrorCode::RateLimited => ErrorClass::RateLimited,
+            ErrorCode::Internal => ErrorClass::Other,
+        }
+    }
+
+    fn classify_cause(cause: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        use bao_tree::io::DecodeError;
+        use iroh_bytes::get::GetResponseError;
+        if let Some(code) = cause.downcast_ref::<ErrorCode>() {
+            return Some(Self::from_code(*code));
+        }
+        if let Some(error) = cause.downcast_ref::<quinn::ConnectionError>() {
+            let code = ErrorCode::from_connection_error(error);
+            return Some(code.map_or(ErrorClass::ConnectionLost, Self::from_code));
+        }
+        if let Some(error) = cause.downcast_ref::<quinn::ReadError>() {
+            let code = ErrorCode::from_read_error(error);
+            return Some(code.map_or(ErrorClass::ConnectionLost, Self::from_code));
+        }
+        if let Some(quinn::WriteError::ConnectionLost(error)) =
+            cause.downcast_ref::<quinn::WriteError>()
+        {
+            let code = ErrorCode::from_connection_error(error);
+            return Some(code.map_or(ErrorClass::ConnectionLost, Self::from_code));
+        }
+        if cause.is::<quinn::WriteError>() {
+            return Some(ErrorClass::ConnectionLost);
+        }
+        if let Some(error) = cause.downcast_ref::<DecodeError>() {
+            return match error {
+                DecodeError::Io(error) => Self::classify_io(error),
+                _ => Some(ErrorClass::Verification),
+            };
+        }
+        if let Some(error) = cause.downcast_ref::<GetResponseError>() {
+            return match error {
+                GetResponseError::Remote(code) => Some(Self::from_code(*code)),
+                GetResponseError::Connection(_)
+                | GetResponseError::Read(_)
+                | GetResponseError::Write(_) => Some(ErrorClass::ConnectionLost),
+                GetResponseError::Decode(_) => Some(ErrorClass::Verification),
+                GetResponseError::Generic(_) => None,
+            };
+        }
+        if let Some(error) = cause.downcast_ref::<io::Error>() {
+            return Self::classify_io(error);
+        }
+        None
+    }
+
+    fn classify_io(error: &io::Error) -> Option<Self> {
+        // io errors often wrap the quinn errors, so look at the wrapped error first
+        if let Some(inner) = error.get_ref() {
+            if let Some(class) = Self::classify_cause(inner) {
+                return Some(class);
+            }
+        }
+        match error.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut => Some(ErrorClass::ConnectionLost),
+            // the provider closes the stream early if it does not have the data
+            io::ErrorKind::UnexpectedEof => Some(ErrorClass::NotFound),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorClass::DialTimeout => write!(f, "dial-timeout"),
+            ErrorClass::ConnectionLost => write!(f, "connection-lost"),
+            ErrorClass::Verification => write!(f, "verification"),
+            ErrorClass::NotFound => write!(f, "not-found"),
+            ErrorClass::Unauthorized => write!(f, "unauthorized"),
+            ErrorClass::Expired => write!(f, "expired"),
+            ErrorClass::RateLimited => write!(f, "rate-limited"),
+            ErrorClass::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl FromStr for ErrorClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dial-timeout" => Ok(ErrorClass::DialTimeout),
+            "connection-lost" => Ok(ErrorClass::ConnectionLost),
+            "verification" => Ok(ErrorClass::Verification),
+            "not-found" => Ok(ErrorClass::NotFound),
+            "unauthorized" => Ok(ErrorClass::Unauthorized),
+            "expired" => Ok(ErrorClass::Expired),
+            "rate-limited" => Ok(ErrorClass::RateLimited),
+            "other" => Ok(ErrorClass::Other),
+            _ => anyhow::bail!("unknown error class: {}", s),
+        }
+    }
+}
+
+/// Decides whether and when a failed download is retried.
+///
+/// The default policy does not retry, but retries the transient error classes once
+/// more attempts are allowed with [`RetryPolicy::with_retries`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Timeout for establishing the connection to the provider.
+    pub dial_timeout: Duration,
+    /// Backoff before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound for the backoff, which doubles with every retry.
+    pub max_backoff: Duration,
+    /// The classes of errors that are retried.
+    pub retry_on: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            dial_timeout: Duration::from_secs(10),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            retry_on: vec![
+                ErrorClass::DialTimeout,
+                ErrorClass::ConnectionLost,
+                ErrorClass::RateLimited,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that makes a single attempt, the default.
+    pub fn no_retry() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum number of retries after the first attempt.
+    pub fn with_retries(self, retries: u32) -> Self {
+        Self {
+            max_attempts: retries.saturating_add(1),
+            ..self
+        }
+    }
+
+    /// Whether an error of class `class` in attempt `attempt` (starting at 1) is retried.
+    pub fn should_retry(&self, attempt: u32, class: ErrorClass) -> bool {
+        attempt < self.max_attempts && self.retry_on.contains(&class)
+    }
+
+    /// The backoff to wait after the failure of attempt `attempt` (starting at 1).
+    ///
+    /// The backoff grows exponentially, and is jittered by up to half its value so that
+    /// many downloaders failing at the same time don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_is_bounded() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..100 {
+            let backoff = policy.backoff(attempt);
+            assert!(backoff <= policy.max_backoff);
+            assert!(backoff >= policy.initial_backoff / 2);
+        }
+        let first = policy.backoff(1);
+        assert!(first <= policy.initial_backoff);
+    }
+
+    #[test]
+    fn should_retry() {
+        let policy = RetryPolicy::default().with_retries(2);
+        assert!(policy.should_retry(1, ErrorClass::ConnectionLost));
+        assert!(policy.should_retry(2, ErrorClass::DialTimeout));
+        assert!(!policy.should_retry(3, ErrorClass::ConnectionLost));
+        assert!(!policy.should_retry(1, ErrorClass::Verification));
+        assert!(!RetryPolicy::no_retry().should_retry(1, ErrorClass::ConnectionLost));
+        assert!(!RetryPolicy::default().should_retry(1, ErrorClass::ConnectionLost));
+    }
+
+    #[test]
+    fn classify() {
+        let eof: anyhow::Error = io::Error::new(io::ErrorKind::UnexpectedEof, "eof").into();
+        assert_eq!(ErrorClass::classify(&eof), ErrorClass::NotFound);
+        let lost: anyhow::Error = quinn::ReadError::UnknownStream.into();
+        assert_eq!(ErrorClass::classify(&lost), ErrorClass::ConnectionLost);
+        let wrapped: anyhow::Error =
+            io::Error::new(io::ErrorKind::Other, quinn::ReadError::UnknownStream).into();
+        assert_eq!(ErrorClass::classify(&wrapped), ErrorClass::ConnectionLost);
+        let other = anyhow::anyhow!("expected StartRoot");
+        assert_eq!(ErrorClass::classify(&other), ErrorClass::Other);
+        let reset: anyhow::Error =
+            io::Error::from(quinn::ReadError::Reset(ErrorCode::Unauthorized.into())).into();
+        assert_eq!(ErrorClass::classify(&reset), ErrorClass::Unauthorized);
+        let limited: anyhow::Error =
+            iroh_bytes::get::GetResponseError::Remote(ErrorCode::RateLimited).into();
+        assert_eq!(ErrorClass::classify(&limited), ErrorClass::RateLimited);
+        let expired: anyhow::Error =
+            iroh_bytes::get::GetResponseError::Remote(ErrorCode::TokenExpired).into();
+        assert_eq!(ErrorClass::classify(&expired), ErrorClass::Expired);
+        let unknown: anyhow::Error = quinn::ReadError::Reset(42u32.into()).into();
+        assert_eq!(ErrorClass::classify(&unknown), ErrorClass::ConnectionLost);
+    }
+}
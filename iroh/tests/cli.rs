@@ -191,6 +191,7 @@ fn cli_provide_tree_resume() -> Result<()> {
     let tmp = testdir!();
     let src = tmp.join("src");
     let src_iroh_data_dir = tmp.join("src_iroh_data_dir");
+    let src_blobs_dir = src_iroh_data_dir.join(iroh::data_dir::BLOBS_DIR);
     let tgt = tmp.join("tgt");
     let tgt_work_dir = tmp.join(".iroh-tmp");
     std::fs::create_dir(&src)?;
@@ -221,7 +222,7 @@ fn cli_provide_tree_resume() -> Result<()> {
 
     // second test - full work dir
     {
-        copy_dir_all(&src_iroh_data_dir, &tgt_work_dir)?;
+        copy_dir_all(&src_blobs_dir, &tgt_work_dir)?;
         let get = make_get_cmd(&ticket, Some(tgt.clone()));
         let get_output = get.unchecked().run()?;
         assert!(get_output.status.success());
@@ -233,7 +234,7 @@ fn cli_provide_tree_resume() -> Result<()> {
 
     // third test - partial work dir - remove some large files
     {
-        copy_dir_all(&src_iroh_data_dir, &tgt_work_dir)?;
+        copy_dir_all(&src_blobs_dir, &tgt_work_dir)?;
         make_partial(&tgt_work_dir, |_hash, size| {
             if size == 100000 {
                 MakePartialResult::Remove
@@ -252,7 +253,7 @@ fn cli_provide_tree_resume() -> Result<()> {
 
     // fourth test - partial work dir - truncate some large files
     {
-        copy_dir_all(&src_iroh_data_dir, &tgt_work_dir)?;
+        copy_dir_all(&src_blobs_dir, &tgt_work_dir)?;
         make_partial(tgt_work_dir, |_hash, size| {
             if size == 100000 {
                 MakePartialResult::Truncate(1024 * 32)
@@ -294,6 +295,7 @@ fn cli_provide_persistence() -> anyhow::Result<()> {
 
     let dir = testdir!();
     let iroh_data_dir = dir.join("iroh_data_dir");
+    let blobs_dir = iroh_data_dir.join(iroh::data_dir::BLOBS_DIR);
 
     let foo_path = dir.join("foo");
     std::fs::write(&foo_path, b"foo")?;
@@ -344,13 +346,13 @@ fn cli_provide_persistence() -> anyhow::Result<()> {
         tokio_util::task::LocalPoolHandle::new(1),
     );
     // should have some data now
-    let db = Store::load_blocking(&iroh_data_dir, &iroh_data_dir, &rt)?;
+    let db = Store::load_blocking(&blobs_dir, &blobs_dir, &rt)?;
     let blobs = db.blobs().collect::<Vec<_>>();
     assert_eq!(blobs.len(), 2);
 
     provide(&bar_path)?;
     // should have more data now
-    let db = Store::load_blocking(&iroh_data_dir, &iroh_data_dir, &rt)?;
+    let db = Store::load_blocking(&blobs_dir, &blobs_dir, &rt)?;
     let blobs = db.blobs().collect::<Vec<_>>();
     assert_eq!(blobs.len(), 4);
 
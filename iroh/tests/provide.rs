@@ -16,8 +16,9 @@ use futures::{
 use iroh::{
     collection::{ArrayLinkStream, Blob, Collection, IrohCollectionParser},
     node::{Builder, Event, Node, StaticTokenAuthHandler},
+    util::retry::ErrorClass,
 };
-use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
+use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt, AsyncSliceWriter};
 use iroh_net::{
     tls::{Keypair, PeerId},
     MagicEndpoint,
@@ -27,15 +28,19 @@ use rand::RngCore;
 use tokio::sync::mpsc;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
-use bao_tree::blake3;
+use bao_tree::{blake3, ChunkNum};
 use iroh_bytes::{
-    baomap::Store,
+    baomap::{range_collections::RangeSet2, MapEntry, PartialMap, PartialMapEntry, Store},
     collection::{CollectionParser, CollectionStats, LinkStream},
-    get::{fsm, fsm::ConnectedNext, Stats},
-    protocol::{AnyGetRequest, CustomGetRequest, GetRequest, RequestToken},
+    get::{self, fsm, fsm::ConnectedNext, Stats},
+    keyed::{HashDomain, NamespaceKey},
+    protocol::{
+        AnyGetRequest, CustomGetRequest, GetRequest, KeyedGetRequest, ListEntry, ListRequest,
+        ProbeRequest, ProbeResponse, RangeSpecSeq, RequestToken,
+    },
     provider::{self, CustomGetHandler, RequestAuthorizationHandler},
     util::runtime,
-    Hash,
+    Hash, IROH_BLOCK_SIZE,
 };
 
 /// Pick up the tokio runtime from the thread local and add a
@@ -147,6 +152,8 @@ fn get_options(peer_id: PeerId, addrs: Vec<SocketAddr>) -> iroh::dial::Options {
         derp_region: None,
         keylog: false,
         derp_map: None,
+        qlog: None,
+        dns_resolver: Default::default(),
     }
 }
 
@@ -419,6 +426,191 @@ async fn test_server_close() {
         .expect("supervisor failed");
 }
 
+/// Cancel a request mid-transfer by dropping the receiving side of the stream, and check
+/// that the provider aborts the transfer instead of sending all the data.
+#[cfg(feature = "mem-db")]
+#[tokio::test]
+async fn test_cancel_mid_transfer() {
+    let rt = test_runtime();
+    setup_logging();
+    let mut db = iroh::baomap::readonly_mem::Store::default();
+    let hash = db.insert(vec![0u8; 1024 * 1024 * 32]);
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let node = test_node(db, addr).runtime(&rt).spawn().await.unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+    let node_addr = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.peer_id();
+
+    let (events_sender, mut events_recv) = mpsc::unbounded_channel();
+    node.subscribe(move |event| {
+        let events_sender = events_sender.clone();
+        async move {
+            events_sender.send(event).ok();
+        }
+        .boxed()
+    })
+    .await
+    .unwrap();
+
+    let opts = get_options(peer_id, node_addr);
+    let connection = iroh::dial::dial(opts).await.unwrap();
+    let request = GetRequest::single(hash).into();
+    let connected = fsm::start(connection.clone(), request)
+        .next()
+        .await
+        .unwrap();
+    let fsm::ConnectedNext::StartRoot(start) = connected.next().await.unwrap() else {
+        panic!("expected root");
+    };
+    let (content, _size) = start.next().next().await.unwrap();
+    let fsm::BlobContentNext::More((content, item)) = content.next().await else {
+        panic!("expected content");
+    };
+    item.unwrap();
+    // dropping the content drops the receive stream, which stops the stream
+    drop(content);
+
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        loop {
+            match events_recv.recv().await {
+                Some(Event::ByteProvide(provider::Event::TransferAborted { .. })) => break,
+                Some(Event::ByteProvide(provider::Event::TransferCollectionCompleted {
+                    ..
+                })) => panic!("transfer completed despite cancellation"),
+                Some(_) => {}
+                None => panic!("events ended"),
+            }
+        }
+    })
+    .await
+    .expect("transfer was not aborted");
+    // the connection itself is still usable
+    assert!(connection.close_reason().is_none());
+}
+
+#[tokio::test]
+async fn test_stalled_requester() {
+    let rt = test_runtime();
+    setup_logging();
+    let mut db = iroh::baomap::readonly_mem::Store::default();
+    let hash = db.insert(vec![0u8; 1024 * 1024 * 32]);
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let node = test_node(db, addr)
+        .write_timeouts(provider::WriteTimeouts {
+            request: None,
+            stall: Some(Duration::from_secs(1)),
+        })
+        .runtime(&rt)
+        .spawn()
+        .await
+        .unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+    let node_addr = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.peer_id();
+
+    let (events_sender, mut events_recv) = mpsc::unbounded_channel();
+    node.subscribe(move |event| {
+        let events_sender = events_sender.clone();
+        async move {
+            events_sender.send(event).ok();
+        }
+        .boxed()
+    })
+    .await
+    .unwrap();
+
+    let opts = get_options(peer_id, node_addr);
+    let connection = iroh::dial::dial(opts).await.unwrap();
+    let request = GetRequest::single(hash).into();
+    let connected = fsm::start(connection.clone(), request)
+        .next()
+        .await
+        .unwrap();
+    let fsm::ConnectedNext::StartRoot(start) = connected.next().await.unwrap() else {
+        panic!("expected root");
+    };
+    // keep the stream open, but never read the content
+    let (_content, _size) = start.next().next().await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        loop {
+            match events_recv.recv().await {
+                Some(Event::ByteProvide(provider::Event::TransferAborted { .. })) => break,
+                Some(Event::ByteProvide(provider::Event::TransferCollectionCompleted {
+                    ..
+                })) => panic!("transfer completed despite stalled requester"),
+                Some(_) => {}
+                None => panic!("events ended"),
+            }
+        }
+    })
+    .await
+    .expect("stalled transfer was not aborted");
+}
+
+#[tokio::test]
+async fn test_serve_partial() {
+    let rt = test_runtime();
+    setup_logging();
+    let db = iroh::baomap::mem::Store::new(rt.clone());
+    let data = vec![1u8; 1024 * 64];
+    let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+    let hash = Hash::from(hash);
+    // only the first half of the blob has been downloaded
+    let entry = db.get_or_create_partial(hash, data.len() as u64).unwrap();
+    let mut outboard_mut = entry.outboard_mut().await.unwrap();
+    outboard_mut.data.write_at(0, &outboard).await.unwrap();
+    let mut data_writer = entry.data_writer().await.unwrap();
+    data_writer
+        .write_at(0, &data[..data.len() / 2])
+        .await
+        .unwrap();
+    let half = RangeSet2::from(..ChunkNum(32));
+    assert_eq!(entry.available_ranges().await.unwrap(), half);
+
+    for serve_partial in [false, true] {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let node = test_node(db.clone(), addr)
+            .serve_partial(serve_partial)
+            .runtime(&rt)
+            .spawn()
+            .await
+            .unwrap();
+        let addrs = node.local_endpoint_addresses().await.unwrap();
+        let opts = get_options(node.peer_id(), addrs);
+        let request = GetRequest::new(hash, RangeSpecSeq::new([half.clone()])).into();
+        let res = tokio::time::timeout(Duration::from_secs(10), get_blob(opts, request))
+            .await
+            .expect("timeout");
+        if serve_partial {
+            assert_eq!(res.unwrap(), &data[..data.len() / 2]);
+        } else {
+            assert!(res.is_err(), "partial entry was served");
+        }
+        node.shutdown();
+    }
+}
+
+/// Get a single blob, returning its data
+async fn get_blob(opts: iroh::dial::Options, request: AnyGetRequest) -> Result<Vec<u8>> {
+    let connection = iroh::dial::dial(opts).await?;
+    read_blob(fsm::start(connection, request)).await
+}
+
+/// Read a single blob from a started request
+async fn read_blob(initial: fsm::AtInitial) -> Result<Vec<u8>> {
+    let connected = initial.next().await?;
+    let ConnectedNext::StartRoot(start) = connected.next().await? else {
+        bail!("expected root");
+    };
+    let (end, data) = start.next().concatenate_into_vec().await?;
+    let fsm::EndBlobNext::Closing(closing) = end.next() else {
+        bail!("expected closing");
+    };
+    closing.next().await?;
+    Ok(data)
+}
+
 /// create an in memory test database containing the given entries and an iroh collection of all entries
 ///
 /// returns the database and the root hash of the collection
@@ -487,7 +679,8 @@ async fn test_run_ticket() {
         let opts = no_token_ticket.as_get_options(Keypair::generate(), None);
         let request = GetRequest::all(no_token_ticket.hash()).into();
         let response = run_get_request(opts, request).await;
-        assert!(response.is_err());
+        let cause = response.expect_err("get without token succeeded");
+        assert_eq!(ErrorClass::classify(&cause), ErrorClass::Unauthorized);
         anyhow::Result::<_>::Ok(())
     })
     .await
@@ -506,6 +699,178 @@ async fn test_run_ticket() {
     .expect("get ticket failed");
 }
 
+#[tokio::test]
+async fn test_not_found() {
+    let rt = test_runtime();
+    let (db, _hash) = create_test_db([("test", b"hello")]);
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let node = test_node(db, addr).runtime(&rt).spawn().await.unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.peer_id();
+    let cause = tokio::time::timeout(Duration::from_secs(10), async move {
+        let opts = get_options(peer_id, addrs);
+        let request = GetRequest::all(Hash::new(b"missing")).into();
+        run_get_request(opts, request).await
+    })
+    .await
+    .expect("timeout")
+    .expect_err("get of a missing hash succeeded");
+    assert_eq!(ErrorClass::classify(&cause), ErrorClass::NotFound);
+}
+
+#[tokio::test]
+async fn test_probe() {
+    let rt = test_runtime();
+    let (db, hashes) = iroh::baomap::readonly_mem::Store::new([("test", b"hello")]);
+    let hash = hashes["test"].into();
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let node = test_node(db, addr).runtime(&rt).spawn().await.unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let opts = get_options(node.peer_id(), addrs);
+    let connection = iroh::dial::dial(opts).await.unwrap();
+
+    let found = get::probe(&connection, ProbeRequest::new(hash)).await.unwrap();
+    assert_eq!(found.size, Some(5));
+    assert!(found.complete);
+    assert!(found.ranges.is_all());
+    let missing = ProbeRequest::new(Hash::new(b"missing"));
+    let missing = get::probe(&connection, missing).await.unwrap();
+    assert_eq!(missing, ProbeResponse::not_found());
+}
+
+#[tokio::test]
+async fn test_list() {
+    let rt = test_runtime();
+    let db = iroh::baomap::mem::Store::new(rt.clone());
+    let hash = db.import_bytes(b"hello".to_vec().into()).await.unwrap();
+    let pin = iroh_bytes::baomap::Pin {
+        hash,
+        recursive: false,
+        expires: None,
+    };
+    db.set_pin("hello".to_string(), pin).await.unwrap();
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+
+    // refused by default
+    let node = test_node(db.clone(), addr).runtime(&rt).spawn().await.unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let connection = iroh::dial::dial(get_options(node.peer_id(), addrs))
+        .await
+        .unwrap();
+    assert!(get::list(&connection, ListRequest::default()).await.is_err());
+    node.shutdown();
+
+    let node = test_node(db, addr)
+        .serve_listing(true)
+        .runtime(&rt)
+        .spawn()
+        .await
+        .unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let connection = iroh::dial::dial(get_options(node.peer_id(), addrs))
+        .await
+        .unwrap();
+    let response = get::list(&connection, ListRequest::default()).await.unwrap();
+    let expected = ListEntry {
+        hash,
+        name: Some("hello".to_string()),
+        size: 5,
+        collection: false,
+    };
+    assert_eq!(response.entries, vec![expected]);
+}
+
+#[tokio::test]
+async fn test_list_flat() {
+    let rt = test_runtime();
+    let dir = tempfile::tempdir().unwrap();
+    let db = iroh::baomap::flat::Store::load(dir.path(), dir.path(), &rt)
+        .await
+        .unwrap();
+    let child = db.import_bytes(b"hello".to_vec().into()).await.unwrap();
+    let blobs = vec![Blob {
+        name: "hello".to_string(),
+        hash: child,
+    }];
+    let collection = Collection::new(blobs, 5).unwrap();
+    let hash = db
+        .import_bytes(collection.to_bytes().unwrap().into())
+        .await
+        .unwrap();
+    let pin = iroh_bytes::baomap::Pin {
+        hash,
+        recursive: true,
+        expires: None,
+    };
+    db.set_pin("dir".to_string(), pin).await.unwrap();
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+
+    let node = test_node(db, addr)
+        .serve_listing(true)
+        .runtime(&rt)
+        .spawn()
+        .await
+        .unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let connection = iroh::dial::dial(get_options(node.peer_id(), addrs))
+        .await
+        .unwrap();
+    let response = get::list(&connection, ListRequest::default()).await.unwrap();
+    let expected = ListEntry {
+        hash,
+        name: Some("dir".to_string()),
+        size: 5,
+        collection: true,
+    };
+    assert_eq!(response.entries, vec![expected]);
+}
+
+#[tokio::test]
+async fn test_keyed_namespace() {
+    let rt = test_runtime();
+    let (db, hashes) = iroh::baomap::readonly_mem::Store::new([("test", b"hello")]);
+    let hash = hashes["test"].into();
+    let key = NamespaceKey::generate();
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let node = test_node(db, addr)
+        .keyed_namespace(key.clone())
+        .runtime(&rt)
+        .spawn()
+        .await
+        .unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+
+    let ticket = node.ticket(hash).await.unwrap();
+    let name = key.name(&hash);
+    assert_eq!(ticket.domain(), HashDomain::Keyed(name));
+    assert_eq!(ticket.hash(), hash);
+    let opts = ticket.as_get_options(Keypair::generate(), None);
+
+    // the plain hash is not served
+    let request = GetRequest::single(hash).into();
+    assert!(get_blob(opts.clone(), request).await.is_err());
+    let connection = iroh::dial::dial(opts.clone()).await.unwrap();
+    let probe = get::probe(&connection, ProbeRequest::new(hash)).await;
+    assert!(probe.is_err());
+
+    // the name is only served with the root hash known to the requester
+    let missing = KeyedGetRequest::new(Hash::new(b"missing"), RangeSpecSeq::all());
+    let request = fsm::start_named(connection.clone(), missing, hash);
+    assert!(read_blob(request).await.is_err());
+    let named = KeyedGetRequest::new(name, RangeSpecSeq::all());
+    let request = fsm::start_named(connection.clone(), named.clone(), Hash::new(b"wrong"));
+    assert!(read_blob(request).await.is_err());
+
+    let data = read_blob(fsm::start_named(connection, named, hash))
+        .await
+        .unwrap();
+    assert_eq!(data, b"hello");
+}
+
 /// Utility to validate that the children of a collection are correct
 fn validate_children(collection: Collection, children: BTreeMap<u64, Bytes>) -> anyhow::Result<()> {
     let blobs = collection.into_inner();
@@ -760,6 +1125,77 @@ async fn test_custom_request_collection() {
     .expect("get failed");
 }
 
+#[derive(Clone, Debug)]
+struct PanickingCustomHandler;
+
+impl CustomGetHandler for PanickingCustomHandler {
+    fn handle(
+        &self,
+        _token: Option<RequestToken>,
+        _data: Bytes,
+    ) -> BoxFuture<'static, anyhow::Result<GetRequest>> {
+        panic!("custom handler bug")
+    }
+}
+
+/// A panicking request handler resets its stream and is reported, while the node keeps
+/// serving other requests.
+#[tokio::test]
+async fn test_request_panic() {
+    let rt = test_runtime();
+    let expected = b"hello".to_vec();
+    let (db, hashes) = iroh::baomap::readonly_mem::Store::new([("test", &expected)]);
+    let hash = Hash::from(*hashes.values().next().unwrap());
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let node = test_node(db, addr)
+        .runtime(&rt)
+        .custom_get_handler(Arc::new(PanickingCustomHandler))
+        .spawn()
+        .await
+        .unwrap();
+    let _drop_guard = node.cancel_token().drop_guard();
+    let (events_sender, mut events_recv) = mpsc::unbounded_channel();
+    node.subscribe(move |event| {
+        let events_sender = events_sender.clone();
+        async move {
+            events_sender.send(event).ok();
+        }
+        .boxed()
+    })
+    .await
+    .unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.peer_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let request = CustomGetRequest {
+            token: None,
+            data: Bytes::from(&b"hello"[..]),
+        }
+        .into();
+        get_blob(get_options(peer_id, addrs.clone()), request)
+            .await
+            .expect_err("request with a panicking handler succeeded");
+        let message = loop {
+            match events_recv.recv().await {
+                Some(Event::ByteProvide(provider::Event::RequestPanicked { message, .. })) => {
+                    break message
+                }
+                Some(_) => {}
+                None => panic!("events ended"),
+            }
+        };
+        assert_eq!(message, "custom handler bug");
+
+        let request = GetRequest::single(hash).into();
+        let data = get_blob(get_options(peer_id, addrs), request).await?;
+        assert_eq!(data, expected);
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("get failed");
+}
+
 #[derive(Clone, Debug)]
 struct CustomAuthHandler;
 